@@ -0,0 +1,151 @@
+use ruma::identifiers::RoomId;
+use ruma_client::HttpClient;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::value::to_raw_value;
+
+use crate::intent::{Intent, IntentError};
+
+impl<C: HttpClient> Intent<C> {
+    /// Get the global account data of type `event_type` of the user, deserialized as `T`, or
+    /// `None` if it isn't set.
+    ///
+    /// Together with `set_account_data`, this can be used to keep settings of a user, like
+    /// the puppet of a bridged user, in Matrix itself.
+    pub async fn get_account_data<T: DeserializeOwned>(
+        &self,
+        event_type: &str,
+    ) -> Result<Option<T>, IntentError<C::Error>> {
+        match self.get_account_data_raw(event_type).await? {
+            Some(content) => Ok(Some(serde_json::from_str(content.get())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the global account data of type `event_type` of the user to `content`.
+    pub async fn set_account_data<T: Serialize>(
+        &self,
+        event_type: &str,
+        content: &T,
+    ) -> Result<(), IntentError<C::Error>> {
+        let content = to_raw_value(content)?;
+        self.set_account_data_raw(event_type, &content).await
+    }
+
+    /// Get the account data of type `event_type` of the user in the room with the given
+    /// `room_id`, deserialized as `T`, or `None` if it isn't set.
+    ///
+    /// Together with `set_room_account_data`, this can be used to keep settings of a portal,
+    /// like its relay mode, as account data of the bot in the room.
+    pub async fn get_room_account_data<T: DeserializeOwned>(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+    ) -> Result<Option<T>, IntentError<C::Error>> {
+        match self.get_room_account_data_raw(room_id, event_type).await? {
+            Some(content) => Ok(Some(serde_json::from_str(content.get())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the account data of type `event_type` of the user in the room with the given
+    /// `room_id` to `content`.
+    pub async fn set_room_account_data<T: Serialize>(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+        content: &T,
+    ) -> Result<(), IntentError<C::Error>> {
+        let content = to_raw_value(content)?;
+        self.set_room_account_data_raw(room_id, event_type, &content)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{RoomId, UserId};
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{Intent, IntentError};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct PortalSettings {
+        relay: bool,
+        notifications: String,
+    }
+
+    #[tokio::test]
+    async fn test_room_account_data() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let path = "/user/@bot:example.org/rooms/!room:example.org/account_data/org.example.portal";
+
+        let settings = PortalSettings {
+            relay: true,
+            notifications: String::from("mentions"),
+        };
+        bot.set_room_account_data(&room_id, "org.example.portal", &settings)
+            .await
+            .unwrap();
+        let requests = state.requests_to(path);
+        assert!(requests[0].path.contains("user_id=@bot:example.org"));
+        assert_eq!(
+            requests[0].body,
+            json!({ "relay": true, "notifications": "mentions" })
+        );
+
+        state.respond_once(
+            path,
+            200,
+            json!({ "relay": true, "notifications": "mentions" }),
+        );
+        let loaded: Option<PortalSettings> = bot
+            .get_room_account_data(&room_id, "org.example.portal")
+            .await
+            .unwrap();
+        assert_eq!(loaded, Some(settings));
+
+        state.respond_once(
+            path,
+            404,
+            json!({ "errcode": "M_NOT_FOUND", "error": "Room account data not found" }),
+        );
+        let loaded: Option<PortalSettings> = bot
+            .get_room_account_data(&room_id, "org.example.portal")
+            .await
+            .unwrap();
+        assert_eq!(loaded, None);
+
+        state.respond_once(path, 200, json!({ "relay": "yes" }));
+        let err = bot
+            .get_room_account_data::<PortalSettings>(&room_id, "org.example.portal")
+            .await;
+        assert!(matches!(err, Err(IntentError::Json(_))));
+    }
+
+    #[tokio::test]
+    async fn test_account_data() {
+        let (client, state) = mock_client();
+        let ghost = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        let path = "/user/@_ext_bob:example.org/account_data/org.example.settings";
+
+        ghost
+            .set_account_data("org.example.settings", &json!({ "color": "red" }))
+            .await
+            .unwrap();
+        assert_eq!(state.requests_to(path)[0].body, json!({ "color": "red" }));
+
+        state.respond(path, 200, json!({ "color": "red" }));
+        let settings: Option<serde_json::Value> = ghost
+            .get_account_data("org.example.settings")
+            .await
+            .unwrap();
+        assert_eq!(settings, Some(json!({ "color": "red" })));
+    }
+}
@@ -0,0 +1,61 @@
+//! Endpoints of the Matrix API that are missing from `ruma`.
+//!
+//! The requests can be sent like any `ruma` request, using `Client::send_request` or an `Intent`.
+
+use ruma::api::error::{FromHttpResponseError, IntoHttpError, ServerError};
+use ruma::api::exports::bytes::BufMut;
+use ruma::api::exports::http::{self, header, Method};
+use ruma::api::{EndpointError, SendAccessToken};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub mod appservice_login;
+pub mod batch_send;
+pub mod knock;
+
+/// Build an authenticated request to `path` with the given JSON `body`.
+fn json_request<T, B>(
+    method: Method,
+    base_url: &str,
+    path: &str,
+    access_token: SendAccessToken<'_>,
+    body: &B,
+) -> Result<http::Request<T>, IntoHttpError>
+where
+    T: Default + BufMut,
+    B: Serialize,
+{
+    let access_token = access_token
+        .get_required_for_endpoint()
+        .ok_or(IntoHttpError::NeedsAuthentication)?;
+
+    let mut buf = T::default();
+    buf.put_slice(&serde_json::to_vec(body)?);
+
+    let request = http::Request::builder()
+        .method(method)
+        .uri(format!("{}{}", base_url.trim_end_matches('/'), path))
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::AUTHORIZATION, format!("Bearer {}", access_token))
+        .body(buf)?;
+    Ok(request)
+}
+
+/// Parse the JSON body of `response`, or the error returned by the homeserver.
+fn json_response<R, T>(
+    response: http::Response<T>,
+) -> Result<R, FromHttpResponseError<ruma::api::client::Error>>
+where
+    R: DeserializeOwned,
+    T: AsRef<[u8]>,
+{
+    if response.status().as_u16() < 400 {
+        return Ok(serde_json::from_slice(response.body().as_ref())?);
+    }
+
+    let err = match ruma::api::client::Error::try_from_http_response(response) {
+        Ok(err) => ServerError::Known(err),
+        Err(err) => ServerError::Unknown(err),
+    };
+    Err(err.into())
+}
@@ -0,0 +1,111 @@
+//! [POST /_matrix/client/r0/login](https://spec.matrix.org/v1.13/client-server-api/#appservice-login)
+//! with the `m.login.application_service` login type, logging in as a user in the namespace of
+//! the application service.
+
+use ruma::api::error::{FromHttpResponseError, IntoHttpError};
+use ruma::api::exports::bytes::BufMut;
+use ruma::api::exports::http::{self, Method};
+use ruma::api::{AuthScheme, IncomingResponse, Metadata, OutgoingRequest, SendAccessToken};
+use ruma::identifiers::{DeviceIdBox, UserId};
+use serde::{Deserialize, Serialize};
+
+use super::{json_request, json_response};
+
+/// A request to log in as a user of the application service, using the token of the application
+/// service.
+#[derive(Debug, Clone)]
+pub struct Request {
+    /// The user to log in as.
+    pub user_id: UserId,
+    /// The ID of the device to log in to, a new device is created if it doesn't exist.
+    pub device_id: Option<DeviceIdBox>,
+    /// The display name of the device, if a new one is created.
+    pub initial_device_display_name: Option<String>,
+}
+
+impl Request {
+    /// Create a new `Request` logging in as the user with the given `user_id` on a new device.
+    pub fn new(user_id: UserId) -> Self {
+        Self {
+            user_id,
+            device_id: None,
+            initial_device_display_name: None,
+        }
+    }
+}
+
+/// The response to a login `Request`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Response {
+    /// The ID of the user that logged in.
+    pub user_id: UserId,
+    /// The access token of the new session.
+    pub access_token: String,
+    /// The ID of the device of the session.
+    pub device_id: DeviceIdBox,
+}
+
+#[derive(Serialize)]
+struct UserIdentifier<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    user: &'a str,
+}
+
+#[derive(Serialize)]
+struct RequestBody<'a> {
+    #[serde(rename = "type")]
+    login_type: &'static str,
+    identifier: UserIdentifier<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<&'a DeviceIdBox>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial_device_display_name: Option<&'a str>,
+}
+
+impl OutgoingRequest for Request {
+    type EndpointError = ruma::api::client::Error;
+    type IncomingResponse = Response;
+
+    const METADATA: Metadata = Metadata {
+        description: "Log in as a user in the namespace of the application service.",
+        method: Method::POST,
+        name: "appservice_login",
+        path: "/_matrix/client/r0/login",
+        rate_limited: true,
+        authentication: AuthScheme::AccessToken,
+    };
+
+    fn try_into_http_request<T: Default + BufMut>(
+        self,
+        base_url: &str,
+        access_token: SendAccessToken<'_>,
+    ) -> Result<http::Request<T>, IntoHttpError> {
+        let body = RequestBody {
+            login_type: "m.login.application_service",
+            identifier: UserIdentifier {
+                kind: "m.id.user",
+                user: self.user_id.as_str(),
+            },
+            device_id: self.device_id.as_ref(),
+            initial_device_display_name: self.initial_device_display_name.as_deref(),
+        };
+        json_request(
+            Method::POST,
+            base_url,
+            Self::METADATA.path,
+            access_token,
+            &body,
+        )
+    }
+}
+
+impl IncomingResponse for Response {
+    type EndpointError = ruma::api::client::Error;
+
+    fn try_from_http_response<T: AsRef<[u8]>>(
+        response: http::Response<T>,
+    ) -> Result<Self, FromHttpResponseError<ruma::api::client::Error>> {
+        json_response(response)
+    }
+}
@@ -0,0 +1,124 @@
+//! [POST /_matrix/client/unstable/org.matrix.msc2716/rooms/{roomId}/batch_send](https://github.com/matrix-org/matrix-spec-proposals/pull/2716)
+//! from MSC2716, inserting a batch of historical events into a room.
+
+use ruma::api::error::{FromHttpResponseError, IntoHttpError};
+use ruma::api::exports::bytes::BufMut;
+use ruma::api::exports::http::{self, Method};
+use ruma::api::exports::percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use ruma::api::{AuthScheme, IncomingResponse, Metadata, OutgoingRequest, SendAccessToken};
+use ruma::identifiers::{EventId, RoomId, UserId};
+use ruma::MilliSecondsSinceUnixEpoch;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{json_request, json_response};
+
+/// An event in a batch.
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchEvent {
+    /// The type of the event.
+    #[serde(rename = "type")]
+    pub event_type: String,
+    /// The user that sent the event, which must be in the namespace of the application service.
+    pub sender: UserId,
+    /// The time the event was originally sent.
+    pub origin_server_ts: MilliSecondsSinceUnixEpoch,
+    /// The JSON content of the event.
+    pub content: Value,
+    /// The state key of the event, if it's a state event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_key: Option<String>,
+}
+
+/// A request to insert a batch of historical events before `prev_event_id`.
+#[derive(Debug, Clone)]
+pub struct Request {
+    /// The room to insert the events into.
+    pub room_id: RoomId,
+    /// The event to insert the events before.
+    pub prev_event_id: EventId,
+    /// The `next_batch_id` of the previous batch, to insert the events before that batch.
+    pub batch_id: Option<String>,
+    /// The state events, like memberships of the senders, that hold at the start of the batch.
+    pub state_events_at_start: Vec<BatchEvent>,
+    /// The events of the batch, in chronological order.
+    pub events: Vec<BatchEvent>,
+}
+
+impl Request {
+    /// Create a new `Request` inserting `events` into the room with the given `room_id` before
+    /// the event with the given `prev_event_id`.
+    pub fn new(room_id: RoomId, prev_event_id: EventId, events: Vec<BatchEvent>) -> Self {
+        Self {
+            room_id,
+            prev_event_id,
+            batch_id: None,
+            state_events_at_start: vec![],
+            events,
+        }
+    }
+}
+
+/// The response to a batch send `Request`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Response {
+    /// The IDs of the state events at the start of the batch.
+    #[serde(default)]
+    pub state_event_ids: Vec<EventId>,
+    /// The IDs of the events of the batch, in the order they were given.
+    pub event_ids: Vec<EventId>,
+    /// The ID to use as `batch_id` to insert the next batch before this one.
+    pub next_batch_id: String,
+}
+
+#[derive(Serialize)]
+struct RequestBody<'a> {
+    state_events_at_start: &'a [BatchEvent],
+    events: &'a [BatchEvent],
+}
+
+impl OutgoingRequest for Request {
+    type EndpointError = ruma::api::client::Error;
+    type IncomingResponse = Response;
+
+    const METADATA: Metadata = Metadata {
+        description: "Insert a batch of historical events into a room.",
+        method: Method::POST,
+        name: "batch_send",
+        path: "/_matrix/client/unstable/org.matrix.msc2716/rooms/:room_id/batch_send",
+        rate_limited: false,
+        authentication: AuthScheme::AccessToken,
+    };
+
+    fn try_into_http_request<T: Default + BufMut>(
+        self,
+        base_url: &str,
+        access_token: SendAccessToken<'_>,
+    ) -> Result<http::Request<T>, IntoHttpError> {
+        let encode = |s: &str| utf8_percent_encode(s, NON_ALPHANUMERIC).to_string();
+        let mut path = format!(
+            "/_matrix/client/unstable/org.matrix.msc2716/rooms/{}/batch_send?prev_event_id={}",
+            encode(self.room_id.as_str()),
+            encode(self.prev_event_id.as_str()),
+        );
+        if let Some(batch_id) = &self.batch_id {
+            path.push_str(&format!("&batch_id={}", encode(batch_id)));
+        }
+
+        let body = RequestBody {
+            state_events_at_start: &self.state_events_at_start,
+            events: &self.events,
+        };
+        json_request(Method::POST, base_url, &path, access_token, &body)
+    }
+}
+
+impl IncomingResponse for Response {
+    type EndpointError = ruma::api::client::Error;
+
+    fn try_from_http_response<T: AsRef<[u8]>>(
+        response: http::Response<T>,
+    ) -> Result<Self, FromHttpResponseError<ruma::api::client::Error>> {
+        json_response(response)
+    }
+}
@@ -0,0 +1,93 @@
+//! [POST /_matrix/client/v3/knock/{roomIdOrAlias}](https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3knockroomidoralias),
+//! asking to join a room with the `knock` join rule.
+
+use ruma::api::error::{FromHttpResponseError, IntoHttpError};
+use ruma::api::exports::bytes::BufMut;
+use ruma::api::exports::http::{self, Method};
+use ruma::api::exports::percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use ruma::api::{AuthScheme, IncomingResponse, Metadata, OutgoingRequest, SendAccessToken};
+use ruma::identifiers::{RoomId, ServerName};
+use serde::{Deserialize, Serialize};
+
+use super::{json_request, json_response};
+
+/// A request to knock on a room.
+#[derive(Debug, Clone)]
+pub struct Request {
+    /// The room to knock on.
+    pub room_id: RoomId,
+    /// The reason for joining the room, shown to the members that can accept the knock.
+    pub reason: Option<String>,
+    /// The servers to knock through, if the homeserver isn't in the room.
+    pub server_name: Vec<Box<ServerName>>,
+}
+
+impl Request {
+    /// Create a new `Request` knocking on the room with the given `room_id`.
+    pub fn new(room_id: RoomId) -> Self {
+        Self {
+            room_id,
+            reason: None,
+            server_name: vec![],
+        }
+    }
+}
+
+/// The response to a knock `Request`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Response {
+    /// The room that was knocked on.
+    pub room_id: RoomId,
+}
+
+#[derive(Serialize)]
+struct RequestBody<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+}
+
+impl OutgoingRequest for Request {
+    type EndpointError = ruma::api::client::Error;
+    type IncomingResponse = Response;
+
+    const METADATA: Metadata = Metadata {
+        description: "Knock on a room, asking to join it.",
+        method: Method::POST,
+        name: "knock",
+        path: "/_matrix/client/v3/knock/:room_id_or_alias",
+        rate_limited: true,
+        authentication: AuthScheme::AccessToken,
+    };
+
+    fn try_into_http_request<T: Default + BufMut>(
+        self,
+        base_url: &str,
+        access_token: SendAccessToken<'_>,
+    ) -> Result<http::Request<T>, IntoHttpError> {
+        let encode = |s: &str| utf8_percent_encode(s, NON_ALPHANUMERIC).to_string();
+        let mut path = format!("/_matrix/client/v3/knock/{}", encode(self.room_id.as_str()));
+        for (i, server_name) in self.server_name.iter().enumerate() {
+            let separator = if i == 0 { '?' } else { '&' };
+            path.push_str(&format!(
+                "{}server_name={}",
+                separator,
+                encode(server_name.as_str())
+            ));
+        }
+
+        let body = RequestBody {
+            reason: self.reason.as_deref(),
+        };
+        json_request(Method::POST, base_url, &path, access_token, &body)
+    }
+}
+
+impl IncomingResponse for Response {
+    type EndpointError = ruma::api::client::Error;
+
+    fn try_from_http_response<T: AsRef<[u8]>>(
+        response: http::Response<T>,
+    ) -> Result<Self, FromHttpResponseError<ruma::api::client::Error>> {
+        json_response(response)
+    }
+}
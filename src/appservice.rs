@@ -1,7 +1,15 @@
+use std::convert::TryFrom;
+
+use ruma::api::client::error::ErrorKind;
+use ruma::api::client::r0::account::whoami;
 use ruma::api::exports::http::Uri;
-use ruma::identifiers::ServerName;
+use ruma::identifiers::{ServerName, UserId};
+use ruma_client::{Client, HttpClient};
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::intent::{Intent, IntentError};
 
 pub use ruma::api::appservice::{Namespace, Namespaces, Registration, RegistrationInit};
 
@@ -51,3 +59,219 @@ impl ApplicationService {
         self.server_url.parse().unwrap()
     }
 }
+
+/// A problem with the setup of an application service, found by `ApplicationService::verify`.
+#[derive(Debug, Error)]
+pub enum VerifyError<E> {
+    /// The homeserver rejected the `as_token`, so the registration isn't loaded by the
+    /// homeserver or has a different token.
+    #[error("{}", self.diagnostic())]
+    TokenRejected(IntentError<E>),
+    /// The `as_token` belongs to another user than the bot user of the registration.
+    #[error("{}", self.diagnostic())]
+    UnexpectedUser(UserId),
+    /// The registration has no exclusive user namespace, so other users could take the user IDs
+    /// of the bridge.
+    #[error("{}", self.diagnostic())]
+    NamespaceNotExclusive,
+    /// The homeserver didn't allow acting as the given user, which probably isn't in the user
+    /// namespace of the registration.
+    #[error("{}", self.diagnostic())]
+    MasqueradeRejected(IntentError<E>),
+    /// The homeserver ignored the `user_id` url parameter, so the registration isn't loaded by
+    /// the homeserver as an application service.
+    #[error("{}", self.diagnostic())]
+    UserIdIgnored,
+    /// The `sender_localpart` of the registration gives an invalid user ID.
+    #[error("{}", self.diagnostic())]
+    InvalidUserId(ruma::identifiers::Error),
+    /// Another request to the homeserver failed.
+    #[error("{0}")]
+    Intent(IntentError<E>),
+}
+
+impl<E> VerifyError<E> {
+    /// Get a description of the problem and how it can be fixed, to show to the administrator of
+    /// the bridge.
+    pub fn diagnostic(&self) -> String {
+        match self {
+            VerifyError::TokenRejected(_) => String::from(
+                "as_token rejected, is the registration file added to the homeserver config?",
+            ),
+            VerifyError::UnexpectedUser(user_id) => format!(
+                "as_token belongs to {}, does sender_localpart match the registration on the homeserver?",
+                user_id
+            ),
+            VerifyError::NamespaceNotExclusive => {
+                String::from("user namespace not exclusive, set exclusive: true in the registration")
+            }
+            VerifyError::MasqueradeRejected(_) => String::from(
+                "acting as a ghost user was rejected, is the user in the namespace of the registration?",
+            ),
+            VerifyError::UserIdIgnored => String::from(
+                "user_id param ignored, is the registration enabled on the homeserver?",
+            ),
+            VerifyError::InvalidUserId(_) => {
+                String::from("sender_localpart doesn't give a valid user ID")
+            }
+            VerifyError::Intent(_) => String::from("a request to the homeserver failed"),
+        }
+    }
+}
+
+impl ApplicationService {
+    /// Check that the homeserver accepts the application service with the given `registration`
+    /// using `client`, which has the `as_token` of the registration.
+    ///
+    /// The bot user is checked using `/account/whoami`, after which `ghost`, a user in the user
+    /// namespace of the registration, is registered if needed and acted as. This should be
+    /// called before the application service starts serving, to fail early with a clear
+    /// `VerifyError::diagnostic`.
+    pub async fn verify<C: HttpClient + Clone>(
+        &self,
+        client: &Client<C>,
+        registration: &Registration,
+        ghost: &UserId,
+    ) -> Result<(), VerifyError<C::Error>> {
+        let bot = UserId::try_from(format!(
+            "@{}:{}",
+            registration.sender_localpart, self.server_name
+        ))
+        .map_err(VerifyError::InvalidUserId)?;
+
+        let response = client
+            .send_request(whoami::Request::new())
+            .await
+            .map_err(|err| match IntentError::from(err) {
+                err if is_token_error(&err) => VerifyError::TokenRejected(err),
+                err => VerifyError::Intent(err),
+            })?;
+        if response.user_id != bot {
+            return Err(VerifyError::UnexpectedUser(response.user_id));
+        }
+
+        if !registration.namespaces.users.iter().any(|ns| ns.exclusive) {
+            return Err(VerifyError::NamespaceNotExclusive);
+        }
+
+        let intent = Intent::new(client.clone(), ghost.clone());
+        let masquerade_error = |err: IntentError<C::Error>| match err.kind() {
+            Some(ErrorKind::Forbidden)
+            | Some(ErrorKind::Exclusive)
+            | Some(ErrorKind::InvalidUsername) => VerifyError::MasqueradeRejected(err),
+            _ => VerifyError::Intent(err),
+        };
+        intent.ensure_registered().await.map_err(masquerade_error)?;
+        let response = intent
+            .send(whoami::Request::new())
+            .await
+            .map_err(masquerade_error)?;
+        if &response.user_id != ghost {
+            return Err(VerifyError::UserIdIgnored);
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether `err` means the access token was rejected.
+fn is_token_error<E>(err: &IntentError<E>) -> bool {
+    matches!(
+        err.kind(),
+        Some(ErrorKind::UnknownToken { .. })
+            | Some(ErrorKind::MissingToken)
+            | Some(ErrorKind::Forbidden)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{ServerName, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{
+        ApplicationService, Namespace, Namespaces, Registration, RegistrationInit, VerifyError,
+    };
+
+    fn registration(exclusive: bool) -> Registration {
+        let mut namespaces = Namespaces::new();
+        namespaces.users.push(Namespace::new(
+            exclusive,
+            String::from("@_ext_.*:example.org"),
+        ));
+        Registration::from(RegistrationInit {
+            id: String::from("bridge"),
+            as_token: String::from("as_token"),
+            hs_token: String::from("hs_token"),
+            namespaces,
+            url: String::from("http://localhost:8080"),
+            sender_localpart: String::from("bridgebot"),
+            rate_limited: None,
+            protocols: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let (client, state) = mock_client();
+        let appservice = ApplicationService::new(
+            <Box<ServerName>>::try_from("example.org").unwrap(),
+            "http://localhost:8008".parse().unwrap(),
+        );
+        let ghost = UserId::try_from("@_ext_verify:example.org").unwrap();
+
+        state.respond(
+            "/register",
+            200,
+            json!({ "user_id": "@_ext_verify:example.org" }),
+        );
+        state.respond(
+            "/whoami",
+            200,
+            json!({ "user_id": "@bridgebot:example.org" }),
+        );
+        state.respond_once(
+            "/whoami",
+            200,
+            json!({ "user_id": "@bridgebot:example.org" }),
+        );
+        state.respond_once(
+            "/whoami",
+            200,
+            json!({ "user_id": "@_ext_verify:example.org" }),
+        );
+        appservice
+            .verify(&client, &registration(true), &ghost)
+            .await
+            .unwrap();
+        let requests = state.requests_to("/whoami");
+        assert!(!requests[0].path.contains("user_id="));
+        assert!(requests[1]
+            .path
+            .contains("user_id=@_ext_verify:example.org"));
+
+        let err = appservice
+            .verify(&client, &registration(false), &ghost)
+            .await;
+        assert!(matches!(err, Err(VerifyError::NamespaceNotExclusive)));
+
+        let err = appservice
+            .verify(&client, &registration(true), &ghost)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, VerifyError::UserIdIgnored));
+        assert!(err.diagnostic().contains("user_id param ignored"));
+
+        state.respond_once(
+            "/whoami",
+            401,
+            json!({ "errcode": "M_UNKNOWN_TOKEN", "error": "Unrecognised access token" }),
+        );
+        let err = appservice
+            .verify(&client, &registration(true), &ghost)
+            .await;
+        assert!(matches!(err, Err(VerifyError::TokenRejected(_))));
+    }
+}
@@ -0,0 +1,299 @@
+use std::time::Duration;
+
+use ruma::events::room::message::{
+    AudioInfo, AudioMessageEventContent, FileInfo, FileMessageEventContent,
+    ImageMessageEventContent, MessageEventContent, MessageType, VideoInfo,
+    VideoMessageEventContent,
+};
+use ruma::events::room::{ImageInfo, ThumbnailInfo};
+use ruma::identifiers::MxcUri;
+use ruma::UInt;
+use ruma_client::HttpClient;
+
+use crate::intent::{Intent, IntentError};
+
+/// The kind of message an `Attachment` is sent as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachmentKind {
+    /// An `m.file` message.
+    File,
+    /// An `m.image` message.
+    Image,
+    /// An `m.audio` message.
+    Audio,
+    /// An `m.video` message.
+    Video,
+}
+
+/// A builder for the content of a message with a file, image, audio or video attachment,
+/// including the `info` block with the metadata of the media.
+///
+/// The media itself is uploaded using `upload`, which also sets the size, or already uploaded
+/// media is used with `build`. Metadata that doesn't apply to the kind of attachment, like the
+/// duration of an image, is left out.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    kind: AttachmentKind,
+    body: String,
+    mimetype: Option<String>,
+    size: Option<UInt>,
+    width: Option<UInt>,
+    height: Option<UInt>,
+    duration: Option<Duration>,
+    thumbnail: Option<(MxcUri, ThumbnailInfo)>,
+}
+
+impl Attachment {
+    /// Create a new `Attachment` of the given `kind`, with `body` as its file name or
+    /// description.
+    pub fn new(kind: AttachmentKind, body: &str) -> Self {
+        Self {
+            kind,
+            body: body.to_string(),
+            mimetype: None,
+            size: None,
+            width: None,
+            height: None,
+            duration: None,
+            thumbnail: None,
+        }
+    }
+
+    /// Create a new `m.file` `Attachment` with the given `filename`.
+    pub fn file(filename: &str) -> Self {
+        Self::new(AttachmentKind::File, filename)
+    }
+
+    /// Create a new `m.image` `Attachment` with the given `body`.
+    pub fn image(body: &str) -> Self {
+        Self::new(AttachmentKind::Image, body)
+    }
+
+    /// Create a new `m.audio` `Attachment` with the given `body`.
+    pub fn audio(body: &str) -> Self {
+        Self::new(AttachmentKind::Audio, body)
+    }
+
+    /// Create a new `m.video` `Attachment` with the given `body`.
+    pub fn video(body: &str) -> Self {
+        Self::new(AttachmentKind::Video, body)
+    }
+
+    /// Get the kind of this attachment.
+    pub fn kind(&self) -> AttachmentKind {
+        self.kind
+    }
+
+    /// Set the MIME type of the media, returning the current `Attachment` to allow method
+    /// chaining.
+    pub fn mimetype(&mut self, mimetype: &str) -> &mut Self {
+        self.mimetype = Some(mimetype.to_string());
+        self
+    }
+
+    /// Set the size of the media in bytes, returning the current `Attachment` to allow method
+    /// chaining.
+    pub fn size(&mut self, size: u64) -> &mut Self {
+        self.size = UInt::new(size);
+        self
+    }
+
+    /// Set the width and height in pixels of an image or video, returning the current
+    /// `Attachment` to allow method chaining.
+    pub fn dimensions(&mut self, width: u32, height: u32) -> &mut Self {
+        self.width = Some(UInt::from(width));
+        self.height = Some(UInt::from(height));
+        self
+    }
+
+    /// Set the duration of audio or a video, returning the current `Attachment` to allow method
+    /// chaining.
+    pub fn duration(&mut self, duration: Duration) -> &mut Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// Set the uploaded thumbnail at `url` with the given `info` of a file, image or video,
+    /// returning the current `Attachment` to allow method chaining.
+    pub fn thumbnail(&mut self, url: MxcUri, info: ThumbnailInfo) -> &mut Self {
+        self.thumbnail = Some((url, info));
+        self
+    }
+
+    /// Upload the thumbnail `data` with the given `mimetype` and dimensions as `intent`, and set
+    /// it as the thumbnail of this attachment.
+    pub async fn upload_thumbnail<C: HttpClient>(
+        &mut self,
+        intent: &Intent<C>,
+        data: &[u8],
+        mimetype: &str,
+        width: u32,
+        height: u32,
+    ) -> Result<&mut Self, IntentError<C::Error>> {
+        let url = intent.upload(data, mimetype, None).await?;
+        let mut info = ThumbnailInfo::new();
+        info.mimetype = Some(mimetype.to_string());
+        info.size = UInt::new(data.len() as u64);
+        info.width = Some(UInt::from(width));
+        info.height = Some(UInt::from(height));
+        Ok(self.thumbnail(url, info))
+    }
+
+    /// Upload the media `data` as `intent`, and build the content of the message with the
+    /// uploaded media, setting its size.
+    ///
+    /// Media without a MIME type is uploaded as `application/octet-stream`.
+    pub async fn upload<C: HttpClient>(
+        &mut self,
+        intent: &Intent<C>,
+        data: &[u8],
+    ) -> Result<MessageEventContent, IntentError<C::Error>> {
+        let mimetype = self
+            .mimetype
+            .as_deref()
+            .unwrap_or("application/octet-stream");
+        let url = intent.upload(data, mimetype, Some(&self.body)).await?;
+        self.size(data.len() as u64);
+        Ok(self.build(url))
+    }
+
+    fn duration_ms(&self) -> Option<UInt> {
+        self.duration
+            .and_then(|duration| UInt::new(duration.as_millis() as u64))
+    }
+
+    fn thumbnail_parts(&self) -> (Option<MxcUri>, Option<Box<ThumbnailInfo>>) {
+        match &self.thumbnail {
+            Some((url, info)) => (Some(url.clone()), Some(Box::new(info.clone()))),
+            None => (None, None),
+        }
+    }
+
+    /// Build the content of the message with the media uploaded at `url`.
+    pub fn build(&self, url: MxcUri) -> MessageEventContent {
+        let (thumbnail_url, thumbnail_info) = self.thumbnail_parts();
+        let body = self.body.clone();
+
+        let msgtype = match self.kind {
+            AttachmentKind::File => {
+                let mut info = FileInfo::new();
+                info.mimetype = self.mimetype.clone();
+                info.size = self.size;
+                info.thumbnail_url = thumbnail_url;
+                info.thumbnail_info = thumbnail_info;
+                let mut content = FileMessageEventContent::plain(body, url, Some(Box::new(info)));
+                content.filename = Some(self.body.clone());
+                MessageType::File(content)
+            }
+            AttachmentKind::Image => {
+                let mut info = ImageInfo::new();
+                info.mimetype = self.mimetype.clone();
+                info.size = self.size;
+                info.width = self.width;
+                info.height = self.height;
+                info.thumbnail_url = thumbnail_url;
+                info.thumbnail_info = thumbnail_info;
+                let content = ImageMessageEventContent::plain(body, url, Some(Box::new(info)));
+                MessageType::Image(content)
+            }
+            AttachmentKind::Audio => {
+                let mut info = AudioInfo::new();
+                info.mimetype = self.mimetype.clone();
+                info.size = self.size;
+                info.duration = self.duration_ms();
+                let content = AudioMessageEventContent::plain(body, url, Some(Box::new(info)));
+                MessageType::Audio(content)
+            }
+            AttachmentKind::Video => {
+                let mut info = VideoInfo::new();
+                info.mimetype = self.mimetype.clone();
+                info.size = self.size;
+                info.width = self.width;
+                info.height = self.height;
+                info.duration = self.duration_ms();
+                info.thumbnail_url = thumbnail_url;
+                info.thumbnail_info = thumbnail_info;
+                let content = VideoMessageEventContent::plain(body, url, Some(Box::new(info)));
+                MessageType::Video(content)
+            }
+        };
+        MessageEventContent::new(msgtype)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    use ruma::identifiers::{MxcUri, UserId};
+    use serde_json::{json, to_value};
+
+    use crate::testing::mock_client;
+    use crate::{Attachment, Intent};
+
+    #[test]
+    fn test_build() {
+        let content = Attachment::audio("voice.ogg")
+            .mimetype("audio/ogg")
+            .size(1234)
+            .dimensions(10, 10)
+            .duration(Duration::from_secs(3))
+            .build(MxcUri::from("mxc://example.org/voice"));
+        assert_eq!(
+            to_value(&content).unwrap(),
+            json!({
+                "msgtype": "m.audio",
+                "body": "voice.ogg",
+                "url": "mxc://example.org/voice",
+                "info": { "mimetype": "audio/ogg", "size": 1234, "duration": 3000 },
+            })
+        );
+
+        let content = Attachment::file("report.pdf").build(MxcUri::from("mxc://example.org/pdf"));
+        assert_eq!(to_value(&content).unwrap()["filename"], "report.pdf");
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let (client, state) = mock_client();
+        let ghost = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+
+        state.respond_once(
+            "/upload",
+            200,
+            json!({ "content_uri": "mxc://example.org/thumb" }),
+        );
+        state.respond_once(
+            "/upload",
+            200,
+            json!({ "content_uri": "mxc://example.org/video" }),
+        );
+        let mut attachment = Attachment::video("clip.mp4");
+        attachment.mimetype("video/mp4").dimensions(640, 480);
+        attachment
+            .upload_thumbnail(&ghost, b"thumb", "image/jpeg", 64, 48)
+            .await
+            .unwrap();
+        let content = attachment.upload(&ghost, b"video data").await.unwrap();
+
+        assert_eq!(
+            to_value(&content).unwrap(),
+            json!({
+                "msgtype": "m.video",
+                "body": "clip.mp4",
+                "url": "mxc://example.org/video",
+                "info": {
+                    "mimetype": "video/mp4",
+                    "size": 10,
+                    "w": 640,
+                    "h": 480,
+                    "thumbnail_url": "mxc://example.org/thumb",
+                    "thumbnail_info": { "mimetype": "image/jpeg", "size": 5, "w": 64, "h": 48 },
+                },
+            })
+        );
+        let uploads = state.requests_to("/upload");
+        assert!(uploads[1].path.contains("filename=clip.mp4"));
+    }
+}
@@ -0,0 +1,261 @@
+use std::sync::Mutex;
+
+use ruma::identifiers::{MxcUri, UserId};
+use ruma_client::HttpClient;
+use serde::{Deserialize, Serialize};
+
+use crate::intent::Intent;
+use crate::mappingdict::{Mappable, MappingDict, MappingId};
+use crate::mediacache::{MediaCacheError, MediaDownloader};
+use crate::store::MappingStore;
+use crate::util::sha512_hex;
+
+/// The avatar last set for a virtual user from a remote URL.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AvatarState {
+    user_id: UserId,
+    url: String,
+    etag: Option<String>,
+    hash: String,
+    mxc_uri: MxcUri,
+}
+
+impl AvatarState {
+    /// Get the ID of the user with this avatar.
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
+
+    /// Get the remote URL the avatar was downloaded from.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Get the entity tag of the downloaded version of the avatar, if the server sent one.
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
+    /// Get the SHA-512 hash of the content of the avatar, as a lowercase hex string.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Get the MXC URI of the uploaded avatar.
+    pub fn mxc_uri(&self) -> &MxcUri {
+        &self.mxc_uri
+    }
+}
+
+/// Avatars are kept by user ID only, so both sides of the mapping are the user ID.
+impl Mappable for AvatarState {
+    type MatrixReference = UserId;
+    type MatrixType = UserId;
+    type ExternalReference = UserId;
+    type ExternalType = UserId;
+
+    fn as_matrix(&self) -> &UserId {
+        &self.user_id
+    }
+    fn into_matrix(self) -> UserId {
+        self.user_id
+    }
+    fn as_external(&self) -> &UserId {
+        &self.user_id
+    }
+    fn into_external(self) -> UserId {
+        self.user_id
+    }
+    fn into_split(self) -> (UserId, UserId) {
+        (self.user_id.clone(), self.user_id)
+    }
+}
+
+/// Keeps the avatars of virtual users in sync with the avatars of the users on the external
+/// service.
+///
+/// The URL, entity tag and content hash of the avatar last set for a user are kept in a
+/// `MappingStore`, so an unchanged avatar is neither downloaded again, if the server supports
+/// entity tags, nor uploaded again.
+pub struct AvatarSync<D, S = Mutex<MappingDict<AvatarState>>> {
+    downloader: D,
+    store: S,
+    max_size: Option<u64>,
+}
+
+impl<D, S> AvatarSync<D, S>
+where
+    D: MediaDownloader,
+    S: MappingStore<AvatarState>,
+{
+    /// Create a new `AvatarSync` downloading avatars using `downloader` and keeping their state
+    /// in `store`.
+    pub fn new(downloader: D, store: S) -> Self {
+        Self {
+            downloader,
+            store,
+            max_size: None,
+        }
+    }
+
+    /// Get the store containing the state of the avatars.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Set the maximum size in bytes of avatars that are uploaded, or `None` to allow any size.
+    pub fn set_max_size(&mut self, max_size: Option<u64>) {
+        self.max_size = max_size;
+    }
+
+    /// Get the state of the avatar last set for the user with the given `user_id`, if any.
+    pub async fn get(&self, user_id: &UserId) -> Result<Option<AvatarState>, S::Error> {
+        self.store.get(MappingId::Matrix(user_id)).await
+    }
+
+    /// Make the avatar at the remote `url` the avatar of the user of `intent`.
+    ///
+    /// The avatar is only uploaded and set if its content differs from the avatar last set
+    /// using this `AvatarSync`, and only downloaded if the server doesn't report it as unchanged
+    /// since then.
+    ///
+    /// Returns the MXC URI of the avatar.
+    pub async fn ensure_avatar_from_url<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+        url: &str,
+    ) -> Result<MxcUri, MediaCacheError<C::Error, S::Error, D::Error>> {
+        let current = self
+            .get(intent.user_id())
+            .await
+            .map_err(MediaCacheError::Store)?;
+        let etag = current
+            .as_ref()
+            .filter(|current| current.url == url)
+            .and_then(|current| current.etag.as_deref());
+
+        let download = self
+            .downloader
+            .download_if_changed(url, etag)
+            .await
+            .map_err(MediaCacheError::Download)?;
+        let download = match download {
+            Some(download) => download,
+            None => match current {
+                Some(current) => return Ok(current.mxc_uri),
+                None => unreachable!("media without etag should be downloaded"),
+            },
+        };
+        let size = download.data.len() as u64;
+        if matches!(self.max_size, Some(max_size) if size > max_size) {
+            return Err(MediaCacheError::TooLarge(size));
+        }
+
+        let hash = sha512_hex(&download.data);
+        let mxc_uri = match current {
+            Some(current) if current.hash == hash => current.mxc_uri,
+            _ => {
+                let content_type = download
+                    .content_type
+                    .as_deref()
+                    .unwrap_or("application/octet-stream");
+                let mxc_uri = intent.upload(&download.data, content_type, None).await?;
+                intent.set_avatar_url(Some(&mxc_uri)).await?;
+                mxc_uri
+            }
+        };
+
+        let state = AvatarState {
+            user_id: intent.user_id().clone(),
+            url: url.to_string(),
+            etag: download.etag,
+            hash,
+            mxc_uri: mxc_uri.clone(),
+        };
+        self.store
+            .insert(state)
+            .await
+            .map_err(MediaCacheError::Store)?;
+        Ok(mxc_uri)
+    }
+
+    /// Remove the avatar of the user of `intent`, and forget its state.
+    pub async fn clear_avatar<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+    ) -> Result<(), MediaCacheError<C::Error, S::Error, D::Error>> {
+        intent.set_avatar_url(None).await?;
+        self.store
+            .remove(MappingId::Matrix(intent.user_id()))
+            .await
+            .map_err(MediaCacheError::Store)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::UserId;
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{AvatarSync, HttpDownloader, Intent, MediaCacheError};
+
+    #[tokio::test]
+    async fn test_avatar_sync() {
+        let (client, state) = mock_client();
+        let ghost = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        let downloader = HttpDownloader::new(state.http_client());
+        let mut avatars: AvatarSync<_> = AvatarSync::new(downloader, Default::default());
+        let url = "https://example.com/avatars/bob.png";
+
+        state.respond("/avatars/bob.png", 200, json!({ "avatar": 1 }));
+        state.respond(
+            "/upload",
+            200,
+            json!({ "content_uri": "mxc://example.org/bob" }),
+        );
+        let mxc_uri = avatars.ensure_avatar_from_url(&ghost, url).await.unwrap();
+        assert_eq!(mxc_uri.as_str(), "mxc://example.org/bob");
+        let profile = state.requests_to("/profile/@_ext_bob:example.org/avatar_url");
+        assert_eq!(profile[0].body["avatar_url"], "mxc://example.org/bob");
+
+        // The same content from another URL isn't uploaded again.
+        state.respond("/avatars/bob2.png", 200, json!({ "avatar": 1 }));
+        avatars
+            .ensure_avatar_from_url(&ghost, "https://example.com/avatars/bob2.png")
+            .await
+            .unwrap();
+        assert_eq!(state.requests_to("/upload").len(), 1);
+        let saved = avatars.get(ghost.user_id()).await.unwrap().unwrap();
+        assert_eq!(saved.url(), "https://example.com/avatars/bob2.png");
+
+        state.respond_once("/avatars/bob2.png", 200, json!({ "avatar": 2 }));
+        state.respond_once(
+            "/upload",
+            200,
+            json!({ "content_uri": "mxc://example.org/bob2" }),
+        );
+        let mxc_uri = avatars
+            .ensure_avatar_from_url(&ghost, "https://example.com/avatars/bob2.png")
+            .await
+            .unwrap();
+        assert_eq!(mxc_uri.as_str(), "mxc://example.org/bob2");
+        assert_eq!(state.requests_to("/upload").len(), 2);
+        assert_eq!(state.requests_to("/avatar_url").len(), 2);
+
+        avatars.set_max_size(Some(4));
+        state.respond("/avatars/large.png", 200, json!({ "avatar": 3 }));
+        let err = avatars
+            .ensure_avatar_from_url(&ghost, "https://example.com/avatars/large.png")
+            .await;
+        assert!(matches!(err, Err(MediaCacheError::TooLarge(12))));
+
+        avatars.clear_avatar(&ghost).await.unwrap();
+        assert!(avatars.get(ghost.user_id()).await.unwrap().is_none());
+        let profile = state.requests_to("/avatar_url");
+        assert_eq!(profile.len(), 3);
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::BTreeSet;
+
+use ruma::api::client::error::ErrorKind;
+use ruma::events::{AnyMessageEventContent, EventContent};
+use ruma::identifiers::{EventId, RoomId, UserId};
+use ruma::MilliSecondsSinceUnixEpoch;
+use ruma_client::HttpClient;
+use serde_json::json;
+
+use crate::api::batch_send::{self, BatchEvent};
+use crate::intent::{Intent, IntentError};
+use crate::messages::BridgedMessage;
+
+/// A message from the history of the external service, to be inserted using `Backfill`.
+#[derive(Debug, Clone)]
+pub struct HistoricalMessage {
+    /// The ID of the message on the external service.
+    pub external_id: String,
+    /// The ghost user that sent the message.
+    pub sender: UserId,
+    /// The time the message was sent on the external service.
+    pub timestamp: MilliSecondsSinceUnixEpoch,
+    /// The content of the message.
+    pub content: AnyMessageEventContent,
+}
+
+/// How a `Backfill` inserts messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackfillMode {
+    /// Use `/batch_send` from MSC2716, falling back to `Massaged` if the homeserver doesn't
+    /// support it.
+    Auto,
+    /// Use `/batch_send` from MSC2716, inserting the messages before a given event.
+    BatchSend,
+    /// Send the messages one by one with their original timestamps, appending them to the room.
+    Massaged,
+}
+
+/// Imports history of the external service into a portal room.
+///
+/// With `/batch_send`, the messages are inserted in batches before the event given using
+/// `before`, and the memberships of the senders are added as state of every batch, so the
+/// senders don't have to be in the room. Otherwise the messages are sent as their senders, who
+/// have to be in the room already, with timestamp massaging.
+pub struct Backfill<'a, C: HttpClient> {
+    bot: &'a Intent<C>,
+    room_id: &'a RoomId,
+    before: Option<EventId>,
+    mode: BackfillMode,
+    batch_size: usize,
+}
+
+impl<'a, C: HttpClient + Clone> Backfill<'a, C> {
+    /// Create a new `Backfill` importing history into the room with the given `room_id` as
+    /// `bot`, sending 100 messages per batch.
+    pub fn new(bot: &'a Intent<C>, room_id: &'a RoomId) -> Self {
+        Self {
+            bot,
+            room_id,
+            before: None,
+            mode: BackfillMode::Auto,
+            batch_size: 100,
+        }
+    }
+
+    /// Insert the messages before the event with the given `event_id` when using `/batch_send`,
+    /// usually the first event of the portal, returning the current `Backfill` to allow method
+    /// chaining.
+    ///
+    /// Without this, messages are always sent with timestamp massaging.
+    pub fn before(&mut self, event_id: EventId) -> &mut Self {
+        self.before = Some(event_id);
+        self
+    }
+
+    /// Set how the messages are inserted, returning the current `Backfill` to allow method
+    /// chaining.
+    pub fn mode(&mut self, mode: BackfillMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Set the amount of messages per `/batch_send` request, returning the current `Backfill` to
+    /// allow method chaining.
+    pub fn batch_size(&mut self, batch_size: usize) -> &mut Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Import the given `messages`, which should be in chronological order.
+    ///
+    /// Returns the bridged messages, in the order of `messages`, to be stored in a message store.
+    pub async fn run(
+        &self,
+        messages: &[HistoricalMessage],
+    ) -> Result<Vec<BridgedMessage>, IntentError<C::Error>> {
+        let before = match (&self.before, self.mode) {
+            (Some(before), BackfillMode::Auto) | (Some(before), BackfillMode::BatchSend) => before,
+            _ => return self.send_massaged(messages).await,
+        };
+
+        match self.batch_send(before, messages).await {
+            Err(err) if self.mode == BackfillMode::Auto && is_unsupported(&err) => {
+                self.send_massaged(messages).await
+            }
+            result => result,
+        }
+    }
+
+    async fn batch_send(
+        &self,
+        before: &EventId,
+        messages: &[HistoricalMessage],
+    ) -> Result<Vec<BridgedMessage>, IntentError<C::Error>> {
+        let mut event_ids = vec![];
+        let mut batch_id = None;
+
+        // every batch is inserted before the previous one, so start with the newest.
+        for batch in messages.rchunks(self.batch_size) {
+            let mut request = batch_send::Request::new(
+                self.room_id.clone(),
+                before.clone(),
+                batch.iter().map(batch_event).collect(),
+            );
+            request.batch_id = batch_id.take();
+            request.state_events_at_start = member_events(batch);
+
+            let response = self.bot.send(request).await?;
+            batch_id = Some(response.next_batch_id);
+            event_ids.push(response.event_ids);
+        }
+
+        let event_ids = event_ids.into_iter().rev().flatten();
+        Ok(messages
+            .iter()
+            .zip(event_ids)
+            .map(|(message, event_id)| self.bridged(message, event_id))
+            .collect())
+    }
+
+    async fn send_massaged(
+        &self,
+        messages: &[HistoricalMessage],
+    ) -> Result<Vec<BridgedMessage>, IntentError<C::Error>> {
+        let mut bridged = Vec::with_capacity(messages.len());
+        for message in messages {
+            let intent = Intent::new(self.bot.client().clone(), message.sender.clone())
+                .with_timestamp(Some(message.timestamp))
+                .with_appservice_client(self.bot.appservice_client().cloned());
+            let event_id = intent.send_message(self.room_id, &message.content).await?;
+            bridged.push(self.bridged(message, event_id));
+        }
+        Ok(bridged)
+    }
+
+    fn bridged(&self, message: &HistoricalMessage, event_id: EventId) -> BridgedMessage {
+        BridgedMessage::new(event_id, self.room_id.clone(), message.external_id.clone())
+    }
+}
+
+fn batch_event(message: &HistoricalMessage) -> BatchEvent {
+    BatchEvent {
+        event_type: message.content.event_type().to_string(),
+        sender: message.sender.clone(),
+        origin_server_ts: message.timestamp,
+        content: serde_json::to_value(&message.content).expect("message should serialize"),
+        state_key: None,
+    }
+}
+
+/// Get join events of the senders of `messages`, at the time of the first message.
+fn member_events(messages: &[HistoricalMessage]) -> Vec<BatchEvent> {
+    let timestamp = match messages.first() {
+        Some(message) => message.timestamp,
+        None => return vec![],
+    };
+    let senders: BTreeSet<&UserId> = messages.iter().map(|message| &message.sender).collect();
+    senders
+        .into_iter()
+        .map(|sender| BatchEvent {
+            event_type: String::from("m.room.member"),
+            sender: sender.clone(),
+            origin_server_ts: timestamp,
+            content: json!({ "membership": "join" }),
+            state_key: Some(sender.to_string()),
+        })
+        .collect()
+}
+
+/// Returns whether `err` means the homeserver doesn't support `/batch_send`.
+fn is_unsupported<E>(err: &IntentError<E>) -> bool {
+    matches!(
+        err.kind(),
+        Some(ErrorKind::Unrecognized) | Some(ErrorKind::NotFound)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::events::room::message::MessageEventContent;
+    use ruma::events::AnyMessageEventContent;
+    use ruma::identifiers::{EventId, RoomId, UserId};
+    use ruma::{MilliSecondsSinceUnixEpoch, UInt};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{Backfill, BackfillMode, HistoricalMessage, Intent};
+
+    fn messages() -> Vec<HistoricalMessage> {
+        (0..3u32)
+            .map(|i| HistoricalMessage {
+                external_id: i.to_string(),
+                sender: UserId::try_from(format!("@_ext_{}:example.org", i % 2)).unwrap(),
+                timestamp: MilliSecondsSinceUnixEpoch(UInt::from(1000 + i)),
+                content: AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(
+                    i.to_string(),
+                )),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_batch_send() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        let path = "/batch_send";
+        state.respond_once(
+            path,
+            200,
+            json!({ "event_ids": ["$1:example.org", "$2:example.org"], "next_batch_id": "b1" }),
+        );
+        state.respond_once(
+            path,
+            200,
+            json!({ "event_ids": ["$0:example.org"], "next_batch_id": "b2" }),
+        );
+        let bridged = Backfill::new(&bot, &room_id)
+            .before(EventId::try_from("$first:example.org").unwrap())
+            .batch_size(2)
+            .run(&messages())
+            .await
+            .unwrap();
+
+        let ids: Vec<_> = bridged
+            .iter()
+            .map(|message| (message.external_id(), message.event_id().as_str()))
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                ("0", "$0:example.org"),
+                ("1", "$1:example.org"),
+                ("2", "$2:example.org"),
+            ]
+        );
+
+        let requests = state.requests_to(path);
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].path.starts_with(
+            "/_matrix/client/unstable/org.matrix.msc2716/rooms/!room:example.org/batch_send\
+             ?prev_event_id=$first:example.org&user_id=@bot:example.org"
+        ));
+        assert!(requests[1].path.contains("&batch_id=b1"));
+        assert_eq!(requests[0].body["events"][0]["content"]["body"], "1");
+        assert_eq!(requests[0].body["events"][0]["origin_server_ts"], 1001);
+        assert_eq!(
+            requests[0].body["state_events_at_start"],
+            json!([
+                {
+                    "type": "m.room.member",
+                    "sender": "@_ext_0:example.org",
+                    "origin_server_ts": 1001,
+                    "content": { "membership": "join" },
+                    "state_key": "@_ext_0:example.org",
+                },
+                {
+                    "type": "m.room.member",
+                    "sender": "@_ext_1:example.org",
+                    "origin_server_ts": 1001,
+                    "content": { "membership": "join" },
+                    "state_key": "@_ext_1:example.org",
+                },
+            ])
+        );
+        assert_eq!(requests[1].body["events"][0]["content"]["body"], "0");
+    }
+
+    #[tokio::test]
+    async fn test_massaged_fallback() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        state.respond(
+            "/batch_send",
+            404,
+            json!({ "errcode": "M_UNRECOGNIZED", "error": "Unrecognized request" }),
+        );
+        state.respond("/send/", 200, json!({ "event_id": "$sent:example.org" }));
+        let bridged = Backfill::new(&bot, &room_id)
+            .before(EventId::try_from("$first:example.org").unwrap())
+            .run(&messages())
+            .await
+            .unwrap();
+        assert_eq!(bridged.len(), 3);
+
+        let requests = state.requests_to("/send/");
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].path.contains("user_id=@_ext_1:example.org"));
+        assert!(requests[1].path.contains("ts=1001"));
+        assert_eq!(requests[1].body["body"], "1");
+
+        let err = Backfill::new(&bot, &room_id)
+            .before(EventId::try_from("$first:example.org").unwrap())
+            .mode(BackfillMode::BatchSend)
+            .run(&messages())
+            .await;
+        assert!(err.is_err());
+    }
+}
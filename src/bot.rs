@@ -0,0 +1,208 @@
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use ruma::events::room::message::MessageEventContent;
+use ruma::events::AnyMessageEventContent;
+use ruma::identifiers::{EventId, MxcUri, RoomId, ServerName, UserId};
+use ruma_client::{Client, HttpClient};
+
+use crate::appservice::Registration;
+use crate::client::AppserviceClient;
+use crate::commands::CommandProcessor;
+use crate::intent::{Intent, IntentError};
+use crate::portal::{Portal, PortalManager};
+use crate::store::MappingStore;
+
+/// The bot user of an application service, the user with the `sender_localpart` of the
+/// registration.
+///
+/// The bot is the default actor of a bridge: it creates the portals and replies to commands in
+/// the admin room. Its profile is set from the configuration of the bridge on `start`.
+#[derive(Debug, Clone)]
+pub struct BridgeBot<C> {
+    intent: Intent<C>,
+    display_name: Option<String>,
+    avatar_url: Option<MxcUri>,
+}
+
+impl<C: HttpClient> BridgeBot<C> {
+    /// Create a new `BridgeBot` acting as the user with the `sender_localpart` of `registration`
+    /// on the homeserver `server_name`, using the given `client` of the application service.
+    pub fn new(
+        client: Client<C>,
+        registration: &Registration,
+        server_name: &ServerName,
+    ) -> Result<Self, ruma::identifiers::Error> {
+        let user_id = UserId::try_from(format!(
+            "@{}:{}",
+            registration.sender_localpart, server_name
+        ))?;
+        Ok(Self::with_user_id(client, user_id))
+    }
+
+    /// Create a new `BridgeBot` acting as `user_id`, using the given `client` of the application
+    /// service.
+    pub fn with_user_id(client: Client<C>, user_id: UserId) -> Self {
+        Self {
+            intent: Intent::new(client, user_id),
+            display_name: None,
+            avatar_url: None,
+        }
+    }
+
+    /// Set the display name the bot gets on `start`, returning the current `BridgeBot` to allow
+    /// method chaining.
+    pub fn display_name(&mut self, display_name: &str) -> &mut Self {
+        self.display_name = Some(display_name.to_string());
+        self
+    }
+
+    /// Set the avatar the bot gets on `start`, returning the current `BridgeBot` to allow method
+    /// chaining.
+    pub fn avatar_url(&mut self, avatar_url: MxcUri) -> &mut Self {
+        self.avatar_url = Some(avatar_url);
+        self
+    }
+
+    /// Send the requests of the bot with the settings of the given `AppserviceClient`, or without
+    /// them if it's `None`.
+    pub fn set_appservice_client(&mut self, client: Option<Arc<AppserviceClient<C>>>) {
+        self.intent.set_appservice_client(client);
+    }
+
+    /// Get the `Intent` acting as the bot.
+    pub fn intent(&self) -> &Intent<C> {
+        &self.intent
+    }
+
+    /// Get the ID of the bot user.
+    pub fn user_id(&self) -> &UserId {
+        self.intent.user_id()
+    }
+
+    /// Make sure the bot user is registered, and set its display name and avatar if they're
+    /// configured.
+    ///
+    /// This should be called when the bridge starts, before the bot is used.
+    pub async fn start(&self) -> Result<(), IntentError<C::Error>> {
+        self.intent.ensure_registered().await?;
+        if let Some(display_name) = &self.display_name {
+            self.intent.set_display_name(Some(display_name)).await?;
+        }
+        if let Some(avatar_url) = &self.avatar_url {
+            self.intent.set_avatar_url(Some(avatar_url)).await?;
+        }
+        Ok(())
+    }
+
+    /// Send a text message with the given `body` to the room with the given `room_id`.
+    ///
+    /// Returns the ID of the sent event.
+    pub async fn send_text(
+        &self,
+        room_id: &RoomId,
+        body: &str,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let content = MessageEventContent::text_plain(body);
+        self.intent
+            .send_message(room_id, &AnyMessageEventContent::RoomMessage(content))
+            .await
+    }
+
+    /// Send a notice with the given `body` to the room with the given `room_id`.
+    ///
+    /// Returns the ID of the sent event.
+    pub async fn send_notice(
+        &self,
+        room_id: &RoomId,
+        body: &str,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let content = MessageEventContent::notice_plain(body);
+        self.intent
+            .send_message(room_id, &AnyMessageEventContent::RoomMessage(content))
+            .await
+    }
+}
+
+impl<C: HttpClient + Clone> BridgeBot<C> {
+    /// Create a `CommandProcessor` replying as the bot to commands starting with `prefix`.
+    pub fn command_processor(&self, prefix: &str) -> CommandProcessor<C> {
+        CommandProcessor::new(self.intent.clone(), prefix)
+    }
+
+    /// Create a `PortalManager` creating rooms as the bot and keeping the portals in `store`,
+    /// with aliases starting with `prefix` on the server of the bot.
+    pub fn portal_manager<S: MappingStore<Portal>>(
+        &self,
+        prefix: &str,
+        store: S,
+    ) -> PortalManager<C, S> {
+        let server_name = self.user_id().server_name().to_owned();
+        PortalManager::new(self.intent.clone(), server_name, prefix, store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{MxcUri, RoomId, ServerName};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{BridgeBot, Namespaces, PortalManager, Registration, RegistrationInit};
+
+    #[tokio::test]
+    async fn test_bridge_bot() {
+        let (client, state) = mock_client();
+        let registration = Registration::from(RegistrationInit {
+            id: String::from("bridge"),
+            as_token: String::from("as_token"),
+            hs_token: String::from("hs_token"),
+            namespaces: Namespaces::new(),
+            url: String::from("http://localhost:8080"),
+            sender_localpart: String::from("bridgebot"),
+            rate_limited: None,
+            protocols: None,
+        });
+        let server_name = <&ServerName>::try_from("example.org").unwrap();
+        let mut bot = BridgeBot::new(client, &registration, server_name).unwrap();
+        bot.display_name("Bridge bot")
+            .avatar_url(MxcUri::from("mxc://example.org/avatar"));
+        assert_eq!(bot.user_id().as_str(), "@bridgebot:example.org");
+
+        state.respond(
+            "/register",
+            200,
+            json!({ "user_id": "@bridgebot:example.org" }),
+        );
+        state.respond("/profile/", 200, json!({}));
+        bot.start().await.unwrap();
+        assert_eq!(
+            state.requests_to("/register")[0].body["username"],
+            "bridgebot"
+        );
+        assert_eq!(
+            state.requests_to("/displayname")[0].body["displayname"],
+            "Bridge bot"
+        );
+        assert_eq!(
+            state.requests_to("/avatar_url")[0].body["avatar_url"],
+            "mxc://example.org/avatar"
+        );
+
+        state.respond("/send/", 200, json!({ "event_id": "$notice:example.org" }));
+        let room_id = RoomId::try_from("!admin:example.org").unwrap();
+        bot.send_notice(&room_id, "Bridge started").await.unwrap();
+        let requests = state.requests_to("/send/m.room.message/");
+        assert_eq!(requests[0].body["msgtype"], "m.notice");
+        assert!(requests[0].path.contains("user_id=@bridgebot:example.org"));
+
+        let portals: PortalManager<_> = bot.portal_manager("_ext_", Default::default());
+        assert_eq!(portals.bot().user_id(), bot.user_id());
+        assert_eq!(
+            portals.alias_for("chan").unwrap().as_str(),
+            "#_ext_chan:example.org"
+        );
+    }
+}
@@ -0,0 +1,327 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ruma::api::exports::bytes::BufMut;
+use ruma::api::exports::http::{self, header, Method};
+use ruma::identifiers::{EventId, MxcUri, RoomId, UserId};
+use ruma_client::HttpClient;
+use serde::{Deserialize, Serialize};
+use serde_json::value::to_raw_value;
+use thiserror::Error;
+
+use crate::intent::{Intent, IntentError};
+
+/// A part of a `BridgeInfo`, describing the protocol, network or channel that is bridged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BridgeInfoSection {
+    /// An ID that is unique within the bridge.
+    pub id: String,
+    /// A human readable name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub displayname: Option<String>,
+    /// An avatar.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<MxcUri>,
+    /// A link to the protocol, network or channel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_url: Option<String>,
+}
+
+impl BridgeInfoSection {
+    /// Create a new `BridgeInfoSection` with the given `id` and no other information.
+    pub fn new(id: String) -> Self {
+        Self {
+            id,
+            displayname: None,
+            avatar_url: None,
+            external_url: None,
+        }
+    }
+}
+
+/// The content of the `m.bridge` state event of MSC2346, describing what a room is bridged to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BridgeInfo {
+    /// The bot user of the bridge.
+    pub bridgebot: UserId,
+    /// The user that set up the bridge, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creator: Option<UserId>,
+    /// The protocol that is bridged.
+    pub protocol: BridgeInfoSection,
+    /// The network of the protocol that is bridged, for protocols with multiple networks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<BridgeInfoSection>,
+    /// The channel that is bridged.
+    pub channel: BridgeInfoSection,
+}
+
+impl BridgeInfo {
+    /// The event types the bridge info is sent as, the MSC2346 type and the unstable type that is
+    /// still used by most clients.
+    pub const EVENT_TYPES: [&'static str; 2] = ["m.bridge", "uk.half-shot.bridge"];
+
+    /// Create a new `BridgeInfo` for `channel` of the given `protocol`.
+    pub fn new(bridgebot: UserId, protocol: BridgeInfoSection, channel: BridgeInfoSection) -> Self {
+        Self {
+            bridgebot,
+            creator: None,
+            protocol,
+            network: None,
+            channel,
+        }
+    }
+
+    /// Get the state key of the bridge info events, which is unique for the bridged channel.
+    pub fn state_key(&self) -> String {
+        let network = self.network.as_ref().map_or("", |network| &network.id);
+        format!("{}://{}/{}", self.protocol.id, network, self.channel.id)
+    }
+
+    /// Send this bridge info to the room with the given `room_id` as `intent`, using all of the
+    /// `BridgeInfo::EVENT_TYPES`.
+    ///
+    /// Returns the IDs of the sent events.
+    pub async fn publish<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+        room_id: &RoomId,
+    ) -> Result<Vec<EventId>, IntentError<C::Error>> {
+        let state_key = self.state_key();
+
+        let mut event_ids = Vec::with_capacity(Self::EVENT_TYPES.len());
+        for event_type in &Self::EVENT_TYPES {
+            let content = to_raw_value(self).expect("bridge info should serialize");
+            let event_id = intent
+                .send_state_raw(room_id, event_type, &state_key, content)
+                .await?;
+            event_ids.push(event_id);
+        }
+        Ok(event_ids)
+    }
+}
+
+/// The state of a bridge or of the connection of a user to the external service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum BridgeStateEvent {
+    /// The bridge is starting.
+    Starting,
+    /// The bridge is running, but not configured yet.
+    Unconfigured,
+    /// The bridge is running.
+    Running,
+    /// The homeserver can't reach the bridge.
+    BridgeUnreachable,
+    /// The bridge is connecting to the external service.
+    Connecting,
+    /// The bridge is connected and fetching history.
+    Backfilling,
+    /// The bridge is connected to the external service.
+    Connected,
+    /// The connection was lost, but will be retried.
+    TransientDisconnect,
+    /// The credentials of the user are invalid.
+    BadCredentials,
+    /// An unknown error occurred.
+    UnknownError,
+    /// The user logged out.
+    LoggedOut,
+}
+
+impl BridgeStateEvent {
+    /// Returns whether this state means that something is wrong.
+    pub fn is_error(self) -> bool {
+        matches!(
+            self,
+            Self::BridgeUnreachable
+                | Self::TransientDisconnect
+                | Self::BadCredentials
+                | Self::UnknownError
+        )
+    }
+}
+
+/// A report of the state of a bridge, as sent to a status endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BridgeState {
+    /// The state.
+    pub state_event: BridgeStateEvent,
+    /// The time of the report, in seconds since the unix epoch.
+    pub timestamp: u64,
+    /// For how many seconds the state is valid.
+    pub ttl: u64,
+    /// An error code, for error states.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// A human readable description of the state.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// The ID of the account on the external service the state is about, if it's not about the
+    /// bridge itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_id: Option<String>,
+    /// The name of the account on the external service the state is about.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_name: Option<String>,
+}
+
+impl BridgeState {
+    /// Create a new `BridgeState` with the given `state_event` at the current time.
+    ///
+    /// Error states are valid for a minute, other states for an hour.
+    pub fn new(state_event: BridgeStateEvent) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let ttl = if state_event.is_error() { 60 } else { 3600 };
+
+        Self {
+            state_event,
+            timestamp,
+            ttl,
+            error: None,
+            message: None,
+            remote_id: None,
+            remote_name: None,
+        }
+    }
+}
+
+/// An error from reporting a `BridgeState`.
+#[derive(Debug, Error)]
+pub enum BridgeStateError<E> {
+    /// Sending the request failed.
+    #[error("reporting the bridge state failed: {0}")]
+    Http(E),
+    /// The request couldn't be built, because the url is invalid.
+    #[error("invalid bridge state request: {0}")]
+    Request(http::Error),
+    /// The status endpoint returned an error status code.
+    #[error("the status endpoint returned {0}")]
+    Status(http::StatusCode),
+}
+
+impl<E> From<http::Error> for BridgeStateError<E> {
+    fn from(err: http::Error) -> Self {
+        BridgeStateError::Request(err)
+    }
+}
+
+/// Reports the state of a bridge to a status endpoint, for monitoring.
+#[derive(Debug, Clone)]
+pub struct BridgeStateReporter<C> {
+    http_client: C,
+    url: String,
+    token: String,
+}
+
+impl<C: HttpClient> BridgeStateReporter<C> {
+    /// Create a new `BridgeStateReporter` posting states to `url` using `http_client`,
+    /// authenticating using `token`, which usually is the `as_token` of the bridge.
+    pub fn new(http_client: C, url: String, token: String) -> Self {
+        Self {
+            http_client,
+            url,
+            token,
+        }
+    }
+
+    /// Post the given `state` to the status endpoint.
+    pub async fn report(&self, state: &BridgeState) -> Result<(), BridgeStateError<C::Error>> {
+        let mut body = C::RequestBody::default();
+        body.put_slice(&serde_json::to_vec(state).expect("bridge state should serialize"));
+
+        let request = http::Request::builder()
+            .method(Method::POST)
+            .uri(&self.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.token))
+            .body(body)?;
+
+        let response = self
+            .http_client
+            .send_http_request(request)
+            .await
+            .map_err(BridgeStateError::Http)?;
+        if !response.status().is_success() {
+            return Err(BridgeStateError::Status(response.status()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{RoomId, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{
+        BridgeInfo, BridgeInfoSection, BridgeState, BridgeStateError, BridgeStateEvent,
+        BridgeStateReporter, Intent,
+    };
+
+    #[tokio::test]
+    async fn test_bridge_info() {
+        let (client, state) = mock_client();
+        let bot = UserId::try_from("@bot:example.org").unwrap();
+        let intent = Intent::new(client, bot.clone());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        let mut info = BridgeInfo::new(
+            bot,
+            BridgeInfoSection::new(String::from("irc")),
+            BridgeInfoSection::new(String::from("#rust")),
+        );
+        info.network = Some(BridgeInfoSection::new(String::from("libera")));
+        assert_eq!(info.state_key(), "irc://libera/#rust");
+
+        state.respond("/state/", 200, json!({ "event_id": "$a:example.org" }));
+        info.publish(&intent, &room_id).await.unwrap();
+
+        let requests = state.requests_to("/state/");
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0]
+            .path
+            .contains("/state/m.bridge/irc://libera/#rust?"));
+        assert!(requests[1].path.contains("/state/uk.half-shot.bridge/"));
+        assert_eq!(
+            requests[0].body,
+            json!({
+                "bridgebot": "@bot:example.org",
+                "protocol": { "id": "irc" },
+                "network": { "id": "libera" },
+                "channel": { "id": "#rust" },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bridge_state() {
+        let (_, state) = mock_client();
+        let reporter = BridgeStateReporter::new(
+            state.http_client(),
+            String::from("https://status.example.org/bridge_state"),
+            String::from("as_token"),
+        );
+
+        let mut bridge_state = BridgeState::new(BridgeStateEvent::BadCredentials);
+        bridge_state.remote_id = Some(String::from("alice"));
+        assert_eq!(bridge_state.ttl, 60);
+        reporter.report(&bridge_state).await.unwrap();
+
+        let request = &state.requests_to("/bridge_state")[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.body["state_event"], "BAD_CREDENTIALS");
+        assert_eq!(request.body["remote_id"], "alice");
+
+        state.respond("/bridge_state", 500, json!({}));
+        let result = reporter
+            .report(&BridgeState::new(BridgeStateEvent::Running))
+            .await;
+        assert!(matches!(result, Err(BridgeStateError::Status(status)) if status == 500));
+    }
+}
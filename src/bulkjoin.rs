@@ -0,0 +1,303 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use futures::stream::{self, StreamExt};
+use ruma::identifiers::{RoomId, UserId};
+use ruma_client::HttpClient;
+
+use crate::client::{is_retryable, sleep, RetryPolicy};
+use crate::intent::Intent;
+use crate::mappingdict::MappingDict;
+use crate::puppet::{Puppet, PuppetError, PuppetManager};
+use crate::store::MappingStore;
+
+/// How far a `BulkJoin` or `MembershipSync` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoinProgress {
+    /// The amount of puppets that are handled.
+    pub total: usize,
+    /// The amount of puppets that are done, including the failed ones.
+    pub done: usize,
+    /// The amount of puppets that failed.
+    pub failed: usize,
+}
+
+pub(crate) type ProgressFn = Box<dyn Fn(&JoinProgress) + Send + Sync>;
+
+/// Counts the puppets that are done and reports every change to a progress callback.
+pub(crate) struct ProgressTracker<'a> {
+    total: usize,
+    done: AtomicUsize,
+    failed: AtomicUsize,
+    on_progress: Option<&'a ProgressFn>,
+}
+
+impl<'a> ProgressTracker<'a> {
+    pub(crate) fn new(total: usize, on_progress: Option<&'a ProgressFn>) -> Self {
+        Self {
+            total,
+            done: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            on_progress,
+        }
+    }
+
+    /// Record that a puppet is done, and whether it failed.
+    pub(crate) fn record(&self, failed: bool) {
+        let done = self.done.fetch_add(1, Ordering::SeqCst) + 1;
+        if failed {
+            self.failed.fetch_add(1, Ordering::SeqCst);
+        }
+        if let Some(f) = self.on_progress {
+            f(&JoinProgress {
+                total: self.total,
+                done,
+                failed: self.failed.load(Ordering::SeqCst),
+            });
+        }
+    }
+}
+
+/// Run `f` until it succeeds or fails with an error that retrying won't help with, following
+/// `retry`.
+///
+/// Waiting between attempts requires the `runtime` feature. Without it, `f` is run only once.
+pub(crate) async fn with_retries<T, E, S, F, Fut>(
+    retry: &RetryPolicy,
+    f: F,
+) -> Result<T, PuppetError<E, S>>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, PuppetError<E, S>>>,
+{
+    let mut attempts = 0;
+    loop {
+        let err = match f().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+        attempts += 1;
+
+        let retryable = matches!(&err, PuppetError::Intent(err) if is_retryable(err));
+        if !cfg!(feature = "runtime") || !retryable || attempts >= retry.max_attempts() {
+            return Err(err);
+        }
+        sleep(retry.backoff(attempts)).await;
+    }
+}
+
+/// The outcome of `BulkJoin::join`.
+#[derive(Debug)]
+pub struct BulkJoinReport<E, S> {
+    /// The puppets that are in the room now, including the ones that already were.
+    pub joined: Vec<UserId>,
+    /// The external IDs of the users of which the puppet failed to join.
+    pub errors: Vec<(String, PuppetError<E, S>)>,
+}
+
+/// Joins many puppets to a room at the same time, like when a portal is created for a channel
+/// with hundreds of users.
+///
+/// Puppets are joined a few at the same time, see `concurrency`, and requests that fail because
+/// of rate limiting or an error of the homeserver are retried following the `RetryPolicy`.
+/// Puppets that are known to be in the room already are skipped. A failing puppet doesn't stop
+/// the others, its error is returned in the report.
+pub struct BulkJoin<'a, C, S = Mutex<MappingDict<Puppet>>> {
+    puppets: &'a PuppetManager<C, S>,
+    inviter: Option<&'a Intent<C>>,
+    concurrency: usize,
+    retry: RetryPolicy,
+    on_progress: Option<ProgressFn>,
+}
+
+impl<'a, C, S> BulkJoin<'a, C, S>
+where
+    C: HttpClient + Clone,
+    S: MappingStore<Puppet>,
+{
+    /// Create a new `BulkJoin` joining the puppets of `puppets`, ten at the same time.
+    pub fn new(puppets: &'a PuppetManager<C, S>) -> Self {
+        Self {
+            puppets,
+            inviter: None,
+            concurrency: 10,
+            retry: RetryPolicy::default(),
+            on_progress: None,
+        }
+    }
+
+    /// Set the amount of puppets that join at the same time, returning the current `BulkJoin` to
+    /// allow method chaining.
+    pub fn concurrency(&mut self, concurrency: usize) -> &mut Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Set when and how often failed requests are retried, returning the current `BulkJoin` to
+    /// allow method chaining.
+    pub fn retry_policy(&mut self, retry: RetryPolicy) -> &mut Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Invite every puppet as `inviter` before it joins, for rooms that can't be joined without
+    /// an invite, returning the current `BulkJoin` to allow method chaining.
+    pub fn invite_as(&mut self, inviter: &'a Intent<C>) -> &mut Self {
+        self.inviter = Some(inviter);
+        self
+    }
+
+    /// Call `f` every time a puppet is done, returning the current `BulkJoin` to allow method
+    /// chaining.
+    pub fn on_progress<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&JoinProgress) + Send + Sync + 'static,
+    {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Join the puppets of the external users with the given `external_ids` to the room with the
+    /// given `room_id`, creating the puppets if needed.
+    pub async fn join(
+        &self,
+        room_id: &RoomId,
+        external_ids: &[String],
+    ) -> BulkJoinReport<C::Error, S::Error> {
+        self.join_inviting(room_id, external_ids, self.inviter)
+            .await
+    }
+
+    /// Like `join`, but inviting the puppets as `inviter` instead of the inviter of this
+    /// `BulkJoin`.
+    pub(crate) async fn join_inviting(
+        &self,
+        room_id: &RoomId,
+        external_ids: &[String],
+        inviter: Option<&Intent<C>>,
+    ) -> BulkJoinReport<C::Error, S::Error> {
+        let progress = ProgressTracker::new(external_ids.len(), self.on_progress.as_ref());
+        let progress = &progress;
+        let results: Vec<_> = stream::iter(external_ids)
+            .map(|external_id| async move {
+                let result =
+                    with_retries(&self.retry, || self.join_one(room_id, external_id, inviter))
+                        .await;
+                progress.record(result.is_err());
+                (external_id, result)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await;
+
+        let mut report = BulkJoinReport {
+            joined: vec![],
+            errors: vec![],
+        };
+        for (external_id, result) in results {
+            match result {
+                Ok(user_id) => report.joined.push(user_id),
+                Err(err) => report.errors.push((external_id.clone(), err)),
+            }
+        }
+        report
+    }
+
+    async fn join_one(
+        &self,
+        room_id: &RoomId,
+        external_id: &str,
+        inviter: Option<&Intent<C>>,
+    ) -> Result<UserId, PuppetError<C::Error, S::Error>> {
+        let puppet = self
+            .puppets
+            .get(external_id)
+            .await
+            .map_err(PuppetError::Store)?;
+        if let Some(puppet) = puppet.filter(|puppet| puppet.is_joined(room_id)) {
+            return Ok(puppet.user_id().clone());
+        }
+
+        let intent = self.puppets.puppet_for(external_id).await?;
+        if let Some(inviter) = inviter {
+            inviter.invite(room_id, intent.user_id()).await?;
+        }
+        self.puppets.ensure_joined(external_id, room_id).await?;
+        Ok(intent.user_id().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use ruma::identifiers::{RoomId, ServerName, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{BulkJoin, Intent, PuppetManager, RetryPolicy};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bulk_join() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(
+            client.clone(),
+            UserId::try_from("@bot:example.org").unwrap(),
+        );
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let puppets: PuppetManager<_> =
+            PuppetManager::new(client, server_name, "_ext_", Default::default());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let external_ids: Vec<_> = (0..20).map(|i| format!("user{}", i)).collect();
+
+        state.respond(
+            "/register",
+            200,
+            json!({ "user_id": "@_ext_user:example.org" }),
+        );
+        state.respond(
+            "/join?user_id=@_ext_user13:example.org",
+            403,
+            json!({ "errcode": "M_FORBIDDEN", "error": "Banned" }),
+        );
+        state.respond_once(
+            "/join?user_id=@_ext_user7:example.org",
+            502,
+            json!({ "errcode": "M_UNKNOWN", "error": "Bad gateway" }),
+        );
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+
+        let progress = Arc::new(Mutex::new(vec![]));
+        let reported = progress.clone();
+        let mut bulk = BulkJoin::new(&puppets);
+        bulk.concurrency(4)
+            .retry_policy(RetryPolicy::new(
+                2,
+                Duration::from_secs(1),
+                Duration::from_secs(1),
+            ))
+            .invite_as(&bot)
+            .on_progress(move |progress| reported.lock().unwrap().push(*progress));
+        let report = bulk.join(&room_id, &external_ids).await;
+
+        // user7 is retried and joins if the retry can wait.
+        let failed = if cfg!(feature = "runtime") { 1 } else { 2 };
+        assert_eq!(report.joined.len(), 20 - failed);
+        assert_eq!(report.errors.len(), failed);
+        assert!(report.errors.iter().any(|(id, _)| id == "user13"));
+        let invites = state.requests_to("/invite").len();
+        assert_eq!(invites, 22 - failed);
+
+        let last = *progress.lock().unwrap().last().unwrap();
+        assert_eq!(progress.lock().unwrap().len(), 20);
+        assert_eq!((last.total, last.done, last.failed), (20, 20, failed));
+
+        // joined puppets are skipped.
+        let report = bulk.join(&room_id, &external_ids[..5]).await;
+        assert_eq!(report.joined.len(), 5);
+        assert_eq!(state.requests_to("/invite").len(), invites);
+    }
+}
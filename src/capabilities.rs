@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+
+use ruma::api::client::r0::capabilities::{get_capabilities, Capabilities};
+use ruma::api::client::unversioned::get_supported_versions;
+use ruma::identifiers::RoomVersionId;
+use ruma_client::HttpClient;
+use serde_json::Value;
+
+use crate::backfill::BackfillMode;
+use crate::intent::{Intent, IntentError};
+
+/// The features supported by a homeserver, as advertised by `/_matrix/client/versions` and
+/// `/_matrix/client/r0/capabilities`.
+///
+/// This is usually probed once on startup using `ServerCapabilities::probe`, after which other
+/// parts of a bridge can use it to pick a code path, like the `BackfillMode`.
+#[derive(Debug, Clone, Default)]
+pub struct ServerCapabilities {
+    versions: Vec<String>,
+    unstable_features: BTreeMap<String, bool>,
+    capabilities: Capabilities,
+}
+
+impl ServerCapabilities {
+    /// Create a new `ServerCapabilities` from the supported spec `versions`, the
+    /// `unstable_features` and the `capabilities` of a homeserver.
+    pub fn new(
+        versions: Vec<String>,
+        unstable_features: BTreeMap<String, bool>,
+        capabilities: Capabilities,
+    ) -> Self {
+        Self {
+            versions,
+            unstable_features,
+            capabilities,
+        }
+    }
+
+    /// Query the features of the homeserver as the user of `intent`, usually the bot.
+    ///
+    /// Homeservers that don't support `/capabilities` are assumed to have the default
+    /// capabilities.
+    pub async fn probe<C: HttpClient>(intent: &Intent<C>) -> Result<Self, IntentError<C::Error>> {
+        let versions = intent.send(get_supported_versions::Request::new()).await?;
+        let capabilities = match intent.send(get_capabilities::Request::new()).await {
+            Ok(response) => response.capabilities,
+            Err(err) if err.matrix_error().is_some() => Capabilities::default(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self::new(
+            versions.versions,
+            versions.unstable_features,
+            capabilities,
+        ))
+    }
+
+    /// Get the versions of the spec supported by the homeserver, like `r0.6.1` or `v1.11`.
+    pub fn versions(&self) -> &[String] {
+        &self.versions
+    }
+
+    /// Returns whether the homeserver supports the version `version` of the spec.
+    pub fn supports_version(&self, version: &str) -> bool {
+        self.versions.iter().any(|v| v == version)
+    }
+
+    /// Returns whether the homeserver supports the `v{major}.{minor}` version of the spec, or
+    /// any later version.
+    pub fn supports_version_at_least(&self, major: u32, minor: u32) -> bool {
+        self.versions
+            .iter()
+            .filter_map(|version| parse_version(version))
+            .any(|version| version >= (major, minor))
+    }
+
+    /// Returns whether the homeserver has enabled the unstable feature `feature`, like
+    /// `org.matrix.msc2716`.
+    pub fn unstable_feature(&self, feature: &str) -> bool {
+        self.unstable_features
+            .get(feature)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Get the value of the capability `capability`, if the homeserver advertises it.
+    pub fn capability(&self, capability: &str) -> Option<Value> {
+        self.capabilities
+            .get(capability)
+            .map(|value| value.into_owned())
+    }
+
+    /// Get the room version the homeserver uses for new rooms.
+    pub fn default_room_version(&self) -> &RoomVersionId {
+        &self.capabilities.room_versions.default
+    }
+
+    /// Returns whether the homeserver supports inserting history using `/batch_send` from
+    /// MSC2716.
+    pub fn supports_batch_send(&self) -> bool {
+        self.unstable_feature("org.matrix.msc2716")
+    }
+
+    /// Returns whether the homeserver supports the authenticated media endpoints from MSC3916.
+    pub fn supports_authenticated_media(&self) -> bool {
+        self.supports_version_at_least(1, 11)
+            || self.unstable_feature("org.matrix.msc3916.stable")
+            || self.unstable_feature("org.matrix.msc3916")
+    }
+
+    /// Returns whether the homeserver sends end-to-end encryption events to the application
+    /// service as described by MSC3202.
+    pub fn supports_appservice_encryption(&self) -> bool {
+        self.unstable_feature("org.matrix.msc3202")
+    }
+
+    /// Get the `BackfillMode` to use with the homeserver.
+    pub fn backfill_mode(&self) -> BackfillMode {
+        if self.supports_batch_send() {
+            BackfillMode::BatchSend
+        } else {
+            BackfillMode::Massaged
+        }
+    }
+}
+
+/// Parse a spec version like `v1.11` into its major and minor version.
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.strip_prefix('v')?.splitn(2, '.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{RoomVersionId, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{BackfillMode, Intent, ServerCapabilities};
+
+    #[tokio::test]
+    async fn test_probe() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+
+        state.respond(
+            "/versions",
+            200,
+            json!({
+                "versions": ["r0.6.1", "v1.1", "v1.11"],
+                "unstable_features": { "org.matrix.msc2716": true, "org.matrix.msc3202": false },
+            }),
+        );
+        state.respond(
+            "/capabilities",
+            200,
+            json!({
+                "capabilities": {
+                    "m.room_versions": { "default": "9", "available": { "9": "stable" } },
+                    "m.set_displayname": { "enabled": false },
+                },
+            }),
+        );
+        let capabilities = ServerCapabilities::probe(&bot).await.unwrap();
+        assert!(capabilities.supports_version("r0.6.1"));
+        assert!(capabilities.supports_version_at_least(1, 2));
+        assert!(!capabilities.supports_version_at_least(2, 0));
+        assert!(capabilities.supports_batch_send());
+        assert!(capabilities.supports_authenticated_media());
+        assert!(!capabilities.supports_appservice_encryption());
+        assert_eq!(capabilities.backfill_mode(), BackfillMode::BatchSend);
+        assert_eq!(
+            capabilities.default_room_version(),
+            &RoomVersionId::try_from("9").unwrap()
+        );
+        assert_eq!(
+            capabilities.capability("m.set_displayname"),
+            Some(json!({ "enabled": false }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_without_capabilities() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+
+        state.respond("/versions", 200, json!({ "versions": ["r0.5.0"] }));
+        state.respond(
+            "/capabilities",
+            404,
+            json!({ "errcode": "M_UNRECOGNIZED", "error": "Unrecognized request" }),
+        );
+        let capabilities = ServerCapabilities::probe(&bot).await.unwrap();
+        assert!(!capabilities.supports_version_at_least(1, 0));
+        assert!(!capabilities.supports_authenticated_media());
+        assert_eq!(capabilities.backfill_mode(), BackfillMode::Massaged);
+    }
+}
@@ -0,0 +1,233 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use ruma::api::error::{FromHttpResponseError, ServerError};
+
+use crate::bridgestate::{BridgeState, BridgeStateEvent};
+use crate::intent::IntentError;
+
+type StateFn = Box<dyn Fn(&BridgeState) + Send + Sync>;
+
+/// Whether a `CircuitBreaker` lets requests through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// The homeserver is reachable, requests are sent.
+    Closed,
+    /// The homeserver is unreachable, requests aren't sent until the cooldown is over.
+    Open,
+    /// The cooldown is over, one request is sent as a probe to find out whether the homeserver
+    /// is reachable again.
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive: u32,
+    open_until: Option<Instant>,
+    probe_until: Option<Instant>,
+}
+
+/// Stops sending requests while the homeserver is unreachable, instead of sending every request
+/// only to have it fail.
+///
+/// After `threshold` consecutive requests fail because the homeserver couldn't be reached or
+/// returned a gateway error, the breaker opens and `allow` refuses requests for the cooldown.
+/// After the cooldown, one request is let through as a probe. If it reaches the homeserver the
+/// breaker closes, otherwise it stays open for another cooldown. Opening and closing are
+/// reported to the `on_state_change` callbacks as a `BridgeState`, to be sent to the status
+/// endpoint of the bridge.
+///
+/// A `SendQueue` given this breaker using `SendQueue::set_circuit_breaker` keeps the messages
+/// queued while it's open, and an `AppserviceClient` given it using
+/// `AppserviceClient::set_circuit_breaker` fails its requests and the ones of its `Intent`s with
+/// `IntentError::Unreachable`.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+    on_state_change: Vec<StateFn>,
+}
+
+impl CircuitBreaker {
+    /// Create a new `CircuitBreaker` opening after `threshold` consecutive failures, for
+    /// `cooldown` at a time.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            state: Mutex::default(),
+            on_state_change: vec![],
+        }
+    }
+
+    /// Call `f` with a `BridgeState` when the breaker opens because the homeserver is
+    /// unreachable, and when it closes again.
+    pub fn on_state_change<F>(&mut self, f: F)
+    where
+        F: Fn(&BridgeState) + Send + Sync + 'static,
+    {
+        self.on_state_change.push(Box::new(f));
+    }
+
+    /// Get whether the breaker lets requests through.
+    pub fn state(&self) -> CircuitState {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match state.open_until {
+            None => CircuitState::Closed,
+            Some(until) if until > Instant::now() => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Returns whether a request may be sent now.
+    ///
+    /// When the breaker is half open, this returns `true` once, for the probe. If the result of
+    /// the probe isn't observed within the cooldown, another probe is allowed.
+    pub fn allow(&self) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match (state.open_until, state.probe_until) {
+            (None, _) => true,
+            (Some(until), _) if until > now => false,
+            (Some(_), Some(probe_until)) if probe_until > now => false,
+            (Some(_), _) => {
+                state.probe_until = Some(now + self.cooldown);
+                true
+            }
+        }
+    }
+
+    /// Observe the error `err` of a failed request.
+    ///
+    /// Errors returned by the homeserver itself show that it's reachable, so they count as a
+    /// success.
+    pub fn observe<E>(&self, err: &IntentError<E>) {
+        if matches!(err, IntentError::Unreachable) {
+            return;
+        }
+        if !is_outage(err) {
+            return self.observe_success();
+        }
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.consecutive = state.consecutive.saturating_add(1);
+        let opened = match state.open_until {
+            Some(_) => false,
+            None => state.consecutive >= self.threshold,
+        };
+        if state.open_until.is_some() || opened {
+            state.open_until = Some(Instant::now() + self.cooldown);
+            state.probe_until = None;
+        }
+        drop(state);
+
+        if opened {
+            let mut report = BridgeState::new(BridgeStateEvent::UnknownError);
+            report.error = Some(String::from("homeserver-unreachable"));
+            report.message = Some(String::from("The homeserver can't be reached"));
+            self.report(&report);
+        }
+    }
+
+    /// Observe a request that reached the homeserver, closing the breaker.
+    pub fn observe_success(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let was_open = state.open_until.is_some();
+        *state = BreakerState::default();
+        drop(state);
+
+        if was_open {
+            self.report(&BridgeState::new(BridgeStateEvent::Running));
+        }
+    }
+
+    /// Get the time to wait before a request may be sent, or `None` if one may be sent now.
+    pub fn wait_time(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let until = match state.open_until? {
+            until if until > now => until,
+            _ => state.probe_until?,
+        };
+        until.checked_duration_since(now)
+    }
+
+    fn report(&self, state: &BridgeState) {
+        for f in &self.on_state_change {
+            f(state);
+        }
+    }
+}
+
+/// The breaker opens after five consecutive failures, for thirty seconds at a time.
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(30))
+    }
+}
+
+/// Returns whether the request that failed with `err` didn't reach the homeserver, either
+/// because it couldn't be connected to or because a proxy in front of it returned an error.
+fn is_outage<E>(err: &IntentError<E>) -> bool {
+    match err {
+        IntentError::Request(ruma_client::Error::Response(_))
+        | IntentError::Registration(ruma_client::Error::Response(_)) => true,
+        IntentError::Request(ruma_client::Error::FromHttpResponse(
+            FromHttpResponseError::Http(ServerError::Unknown(_)),
+        )) => true,
+        err => err
+            .matrix_error()
+            .is_some_and(|err| (502..=504).contains(&err.status_code.as_u16())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::{BridgeStateEvent, CircuitBreaker, CircuitState, IntentError};
+
+    fn outage() -> IntentError<()> {
+        IntentError::Request(ruma_client::Error::Response(()))
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let cooldown = Duration::from_millis(50);
+        let mut breaker = CircuitBreaker::new(2, cooldown);
+        let states = Arc::new(Mutex::new(vec![]));
+        let reported = states.clone();
+        breaker.on_state_change(move |state| reported.lock().unwrap().push(state.state_event));
+
+        breaker.observe(&outage());
+        assert!(breaker.allow());
+        breaker.observe_success();
+        breaker.observe(&outage());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.observe(&outage());
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+        assert!(breaker.wait_time().unwrap() <= cooldown);
+        assert_eq!(*states.lock().unwrap(), [BridgeStateEvent::UnknownError]);
+
+        // a failing probe opens the breaker again, without reporting it again.
+        std::thread::sleep(cooldown);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        breaker.observe(&outage());
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(states.lock().unwrap().len(), 1);
+
+        std::thread::sleep(cooldown);
+        assert!(breaker.allow());
+        breaker.observe_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(breaker.wait_time(), None);
+        assert_eq!(
+            *states.lock().unwrap(),
+            [BridgeStateEvent::UnknownError, BridgeStateEvent::Running]
+        );
+    }
+}
@@ -0,0 +1,597 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use ruma::api::client::error::ErrorKind;
+use ruma::api::OutgoingRequest;
+use ruma::identifiers::UserId;
+use ruma_client::{Client, HttpClient};
+
+use crate::circuitbreaker::{CircuitBreaker, CircuitState};
+use crate::intent::{Intent, IntentError};
+use crate::ratelimit::RateLimitMonitor;
+use crate::request::RequestBuilder;
+
+/// When and how often an `AppserviceClient` retries a failed request.
+///
+/// Only requests that failed because of rate limiting, an error of the homeserver itself or a
+/// failure to reach it are retried, since retrying other errors won't help.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Create a new `RetryPolicy` attempting a request `max_attempts` times, waiting `initial`
+    /// after the first failed attempt, which is doubled after every next failed attempt up to
+    /// `max`.
+    pub fn new(max_attempts: u32, initial: Duration, max: Duration) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            initial_backoff: initial,
+            max_backoff: max,
+        }
+    }
+
+    /// Create a new `RetryPolicy` that never retries.
+    pub fn none() -> Self {
+        Self::new(1, Duration::from_secs(0), Duration::from_secs(0))
+    }
+
+    /// Get the amount of times a request is attempted.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Get the time to wait after the given amount of failed `attempts`.
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+/// A request is attempted three times by default, waiting one second and then two seconds.
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(3, Duration::from_secs(1), Duration::from_secs(30))
+    }
+}
+
+/// A `Client` of an application service, with the settings every request is made with.
+///
+/// The `Client` has the url of the homeserver and the `as_token` of the registration. Requests
+/// are started using `request` or `request_as`, which give a `RequestBuilder` that already has
+/// the default url parameters and headers, or sent directly using `send` and `send_as`, which
+/// also apply the `RetryPolicy` and report to the `RateLimitMonitor`. The `Intent`s created using
+/// `intent` send their requests the same way.
+///
+/// Waiting between attempts requires the `runtime` feature. Without it, requests are attempted
+/// only once.
+#[derive(Clone)]
+pub struct AppserviceClient<C> {
+    client: Client<C>,
+    params: Vec<(String, String)>,
+    headers: HeaderMap,
+    retry: RetryPolicy,
+    rate_limit: Option<Arc<RateLimitMonitor>>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl<C: std::fmt::Debug> std::fmt::Debug for AppserviceClient<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AppserviceClient")
+            .field("client", &self.client)
+            .field("params", &self.params)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .field("retry", &self.retry)
+            .field("rate_limit", &self.rate_limit.is_some())
+            .field("circuit_breaker", &self.circuit_breaker.is_some())
+            .finish()
+    }
+}
+
+impl<C: HttpClient> AppserviceClient<C> {
+    /// Create a new `AppserviceClient` making requests to the homeserver at `homeserver_url`
+    /// using `http_client`, authenticated using `as_token`.
+    pub fn new(http_client: C, homeserver_url: String, as_token: String) -> Self {
+        Self::from_client(Client::with_http_client(
+            http_client,
+            homeserver_url,
+            Some(as_token),
+        ))
+    }
+
+    /// Create a new `AppserviceClient` making requests using `client`, which should have the
+    /// `as_token` of the registration.
+    pub fn from_client(client: Client<C>) -> Self {
+        Self {
+            client,
+            params: vec![],
+            headers: HeaderMap::new(),
+            retry: RetryPolicy::default(),
+            rate_limit: None,
+            circuit_breaker: None,
+        }
+    }
+
+    /// Get the `Client` used by this `AppserviceClient`.
+    pub fn client(&self) -> &Client<C> {
+        &self.client
+    }
+
+    /// Set the url parameter `key` to `value` on every request, or stop setting it if `value` is
+    /// `None`.
+    pub fn set_default_param(&mut self, key: &str, value: Option<&str>) {
+        self.params.retain(|(k, _)| k != key);
+        if let Some(value) = value {
+            self.params.push((key.to_string(), value.to_string()));
+        }
+    }
+
+    /// Set the header `name` to `value` on every request, like an `X-Request-Id` or the
+    /// credentials of an authenticating proxy, or stop setting it if `value` is `None`.
+    ///
+    /// The headers are also set on the requests of the `Intent`s of this client, including an
+    /// `Intent` created using `Intent::authenticated` given this client.
+    pub fn set_default_header(&mut self, name: HeaderName, value: Option<HeaderValue>) {
+        match value {
+            Some(value) => self.headers.insert(name, value),
+            None => self.headers.remove(name),
+        };
+    }
+
+    /// Set when and how often `send`, `send_as` and the `Intent`s of this client retry failed
+    /// requests.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// Report the responses of the homeserver to `send`, `send_as` and the `Intent`s of this
+    /// client to the given rate limit `monitor`, and wait before sending while it asks to wait.
+    pub fn set_rate_limit_monitor(&mut self, monitor: Option<Arc<RateLimitMonitor>>) {
+        self.rate_limit = monitor;
+    }
+
+    /// Report the responses of the homeserver to `send`, `send_as` and the `Intent`s of this
+    /// client to the given circuit `breaker`, and fail them with `IntentError::Unreachable`
+    /// without sending them while it's open.
+    pub fn set_circuit_breaker(&mut self, breaker: Option<Arc<CircuitBreaker>>) {
+        self.circuit_breaker = breaker;
+    }
+
+    /// Get a `RequestBuilder` for `request`, with the default url parameters and headers set.
+    pub fn request<R: OutgoingRequest>(&self, request: R) -> RequestBuilder<'_, C, R> {
+        self.request_using(&self.client, request)
+    }
+
+    /// Get a `RequestBuilder` for `request` made using `client`, with the default url parameters
+    /// and headers set.
+    fn request_using<'a, R: OutgoingRequest>(
+        &self,
+        client: &'a Client<C>,
+        request: R,
+    ) -> RequestBuilder<'a, C, R> {
+        let mut builder = RequestBuilder::new(client, request);
+        for (key, value) in &self.params {
+            builder.param(key, value);
+        }
+        for (name, value) in &self.headers {
+            builder.header(name.clone(), value.clone());
+        }
+        builder
+    }
+
+    /// Get a `RequestBuilder` for `request` masquerading as the user with the given `user_id`,
+    /// with the default url parameters and headers set.
+    pub fn request_as<R: OutgoingRequest>(
+        &self,
+        user_id: &UserId,
+        request: R,
+    ) -> RequestBuilder<'_, C, R> {
+        let mut builder = self.request(request);
+        builder.user_id(user_id);
+        builder
+    }
+
+    /// Send `request` as the bot user, retrying it following the `RetryPolicy`.
+    pub async fn send<R>(&self, request: R) -> Result<R::IncomingResponse, IntentError<C::Error>>
+    where
+        R: OutgoingRequest<EndpointError = ruma::api::client::Error> + Clone,
+    {
+        self.send_with(&self.client, request, |_| {}).await
+    }
+
+    /// Send `request` masquerading as the user with the given `user_id`, retrying it following
+    /// the `RetryPolicy`.
+    pub async fn send_as<R>(
+        &self,
+        user_id: &UserId,
+        request: R,
+    ) -> Result<R::IncomingResponse, IntentError<C::Error>>
+    where
+        R: OutgoingRequest<EndpointError = ruma::api::client::Error> + Clone,
+    {
+        self.send_with(&self.client, request, |builder| {
+            builder.user_id(user_id);
+        })
+        .await
+    }
+
+    /// Send `request` using `client`, which is either the `Client` of this `AppserviceClient` or
+    /// the one of an authenticated `Intent`, after giving the `RequestBuilder` of every attempt
+    /// to `configure`.
+    pub(crate) async fn send_with<R, F>(
+        &self,
+        client: &Client<C>,
+        request: R,
+        configure: F,
+    ) -> Result<R::IncomingResponse, IntentError<C::Error>>
+    where
+        R: OutgoingRequest + Clone,
+        IntentError<C::Error>: From<ruma_client::Error<C::Error, R::EndpointError>>,
+        F: Fn(&mut RequestBuilder<'_, C, R>),
+    {
+        let mut attempts = 0;
+        loop {
+            if let Some(wait) = self.rate_limit.as_ref().and_then(|m| m.wait_time()) {
+                sleep(wait).await;
+            }
+            if let Some(breaker) = &self.circuit_breaker {
+                if !breaker.allow() {
+                    return Err(IntentError::Unreachable);
+                }
+            }
+
+            let mut builder = self.request_using(client, request.clone());
+            configure(&mut builder);
+            let err = match builder.request().await {
+                Ok(response) => {
+                    if let Some(monitor) = &self.rate_limit {
+                        monitor.observe_success();
+                    }
+                    if let Some(breaker) = &self.circuit_breaker {
+                        breaker.observe_success();
+                    }
+                    return Ok(response);
+                }
+                Err(err) => IntentError::from(err),
+            };
+            attempts += 1;
+
+            let limited = self.rate_limit.as_ref().and_then(|m| m.observe(&err));
+            let open = self.circuit_breaker.as_ref().is_some_and(|breaker| {
+                breaker.observe(&err);
+                breaker.state() != CircuitState::Closed
+            });
+            if !cfg!(feature = "runtime")
+                || attempts >= self.retry.max_attempts
+                || !is_retryable(&err)
+                || open
+            {
+                return Err(err);
+            }
+            let retry_after = match err.kind() {
+                Some(ErrorKind::LimitExceeded {
+                    retry_after_ms: Some(retry_after),
+                }) => *retry_after,
+                _ => Duration::from_secs(0),
+            };
+            let backoff = self.retry.backoff(attempts).max(retry_after);
+            // The monitor is waited for at the start of the next attempt.
+            if limited.is_none() {
+                sleep(backoff).await;
+            }
+        }
+    }
+}
+
+impl<C: HttpClient + Clone> AppserviceClient<C> {
+    /// Get an `Intent` acting as the user with the given `user_id`, sending its requests with
+    /// the settings of this `AppserviceClient`.
+    pub fn intent(&self, user_id: UserId) -> Intent<C> {
+        Intent::new(self.client.clone(), user_id)
+            .with_appservice_client(Some(Arc::new(self.clone())))
+    }
+}
+
+/// Returns whether retrying the request that failed with `err` might help.
+pub(crate) fn is_retryable<E>(err: &IntentError<E>) -> bool {
+    match err {
+        IntentError::Request(ruma_client::Error::Response(_)) => true,
+        err => match err.matrix_error() {
+            Some(matrix) => {
+                matches!(matrix.kind, ErrorKind::LimitExceeded { .. })
+                    || matrix.status_code.is_server_error()
+            }
+            None => false,
+        },
+    }
+}
+
+#[cfg(feature = "runtime")]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await;
+}
+
+#[cfg(not(feature = "runtime"))]
+pub(crate) async fn sleep(_duration: Duration) {}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use hyper::header::{HeaderName, HeaderValue};
+    use ruma::api::client::r0::membership::join_room_by_id;
+    use ruma::events::room::message::MessageEventContent;
+    use ruma::events::AnyMessageEventContent;
+    use ruma::identifiers::{RoomId, ServerName, UserId};
+    use ruma_client::Client;
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{
+        AppserviceClient, CircuitBreaker, CircuitState, Intent, IntentError, PuppetManager,
+        RateLimitMonitor, RetryPolicy, SendQueue,
+    };
+
+    fn text(body: &str) -> AnyMessageEventContent {
+        AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(body))
+    }
+
+    #[tokio::test]
+    async fn test_request_builders() {
+        let (client, state) = mock_client();
+        let mut client = AppserviceClient::from_client(client);
+        client.set_default_param("org.example.bridge", Some("1"));
+        client.set_default_header(
+            HeaderName::from_static("x-request-id"),
+            Some(HeaderValue::from_static("default")),
+        );
+        let ghost = UserId::try_from("@_ext_bob:example.org").unwrap();
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+
+        let mut builder = client.request_as(&ghost, join_room_by_id::Request::new(&room_id));
+        builder
+            .header(
+                HeaderName::from_static("x-request-id"),
+                HeaderValue::from_static("join-1"),
+            )
+            .header(
+                HeaderName::from_static("x-proxy-auth"),
+                HeaderValue::from_static("secret"),
+            );
+        builder.request().await.unwrap();
+        let request = &state.requests_to("/join")[0];
+        assert!(request.path.contains("org.example.bridge=1"));
+        assert!(request.path.contains("user_id=@_ext_bob:example.org"));
+        assert_eq!(request.headers["x-request-id"], "join-1");
+        assert_eq!(request.headers["x-proxy-auth"], "secret");
+        assert!(request.headers.contains_key("authorization"));
+
+        client
+            .send(join_room_by_id::Request::new(&room_id))
+            .await
+            .unwrap();
+        assert_eq!(
+            state.requests_to("/join")[1].headers["x-request-id"],
+            "default"
+        );
+
+        client.set_default_param("org.example.bridge", None);
+        client.set_default_header(HeaderName::from_static("x-request-id"), None);
+        client
+            .send(join_room_by_id::Request::new(&room_id))
+            .await
+            .unwrap();
+        let request = &state.requests_to("/join")[2];
+        assert!(!request.path.contains("org.example.bridge"));
+        assert!(!request.path.contains("user_id"));
+        assert!(!request.headers.contains_key("x-request-id"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry() {
+        let (client, state) = mock_client();
+        let mut client = AppserviceClient::from_client(client);
+        let monitor = Arc::new(RateLimitMonitor::new(true));
+        client.set_rate_limit_monitor(Some(monitor.clone()));
+        client.set_retry_policy(RetryPolicy::new(
+            3,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        ));
+        let ghost = UserId::try_from("@_ext_bob:example.org").unwrap();
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+
+        state.respond_once(
+            "/join",
+            429,
+            json!({ "errcode": "M_LIMIT_EXCEEDED", "error": "Too many requests" }),
+        );
+        state.respond_once(
+            "/join",
+            502,
+            json!({ "errcode": "M_UNKNOWN", "error": "Bad gateway" }),
+        );
+        let result = client
+            .send_as(&ghost, join_room_by_id::Request::new(&room_id))
+            .await;
+        assert_eq!(cfg!(feature = "runtime"), result.is_ok());
+        assert_eq!(monitor.rate_limited_count(), 1);
+
+        state.respond_once(
+            "/join",
+            403,
+            json!({ "errcode": "M_FORBIDDEN", "error": "No" }),
+        );
+        let before = state.requests_to("/join").len();
+        let result = client
+            .send_as(&ghost, join_room_by_id::Request::new(&room_id))
+            .await;
+        assert!(result.is_err());
+        assert_eq!(state.requests_to("/join").len(), before + 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_intent_settings() {
+        let (client, state) = mock_client();
+        let mut appservice = AppserviceClient::from_client(client.clone());
+        appservice.set_default_param("org.example.bridge", Some("1"));
+        let monitor = Arc::new(RateLimitMonitor::new(true));
+        appservice.set_rate_limit_monitor(Some(monitor.clone()));
+        appservice.set_retry_policy(RetryPolicy::new(
+            2,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        ));
+        let ghost = UserId::try_from("@_ext_bob:example.org").unwrap();
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+
+        state.respond_once(
+            "/join",
+            429,
+            json!({ "errcode": "M_LIMIT_EXCEEDED", "error": "Too many requests" }),
+        );
+        let intent = appservice.intent(ghost.clone());
+        let result = intent.send(join_room_by_id::Request::new(&room_id)).await;
+        assert_eq!(cfg!(feature = "runtime"), result.is_ok());
+        assert_eq!(monitor.rate_limited_count(), 1);
+        let request = state.requests_to("/join").pop().unwrap();
+        assert!(request.path.contains("org.example.bridge=1"));
+        assert!(request.path.contains("user_id=@_ext_bob:example.org"));
+
+        let appservice = Arc::new(appservice);
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let mut puppets: PuppetManager<_> =
+            PuppetManager::new(client.clone(), server_name, "_ext_", Default::default());
+        puppets.set_appservice_client(Some(appservice.clone()));
+        state.respond("/register", 200, json!({ "user_id": ghost.as_str() }));
+        let intent = puppets.puppet_for("bob").await.unwrap();
+        let request = state.requests_to("/register").pop().unwrap();
+        assert!(request.path.contains("org.example.bridge=1"));
+        assert!(!request.path.contains("user_id"));
+        intent.join(&room_id).await.unwrap();
+        let request = state.requests_to("/join").pop().unwrap();
+        assert!(request.path.contains("org.example.bridge=1"));
+
+        // the queue retries messages itself, so every attempt is sent once.
+        let mut queue = SendQueue::new(client);
+        queue.set_appservice_client(Some(appservice));
+        queue.set_backoff(Duration::from_secs(0), Duration::from_secs(0));
+        queue
+            .enqueue(ghost, room_id, "m1".to_string(), None, &text("Hi"))
+            .await
+            .unwrap();
+        state.respond("/send/", 200, json!({ "event_id": "$sent:example.org" }));
+        state.respond_once(
+            "/send/",
+            502,
+            json!({ "errcode": "M_UNKNOWN", "error": "Bad gateway" }),
+        );
+        assert_eq!(queue.flush().await, 0);
+        assert_eq!(state.requests_to("/send/").len(), 1);
+        assert_eq!(queue.flush().await, 1);
+        let request = state.requests_to("/send/").pop().unwrap();
+        assert!(request.path.contains("org.example.bridge=1"));
+        assert!(request.path.contains("user_id=@_ext_bob:example.org"));
+    }
+
+    #[tokio::test]
+    async fn test_intent_headers() {
+        let (client, state) = mock_client();
+        let mut appservice = AppserviceClient::from_client(client.clone());
+        appservice.set_default_header(
+            HeaderName::from_static("x-proxy-auth"),
+            Some(HeaderValue::from_static("secret")),
+        );
+        let ghost = UserId::try_from("@_ext_bob:example.org").unwrap();
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+        state.respond("/send/", 200, json!({ "event_id": "$sent:example.org" }));
+        state.respond("/register", 200, json!({ "user_id": ghost.as_str() }));
+
+        let intent = appservice.intent(ghost.clone());
+        intent.ensure_registered().await.unwrap();
+        intent.join(&room_id).await.unwrap();
+        let request = state.requests_to("/register").pop().unwrap();
+        assert_eq!(request.headers["x-proxy-auth"], "secret");
+        let request = state.requests_to("/join").pop().unwrap();
+        assert_eq!(request.headers["x-proxy-auth"], "secret");
+        assert_eq!(request.headers["authorization"], "Bearer as_token");
+
+        let appservice = Arc::new(appservice);
+        let mut queue = SendQueue::new(client.clone());
+        queue.set_appservice_client(Some(appservice.clone()));
+        queue
+            .enqueue(ghost, room_id.clone(), "m1".to_string(), None, &text("Hi"))
+            .await
+            .unwrap();
+        assert_eq!(queue.flush().await, 1);
+        let request = state.requests_to("/send/").pop().unwrap();
+        assert_eq!(request.headers["x-proxy-auth"], "secret");
+
+        let user_client = Client::with_http_client(
+            state.http_client(),
+            String::from("https://matrix.example.org"),
+            Some(String::from("user_token")),
+        );
+        let alice = UserId::try_from("@alice:example.org").unwrap();
+        Intent::authenticated(user_client, alice)
+            .with_appservice_client(Some(appservice))
+            .join(&room_id)
+            .await
+            .unwrap();
+        let request = state.requests_to("/join").pop().unwrap();
+        assert_eq!(request.headers["x-proxy-auth"], "secret");
+        assert_eq!(request.headers["authorization"], "Bearer user_token");
+        assert!(!request.path.contains("user_id"));
+    }
+
+    #[tokio::test]
+    async fn test_intent_circuit_breaker() {
+        let (client, state) = mock_client();
+        let mut appservice = AppserviceClient::from_client(client.clone());
+        let breaker = Arc::new(CircuitBreaker::new(1, Duration::from_secs(60)));
+        appservice.set_circuit_breaker(Some(breaker.clone()));
+        appservice.set_retry_policy(RetryPolicy::none());
+        let ghost = UserId::try_from("@_ext_bob:example.org").unwrap();
+        let intent = appservice.intent(ghost.clone());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        // a failed registration opens the breaker too.
+        state.respond_once(
+            "/register",
+            502,
+            json!({ "errcode": "M_UNKNOWN", "error": "Bad gateway" }),
+        );
+        assert!(intent.ensure_registered().await.is_err());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        let result = intent.send(join_room_by_id::Request::new(&room_id)).await;
+        assert!(matches!(result, Err(IntentError::Unreachable)));
+        let result = intent.ensure_registered().await;
+        assert!(matches!(result, Err(IntentError::Unreachable)));
+        assert!(state.requests_to("/join").is_empty());
+        assert_eq!(state.requests_to("/register").len(), 1);
+
+        let mut queue = SendQueue::new(client);
+        queue.set_appservice_client(Some(Arc::new(appservice)));
+        queue
+            .enqueue(ghost, room_id, "m1".to_string(), None, &text("Hi"))
+            .await
+            .unwrap();
+        assert_eq!(queue.flush().await, 0);
+        assert!(state.requests_to("/send/").is_empty());
+        assert_eq!(queue.pending(), 1);
+    }
+}
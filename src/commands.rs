@@ -0,0 +1,510 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Mutex, PoisonError};
+
+use ruma::events::room::member::MembershipState;
+use ruma::events::room::message::{MessageEventContent, MessageType};
+use ruma::events::{AnyMessageEvent, AnyMessageEventContent, AnyRoomEvent, AnyStateEvent};
+use ruma::identifiers::{RoomId, UserId};
+use ruma::serde::Raw;
+use ruma_client::HttpClient;
+
+use crate::intent::{Intent, IntentError};
+
+/// The context a command is run in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandContext {
+    /// The user that sent the command.
+    pub sender: UserId,
+    /// The room the command was sent in.
+    pub room_id: RoomId,
+    /// The arguments given to the command, by name.
+    pub args: HashMap<String, String>,
+}
+
+impl CommandContext {
+    /// Get the argument with the given `name`, or `None` if it's an optional argument that
+    /// wasn't given.
+    pub fn arg(&self, name: &str) -> Option<&str> {
+        self.args.get(name).map(String::as_str)
+    }
+}
+
+/// The result of a command, the reply on success or an error message on failure.
+pub type CommandResult = Result<String, String>;
+
+type Handler = Box<
+    dyn Fn(CommandContext) -> Pin<Box<dyn Future<Output = CommandResult> + Send>> + Send + Sync,
+>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArgumentKind {
+    Required,
+    Optional,
+    Rest,
+}
+
+#[derive(Debug, Clone)]
+struct Argument {
+    name: String,
+    help: String,
+    kind: ArgumentKind,
+}
+
+/// A command of the bridge bot, with its arguments, help text and handler.
+pub struct Command {
+    name: String,
+    help: String,
+    args: Vec<Argument>,
+    handler: Handler,
+}
+
+impl Command {
+    /// Create a new `Command` called `name` without arguments, running `handler` when it's used.
+    pub fn new<F, R>(name: &str, help: &str, handler: F) -> Self
+    where
+        F: Fn(CommandContext) -> R + Send + Sync + 'static,
+        R: Future<Output = CommandResult> + Send + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            help: help.to_string(),
+            args: vec![],
+            handler: Box::new(move |ctx| Box::pin(handler(ctx))),
+        }
+    }
+
+    fn add_arg(&mut self, name: &str, help: &str, kind: ArgumentKind) -> &mut Self {
+        assert!(
+            !matches!(self.args.last(), Some(arg) if arg.kind == ArgumentKind::Rest),
+            "no arguments can follow a rest argument"
+        );
+        self.args.push(Argument {
+            name: name.to_string(),
+            help: help.to_string(),
+            kind,
+        });
+        self
+    }
+
+    /// Add a required argument, returning the current command to allow method chaining.
+    pub fn arg(&mut self, name: &str, help: &str) -> &mut Self {
+        self.add_arg(name, help, ArgumentKind::Required)
+    }
+
+    /// Add an optional argument, returning the current command to allow method chaining.
+    pub fn optional_arg(&mut self, name: &str, help: &str) -> &mut Self {
+        self.add_arg(name, help, ArgumentKind::Optional)
+    }
+
+    /// Add a required argument containing the rest of the message, returning the current command
+    /// to allow method chaining. No arguments can be added after it.
+    pub fn rest_arg(&mut self, name: &str, help: &str) -> &mut Self {
+        self.add_arg(name, help, ArgumentKind::Rest)
+    }
+
+    /// Get the usage of this command, like `!bridge name <arg> [optional]`.
+    pub fn usage(&self, prefix: &str) -> String {
+        let mut usage = format!("{} {}", prefix, self.name);
+        for arg in &self.args {
+            let arg = match arg.kind {
+                ArgumentKind::Required => format!(" <{}>", arg.name),
+                ArgumentKind::Optional => format!(" [{}]", arg.name),
+                ArgumentKind::Rest => format!(" <{}...>", arg.name),
+            };
+            usage.push_str(&arg);
+        }
+        usage
+    }
+
+    /// Match the given `args` to the arguments of this command.
+    fn parse_args(&self, mut args: Vec<String>) -> Option<HashMap<String, String>> {
+        let mut res = HashMap::new();
+        for arg in &self.args {
+            if args.is_empty() {
+                if arg.kind == ArgumentKind::Optional {
+                    continue;
+                }
+                return None;
+            }
+
+            let value = match arg.kind {
+                ArgumentKind::Rest => std::mem::take(&mut args).join(" "),
+                _ => args.remove(0),
+            };
+            res.insert(arg.name.clone(), value);
+        }
+
+        if args.is_empty() {
+            Some(res)
+        } else {
+            None
+        }
+    }
+}
+
+/// Split `s` into whitespace separated arguments, keeping text between double quotes together.
+fn split_args(s: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut in_quotes = false;
+
+    for c in s.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                in_arg = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+    args
+}
+
+/// Handles the commands sent to the bot of a bridge.
+///
+/// Commands are messages starting with the prefix, like `!bridge help`, sent in the admin room
+/// or in a direct chat with the bot. In direct chats, the prefix can be left out. Rooms the bot
+/// is invited to as a direct chat are recognized automatically.
+///
+/// The `help`, `ping` and `version` commands are built in.
+pub struct CommandProcessor<C> {
+    bot: Intent<C>,
+    prefix: String,
+    version: String,
+    admin_room: Option<RoomId>,
+    direct_rooms: Mutex<HashSet<RoomId>>,
+    commands: BTreeMap<String, Command>,
+}
+
+impl<C: HttpClient> CommandProcessor<C> {
+    /// Create a new `CommandProcessor` replying as `bot`, to commands starting with `prefix`.
+    pub fn new(bot: Intent<C>, prefix: &str) -> Self {
+        Self {
+            bot,
+            prefix: prefix.to_string(),
+            version: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            admin_room: None,
+            direct_rooms: Mutex::new(HashSet::new()),
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// Set the admin room, in which commands are accepted.
+    pub fn set_admin_room(&mut self, room_id: Option<RoomId>) {
+        self.admin_room = room_id;
+    }
+
+    /// Set the version the `version` command replies with, instead of the version of this
+    /// library.
+    pub fn set_version(&mut self, version: String) {
+        self.version = version;
+    }
+
+    /// Accept commands in the room with the given `room_id`, a direct chat with the bot.
+    pub fn add_direct_room(&self, room_id: RoomId) {
+        let mut rooms = self
+            .direct_rooms
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        rooms.insert(room_id);
+    }
+
+    /// Stop accepting commands in the direct chat with the given `room_id`.
+    pub fn remove_direct_room(&self, room_id: &RoomId) {
+        let mut rooms = self
+            .direct_rooms
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        rooms.remove(room_id);
+    }
+
+    fn is_direct_room(&self, room_id: &RoomId) -> bool {
+        let rooms = self
+            .direct_rooms
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        rooms.contains(room_id)
+    }
+
+    /// Add the given `command`, replacing any command with the same name.
+    pub fn add_command(&mut self, command: Command) {
+        self.commands.insert(command.name.clone(), command);
+    }
+
+    /// Get the arguments of the command in `body` sent in the room with the given `room_id`, or
+    /// `None` if it isn't a command.
+    fn command_args(&self, room_id: &RoomId, body: &str) -> Option<Vec<String>> {
+        let is_direct = self.is_direct_room(room_id);
+        if !is_direct && self.admin_room.as_ref() != Some(room_id) {
+            return None;
+        }
+
+        let mut args = split_args(body);
+        if args.first() == Some(&self.prefix) {
+            args.remove(0);
+        } else if !is_direct {
+            return None;
+        }
+
+        if args.is_empty() {
+            None
+        } else {
+            Some(args)
+        }
+    }
+
+    fn help(&self) -> String {
+        let mut help = vec![
+            format!("{} help - Show this help.", self.prefix),
+            format!(
+                "{} ping - Check whether the bridge is running.",
+                self.prefix
+            ),
+            format!("{} version - Show the version of the bridge.", self.prefix),
+        ];
+        for command in self.commands.values() {
+            help.push(format!(
+                "{} - {}",
+                command.usage(&self.prefix),
+                command.help
+            ));
+            for arg in &command.args {
+                help.push(format!("    {}: {}", arg.name, arg.help));
+            }
+        }
+        help.join("\n")
+    }
+
+    /// Run the command with the given `args`, returning the reply.
+    async fn run(&self, sender: &UserId, room_id: &RoomId, mut args: Vec<String>) -> String {
+        let name = args.remove(0);
+        match name.as_str() {
+            "help" => return self.help(),
+            "ping" => return String::from("Pong!"),
+            "version" => return self.version.clone(),
+            _ => {}
+        }
+
+        let command = match self.commands.get(&name) {
+            Some(command) => command,
+            None => {
+                return format!(
+                    "Unknown command {}, use `{} help` to get a list of commands.",
+                    name, self.prefix
+                )
+            }
+        };
+        let args = match command.parse_args(args) {
+            Some(args) => args,
+            None => return format!("Usage: {}", command.usage(&self.prefix)),
+        };
+
+        let ctx = CommandContext {
+            sender: sender.clone(),
+            room_id: room_id.clone(),
+            args,
+        };
+        match (command.handler)(ctx).await {
+            Ok(reply) => reply,
+            Err(err) => format!("Error: {}", err),
+        }
+    }
+
+    /// Handle the given `event`, running the command in it and replying to it if it's a command.
+    ///
+    /// Returns whether the event was a command.
+    pub async fn handle_event(&self, event: &AnyRoomEvent) -> Result<bool, IntentError<C::Error>> {
+        let event = match event {
+            AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(event)) => event,
+            AnyRoomEvent::State(AnyStateEvent::RoomMember(event)) => {
+                let is_bot = event.state_key == self.bot.user_id().as_str();
+                if is_bot
+                    && event.content.membership == MembershipState::Invite
+                    && event.content.is_direct == Some(true)
+                {
+                    self.add_direct_room(event.room_id.clone());
+                }
+                return Ok(false);
+            }
+            _ => return Ok(false),
+        };
+
+        if &event.sender == self.bot.user_id() || event.content.new_content.is_some() {
+            return Ok(false);
+        }
+        let body = match &event.content.msgtype {
+            MessageType::Text(content) => &content.body,
+            _ => return Ok(false),
+        };
+        let args = match self.command_args(&event.room_id, body) {
+            Some(args) => args,
+            None => return Ok(false),
+        };
+
+        let reply = self.run(&event.sender, &event.room_id, args).await;
+        let content = AnyMessageEventContent::RoomMessage(MessageEventContent::notice_plain(reply));
+        self.bot.send_message(&event.room_id, &content).await?;
+        Ok(true)
+    }
+
+    /// Handle the given `events` from a transaction, using `handle_event`. Events that can't be
+    /// deserialized are skipped.
+    pub async fn handle_events(
+        &self,
+        events: &[Raw<AnyRoomEvent>],
+    ) -> Result<(), IntentError<C::Error>> {
+        for event in events {
+            if let Ok(event) = event.deserialize() {
+                self.handle_event(&event).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::events::AnyRoomEvent;
+    use ruma::identifiers::UserId;
+    use serde_json::{json, Value};
+
+    use crate::testing::mock_client;
+    use crate::{Command, CommandProcessor, Intent};
+
+    fn message(room_id: &str, sender: &str, body: &str) -> AnyRoomEvent {
+        serde_json::from_value(json!({
+            "type": "m.room.message",
+            "event_id": "$event:example.org",
+            "room_id": room_id,
+            "sender": sender,
+            "origin_server_ts": 0,
+            "content": { "msgtype": "m.text", "body": body },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(
+            super::split_args(r#"  !bridge  login "Alice B"  x"#),
+            vec!["!bridge", "login", "Alice B", "x"]
+        );
+        assert_eq!(super::split_args(r#"a """#), vec!["a", ""]);
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let (client, state) = mock_client();
+        state.respond("/send/", 200, json!({ "event_id": "$reply:example.org" }));
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+
+        let mut processor = CommandProcessor::new(bot, "!bridge");
+        processor.set_admin_room(Some("!admin:example.org".parse().unwrap()));
+        let mut command = Command::new("login", "Log in to the network.", |ctx| async move {
+            match ctx.arg("password") {
+                Some(password) => Ok(format!(
+                    "{} {} {}",
+                    ctx.sender,
+                    ctx.arg("user").unwrap(),
+                    password
+                )),
+                None => Err(String::from("no password")),
+            }
+        });
+        command
+            .arg("user", "The user to log in as.")
+            .optional_arg("password", "The password.");
+        processor.add_command(command);
+
+        let replies = |count| -> Vec<Value> {
+            let requests = state.requests_to("/send/");
+            assert_eq!(requests.len(), count);
+            requests
+                .into_iter()
+                .map(|r| r.body["body"].clone())
+                .collect()
+        };
+
+        let alice = "@alice:example.org";
+        assert!(processor
+            .handle_event(&message("!admin:example.org", alice, "!bridge ping"))
+            .await
+            .unwrap());
+        assert!(!processor
+            .handle_event(&message("!admin:example.org", alice, "ping"))
+            .await
+            .unwrap());
+        assert!(!processor
+            .handle_event(&message("!other:example.org", alice, "!bridge ping"))
+            .await
+            .unwrap());
+        processor
+            .handle_event(&message(
+                "!admin:example.org",
+                alice,
+                "!bridge login alice hunter2",
+            ))
+            .await
+            .unwrap();
+        processor
+            .handle_event(&message("!admin:example.org", alice, "!bridge login alice"))
+            .await
+            .unwrap();
+        processor
+            .handle_event(&message("!admin:example.org", alice, "!bridge login"))
+            .await
+            .unwrap();
+        assert_eq!(
+            replies(4),
+            vec![
+                json!("Pong!"),
+                json!("@alice:example.org alice hunter2"),
+                json!("Error: no password"),
+                json!("Usage: !bridge login <user> [password]"),
+            ]
+        );
+
+        // a direct chat invite makes the room accept commands without the prefix.
+        let invite = serde_json::from_value(json!({
+            "type": "m.room.member",
+            "event_id": "$invite:example.org",
+            "room_id": "!dm:example.org",
+            "sender": alice,
+            "state_key": "@bot:example.org",
+            "origin_server_ts": 0,
+            "content": { "membership": "invite", "is_direct": true },
+        }))
+        .unwrap();
+        assert!(!processor.handle_event(&invite).await.unwrap());
+        assert!(processor
+            .handle_event(&message("!dm:example.org", alice, "help"))
+            .await
+            .unwrap());
+        assert!(!processor
+            .handle_event(&message("!dm:example.org", "@bot:example.org", "help"))
+            .await
+            .unwrap());
+        let help = replies(5).pop().unwrap();
+        assert!(help
+            .as_str()
+            .unwrap()
+            .contains("!bridge login <user> [password] - Log in to the network."));
+    }
+}
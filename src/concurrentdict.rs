@@ -0,0 +1,591 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::mappingdict::{Mappable, MappingDict, MappingId};
+
+/// The amount of shards used by `ConcurrentMappingDict::new`.
+const DEFAULT_SHARDS: usize = 16;
+
+/// A part of a `ConcurrentMappingDict`.
+/// Items are kept in the shard of their Matrix ID, the mapping from the external ID to the Matrix
+/// ID is kept in the shard of the external ID.
+struct Shard<V: Mappable> {
+    items: HashMap<V::MatrixType, V>,
+    external_to_matrix: HashMap<V::ExternalType, V::MatrixType>,
+}
+
+impl<V: Mappable> Default for Shard<V> {
+    fn default() -> Self {
+        Self {
+            items: HashMap::new(),
+            external_to_matrix: HashMap::new(),
+        }
+    }
+}
+
+/// A version of `MappingDict` that can be shared between threads and async tasks without an
+/// external lock.
+///
+/// The items are spread over multiple independently locked shards, so operations on different
+/// items generally don't block each other. Since references can't outlive the locks, items are
+/// returned as clones or accessed using a closure.
+pub struct ConcurrentMappingDict<V: Mappable> {
+    shards: Box<[RwLock<Shard<V>>]>,
+    hasher: RandomState,
+}
+
+/// A set of write locked shards, always locked in ascending order to prevent deadlocks.
+struct LockedShards<'a, V: Mappable> {
+    dict: &'a ConcurrentMappingDict<V>,
+    guards: Vec<(usize, RwLockWriteGuard<'a, Shard<V>>)>,
+}
+
+impl<'a, V: Mappable> LockedShards<'a, V> {
+    fn contains(&self, index: usize) -> bool {
+        self.guards.iter().any(|(i, _)| *i == index)
+    }
+
+    fn get(&mut self, index: usize) -> &mut Shard<V> {
+        let (_, guard) = self
+            .guards
+            .iter_mut()
+            .find(|(i, _)| *i == index)
+            .expect("shard should be locked");
+        guard
+    }
+
+    /// Returns the shards that have to be locked to remove the item associated with the given
+    /// `identifier`.
+    fn required_for_remove(
+        &mut self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+    ) -> Vec<usize> {
+        let dict = self.dict;
+        match identifier {
+            MappingId::Matrix(m) => {
+                let shard = dict.shard_of(m);
+                let mut res = vec![shard];
+                if let Some(item) = self.get(shard).items.get(m) {
+                    res.push(dict.shard_of(item.as_external()));
+                }
+                res
+            }
+            MappingId::External(e) => {
+                let shard = dict.shard_of(e);
+                let mut res = vec![shard];
+                if let Some(m) = self.get(shard).external_to_matrix.get(e) {
+                    res.push(dict.shard_of::<V::MatrixReference>(m.borrow()));
+                }
+                res
+            }
+        }
+    }
+
+    /// Returns the item associated with the given `identifier`, the shards returned by
+    /// `required_for_remove` must be locked.
+    fn get_item(
+        &mut self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+    ) -> Option<&V> {
+        let dict = self.dict;
+        let m = match identifier {
+            MappingId::Matrix(m) => m.to_owned(),
+            MappingId::External(e) => {
+                let m: &V::MatrixReference = self
+                    .get(dict.shard_of(e))
+                    .external_to_matrix
+                    .get(e)?
+                    .borrow();
+                m.to_owned()
+            }
+        };
+        let m: &V::MatrixReference = m.borrow();
+        self.get(dict.shard_of(m)).items.get(m)
+    }
+
+    /// Insert `item`, replacing the items with the same IDs, the shards returned by
+    /// `required_for_remove` for both IDs of the item must be locked.
+    fn insert(&mut self, item: V) -> Vec<V> {
+        let dict = self.dict;
+        let replaced = self
+            .remove(MappingId::Matrix(item.as_matrix()))
+            .into_iter()
+            .chain(self.remove(MappingId::External(item.as_external())))
+            .collect();
+
+        self.get(dict.shard_of(item.as_external()))
+            .external_to_matrix
+            .insert(item.as_external().to_owned(), item.as_matrix().to_owned());
+        self.get(dict.shard_of(item.as_matrix()))
+            .items
+            .insert(item.as_matrix().to_owned(), item);
+
+        replaced
+    }
+
+    /// Remove the item associated with the given `identifier`, the shards returned by
+    /// `required_for_remove` must be locked.
+    fn remove(
+        &mut self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+    ) -> Option<V> {
+        let dict = self.dict;
+        match identifier {
+            MappingId::Matrix(m) => {
+                let item = self.get(dict.shard_of(m)).items.remove(m)?;
+                self.get(dict.shard_of(item.as_external()))
+                    .external_to_matrix
+                    .remove(item.as_external());
+                Some(item)
+            }
+            MappingId::External(e) => {
+                let m = self.get(dict.shard_of(e)).external_to_matrix.remove(e)?;
+                let m: &V::MatrixReference = m.borrow();
+                self.get(dict.shard_of(m)).items.remove(m)
+            }
+        }
+    }
+}
+
+impl<V> ConcurrentMappingDict<V>
+where
+    V: Mappable,
+{
+    /// Create a new empty `ConcurrentMappingDict`.
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Create a new empty `ConcurrentMappingDict` using the given amount of shards.
+    /// More shards means less contention between threads, at the cost of some memory.
+    ///
+    /// # Panics
+    /// Panics if `shards` is zero.
+    pub fn with_shards(shards: usize) -> Self {
+        assert!(
+            shards > 0,
+            "a ConcurrentMappingDict needs at least one shard"
+        );
+
+        Self {
+            shards: (0..shards).map(|_| RwLock::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard_of<Q: ?Sized + Hash>(&self, key: &Q) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    fn read(&self, index: usize) -> RwLockReadGuard<'_, Shard<V>> {
+        self.shards[index]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self, index: usize) -> RwLockWriteGuard<'_, Shard<V>> {
+        self.shards[index]
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the shards in `indices`, and everything `required` returns for them, which may only
+    /// look into the shards in `indices`.
+    fn lock<F>(&self, mut indices: Vec<usize>, required: F) -> LockedShards<'_, V>
+    where
+        F: Fn(&mut LockedShards<'_, V>) -> Vec<usize>,
+    {
+        loop {
+            indices.sort_unstable();
+            indices.dedup();
+
+            let mut locked = LockedShards {
+                dict: self,
+                guards: indices.iter().map(|i| (*i, self.write(*i))).collect(),
+            };
+
+            let missing: Vec<_> = required(&mut locked)
+                .into_iter()
+                .filter(|i| !locked.contains(*i))
+                .collect();
+            if missing.is_empty() {
+                return locked;
+            }
+
+            // the other shards of the items are only known now, retry while locking those too.
+            drop(locked);
+            indices.extend(missing);
+        }
+    }
+
+    /// Returns the Matrix ID currently mapped to the external ID `e`.
+    fn matrix_id_of(&self, e: &V::ExternalReference) -> Option<V::MatrixType> {
+        let shard = self.read(self.shard_of(e));
+        let m: &V::MatrixReference = shard.external_to_matrix.get(e)?.borrow();
+        Some(m.to_owned())
+    }
+
+    /// Lock all shards needed to insert `item`.
+    fn lock_for_insert(&self, item: &V) -> LockedShards<'_, V> {
+        self.lock(
+            vec![
+                self.shard_of(item.as_matrix()),
+                self.shard_of(item.as_external()),
+            ],
+            |locked| {
+                let mut res = locked.required_for_remove(MappingId::Matrix(item.as_matrix()));
+                res.extend(locked.required_for_remove(MappingId::External(item.as_external())));
+                res
+            },
+        )
+    }
+
+    /// Inserts the given `item` in the current `ConcurrentMappingDict`, replacing any items that
+    /// have the same Matrix ID or external ID.
+    ///
+    /// Returns the replaced items.
+    pub fn insert(&self, item: V) -> Vec<V> {
+        self.lock_for_insert(&item).insert(item)
+    }
+
+    /// Returns a clone of the item associated with the given `identifier`, inserting the result
+    /// of `default` if there is none.
+    ///
+    /// This is atomic: if multiple tasks race to create the same item, all of them get the item
+    /// that was inserted first, and the other created items are dropped.
+    ///
+    /// # Panics
+    /// Panics if the created item doesn't have the given `identifier`.
+    pub fn get_or_insert_with<F>(
+        &self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+        default: F,
+    ) -> V
+    where
+        V: Clone,
+        F: FnOnce() -> V,
+    {
+        if let Some(item) = self.get(identifier.clone()) {
+            return item;
+        }
+
+        let item = default();
+        let matches = match identifier {
+            MappingId::Matrix(m) => item.as_matrix() == m,
+            MappingId::External(e) => item.as_external() == e,
+        };
+        assert!(matches, "created item should have the given ID");
+
+        // the item could have been inserted while `default` was running.
+        let mut locked = self.lock_for_insert(&item);
+        if let Some(existing) = locked.get_item(identifier) {
+            return existing.clone();
+        }
+
+        locked.insert(item.clone());
+        item
+    }
+
+    /// Returns a clone of the item associated with the given `identifier`, or `None` if no such
+    /// item exists.
+    pub fn get(&self, identifier: MappingId<V::ExternalReference, V::MatrixReference>) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.with(identifier, V::clone)
+    }
+
+    /// Run `f` with a reference to the item associated with the given `identifier`, returning its
+    /// result, or `None` if no such item exists.
+    ///
+    /// The shard containing the item is locked while `f` runs, so it should not access this
+    /// `ConcurrentMappingDict`.
+    pub fn with<R, F>(
+        &self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+        f: F,
+    ) -> Option<R>
+    where
+        F: FnOnce(&V) -> R,
+    {
+        match identifier {
+            MappingId::Matrix(m) => self.read(self.shard_of(m)).items.get(m).map(f),
+            MappingId::External(e) => loop {
+                let m = self.matrix_id_of(e)?;
+                let m: &V::MatrixReference = m.borrow();
+
+                let shard = self.read(self.shard_of(m));
+                match shard.items.get(m) {
+                    Some(item) if item.as_external() == e => return Some(f(item)),
+                    // the item was changed in between, look it up again.
+                    _ => continue,
+                }
+            },
+        }
+    }
+
+    /// Run `f` with a mutable reference to the item associated with the given `identifier`,
+    /// returning its result, or `None` if no such item exists.
+    ///
+    /// `f` must not change the IDs of the item. The shard containing the item is locked while `f`
+    /// runs, so it should not access this `ConcurrentMappingDict`.
+    pub fn with_mut<R, F>(
+        &self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+        f: F,
+    ) -> Option<R>
+    where
+        F: FnOnce(&mut V) -> R,
+    {
+        match identifier {
+            MappingId::Matrix(m) => self.write(self.shard_of(m)).items.get_mut(m).map(f),
+            MappingId::External(e) => loop {
+                let m = self.matrix_id_of(e)?;
+                let m: &V::MatrixReference = m.borrow();
+
+                let mut shard = self.write(self.shard_of(m));
+                match shard.items.get_mut(m) {
+                    Some(item) if item.as_external() == e => return Some(f(item)),
+                    _ => continue,
+                }
+            },
+        }
+    }
+
+    /// Returns whether or not this `ConcurrentMappingDict` contains an item associated with the
+    /// given `identifier`.
+    pub fn has(&self, identifier: MappingId<V::ExternalReference, V::MatrixReference>) -> bool {
+        match identifier {
+            MappingId::Matrix(m) => self.read(self.shard_of(m)).items.contains_key(m),
+            MappingId::External(e) => self
+                .read(self.shard_of(e))
+                .external_to_matrix
+                .contains_key(e),
+        }
+    }
+
+    /// If this `ConcurrentMappingDict` contains an item associated with the given `identifier`,
+    /// remove it and return it.
+    /// If no such item exists, this function returns `None`.
+    pub fn remove(
+        &self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+    ) -> Option<V> {
+        let first = match identifier {
+            MappingId::Matrix(m) => self.shard_of(m),
+            MappingId::External(e) => self.shard_of(e),
+        };
+
+        self.lock(vec![first], |locked| {
+            locked.required_for_remove(identifier.clone())
+        })
+        .remove(identifier)
+    }
+
+    /// Returns the amount of items in this `ConcurrentMappingDict`.
+    /// Since other threads can change the items while counting, this is only a snapshot.
+    pub fn len(&self) -> usize {
+        (0..self.shards.len())
+            .map(|i| self.read(i).items.len())
+            .sum()
+    }
+
+    /// Returns whether this `ConcurrentMappingDict` contains no items.
+    pub fn is_empty(&self) -> bool {
+        (0..self.shards.len()).all(|i| self.read(i).items.is_empty())
+    }
+
+    /// Run `f` for every item in this `ConcurrentMappingDict`.
+    /// Only one shard is locked at a time, so items inserted or removed meanwhile may or may not
+    /// be visited.
+    pub fn for_each<F: FnMut(&V)>(&self, mut f: F) {
+        for i in 0..self.shards.len() {
+            self.read(i).items.values().for_each(&mut f);
+        }
+    }
+
+    /// Returns clones of all items in this `ConcurrentMappingDict`, in no particular order.
+    pub fn to_vec(&self) -> Vec<V>
+    where
+        V: Clone,
+    {
+        let mut res = Vec::with_capacity(self.len());
+        self.for_each(|item| res.push(item.clone()));
+        res
+    }
+
+    /// Convert this `ConcurrentMappingDict` into a `MappingDict` containing the same items.
+    pub fn into_dict(self) -> MappingDict<V> {
+        let items = self
+            .shards
+            .into_vec()
+            .into_iter()
+            .flat_map(|shard| {
+                shard
+                    .into_inner()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .items
+                    .into_values()
+            })
+            .collect();
+        MappingDict::from_vec(items)
+    }
+}
+
+impl<V: Mappable> Default for ConcurrentMappingDict<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V: Mappable> From<MappingDict<V>> for ConcurrentMappingDict<V> {
+    fn from(dict: MappingDict<V>) -> Self {
+        let res = Self::new();
+        for item in dict {
+            res.insert(item);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{ConcurrentMappingDict, Mappable, MappingDict, MappingId};
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Item {
+        matrix: String,
+        external: u64,
+        value: u64,
+    }
+
+    impl Item {
+        fn new(matrix: &str, external: u64) -> Self {
+            Self {
+                matrix: matrix.to_string(),
+                external,
+                value: 0,
+            }
+        }
+    }
+
+    impl Mappable for Item {
+        type MatrixReference = str;
+        type MatrixType = String;
+        type ExternalReference = u64;
+        type ExternalType = u64;
+
+        fn as_matrix(&self) -> &str {
+            &self.matrix
+        }
+        fn into_matrix(self) -> String {
+            self.matrix
+        }
+        fn as_external(&self) -> &u64 {
+            &self.external
+        }
+        fn into_external(self) -> u64 {
+            self.external
+        }
+        fn into_split(self) -> (String, u64) {
+            (self.matrix, self.external)
+        }
+    }
+
+    #[test]
+    fn test_dual_keys() {
+        let dict = ConcurrentMappingDict::with_shards(4);
+        assert!(dict.insert(Item::new("!a", 1)).is_empty());
+        assert!(dict.insert(Item::new("!b", 2)).is_empty());
+        assert_eq!(dict.len(), 2);
+
+        assert_eq!(dict.get(MappingId::External(&2)), Some(Item::new("!b", 2)));
+        assert_eq!(dict.get(MappingId::Matrix("!a")), Some(Item::new("!a", 1)));
+        assert_eq!(
+            dict.with(MappingId::External(&1), |item| item.matrix.clone()),
+            Some("!a".to_string())
+        );
+
+        // replaces both items, which have their IDs in different places.
+        let mut replaced = dict.insert(Item::new("!a", 2));
+        replaced.sort_by_key(|item| item.external);
+        assert_eq!(replaced, vec![Item::new("!a", 1), Item::new("!b", 2)]);
+        assert_eq!(dict.len(), 1);
+        assert!(!dict.has(MappingId::External(&1)));
+        assert!(!dict.has(MappingId::Matrix("!b")));
+
+        dict.with_mut(MappingId::External(&2), |item| item.value = 5);
+        assert_eq!(dict.get(MappingId::Matrix("!a")).unwrap().value, 5);
+
+        assert_eq!(dict.remove(MappingId::External(&2)).unwrap().value, 5);
+        assert_eq!(dict.remove(MappingId::Matrix("!a")), None);
+        assert!(dict.is_empty());
+    }
+
+    #[test]
+    fn test_threads() {
+        let dict = Arc::new(ConcurrentMappingDict::new());
+
+        let handles: Vec<_> = (0..4u64)
+            .map(|t| {
+                let dict = dict.clone();
+                std::thread::spawn(move || {
+                    for i in 0..250 {
+                        let id = t * 1000 + i;
+                        dict.insert(Item::new(&format!("!{}", id), id));
+                        if i % 2 == 0 {
+                            assert!(dict.remove(MappingId::External(&id)).is_some());
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(dict.len(), 500);
+        assert_eq!(
+            dict.get(MappingId::Matrix("!3001")),
+            Some(Item::new("!3001", 3001))
+        );
+        assert!(!dict.has(MappingId::External(&3000)));
+    }
+
+    #[test]
+    fn test_into_dict() {
+        let dict = ConcurrentMappingDict::from(MappingDict::from_vec(vec![
+            Item::new("!a", 1),
+            Item::new("!b", 2),
+        ]));
+        let dict = dict.into_dict();
+        assert_eq!(dict.get(MappingId::External(&2)), Some(&Item::new("!b", 2)));
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let dict = Arc::new(ConcurrentMappingDict::new());
+
+        let handles: Vec<_> = (0..8u64)
+            .map(|t| {
+                let dict = dict.clone();
+                std::thread::spawn(move || {
+                    dict.get_or_insert_with(MappingId::External(&1), || Item {
+                        value: t,
+                        ..Item::new("!a", 1)
+                    })
+                })
+            })
+            .collect();
+        let items: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(dict.len(), 1);
+        let item = dict.get(MappingId::Matrix("!a")).unwrap();
+        assert!(items.iter().all(|i| i == &item));
+    }
+}
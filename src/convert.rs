@@ -1,24 +1,381 @@
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+
+use ruma::identifiers::UserId;
+
+use crate::error::Error;
+use crate::mappingdict::{Mappable, MappingDict, MappingId};
+use crate::matrix::MatrixToItem;
+
+#[cfg(feature = "emoji")]
+pub mod emoji {
+    use std::borrow::Cow;
+
+    fn is_regional_indicator(c: char) -> bool {
+        matches!(c, '\u{1F1E6}'..='\u{1F1FF}')
+    }
+
+    /// Whether `c` continues an emoji sequence of which `prev` is the last character.
+    fn continues_sequence(prev: char, c: char) -> bool {
+        prev == '\u{200D}'
+            || matches!(
+                c,
+                '\u{200D}'
+                    | '\u{FE0E}'
+                    | '\u{FE0F}'
+                    | '\u{20E3}'
+                    | '\u{1F3FB}'..='\u{1F3FF}'
+                    | '\u{E0020}'..='\u{E007F}'
+            )
+            || (is_regional_indicator(prev) && is_regional_indicator(c))
+    }
+
+    /// Write the given sequence of characters to `out`, replacing the longest known emoji at
+    /// every position with its shortcode.  Skin tones are dropped.
+    fn push_sequence(seq: &str, out: &mut String) {
+        let mut rest = seq;
+        while let Some(first) = rest.chars().next() {
+            let found = rest
+                .char_indices()
+                .map(|(i, c)| i + c.len_utf8())
+                .rev()
+                .find_map(|end| {
+                    let emoji = emojis::get(&rest[..end])?;
+                    // emoji with a skin tone don't have a shortcode of their own.
+                    let shortcode = emoji
+                        .shortcode()
+                        .or_else(|| emoji.with_skin_tone(emojis::SkinTone::Default)?.shortcode())?;
+                    Some((end, shortcode))
+                });
+
+            match found {
+                Some((end, shortcode)) => {
+                    out.push(':');
+                    out.push_str(shortcode);
+                    out.push(':');
+                    rest = &rest[end..];
+                }
+                None => {
+                    out.push(first);
+                    rest = &rest[first.len_utf8()..];
+                }
+            }
+        }
+    }
+
+    /// Replaces emoji with their shortcodes in text that is written one character at a time.
+    #[derive(Default)]
+    pub(crate) struct ShortcodeEncoder {
+        /// The possible emoji sequence currently being read.
+        seq: String,
+    }
+
+    impl ShortcodeEncoder {
+        pub(crate) fn push(&mut self, c: char, out: &mut String) {
+            if let Some(prev) = self.seq.chars().last() {
+                if continues_sequence(prev, c) {
+                    self.seq.push(c);
+                    return;
+                }
+                self.finish(out);
+            }
+
+            if c.is_ascii() {
+                out.push(c);
+            } else {
+                self.seq.push(c);
+            }
+        }
+
+        pub(crate) fn finish(&mut self, out: &mut String) {
+            push_sequence(&self.seq, out);
+            self.seq.clear();
+        }
+    }
+
+    /// Replace the `:shortcode:`s in `s` with the Unicode emoji, leaving unknown shortcodes as
+    /// is.
+    pub fn shortcodes_to_unicode(s: &str) -> Cow<'_, str> {
+        let mut res = String::new();
+        let mut replaced = false;
+        let mut last = 0;
+
+        let mut i = 0;
+        while let Some(k) = s[i..].find(':') {
+            let start = i + k;
+            let name_len = s[start + 1..]
+                .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-')))
+                .unwrap_or(s.len() - start - 1);
+            let end = start + 1 + name_len;
+
+            if name_len == 0 {
+                i = start + 1;
+                continue;
+            }
+            // the closing colon could also open the next shortcode.
+            i = end;
+
+            if !s[end..].starts_with(':') {
+                continue;
+            }
+            if let Some(emoji) = emojis::get_by_shortcode(&s[start + 1..end]) {
+                res.push_str(&s[last..start]);
+                res.push_str(emoji.as_str());
+                replaced = true;
+                last = end + 1;
+                i = end + 1;
+            }
+        }
+
+        if !replaced {
+            return Cow::Borrowed(s);
+        }
+        res.push_str(&s[last..]);
+        Cow::Owned(res)
+    }
+
+    /// Replace the Unicode emoji in `s` with their `:shortcode:`, for networks that can't render
+    /// them.  Keycap sequences like 1\u{FE0F}\u{20E3} are not replaced.
+    pub fn unicode_to_shortcodes(s: &str) -> Cow<'_, str> {
+        if s.is_ascii() {
+            return Cow::Borrowed(s);
+        }
+
+        let mut encoder = ShortcodeEncoder::default();
+        let mut res = String::with_capacity(s.len());
+        for c in s.chars() {
+            encoder.push(c, &mut res);
+        }
+        encoder.finish(&mut res);
+        Cow::Owned(res)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use crate::convert::emoji::{shortcodes_to_unicode, unicode_to_shortcodes};
+
+        #[test]
+        fn test_shortcodes_to_unicode() {
+            assert_eq!("hi 😄!", shortcodes_to_unicode("hi :smile:!"));
+            assert_eq!("👍👍", shortcodes_to_unicode(":+1::+1:"));
+            assert_eq!(
+                "12:30:45 :nope: a:🚀",
+                shortcodes_to_unicode("12:30:45 :nope: a::rocket:")
+            );
+        }
+
+        #[test]
+        fn test_unicode_to_shortcodes() {
+            assert_eq!("hi :smile:!", unicode_to_shortcodes("hi 😄!"));
+            assert_eq!("caf\u{e9} :+1:", unicode_to_shortcodes("caf\u{e9} 👍"));
+            assert_eq!(
+                ":netherlands::de:",
+                unicode_to_shortcodes("\u{1F1F3}\u{1F1F1}\u{1F1E9}\u{1F1EA}")
+            );
+            assert_eq!(":wave:", unicode_to_shortcodes("👋🏼"));
+        }
+    }
+}
+
 pub mod to_external {
     use std::borrow::Cow;
-    use std::collections::HashMap;
+    use std::cell::RefCell;
+    use std::collections::{HashMap, VecDeque};
     use std::convert::TryFrom;
+    use std::future::Future;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
 
-    use lol_html::rewrite_str;
-    use ruma::identifiers::{RoomAliasId, UserId};
+    use futures::future::{join, join_all};
+    use lol_html::{rewrite_str, DocumentContentHandlers, HtmlRewriter};
+    use ruma::api::exports::http::Uri;
+    use ruma::identifiers::{EventId, MxcUri, RoomAliasId, RoomIdOrAliasId, UserId};
+
+    #[cfg(feature = "emoji")]
+    use crate::convert::emoji::ShortcodeEncoder;
+    use crate::error::Error;
+    use crate::matrix::mxc_to_url;
 
     pub use lol_html::{
         html_content::{ContentType, Element},
         ElementContentHandlers, Settings,
     };
 
-    type UserMapper<'a> = &'a dyn Fn(UserId, &Info) -> Option<String>;
-    type RoomMapper<'a> = &'a dyn Fn(RoomAliasId, &Info) -> Option<String>;
-    type ElementClosure<'a> = &'a dyn Fn(&mut Element<'_, '_>, &Info);
+    type UserMapper<'a> = Box<dyn Fn(UserId, &Info) -> Option<String> + Send + Sync + 'a>;
+    type RoomMapper<'a> = Box<dyn Fn(RoomAliasId, &Info) -> Option<String> + Send + Sync + 'a>;
+    type ElementClosure<'a> = Box<dyn Fn(&mut Element<'_, '_>, &Info) + Send + Sync + 'a>;
+    type ListClosure<'a> = Box<dyn Fn(&mut Element<'_, '_>, &List, &Info) + Send + Sync + 'a>;
+    type ListItemClosure<'a> =
+        Box<dyn Fn(&mut Element<'_, '_>, &ListItem, &Info) + Send + Sync + 'a>;
+    type TableRowClosure<'a> =
+        Box<dyn Fn(&mut Element<'_, '_>, &TableRow, &Info) + Send + Sync + 'a>;
+    type SpoilerClosure<'a> = Box<dyn Fn(&mut Element<'_, '_>, &Spoiler, &Info) + Send + Sync + 'a>;
+    type ImageClosure<'a> = Box<dyn Fn(&mut Element<'_, '_>, &Image, &Info) + Send + Sync + 'a>;
+    type ColorClosure<'a> = Box<dyn Fn(&mut Element<'_, '_>, &Colors, &Info) + Send + Sync + 'a>;
+    type CodeBlockClosure<'a> =
+        Box<dyn Fn(&mut Element<'_, '_>, &CodeBlock, &Info) + Send + Sync + 'a>;
+
+    /// Information about an `<ul>` or `<ol>` element, given to the list handler.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct List {
+        /// The amount of lists this list is nested in, 0 for a top-level list.
+        pub depth: usize,
+        /// Whether this list is an ordered (`<ol>`) list.
+        pub ordered: bool,
+    }
+
+    /// Information about a `<li>` element, given to the list item handler.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ListItem {
+        /// The amount of lists the list containing this item is nested in, 0 for an item of a
+        /// top-level list.
+        pub depth: usize,
+        /// The number of this item if it is part of an ordered list, respecting the `start`
+        /// attribute of the list.
+        pub number: Option<u64>,
+    }
+
+    /// Information about a `<tr>` element, given to the table row handler.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct TableRow {
+        /// The index of this row in its table, starting at 0.
+        pub index: usize,
+        /// If the previous row in the table was a header row (containing `<th>` cells), the
+        /// amount of cells in that row.
+        pub header_columns: Option<usize>,
+    }
+
+    /// Information about a spoiler (an element with the `data-mx-spoiler` attribute), given to
+    /// the spoiler handler.
+    ///
+    /// The body of the spoiler is the content of the element, which is converted like the rest of
+    /// the message.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Spoiler {
+        /// The reason given for the spoiler, if any.
+        pub reason: Option<String>,
+    }
+
+    /// Information about an `<img>` element, given to the image handler.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Image {
+        /// The MXC URI of the image, or `None` if the image has no valid MXC URI as source.
+        pub src: Option<MxcUri>,
+        /// The alternative text of the image.
+        pub alt: Option<String>,
+        /// The title of the image.
+        pub title: Option<String>,
+        /// The width of the image, in pixels.
+        pub width: Option<u32>,
+        /// The height of the image, in pixels.
+        pub height: Option<u32>,
+        /// Whether the image is a custom emote (has the `data-mx-emoticon` attribute).
+        pub emoticon: bool,
+    }
+
+    impl Image {
+        fn from_element(el: &Element) -> Self {
+            let dimension = |name| el.get_attribute(name).and_then(|v| v.trim().parse().ok());
+            Self {
+                src: el
+                    .get_attribute("src")
+                    .map(MxcUri::from)
+                    .filter(|src| src.is_valid()),
+                alt: el.get_attribute("alt"),
+                title: el.get_attribute("title"),
+                width: dimension("width"),
+                height: dimension("height"),
+                emoticon: el.has_attribute("data-mx-emoticon"),
+            }
+        }
+
+        /// Get the HTTP URL of the image, downloaded through the homeserver configured in the
+        /// given `info`.
+        pub fn http_url(&self, info: &Info) -> Option<Uri> {
+            let homeserver_url = info.homeserver_url.as_ref()?;
+            mxc_to_url(homeserver_url, self.src.as_ref()?).ok()
+        }
+    }
+
+    /// An RGB color.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Color {
+        /// The red component of the color.
+        pub red: u8,
+        /// The green component of the color.
+        pub green: u8,
+        /// The blue component of the color.
+        pub blue: u8,
+    }
+
+    impl Color {
+        /// Parse a color in the `#rrggbb` format, returning `None` if the given string is not in
+        /// that format.
+        pub fn from_hex(s: &str) -> Option<Self> {
+            let hex = s.trim().strip_prefix('#')?;
+            if hex.len() != 6 {
+                return None;
+            }
+            let component = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+            Some(Self {
+                red: component(0)?,
+                green: component(2)?,
+                blue: component(4)?,
+            })
+        }
+    }
+
+    /// The colors of a `<font>` or `<span>` element, given to the color handler.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Colors {
+        /// The text color, from the `data-mx-color` or `color` attribute.
+        pub foreground: Option<Color>,
+        /// The background color, from the `data-mx-bg-color` attribute.
+        pub background: Option<Color>,
+    }
+
+    impl Colors {
+        fn from_element(el: &Element) -> Option<Self> {
+            let color = |name| el.get_attribute(name).and_then(|c| Color::from_hex(&c));
+            let colors = Self {
+                foreground: color("data-mx-color").or_else(|| color("color")),
+                background: color("data-mx-bg-color"),
+            };
+
+            if colors.foreground.is_none() && colors.background.is_none() {
+                None
+            } else {
+                Some(colors)
+            }
+        }
+    }
+
+    /// Information about a code block (a `<pre>` element), given to the code block handler.
+    #[derive(Debug, Clone, PartialEq, Eq, Default)]
+    pub struct CodeBlock {
+        /// The language of the code, from the `language-` class of the `<code>` element.
+        pub language: Option<String>,
+        /// The text content of the code block.
+        pub content: String,
+    }
 
     pub struct Info<'a> {
         user_mapper: UserMapper<'a>,
         room_mapper: RoomMapper<'a>,
         element_handlers: HashMap<String, ElementClosure<'a>>,
+        list_handler: Option<ListClosure<'a>>,
+        list_item_handler: Option<ListItemClosure<'a>>,
+        table_row_handler: Option<TableRowClosure<'a>>,
+        spoiler_handler: Option<SpoilerClosure<'a>>,
+        image_handler: Option<ImageClosure<'a>>,
+        color_handler: Option<ColorClosure<'a>>,
+        code_block_handler: Option<CodeBlockClosure<'a>>,
+        homeserver_url: Option<Uri>,
+        trim_output: bool,
+        decode_entities: bool,
+        /// Whether the handlers mark line prefixes, see `line_prefix`.
+        line_prefixes: bool,
+        #[cfg(feature = "emoji")]
+        emoji_shortcodes: bool,
     }
 
     pub fn generate_user_mapper_from_hashmap(
@@ -36,27 +393,147 @@ pub mod to_external {
     impl<'a> Info<'a> {
         pub fn new() -> Self {
             Self {
-                user_mapper: &|_: UserId, _: &Info| None,
-                room_mapper: &|_: RoomAliasId, _: &Info| None,
+                user_mapper: Box::new(|_: UserId, _: &Info| None),
+                room_mapper: Box::new(|_: RoomAliasId, _: &Info| None),
                 element_handlers: HashMap::new(),
+                list_handler: None,
+                list_item_handler: None,
+                table_row_handler: None,
+                spoiler_handler: None,
+                image_handler: None,
+                color_handler: None,
+                code_block_handler: None,
+                homeserver_url: None,
+                trim_output: false,
+                decode_entities: false,
+                line_prefixes: false,
+                #[cfg(feature = "emoji")]
+                emoji_shortcodes: false,
             }
         }
 
-        pub fn user_mapper(&mut self, f: &'a dyn Fn(UserId, &Info) -> Option<String>) -> &mut Self {
-            self.user_mapper = f;
+        pub fn user_mapper<F>(&mut self, f: F) -> &mut Self
+        where
+            F: Fn(UserId, &Info) -> Option<String> + Send + Sync + 'a,
+        {
+            self.user_mapper = Box::new(f);
+            self
+        }
+
+        pub fn room_mapper<F>(&mut self, f: F) -> &mut Self
+        where
+            F: Fn(RoomAliasId, &Info) -> Option<String> + Send + Sync + 'a,
+        {
+            self.room_mapper = Box::new(f);
+            self
+        }
+
+        pub fn add_element_handler<F>(&mut self, element: String, f: F) -> &mut Self
+        where
+            F: Fn(&mut Element<'_, '_>, &Info) + Send + Sync + 'a,
+        {
+            self.element_handlers.insert(element, Box::new(f));
+            self
+        }
+
+        /// Set the handler called for `<ul>` and `<ol>` elements that have no element handler.
+        pub fn list_handler<F>(&mut self, f: F) -> &mut Self
+        where
+            F: Fn(&mut Element<'_, '_>, &List, &Info) + Send + Sync + 'a,
+        {
+            self.list_handler = Some(Box::new(f));
             self
         }
 
-        pub fn room_mapper(
-            &mut self,
-            f: &'a dyn Fn(RoomAliasId, &Info) -> Option<String>,
-        ) -> &mut Self {
-            self.room_mapper = f;
+        /// Set the handler called for `<li>` elements that have no element handler.
+        pub fn list_item_handler<F>(&mut self, f: F) -> &mut Self
+        where
+            F: Fn(&mut Element<'_, '_>, &ListItem, &Info) + Send + Sync + 'a,
+        {
+            self.list_item_handler = Some(Box::new(f));
             self
         }
 
-        pub fn add_element_handler(&mut self, element: String, f: ElementClosure<'a>) -> &mut Self {
-            self.element_handlers.insert(element, f);
+        /// Set the handler called for `<tr>` elements that have no element handler.
+        pub fn table_row_handler<F>(&mut self, f: F) -> &mut Self
+        where
+            F: Fn(&mut Element<'_, '_>, &TableRow, &Info) + Send + Sync + 'a,
+        {
+            self.table_row_handler = Some(Box::new(f));
+            self
+        }
+
+        /// Set the handler called for spoilers, elements with the `data-mx-spoiler` attribute.
+        /// The spoiler handler takes precedence over element handlers.
+        pub fn spoiler_handler<F>(&mut self, f: F) -> &mut Self
+        where
+            F: Fn(&mut Element<'_, '_>, &Spoiler, &Info) + Send + Sync + 'a,
+        {
+            self.spoiler_handler = Some(Box::new(f));
+            self
+        }
+
+        /// Set the handler called for `<img>` elements that have no element handler.
+        ///
+        /// Without an image handler, images are replaced by their alternative text, followed by
+        /// their HTTP URL if a homeserver URL is set and the image is not a custom emote.
+        pub fn image_handler<F>(&mut self, f: F) -> &mut Self
+        where
+            F: Fn(&mut Element<'_, '_>, &Image, &Info) + Send + Sync + 'a,
+        {
+            self.image_handler = Some(Box::new(f));
+            self
+        }
+
+        /// Set the handler called for `<font>` and `<span>` elements that have a color set. The
+        /// color handler takes precedence over element handlers.
+        ///
+        /// Without a color handler, colors are dropped.
+        pub fn color_handler<F>(&mut self, f: F) -> &mut Self
+        where
+            F: Fn(&mut Element<'_, '_>, &Colors, &Info) + Send + Sync + 'a,
+        {
+            self.color_handler = Some(Box::new(f));
+            self
+        }
+
+        /// Set the handler called for `<pre>` elements that have no element handler.
+        ///
+        /// Elements inside a code block are always stripped, so the content of the element
+        /// handled is the plain text of the code.
+        pub fn code_block_handler<F>(&mut self, f: F) -> &mut Self
+        where
+            F: Fn(&mut Element<'_, '_>, &CodeBlock, &Info) + Send + Sync + 'a,
+        {
+            self.code_block_handler = Some(Box::new(f));
+            self
+        }
+
+        /// Set the URL of the homeserver used to convert MXC URIs into HTTP URLs.
+        pub fn homeserver_url(&mut self, url: Uri) -> &mut Self {
+            self.homeserver_url = Some(url);
+            self
+        }
+
+        /// Set whether to trim newlines surrounding the output, and collapse runs of blank lines
+        /// in the output into a single blank line.
+        pub fn trim_output(&mut self, trim: bool) -> &mut Self {
+            self.trim_output = trim;
+            self
+        }
+
+        /// Set whether to decode HTML character references (like `&amp;`) in the output, for
+        /// when the output is not interpreted as HTML or markdown.
+        pub fn decode_entities(&mut self, decode: bool) -> &mut Self {
+            self.decode_entities = decode;
+            self
+        }
+
+        /// Set whether to replace Unicode emoji in the output with their `:shortcode:`, for
+        /// networks that can't render them.
+        #[cfg(feature = "emoji")]
+        pub fn emoji_shortcodes(&mut self, emoji_shortcodes: bool) -> &mut Self {
+            self.emoji_shortcodes = emoji_shortcodes;
             self
         }
     }
@@ -67,7 +544,14 @@ pub mod to_external {
         }
     }
 
-    fn stringify_a_tag(el: &mut Element, info: &Info) {
+    /// Mentions of a message that were resolved up front, see `convert_async`.
+    #[derive(Default)]
+    struct ResolvedMentions {
+        users: HashMap<UserId, Option<String>>,
+        rooms: HashMap<RoomAliasId, Option<String>>,
+    }
+
+    fn stringify_a_tag(el: &mut Element, info: &Info, resolved: Option<&ResolvedMentions>) {
         let normal = |el: &mut Element, url: Option<String>| match info.element_handlers.get("a") {
             Some(f) => {
                 f(el, info);
@@ -93,261 +577,2936 @@ pub mod to_external {
         };
 
         let s = match mentioned.chars().next() {
-            Some('@') => {
-                let mentioned = UserId::try_from(mentioned).unwrap();
-                (info.user_mapper)(mentioned, info)
-            }
-            Some('#') => {
-                let room = RoomAliasId::try_from(mentioned).unwrap();
-                (info.room_mapper)(room, info)
-            }
+            // malformed IDs are written as plain links.
+            Some('@') => match (UserId::try_from(mentioned).ok(), resolved) {
+                (Some(mentioned), Some(resolved)) => {
+                    resolved.users.get(&mentioned).cloned().flatten()
+                }
+                (Some(mentioned), None) => (info.user_mapper)(mentioned, info),
+                (None, _) => None,
+            },
+            Some('#') => match (RoomAliasId::try_from(mentioned).ok(), resolved) {
+                (Some(room), Some(resolved)) => resolved.rooms.get(&room).cloned().flatten(),
+                (Some(room), None) => (info.room_mapper)(room, info),
+                (None, _) => None,
+            },
             _ => None,
         };
 
         if let Some(s) = s {
-            el.replace(&s, ContentType::Html)
+            el.replace(&remove_markers(&s, info), ContentType::Html)
         } else {
             normal(el, Some(href))
         }
     }
 
-    pub fn convert(s: &str, info: &Info) -> Result<String, &'static str> {
-        let mut settings = Settings::default();
-
-        settings.element_content_handlers = vec![
-            ((
-                Cow::Owned("body".parse().unwrap()),
-                ElementContentHandlers::default().comments(|c| {
-                    c.remove();
-                    Ok(())
-                }),
-            )),
-            ((
-                Cow::Owned("a".parse().unwrap()),
-                ElementContentHandlers::default().element(|e| {
-                    stringify_a_tag(e, info);
-                    Ok(())
-                }),
-            )),
-            ((
-                Cow::Owned("*".parse().unwrap()),
-                ElementContentHandlers::default().element(|e| {
-                    let tag = e.tag_name();
-                    if let Some(handler) = info.element_handlers.get(&tag) {
-                        handler(e, info);
-                    } else {
-                        e.remove_and_keep_content();
-                    }
-                    Ok(())
-                }),
-            )),
-        ];
+    /// Conversion state that has to be kept track of while walking the HTML tree.
+    #[derive(Default)]
+    struct State {
+        /// For every list we're currently in, the next item number if it's an ordered list.
+        lists: Vec<Option<u64>>,
+        tables: Vec<TableState>,
+        pre_depth: usize,
+        /// The code blocks in the document that are not handled yet.
+        code_blocks: VecDeque<CodeBlock>,
+    }
 
-        Ok(rewrite_str(s, settings).unwrap())
+    #[derive(Default)]
+    struct TableState {
+        rows: usize,
+        cells: usize,
+        header: bool,
     }
 
-    #[cfg(test)]
-    mod tests {
-        use std::collections::HashMap;
-        use std::convert::TryFrom;
+    fn handle_element(el: &mut Element, info: &Info, state: &Rc<RefCell<State>>) {
+        let tag = el.tag_name();
 
-        use lol_html::html_content::ContentType;
-        use ruma::identifiers::{user_id, RoomAliasId, UserId};
+        // anchors are handled by `stringify_a_tag`.
+        if tag == "a" {
+            return;
+        }
 
-        use crate::convert::to_external::{convert, Element, Info};
+        // elements in preformatted text are only used for markup, like syntax highlighting.
+        if state.borrow().pre_depth > 0 {
+            el.remove_and_keep_content();
+            return;
+        }
 
-        #[test]
-        fn test_stripping() {
-            let info = Info::new();
+        match tag.as_str() {
+            "th" | "td" => {
+                if let Some(table) = state.borrow_mut().tables.last_mut() {
+                    table.cells += 1;
+                    table.header |= tag == "th";
+                }
+            }
+            _ => {}
+        }
 
-            let before = "<b>kaas</b>".to_string();
-            let after = convert(&before, &info).unwrap();
-            assert_eq!(after, "kaas");
+        let spoiler = match &info.spoiler_handler {
+            Some(f) if el.has_attribute("data-mx-spoiler") => Some(f),
+            _ => None,
+        };
+
+        let colors = match (&info.color_handler, tag.as_str()) {
+            (Some(f), "font" | "span") => Colors::from_element(el).map(|colors| (f, colors)),
+            _ => None,
+        };
+
+        if let Some(f) = spoiler {
+            let reason = el
+                .get_attribute("data-mx-spoiler")
+                .filter(|reason| !reason.is_empty());
+            f(el, &Spoiler { reason }, info);
+        } else if let Some((f, colors)) = colors {
+            f(el, &colors, info);
+        } else if let Some(handler) = info.element_handlers.get(&tag) {
+            handler(el, info);
+        } else if let (Some(f), "pre") = (&info.code_block_handler, tag.as_str()) {
+            let block = state.borrow_mut().code_blocks.pop_front();
+            f(el, &block.unwrap_or_default(), info);
+        } else if tag == "img" {
+            let image = Image::from_element(el);
+            match &info.image_handler {
+                Some(f) => f(el, &image, info),
+                None => default_image(el, &image, info),
+            }
+        } else if let (Some(f), "ul" | "ol") = (&info.list_handler, tag.as_str()) {
+            let list = List {
+                depth: state.borrow().lists.len(),
+                ordered: tag == "ol",
+            };
+            f(el, &list, info);
+        } else if let (Some(f), "li") = (&info.list_item_handler, tag.as_str()) {
+            let item = {
+                let mut state = state.borrow_mut();
+                let depth = state.lists.len().saturating_sub(1);
+                let number = state.lists.last_mut().and_then(|n| {
+                    let number = *n;
+                    *n = n.map(|n| n + 1);
+                    number
+                });
+                ListItem { depth, number }
+            };
+            f(el, &item, info);
+        } else if let (Some(f), "tr") = (&info.table_row_handler, tag.as_str()) {
+            let row = state.borrow_mut().tables.last_mut().map(|table| {
+                let row = TableRow {
+                    index: table.rows,
+                    header_columns: if table.header {
+                        Some(table.cells)
+                    } else {
+                        None
+                    },
+                };
+                table.rows += 1;
+                table.cells = 0;
+                table.header = false;
+                row
+            });
+            match row {
+                Some(row) => f(el, &row, info),
+                None => el.remove_and_keep_content(),
+            }
+        } else {
+            el.remove_and_keep_content();
         }
 
-        #[test]
-        fn test_anchor() {
-            let mut user_mapping = HashMap::new();
-            user_mapping.insert(user_id!("@tomsg_tom:lieuwe.xyz"), "tom".to_string());
+        // keep track of the nesting of lists, tables and preformatted text, popping it again
+        // when the element ends.
+        let pushed = {
+            let mut state = state.borrow_mut();
+            match tag.as_str() {
+                "ul" => state.lists.push(None),
+                "ol" => {
+                    let start = el
+                        .get_attribute("start")
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or(1);
+                    state.lists.push(Some(start));
+                }
+                "table" => state.tables.push(TableState::default()),
+                "pre" => state.pre_depth += 1,
+                _ => return,
+            }
+            tag
+        };
 
-            let mut info = Info::new();
-            let f = move |user_id: UserId, _: &Info| user_mapping.get(&user_id).cloned();
-            info.user_mapper(&f);
+        let state = Rc::clone(state);
+        let _ = el.on_end_tag(move |_| {
+            let mut state = state.borrow_mut();
+            match pushed.as_str() {
+                "ul" | "ol" => {
+                    state.lists.pop();
+                }
+                "table" => {
+                    state.tables.pop();
+                }
+                "pre" => state.pre_depth -= 1,
+                _ => unreachable!(),
+            }
+            Ok(())
+        });
+    }
 
-            let before =
-                "<a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">tom (tomsg)</a>".to_string();
+    fn default_image(el: &mut Element, image: &Image, info: &Info) {
+        let text = image
+            .alt
+            .as_deref()
+            .or(image.title.as_deref())
+            .unwrap_or("image");
 
-            let after = convert(&before, &info).unwrap();
-            assert_eq!(after, "tom");
+        match image.http_url(info) {
+            Some(url) if !image.emoticon => {
+                el.replace(&format!("{} ({})", text, url), ContentType::Html)
+            }
+            _ => el.replace(text, ContentType::Html),
         }
+    }
 
-        #[test]
-        fn test_anchor_room() {
-            let mut room_mapping = HashMap::new();
-            room_mapping.insert(
-                RoomAliasId::try_from("#tomsg:lieuwe.xyz").unwrap(),
-                "tomsg".to_string(),
-            );
+    /// Decode the character reference with the given name (the part between `&` and `;`).
+    fn decode_reference(name: &str) -> Option<char> {
+        match name {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            name => name
+                .strip_prefix("#x")
+                .or_else(|| name.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| name.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(std::char::from_u32),
+        }
+    }
 
-            let mut info = Info::new();
-            let f = move |room_id: RoomAliasId, _: &Info| room_mapping.get(&room_id).cloned();
-            info.room_mapper(&f);
+    /// The post-processing of the output of a conversion, configured by `Info::trim_output` and
+    /// `Info::decode_entities`.
+    ///
+    /// The output is processed incrementally, so that it can also be applied while streaming.
+    #[derive(Default)]
+    struct PostProcessor {
+        trim: bool,
+        decode: bool,
+        /// Whether any character other than a newline has been written.
+        started: bool,
+        /// The amount of newlines held back, since they might be trailing.
+        newlines: usize,
+        /// The name of the character reference currently being read.
+        reference: Option<String>,
+        /// The bytes of an incomplete UTF-8 character at the end of the previous chunk.
+        incomplete: Vec<u8>,
+        /// Whether line prefix markers are applied, see `line_prefix`.
+        line_prefixes: bool,
+        /// The prefixes of the lines in the open blockquotes and list items, outermost first.
+        prefixes: Vec<String>,
+        /// The line prefix currently being read.
+        prefix: Option<String>,
+        /// Whether anything has been written on the current line.
+        mid_line: bool,
+        /// The least amount of prefixes in effect since the held back newlines started, which
+        /// the blank lines among them get.
+        gap: usize,
+        /// Whether newlines are dropped until the next character, after a list marker.
+        swallow: bool,
+        #[cfg(feature = "emoji")]
+        emoji: Option<ShortcodeEncoder>,
+    }
 
-            let before = "<a href=\"https://matrix.to/#/#tomsg:lieuwe.xyz\">tomsg</a>".to_string();
+    impl PostProcessor {
+        /// The maximum length of a character reference name we try to decode.
+        const MAX_REFERENCE_LEN: usize = 32;
 
-            let after = convert(&before, &info).unwrap();
-            assert_eq!(after, "tomsg");
+        fn new(info: &Info) -> Self {
+            Self {
+                trim: info.trim_output,
+                decode: info.decode_entities,
+                line_prefixes: info.line_prefixes,
+                #[cfg(feature = "emoji")]
+                emoji: if info.emoji_shortcodes {
+                    Some(ShortcodeEncoder::default())
+                } else {
+                    None
+                },
+                ..Self::default()
+            }
         }
 
-        #[test]
-        fn test_complex() {
-            let mut user_mapping = HashMap::new();
-            user_mapping.insert(user_id!("@tomsg_tom:lieuwe.xyz"), "tom".to_string());
-            user_mapping.insert(user_id!("@lieuwe:lieuwe.xyz"), "lieuwe".to_string());
+        #[cfg(not(feature = "emoji"))]
+        fn is_noop(&self) -> bool {
+            !self.trim && !self.decode && !self.line_prefixes
+        }
 
-            let mut info = Info::new();
-            let f = move |user_id: UserId, _: &Info| user_mapping.get(&user_id).cloned();
-            info.user_mapper(&f);
+        #[cfg(feature = "emoji")]
+        fn is_noop(&self) -> bool {
+            !self.trim && !self.decode && !self.line_prefixes && self.emoji.is_none()
+        }
 
-            let before =
-            "<a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">tom (tomsg)</a>: How're you doing, greetings <a href=\"https://matrix.to/#/@lieuwe:lieuwe.xyz\">henk</a>. Btw, here is a cool link <a href=\"google.nl\">bing</a>".to_string();
+        fn push_bytes(&mut self, bytes: &[u8], out: &mut String) {
+            self.incomplete.extend_from_slice(bytes);
+            let buf = std::mem::take(&mut self.incomplete);
 
-            let after = convert(&before, &info).unwrap();
-            assert_eq!(
-            after,
-            "tom: How're you doing, greetings lieuwe. Btw, here is a cool link [bing](google.nl)"
-        );
+            let valid = match std::str::from_utf8(&buf) {
+                Ok(s) => s.len(),
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(_) => {
+                    self.push_str(&String::from_utf8_lossy(&buf), out);
+                    return;
+                }
+            };
+
+            // safety: `from_utf8` validated the bytes up to `valid`.
+            let s = unsafe { std::str::from_utf8_unchecked(&buf[..valid]) };
+            self.push_str(s, out);
+            self.incomplete = buf[valid..].to_vec();
         }
 
-        #[test]
-        fn test_element_handlers() {
-            let mut info = Info::new();
-            info.add_element_handler("a".to_string(), &|el: &mut Element<'_, '_>, _: &Info| {
-                el.replace("test", ContentType::Html);
-            });
+        fn push_str(&mut self, s: &str, out: &mut String) {
+            for c in s.chars() {
+                self.push_char(c, out);
+            }
+        }
 
-            let before = "<a href=\"google.nl\">this will be gone</a>";
+        fn push_char(&mut self, c: char, out: &mut String) {
+            if self.line_prefixes && self.read_marker(c) {
+                return;
+            }
+            if c == '\n' && self.swallow {
+                return;
+            }
+            if !self.trim {
+                return self.write_char(c, out);
+            }
 
-            let after = convert(&before, &info).unwrap();
-            assert_eq!(after, "test");
+            // trim surrounding newlines and collapse runs of blank lines into a single blank line.
+            if c == '\n' {
+                if self.started {
+                    if self.newlines == 0 {
+                        self.gap = self.prefixes.len();
+                    }
+                    self.newlines += 1;
+                }
+                return;
+            }
+            for i in 0..self.newlines.min(2) {
+                if i > 0 {
+                    let blank = self.prefixes[..self.gap].concat();
+                    self.emit_str(blank.trim_end(), out);
+                }
+                self.decode_char('\n', out);
+                self.mid_line = false;
+            }
+            self.newlines = 0;
+            self.started = true;
+            self.write_char(c, out);
         }
 
-        #[test]
-        fn test_complex2() {
-            let before = "<mx-reply><blockquote><a href=\"https://matrix.to/#/!opVyAOHWsarCVcEQkE:lieuwe.xyz/$wjpDcX-sy3dLophlXRfL0pyE4yotZ5XK8v1DF_VMpoU?via=lieuwe.xyz\">In reply to</a> <a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">@tomsg_tom:lieuwe.xyz</a><br>⛄️</blockquote></mx-reply>Hallo <a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">tom (tomsg)</a> dit is een test <em>kaas</em> <strong>ham</strong> <a href=\"http://tomsmeding.com/f/kaas.png\">coole site</a>";
-            let after = "Hallo tom dit is een test *kaas* **ham** [coole site](http://tomsmeding.com/f/kaas.png)";
+        /// Handle `c` if it's part of a line prefix marker, returning whether it was.
+        fn read_marker(&mut self, c: char) -> bool {
+            if let Some(mut prefix) = self.prefix.take() {
+                if c == PREFIX_END {
+                    // a prefix pushed after a list marker continues its line.
+                    self.swallow = self.mid_line && self.newlines == 0;
+                    self.prefixes.push(prefix);
+                } else {
+                    prefix.push(c);
+                    self.prefix = Some(prefix);
+                }
+                return true;
+            }
 
-            let mut info = Info::new();
+            match c {
+                PREFIX_START => self.prefix = Some(String::new()),
+                PREFIX_POP => {
+                    self.prefixes.pop();
+                    self.gap = self.gap.min(self.prefixes.len());
+                    self.swallow = false;
+                }
+                _ => return false,
+            }
+            true
+        }
 
-            info.add_element_handler(
+        /// Write `c`, preceded by the line prefixes if it starts a line.
+        fn write_char(&mut self, c: char, out: &mut String) {
+            if c == '\n' {
+                if !self.mid_line && !self.prefixes.is_empty() {
+                    let blank = self.prefixes.concat();
+                    self.emit_str(blank.trim_end(), out);
+                }
+                self.mid_line = false;
+                return self.decode_char(c, out);
+            }
+
+            if !self.mid_line {
+                let prefixes = self.prefixes.concat();
+                self.emit_str(&prefixes, out);
+                self.mid_line = true;
+            }
+            self.swallow = false;
+            self.decode_char(c, out);
+        }
+
+        fn decode_char(&mut self, c: char, out: &mut String) {
+            if !self.decode {
+                return self.emit(c, out);
+            }
+
+            if let Some(mut name) = self.reference.take() {
+                if c == ';' {
+                    match decode_reference(&name) {
+                        Some(decoded) => self.emit(decoded, out),
+                        None => {
+                            self.emit('&', out);
+                            self.emit_str(&name, out);
+                            self.emit(';', out);
+                        }
+                    }
+                    return;
+                } else if (c.is_ascii_alphanumeric() || c == '#')
+                    && name.len() < Self::MAX_REFERENCE_LEN
+                {
+                    name.push(c);
+                    self.reference = Some(name);
+                    return;
+                }
+
+                // not a character reference after all.
+                self.emit('&', out);
+                self.emit_str(&name, out);
+            }
+
+            if c == '&' {
+                self.reference = Some(String::new());
+            } else {
+                self.emit(c, out);
+            }
+        }
+
+        /// Write a fully processed character to `out`.
+        fn emit(&mut self, c: char, out: &mut String) {
+            #[cfg(feature = "emoji")]
+            if let Some(encoder) = &mut self.emoji {
+                return encoder.push(c, out);
+            }
+
+            out.push(c);
+        }
+
+        fn emit_str(&mut self, s: &str, out: &mut String) {
+            for c in s.chars() {
+                self.emit(c, out);
+            }
+        }
+
+        fn finish(mut self, out: &mut String) {
+            if !self.incomplete.is_empty() {
+                let incomplete = std::mem::take(&mut self.incomplete);
+                self.push_str(&String::from_utf8_lossy(&incomplete), out);
+            }
+            if let Some(name) = self.reference.take() {
+                self.emit('&', out);
+                self.emit_str(&name, out);
+            }
+
+            #[cfg(feature = "emoji")]
+            if let Some(encoder) = &mut self.emoji {
+                encoder.finish(out);
+            }
+        }
+    }
+
+    /// Decode the character references in `s`, leaving unknown or malformed references as is.
+    fn decode_entities(s: &str) -> String {
+        let mut processor = PostProcessor {
+            decode: true,
+            ..PostProcessor::default()
+        };
+
+        let mut res = String::with_capacity(s.len());
+        processor.push_str(s, &mut res);
+        processor.finish(&mut res);
+        res
+    }
+
+    /// Collect the code blocks in the given HTML, in document order.
+    fn collect_code_blocks(s: &str, info: &Info) -> VecDeque<CodeBlock> {
+        let blocks = RefCell::new(VecDeque::new());
+
+        let settings = Settings {
+            element_content_handlers: vec![
+                (
+                    Cow::Owned("pre".parse().unwrap()),
+                    ElementContentHandlers::default()
+                        .element(|_| {
+                            blocks.borrow_mut().push_back(CodeBlock::default());
+                            Ok(())
+                        })
+                        .text(|t| {
+                            if let Some(block) = blocks.borrow_mut().back_mut() {
+                                block.content.push_str(t.as_str());
+                            }
+                            Ok(())
+                        }),
+                ),
+                (
+                    Cow::Owned("pre > code".parse().unwrap()),
+                    ElementContentHandlers::default().element(|e| {
+                        let language = e.get_attribute("class").and_then(|class| {
+                            remove_markers(&class, info)
+                                .split_whitespace()
+                                .find_map(|c| c.strip_prefix("language-"))
+                                .map(String::from)
+                        });
+                        if let Some(block) = blocks.borrow_mut().back_mut() {
+                            block.language = block.language.take().or(language);
+                        }
+                        Ok(())
+                    }),
+                ),
+            ],
+            ..Settings::default()
+        };
+
+        if rewrite_str(s, settings).is_err() {
+            return VecDeque::new();
+        }
+
+        let mut blocks = blocks.into_inner();
+        for block in blocks.iter_mut() {
+            block.content = remove_markers(&decode_entities(&block.content), info).into_owned();
+        }
+        blocks
+    }
+
+    fn build_settings<'h>(
+        info: &'h Info,
+        state: Rc<RefCell<State>>,
+        resolved: Option<&'h ResolvedMentions>,
+    ) -> Settings<'h, 'static> {
+        let mut handlers = vec![];
+        if info.line_prefixes {
+            handlers.push((
+                Cow::Owned("*".parse().unwrap()),
+                ElementContentHandlers::default().element(move |e| {
+                    remove_attribute_markers(e, info);
+                    Ok(())
+                }),
+            ));
+        }
+        handlers.extend(vec![
+            (
+                Cow::Owned("body".parse().unwrap()),
+                ElementContentHandlers::default().comments(|c| {
+                    c.remove();
+                    Ok(())
+                }),
+            ),
+            (
+                Cow::Owned("a".parse().unwrap()),
+                ElementContentHandlers::default().element(move |e| {
+                    stringify_a_tag(e, info, resolved);
+                    Ok(())
+                }),
+            ),
+            (
+                Cow::Owned("*".parse().unwrap()),
+                ElementContentHandlers::default().element(move |e| {
+                    handle_element(e, info, &state);
+                    Ok(())
+                }),
+            ),
+        ]);
+
+        Settings {
+            element_content_handlers: handlers,
+            document_content_handlers: if info.line_prefixes {
+                vec![DocumentContentHandlers::default().text(move |t| {
+                    let text = remove_markers(t.as_str(), info);
+                    if let Cow::Owned(text) = text {
+                        t.replace(&text, ContentType::Html);
+                    }
+                    Ok(())
+                })]
+            } else {
+                vec![]
+            },
+            ..Settings::default()
+        }
+    }
+
+    /// Remove the line prefix markers from the attributes of `el`, before they are read by the
+    /// other handlers.
+    fn remove_attribute_markers(el: &mut Element, info: &Info) {
+        let attributes: Vec<_> = el
+            .attributes()
+            .iter()
+            .filter_map(|a| match remove_markers(&a.value(), info) {
+                Cow::Owned(value) => Some((a.name(), value)),
+                Cow::Borrowed(_) => None,
+            })
+            .collect();
+        for (name, value) in attributes {
+            let _ = el.set_attribute(&name, &value);
+        }
+    }
+
+    /// Remove the line prefix markers from `s` if `info` uses line prefixes, so the message can't
+    /// mess with the prefixes.
+    fn remove_markers<'s>(s: &'s str, info: &Info) -> Cow<'s, str> {
+        let is_marker = |c| matches!(c, PREFIX_START | PREFIX_END | PREFIX_POP);
+        if info.line_prefixes && s.contains(is_marker) {
+            Cow::Owned(s.replace(is_marker, ""))
+        } else {
+            Cow::Borrowed(s)
+        }
+    }
+
+    pub fn convert(s: &str, info: &Info) -> Result<String, Error> {
+        convert_with(s, info, None)
+    }
+
+    fn convert_with(
+        s: &str,
+        info: &Info,
+        resolved: Option<&ResolvedMentions>,
+    ) -> Result<String, Error> {
+        let state = Rc::new(RefCell::new(State::default()));
+        if info.code_block_handler.is_some() && s.contains("<pre") {
+            state.borrow_mut().code_blocks = collect_code_blocks(s, info);
+        }
+
+        let res = rewrite_str(s, build_settings(info, state, resolved))
+            .map_err(|err| Error::Conversion(err.to_string()))?;
+
+        let mut processor = PostProcessor::new(info);
+        if processor.is_noop() {
+            return Ok(res);
+        }
+        let mut processed = String::with_capacity(res.len());
+        processor.push_str(&res, &mut processed);
+        processor.finish(&mut processed);
+        Ok(processed)
+    }
+
+    /// A user or room mentioned in a Matrix message.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub enum MentionTarget {
+        User(UserId),
+        Room(RoomAliasId),
+    }
+
+    /// A mention in a Matrix message, as found by `extract_mentions`.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Mention {
+        pub target: MentionTarget,
+        /// What the mention is converted to by the mappers of the `Info`, if anything.
+        pub mapped: Option<String>,
+    }
+
+    /// Collect the distinct users and room aliases mentioned in the given HTML, in document order.
+    fn collect_mentions(s: &str) -> Vec<MentionTarget> {
+        let mentions = RefCell::new(Vec::new());
+
+        let settings = Settings {
+            element_content_handlers: vec![(
+                Cow::Owned("a[href]".parse().unwrap()),
+                ElementContentHandlers::default().element(|el| {
+                    let href = el.get_attribute("href").unwrap_or_default();
+                    let mentioned = match href.strip_prefix("https://matrix.to/#/") {
+                        None => return Ok(()),
+                        Some(suffix) => suffix,
+                    };
+
+                    let target = match mentioned.chars().next() {
+                        Some('@') => UserId::try_from(mentioned).ok().map(MentionTarget::User),
+                        Some('#') => RoomAliasId::try_from(mentioned)
+                            .ok()
+                            .map(MentionTarget::Room),
+                        _ => None,
+                    };
+
+                    let mut mentions = mentions.borrow_mut();
+                    if let Some(target) = target {
+                        if !mentions.contains(&target) {
+                            mentions.push(target);
+                        }
+                    }
+
+                    Ok(())
+                }),
+            )],
+            ..Settings::default()
+        };
+        if rewrite_str(s, settings).is_err() {
+            return Vec::new();
+        }
+
+        mentions.into_inner()
+    }
+
+    /// Get the distinct users and rooms mentioned in the given HTML, in document order, together
+    /// with what the mappers of `info` convert them to.
+    ///
+    /// This allows bridges to notify the mentioned users on the external network without parsing
+    /// the converted message.
+    pub fn extract_mentions(html: &str, info: &Info) -> Vec<Mention> {
+        collect_mentions(html)
+            .into_iter()
+            .map(|target| {
+                let mapped = match &target {
+                    MentionTarget::User(user_id) => (info.user_mapper)(user_id.clone(), info),
+                    MentionTarget::Room(alias) => (info.room_mapper)(alias.clone(), info),
+                };
+                Mention { target, mapped }
+            })
+            .collect()
+    }
+
+    /// Like `convert`, but with mappers that return futures, for when resolving a mention
+    /// requires I/O.
+    ///
+    /// Since the HTML rewriter can't wait on futures, this first collects all mentions in `s`,
+    /// resolves them concurrently using `user_mapper` and `room_mapper`, and then converts `s`
+    /// using the results. The synchronous mappers set on `info` are not used.
+    pub async fn convert_async<UF, UFut, RF, RFut>(
+        s: &str,
+        info: &Info<'_>,
+        user_mapper: UF,
+        room_mapper: RF,
+    ) -> Result<String, Error>
+    where
+        UF: Fn(UserId) -> UFut,
+        UFut: Future<Output = Option<String>>,
+        RF: Fn(RoomAliasId) -> RFut,
+        RFut: Future<Output = Option<String>>,
+    {
+        let (mut users, mut rooms) = (vec![], vec![]);
+        for target in collect_mentions(s) {
+            match target {
+                MentionTarget::User(user_id) => users.push(user_id),
+                MentionTarget::Room(alias) => rooms.push(alias),
+            }
+        }
+
+        let users = join_all(users.into_iter().map(|user_id| {
+            let fut = user_mapper(user_id.clone());
+            async move { (user_id, fut.await) }
+        }));
+        let rooms = join_all(rooms.into_iter().map(|alias| {
+            let fut = room_mapper(alias.clone());
+            async move { (alias, fut.await) }
+        }));
+        let (users, rooms) = join(users, rooms).await;
+
+        let resolved = ResolvedMentions {
+            users: users.into_iter().collect(),
+            rooms: rooms.into_iter().collect(),
+        };
+        convert_with(s, info, Some(&resolved))
+    }
+
+    type SinkClosure<'h> = Box<dyn FnMut(&[u8]) + 'h>;
+
+    /// The destination of the output of a `StreamingConverter`.
+    struct Sink<W: Write> {
+        writer: W,
+        processor: PostProcessor,
+        buf: String,
+        error: Option<io::Error>,
+    }
+
+    impl<W: Write> Sink<W> {
+        fn write(&mut self, chunk: &[u8]) {
+            if self.error.is_some() {
+                return;
+            }
+
+            let res = if self.processor.is_noop() {
+                self.writer.write_all(chunk)
+            } else {
+                self.processor.push_bytes(chunk, &mut self.buf);
+                let res = self.writer.write_all(self.buf.as_bytes());
+                self.buf.clear();
+                res
+            };
+            if let Err(e) = res {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// A converter that converts HTML given in chunks, writing the output to a writer as it
+    /// goes. This bounds the memory used when converting very large messages.
+    ///
+    /// Since the input can't be looked ahead in, code blocks are given to the code block handler
+    /// without their language and content.
+    pub struct StreamingConverter<'h, W: Write> {
+        rewriter: HtmlRewriter<'h, SinkClosure<'h>>,
+        sink: Rc<RefCell<Sink<W>>>,
+    }
+
+    impl<'h, W: Write + 'h> StreamingConverter<'h, W> {
+        /// Create a new `StreamingConverter` using the given `info`, writing the output to
+        /// `writer`.
+        pub fn new(info: &'h Info, writer: W) -> Self {
+            let state = Rc::new(RefCell::new(State::default()));
+            let sink = Rc::new(RefCell::new(Sink {
+                writer,
+                processor: PostProcessor::new(info),
+                buf: String::new(),
+                error: None,
+            }));
+
+            let output_sink = {
+                let sink = Rc::clone(&sink);
+                Box::new(move |chunk: &[u8]| sink.borrow_mut().write(chunk))
+            };
+
+            Self {
+                rewriter: HtmlRewriter::new(build_settings(info, state, None), output_sink),
+                sink,
+            }
+        }
+
+        /// Convert the given chunk of HTML.
+        pub fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+            self.rewriter
+                .write(chunk)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            match self.sink.borrow_mut().error.take() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+
+        /// Finish the conversion, flushing the remaining output and returning the writer.
+        pub fn end(self) -> io::Result<W> {
+            self.rewriter
+                .end()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+            let sink = match Rc::try_unwrap(self.sink) {
+                Ok(sink) => sink.into_inner(),
+                Err(_) => unreachable!("the rewriter holding the sink has ended"),
+            };
+            if let Some(e) = sink.error {
+                return Err(e);
+            }
+
+            let Sink {
+                mut writer,
+                processor,
+                mut buf,
+                ..
+            } = sink;
+            processor.finish(&mut buf);
+            writer.write_all(buf.as_bytes())?;
+            writer.flush()?;
+            Ok(writer)
+        }
+    }
+
+    /// Convert the HTML read from `reader`, writing the output to `writer`, without buffering
+    /// the complete input or output.
+    pub fn convert_stream<R: Read, W: Write>(
+        mut reader: R,
+        writer: W,
+        info: &Info,
+    ) -> io::Result<W> {
+        let mut converter = StreamingConverter::new(info, writer);
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            converter.write(&buf[..n])?;
+        }
+        converter.end()
+    }
+
+    /// A reply fallback (`<mx-reply>`) extracted from a message by `extract_reply`.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ParsedReply {
+        /// The room the replied-to event is in.
+        pub room: RoomIdOrAliasId,
+        /// The ID of the replied-to event.
+        pub event_id: EventId,
+        /// The sender of the replied-to event, if the fallback mentions it.
+        pub sender: Option<UserId>,
+        /// The HTML body of the replied-to event, as quoted by the fallback.
+        pub body: String,
+    }
+
+    /// Decode the percent-encoded bytes in `s`.
+    fn percent_decode(s: &str) -> String {
+        let bytes = s.as_bytes();
+        let mut res = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let decoded = match bytes[i] {
+                b'%' => s
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                _ => None,
+            };
+            match decoded {
+                Some(b) => {
+                    res.push(b);
+                    i += 3;
+                }
+                None => {
+                    res.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+        String::from_utf8_lossy(&res).into_owned()
+    }
+
+    /// Parse the matrix.to permalink of the replied-to event into the room and event ID.
+    fn parse_event_permalink(href: &str) -> Option<(RoomIdOrAliasId, EventId)> {
+        let path = href.strip_prefix("https://matrix.to/#/")?;
+        let path = path.split('?').next()?;
+        let mut parts = path.splitn(2, '/');
+        let room = RoomIdOrAliasId::try_from(percent_decode(parts.next()?)).ok()?;
+        let event_id = EventId::try_from(percent_decode(parts.next()?)).ok()?;
+        Some((room, event_id))
+    }
+
+    /// Split the reply fallback (`<mx-reply>`) off the given message HTML.
+    ///
+    /// Returns the HTML with the fallback removed, and the information contained in the fallback
+    /// if the message has a well-formed one. This allows bridges to render replies natively on
+    /// the external network, instead of removing the fallback using an element handler.
+    pub fn extract_reply(html: &str) -> (String, Option<ParsedReply>) {
+        const START: &str = "<mx-reply>";
+        const END: &str = "</mx-reply>";
+
+        let (start, end) = match (html.find(START), html.find(END)) {
+            (Some(start), Some(end)) if start < end => (start, end),
+            _ => return (html.to_string(), None),
+        };
+
+        let stripped = format!("{}{}", &html[..start], &html[end + END.len()..]);
+        let fallback = &html[start + START.len()..end];
+
+        let hrefs = RefCell::new(Vec::new());
+        let settings = Settings {
+            element_content_handlers: vec![(
+                Cow::Owned("a[href]".parse().unwrap()),
+                ElementContentHandlers::default().element(|e| {
+                    hrefs.borrow_mut().extend(e.get_attribute("href"));
+                    Ok(())
+                }),
+            )],
+            ..Settings::default()
+        };
+        if rewrite_str(fallback, settings).is_err() {
+            return (stripped, None);
+        }
+        let hrefs = hrefs.into_inner();
+
+        let (room, event_id) = match hrefs.first().and_then(|h| parse_event_permalink(h)) {
+            Some(permalink) => permalink,
+            None => return (stripped, None),
+        };
+        let sender = hrefs
+            .get(1)
+            .and_then(|h| h.strip_prefix("https://matrix.to/#/"))
+            .and_then(|user| UserId::try_from(percent_decode(user)).ok());
+
+        let body = ["<br>", "<br/>", "<br />"]
+            .iter()
+            .filter_map(|br| fallback.find(br).map(|i| i + br.len()))
+            .min()
+            .map(|body_start| {
+                let body = &fallback[body_start..];
+                let body_end = body.rfind("</blockquote>").unwrap_or(body.len());
+                body[..body_end].to_string()
+            })
+            .unwrap_or_default();
+
+        let reply = ParsedReply {
+            room,
+            event_id,
+            sender,
+            body,
+        };
+        (stripped, Some(reply))
+    }
+
+    /// The maximum size of a single message on an external network.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Budget {
+        /// The maximum amount of bytes in a message, when encoded as UTF-8.
+        Bytes(usize),
+        /// The maximum amount of characters in a message.
+        Chars(usize),
+    }
+
+    impl Budget {
+        /// Get the end of the longest prefix of `s` that fits in this budget.
+        fn fit(self, s: &str) -> usize {
+            match self {
+                Budget::Bytes(n) if n >= s.len() => s.len(),
+                Budget::Bytes(n) => (0..=n).rev().find(|&i| s.is_char_boundary(i)).unwrap(),
+                Budget::Chars(n) => s.char_indices().nth(n).map_or(s.len(), |(i, _)| i),
+            }
+        }
+    }
+
+    /// Find the markdown links in `s`, like `[text](url)`.
+    fn markdown_links(s: &str) -> Vec<std::ops::Range<usize>> {
+        let mut res = vec![];
+
+        let mut i = 0;
+        while let Some(k) = s[i..].find('[') {
+            let start = i + k;
+            i = start + 1;
+
+            let close = match s[start..].find("](") {
+                Some(k) => start + k,
+                None => break,
+            };
+            if let Some(k) = s[close..].find(')') {
+                res.push(start..close + k + 1);
+                i = close + k + 1;
+            }
+        }
+
+        res
+    }
+
+    /// Split the converted message `s` into messages that fit in the given `budget`.
+    ///
+    /// Messages are split at newlines if possible, otherwise at whitespace. Markdown links and
+    /// the strings in `keep_together`, like the names mentions were converted to, are not split
+    /// unless they don't fit in a single message on their own. Characters are never split.
+    pub fn split_message(s: &str, budget: Budget, keep_together: &[&str]) -> Vec<String> {
+        let mut protected = markdown_links(s);
+        for keep in keep_together.iter().filter(|k| !k.is_empty()) {
+            protected.extend(s.match_indices(keep).map(|(i, k)| i..i + k.len()));
+        }
+        let inside = |pos: usize| protected.iter().any(|r| r.start < pos && pos < r.end);
+
+        let mut res = vec![];
+        let mut pos = 0;
+        loop {
+            let rest = &s[pos..];
+            let fit = budget.fit(rest);
+            if fit == rest.len() {
+                if !rest.trim_end().is_empty() {
+                    res.push(rest.trim_end().to_string());
+                }
+                break;
+            }
+
+            // whitespace right after the part that fits is a fine place to split too.
+            let window = match rest[fit..].chars().next() {
+                Some(c) if c.is_whitespace() => &rest[..fit + c.len_utf8()],
+                _ => &rest[..fit],
+            };
+            let valid = |i: usize| i > 0 && !inside(pos + i);
+
+            let split = window
+                .rmatch_indices('\n')
+                .map(|(i, _)| i)
+                .find(|&i| valid(i))
+                .or_else(|| {
+                    window
+                        .char_indices()
+                        .rev()
+                        .filter(|(_, c)| c.is_whitespace())
+                        .map(|(i, _)| i)
+                        .find(|&i| valid(i))
+                })
+                .or_else(|| {
+                    protected
+                        .iter()
+                        .find(|r| r.start > pos && r.start < pos + fit && pos + fit < r.end)
+                        .map(|r| r.start - pos)
+                })
+                .filter(|&i| i > 0)
+                .unwrap_or(fit.max(rest.chars().next().map_or(0, char::len_utf8)));
+
+            let part = rest[..split].trim_end();
+            if !part.is_empty() {
+                res.push(part.to_string());
+            }
+
+            pos += split;
+            pos += s[pos..].len() - s[pos..].trim_start().len();
+            if pos >= s.len() {
+                break;
+            }
+        }
+
+        res
+    }
+
+    /// Starts a line prefix in the output of a handler, which is ended by `PREFIX_END`.
+    const PREFIX_START: char = '\u{F0000}';
+    const PREFIX_END: char = '\u{F0001}';
+    /// Ends the line prefix started last.
+    const PREFIX_POP: char = '\u{F0002}';
+
+    /// Mark the start of the lines prefixed with `prefix`, until the next `PREFIX_POP`.
+    ///
+    /// The post-processing writes the prefix at the start of every line from there, since the
+    /// handlers can't see the content of an element. Only used by presets that set
+    /// `Info::line_prefixes`.
+    fn line_prefix(prefix: &str) -> String {
+        format!("{}{}{}", PREFIX_START, prefix, PREFIX_END)
+    }
+
+    fn wrap(el: &mut Element, before: &str, after: &str) {
+        el.prepend(before, ContentType::Html);
+        el.remove_and_keep_content();
+        el.append(after, ContentType::Html);
+    }
+
+    mod markdown {
+        use super::{
+            line_prefix, wrap, CodeBlock, ContentType, Element, Info, List, ListItem, Spoiler,
+            TableRow, PREFIX_POP,
+        };
+
+        pub fn spoiler(el: &mut Element, _: &Spoiler, _: &Info) {
+            wrap(el, "||", "||");
+        }
+
+        pub fn emphasis(el: &mut Element, _: &Info) {
+            wrap(el, "*", "*");
+        }
+
+        pub fn strong(el: &mut Element, _: &Info) {
+            wrap(el, "**", "**");
+        }
+
+        pub fn strikethrough(el: &mut Element, _: &Info) {
+            wrap(el, "~~", "~~");
+        }
+
+        pub fn code(el: &mut Element, _: &Info) {
+            wrap(el, "`", "`");
+        }
+
+        pub fn code_block(el: &mut Element, block: &CodeBlock, _: &Info) {
+            let language = block.language.as_deref().unwrap_or("");
+            let end = if block.content.ends_with('\n') {
+                "```\n\n"
+            } else {
+                "\n```\n\n"
+            };
+            wrap(el, &format!("\n\n```{}\n", language), end);
+        }
+
+        pub fn heading(el: &mut Element, _: &Info) {
+            let level = el.tag_name()[1..].parse().unwrap_or(1);
+            wrap(el, &format!("\n\n{} ", "#".repeat(level)), "\n\n");
+        }
+
+        pub fn blockquote(el: &mut Element, _: &Info) {
+            let before = format!("\n\n{}", line_prefix("> "));
+            wrap(el, &before, &format!("{}\n\n", PREFIX_POP));
+        }
+
+        pub fn paragraph(el: &mut Element, _: &Info) {
+            wrap(el, "\n\n", "\n\n");
+        }
+
+        pub fn line_break(el: &mut Element, _: &Info) {
+            el.replace("\n", ContentType::Html);
+        }
+
+        pub fn horizontal_rule(el: &mut Element, _: &Info) {
+            el.replace("\n\n---\n\n", ContentType::Html);
+        }
+
+        pub fn table(el: &mut Element, _: &Info) {
+            wrap(el, "\n\n", "\n\n");
+        }
+
+        pub fn table_cell(el: &mut Element, _: &Info) {
+            wrap(el, " ", " |");
+        }
+
+        pub fn list(el: &mut Element, list: &List, _: &Info) {
+            if list.depth == 0 {
+                wrap(el, "\n\n", "\n\n");
+            } else {
+                el.remove_and_keep_content();
+            }
+        }
+
+        pub fn list_item(el: &mut Element, item: &ListItem, _: &Info) {
+            let marker = match item.number {
+                Some(n) => format!("{}.", n),
+                None => String::from("-"),
+            };
+            // the lines after the first, and nested lists, are indented under the marker.
+            let indent = " ".repeat((marker.len() + 1).max(4));
+            let prefix = format!("\n{} {}", marker, line_prefix(&indent));
+            el.prepend(&prefix, ContentType::Html);
+            el.remove_and_keep_content();
+            el.append(&PREFIX_POP.to_string(), ContentType::Html);
+        }
+
+        pub fn table_row(el: &mut Element, row: &TableRow, _: &Info) {
+            let mut prefix = String::from("\n|");
+            if let Some(columns) = row.header_columns {
+                prefix.push_str(&" --- |".repeat(columns));
+                prefix.push_str("\n|");
+            }
+            el.prepend(&prefix, ContentType::Html);
+            el.remove_and_keep_content();
+        }
+    }
+
+    mod plain {
+        use super::{wrap, ContentType, Element, Info, List, ListItem, Spoiler, TableRow};
+
+        pub fn spoiler(el: &mut Element, spoiler: &Spoiler, _: &Info) {
+            match &spoiler.reason {
+                Some(reason) => wrap(el, &format!("[spoiler: {}] ", reason), ""),
+                None => wrap(el, "[spoiler] ", ""),
+            }
+        }
+
+        pub fn link(el: &mut Element, _: &Info) {
+            el.remove_and_keep_content();
+            if let Some(href) = el.get_attribute("href") {
+                el.append(&format!(" ({})", href), ContentType::Html);
+            }
+        }
+
+        pub fn remove(el: &mut Element, _: &Info) {
+            el.remove();
+        }
+
+        pub fn block(el: &mut Element, _: &Info) {
+            wrap(el, "\n", "\n");
+        }
+
+        pub fn line_break(el: &mut Element, _: &Info) {
+            el.replace("\n", ContentType::Html);
+        }
+
+        pub fn table_cell(el: &mut Element, _: &Info) {
+            wrap(el, " ", " |");
+        }
+
+        pub fn list(el: &mut Element, list: &List, _: &Info) {
+            if list.depth == 0 {
+                wrap(el, "\n", "\n\n");
+            } else {
+                el.remove_and_keep_content();
+            }
+        }
+
+        pub fn list_item(el: &mut Element, item: &ListItem, _: &Info) {
+            let marker = match item.number {
+                Some(n) => format!("{}.", n),
+                None => String::from("-"),
+            };
+            let prefix = format!("\n{}{} ", "  ".repeat(item.depth), marker);
+            el.prepend(&prefix, ContentType::Html);
+            el.remove_and_keep_content();
+        }
+
+        pub fn table_row(el: &mut Element, _: &TableRow, _: &Info) {
+            el.prepend("\n|", ContentType::Html);
+            el.remove_and_keep_content();
+        }
+    }
+
+    mod irc {
+        use super::{wrap, Color, Colors, Element, Info, Spoiler};
+
+        const MIRC_COLORS: [(u8, u8, u8); 16] = [
+            (255, 255, 255),
+            (0, 0, 0),
+            (0, 0, 127),
+            (0, 147, 0),
+            (255, 0, 0),
+            (127, 0, 0),
+            (156, 0, 156),
+            (252, 127, 0),
+            (255, 255, 0),
+            (0, 252, 0),
+            (0, 147, 147),
+            (0, 255, 255),
+            (0, 0, 252),
+            (255, 0, 255),
+            (127, 127, 127),
+            (210, 210, 210),
+        ];
+
+        /// Get the mIRC color code closest to the given color.
+        fn color_code(color: Color) -> usize {
+            let distance = |(r, g, b): &(u8, u8, u8)| {
+                let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+                d(color.red, *r) + d(color.green, *g) + d(color.blue, *b)
+            };
+            (0..MIRC_COLORS.len())
+                .min_by_key(|i| distance(&MIRC_COLORS[*i]))
+                .unwrap()
+        }
+
+        pub fn bold(el: &mut Element, _: &Info) {
+            wrap(el, "\x02", "\x02");
+        }
+
+        pub fn italic(el: &mut Element, _: &Info) {
+            wrap(el, "\x1D", "\x1D");
+        }
+
+        pub fn underline(el: &mut Element, _: &Info) {
+            wrap(el, "\x1F", "\x1F");
+        }
+
+        pub fn strikethrough(el: &mut Element, _: &Info) {
+            wrap(el, "\x1E", "\x1E");
+        }
+
+        pub fn monospace(el: &mut Element, _: &Info) {
+            wrap(el, "\x11", "\x11");
+        }
+
+        /// Ends a color. The empty bold after the color code keeps a digit right after it from
+        /// being read as a new color.
+        const COLOR_END: &str = "\x03\x02\x02";
+
+        pub fn spoiler(el: &mut Element, _: &Spoiler, _: &Info) {
+            // black on black, the closest thing IRC has to spoilers.
+            wrap(el, "\x0301,01", COLOR_END);
+        }
+
+        pub fn color(el: &mut Element, colors: &Colors, _: &Info) {
+            let fg = colors.foreground.map(color_code);
+            let bg = colors.background.map(color_code);
+
+            match (fg, bg) {
+                (Some(fg), Some(bg)) => wrap(el, &format!("\x03{:02},{:02}", fg, bg), COLOR_END),
+                (Some(fg), None) => wrap(el, &format!("\x03{:02}", fg), COLOR_END),
+                // IRC can't set only the background color, so use the default color (99) as the
+                // foreground.
+                (None, Some(bg)) => wrap(el, &format!("\x0399,{:02}", bg), COLOR_END),
+                (None, None) => el.remove_and_keep_content(),
+            }
+        }
+    }
+
+    mod discord {
+        use super::{wrap, Element, Info};
+
+        pub fn underline(el: &mut Element, _: &Info) {
+            wrap(el, "__", "__");
+        }
+    }
+
+    mod telegram {
+        use super::{ContentType, Element, Info, Spoiler};
+
+        /// Keep the element, but only with the given attributes.
+        fn keep(el: &mut Element, attributes: &[&str]) {
+            let remove: Vec<_> = el
+                .attributes()
+                .iter()
+                .map(|a| a.name())
+                .filter(|name| !attributes.contains(&name.as_str()))
+                .collect();
+            for name in remove {
+                el.remove_attribute(&name);
+            }
+        }
+
+        pub fn formatting(el: &mut Element, _: &Info) {
+            keep(el, &[]);
+        }
+
+        pub fn link(el: &mut Element, _: &Info) {
+            keep(el, &["href"]);
+        }
+
+        pub fn heading(el: &mut Element, _: &Info) {
+            keep(el, &[]);
+            let _ = el.set_tag_name("b");
+            el.after("\n", ContentType::Html);
+        }
+
+        pub fn spoiler(el: &mut Element, _: &Spoiler, _: &Info) {
+            keep(el, &[]);
+            let _ = el.set_tag_name("tg-spoiler");
+        }
+    }
+
+    mod whatsapp {
+        use super::{wrap, Element, Info};
+
+        pub fn bold(el: &mut Element, _: &Info) {
+            wrap(el, "*", "*");
+        }
+
+        pub fn italic(el: &mut Element, _: &Info) {
+            wrap(el, "_", "_");
+        }
+
+        pub fn strikethrough(el: &mut Element, _: &Info) {
+            wrap(el, "~", "~");
+        }
+
+        pub fn monospace(el: &mut Element, _: &Info) {
+            wrap(el, "```", "```");
+        }
+
+        pub fn blockquote(el: &mut Element, _: &Info) {
+            wrap(el, "\n> ", "\n");
+        }
+    }
+
+    type ElementFn = fn(&mut Element<'_, '_>, &Info);
+
+    fn add_element_handlers(info: &mut Info<'static>, handlers: &[(&[&str], ElementFn)]) {
+        for (tags, f) in handlers {
+            for tag in tags.iter() {
+                info.add_element_handler(tag.to_string(), *f);
+            }
+        }
+    }
+
+    /// Create an `Info` with element handlers installed that convert HTML into CommonMark.
+    ///
+    /// Handlers for specific elements can be overridden using `Info::add_element_handler`, and
+    /// user and room mappers can be set as usual.
+    pub fn markdown_preset() -> Info<'static> {
+        let mut info = Info::new();
+
+        add_element_handlers(
+            &mut info,
+            &[
+                (&["em", "i"], markdown::emphasis),
+                (&["strong", "b"], markdown::strong),
+                (&["del", "s", "strike"], markdown::strikethrough),
+                (&["code"], markdown::code),
+                (&["h1", "h2", "h3", "h4", "h5", "h6"], markdown::heading),
+                (&["blockquote"], markdown::blockquote),
+                (&["p"], markdown::paragraph),
+                (&["br"], markdown::line_break),
+                (&["hr"], markdown::horizontal_rule),
+                (&["table"], markdown::table),
+                (&["th", "td"], markdown::table_cell),
+            ],
+        );
+
+        info.list_handler(markdown::list)
+            .list_item_handler(markdown::list_item)
+            .table_row_handler(markdown::table_row)
+            .spoiler_handler(markdown::spoiler)
+            .code_block_handler(markdown::code_block)
+            .trim_output(true);
+        info.line_prefixes = true;
+
+        info
+    }
+
+    /// Create an `Info` with element handlers installed that convert HTML into plain text, for
+    /// networks without any formatting.
+    ///
+    /// Block elements and `<br>` tags are converted into line breaks, list items are prefixed
+    /// with a bullet or their number, links are written as `text (url)`, and reply fallbacks
+    /// (`<mx-reply>`) are removed.
+    pub fn plain_text_preset() -> Info<'static> {
+        let mut info = Info::new();
+
+        add_element_handlers(
+            &mut info,
+            &[
+                (&["a"], plain::link),
+                (&["mx-reply"], plain::remove),
+                (
+                    &[
+                        "p",
+                        "div",
+                        "pre",
+                        "blockquote",
+                        "table",
+                        "h1",
+                        "h2",
+                        "h3",
+                        "h4",
+                        "h5",
+                        "h6",
+                        "hr",
+                    ],
+                    plain::block,
+                ),
+                (&["br"], plain::line_break),
+                (&["th", "td"], plain::table_cell),
+            ],
+        );
+
+        info.list_handler(plain::list)
+            .list_item_handler(plain::list_item)
+            .table_row_handler(plain::table_row)
+            .spoiler_handler(plain::spoiler)
+            .trim_output(true)
+            .decode_entities(true);
+
+        info
+    }
+
+    /// A chat protocol for which `Info::with_preset` can create an `Info`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum Protocol {
+        /// IRC, using mIRC control codes for formatting.
+        Irc,
+        /// Discord, using its markdown dialect.
+        Discord,
+        /// Telegram, using the subset of HTML supported by the Bot API.
+        Telegram,
+        /// WhatsApp, using its formatting characters.
+        WhatsApp,
+    }
+
+    impl Info<'static> {
+        /// Create an `Info` with element handlers installed that convert HTML into the formatting
+        /// used by the given `protocol`.
+        ///
+        /// Like with the other presets, handlers for specific elements can be overridden using
+        /// `Info::add_element_handler`.
+        pub fn with_preset(protocol: Protocol) -> Self {
+            match protocol {
+                Protocol::Irc => {
+                    let mut info = plain_text_preset();
+                    add_element_handlers(
+                        &mut info,
+                        &[
+                            (&["strong", "b"], irc::bold),
+                            (&["em", "i"], irc::italic),
+                            (&["u"], irc::underline),
+                            (&["del", "s", "strike"], irc::strikethrough),
+                            (&["code"], irc::monospace),
+                        ],
+                    );
+                    info.spoiler_handler(irc::spoiler).color_handler(irc::color);
+                    info
+                }
+                Protocol::Discord => {
+                    let mut info = markdown_preset();
+                    add_element_handlers(
+                        &mut info,
+                        &[(&["u"], discord::underline), (&["mx-reply"], plain::remove)],
+                    );
+                    info.decode_entities(true);
+                    info
+                }
+                Protocol::Telegram => {
+                    let mut info = plain_text_preset();
+                    add_element_handlers(
+                        &mut info,
+                        &[
+                            (
+                                &[
+                                    "b",
+                                    "strong",
+                                    "i",
+                                    "em",
+                                    "u",
+                                    "ins",
+                                    "s",
+                                    "strike",
+                                    "del",
+                                    "code",
+                                    "pre",
+                                    "blockquote",
+                                ],
+                                telegram::formatting,
+                            ),
+                            (&["a"], telegram::link),
+                            (&["h1", "h2", "h3", "h4", "h5", "h6"], telegram::heading),
+                        ],
+                    );
+                    info.spoiler_handler(telegram::spoiler)
+                        .decode_entities(false);
+                    info
+                }
+                Protocol::WhatsApp => {
+                    let mut info = plain_text_preset();
+                    add_element_handlers(
+                        &mut info,
+                        &[
+                            (&["strong", "b"], whatsapp::bold),
+                            (&["em", "i"], whatsapp::italic),
+                            (&["del", "s", "strike"], whatsapp::strikethrough),
+                            (&["code", "pre"], whatsapp::monospace),
+                            (&["blockquote"], whatsapp::blockquote),
+                        ],
+                    );
+                    info
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::collections::HashMap;
+        use std::convert::TryFrom;
+
+        use lol_html::html_content::ContentType;
+        use ruma::identifiers::{user_id, RoomAliasId, UserId};
+
+        use crate::convert::to_external::{
+            convert, convert_async, convert_stream, extract_mentions, extract_reply,
+            markdown_preset, plain_text_preset, split_message, Budget, CodeBlock, Color, Colors,
+            Element, Image, Info, Mention, MentionTarget, Protocol, Spoiler, StreamingConverter,
+        };
+
+        #[test]
+        fn test_stripping() {
+            let info = Info::new();
+
+            let before = "<b>kaas</b>".to_string();
+            let after = convert(&before, &info).unwrap();
+            assert_eq!(after, "kaas");
+        }
+
+        #[test]
+        fn test_anchor() {
+            let mut user_mapping = HashMap::new();
+            user_mapping.insert(user_id!("@tomsg_tom:lieuwe.xyz"), "tom".to_string());
+
+            let mut info = Info::new();
+            let f = move |user_id: UserId, _: &Info| user_mapping.get(&user_id).cloned();
+            info.user_mapper(f);
+
+            let before =
+                "<a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">tom (tomsg)</a>".to_string();
+
+            let after = convert(&before, &info).unwrap();
+            assert_eq!(after, "tom");
+        }
+
+        #[test]
+        fn test_anchor_room() {
+            let mut room_mapping = HashMap::new();
+            room_mapping.insert(
+                RoomAliasId::try_from("#tomsg:lieuwe.xyz").unwrap(),
+                "tomsg".to_string(),
+            );
+
+            let mut info = Info::new();
+            let f = move |room_id: RoomAliasId, _: &Info| room_mapping.get(&room_id).cloned();
+            info.room_mapper(f);
+
+            let before = "<a href=\"https://matrix.to/#/#tomsg:lieuwe.xyz\">tomsg</a>".to_string();
+
+            let after = convert(&before, &info).unwrap();
+            assert_eq!(after, "tomsg");
+        }
+
+        #[test]
+        fn test_complex() {
+            let mut user_mapping = HashMap::new();
+            user_mapping.insert(user_id!("@tomsg_tom:lieuwe.xyz"), "tom".to_string());
+            user_mapping.insert(user_id!("@lieuwe:lieuwe.xyz"), "lieuwe".to_string());
+
+            let mut info = Info::new();
+            let f = move |user_id: UserId, _: &Info| user_mapping.get(&user_id).cloned();
+            info.user_mapper(f);
+
+            let before =
+            "<a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">tom (tomsg)</a>: How're you doing, greetings <a href=\"https://matrix.to/#/@lieuwe:lieuwe.xyz\">henk</a>. Btw, here is a cool link <a href=\"google.nl\">bing</a>".to_string();
+
+            let after = convert(&before, &info).unwrap();
+            assert_eq!(
+            after,
+            "tom: How're you doing, greetings lieuwe. Btw, here is a cool link [bing](google.nl)"
+        );
+        }
+
+        #[test]
+        fn test_element_handlers() {
+            let mut info = Info::new();
+            info.add_element_handler("a".to_string(), |el: &mut Element<'_, '_>, _: &Info| {
+                el.replace("test", ContentType::Html);
+            });
+
+            let before = "<a href=\"google.nl\">this will be gone</a>";
+
+            let after = convert(before, &info).unwrap();
+            assert_eq!(after, "test");
+        }
+
+        #[test]
+        fn test_complex2() {
+            let before = "<mx-reply><blockquote><a href=\"https://matrix.to/#/!opVyAOHWsarCVcEQkE:lieuwe.xyz/$wjpDcX-sy3dLophlXRfL0pyE4yotZ5XK8v1DF_VMpoU?via=lieuwe.xyz\">In reply to</a> <a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">@tomsg_tom:lieuwe.xyz</a><br>⛄️</blockquote></mx-reply>Hallo <a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">tom (tomsg)</a> dit is een test <em>kaas</em> <strong>ham</strong> <a href=\"http://tomsmeding.com/f/kaas.png\">coole site</a>";
+            let after = "Hallo tom dit is een test *kaas* **ham** [coole site](http://tomsmeding.com/f/kaas.png)";
+
+            let mut info = Info::new();
+
+            info.add_element_handler(
                 "mx-reply".to_string(),
-                &|el: &mut Element<'_, '_>, _: &Info| el.remove(),
+                |el: &mut Element<'_, '_>, _: &Info| el.remove(),
             );
-            info.add_element_handler("em".to_string(), &|el: &mut Element<'_, '_>, _: &Info| {
+            info.add_element_handler("em".to_string(), |el: &mut Element<'_, '_>, _: &Info| {
                 el.prepend("*", lol_html::html_content::ContentType::Html);
                 el.remove_and_keep_content();
                 el.append("*", lol_html::html_content::ContentType::Html);
             });
             info.add_element_handler(
                 "strong".to_string(),
-                &|el: &mut Element<'_, '_>, _: &Info| {
+                |el: &mut Element<'_, '_>, _: &Info| {
                     el.prepend("**", lol_html::html_content::ContentType::Html);
                     el.remove_and_keep_content();
                     el.append("**", lol_html::html_content::ContentType::Html);
                 },
             );
 
-            let mut user_mapping: HashMap<UserId, String> = HashMap::new();
-            user_mapping.insert(user_id!("@tomsg_tom:lieuwe.xyz"), "tom".to_string());
+            let mut user_mapping: HashMap<UserId, String> = HashMap::new();
+            user_mapping.insert(user_id!("@tomsg_tom:lieuwe.xyz"), "tom".to_string());
+
+            let f = move |user_id: UserId, _: &Info| user_mapping.get(&user_id).cloned();
+            info.user_mapper(f);
+
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_markdown_inline() {
+            let info = markdown_preset();
+
+            let before = "<p>this is <em>very</em> <strong>important</strong>, run <code>cargo test</code> <del>now</del></p>";
+            let after = "this is *very* **important**, run `cargo test` ~~now~~";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_markdown_blocks() {
+            let info = markdown_preset();
+
+            let before = "<h2>Title</h2><blockquote>quoted</blockquote><pre><code class=\"language-rust\">fn <span>main</span>() {}</code></pre>after<br>newline";
+            let after = "## Title\n\n> quoted\n\n```rust\nfn main() {}\n```\n\nafter\nnewline";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_markdown_lists() {
+            let info = markdown_preset();
+
+            let before =
+                "intro<ul><li>a<ol start=\"3\"><li>b</li><li>c</li></ol></li><li>d</li></ul>outro";
+            let after = "intro\n\n- a\n    3. b\n    4. c\n- d\n\noutro";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_markdown_multiline_blocks() {
+            let info = markdown_preset();
+
+            let before = "<blockquote><p>a</p><p>b</p></blockquote><p>after</p>";
+            let after = "> a\n>\n> b\n\nafter";
+            assert_eq!(after, convert(before, &info).unwrap());
+
+            let before = "before<blockquote>a<br>b<blockquote>nested</blockquote></blockquote>";
+            let after = "before\n\n> a\n> b\n>\n> > nested";
+            assert_eq!(after, convert(before, &info).unwrap());
+
+            let before = "<ul><li><p>one</p><p>more</p></li><li><p>two<br>lines</p></li></ul>";
+            let after = "- one\n\n    more\n\n- two\n    lines";
+            assert_eq!(after, convert(before, &info).unwrap());
+
+            let before = "<blockquote><ol><li>one</li><li>two</li></ol></blockquote>";
+            let after = "> 1. one\n> 2. two";
+            assert_eq!(after, convert(before, &info).unwrap());
+
+            // the markers of the line prefixes can't be injected.
+            let before = "a\u{F0000}x\u{F0001}\u{F0002}<br>b";
+            assert_eq!("ax\nb", convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_markdown_injected_markers() {
+            let mut info = markdown_preset();
+            info.user_mapper(|_, _| Some("al\u{F0000}ice".to_string()));
+
+            // the markers of the line prefixes are removed from attributes and mapped mentions
+            // too, in both the whole and the streaming converter.
+            let cases = [
+                (
+                    "<a href=\"https://example.org/\u{F0000}x\">link</a> and the rest",
+                    "[link](https://example.org/x) and the rest",
+                ),
+                (
+                    "<img src=\"mxc://example.org/abc\" alt=\"a\u{F0000}cat\"> and the rest",
+                    "acat and the rest",
+                ),
+                (
+                    "<span data-mx-spoiler=\"r\u{F0000}x\">hidden</span> and the rest",
+                    "||hidden|| and the rest",
+                ),
+                (
+                    "<a href=\"https://matrix.to/#/@alice:example.org\">Alice</a> and the rest",
+                    "alice and the rest",
+                ),
+            ];
+            for (before, after) in &cases {
+                assert_eq!(*after, convert(before, &info).unwrap());
+
+                let mut streamed = Vec::new();
+                let mut converter = StreamingConverter::new(&info, &mut streamed);
+                converter.write(before.as_bytes()).unwrap();
+                converter.end().unwrap();
+                assert_eq!(*after, String::from_utf8(streamed).unwrap());
+            }
+
+            let before =
+                "<pre><code class=\"language-\u{F0000}rs\">a\u{F0000}b &#983040;</code></pre>after";
+            let after = "```rs\nab &#983040;\n```\n\nafter";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_markdown_table() {
+            let info = markdown_preset();
+
+            let before = "<table><thead><tr><th>a</th><th>b</th></tr></thead><tbody><tr><td>1</td><td>2</td></tr></tbody></table>";
+            let after = "| a | b |\n| --- | --- |\n| 1 | 2 |";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_markdown_override() {
+            let mut info = markdown_preset();
+            info.add_element_handler("em".to_string(), |el: &mut Element<'_, '_>, _: &Info| {
+                el.prepend("_", ContentType::Html);
+                el.remove_and_keep_content();
+                el.append("_", ContentType::Html);
+            });
+
+            let mut user_mapping = HashMap::new();
+            user_mapping.insert(user_id!("@tomsg_tom:lieuwe.xyz"), "tom".to_string());
+            let f = move |user_id: UserId, _: &Info| user_mapping.get(&user_id).cloned();
+            info.user_mapper(f);
+
+            let before =
+                "<em>hi</em> <a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">tom (tomsg)</a>";
+            let after = "_hi_ tom";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_plain_text() {
+            let info = plain_text_preset();
+
+            let before = "<mx-reply><blockquote>quoted</blockquote></mx-reply><p><strong>Hi</strong> &amp; welcome, see <a href=\"https://example.com\">the site</a></p><ul><li>one</li><li>two<ol><li>three</li></ol></li></ul>bye<br>now";
+            let after = "Hi & welcome, see the site (https://example.com)\n\n- one\n- two\n  1. three\n\nbye\nnow";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_preset_irc() {
+            let info = Info::with_preset(Protocol::Irc);
+
+            let before = "<strong>bold</strong> <em>it</em> <code>x</code> <font data-mx-color=\"#ff0000\">red</font> <span data-mx-spoiler>secret</span> &amp;";
+            let after = "\x02bold\x02 \x1Dit\x1D \x11x\x11 \x0304red\x03\x02\x02 \x0301,01secret\x03\x02\x02 &";
+            assert_eq!(after, convert(before, &info).unwrap());
+
+            // a digit after a color isn't read as a color code.
+            let before = "<font data-mx-color=\"#ff0000\">red</font>12 apples";
+            let after = "\x0304red\x03\x02\x0212 apples";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_preset_discord() {
+            let info = Info::with_preset(Protocol::Discord);
+
+            let before = "<blockquote>quote</blockquote><u>under</u> <span data-mx-spoiler=\"plot\">secret</span> &lt;3";
+            let after = "> quote\n\n__under__ ||secret|| <3";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_preset_telegram() {
+            let info = Info::with_preset(Protocol::Telegram);
+
+            let before = "<h1>Title</h1><strong class=\"x\">bold</strong> <a href=\"https://example.com\" title=\"t\">link</a> <span data-mx-spoiler>secret</span> <font color=\"red\">&lt;3</font>";
+            let after = "<b>Title</b>\n<strong>bold</strong> <a href=\"https://example.com\">link</a> <tg-spoiler>secret</tg-spoiler> &lt;3";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_preset_whatsapp() {
+            let info = Info::with_preset(Protocol::WhatsApp);
+
+            let before = "<strong>bold</strong> <em>it</em> <del>gone</del> <code>mono</code>";
+            let after = "*bold* _it_ ~gone~ ```mono```";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_spoiler_handler() {
+            let mut info = Info::new();
+            info.spoiler_handler(|el: &mut Element<'_, '_>, spoiler: &Spoiler, _: &Info| {
+                let reason = spoiler.reason.as_deref().unwrap_or("none");
+                el.prepend(&format!("<{}>", reason), ContentType::Text);
+                el.remove_and_keep_content();
+            });
+
+            let before =
+                "<span data-mx-spoiler=\"plot\">he dies</span> <span data-mx-spoiler>x</span>";
+            let after = "&lt;plot&gt;he dies &lt;none&gt;x";
+            assert_eq!(after, convert(before, &info).unwrap());
+
+            let info = plain_text_preset();
+            let before = "<span data-mx-spoiler=\"plot\">he dies</span>";
+            assert_eq!("[spoiler: plot] he dies", convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_extract_reply() {
+            let before = "<mx-reply><blockquote><a href=\"https://matrix.to/#/!opVyAOHWsarCVcEQkE:lieuwe.xyz/$wjpDcX-sy3dLophlXRfL0pyE4yotZ5XK8v1DF_VMpoU?via=lieuwe.xyz\">In reply to</a> <a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">@tomsg_tom:lieuwe.xyz</a><br>⛄️ <em>hi</em></blockquote></mx-reply>Hallo";
+
+            let (html, reply) = extract_reply(before);
+            assert_eq!(html, "Hallo");
+
+            let reply = reply.unwrap();
+            assert_eq!(reply.room.as_str(), "!opVyAOHWsarCVcEQkE:lieuwe.xyz");
+            assert_eq!(
+                reply.event_id.as_str(),
+                "$wjpDcX-sy3dLophlXRfL0pyE4yotZ5XK8v1DF_VMpoU"
+            );
+            assert_eq!(reply.sender, Some(user_id!("@tomsg_tom:lieuwe.xyz")));
+            assert_eq!(reply.body, "⛄️ <em>hi</em>");
+
+            let (html, reply) = extract_reply("no reply");
+            assert_eq!(html, "no reply");
+            assert!(reply.is_none());
+        }
+
+        #[test]
+        fn test_images() {
+            let mut info = Info::new();
+            info.homeserver_url("https://lieuwe.xyz".parse().unwrap());
+
+            let before = "<img src=\"mxc://lieuwe.xyz/abc\" alt=\"cat.png\"> <img src=\"mxc://lieuwe.xyz/def\" alt=\":party:\" data-mx-emoticon height=\"32\">";
+            let after =
+                "cat.png (https://lieuwe.xyz/_matrix/media/r0/download/lieuwe.xyz/abc) :party:";
+            assert_eq!(after, convert(before, &info).unwrap());
+
+            info.image_handler(|el: &mut Element<'_, '_>, image: &Image, _: &Info| {
+                let kind = if image.emoticon { "emote" } else { "image" };
+                let s = format!("{} {:?} {:?}", kind, image.src, image.height);
+                el.replace(&s, ContentType::Html);
+            });
+            let after =
+                "image Some(mxc://lieuwe.xyz/abc) None emote Some(mxc://lieuwe.xyz/def) Some(32)";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_color_handler() {
+            let mut info = Info::new();
+            info.color_handler(|el: &mut Element<'_, '_>, colors: &Colors, _: &Info| {
+                el.replace(&format!("{:?}", colors), ContentType::Html);
+            });
+
+            let before = "<font data-mx-color=\"#ff0080\" data-mx-bg-color=\"#000000\">x</font><span data-mx-bg-color=\"#FFFFFF\">y</span><span>z</span><font color=\"red\">w</font>";
+            let red = Color {
+                red: 255,
+                green: 0,
+                blue: 128,
+            };
+            let black = Color {
+                red: 0,
+                green: 0,
+                blue: 0,
+            };
+            let white = Color {
+                red: 255,
+                green: 255,
+                blue: 255,
+            };
+            let after = format!(
+                "{:?}{:?}zw",
+                Colors {
+                    foreground: Some(red),
+                    background: Some(black),
+                },
+                Colors {
+                    foreground: None,
+                    background: Some(white),
+                }
+            );
+            assert_eq!(after, convert(before, &info).unwrap());
+
+            let info = Info::with_preset(Protocol::Irc);
+            let before = "<span data-mx-bg-color=\"#00ff00\">y</span>";
+            assert_eq!("\x0399,09y\x03\x02\x02", convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_code_block_handler() {
+            let mut info = Info::new();
+            info.code_block_handler(|el: &mut Element<'_, '_>, block: &CodeBlock, _: &Info| {
+                let s = format!("{:?}: {}", block.language, block.content);
+                el.replace(&s, ContentType::Html);
+            });
+
+            let before = "<pre><code class=\"hl language-python\">print(<b>1</b> &lt; 2)</code></pre><pre>plain</pre>";
+            let after = "Some(\"python\"): print(1 < 2)None: plain";
+            assert_eq!(after, convert(before, &info).unwrap());
+
+            let info = markdown_preset();
+            let before = "<pre><code>a\nb\n</code></pre>";
+            assert_eq!("```\na\nb\n```", convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_streaming() {
+            let info = plain_text_preset();
+
+            let before = "<p>\n\nÄ &amp; ⛄️ <strong>bold</strong> &#x1F600;</p><ul><li>één</li><li>two &lt;3</li></ul>\n\n\n";
+            let expected = convert(before, &info).unwrap();
+
+            for chunk_size in 1..8 {
+                let mut converter = StreamingConverter::new(&info, Vec::new());
+                for chunk in before.as_bytes().chunks(chunk_size) {
+                    converter.write(chunk).unwrap();
+                }
+                let after = converter.end().unwrap();
+                assert_eq!(expected, String::from_utf8(after).unwrap());
+            }
+
+            let info = Info::new();
+            let after = convert_stream(before.as_bytes(), Vec::new(), &info).unwrap();
+            assert_eq!(
+                convert(before, &info).unwrap(),
+                String::from_utf8(after).unwrap()
+            );
+        }
+
+        #[test]
+        fn test_decode_entities() {
+            let mut info = Info::new();
+            info.decode_entities(true);
+
+            let before = "&lt;3 &#x1F600; &#65; &bogus; a & b";
+            let after = "<3 😀 A &bogus; a & b";
+            assert_eq!(after, convert(before, &info).unwrap());
+        }
+
+        #[test]
+        fn test_convert_async() {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+
+            let calls = AtomicUsize::new(0);
+            let user_mapper = |user_id: UserId| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if user_id.localpart() == "tomsg_tom" {
+                        Some("tom".to_string())
+                    } else {
+                        None
+                    }
+                }
+            };
+            let room_mapper = |_: RoomAliasId| async { Some("#kaas".to_string()) };
+
+            let before = "<a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">x</a>, \
+                <a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">x</a>, \
+                <a href=\"https://matrix.to/#/@other:lieuwe.xyz\">other</a> in \
+                <a href=\"https://matrix.to/#/#kaas:lieuwe.xyz\">room</a>";
+            let after = futures::executor::block_on(convert_async(
+                before,
+                &Info::new(),
+                user_mapper,
+                room_mapper,
+            ))
+            .unwrap();
+
+            assert_eq!(
+                after,
+                "tom, tom, [other](https://matrix.to/#/@other:lieuwe.xyz) in #kaas"
+            );
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+        }
+
+        #[cfg(feature = "emoji")]
+        #[test]
+        fn test_emoji_shortcodes() {
+            let mut info = Info::new();
+            info.emoji_shortcodes(true).decode_entities(true);
+
+            let before = "<b>hi</b> 😄 &#x1F44D; caf\u{e9}";
+            assert_eq!("hi :smile: :+1: caf\u{e9}", convert(before, &info).unwrap());
+
+            let mut after = Vec::new();
+            let mut converter = StreamingConverter::new(&info, &mut after);
+            for chunk in before.as_bytes().chunks(3) {
+                converter.write(chunk).unwrap();
+            }
+            converter.end().unwrap();
+            assert_eq!(
+                "hi :smile: :+1: caf\u{e9}",
+                String::from_utf8(after).unwrap()
+            );
+        }
+
+        #[test]
+        fn test_extract_mentions() {
+            let mut info = Info::new();
+            info.user_mapper(|user_id, _| {
+                Some(user_id.localpart().trim_start_matches("irc_").to_string())
+            });
+
+            let before = "<a href=\"https://matrix.to/#/@irc_tom:lieuwe.xyz\">Tom</a>: see \
+                <a href=\"https://matrix.to/#/#kaas:lieuwe.xyz\">#kaas</a>, \
+                <a href=\"https://example.com\">link</a> \
+                <a href=\"https://matrix.to/#/@irc_tom:lieuwe.xyz\">Tom</a>";
+            assert_eq!(
+                extract_mentions(before, &info),
+                vec![
+                    Mention {
+                        target: MentionTarget::User(user_id!("@irc_tom:lieuwe.xyz")),
+                        mapped: Some("tom".to_string()),
+                    },
+                    Mention {
+                        target: MentionTarget::Room(
+                            RoomAliasId::try_from("#kaas:lieuwe.xyz").unwrap()
+                        ),
+                        mapped: None,
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn test_malformed_mentions() {
+            let mut info = Info::new();
+            info.user_mapper(|_, _| Some("user".to_string()))
+                .room_mapper(|_, _| Some("room".to_string()));
+
+            let before = "<a href=\"https://matrix.to/#/@bad\">bad user</a> and \
+                <a href=\"https://matrix.to/#/#bad\">bad room</a>";
+            let after =
+                "[bad user](https://matrix.to/#/@bad) and [bad room](https://matrix.to/#/#bad)";
+            assert_eq!(after, convert(before, &info).unwrap());
+            assert_eq!(extract_mentions(before, &info), vec![]);
+
+            let after = futures::executor::block_on(convert_async(
+                before,
+                &Info::new(),
+                |_: UserId| async { Some("user".to_string()) },
+                |_: RoomAliasId| async { Some("room".to_string()) },
+            ))
+            .unwrap();
+            assert_eq!(
+                after,
+                "[bad user](https://matrix.to/#/@bad) and [bad room](https://matrix.to/#/#bad)"
+            );
+        }
+
+        #[test]
+        fn test_split_message() {
+            assert_eq!(
+                split_message("hello world foo", Budget::Bytes(11), &[]),
+                vec!["hello world", "foo"]
+            );
+            assert_eq!(
+                split_message("one two\nthree four", Budget::Bytes(14), &[]),
+                vec!["one two", "three four"]
+            );
+            let link = "see [the docs](https://example.com) now";
+            assert_eq!(
+                split_message(link, Budget::Bytes(32), &[]),
+                vec!["see", "[the docs](https://example.com)", "now"]
+            );
+            // links that don't fit in a message on their own have to be split.
+            assert_eq!(
+                split_message(link, Budget::Bytes(20), &[]),
+                vec!["see", "[the docs](https://e", "xample.com) now"]
+            );
+            assert_eq!(
+                split_message("hi Tom (IRC) there", Budget::Bytes(10), &["Tom (IRC)"]),
+                vec!["hi", "Tom (IRC)", "there"]
+            );
+            assert_eq!(
+                split_message("ééééé", Budget::Bytes(5), &[]),
+                vec!["éé", "éé", "é"]
+            );
+            assert_eq!(
+                split_message("ééééé", Budget::Chars(3), &[]),
+                vec!["ééé", "éé"]
+            );
+            assert!(split_message("   ", Budget::Chars(3), &[]).is_empty());
+        }
+
+        #[test]
+        fn test_shared_info() {
+            let mut user_mapping = HashMap::new();
+            user_mapping.insert(user_id!("@tomsg_tom:lieuwe.xyz"), "tom".to_string());
+
+            let mut info = Info::new();
+            info.user_mapper(move |user_id, _| user_mapping.get(&user_id).cloned());
+            info.add_element_handler("b".to_string(), |el, _| super::wrap(el, "*", "*"));
+            let info = std::sync::Arc::new(info);
+
+            let shared = info.clone();
+            let after = std::thread::spawn(move || {
+                let before = "<b><a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">x</a></b>";
+                convert(before, &shared).unwrap()
+            })
+            .join()
+            .unwrap();
+            assert_eq!(after, "*tom*");
+        }
+    }
+}
+
+pub mod to_matrix {
+    use std::collections::HashMap;
+    use std::ops::Range;
+    use std::sync::{Arc, Mutex, PoisonError};
+
+    #[cfg(feature = "emoji")]
+    use crate::convert::emoji::shortcodes_to_unicode;
+    use crate::matrix::MatrixToItem;
+
+    use ruma::events::room::message::MessageEventContent;
+    use ruma::identifiers::UserId;
+    use serde::ser::Error as _;
+    use serde::{Serialize, Serializer};
+    use serde_json::json;
+
+    // HACK
+    use regex::escape;
+
+    #[cfg(feature = "pcre2")]
+    use pcre2::bytes::{Regex, RegexBuilder};
+    #[cfg(not(feature = "pcre2"))]
+    use regex::{Regex, RegexBuilder};
+
+    /// A set of names that should be replaced with links to Matrix users, rooms or events.
+    ///
+    /// The regex used to find the names is rebuilt lazily, the first time a message is converted
+    /// after the names or options have changed.
+    pub struct MentionMatcher {
+        /// A map from the names to what they link to.
+        map: HashMap<String, Target>,
+        escape: bool,
+        intentional_mentions: bool,
+        case_insensitive: bool,
+        boundary_chars: Option<String>,
+        sigil: Option<(String, bool)>,
+        #[cfg(feature = "emoji")]
+        emoji_shortcodes: bool,
+        regex: Mutex<Option<Arc<BuiltRegex>>>,
+    }
+
+    impl MentionMatcher {
+        pub fn new() -> Self {
+            Self {
+                map: HashMap::new(),
+                escape: true,
+                intentional_mentions: true,
+                case_insensitive: false,
+                boundary_chars: None,
+                sigil: None,
+                #[cfg(feature = "emoji")]
+                emoji_shortcodes: false,
+                regex: Mutex::new(None),
+            }
+        }
+
+        /// Link the given `name` to `item`, returning the URL the name previously linked to.
+        pub fn insert(&mut self, name: impl Into<String>, item: &MatrixToItem) -> Option<String> {
+            let target = Target {
+                url: item.to_url_string(),
+                user_id: match item {
+                    MatrixToItem::User(user_id) => Some((*user_id).clone()),
+                    _ => None,
+                },
+            };
+            let prev = self.map.insert(name.into(), target);
+            // changing just the target of a name doesn't change the regex.
+            if prev.is_none() {
+                self.invalidate();
+            }
+            prev.map(|target| target.url)
+        }
+
+        /// Stop linking the given `name`, returning the URL it linked to.
+        pub fn remove(&mut self, name: &str) -> Option<String> {
+            let prev = self.map.remove(name);
+            if prev.is_some() {
+                self.invalidate();
+            }
+            prev.map(|target| target.url)
+        }
+
+        pub fn contains(&self, name: &str) -> bool {
+            self.map.contains_key(name)
+        }
+
+        pub fn len(&self) -> usize {
+            self.map.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.map.is_empty()
+        }
+
+        /// Whether names should be matched case-insensitively, defaults to `false`.
+        pub fn case_insensitive(&mut self, case_insensitive: bool) -> &mut Self {
+            self.case_insensitive = case_insensitive;
+            self.invalidate();
+            self
+        }
+
+        /// Only match names surrounded by the given characters, or the start or end of the
+        /// message.  By default names are surrounded by any non-word character.
+        pub fn boundary_chars(&mut self, chars: impl Into<String>) -> &mut Self {
+            self.boundary_chars = Some(chars.into());
+            self.invalidate();
+            self
+        }
+
+        /// Match names prefixed with `sigil`, like `@tom`.  If `required` is `false`, names
+        /// without the sigil are matched as well.  The sigil is replaced together with the name.
+        pub fn sigil(&mut self, sigil: impl Into<String>, required: bool) -> &mut Self {
+            self.sigil = Some((sigil.into(), required));
+            self.invalidate();
+            self
+        }
+
+        /// Whether `to_content` should list the mentioned users in `m.mentions`, as intentional
+        /// mentions (MSC3952), defaults to `true`.
+        pub fn intentional_mentions(&mut self, intentional_mentions: bool) -> &mut Self {
+            self.intentional_mentions = intentional_mentions;
+            self
+        }
 
-            let f = move |user_id: UserId, _: &Info| user_mapping.get(&user_id).cloned();
-            info.user_mapper(&f);
+        /// Whether to HTML-escape the text around mentions, defaults to `true`.
+        ///
+        /// Only disable this when the input is already sanitized HTML.
+        pub fn escape(&mut self, escape: bool) -> &mut Self {
+            self.escape = escape;
+            self
+        }
 
-            assert_eq!(after, convert(&before, &info).unwrap());
+        /// Whether to replace `:shortcode:`s with Unicode emoji, defaults to `false`.
+        #[cfg(feature = "emoji")]
+        pub fn emoji_shortcodes(&mut self, emoji_shortcodes: bool) -> &mut Self {
+            self.emoji_shortcodes = emoji_shortcodes;
+            self
+        }
+
+        fn invalidate(&mut self) {
+            *self.regex.get_mut().unwrap_or_else(PoisonError::into_inner) = None;
+        }
+
+        /// Get the regex for the current names, building it if required.
+        fn regex(&self) -> Arc<BuiltRegex> {
+            let mut regex = self.regex.lock().unwrap_or_else(PoisonError::into_inner);
+            regex
+                .get_or_insert_with(|| Arc::new(build_regex(self)))
+                .clone()
+        }
+
+        fn lookup(&self, regex: &BuiltRegex, name: &str) -> Option<&Target> {
+            match &regex.lowercase {
+                Some(lowercase) => self.map.get(lowercase.get(&name.to_lowercase())?),
+                None => self.map.get(name),
+            }
         }
     }
-}
 
-pub mod to_matrix {
-    use std::collections::HashMap;
+    impl Default for MentionMatcher {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
 
-    use crate::matrix::MatrixToItem;
+    impl<'a, S: Into<String>> Extend<(S, MatrixToItem<'a>)> for MentionMatcher {
+        fn extend<T: IntoIterator<Item = (S, MatrixToItem<'a>)>>(&mut self, iter: T) {
+            for (name, item) in iter {
+                self.insert(name, &item);
+            }
+        }
+    }
 
-    // HACK
-    use regex::escape;
+    /// What a name in a `MentionMatcher` links to.
+    struct Target {
+        url: String,
+        /// The user, if the name belongs to a user.
+        user_id: Option<UserId>,
+    }
 
-    use pcre2::bytes::Regex;
+    struct BuiltRegex {
+        regex: Regex,
+        /// When matching case-insensitively, a map from the lowercased names to the names in the
+        /// mapping.
+        lowercase: Option<HashMap<String, String>>,
+    }
 
-    pub struct Info<'a> {
-        pub map: HashMap<String, MatrixToItem<'a>>,
+    impl BuiltRegex {
+        /// Find the mentions in `s`, returning the range of every mention including its sigil,
+        /// and the range of the name itself.
+        #[cfg(feature = "pcre2")]
+        fn find_mentions(&self, s: &str) -> Vec<(Range<usize>, Range<usize>)> {
+            // matching fails when PCRE2 hits its match or depth limit, stop at the first error
+            // instead, keeping the mentions found before it.
+            self.regex
+                .captures_iter(s.as_bytes())
+                .map_while(Result::ok)
+                .map(|cap| {
+                    // the whole match includes the sigil, if any.
+                    let whole = cap.get(0).unwrap();
+                    let name = cap.get(1).unwrap();
+                    (whole.start()..whole.end(), name.start()..name.end())
+                })
+                .collect()
+        }
+
+        /// Find the mentions in `s`, returning the range of every mention including its sigil,
+        /// and the range of the name itself.
+        #[cfg(not(feature = "pcre2"))]
+        fn find_mentions(&self, s: &str) -> Vec<(Range<usize>, Range<usize>)> {
+            // the `regex` crate doesn't support lookarounds, so the boundaries are part of the
+            // match.  Continue searching at the end of the name, since the trailing boundary can
+            // be the leading boundary of the next mention.
+            let mut res = vec![];
+            let mut pos = 0;
+            while let Some(cap) = self.regex.captures_at(s, pos) {
+                let whole = cap.get(1).unwrap();
+                let name = cap.get(2).unwrap();
+                res.push((whole.start()..name.end(), name.start()..name.end()));
+                pos = name.end();
+            }
+            res
+        }
+    }
+
+    /// Escape the characters in `s` that have a special meaning in HTML.
+    fn escape_html(s: &str) -> String {
+        let mut res = String::with_capacity(s.len());
+        for c in s.chars() {
+            match c {
+                '&' => res.push_str("&amp;"),
+                '<' => res.push_str("&lt;"),
+                '>' => res.push_str("&gt;"),
+                '"' => res.push_str("&quot;"),
+                '\'' => res.push_str("&#39;"),
+                c => res.push(c),
+            }
+        }
+        res
+    }
+
+    /// Wrap the given plain `text` in a Matrix spoiler, optionally with the given `reason`.
+    pub fn spoiler(text: &str, reason: Option<&str>) -> String {
+        format!(
+            "<span data-mx-spoiler=\"{}\">{}</span>",
+            escape_html(reason.unwrap_or("")),
+            escape_html(text)
+        )
+    }
+
+    fn build_regex(matcher: &MentionMatcher) -> BuiltRegex {
+        let boundary = match &matcher.boundary_chars {
+            // the same as `\W` in PCRE2 without Unicode properties.
+            None => "[^0-9A-Za-z_]".to_string(),
+            Some(chars) => {
+                let mut class = "[".to_string();
+                for c in chars.chars() {
+                    if matches!(c, '\\' | ']' | '[' | '^' | '-') {
+                        class.push('\\');
+                    }
+                    class.push(c);
+                }
+                class + "]"
+            }
+        };
+
+        let sigil = match &matcher.sigil {
+            None => String::new(),
+            Some((sigil, true)) => escape(sigil),
+            Some((sigil, false)) => format!("(?:{})?", escape(sigil)),
+        };
+        let names = matcher
+            .map
+            .keys()
+            .map(|key| escape(key))
+            .collect::<Vec<_>>()
+            .join("|");
+
+        #[cfg(feature = "pcre2")]
+        let regex = {
+            let regex_string = format!("(?<=^|{b}){}({})(?=$|{b})", sigil, names, b = boundary);
+            RegexBuilder::new()
+                .utf(true)
+                .caseless(matcher.case_insensitive)
+                .build(&regex_string)
+                .unwrap()
+        };
+        #[cfg(not(feature = "pcre2"))]
+        let regex = {
+            let regex_string =
+                format!("(?:^|{b})((?:{})({}))(?:$|{b})", sigil, names, b = boundary);
+            RegexBuilder::new(&regex_string)
+                .case_insensitive(matcher.case_insensitive)
+                .build()
+                .unwrap()
+        };
+
+        let lowercase = if matcher.case_insensitive {
+            Some(
+                matcher
+                    .map
+                    .keys()
+                    .map(|key| (key.to_lowercase(), key.clone()))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+
+        BuiltRegex { regex, lowercase }
+    }
+
+    /// Convert the plain text message `s` to Matrix HTML, replacing the names in `matcher` with
+    /// links.
+    ///
+    /// The rest of the text is HTML-escaped, unless disabled using `MentionMatcher::escape`.
+    /// Names in code spans and URLs are left alone, or when the input is HTML, names in tags and
+    /// in `<code>`, `<pre>` and `<a>` elements.
+    pub fn convert(matcher: &MentionMatcher, s: &str) -> String {
+        linkify(matcher, s, matcher.escape, &mut vec![])
+    }
+
+    /// A message converted to Matrix, ready to be sent.
+    ///
+    /// This serializes to the `m.room.message` content, including `m.mentions` if set.
+    #[derive(Debug, Clone)]
+    pub struct MessageContent {
+        pub content: MessageEventContent,
+        /// The users mentioned in the message, as intentional mentions (MSC3952).
+        pub mentions: Option<Vec<UserId>>,
+    }
+
+    impl Serialize for MessageContent {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut value = serde_json::to_value(&self.content).map_err(S::Error::custom)?;
+            if let Some(mentions) = &self.mentions {
+                value["m.mentions"] = json!({ "user_ids": mentions });
+            }
+            value.serialize(serializer)
+        }
+    }
+
+    /// Convert the plain text message `s` to a text message, with the names in `matcher` replaced
+    /// with links in the `formatted_body`.
+    ///
+    /// The `formatted_body` is left out if it wouldn't add anything to the `body`.
+    pub fn to_content(matcher: &MentionMatcher, s: &str) -> MessageContent {
+        let mut mentions = vec![];
+        let formatted = linkify(matcher, s, true, &mut mentions);
+
+        #[cfg(feature = "emoji")]
+        let body = if matcher.emoji_shortcodes {
+            shortcodes_to_unicode(s).into_owned()
+        } else {
+            s.to_string()
+        };
+        #[cfg(not(feature = "emoji"))]
+        let body = s.to_string();
+
+        let content = if formatted == escape_html(&body) {
+            MessageEventContent::text_plain(body)
+        } else {
+            MessageEventContent::text_html(body, formatted)
+        };
+
+        MessageContent {
+            content,
+            mentions: if matcher.intentional_mentions {
+                Some(mentions)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Replace the mentions in `s` with links, HTML-escaping the other text if `escape` is set.
+    ///
+    /// The mentioned users are added to `mentions`.
+    fn linkify(
+        matcher: &MentionMatcher,
+        s: &str,
+        escape: bool,
+        mentions: &mut Vec<UserId>,
+    ) -> String {
+        let mut protected = if escape {
+            protected_text_ranges(s)
+        } else {
+            protected_html_ranges(s)
+        };
+        protected.sort_by_key(|r| r.start);
+
+        let escaped = |s: &str| {
+            if escape {
+                escape_html(s)
+            } else {
+                s.to_string()
+            }
+        };
+        // the text in the given range, with emoji shortcodes replaced outside of protected
+        // ranges if enabled.
+        let text = |range: Range<usize>| -> String {
+            #[cfg(feature = "emoji")]
+            if matcher.emoji_shortcodes {
+                let mut res = String::with_capacity(range.len());
+                let mut pos = range.start;
+                for r in &protected {
+                    if r.end <= pos || r.start >= range.end {
+                        continue;
+                    }
+                    let start = r.start.max(pos);
+                    let end = r.end.min(range.end);
+                    res += &escaped(&shortcodes_to_unicode(&s[pos..start]));
+                    res += &escaped(&s[start..end]);
+                    pos = end;
+                }
+                res += &escaped(&shortcodes_to_unicode(&s[pos..range.end]));
+                return res;
+            }
+
+            escaped(&s[range])
+        };
+
+        let mut res = String::with_capacity(s.len());
+        let mut last = 0;
+        for (whole, name, to) in find_targets(matcher, s, &protected) {
+            let name = &s[name];
+            if let Some(user_id) = &to.user_id {
+                if !mentions.contains(user_id) {
+                    mentions.push(user_id.clone());
+                }
+            }
+
+            res += &text(last..whole.start);
+            res += &format!("<a href=\"{}\">{}</a>", escape_html(&to.url), escaped(name));
+            last = whole.end;
+        }
+        res += &text(last..s.len());
+        res
+    }
+
+    /// Find the names of `matcher` in `s` outside of the `protected` ranges, returning the range
+    /// of every mention including its sigil, the range of the name, and what it links to.
+    fn find_targets<'m>(
+        matcher: &'m MentionMatcher,
+        s: &str,
+        protected: &[Range<usize>],
+    ) -> Vec<(Range<usize>, Range<usize>, &'m Target)> {
+        // an empty map would result in a regex matching the empty string everywhere.
+        if matcher.is_empty() {
+            return vec![];
+        }
+
+        let regex = matcher.regex();
+        regex
+            .find_mentions(s)
+            .into_iter()
+            .filter(|(whole, _)| {
+                !protected
+                    .iter()
+                    .any(|r| r.start < whole.end && whole.start < r.end)
+            })
+            .filter_map(|(whole, name)| {
+                let target = matcher.lookup(&regex, &s[name.clone()])?;
+                Some((whole, name, target))
+            })
+            .collect()
+    }
+
+    /// A name found in an external message by `extract_mentions`.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ExternalMention {
+        /// The name as it appears in the message.
+        pub name: String,
+        /// The matrix.to URL the name links to.
+        pub url: String,
+        /// The mentioned user, if the name belongs to a user.
+        pub user_id: Option<UserId>,
+    }
+
+    /// Get the distinct mentions of the names in `matcher` in the external message `s`, in
+    /// order of appearance, without converting the message.
+    pub fn extract_mentions(matcher: &MentionMatcher, s: &str) -> Vec<ExternalMention> {
+        let protected = if matcher.escape {
+            protected_text_ranges(s)
+        } else {
+            protected_html_ranges(s)
+        };
+
+        let mut res: Vec<ExternalMention> = vec![];
+        for (_, name, target) in find_targets(matcher, s, &protected) {
+            if res.iter().any(|m| m.url == target.url) {
+                continue;
+            }
+            res.push(ExternalMention {
+                name: s[name].to_string(),
+                url: target.url.clone(),
+                user_id: target.user_id.clone(),
+            });
+        }
+        res
     }
 
-    pub struct BuiltRegex(Regex);
+    /// Find the ranges of `s` in which mentions shouldn't be replaced when `s` is plain text: code
+    /// spans delimited by backticks, and URLs.
+    fn protected_text_ranges(s: &str) -> Vec<Range<usize>> {
+        let bytes = s.as_bytes();
+        let mut res = vec![];
+
+        // code spans and blocks, opened and closed by the same amount of backticks.
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] != b'`' {
+                i += 1;
+                continue;
+            }
 
-    pub fn build_regex(info: &Info) -> BuiltRegex {
-        let mut regex_string = r"(?<=^|\W)(".to_string();
-        for (i, (key, _)) in info.map.iter().enumerate() {
-            if i > 0 {
-                regex_string += "|";
+            let start = i;
+            while i < bytes.len() && bytes[i] == b'`' {
+                i += 1;
             }
+            let fence = &s[start..i];
+
+            let mut j = i;
+            let end = loop {
+                match s[j..].find(fence) {
+                    None => break None,
+                    Some(k) => {
+                        let k = j + k;
+                        let run_end = k + s[k..].bytes().take_while(|&b| b == b'`').count();
+                        if run_end - k == fence.len() {
+                            break Some(run_end);
+                        }
+                        j = run_end;
+                    }
+                }
+            };
+            if let Some(end) = end {
+                res.push(start..end);
+                i = end;
+            }
+        }
 
-            regex_string += &escape(key);
+        // URLs, from their scheme up to the next whitespace.
+        let mut searched = 0;
+        while let Some(k) = s[searched..].find("://") {
+            let k = searched + k;
+            let start = s[..k]
+                .rfind(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')))
+                .map(|i| i + 1)
+                .unwrap_or(0);
+            let end = s[k..]
+                .find(char::is_whitespace)
+                .map(|i| k + i)
+                .unwrap_or_else(|| s.len());
+            if start < k {
+                res.push(start..end);
+            }
+            searched = end.max(k + 3);
         }
-        regex_string += r")(?=$|\W)";
 
-        let regex = Regex::new(&regex_string).unwrap();
-        BuiltRegex(regex)
+        res
     }
 
-    pub fn convert(regex: BuiltRegex, mut s: String, info: &Info) -> String {
-        let s_cloned = s.clone();
-        // find names that are in the map, and replace them with an url.
-        let captures = regex.0.captures_iter(s_cloned.as_bytes());
+    /// Find the ranges of `s` in which mentions shouldn't be replaced when `s` is HTML: the tags
+    /// themselves, and the contents of `<code>`, `<pre>` and `<a>` elements.
+    fn protected_html_ranges(s: &str) -> Vec<Range<usize>> {
+        let lower = s.to_ascii_lowercase();
+        let mut res = vec![];
+
+        let mut i = 0;
+        while let Some(k) = lower[i..].find('<') {
+            let start = i + k;
+            let end = match lower[start..].find('>') {
+                Some(k) => start + k + 1,
+                None => {
+                    res.push(start..s.len());
+                    break;
+                }
+            };
+
+            let name: String = lower[start + 1..end]
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect();
+            let end = if matches!(name.as_str(), "code" | "pre" | "a") {
+                let close = format!("</{}", name);
+                match lower[end..].find(&close) {
+                    Some(k) => {
+                        let k = end + k;
+                        lower[k..].find('>').map(|i| k + i + 1).unwrap_or(s.len())
+                    }
+                    None => s.len(),
+                }
+            } else {
+                end
+            };
 
-        let mut delta = 0i64;
-        for cap in captures {
-            let m = cap.unwrap().get(1).unwrap();
-            // safety: we know the input bytes to the regex is well-formed utf-8, the regex only
-            // works on utf-8 runes.  Therefore, the capture group also must only contain
-            // well-formed utf-8 bytes.
-            let name = unsafe { std::str::from_utf8_unchecked(m.as_bytes()) };
-            let to = info.map.get(name).unwrap();
-            let to = to.to_url_string();
-            let to = format!("<a href=\"{}\">{}</a>", to, name);
+            res.push(start..end);
+            i = end;
+        }
 
-            let start = (m.start() as i64 + delta) as usize;
-            let end = (m.end() as i64 + delta) as usize;
+        res
+    }
 
-            s.replace_range(start..end, &to);
+    /// A message converted from an external format, with both the plain `body` fallback and the
+    /// `formatted_body` in Matrix HTML.
+    #[cfg(feature = "markdown")]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Formatted {
+        pub body: String,
+        pub formatted_body: String,
+    }
 
-            delta += (to.len() as i64) - ((end - start) as i64);
+    /// Convert the external markdown message `s` to Matrix HTML, replacing the names in `matcher`
+    /// with links.
+    ///
+    /// Raw HTML in the input is escaped, and images are converted to links, since Matrix only
+    /// allows `mxc://` images. Mentions are not replaced in code and link texts. The input
+    /// message is used verbatim as the plain `body`.
+    #[cfg(feature = "markdown")]
+    pub fn from_markdown(matcher: &MentionMatcher, s: &str) -> Formatted {
+        use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag, TagEnd, TextMergeStream};
+
+        let mut code_depth = 0usize;
+        let mut link_depth = 0usize;
+
+        // the parser splits text at delimiters and entities, so merge the adjacent text events to
+        // match names containing those.
+        let parser = TextMergeStream::new(Parser::new_ext(s, Options::ENABLE_STRIKETHROUGH));
+        let events = parser.map(|event| match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                code_depth += 1;
+                Event::Start(Tag::CodeBlock(kind))
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                code_depth -= 1;
+                Event::End(TagEnd::CodeBlock)
+            }
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            })
+            | Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => {
+                link_depth += 1;
+                Event::Start(Tag::Link {
+                    link_type,
+                    dest_url,
+                    title,
+                    id,
+                })
+            }
+            Event::End(TagEnd::Link) | Event::End(TagEnd::Image) => {
+                link_depth -= 1;
+                Event::End(TagEnd::Link)
+            }
+            Event::Text(text) if code_depth == 0 && link_depth == 0 => {
+                Event::InlineHtml(CowStr::from(linkify(matcher, &text, true, &mut vec![])))
+            }
+            Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+            event => event,
+        });
+
+        let mut formatted_body = String::with_capacity(s.len() * 3 / 2);
+        html::push_html(&mut formatted_body, events);
+
+        // a message consisting of a single paragraph doesn't need to be wrapped in one.
+        let trimmed = formatted_body.trim_end();
+        if let Some(inner) = trimmed
+            .strip_prefix("<p>")
+            .and_then(|s| s.strip_suffix("</p>"))
+        {
+            if !inner.contains("<p>") {
+                formatted_body = inner.to_string();
+            }
         }
 
-        s
+        Formatted {
+            body: s.to_string(),
+            formatted_body,
+        }
     }
 
     #[cfg(test)]
     mod tests {
-        use std::collections::HashMap;
-
         use ruma::identifiers::user_id;
 
-        use crate::convert::to_matrix::{build_regex, convert, Info};
+        use serde_json::json;
+
+        use crate::convert::to_matrix::{
+            convert, extract_mentions, spoiler, to_content, ExternalMention, MentionMatcher,
+        };
         use crate::MatrixToItem;
 
+        #[test]
+        #[cfg(feature = "pcre2")]
+        fn test_match_limit() {
+            use super::BuiltRegex;
+
+            let regex = BuiltRegex {
+                regex: pcre2::bytes::RegexBuilder::new()
+                    .build("(*LIMIT_MATCH=1000)(x|(?:a|b)*c)")
+                    .unwrap(),
+                lowercase: None,
+            };
+            let s = format!("x {}", "ab".repeat(1000));
+            assert_eq!(regex.find_mentions(&s), vec![(0..1, 0..1)]);
+        }
+
+        #[test]
+        fn test_spoiler() {
+            assert_eq!(
+                "<span data-mx-spoiler=\"&quot;plot&quot;\">a &lt; b</span>",
+                spoiler("a < b", Some("\"plot\""))
+            );
+            assert_eq!(
+                "<span data-mx-spoiler=\"\">secret</span>",
+                spoiler("secret", None)
+            );
+        }
+
         #[test]
         fn test_mapping() {
             let before = "hello tom";
             let after = "hello <a href=\"https://matrix.to/#/@tomsg_tom:lieuwe.xyz\">tom</a>";
 
-            let mut map = HashMap::new();
+            let mut matcher = MentionMatcher::new();
             let sed = user_id!("@tomsg_tom:lieuwe.xyz");
-            map.insert("tom".to_string(), MatrixToItem::User(&sed));
+            matcher.insert("tom", &MatrixToItem::User(&sed));
 
-            let info = Info { map };
-            let regex = build_regex(&info);
-
-            assert_eq!(after, convert(regex, before.to_string(), &info));
+            assert_eq!(after, convert(&matcher, before));
         }
 
         #[test]
@@ -355,14 +3514,11 @@ pub mod to_matrix {
             let before = "hello sed[m]";
             let after = "hello <a href=\"https://matrix.to/#/@sed:t2bot.io\">sed[m]</a>";
 
-            let mut map = HashMap::new();
+            let mut matcher = MentionMatcher::new();
             let sed = user_id!("@sed:t2bot.io");
-            map.insert("sed[m]".to_string(), MatrixToItem::User(&sed));
-
-            let info = Info { map };
-            let regex = build_regex(&info);
+            matcher.insert("sed[m]", &MatrixToItem::User(&sed));
 
-            assert_eq!(after, convert(regex, before.to_string(), &info));
+            assert_eq!(after, convert(&matcher, before));
         }
 
         #[test]
@@ -370,18 +3526,393 @@ pub mod to_matrix {
             let before = "hello sed[m] voyager[m]";
             let after = "hello <a href=\"https://matrix.to/#/@sed:t2bot.io\">sed[m]</a> <a href=\"https://matrix.to/#/@voyager:t2bot.io\">voyager[m]</a>";
 
-            let mut map = HashMap::new();
             let sed = user_id!("@sed:t2bot.io");
-            map.insert("sed[m]".to_string(), MatrixToItem::User(&sed));
             let voyager = user_id!("@voyager:t2bot.io");
-            map.insert("voyager[m]".to_string(), MatrixToItem::User(&voyager));
+            let mut matcher = MentionMatcher::new();
+            matcher.extend(vec![
+                ("sed[m]", MatrixToItem::User(&sed)),
+                ("voyager[m]", MatrixToItem::User(&voyager)),
+            ]);
+
+            assert_eq!(after, convert(&matcher, before));
+        }
+
+        #[test]
+        fn test_escaping() {
+            let mut matcher = MentionMatcher::new();
+            let sed = user_id!("@sed:t2bot.io");
+            matcher.insert("sed", &MatrixToItem::User(&sed));
+
+            let before = "<b>sed</b> & co";
+            let after =
+                "&lt;b&gt;<a href=\"https://matrix.to/#/@sed:t2bot.io\">sed</a>&lt;/b&gt; &amp; co";
+            assert_eq!(after, convert(&matcher, before));
+
+            matcher.escape(false);
+            let after = "<b><a href=\"https://matrix.to/#/@sed:t2bot.io\">sed</a></b> & co";
+            assert_eq!(after, convert(&matcher, before));
+
+            assert_eq!("a &lt; b", convert(&MentionMatcher::new(), "a < b"));
+        }
+
+        #[test]
+        fn test_mapping_overlap() {
+            let before = "tom to,to tomas";
+            let after = "<a href=\"https://matrix.to/#/@tom:lieuwe.xyz\">tom</a> \
+                <a href=\"https://matrix.to/#/@to:lieuwe.xyz\">to</a>,\
+                <a href=\"https://matrix.to/#/@to:lieuwe.xyz\">to</a> tomas";
+
+            let mut matcher = MentionMatcher::new();
+            let to = user_id!("@to:lieuwe.xyz");
+            matcher.insert("to", &MatrixToItem::User(&to));
+            let tom = user_id!("@tom:lieuwe.xyz");
+            matcher.insert("tom", &MatrixToItem::User(&tom));
+
+            assert_eq!(after, convert(&matcher, before));
+        }
+
+        #[test]
+        fn test_matcher_updates() {
+            let mut matcher = MentionMatcher::new();
+            let sed = user_id!("@sed:t2bot.io");
+            let voyager = user_id!("@voyager:t2bot.io");
+
+            matcher.insert("sed", &MatrixToItem::User(&sed));
+            assert_eq!(
+                "<a href=\"https://matrix.to/#/@sed:t2bot.io\">sed</a> voyager",
+                convert(&matcher, "sed voyager")
+            );
+
+            matcher.insert("voyager", &MatrixToItem::User(&voyager));
+            assert_eq!(
+                Some("https://matrix.to/#/@sed:t2bot.io".to_string()),
+                matcher.remove("sed")
+            );
+            assert_eq!(
+                "sed <a href=\"https://matrix.to/#/@voyager:t2bot.io\">voyager</a>",
+                convert(&matcher, "sed voyager")
+            );
+
+            matcher.insert("voyager", &MatrixToItem::User(&sed));
+            assert_eq!(
+                "sed <a href=\"https://matrix.to/#/@sed:t2bot.io\">voyager</a>",
+                convert(&matcher, "sed voyager")
+            );
+            assert_eq!(None, matcher.remove("sed"));
+            assert_eq!(1, matcher.len());
+        }
+
+        #[test]
+        fn test_protected_regions() {
+            let mut matcher = MentionMatcher::new();
+            let sed = user_id!("@sed:t2bot.io");
+            matcher.insert("sed", &MatrixToItem::User(&sed));
+            let link = "<a href=\"https://matrix.to/#/@sed:t2bot.io\">sed</a>";
+
+            let before = "sed: see `sed -i` and https://example.com/sed or ```\nsed\n```";
+            let after = format!(
+                "{}: see `sed -i` and https://example.com/sed or ```\nsed\n```",
+                link
+            );
+            assert_eq!(after, convert(&matcher, before));
+
+            matcher.escape(false);
+            let before = "<b title=\"sed\">sed</b> <code>sed</code> <pre>x\nsed</pre> \
+                <a href=\"https://example.com/sed\">sed</a> <abbr>sed</abbr>";
+            let after = format!(
+                "<b title=\"sed\">{}</b> <code>sed</code> <pre>x\nsed</pre> \
+                <a href=\"https://example.com/sed\">sed</a> <abbr>{}</abbr>",
+                link, link
+            );
+            assert_eq!(after, convert(&matcher, before));
+        }
+
+        #[test]
+        fn test_to_content() {
+            let mut matcher = MentionMatcher::new();
+            let sed = user_id!("@sed:t2bot.io");
+            matcher.insert("sed", &MatrixToItem::User(&sed));
+
+            let content = serde_json::to_value(to_content(&matcher, "sed: sed & co")).unwrap();
+            assert_eq!(
+                content,
+                json!({
+                    "msgtype": "m.text",
+                    "body": "sed: sed & co",
+                    "format": "org.matrix.custom.html",
+                    "formatted_body": "<a href=\"https://matrix.to/#/@sed:t2bot.io\">sed</a>: \
+                        <a href=\"https://matrix.to/#/@sed:t2bot.io\">sed</a> &amp; co",
+                    "m.mentions": { "user_ids": ["@sed:t2bot.io"] },
+                })
+            );
+
+            matcher.intentional_mentions(false);
+            let content = serde_json::to_value(to_content(&matcher, "a < b")).unwrap();
+            assert_eq!(content, json!({ "msgtype": "m.text", "body": "a < b" }));
+        }
+
+        #[test]
+        fn test_extract_mentions() {
+            let mut matcher = MentionMatcher::new();
+            let sed = user_id!("@sed:t2bot.io");
+            matcher.insert("sed", &MatrixToItem::User(&sed));
+            let voyager = user_id!("@voyager:t2bot.io");
+            matcher.insert("voyager", &MatrixToItem::User(&voyager));
+
+            let mentions = extract_mentions(&matcher, "voyager: `sed` sed, sed");
+            assert_eq!(
+                mentions,
+                vec![
+                    ExternalMention {
+                        name: "voyager".to_string(),
+                        url: "https://matrix.to/#/@voyager:t2bot.io".to_string(),
+                        user_id: Some(voyager),
+                    },
+                    ExternalMention {
+                        name: "sed".to_string(),
+                        url: "https://matrix.to/#/@sed:t2bot.io".to_string(),
+                        user_id: Some(sed),
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn test_matching_options() {
+            let mut matcher = MentionMatcher::new();
+            let sed = user_id!("@sed:t2bot.io");
+            matcher.insert("Sed", &MatrixToItem::User(&sed));
+            let link = "<a href=\"https://matrix.to/#/@sed:t2bot.io\">";
 
-            let info = Info { map };
-            let regex = build_regex(&info);
+            matcher.case_insensitive(true);
+            let after = format!("{}sed</a>: hi {}SED</a>", link, link);
+            assert_eq!(after, convert(&matcher, "sed: hi SED"));
 
-            assert_eq!(after, convert(regex, before.to_string(), &info));
+            matcher.boundary_chars(" ");
+            let after = format!("sed: hi {}SED</a>", link);
+            assert_eq!(after, convert(&matcher, "sed: hi SED"));
+
+            matcher.sigil("@", true);
+            let after = format!("sed {}sed</a>", link);
+            assert_eq!(after, convert(&matcher, "sed @sed"));
+
+            matcher.sigil("@", false);
+            let after = format!("{}sed</a> {}sed</a>", link, link);
+            assert_eq!(after, convert(&matcher, "sed @sed"));
+        }
+
+        #[cfg(feature = "emoji")]
+        #[test]
+        fn test_emoji_shortcodes() {
+            let mut matcher = MentionMatcher::new();
+            matcher.emoji_shortcodes(true);
+            assert_eq!(
+                "&lt;3 😄 `:smile:` https://example.com/:smile:",
+                convert(&matcher, "<3 :smile: `:smile:` https://example.com/:smile:")
+            );
+
+            let sed = user_id!("@sed:t2bot.io");
+            matcher.insert("sed", &MatrixToItem::User(&sed));
+            assert_eq!(
+                "<a href=\"https://matrix.to/#/@sed:t2bot.io\">sed</a>: 👍",
+                convert(&matcher, "sed: :+1:")
+            );
+        }
+
+        #[cfg(feature = "markdown")]
+        #[test]
+        fn test_from_markdown() {
+            use crate::convert::to_matrix::from_markdown;
+
+            let mut matcher = MentionMatcher::new();
+            let sed = user_id!("@sed:t2bot.io");
+            matcher.insert("sed", &MatrixToItem::User(&sed));
+
+            let before =
+                "hi sed, **bold** _it_ ~~gone~~ `sed` <b>x</b> & [sed](https://example.com)";
+            let res = from_markdown(&matcher, before);
+            assert_eq!(res.body, before);
+            assert_eq!(
+                res.formatted_body,
+                "hi <a href=\"https://matrix.to/#/@sed:t2bot.io\">sed</a>, <strong>bold</strong> \
+                 <em>it</em> <del>gone</del> <code>sed</code> &lt;b&gt;x&lt;/b&gt; &amp; \
+                 <a href=\"https://example.com\">sed</a>"
+            );
+
+            let before = "> quote\n\n- one\n- two\n\n```rust\nlet sed = 1;\n```";
+            let res = from_markdown(&matcher, before);
+            assert_eq!(
+                res.formatted_body,
+                "<blockquote>\n<p>quote</p>\n</blockquote>\n<ul>\n<li>one</li>\n<li>two</li>\n</ul>\n\
+                 <pre><code class=\"language-rust\">let sed = 1;\n</code></pre>\n"
+            );
+
+            // names split over several text events by the parser.
+            let bob = user_id!("@bob:example.org");
+            matcher.insert("bob_smith_", &MatrixToItem::User(&bob));
+            matcher.insert("Tom & Jerry", &MatrixToItem::User(&bob));
+            let res = from_markdown(&matcher, "hi bob_smith_ and Tom &amp; Jerry");
+            assert_eq!(
+                res.formatted_body,
+                "hi <a href=\"https://matrix.to/#/@bob:example.org\">bob_smith_</a> and \
+                 <a href=\"https://matrix.to/#/@bob:example.org\">Tom &amp; Jerry</a>"
+            );
+        }
+    }
+}
+
+/// Both directions of message conversion, sharing a single mapping between Matrix users and their
+/// names on the external network.
+///
+/// Mentions of mapped Matrix users are replaced with their external names when converting to the
+/// external network, and external names are replaced with mentions of the Matrix users when
+/// converting to Matrix.
+pub struct Converter<V: Mappable> {
+    users: Arc<RwLock<MappingDict<V>>>,
+    info: to_external::Info<'static>,
+    matcher: to_matrix::MentionMatcher,
+}
+
+impl<V> Converter<V>
+where
+    V: Mappable<
+            MatrixReference = UserId,
+            MatrixType = UserId,
+            ExternalReference = str,
+            ExternalType = String,
+        > + Send
+        + Sync
+        + 'static,
+{
+    /// Create a new `Converter` using the given configurations for both directions.
+    ///
+    /// The user mapper of `info` is replaced by a lookup in the mapping of this `Converter`.
+    pub fn new(mut info: to_external::Info<'static>, matcher: to_matrix::MentionMatcher) -> Self {
+        let users = Arc::new(RwLock::new(MappingDict::new()));
+
+        let mapping = users.clone();
+        info.user_mapper(move |user_id, _| {
+            let users = mapping.read().unwrap_or_else(PoisonError::into_inner);
+            users
+                .get(MappingId::Matrix(&user_id))
+                .map(|user: &V| user.as_external().to_string())
+        });
+
+        Self {
+            users,
+            info,
+            matcher,
         }
     }
+
+    /// Map the Matrix user of the given `user` to its external name, in both directions.
+    ///
+    /// Existing mappings of either the Matrix user or the external name are replaced.
+    pub fn insert(&mut self, user: V) {
+        let mut users = self.users.write().unwrap_or_else(PoisonError::into_inner);
+        for identifier in [
+            MappingId::Matrix(user.as_matrix()),
+            MappingId::External(user.as_external()),
+        ] {
+            if let Some(old) = users.remove(identifier) {
+                self.matcher.remove(old.as_external());
+            }
+        }
+        self.matcher
+            .insert(user.as_external(), &MatrixToItem::User(user.as_matrix()));
+        users.insert(user);
+    }
+
+    /// Remove the mapping of the user with the given `identifier`, in both directions.
+    pub fn remove(&mut self, identifier: MappingId<str, UserId>) -> Option<V> {
+        let mut users = self.users.write().unwrap_or_else(PoisonError::into_inner);
+        let user = users.remove(identifier)?;
+        self.matcher.remove(user.as_external());
+        Some(user)
+    }
+
+    /// Get the current mapping of users.
+    pub fn users(&self) -> RwLockReadGuard<'_, MappingDict<V>> {
+        self.users.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Convert the given Matrix HTML to the external format.
+    pub fn matrix_to_external(&self, html: &str) -> Result<String, Error> {
+        to_external::convert(html, &self.info)
+    }
+
+    /// Convert the given external plain text message to Matrix message content.
+    pub fn external_to_matrix(&self, text: &str) -> to_matrix::MessageContent {
+        to_matrix::to_content(&self.matcher, text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::identifiers::{user_id, UserId};
+
+    use crate::convert::{to_external, to_matrix, Converter};
+    use crate::{Mappable, MappingId};
+
+    struct User {
+        user_id: UserId,
+        nick: String,
+    }
+
+    impl Mappable for User {
+        type MatrixReference = UserId;
+        type MatrixType = UserId;
+        type ExternalReference = str;
+        type ExternalType = String;
+
+        fn as_matrix(&self) -> &UserId {
+            &self.user_id
+        }
+        fn into_matrix(self) -> UserId {
+            self.user_id
+        }
+        fn as_external(&self) -> &str {
+            &self.nick
+        }
+        fn into_external(self) -> String {
+            self.nick
+        }
+        fn into_split(self) -> (UserId, String) {
+            (self.user_id, self.nick)
+        }
+    }
+
+    #[test]
+    fn test_converter() {
+        let mut converter = Converter::new(
+            to_external::Info::with_preset(to_external::Protocol::Irc),
+            to_matrix::MentionMatcher::new(),
+        );
+        converter.insert(User {
+            user_id: user_id!("@irc_tom:lieuwe.xyz"),
+            nick: "tom".to_string(),
+        });
+
+        let html = "hi <a href=\"https://matrix.to/#/@irc_tom:lieuwe.xyz\">Tom (IRC)</a>";
+        assert_eq!("hi tom", converter.matrix_to_external(html).unwrap());
+        let content = converter.external_to_matrix("hi tom");
+        assert_eq!(
+            content.mentions,
+            Some(vec![user_id!("@irc_tom:lieuwe.xyz")])
+        );
+
+        let user = converter.remove(MappingId::External("tom")).unwrap();
+        assert_eq!(user.nick, "tom");
+        assert_eq!(
+            "hi Tom (IRC) (https://matrix.to/#/@irc_tom:lieuwe.xyz)",
+            converter.matrix_to_external(html).unwrap()
+        );
+        assert_eq!(
+            converter.external_to_matrix("hi tom").mentions,
+            Some(vec![])
+        );
+        assert!(converter.users().iter().next().is_none());
+    }
 }
 
 /*
@@ -0,0 +1,128 @@
+use ruma::api::client::error::ErrorKind;
+use ruma::identifiers::{EventId, RoomId};
+use ruma_client::HttpClient;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::value::to_raw_value;
+
+use crate::intent::{Intent, IntentError};
+
+/// The content of a custom state event a bridge uses to keep metadata of a portal in the room,
+/// like the ID of the external channel or how far it has been synced.
+///
+/// The event type is the prefix of the bridge, like `com.example.bridge`, followed by a `.` and
+/// `EVENT_TYPE`, so all bridge state events of a bridge share a namespace.
+pub trait BridgeStateContent: Serialize + DeserializeOwned {
+    /// The last part of the event type, like `channel`.
+    const EVENT_TYPE: &'static str;
+
+    /// Get the full event type of this content for a bridge with the given `prefix`.
+    fn event_type(prefix: &str) -> String {
+        format!("{}.{}", prefix, Self::EVENT_TYPE)
+    }
+}
+
+impl<C: HttpClient> Intent<C> {
+    /// Get the bridge state event with the given `state_key` of type `T` in the room with the
+    /// given `room_id`, for the bridge with the event type `prefix`, or `None` if it isn't set.
+    pub async fn get_bridge_state_event<T: BridgeStateContent>(
+        &self,
+        room_id: &RoomId,
+        prefix: &str,
+        state_key: &str,
+    ) -> Result<Option<T>, IntentError<C::Error>> {
+        let event_type = T::event_type(prefix);
+        match self.get_state_raw(room_id, &event_type, state_key).await {
+            Ok(content) => Ok(Some(serde_json::from_str(content.get())?)),
+            Err(err) if err.kind() == Some(&ErrorKind::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Set the bridge state event with the given `state_key` of type `T` in the room with the
+    /// given `room_id` to `content`, for the bridge with the event type `prefix`.
+    ///
+    /// Returns the ID of the sent event.
+    pub async fn set_bridge_state_event<T: BridgeStateContent>(
+        &self,
+        room_id: &RoomId,
+        prefix: &str,
+        state_key: &str,
+        content: &T,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let event_type = T::event_type(prefix);
+        let content = to_raw_value(content)?;
+        self.send_state_raw(room_id, &event_type, state_key, content)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{RoomId, UserId};
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{BridgeStateContent, Intent};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct ChannelInfo {
+        channel_id: String,
+        last_synced: Option<String>,
+    }
+
+    impl BridgeStateContent for ChannelInfo {
+        const EVENT_TYPE: &'static str = "channel";
+    }
+
+    #[tokio::test]
+    async fn test_bridge_state_event() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let path = "/rooms/!room:example.org/state/org.example.bridge.channel";
+
+        let info = ChannelInfo {
+            channel_id: String::from("#rust"),
+            last_synced: None,
+        };
+        state.respond(path, 200, json!({ "event_id": "$state:example.org" }));
+        bot.set_bridge_state_event(&room_id, "org.example.bridge", "", &info)
+            .await
+            .unwrap();
+        let requests = state.requests_to(path);
+        assert_eq!(requests[0].method, "PUT");
+        assert_eq!(
+            requests[0].body,
+            json!({ "channel_id": "#rust", "last_synced": null })
+        );
+
+        state.respond_once(
+            path,
+            200,
+            json!({ "channel_id": "#rust", "last_synced": "$last:example.org" }),
+        );
+        let loaded: Option<ChannelInfo> = bot
+            .get_bridge_state_event(&room_id, "org.example.bridge", "")
+            .await
+            .unwrap();
+        assert_eq!(
+            loaded.unwrap().last_synced.as_deref(),
+            Some("$last:example.org")
+        );
+
+        state.respond_once(
+            path,
+            404,
+            json!({ "errcode": "M_NOT_FOUND", "error": "Event not found." }),
+        );
+        let loaded: Option<ChannelInfo> = bot
+            .get_bridge_state_event(&room_id, "org.example.bridge", "")
+            .await
+            .unwrap();
+        assert!(loaded.is_none());
+    }
+}
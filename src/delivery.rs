@@ -0,0 +1,209 @@
+use ruma::events::room::message::{InReplyTo, MessageEventContent, Relation};
+use ruma::events::AnyMessageEventContent;
+use ruma::identifiers::{EventId, RoomId};
+use ruma_client::HttpClient;
+use serde_json::json;
+use serde_json::value::{to_raw_value, RawValue};
+
+use crate::intent::{Intent, IntentError};
+use crate::messages::edit_content;
+
+/// The event type of message send status events, as used by mautrix bridges.
+pub const MESSAGE_STATUS_EVENT_TYPE: &str = "com.beeper.message_send_status";
+
+/// Whether a message sent by a Matrix user reached the external service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// The message is still being sent.
+    Pending,
+    /// The message reached the external service.
+    Sent,
+    /// Sending the message failed because of `reason`.
+    Failed {
+        /// A human readable description of the failure.
+        reason: String,
+        /// Whether sending the message again could work.
+        can_retry: bool,
+    },
+}
+
+impl DeliveryStatus {
+    /// Get the text of the status notice of this status.
+    pub fn notice_text(&self) -> String {
+        match self {
+            DeliveryStatus::Pending => String::from("\u{23f3} Sending message..."),
+            DeliveryStatus::Sent => String::from("\u{2713} Message delivered"),
+            DeliveryStatus::Failed { reason, .. } => {
+                format!("\u{26a0} Your message was not bridged: {}", reason)
+            }
+        }
+    }
+}
+
+/// Build the content of a message send status event with the given `status` of the event with
+/// the given `event_id` on the external service called `network`.
+pub fn message_status_content(
+    event_id: &EventId,
+    network: &str,
+    status: &DeliveryStatus,
+) -> Box<RawValue> {
+    let mut content = json!({
+        "network": network,
+        "m.relates_to": { "rel_type": "m.reference", "event_id": event_id },
+    });
+    let fields = match status {
+        DeliveryStatus::Pending => json!({
+            "status": "PENDING",
+            "success": false,
+            "still_working": true,
+        }),
+        DeliveryStatus::Sent => json!({
+            "status": "SUCCESS",
+            "success": true,
+        }),
+        DeliveryStatus::Failed { reason, can_retry } => json!({
+            "status": if *can_retry { "FAIL_RETRIABLE" } else { "FAIL_PERMANENT" },
+            "success": false,
+            "reason": "m.foreign_network_error",
+            "message": reason,
+            "can_retry": can_retry,
+            "is_certain": true,
+        }),
+    };
+    if let (Some(content), Some(fields)) = (content.as_object_mut(), fields.as_object()) {
+        content.extend(fields.clone());
+    }
+    to_raw_value(&content).expect("status should serialize")
+}
+
+impl<C: HttpClient> Intent<C> {
+    /// Send a message send status event with the given `status` of the event with the given
+    /// `event_id` in the room with the given `room_id`, for clients that show delivery status.
+    ///
+    /// Returns the ID of the sent event.
+    pub async fn send_message_status(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        network: &str,
+        status: &DeliveryStatus,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let content = message_status_content(event_id, network, status);
+        self.send_message_raw(room_id, MESSAGE_STATUS_EVENT_TYPE, content)
+            .await
+    }
+
+    /// Send a notice with the given `status` of the event with the given `event_id` in the room
+    /// with the given `room_id`, as a reply to that event, for clients that don't show message
+    /// send status events.
+    ///
+    /// Returns the ID of the notice, which can be updated using `update_status_notice`.
+    pub async fn send_status_notice(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        status: &DeliveryStatus,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let mut content = MessageEventContent::notice_plain(status.notice_text());
+        content.relates_to = Some(Relation::Reply {
+            in_reply_to: InReplyTo::new(event_id.clone()),
+        });
+        self.send_message(room_id, &AnyMessageEventContent::RoomMessage(content))
+            .await
+    }
+
+    /// Edit the status notice with the given `notice_id` in the room with the given `room_id` to
+    /// show `status`.
+    ///
+    /// Returns the ID of the edit.
+    pub async fn update_status_notice(
+        &self,
+        room_id: &RoomId,
+        notice_id: &EventId,
+        status: &DeliveryStatus,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let new_content = MessageEventContent::notice_plain(status.notice_text());
+        let content = edit_content(notice_id, new_content);
+        self.send_message(room_id, &AnyMessageEventContent::RoomMessage(content))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{EventId, RoomId, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{DeliveryStatus, Intent};
+
+    #[tokio::test]
+    async fn test_message_status() {
+        let (client, state) = mock_client();
+        let intent = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let event_id = EventId::try_from("$message:example.org").unwrap();
+
+        state.respond("/send/", 200, json!({ "event_id": "$status:example.org" }));
+        intent
+            .send_message_status(&room_id, &event_id, "irc", &DeliveryStatus::Sent)
+            .await
+            .unwrap();
+        let failed = DeliveryStatus::Failed {
+            reason: String::from("Channel is moderated"),
+            can_retry: false,
+        };
+        intent
+            .send_message_status(&room_id, &event_id, "irc", &failed)
+            .await
+            .unwrap();
+
+        let requests = state.requests_to("/send/com.beeper.message_send_status/");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].body,
+            json!({
+                "network": "irc",
+                "m.relates_to": { "rel_type": "m.reference", "event_id": "$message:example.org" },
+                "status": "SUCCESS",
+                "success": true,
+            })
+        );
+        assert_eq!(requests[1].body["status"], "FAIL_PERMANENT");
+        assert_eq!(requests[1].body["message"], "Channel is moderated");
+    }
+
+    #[tokio::test]
+    async fn test_status_notice() {
+        let (client, state) = mock_client();
+        let intent = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let event_id = EventId::try_from("$message:example.org").unwrap();
+
+        state.respond("/send/", 200, json!({ "event_id": "$notice:example.org" }));
+        let notice_id = intent
+            .send_status_notice(&room_id, &event_id, &DeliveryStatus::Pending)
+            .await
+            .unwrap();
+        intent
+            .update_status_notice(&room_id, &notice_id, &DeliveryStatus::Sent)
+            .await
+            .unwrap();
+
+        let requests = state.requests_to("/send/m.room.message/");
+        assert_eq!(
+            requests[0].body["m.relates_to"],
+            json!({ "m.in_reply_to": { "event_id": "$message:example.org" } })
+        );
+        assert_eq!(
+            requests[1].body["m.new_content"]["body"],
+            "\u{2713} Message delivered"
+        );
+        assert_eq!(
+            requests[1].body["m.relates_to"]["event_id"],
+            "$notice:example.org"
+        );
+    }
+}
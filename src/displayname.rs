@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, HashMap};
+
+use ruma::api::client::r0::membership::joined_members::RoomMember;
+use ruma::identifiers::UserId;
+
+/// Get `display_name` followed by `id` in parentheses, which is how a name shared by multiple
+/// users is shown, like `Alice (@alice:example.org)`.
+pub fn disambiguate(display_name: &str, id: &str) -> String {
+    format!("{} ({})", display_name, id)
+}
+
+/// Get the displayname of `member`, or `None` if it has none or an empty one.
+fn display_name_of(member: &RoomMember) -> Option<&str> {
+    member
+        .display_name
+        .as_deref()
+        .filter(|name| !name.trim().is_empty())
+}
+
+/// Returns whether another user in `members` than the one with the given `user_id` has the
+/// displayname `name`.
+fn is_ambiguous(user_id: &UserId, name: &str, members: &BTreeMap<UserId, RoomMember>) -> bool {
+    members
+        .iter()
+        .any(|(other, member)| other != user_id && display_name_of(member) == Some(name))
+}
+
+/// Get the name of the user with the given `user_id` as Matrix clients show it in a room with
+/// the given `members`, following the disambiguation rules of the specification.
+///
+/// That is their displayname if no other member has the same displayname, their displayname
+/// followed by their user ID if another member does, and their user ID if they don't have a
+/// displayname or aren't a member.
+pub fn matrix_display_name(user_id: &UserId, members: &BTreeMap<UserId, RoomMember>) -> String {
+    match members.get(user_id).and_then(display_name_of) {
+        Some(name) if is_ambiguous(user_id, name, members) => disambiguate(name, user_id.as_str()),
+        Some(name) => name.to_string(),
+        None => user_id.to_string(),
+    }
+}
+
+/// Get the name to show on the external service for the Matrix user with the given `user_id`
+/// and `display_name`, which is their displayname or, without one, the localpart of their user
+/// ID.
+///
+/// User IDs mean little to users of other networks, so unlike `matrix_display_name` the full user
+/// ID is only shown when it's needed to tell users apart, see `pretty_names`.
+pub fn pretty_name(user_id: &UserId, display_name: Option<&str>) -> String {
+    match display_name.map(str::trim).filter(|name| !name.is_empty()) {
+        Some(name) => name.to_string(),
+        None => user_id.localpart().to_string(),
+    }
+}
+
+/// Get the names to show on the external service for the given room `members`, see
+/// `pretty_name`, followed by their user ID for members with the same name.
+pub fn pretty_names(members: &BTreeMap<UserId, RoomMember>) -> HashMap<UserId, String> {
+    let names: Vec<_> = members
+        .iter()
+        .map(|(user_id, member)| (user_id, pretty_name(user_id, display_name_of(member))))
+        .collect();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for (_, name) in &names {
+        *counts.entry(name.as_str()).or_default() += 1;
+    }
+
+    names
+        .iter()
+        .map(|(user_id, name)| {
+            let name = if counts[name.as_str()] > 1 {
+                disambiguate(name, user_id.as_str())
+            } else {
+                name.clone()
+            };
+            ((*user_id).clone(), name)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+
+    use ruma::api::client::r0::membership::joined_members::RoomMember;
+    use ruma::identifiers::UserId;
+
+    use crate::{matrix_display_name, pretty_name, pretty_names};
+
+    fn members(names: &[(&str, Option<&str>)]) -> BTreeMap<UserId, RoomMember> {
+        names
+            .iter()
+            .map(|(user_id, name)| {
+                let mut member = RoomMember::new();
+                member.display_name = name.map(String::from);
+                (UserId::try_from(*user_id).unwrap(), member)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_matrix_display_name() {
+        let members = members(&[
+            ("@alice:example.org", Some("Alice")),
+            ("@alice:other.org", Some("Alice")),
+            ("@bob:example.org", Some("Bob")),
+            ("@carol:example.org", None),
+            ("@dave:example.org", Some(" ")),
+        ]);
+        let name =
+            |user_id: &str| matrix_display_name(&UserId::try_from(user_id).unwrap(), &members);
+        assert_eq!(name("@alice:example.org"), "Alice (@alice:example.org)");
+        assert_eq!(name("@alice:other.org"), "Alice (@alice:other.org)");
+        assert_eq!(name("@bob:example.org"), "Bob");
+        assert_eq!(name("@carol:example.org"), "@carol:example.org");
+        assert_eq!(name("@dave:example.org"), "@dave:example.org");
+        assert_eq!(name("@eve:example.org"), "@eve:example.org");
+    }
+
+    #[test]
+    fn test_pretty_names() {
+        let user_id = UserId::try_from("@carol:example.org").unwrap();
+        assert_eq!(pretty_name(&user_id, Some(" Carol ")), "Carol");
+        assert_eq!(pretty_name(&user_id, None), "carol");
+
+        let members = members(&[
+            ("@bob:example.org", Some("Bob")),
+            ("@bob:other.org", None),
+            ("@carol:example.org", None),
+            ("@robert:example.org", Some("bob")),
+        ]);
+        let names = pretty_names(&members);
+        assert_eq!(names.len(), 4);
+        let name = |user_id: &str| names[&UserId::try_from(user_id).unwrap()].as_str();
+        assert_eq!(name("@bob:example.org"), "Bob");
+        assert_eq!(name("@bob:other.org"), "bob (@bob:other.org)");
+        assert_eq!(name("@robert:example.org"), "bob (@robert:example.org)");
+        assert_eq!(name("@carol:example.org"), "carol");
+    }
+}
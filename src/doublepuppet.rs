@@ -0,0 +1,394 @@
+use std::sync::Mutex;
+
+use ruma::api::client::error::ErrorKind;
+use ruma::api::client::r0::account::whoami;
+use ruma::api::client::r0::session::login::{self, LoginInfo, UserIdentifier};
+use ruma::identifiers::{DeviceIdBox, UserId};
+use ruma_client::{Client, HttpClient};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::intent::{Intent, IntentError};
+use crate::mappingdict::{Mappable, MappingDict, MappingId};
+use crate::puppet::{Puppet, PuppetError, PuppetManager};
+use crate::store::MappingStore;
+use crate::util::hmac_sha512_hex;
+
+/// The access token of a real Matrix user, used to bridge what they do on the external service
+/// as coming from their own account instead of from a puppet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DoublePuppet {
+    user_id: UserId,
+    external_id: String,
+    access_token: String,
+    device_id: Option<DeviceIdBox>,
+}
+
+impl DoublePuppet {
+    /// Get the Matrix ID of the user.
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
+
+    /// Get the ID of the user on the external service.
+    pub fn external_id(&self) -> &str {
+        &self.external_id
+    }
+
+    /// Get the access token of the user.
+    pub fn access_token(&self) -> &str {
+        &self.access_token
+    }
+
+    /// Get the ID of the device of the access token, if it's known.
+    pub fn device_id(&self) -> Option<&DeviceIdBox> {
+        self.device_id.as_ref()
+    }
+}
+
+impl Mappable for DoublePuppet {
+    type MatrixReference = UserId;
+    type MatrixType = UserId;
+    type ExternalReference = str;
+    type ExternalType = String;
+
+    fn as_matrix(&self) -> &UserId {
+        &self.user_id
+    }
+    fn into_matrix(self) -> UserId {
+        self.user_id
+    }
+    fn as_external(&self) -> &str {
+        &self.external_id
+    }
+    fn into_external(self) -> String {
+        self.external_id
+    }
+    fn into_split(self) -> (UserId, String) {
+        (self.user_id, self.external_id)
+    }
+}
+
+/// An error from a `DoublePuppetManager`.
+#[derive(Debug, Error)]
+pub enum DoublePuppetError<E, S> {
+    /// A request to the homeserver failed.
+    #[error("{0}")]
+    Intent(IntentError<E>),
+    /// Loading or saving a double puppet failed.
+    #[error("loading or saving a double puppet failed: {0}")]
+    Store(S),
+    /// The access token is invalid or belongs to another user.
+    #[error("the access token is invalid or belongs to another user")]
+    InvalidToken,
+    /// Shared secret login was used without setting a shared secret.
+    #[error("shared secret login used without a shared secret")]
+    NoSharedSecret,
+}
+
+impl<E, S> From<IntentError<E>> for DoublePuppetError<E, S> {
+    fn from(err: IntentError<E>) -> Self {
+        DoublePuppetError::Intent(err)
+    }
+}
+
+/// Manages the access tokens of real Matrix users, so their actions on the external service can
+/// be bridged as coming from their own Matrix account.
+///
+/// Tokens can be given by the users themselves, or obtained by logging in with a password or the
+/// shared secret of the [shared secret authenticator] of the homeserver. When a user has no
+/// token, `intent_for` falls back to the puppet of the user.
+///
+/// [shared secret authenticator]: https://github.com/devture/matrix-synapse-shared-secret-auth
+pub struct DoublePuppetManager<C, S = Mutex<MappingDict<DoublePuppet>>> {
+    http_client: C,
+    homeserver_url: String,
+    shared_secret: Option<Vec<u8>>,
+    store: S,
+}
+
+impl<C, S> DoublePuppetManager<C, S>
+where
+    C: HttpClient + Clone,
+    S: MappingStore<DoublePuppet>,
+{
+    /// Create a new `DoublePuppetManager`, making requests to the homeserver at `homeserver_url`
+    /// using `http_client` and keeping the tokens in `store`.
+    pub fn new(http_client: C, homeserver_url: String, store: S) -> Self {
+        Self {
+            http_client,
+            homeserver_url,
+            shared_secret: None,
+            store,
+        }
+    }
+
+    /// Set the shared secret used by `login_shared_secret`.
+    pub fn set_shared_secret(&mut self, shared_secret: &[u8]) {
+        self.shared_secret = Some(shared_secret.to_vec());
+    }
+
+    /// Get the store containing the double puppets.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Get a `Client` using the given `access_token`, or no access token if it's `None`.
+    fn client(&self, access_token: Option<String>) -> Client<C> {
+        Client::with_http_client(
+            self.http_client.clone(),
+            self.homeserver_url.clone(),
+            access_token,
+        )
+    }
+
+    /// Get the double puppet of the Matrix user with the given `user_id`, if it exists.
+    pub async fn get(&self, user_id: &UserId) -> Result<Option<DoublePuppet>, S::Error> {
+        self.store.get(MappingId::Matrix(user_id)).await
+    }
+
+    /// Get the double puppet of the external user `external_id`, if it exists.
+    pub async fn get_by_external(
+        &self,
+        external_id: &str,
+    ) -> Result<Option<DoublePuppet>, S::Error> {
+        self.store.get(MappingId::External(external_id)).await
+    }
+
+    /// Forget the access token of the Matrix user with the given `user_id`, returning the double
+    /// puppet. The token itself isn't logged out.
+    pub async fn remove(&self, user_id: &UserId) -> Result<Option<DoublePuppet>, S::Error> {
+        self.store.remove(MappingId::Matrix(user_id)).await
+    }
+
+    /// Returns whether `access_token` is a valid access token of the user with the given
+    /// `user_id`.
+    pub async fn validate(
+        &self,
+        user_id: &UserId,
+        access_token: &str,
+    ) -> Result<bool, IntentError<C::Error>> {
+        let client = self.client(Some(access_token.to_string()));
+        match client.send_request(whoami::Request::new()).await {
+            Ok(response) => Ok(&response.user_id == user_id),
+            Err(err) => match IntentError::from(err) {
+                err if matches!(err.kind(), Some(ErrorKind::UnknownToken { .. })) => Ok(false),
+                err => Err(err),
+            },
+        }
+    }
+
+    /// Use `access_token` as the access token of the Matrix user with the given `user_id`, who is
+    /// `external_id` on the external service.
+    ///
+    /// Returns `DoublePuppetError::InvalidToken` if the token doesn't belong to `user_id`.
+    pub async fn set_token(
+        &self,
+        user_id: UserId,
+        external_id: String,
+        access_token: String,
+    ) -> Result<DoublePuppet, DoublePuppetError<C::Error, S::Error>> {
+        if !self.validate(&user_id, &access_token).await? {
+            return Err(DoublePuppetError::InvalidToken);
+        }
+
+        self.insert(DoublePuppet {
+            user_id,
+            external_id,
+            access_token,
+            device_id: None,
+        })
+        .await
+    }
+
+    async fn insert(
+        &self,
+        puppet: DoublePuppet,
+    ) -> Result<DoublePuppet, DoublePuppetError<C::Error, S::Error>> {
+        self.store
+            .insert(puppet.clone())
+            .await
+            .map_err(DoublePuppetError::Store)?;
+        Ok(puppet)
+    }
+
+    /// Log in as the Matrix user with the given `user_id` using their `password`, and use the new
+    /// access token for the user, who is `external_id` on the external service.
+    pub async fn login_password(
+        &self,
+        user_id: UserId,
+        external_id: String,
+        password: &str,
+    ) -> Result<DoublePuppet, DoublePuppetError<C::Error, S::Error>> {
+        let login_info = LoginInfo::Password {
+            identifier: UserIdentifier::MatrixId(user_id.as_str()),
+            password,
+        };
+        let mut request = login::Request::new(login_info);
+        request.initial_device_display_name = Some("Double puppet");
+
+        let response = self
+            .client(None)
+            .send_request(request)
+            .await
+            .map_err(IntentError::from)?;
+        if response.user_id != user_id {
+            return Err(DoublePuppetError::InvalidToken);
+        }
+
+        self.insert(DoublePuppet {
+            user_id,
+            external_id,
+            access_token: response.access_token,
+            device_id: Some(response.device_id),
+        })
+        .await
+    }
+
+    /// Log in as the Matrix user with the given `user_id` using the shared secret set with
+    /// `set_shared_secret`, and use the new access token for the user, who is `external_id` on
+    /// the external service.
+    ///
+    /// The password is the HMAC-SHA512 of the user ID, as expected by the shared secret
+    /// authenticator.
+    pub async fn login_shared_secret(
+        &self,
+        user_id: UserId,
+        external_id: String,
+    ) -> Result<DoublePuppet, DoublePuppetError<C::Error, S::Error>> {
+        let secret = match &self.shared_secret {
+            Some(secret) => secret,
+            None => return Err(DoublePuppetError::NoSharedSecret),
+        };
+
+        let password = hmac_sha512_hex(secret, user_id.as_str().as_bytes());
+        self.login_password(user_id, external_id, &password).await
+    }
+
+    /// Check the access tokens of all double puppets, removing the ones that are no longer valid.
+    ///
+    /// Returns the removed double puppets.
+    pub async fn revalidate(
+        &self,
+    ) -> Result<Vec<DoublePuppet>, DoublePuppetError<C::Error, S::Error>> {
+        let puppets = self.store.items().await.map_err(DoublePuppetError::Store)?;
+
+        let mut removed = vec![];
+        for puppet in puppets {
+            if self.validate(&puppet.user_id, &puppet.access_token).await? {
+                continue;
+            }
+            self.store
+                .remove(MappingId::Matrix(&puppet.user_id))
+                .await
+                .map_err(DoublePuppetError::Store)?;
+            removed.push(puppet);
+        }
+        Ok(removed)
+    }
+
+    /// Get an `Intent` acting as the external user `external_id`, which is the Matrix account of
+    /// the user if they have a double puppet, and their puppet in `puppets` otherwise.
+    ///
+    /// The `Intent` of a double puppet uses the `AppserviceClient` of `puppets`, if any.
+    pub async fn intent_for<P>(
+        &self,
+        external_id: &str,
+        puppets: &PuppetManager<C, P>,
+    ) -> Result<Intent<C>, PuppetError<C::Error, S::Error>>
+    where
+        P: MappingStore<Puppet, Error = S::Error>,
+    {
+        let puppet = self
+            .store
+            .get(MappingId::External(external_id))
+            .await
+            .map_err(PuppetError::Store)?;
+
+        match puppet {
+            Some(puppet) => {
+                let client = self.client(Some(puppet.access_token));
+                Ok(Intent::authenticated(client, puppet.user_id)
+                    .with_appservice_client(puppets.appservice_client().cloned()))
+            }
+            None => puppets.puppet_for(external_id).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{ServerName, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::util::hmac_sha512_hex;
+    use crate::{DoublePuppetError, DoublePuppetManager, PuppetManager};
+
+    #[tokio::test]
+    async fn test_double_puppets() {
+        let (client, state) = mock_client();
+        let http_client = state.http_client();
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let puppets: PuppetManager<_> =
+            PuppetManager::new(client, server_name, "_ext_", Default::default());
+        let mut manager: DoublePuppetManager<_> = DoublePuppetManager::new(
+            http_client,
+            String::from("https://matrix.example.org"),
+            Default::default(),
+        );
+        let alice = UserId::try_from("@alice:example.org").unwrap();
+
+        state.respond(
+            "/register",
+            200,
+            json!({ "user_id": "@_ext_bob:example.org" }),
+        );
+        state.respond("/whoami", 200, json!({ "user_id": "@alice:example.org" }));
+        let result = manager
+            .set_token(alice.clone(), String::from("alice"), String::from("token"))
+            .await;
+        assert!(result.is_ok());
+        let bob = UserId::try_from("@bob:example.org").unwrap();
+        let result = manager
+            .set_token(bob.clone(), String::from("bob"), String::from("token"))
+            .await;
+        assert!(matches!(result, Err(DoublePuppetError::InvalidToken)));
+
+        // alice acts as herself, bob falls back to his puppet.
+        let intent = manager.intent_for("alice", &puppets).await.unwrap();
+        assert_eq!(intent.user_id(), &alice);
+        let intent = manager.intent_for("bob", &puppets).await.unwrap();
+        assert_eq!(intent.user_id().as_str(), "@_ext_bob:example.org");
+
+        let result = manager
+            .login_shared_secret(bob.clone(), String::from("bob"))
+            .await;
+        assert!(matches!(result, Err(DoublePuppetError::NoSharedSecret)));
+        manager.set_shared_secret(b"secret");
+        state.respond(
+            "/login",
+            200,
+            json!({ "user_id": "@bob:example.org", "access_token": "bob_token", "device_id": "DEV" }),
+        );
+        let puppet = manager
+            .login_shared_secret(bob.clone(), String::from("bob"))
+            .await
+            .unwrap();
+        assert_eq!(puppet.access_token(), "bob_token");
+        let login = &state.requests_to("/login")[0].body;
+        assert_eq!(login["type"], "m.login.password");
+        assert_eq!(
+            login["password"],
+            hmac_sha512_hex(b"secret", b"@bob:example.org")
+        );
+
+        // bob's token belongs to alice according to the homeserver.
+        let removed = manager.revalidate().await.unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].user_id(), &bob);
+        assert!(manager.get(&alice).await.unwrap().is_some());
+    }
+}
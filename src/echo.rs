@@ -0,0 +1,175 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+use ruma::events::AnyRoomEvent;
+use ruma::identifiers::{EventId, UserId};
+
+use crate::filter::EventFilter;
+
+/// The default number of sent events remembered by an `EchoTracker`.
+pub const DEFAULT_ECHO_CAPACITY: usize = 1024;
+
+#[derive(Debug, Default)]
+struct EchoState {
+    users: HashSet<UserId>,
+    events: HashSet<EventId>,
+    order: VecDeque<EventId>,
+}
+
+/// Recognizes the events the bridge sent itself when the homeserver pushes them back in a
+/// transaction, so they aren't bridged back to the external service.
+///
+/// An event is an echo if it was sent by one of the virtual users of the bridge, or if its ID is
+/// one of the events recently sent through an `Intent` using this tracker, see
+/// `Intent::with_echo_tracker`. The latter also catches the events sent by double puppets, which
+/// are real Matrix users.
+///
+/// The users sending through an `Intent` of the application service are added automatically, and
+/// a `PuppetManager` adds its puppets when they are created. The homeserver may push an event
+/// back before the request sending it returns, so events of double puppets can still slip
+/// through in that case.
+#[derive(Debug)]
+pub struct EchoTracker {
+    capacity: usize,
+    state: Mutex<EchoState>,
+}
+
+impl Default for EchoTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EchoTracker {
+    /// Create a new `EchoTracker` remembering the last `DEFAULT_ECHO_CAPACITY` sent events.
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_ECHO_CAPACITY)
+    }
+
+    /// Create a new `EchoTracker` remembering the last `capacity` sent events.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Mark every event sent by the user with the given `user_id` as an echo, for example for a
+    /// puppet loaded from the store at startup.
+    pub fn add_user(&self, user_id: UserId) {
+        self.state.lock().unwrap().users.insert(user_id);
+    }
+
+    /// Stop marking the events sent by the user with the given `user_id` as echoes.
+    ///
+    /// Returns whether the user was known.
+    pub fn remove_user(&self, user_id: &UserId) -> bool {
+        self.state.lock().unwrap().users.remove(user_id)
+    }
+
+    /// Returns whether the user with the given `user_id` is known to belong to the bridge.
+    pub fn is_bridge_user(&self, user_id: &UserId) -> bool {
+        self.state.lock().unwrap().users.contains(user_id)
+    }
+
+    /// Remember that the bridge sent the event with the given `event_id`, forgetting the oldest
+    /// remembered event if the tracker is full.
+    pub fn record_event(&self, event_id: EventId) {
+        let mut state = self.state.lock().unwrap();
+        if self.capacity == 0 || !state.events.insert(event_id.clone()) {
+            return;
+        }
+        state.order.push_back(event_id);
+        while state.order.len() > self.capacity {
+            if let Some(oldest) = state.order.pop_front() {
+                state.events.remove(&oldest);
+            }
+        }
+    }
+
+    /// Returns whether the event with the given `event_id` was recently sent by the bridge.
+    pub fn is_sent(&self, event_id: &EventId) -> bool {
+        self.state.lock().unwrap().events.contains(event_id)
+    }
+
+    /// Returns whether `event` was sent by the bridge itself.
+    pub fn is_echo(&self, event: &AnyRoomEvent) -> bool {
+        let state = self.state.lock().unwrap();
+        state.users.contains(event.sender()) || state.events.contains(event.event_id())
+    }
+}
+
+impl EventFilter for EchoTracker {
+    fn allows(&self, event: &AnyRoomEvent) -> bool {
+        !self.is_echo(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    use ruma::events::room::message::MessageEventContent;
+    use ruma::events::{AnyMessageEventContent, AnyRoomEvent};
+    use ruma::identifiers::{EventId, RoomId, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{EchoTracker, EventFilters, Intent};
+
+    fn message(event_id: &str, sender: &str) -> AnyRoomEvent {
+        serde_json::from_value(json!({
+            "type": "m.room.message",
+            "event_id": event_id,
+            "room_id": "!room:example.org",
+            "sender": sender,
+            "origin_server_ts": 0,
+            "content": { "msgtype": "m.text", "body": "hi" },
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_echo_tracker() {
+        let (client, state) = mock_client();
+        let tracker = Arc::new(EchoTracker::new());
+        let ghost = Intent::new(
+            client.clone(),
+            UserId::try_from("@_ext_bob:example.org").unwrap(),
+        )
+        .with_echo_tracker(Some(tracker.clone()));
+        let double_puppet =
+            Intent::authenticated(client, UserId::try_from("@alice:example.org").unwrap())
+                .with_echo_tracker(Some(tracker.clone()));
+        let mut filters = EventFilters::new();
+        filters.add(tracker.clone());
+
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("hello"));
+        state.respond_once("/send/", 200, json!({ "event_id": "$ghost:example.org" }));
+        ghost.send_message(&room_id, &content).await.unwrap();
+        state.respond_once("/send/", 200, json!({ "event_id": "$puppet:example.org" }));
+        double_puppet
+            .send_message(&room_id, &content)
+            .await
+            .unwrap();
+
+        assert!(!filters.allows(&message("$ghost:example.org", "@_ext_bob:example.org")));
+        assert!(!filters.allows(&message("$other:example.org", "@_ext_bob:example.org")));
+        assert!(!filters.allows(&message("$puppet:example.org", "@alice:example.org")));
+        assert!(filters.allows(&message("$other:example.org", "@alice:example.org")));
+        assert!(!tracker.is_bridge_user(double_puppet.user_id()));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let tracker = EchoTracker::with_capacity(2);
+        for i in 0..3 {
+            tracker.record_event(EventId::try_from(format!("${}:example.org", i)).unwrap());
+        }
+        assert!(!tracker.is_sent(&EventId::try_from("$0:example.org").unwrap()));
+        assert!(tracker.is_sent(&EventId::try_from("$1:example.org").unwrap()));
+        assert!(tracker.is_sent(&EventId::try_from("$2:example.org").unwrap()));
+    }
+}
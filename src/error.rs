@@ -0,0 +1,308 @@
+use std::error::Error as StdError;
+
+use hyper::http;
+use thiserror::Error;
+
+use crate::appservice::VerifyError;
+use crate::bridgestate::BridgeStateError;
+use crate::doublepuppet::DoublePuppetError;
+use crate::intent::IntentError;
+use crate::join::JoinError;
+use crate::mappingdict::{KeyUpdateError, PersistError};
+use crate::matrix::MxcConversionError;
+use crate::mediacache::{DownloadError, MediaCacheError};
+use crate::portal::PortalError;
+use crate::puppet::PuppetError;
+use crate::reconcile::ReconcileError;
+use crate::service::AppserviceError;
+use crate::stickers::MediaError;
+
+#[cfg(feature = "store")]
+use crate::sqlite::StoreError;
+
+/// A boxed error of any type, as kept by `Error`.
+pub type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// An error from anywhere in this crate, to match on the cause of a failure without knowing
+/// which component it came from.
+///
+/// Every error type of this crate converts into an `Error`, so `?` can be used to return them
+/// from a function returning an `Error`. Errors returned by the homeserver become `Matrix`, with
+/// the `errcode` of the error, so they can be matched on programmatically.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Sending a request or receiving its response failed, or a server responded with an
+    /// unexpected status or body.
+    #[error("HTTP request failed: {0}")]
+    Http(BoxError),
+    /// The homeserver returned an error.
+    #[error("homeserver returned {errcode} ({status}): {message}")]
+    Matrix {
+        /// The `errcode` of the error, like `M_FORBIDDEN`.
+        errcode: String,
+        /// The human-readable message of the error.
+        message: String,
+        /// The HTTP status code of the response.
+        status: u16,
+    },
+    /// Converting a message, media or identifier between Matrix and the external service
+    /// failed.
+    #[error("conversion failed: {0}")]
+    Conversion(String),
+    /// Loading or saving data in a store failed.
+    #[error("store error: {0}")]
+    Store(BoxError),
+    /// The configuration or registration of the application service is invalid.
+    #[error("invalid configuration: {0}")]
+    Config(String),
+}
+
+impl Error {
+    /// Get the `errcode` of the error returned by the homeserver, if any.
+    pub fn errcode(&self) -> Option<&str> {
+        match self {
+            Error::Matrix { errcode, .. } => Some(errcode),
+            _ => None,
+        }
+    }
+
+    fn store<S: StdError + Send + Sync + 'static>(err: S) -> Self {
+        Error::Store(Box::new(err))
+    }
+}
+
+impl<E: StdError + Send + Sync + 'static> From<IntentError<E>> for Error {
+    fn from(err: IntentError<E>) -> Self {
+        match err.matrix_error() {
+            Some(matrix) => Error::Matrix {
+                errcode: matrix.kind.to_string(),
+                message: matrix.message.clone(),
+                status: matrix.status_code.as_u16(),
+            },
+            None => Error::Http(Box::new(err)),
+        }
+    }
+}
+
+impl<E: StdError + Send + Sync + 'static> From<JoinError<E>> for Error {
+    fn from(err: JoinError<E>) -> Self {
+        match err {
+            JoinError::Intent(err) => err.into(),
+            err @ JoinError::KnockPending => Error::Http(Box::new(err)),
+        }
+    }
+}
+
+impl<E, S> From<PortalError<E, S>> for Error
+where
+    E: StdError + Send + Sync + 'static,
+    S: StdError + Send + Sync + 'static,
+{
+    fn from(err: PortalError<E, S>) -> Self {
+        match err {
+            PortalError::Intent(err) => err.into(),
+            PortalError::Store(err) => Error::store(err),
+            err @ PortalError::InvalidAlias(_) => Error::Config(err.to_string()),
+        }
+    }
+}
+
+impl<E, S> From<PuppetError<E, S>> for Error
+where
+    E: StdError + Send + Sync + 'static,
+    S: StdError + Send + Sync + 'static,
+{
+    fn from(err: PuppetError<E, S>) -> Self {
+        match err {
+            PuppetError::Intent(err) => err.into(),
+            PuppetError::Store(err) => Error::store(err),
+            err @ PuppetError::InvalidUserId(_) => Error::Config(err.to_string()),
+        }
+    }
+}
+
+impl<E, P, S> From<ReconcileError<E, P, S>> for Error
+where
+    E: StdError + Send + Sync + 'static,
+    P: StdError + Send + Sync + 'static,
+    S: StdError + Send + Sync + 'static,
+{
+    fn from(err: ReconcileError<E, P, S>) -> Self {
+        match err {
+            ReconcileError::Intent(err) => err.into(),
+            ReconcileError::PortalStore(err) => Error::store(err),
+            ReconcileError::PuppetStore(err) => Error::store(err),
+        }
+    }
+}
+
+impl<E, S> From<AppserviceError<E, S>> for Error
+where
+    E: StdError + Send + Sync + 'static,
+    S: StdError + Send + Sync + 'static,
+{
+    fn from(err: AppserviceError<E, S>) -> Self {
+        match err {
+            AppserviceError::Intent(err) => err.into(),
+            AppserviceError::Store(err) => Error::store(err),
+            AppserviceError::Portal(err) => err.into(),
+            AppserviceError::Record(err) => Error::store(err),
+            AppserviceError::Handler(err) => err,
+            #[cfg(feature = "serve")]
+            AppserviceError::Server(err) => Error::Http(Box::new(err)),
+        }
+    }
+}
+
+impl<E, S> From<DoublePuppetError<E, S>> for Error
+where
+    E: StdError + Send + Sync + 'static,
+    S: StdError + Send + Sync + 'static,
+{
+    fn from(err: DoublePuppetError<E, S>) -> Self {
+        match err {
+            DoublePuppetError::Intent(err) => err.into(),
+            DoublePuppetError::Store(err) => Error::store(err),
+            err => Error::Config(err.to_string()),
+        }
+    }
+}
+
+impl<E, S> From<MediaError<E, S>> for Error
+where
+    E: StdError + Send + Sync + 'static,
+    S: StdError + Send + Sync + 'static,
+{
+    fn from(err: MediaError<E, S>) -> Self {
+        match err {
+            MediaError::Intent(err) => err.into(),
+            MediaError::Store(err) => Error::store(err),
+        }
+    }
+}
+
+impl<E, S, D> From<MediaCacheError<E, S, D>> for Error
+where
+    E: StdError + Send + Sync + 'static,
+    S: StdError + Send + Sync + 'static,
+    D: StdError + Send + Sync + 'static,
+{
+    fn from(err: MediaCacheError<E, S, D>) -> Self {
+        match err {
+            MediaCacheError::Intent(err) => err.into(),
+            MediaCacheError::Store(err) => Error::store(err),
+            MediaCacheError::Download(err) => Error::Http(Box::new(err)),
+            err => Error::Conversion(err.to_string()),
+        }
+    }
+}
+
+impl<E: StdError + Send + Sync + 'static> From<DownloadError<E>> for Error {
+    fn from(err: DownloadError<E>) -> Self {
+        match err {
+            err @ DownloadError::InvalidUrl(_) => Error::Conversion(err.to_string()),
+            err => Error::Http(Box::new(err)),
+        }
+    }
+}
+
+impl<E: StdError + Send + Sync + 'static> From<BridgeStateError<E>> for Error {
+    fn from(err: BridgeStateError<E>) -> Self {
+        match err {
+            err @ BridgeStateError::Request(_) => Error::Config(err.to_string()),
+            err => Error::Http(Box::new(err)),
+        }
+    }
+}
+
+impl<E: StdError + Send + Sync + 'static> From<VerifyError<E>> for Error {
+    fn from(err: VerifyError<E>) -> Self {
+        match err {
+            VerifyError::Intent(err) => err.into(),
+            err => Error::Config(err.diagnostic()),
+        }
+    }
+}
+
+impl From<PersistError> for Error {
+    fn from(err: PersistError) -> Self {
+        Error::store(err)
+    }
+}
+
+impl From<KeyUpdateError> for Error {
+    fn from(err: KeyUpdateError) -> Self {
+        Error::store(err)
+    }
+}
+
+impl From<MxcConversionError> for Error {
+    fn from(err: MxcConversionError) -> Self {
+        Error::Conversion(err.to_string())
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(err: http::Error) -> Self {
+        Error::Http(Box::new(err))
+    }
+}
+
+#[cfg(feature = "store")]
+impl From<StoreError> for Error {
+    fn from(err: StoreError) -> Self {
+        Error::store(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::{Infallible, TryFrom};
+
+    use ruma::identifiers::{RoomId, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{Error, Intent, KeyUpdateError, MxcConversionError, PuppetError};
+
+    #[tokio::test]
+    async fn test_from_intent_error() {
+        let (client, state) = mock_client();
+        let ghost = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        state.respond_once(
+            "/join",
+            403,
+            json!({ "errcode": "M_FORBIDDEN", "error": "You are not invited" }),
+        );
+        let err = Error::from(ghost.join(&room_id).await.unwrap_err());
+        assert_eq!(err.errcode(), Some("M_FORBIDDEN"));
+        assert!(matches!(err, Error::Matrix { status: 403, .. }));
+        assert_eq!(
+            err.to_string(),
+            "homeserver returned M_FORBIDDEN (403): You are not invited"
+        );
+
+        state.respond_once("/join", 200, json!({ "not": "a join response" }));
+        let err = Error::from(ghost.join(&room_id).await.unwrap_err());
+        assert!(matches!(err, Error::Http(_)));
+        assert_eq!(err.errcode(), None);
+    }
+
+    #[test]
+    fn test_from_component_errors() {
+        let err = UserId::try_from("not a user id").unwrap_err();
+        let err: PuppetError<Infallible, Infallible> = PuppetError::InvalidUserId(err);
+        assert!(matches!(Error::from(err), Error::Config(_)));
+
+        assert!(matches!(
+            Error::from(KeyUpdateError::Occupied),
+            Error::Store(_)
+        ));
+        assert!(matches!(
+            Error::from(MxcConversionError::InvalidMxc),
+            Error::Conversion(_)
+        ));
+    }
+}
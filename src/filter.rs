@@ -0,0 +1,285 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use ruma::events::{AnyRoomEvent, AnyStateEvent};
+use ruma::identifiers::{RoomId, UserId};
+use ruma::serde::Raw;
+
+/// A filter deciding which events from the homeserver are handled by the bridge.
+pub trait EventFilter: Send + Sync {
+    /// Returns whether `event` should be handled.
+    fn allows(&self, event: &AnyRoomEvent) -> bool;
+}
+
+/// A filter can be shared, so it can still be changed after it has been added to an
+/// `EventFilters`.
+impl<T: EventFilter + ?Sized> EventFilter for Arc<T> {
+    fn allows(&self, event: &AnyRoomEvent) -> bool {
+        (**self).allows(event)
+    }
+}
+
+struct FnFilter<F>(F);
+
+impl<F> EventFilter for FnFilter<F>
+where
+    F: Fn(&AnyRoomEvent) -> bool + Send + Sync,
+{
+    fn allows(&self, event: &AnyRoomEvent) -> bool {
+        (self.0)(event)
+    }
+}
+
+/// A chain of filters that run before events are dispatched to the handlers of the bridge.
+///
+/// An event is handled only if every filter allows it. The filters run in the order they were
+/// added, and the chain stops at the first filter that drops the event.
+#[derive(Default)]
+pub struct EventFilters {
+    filters: Vec<Box<dyn EventFilter>>,
+}
+
+impl EventFilters {
+    /// Create a new `EventFilters` without filters, allowing every event.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the given `filter` to the end of the chain, returning the current `EventFilters` to
+    /// allow method chaining.
+    pub fn add<F: EventFilter + 'static>(&mut self, filter: F) -> &mut Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// Add a filter allowing the events for which `f` returns `true` to the end of the chain,
+    /// returning the current `EventFilters` to allow method chaining.
+    pub fn add_fn<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&AnyRoomEvent) -> bool + Send + Sync + 'static,
+    {
+        self.add(FnFilter(f))
+    }
+
+    /// Returns whether every filter allows `event`.
+    pub fn allows(&self, event: &AnyRoomEvent) -> bool {
+        self.filters.iter().all(|filter| filter.allows(event))
+    }
+
+    /// Get the `events` from a transaction that should be handled. Events that can't be
+    /// deserialized are skipped.
+    pub fn filter(&self, events: &[Raw<AnyRoomEvent>]) -> Vec<AnyRoomEvent> {
+        events
+            .iter()
+            .filter_map(|event| event.deserialize().ok())
+            .filter(|event| self.allows(event))
+            .collect()
+    }
+}
+
+type UserPredicate = Box<dyn Fn(&UserId) -> bool + Send + Sync>;
+
+/// Drops the events sent by the bridge itself, so messages the bridge sent to Matrix aren't
+/// bridged back to the external service.
+///
+/// Events sent by the bot user and by the virtual users of the bridge are dropped.
+pub struct EchoFilter {
+    bot: UserId,
+    is_ghost: UserPredicate,
+}
+
+impl EchoFilter {
+    /// Create a new `EchoFilter` dropping the events of the bot user with the given `bot` user
+    /// ID, and of the users for which `is_ghost` returns `true`.
+    pub fn new<F>(bot: UserId, is_ghost: F) -> Self
+    where
+        F: Fn(&UserId) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            bot,
+            is_ghost: Box::new(is_ghost),
+        }
+    }
+
+    /// Create a new `EchoFilter` dropping the events of the bot user with the given `bot` user
+    /// ID, and of the users on the server of the bot whose localpart starts with `prefix`.
+    pub fn with_prefix(bot: UserId, prefix: &str) -> Self {
+        let server_name = bot.server_name().to_owned();
+        let prefix = prefix.to_string();
+        Self::new(bot, move |user_id| {
+            user_id.server_name() == &*server_name && user_id.localpart().starts_with(&prefix)
+        })
+    }
+
+    /// Returns whether the user with the given `user_id` belongs to the bridge.
+    pub fn is_bridge_user(&self, user_id: &UserId) -> bool {
+        user_id == &self.bot || (self.is_ghost)(user_id)
+    }
+}
+
+impl EventFilter for EchoFilter {
+    fn allows(&self, event: &AnyRoomEvent) -> bool {
+        !self.is_bridge_user(event.sender())
+    }
+}
+
+/// Drops the events sent by ignored users.
+#[derive(Debug, Default)]
+pub struct IgnoreFilter {
+    users: Mutex<HashSet<UserId>>,
+}
+
+impl IgnoreFilter {
+    /// Create a new `IgnoreFilter` without ignored users.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ignore the events of the user with the given `user_id`.
+    pub fn ignore(&self, user_id: UserId) {
+        self.users.lock().unwrap().insert(user_id);
+    }
+
+    /// Stop ignoring the events of the user with the given `user_id`.
+    ///
+    /// Returns whether the user was ignored.
+    pub fn unignore(&self, user_id: &UserId) -> bool {
+        self.users.lock().unwrap().remove(user_id)
+    }
+
+    /// Returns whether the events of the user with the given `user_id` are ignored.
+    pub fn is_ignored(&self, user_id: &UserId) -> bool {
+        self.users.lock().unwrap().contains(user_id)
+    }
+}
+
+impl EventFilter for IgnoreFilter {
+    fn allows(&self, event: &AnyRoomEvent) -> bool {
+        !self.is_ignored(event.sender())
+    }
+}
+
+/// Drops the events in rooms the bridge doesn't handle, such as rooms without a portal.
+///
+/// Membership events are always allowed, so the bridge still sees invites to new rooms.
+#[derive(Debug, Default)]
+pub struct RoomFilter {
+    rooms: Mutex<HashSet<RoomId>>,
+}
+
+impl RoomFilter {
+    /// Create a new `RoomFilter` that doesn't handle any rooms yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle the events in the room with the given `room_id`.
+    pub fn add_room(&self, room_id: RoomId) {
+        self.rooms.lock().unwrap().insert(room_id);
+    }
+
+    /// Stop handling the events in the room with the given `room_id`.
+    ///
+    /// Returns whether the room was handled.
+    pub fn remove_room(&self, room_id: &RoomId) -> bool {
+        self.rooms.lock().unwrap().remove(room_id)
+    }
+
+    /// Returns whether the events in the room with the given `room_id` are handled.
+    pub fn contains(&self, room_id: &RoomId) -> bool {
+        self.rooms.lock().unwrap().contains(room_id)
+    }
+}
+
+impl EventFilter for RoomFilter {
+    fn allows(&self, event: &AnyRoomEvent) -> bool {
+        matches!(event, AnyRoomEvent::State(AnyStateEvent::RoomMember(_)))
+            || self.contains(event.room_id())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    use ruma::events::AnyRoomEvent;
+    use ruma::identifiers::{RoomId, UserId};
+    use ruma::serde::Raw;
+    use serde_json::{json, value::to_raw_value};
+
+    use crate::{EchoFilter, EventFilters, IgnoreFilter, RoomFilter};
+
+    fn message(room_id: &str, sender: &str, body: &str) -> Raw<AnyRoomEvent> {
+        let event = json!({
+            "type": "m.room.message",
+            "event_id": "$event:example.org",
+            "room_id": room_id,
+            "sender": sender,
+            "origin_server_ts": 0,
+            "content": { "msgtype": "m.text", "body": body },
+        });
+        Raw::from_json(to_raw_value(&event).unwrap())
+    }
+
+    fn bodies(events: &[AnyRoomEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| {
+                serde_json::to_value(event).unwrap()["content"]["body"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_event_filters() {
+        let bot = UserId::try_from("@bot:example.org").unwrap();
+        let ignored = Arc::new(IgnoreFilter::new());
+        let rooms = Arc::new(RoomFilter::new());
+        rooms.add_room(RoomId::try_from("!room:example.org").unwrap());
+
+        let mut filters = EventFilters::new();
+        filters
+            .add(EchoFilter::with_prefix(bot, "_ext_"))
+            .add(ignored.clone())
+            .add(rooms.clone())
+            .add_fn(|event| event.sender().localpart() != "spammer");
+
+        ignored.ignore(UserId::try_from("@troll:example.org").unwrap());
+        let events = vec![
+            message("!room:example.org", "@alice:example.org", "1"),
+            message("!room:example.org", "@bot:example.org", "2"),
+            message("!room:example.org", "@_ext_bob:example.org", "3"),
+            message("!room:example.org", "@_ext_bob:other.org", "4"),
+            message("!room:example.org", "@troll:example.org", "5"),
+            message("!other:example.org", "@alice:example.org", "6"),
+            message("!room:example.org", "@spammer:example.org", "7"),
+        ];
+        assert_eq!(bodies(&filters.filter(&events)), ["1", "4"]);
+
+        assert!(ignored.unignore(&UserId::try_from("@troll:example.org").unwrap()));
+        rooms.add_room(RoomId::try_from("!other:example.org").unwrap());
+        assert_eq!(bodies(&filters.filter(&events)), ["1", "4", "5", "6"]);
+    }
+
+    #[test]
+    fn test_room_filter_membership() {
+        let rooms = RoomFilter::new();
+        let mut filters = EventFilters::new();
+        filters.add(rooms);
+        let invite: AnyRoomEvent = serde_json::from_value(json!({
+            "type": "m.room.member",
+            "event_id": "$invite:example.org",
+            "room_id": "!new:example.org",
+            "sender": "@alice:example.org",
+            "state_key": "@bot:example.org",
+            "origin_server_ts": 0,
+            "content": { "membership": "invite" },
+        }))
+        .unwrap();
+        assert!(filters.allows(&invite));
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use hyper::client::connect::Connect;
+use hyper::client::HttpConnector;
+use hyper::http;
+use hyper::Body;
+use ruma_client::{Client, HttpClient};
+use tokio::sync::Semaphore;
+
+use crate::client::AppserviceClient;
+use crate::proxy::{ProxyConfig, ProxyConnector};
+
+/// The settings of the connections a `HyperClient` makes.
+///
+/// Bridges send requests in bursts, like when a message is bridged to many rooms or many puppets
+/// join a portal. The defaults keep connections to the homeserver open between bursts, so they
+/// don't have to be set up again every time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpConfig {
+    /// The maximum amount of idle connections kept open per host.
+    pub max_idle_per_host: usize,
+    /// How long an idle connection is kept open, or `None` to keep it open until the server
+    /// closes it.
+    pub idle_timeout: Option<Duration>,
+    /// The interval of the TCP keep-alive probes, or `None` to not send them.
+    pub tcp_keepalive: Option<Duration>,
+    /// The maximum amount of requests sent to the same host at the same time, or `None` for no
+    /// limit. Requests over the limit wait for an earlier one to finish.
+    pub max_requests_per_host: Option<usize>,
+    /// How long to wait for a connection to be set up, or `None` to wait indefinitely.
+    pub connect_timeout: Option<Duration>,
+    /// Whether to disable Nagle's algorithm, sending small requests immediately.
+    pub nodelay: bool,
+    /// The proxies the requests go through.
+    pub proxy: ProxyConfig,
+}
+
+/// Up to 32 idle connections are kept open per host for 90 seconds, with TCP keep-alive probes
+/// every 60 seconds, and connecting times out after 10 seconds. No proxies are used, see
+/// `ProxyConfig::from_env` to use the proxies of the environment.
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_per_host: 32,
+            idle_timeout: Some(Duration::from_secs(90)),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            max_requests_per_host: None,
+            connect_timeout: Some(Duration::from_secs(10)),
+            nodelay: true,
+            proxy: ProxyConfig::default(),
+        }
+    }
+}
+
+impl HttpConfig {
+    /// Get an `HttpConnector` with the TCP settings of this config.
+    pub fn connector(&self) -> HttpConnector {
+        let mut connector = HttpConnector::new();
+        connector.set_keepalive(self.tcp_keepalive);
+        connector.set_connect_timeout(self.connect_timeout);
+        connector.set_nodelay(self.nodelay);
+        connector.enforce_http(false);
+        connector
+    }
+
+    /// Get a `ProxyConnector` with the TCP settings and proxies of this config, to wrap in a TLS
+    /// connector given to `HyperClient::with_connector`.
+    pub fn proxy_connector(&self) -> ProxyConnector {
+        ProxyConnector::new(self.connector(), self.proxy.clone())
+    }
+}
+
+/// An `HttpClient` using hyper, with its connections configured by an `HttpConfig`.
+///
+/// Only HTTP/1 is supported. `new` gives a client without TLS, which is enough for a homeserver
+/// on the same machine or network. For TLS, wrap `HttpConfig::proxy_connector` in a TLS
+/// connector and use `with_connector`.
+#[derive(Debug, Clone)]
+pub struct HyperClient<T = ProxyConnector> {
+    client: hyper::Client<T>,
+    proxy: ProxyConfig,
+    max_requests_per_host: Option<usize>,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl HyperClient {
+    /// Create a new `HyperClient` without TLS, following `config`.
+    pub fn new(config: &HttpConfig) -> Self {
+        Self::with_connector(config, config.proxy_connector())
+    }
+}
+
+impl<T: Connect + Clone> HyperClient<T> {
+    /// Create a new `HyperClient` making connections using `connector`, following the pool and
+    /// proxy settings of `config`. The `connector` should use the same proxies.
+    pub fn with_connector(config: &HttpConfig, connector: T) -> Self {
+        let client = hyper::Client::builder()
+            .pool_max_idle_per_host(config.max_idle_per_host)
+            .pool_idle_timeout(config.idle_timeout)
+            .build(connector);
+        Self {
+            client,
+            proxy: config.proxy.clone(),
+            max_requests_per_host: config.max_requests_per_host,
+            hosts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Get the semaphore limiting the requests to `host`, if the requests are limited.
+    fn limit_for(&self, host: &str) -> Option<Arc<Semaphore>> {
+        let max = self.max_requests_per_host?;
+        let mut hosts = self.hosts.lock().unwrap_or_else(PoisonError::into_inner);
+        let semaphore = hosts
+            .entry(host.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(max.max(1))));
+        Some(semaphore.clone())
+    }
+}
+
+#[async_trait]
+impl<T> HttpClient for HyperClient<T>
+where
+    T: Connect + Clone + Send + Sync + 'static,
+{
+    type RequestBody = BytesMut;
+    type ResponseBody = Bytes;
+    type Error = hyper::Error;
+
+    async fn send_http_request(
+        &self,
+        mut req: http::Request<BytesMut>,
+    ) -> Result<http::Response<Bytes>, hyper::Error> {
+        if let Some(authorization) = self.proxy.authorization_for(req.uri()) {
+            req.headers_mut()
+                .insert(http::header::PROXY_AUTHORIZATION, authorization);
+        }
+        let host = req
+            .uri()
+            .authority()
+            .map(|authority| authority.to_string())
+            .unwrap_or_default();
+        let _permit = match self.limit_for(&host) {
+            Some(semaphore) => Some(
+                semaphore
+                    .acquire_owned()
+                    .await
+                    .expect("the semaphore is never closed"),
+            ),
+            None => None,
+        };
+
+        let (head, body) = self
+            .client
+            .request(req.map(|body| Body::from(body.freeze())))
+            .await?
+            .into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        Ok(http::Response::from_parts(head, body))
+    }
+}
+
+impl AppserviceClient<HyperClient> {
+    /// Create a new `AppserviceClient` making requests to the homeserver at `homeserver_url`
+    /// using a `HyperClient` following `config`, authenticated using `as_token`.
+    pub fn with_config(homeserver_url: String, as_token: String, config: &HttpConfig) -> Self {
+        Self::from_client(Client::with_http_client(
+            HyperClient::new(config),
+            homeserver_url,
+            Some(as_token),
+        ))
+    }
+}
+
+#[cfg(all(test, feature = "serve"))]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{http, Body, Response, Server};
+    use ruma_client::HttpClient;
+
+    use crate::{HttpConfig, HyperClient};
+
+    #[tokio::test]
+    async fn test_max_requests_per_host() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let (active2, max_active2) = (active.clone(), max_active.clone());
+        let service = make_service_fn(move |_| {
+            let (active, max_active) = (active2.clone(), max_active2.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    let (active, max_active) = (active.clone(), max_active.clone());
+                    async move {
+                        let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_active.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        Ok::<_, Infallible>(Response::new(Body::from("{}")))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let config = HttpConfig {
+            max_requests_per_host: Some(2),
+            ..Default::default()
+        };
+        let client = HyperClient::new(&config);
+        let requests = (0..6).map(|_| {
+            let request = http::Request::get(format!("http://{}/", addr))
+                .body(BytesMut::new())
+                .unwrap();
+            client.send_http_request(request)
+        });
+        for response in futures::future::join_all(requests).await {
+            let response = response.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(&response.body()[..], b"{}");
+        }
+        assert_eq!(max_active.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,849 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ruma::api::client::error::ErrorKind;
+use ruma::api::client::r0::account::register::{self, LoginType, RegistrationKind};
+use ruma::api::client::r0::alias::delete_alias;
+use ruma::api::client::r0::appservice::set_room_visibility as set_appservice_room_visibility;
+use ruma::api::client::r0::config::{
+    get_global_account_data, get_room_account_data, set_global_account_data, set_room_account_data,
+};
+use ruma::api::client::r0::device::update_device;
+use ruma::api::client::r0::directory::{get_room_visibility, set_room_visibility};
+use ruma::api::client::r0::media::create_content;
+use ruma::api::client::r0::membership::{
+    get_member_events, invite_user, join_room_by_id,
+    joined_members::{self, RoomMember},
+    joined_rooms, kick_user, leave_room,
+};
+use ruma::api::client::r0::message::send_message_event;
+use ruma::api::client::r0::presence::set_presence;
+use ruma::api::client::r0::profile::{set_avatar_url, set_display_name};
+use ruma::api::client::r0::read_marker::set_read_marker;
+use ruma::api::client::r0::receipt::create_receipt;
+use ruma::api::client::r0::redact::redact_event;
+use ruma::api::client::r0::room::{get_room_event, Visibility};
+use ruma::api::client::r0::state::{get_state_events_for_key, send_state_event};
+use ruma::api::client::r0::typing::create_typing_event::{self, Typing};
+use ruma::api::client::r0::uiaa::UiaaResponse;
+use ruma::api::error::{FromHttpResponseError, ServerError};
+use ruma::api::OutgoingRequest;
+use ruma::events::reaction::{self, ReactionEventContent};
+use ruma::events::room::member::MemberEvent;
+use ruma::events::room::message::{InReplyTo, MessageEventContent, Relation};
+use ruma::events::{AnyMessageEventContent, AnyRoomEvent, EventType};
+use ruma::identifiers::{DeviceId, DeviceIdBox, EventId, MxcUri, RoomAliasId, RoomId, UserId};
+use ruma::presence::PresenceState;
+use ruma::receipt::ReceiptType;
+use ruma::serde::Raw;
+use ruma::MilliSecondsSinceUnixEpoch;
+use ruma_client::{Client, HttpClient};
+use serde_json::value::{to_raw_value, RawValue};
+use thiserror::Error;
+
+use crate::api::appservice_login;
+use crate::client::AppserviceClient;
+use crate::echo::EchoTracker;
+use crate::request::RequestBuilder;
+use crate::util::transaction_id;
+
+/// An error from a request made through an `Intent`.
+#[derive(Debug, Error)]
+pub enum IntentError<E> {
+    /// A request to the homeserver failed.
+    #[error("request to the homeserver failed: {0}")]
+    Request(ruma_client::Error<E, ruma::api::client::Error>),
+    /// Registering the user failed.
+    #[error("registering the user failed: {0}")]
+    Registration(ruma_client::Error<E, UiaaResponse>),
+    /// JSON returned by the homeserver doesn't have the expected format.
+    #[error("unexpected JSON from the homeserver: {0}")]
+    Json(serde_json::Error),
+    /// The request wasn't sent, because the homeserver is unreachable, see `CircuitBreaker`.
+    #[error("the homeserver is unreachable")]
+    Unreachable,
+}
+
+impl<E> IntentError<E> {
+    /// Get the error returned by the homeserver, if any.
+    pub fn matrix_error(&self) -> Option<&ruma::api::client::Error> {
+        match self {
+            IntentError::Request(ruma_client::Error::FromHttpResponse(
+                FromHttpResponseError::Http(ServerError::Known(err)),
+            )) => Some(err),
+            IntentError::Registration(ruma_client::Error::FromHttpResponse(
+                FromHttpResponseError::Http(ServerError::Known(UiaaResponse::MatrixError(err))),
+            )) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Get the kind of the error returned by the homeserver, if any.
+    pub fn kind(&self) -> Option<&ErrorKind> {
+        self.matrix_error().map(|err| &err.kind)
+    }
+}
+
+impl<E> From<ruma_client::Error<E, ruma::api::client::Error>> for IntentError<E> {
+    fn from(err: ruma_client::Error<E, ruma::api::client::Error>) -> Self {
+        IntentError::Request(err)
+    }
+}
+
+impl<E> From<ruma_client::Error<E, UiaaResponse>> for IntentError<E> {
+    fn from(err: ruma_client::Error<E, UiaaResponse>) -> Self {
+        IntentError::Registration(err)
+    }
+}
+
+impl<E> From<serde_json::Error> for IntentError<E> {
+    fn from(err: serde_json::Error) -> Self {
+        IntentError::Json(err)
+    }
+}
+
+/// A handle to act on the homeserver as a user in the namespace of the application service,
+/// either a virtual user or the bot user itself.
+///
+/// Every request is made using the `Client` of the application service, masquerading as the user
+/// using the `user_id` url parameter. An `Intent` created using `Intent::authenticated` instead
+/// uses a client with the access token of the user itself.
+///
+/// An `Intent` can be given the original timestamp of a bridged event using `with_timestamp`,
+/// which is then sent as the `ts` url parameter of every request, so the events it sends show
+/// the time they were sent on the external service.
+///
+/// The events sent by an `Intent` given an `EchoTracker` using `with_echo_tracker` are recorded,
+/// so they can be recognized when the homeserver pushes them back to the application service.
+///
+/// The requests of an `Intent` given an `AppserviceClient` using `with_appservice_client`, like
+/// the ones created using `AppserviceClient::intent`, are sent with the default url parameters
+/// and headers, the `RetryPolicy`, the rate limit monitor and the circuit breaker of that client.
+#[derive(Debug, Clone)]
+pub struct Intent<C> {
+    client: Client<C>,
+    user_id: UserId,
+    masquerade: bool,
+    timestamp: Option<MilliSecondsSinceUnixEpoch>,
+    echo: Option<Arc<EchoTracker>>,
+    appservice: Option<Arc<AppserviceClient<C>>>,
+}
+
+impl<C: HttpClient> Intent<C> {
+    /// Create a new `Intent` acting as `user_id`, using the given `client` of the application
+    /// service.
+    pub fn new(client: Client<C>, user_id: UserId) -> Self {
+        Self {
+            client,
+            user_id,
+            masquerade: true,
+            timestamp: None,
+            echo: None,
+            appservice: None,
+        }
+    }
+
+    /// Create a new `Intent` acting as `user_id`, using a `client` that is logged in as that user,
+    /// for example a real Matrix user that is double puppeted.
+    pub fn authenticated(client: Client<C>, user_id: UserId) -> Self {
+        Self {
+            client,
+            user_id,
+            masquerade: false,
+            timestamp: None,
+            echo: None,
+            appservice: None,
+        }
+    }
+
+    /// Get the ID of the user this `Intent` is acting as.
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
+
+    /// Get the `Client` used by this `Intent`.
+    pub fn client(&self) -> &Client<C> {
+        &self.client
+    }
+
+    /// Send the requests of this `Intent` with the given original `timestamp`, or with the
+    /// current time if it's `None`.
+    ///
+    /// Only the application service may set the timestamp, so it's ignored by an `Intent`
+    /// created using `Intent::authenticated`.
+    pub fn with_timestamp(mut self, timestamp: Option<MilliSecondsSinceUnixEpoch>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Get the original timestamp the requests of this `Intent` are sent with, if any.
+    pub fn timestamp(&self) -> Option<MilliSecondsSinceUnixEpoch> {
+        self.timestamp
+    }
+
+    /// Record the events sent by this `Intent` in the given echo `tracker`, or stop recording
+    /// them if it's `None`.
+    pub fn with_echo_tracker(mut self, tracker: Option<Arc<EchoTracker>>) -> Self {
+        self.echo = tracker;
+        self
+    }
+
+    /// Get the echo tracker the events sent by this `Intent` are recorded in, if any.
+    pub fn echo_tracker(&self) -> Option<&Arc<EchoTracker>> {
+        self.echo.as_ref()
+    }
+
+    /// Send the requests of this `Intent` with the settings of the given `AppserviceClient`, or
+    /// without them if it's `None`.
+    ///
+    /// The requests are still made using the `Client` of this `Intent`, so an `Intent` created
+    /// using `Intent::authenticated` keeps using the access token of its user.
+    pub fn with_appservice_client(mut self, client: Option<Arc<AppserviceClient<C>>>) -> Self {
+        self.set_appservice_client(client);
+        self
+    }
+
+    pub(crate) fn set_appservice_client(&mut self, client: Option<Arc<AppserviceClient<C>>>) {
+        self.appservice = client;
+    }
+
+    /// Get the `AppserviceClient` of which the settings are used by this `Intent`, if any.
+    pub fn appservice_client(&self) -> Option<&Arc<AppserviceClient<C>>> {
+        self.appservice.as_ref()
+    }
+
+    /// Record the event with the given `event_id` sent by this `Intent` in its echo tracker.
+    ///
+    /// The user is only marked as a bridge user when masquerading, as the user of an
+    /// authenticated `Intent` also sends events through their own client.
+    fn record_echo(&self, event_id: &EventId) {
+        if let Some(tracker) = &self.echo {
+            if self.masquerade {
+                tracker.add_user(self.user_id.clone());
+            }
+            tracker.record_event(event_id.clone());
+        }
+    }
+
+    /// Send the given `request` as the user of this `Intent`.
+    ///
+    /// If this `Intent` has an `AppserviceClient`, the request is sent with its settings, so it's
+    /// retried following its `RetryPolicy` and fails with `IntentError::Unreachable` while its
+    /// circuit breaker is open.
+    pub async fn send<R>(&self, request: R) -> Result<R::IncomingResponse, IntentError<C::Error>>
+    where
+        R: OutgoingRequest<EndpointError = ruma::api::client::Error> + Clone,
+    {
+        match &self.appservice {
+            Some(appservice) => {
+                appservice
+                    .send_with(&self.client, request, |builder| self.configure(builder))
+                    .await
+            }
+            None => {
+                let mut builder = RequestBuilder::new(&self.client, request);
+                self.configure(&mut builder);
+                Ok(builder.request().await?)
+            }
+        }
+    }
+
+    /// Send the given `request` as the application service itself, with the settings of the
+    /// `AppserviceClient` of this `Intent` if it has one.
+    async fn send_unmasqueraded<R>(
+        &self,
+        request: R,
+    ) -> Result<R::IncomingResponse, IntentError<C::Error>>
+    where
+        R: OutgoingRequest + Clone,
+        IntentError<C::Error>: From<ruma_client::Error<C::Error, R::EndpointError>>,
+    {
+        match &self.appservice {
+            Some(appservice) => appservice.send_with(&self.client, request, |_| {}).await,
+            None => Ok(self.client.send_request(request).await?),
+        }
+    }
+
+    /// Set the url parameters of the user of this `Intent` on `builder`.
+    fn configure<R: OutgoingRequest>(&self, builder: &mut RequestBuilder<'_, C, R>) {
+        if !self.masquerade {
+            return;
+        }
+        builder.user_id(&self.user_id);
+        if let Some(timestamp) = self.timestamp {
+            builder.timestamp(u64::from(timestamp.get()) as i64);
+        }
+    }
+
+    /// Register the user of this `Intent`, if it isn't registered yet.
+    pub async fn ensure_registered(&self) -> Result<(), IntentError<C::Error>> {
+        let mut request = register::Request::new();
+        request.username = Some(self.user_id.localpart());
+        request.kind = RegistrationKind::User;
+        request.inhibit_login = true;
+        request.login_type = Some(&LoginType::ApplicationService);
+
+        match self.send_unmasqueraded(request).await {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == Some(&ErrorKind::UserInUse) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Log in as the user of this `Intent` using the token of the application service, on the
+    /// device with the given `device_id` or on a new device if it's `None`.
+    ///
+    /// The new access token can be used for things that need a real device, like end-to-end
+    /// encryption.
+    pub async fn login(
+        &self,
+        device_id: Option<DeviceIdBox>,
+        device_display_name: Option<&str>,
+    ) -> Result<appservice_login::Response, IntentError<C::Error>> {
+        let mut request = appservice_login::Request::new(self.user_id.clone());
+        request.device_id = device_id;
+        request.initial_device_display_name = device_display_name.map(String::from);
+        self.send_unmasqueraded(request).await
+    }
+
+    /// Create the device with the given `device_id` for the user, or update its display name if
+    /// it exists, without logging in as described by MSC4190.
+    pub async fn create_device(
+        &self,
+        device_id: &DeviceId,
+        display_name: Option<&str>,
+    ) -> Result<(), IntentError<C::Error>> {
+        let mut request = update_device::Request::new(device_id);
+        request.display_name = display_name.map(String::from);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Join the room with the given `room_id`.
+    pub async fn join(&self, room_id: &RoomId) -> Result<RoomId, IntentError<C::Error>> {
+        let response = self.send(join_room_by_id::Request::new(room_id)).await?;
+        Ok(response.room_id)
+    }
+
+    /// Leave the room with the given `room_id`.
+    pub async fn leave(&self, room_id: &RoomId) -> Result<(), IntentError<C::Error>> {
+        self.send(leave_room::Request::new(room_id)).await?;
+        Ok(())
+    }
+
+    /// Invite `user_id` to the room with the given `room_id`.
+    pub async fn invite(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<(), IntentError<C::Error>> {
+        let recipient = invite_user::InvitationRecipient::UserId { user_id };
+        self.send(invite_user::Request::new(room_id, recipient))
+            .await?;
+        Ok(())
+    }
+
+    /// Kick the user with the given `user_id` from the room with the given `room_id`, with an
+    /// optional `reason`.
+    pub async fn kick(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        reason: Option<&str>,
+    ) -> Result<(), IntentError<C::Error>> {
+        let mut request = kick_user::Request::new(room_id, user_id);
+        request.reason = reason;
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Get the membership events of all members of the room with the given `room_id`, including
+    /// users that were invited, left or were banned.
+    ///
+    /// Events that can't be deserialized are skipped.
+    pub async fn members(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Vec<MemberEvent>, IntentError<C::Error>> {
+        let response = self.send(get_member_events::Request::new(room_id)).await?;
+        let members = response
+            .chunk
+            .iter()
+            .filter_map(|event| event.deserialize().ok())
+            .collect();
+        Ok(members)
+    }
+
+    /// Get the joined members of the room with the given `room_id`, with their displayname and
+    /// avatar in the room.
+    ///
+    /// Unlike `members`, this is a single lightweight request, meant for application services.
+    pub async fn joined_members(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<UserId, RoomMember>, IntentError<C::Error>> {
+        let response = self.send(joined_members::Request::new(room_id)).await?;
+        Ok(response.joined)
+    }
+
+    /// Get the IDs of the rooms this user is joined to.
+    pub async fn joined_rooms(&self) -> Result<Vec<RoomId>, IntentError<C::Error>> {
+        let response = self.send(joined_rooms::Request::new()).await?;
+        Ok(response.joined_rooms)
+    }
+
+    /// Send a message event with the given `content` to the room with the given `room_id`.
+    ///
+    /// Returns the ID of the sent event.
+    pub async fn send_message(
+        &self,
+        room_id: &RoomId,
+        content: &AnyMessageEventContent,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let txn_id = transaction_id();
+        let request = send_message_event::Request::new(room_id, &txn_id, content);
+        let response = self.send(request).await?;
+        self.record_echo(&response.event_id);
+        Ok(response.event_id)
+    }
+
+    /// Send a notice to the room with the given `room_id` that a message failed to bridge because
+    /// of `reason`, as a reply to the event with the given `event_id` if that's the message.
+    ///
+    /// Returns the ID of the sent event.
+    pub async fn send_failure_notice(
+        &self,
+        room_id: &RoomId,
+        event_id: Option<&EventId>,
+        reason: &str,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let body = format!("\u{26a0} Your message was not bridged: {}", reason);
+        let mut content = MessageEventContent::notice_plain(body);
+        if let Some(event_id) = event_id {
+            content.relates_to = Some(Relation::Reply {
+                in_reply_to: InReplyTo::new(event_id.clone()),
+            });
+        }
+        self.send_message(room_id, &AnyMessageEventContent::RoomMessage(content))
+            .await
+    }
+
+    /// Send a message event of type `event_type` with the given JSON `content` to the room with
+    /// the given `room_id`, for event types or content not known to `ruma`.
+    ///
+    /// Returns the ID of the sent event.
+    pub async fn send_message_raw(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+        content: Box<RawValue>,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let txn_id = transaction_id();
+        let content = Raw::from_json(content);
+        let request = send_message_event::Request::new_raw(room_id, &txn_id, event_type, content);
+        let response = self.send(request).await?;
+        self.record_echo(&response.event_id);
+        Ok(response.event_id)
+    }
+
+    /// Get the event with the given `event_id` in the room with the given `room_id`.
+    pub async fn get_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Raw<AnyRoomEvent>, IntentError<C::Error>> {
+        let request = get_room_event::Request::new(room_id, event_id);
+        let response = self.send(request).await?;
+        Ok(response.event)
+    }
+
+    /// React to the event with the given `event_id` in the room with the given `room_id` with
+    /// `key`, usually an emoji.
+    ///
+    /// Returns the ID of the reaction event, which can be redacted to remove the reaction.
+    pub async fn react(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        key: &str,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let relation = reaction::Relation::new(event_id.clone(), key.to_string());
+        let content = AnyMessageEventContent::Reaction(ReactionEventContent::new(relation));
+        self.send_message(room_id, &content).await
+    }
+
+    /// Redact the event with the given `event_id` in the room with the given `room_id`, with an
+    /// optional `reason`.
+    ///
+    /// Returns the ID of the redaction event.
+    pub async fn redact(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        reason: Option<&str>,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let txn_id = transaction_id();
+        let mut request = redact_event::Request::new(room_id, event_id, &txn_id);
+        request.reason = reason;
+        let response = self.send(request).await?;
+        self.record_echo(&response.event_id);
+        Ok(response.event_id)
+    }
+
+    /// Get the JSON content of the state event of type `event_type` with the given `state_key`
+    /// in the room with the given `room_id`.
+    pub async fn get_state_raw(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+        state_key: &str,
+    ) -> Result<Box<RawValue>, IntentError<C::Error>> {
+        let event_type = EventType::from(event_type);
+        let request = get_state_events_for_key::Request::new(room_id, event_type, state_key);
+        let response = self.send(request).await?;
+        Ok(response.content.into_json())
+    }
+
+    /// Send a state event of type `event_type` with the given `state_key` and JSON `content` to
+    /// the room with the given `room_id`, for event types not known to `ruma`.
+    ///
+    /// Returns the ID of the sent event.
+    pub async fn send_state_raw(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+        state_key: &str,
+        content: Box<RawValue>,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let content = Raw::from_json(content);
+        let request = send_state_event::Request::new_raw(room_id, event_type, state_key, content);
+        let response = self.send(request).await?;
+        self.record_echo(&response.event_id);
+        Ok(response.event_id)
+    }
+
+    /// Get whether the room with the given `room_id` is published in the room directory of the
+    /// homeserver.
+    pub async fn room_visibility(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Visibility, IntentError<C::Error>> {
+        let response = self
+            .send(get_room_visibility::Request::new(room_id))
+            .await?;
+        Ok(response.visibility)
+    }
+
+    /// Publish the room with the given `room_id` in the room directory of the homeserver if
+    /// `visibility` is public, or remove it from the directory if it's private.
+    pub async fn set_room_visibility(
+        &self,
+        room_id: &RoomId,
+        visibility: Visibility,
+    ) -> Result<(), IntentError<C::Error>> {
+        self.send(set_room_visibility::Request::new(room_id, visibility))
+            .await?;
+        Ok(())
+    }
+
+    /// Publish the room with the given `room_id` in the room directory of the application
+    /// service for the network `network_id` if `visibility` is public, or remove it if it's
+    /// private.
+    pub async fn set_appservice_room_visibility(
+        &self,
+        network_id: &str,
+        room_id: &RoomId,
+        visibility: Visibility,
+    ) -> Result<(), IntentError<C::Error>> {
+        let request = set_appservice_room_visibility::Request::new(network_id, room_id, visibility);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Remove the room alias `alias`. Only the creator of the alias and room admins may do so.
+    pub async fn delete_alias(&self, alias: &RoomAliasId) -> Result<(), IntentError<C::Error>> {
+        self.send(delete_alias::Request::new(alias)).await?;
+        Ok(())
+    }
+
+    /// Get the JSON content of the global account data of type `event_type` of the user, or
+    /// `None` if it isn't set.
+    pub async fn get_account_data_raw(
+        &self,
+        event_type: &str,
+    ) -> Result<Option<Box<RawValue>>, IntentError<C::Error>> {
+        let request = get_global_account_data::Request::new(&self.user_id, event_type);
+        match self.send(request).await {
+            Ok(response) => Ok(Some(response.account_data.into_json())),
+            Err(err) if err.kind() == Some(&ErrorKind::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Set the global account data of type `event_type` of the user to the JSON `content`.
+    pub async fn set_account_data_raw(
+        &self,
+        event_type: &str,
+        content: &RawValue,
+    ) -> Result<(), IntentError<C::Error>> {
+        let request = set_global_account_data::Request::new(content, event_type, &self.user_id);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Get the JSON content of the account data of type `event_type` of the user in the room
+    /// with the given `room_id`, or `None` if it isn't set.
+    pub async fn get_room_account_data_raw(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+    ) -> Result<Option<Box<RawValue>>, IntentError<C::Error>> {
+        let request = get_room_account_data::Request::new(&self.user_id, room_id, event_type);
+        match self.send(request).await {
+            Ok(response) => Ok(Some(response.account_data.into_json())),
+            Err(err) if err.kind() == Some(&ErrorKind::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Set the account data of type `event_type` of the user in the room with the given
+    /// `room_id` to the JSON `content`.
+    pub async fn set_room_account_data_raw(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+        content: &RawValue,
+    ) -> Result<(), IntentError<C::Error>> {
+        let request =
+            set_room_account_data::Request::new(content, event_type, room_id, &self.user_id);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Mark the room with the given `room_id` as a direct chat with `user_id` in the `m.direct`
+    /// account data of the user, so clients show it as a direct message.
+    pub async fn add_direct_room(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<(), IntentError<C::Error>> {
+        let mut direct: BTreeMap<UserId, Vec<RoomId>> =
+            match self.get_account_data_raw("m.direct").await? {
+                Some(content) => serde_json::from_str(content.get()).unwrap_or_default(),
+                None => BTreeMap::new(),
+            };
+        let rooms = direct.entry(user_id.clone()).or_default();
+        if rooms.contains(room_id) {
+            return Ok(());
+        }
+        rooms.push(room_id.clone());
+
+        let content = to_raw_value(&direct).expect("m.direct should serialize");
+        self.set_account_data_raw("m.direct", &content).await
+    }
+
+    /// Send a read receipt for the event with the given `event_id` in the room with the given
+    /// `room_id`, marking it and all events before it as read by the user.
+    pub async fn send_read_receipt(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<(), IntentError<C::Error>> {
+        let request = create_receipt::Request::new(room_id, ReceiptType::Read, event_id);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Move the fully read marker of the user in the room with the given `room_id` to the event
+    /// with the given `fully_read` ID, and optionally send a read receipt for `read_receipt`.
+    pub async fn set_read_markers(
+        &self,
+        room_id: &RoomId,
+        fully_read: &EventId,
+        read_receipt: Option<&EventId>,
+    ) -> Result<(), IntentError<C::Error>> {
+        let mut request = set_read_marker::Request::new(room_id, fully_read);
+        request.read_receipt = read_receipt;
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Mark the user as typing in the room with the given `room_id` for the given `timeout`, or
+    /// as not typing if `timeout` is `None`.
+    pub async fn set_typing(
+        &self,
+        room_id: &RoomId,
+        timeout: Option<Duration>,
+    ) -> Result<(), IntentError<C::Error>> {
+        let state = match timeout {
+            Some(timeout) => Typing::Yes(timeout),
+            None => Typing::No,
+        };
+        let request = create_typing_event::Request::new(&self.user_id, room_id, state);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Set the presence of the user, with an optional status message.
+    pub async fn set_presence(
+        &self,
+        presence: PresenceState,
+        status_msg: Option<&str>,
+    ) -> Result<(), IntentError<C::Error>> {
+        let mut request = set_presence::Request::new(&self.user_id, presence);
+        request.status_msg = status_msg;
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Set the display name of the user, or remove it if `display_name` is `None`.
+    pub async fn set_display_name(
+        &self,
+        display_name: Option<&str>,
+    ) -> Result<(), IntentError<C::Error>> {
+        self.send(set_display_name::Request::new(&self.user_id, display_name))
+            .await?;
+        Ok(())
+    }
+
+    /// Set the avatar of the user, or remove it if `avatar_url` is `None`.
+    pub async fn set_avatar_url(
+        &self,
+        avatar_url: Option<&MxcUri>,
+    ) -> Result<(), IntentError<C::Error>> {
+        self.send(set_avatar_url::Request::new(&self.user_id, avatar_url))
+            .await?;
+        Ok(())
+    }
+
+    /// Upload `data` with the given `content_type` and optional `filename` to the media
+    /// repository of the homeserver.
+    ///
+    /// Returns the MXC URI of the uploaded media.
+    pub async fn upload(
+        &self,
+        data: &[u8],
+        content_type: &str,
+        filename: Option<&str>,
+    ) -> Result<MxcUri, IntentError<C::Error>> {
+        let mut request = create_content::Request::new(data);
+        request.content_type = Some(content_type);
+        request.filename = filename;
+        let response = self.send(request).await?;
+        Ok(response.content_uri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::events::room::message::MessageEventContent;
+    use ruma::events::AnyMessageEventContent;
+    use ruma::identifiers::{RoomId, UserId};
+    use ruma::{MilliSecondsSinceUnixEpoch, UInt};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::Intent;
+
+    #[tokio::test]
+    async fn test_intent() {
+        let (client, state) = mock_client();
+        let user_id = UserId::try_from("@_ext_alice:example.org").unwrap();
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let intent = Intent::new(client, user_id);
+
+        state.respond("/send/", 200, json!({ "event_id": "$event:example.org" }));
+        let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("hi"));
+        let event_id = intent.send_message(&room_id, &content).await.unwrap();
+        assert_eq!(event_id.as_str(), "$event:example.org");
+
+        let request = &state.requests()[0];
+        assert_eq!(request.method, "PUT");
+        assert!(request.path.ends_with("?user_id=@_ext_alice:example.org"));
+        assert_eq!(request.body["body"], "hi");
+
+        let massaged = intent
+            .clone()
+            .with_timestamp(Some(MilliSecondsSinceUnixEpoch(UInt::from(1234u32))));
+        massaged.send_message(&room_id, &content).await.unwrap();
+        let request = state.requests().pop().unwrap();
+        assert!(request.path.contains("ts=1234"));
+        assert!(request.path.contains("user_id=@_ext_alice:example.org"));
+
+        intent
+            .send_failure_notice(&room_id, Some(&event_id), "Too large")
+            .await
+            .unwrap();
+        let request = &state.requests()[2];
+        assert!(!request.path.contains("ts="));
+        assert_eq!(
+            request.body,
+            json!({
+                "msgtype": "m.notice",
+                "body": "\u{26a0} Your message was not bridged: Too large",
+                "m.relates_to": { "m.in_reply_to": { "event_id": "$event:example.org" } },
+            })
+        );
+
+        state.respond(
+            "/register",
+            400,
+            json!({ "errcode": "M_USER_IN_USE", "error": "User ID already taken." }),
+        );
+        intent.ensure_registered().await.unwrap();
+        state.respond_once(
+            "/register",
+            403,
+            json!({ "errcode": "M_FORBIDDEN", "error": "Not in namespace." }),
+        );
+        assert!(intent.ensure_registered().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let (client, state) = mock_client();
+        let user_id = UserId::try_from("@_ext_alice:example.org").unwrap();
+        let intent = Intent::new(client, user_id);
+
+        state.respond(
+            "/login",
+            200,
+            json!({
+                "user_id": "@_ext_alice:example.org",
+                "access_token": "token",
+                "device_id": "BRIDGE",
+            }),
+        );
+        let response = intent
+            .login(Some("BRIDGE".into()), Some("Bridge"))
+            .await
+            .unwrap();
+        assert_eq!(response.access_token, "token");
+
+        let request = &state.requests_to("/login")[0];
+        assert_eq!(request.path, "/_matrix/client/r0/login");
+        assert_eq!(
+            request.body,
+            json!({
+                "type": "m.login.application_service",
+                "identifier": { "type": "m.id.user", "user": "@_ext_alice:example.org" },
+                "device_id": "BRIDGE",
+                "initial_device_display_name": "Bridge",
+            })
+        );
+
+        state.respond_once(
+            "/login",
+            403,
+            json!({ "errcode": "M_FORBIDDEN", "error": "Not in namespace." }),
+        );
+        let err = intent.login(None, None).await.unwrap_err();
+        assert_eq!(
+            err.kind(),
+            Some(&ruma::api::client::error::ErrorKind::Forbidden)
+        );
+    }
+}
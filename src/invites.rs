@@ -0,0 +1,349 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use ruma::events::room::member::{MemberEvent, MembershipState};
+use ruma::events::{AnyRoomEvent, AnyStateEvent};
+use ruma::identifiers::{RoomId, ServerName, UserId};
+use ruma_client::{Client, HttpClient};
+use serde_json::{json, value::to_raw_value};
+
+use crate::client::AppserviceClient;
+use crate::intent::Intent;
+use crate::portal::{Portal, PortalError, PortalManager};
+use crate::store::MappingStore;
+
+/// An invite of a puppet to a room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    /// The room the puppet is invited to.
+    pub room_id: RoomId,
+    /// The user that sent the invite.
+    pub sender: UserId,
+    /// The invited puppet.
+    pub user_id: UserId,
+    /// Whether the invite is for a direct chat.
+    pub is_direct: bool,
+}
+
+impl Invite {
+    /// Get the invite in the membership `event`, or `None` if it isn't an invite.
+    pub fn from_event(event: &MemberEvent) -> Option<Self> {
+        if event.content.membership != MembershipState::Invite {
+            return None;
+        }
+        Some(Self {
+            room_id: event.room_id.clone(),
+            sender: event.sender.clone(),
+            user_id: UserId::try_from(event.state_key.as_str()).ok()?,
+            is_direct: event.content.is_direct == Some(true),
+        })
+    }
+}
+
+/// What an `InvitePolicy` does with an invite.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InviteAction {
+    /// The puppet joins the room.
+    Accept,
+    /// The puppet joins the room, which becomes the direct chat portal with the given external
+    /// ID.
+    CreateDm(String),
+    /// The puppet rejects the invite, with the given reason if any.
+    Reject(Option<String>),
+    /// The invite is left alone and passed to the `on_forward` callbacks.
+    Forward,
+}
+
+type UserPredicate = Box<dyn Fn(&UserId) -> bool + Send + Sync>;
+type RuleFn = Box<dyn Fn(&Invite) -> InviteAction + Send + Sync>;
+type ForwardFn = Box<dyn Fn(&Invite) + Send + Sync>;
+
+/// Decides what happens with the invites of users to the puppets of the bridge, and carries it
+/// out.
+///
+/// Invites from users that aren't allowed are rejected, with the reason set using
+/// `set_rejection_reason`. By default every user is allowed, which can be limited using
+/// `allow_user` and `allow_server`. What happens with the invites of allowed users is decided by
+/// the rule set using `set_rule`, which accepts every invite by default.
+///
+/// The `on_forward` callbacks are called synchronously, so any requests they make should be
+/// spawned.
+pub struct InvitePolicy<C> {
+    client: Client<C>,
+    appservice: Option<Arc<AppserviceClient<C>>>,
+    is_ghost: UserPredicate,
+    allowed_users: HashSet<UserId>,
+    allowed_servers: HashSet<Box<ServerName>>,
+    rejection_reason: Option<String>,
+    rule: RuleFn,
+    on_forward: Vec<ForwardFn>,
+}
+
+impl<C: HttpClient + Clone> InvitePolicy<C> {
+    /// Create a new `InvitePolicy` handling the invites of the users for which `is_ghost` returns
+    /// `true`, acting as them using `client`.
+    pub fn new<F>(client: Client<C>, is_ghost: F) -> Self
+    where
+        F: Fn(&UserId) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            client,
+            appservice: None,
+            is_ghost: Box::new(is_ghost),
+            allowed_users: HashSet::new(),
+            allowed_servers: HashSet::new(),
+            rejection_reason: None,
+            rule: Box::new(|_| InviteAction::Accept),
+            on_forward: vec![],
+        }
+    }
+
+    /// Create a new `InvitePolicy` handling the invites of the users on the server with the given
+    /// `server_name` whose localpart starts with `prefix`, acting as them using `client`.
+    pub fn with_prefix(client: Client<C>, server_name: &ServerName, prefix: &str) -> Self {
+        let server_name = server_name.to_owned();
+        let prefix = prefix.to_string();
+        Self::new(client, move |user_id| {
+            user_id.server_name() == &*server_name && user_id.localpart().starts_with(&prefix)
+        })
+    }
+
+    /// Send the requests of the puppets with the settings of the given `AppserviceClient`, or
+    /// without them if it's `None`.
+    pub fn set_appservice_client(&mut self, client: Option<Arc<AppserviceClient<C>>>) {
+        self.appservice = client;
+    }
+
+    /// Allow the user with the given `user_id` to invite puppets.
+    pub fn allow_user(&mut self, user_id: UserId) {
+        self.allowed_users.insert(user_id);
+    }
+
+    /// Allow all users on the server with the given `server_name` to invite puppets.
+    pub fn allow_server(&mut self, server_name: Box<ServerName>) {
+        self.allowed_servers.insert(server_name);
+    }
+
+    /// Set the reason given when rejecting the invites of users that aren't allowed.
+    pub fn set_rejection_reason(&mut self, reason: Option<String>) {
+        self.rejection_reason = reason;
+    }
+
+    /// Use `f` to decide what happens with the invites of allowed users.
+    pub fn set_rule<F>(&mut self, f: F)
+    where
+        F: Fn(&Invite) -> InviteAction + Send + Sync + 'static,
+    {
+        self.rule = Box::new(f);
+    }
+
+    /// Call `f` with the invites that are forwarded by the rule.
+    pub fn on_forward<F>(&mut self, f: F)
+    where
+        F: Fn(&Invite) + Send + Sync + 'static,
+    {
+        self.on_forward.push(Box::new(f));
+    }
+
+    /// Returns whether the user with the given `user_id` is allowed to invite puppets.
+    pub fn is_allowed(&self, user_id: &UserId) -> bool {
+        if self.allowed_users.is_empty() && self.allowed_servers.is_empty() {
+            return true;
+        }
+        self.allowed_users.contains(user_id) || self.allowed_servers.contains(user_id.server_name())
+    }
+
+    /// Decide what happens with `invite`, without carrying it out.
+    pub fn decide(&self, invite: &Invite) -> InviteAction {
+        if self.is_allowed(&invite.sender) {
+            (self.rule)(invite)
+        } else {
+            InviteAction::Reject(self.rejection_reason.clone())
+        }
+    }
+
+    /// Handle `event` if it's an invite of a puppet, carrying out what the policy decides. A
+    /// direct chat portal is added to `portals`, replacing any portal with the same external ID.
+    ///
+    /// Returns the invite and what was done with it, or `None` if the event isn't an invite of a
+    /// puppet.
+    pub async fn handle_event<S>(
+        &self,
+        event: &AnyRoomEvent,
+        portals: &PortalManager<C, S>,
+    ) -> Result<Option<(Invite, InviteAction)>, PortalError<C::Error, S::Error>>
+    where
+        S: MappingStore<Portal>,
+    {
+        let invite = match event {
+            AnyRoomEvent::State(AnyStateEvent::RoomMember(event)) => Invite::from_event(event),
+            _ => None,
+        };
+        let invite = match invite {
+            Some(invite) if (self.is_ghost)(&invite.user_id) => invite,
+            _ => return Ok(None),
+        };
+
+        let action = self.decide(&invite);
+        let ghost = Intent::new(self.client.clone(), invite.user_id.clone())
+            .with_appservice_client(self.appservice.clone());
+        match &action {
+            InviteAction::Accept => {
+                ghost.ensure_registered().await?;
+                ghost.join(&invite.room_id).await?;
+            }
+            InviteAction::CreateDm(external_id) => {
+                ghost.ensure_registered().await?;
+                ghost.join(&invite.room_id).await?;
+                ghost
+                    .add_direct_room(&invite.sender, &invite.room_id)
+                    .await?;
+                let portal = Portal::new(invite.room_id.clone(), external_id.clone(), None);
+                portals
+                    .store()
+                    .insert(portal)
+                    .await
+                    .map_err(PortalError::Store)?;
+            }
+            InviteAction::Reject(None) => ghost.leave(&invite.room_id).await?,
+            InviteAction::Reject(Some(reason)) => {
+                // leaving using a state event, since `/leave` doesn't take a reason yet.
+                let content = json!({ "membership": "leave", "reason": reason });
+                let content = to_raw_value(&content).expect("member event should serialize");
+                ghost
+                    .send_state_raw(
+                        &invite.room_id,
+                        "m.room.member",
+                        invite.user_id.as_str(),
+                        content,
+                    )
+                    .await?;
+            }
+            InviteAction::Forward => {
+                for f in &self.on_forward {
+                    f(&invite);
+                }
+            }
+        }
+        Ok(Some((invite, action)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+
+    use ruma::events::AnyRoomEvent;
+    use ruma::identifiers::{RoomId, ServerName, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{Intent, InviteAction, InvitePolicy, PortalManager};
+
+    fn invite(sender: &str, user_id: &str, is_direct: bool) -> AnyRoomEvent {
+        serde_json::from_value(json!({
+            "type": "m.room.member",
+            "event_id": "$invite:example.org",
+            "room_id": "!room:example.org",
+            "sender": sender,
+            "state_key": user_id,
+            "origin_server_ts": 0,
+            "content": { "membership": "invite", "is_direct": is_direct },
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_invite_policy() {
+        let (client, state) = mock_client();
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let bot = Intent::new(
+            client.clone(),
+            UserId::try_from("@bot:example.org").unwrap(),
+        );
+        let portals: PortalManager<_> =
+            PortalManager::new(bot, server_name.clone(), "_ext_", Default::default());
+
+        let mut policy = InvitePolicy::with_prefix(client, &server_name, "_ext_");
+        policy.allow_server(server_name);
+        policy.set_rejection_reason(Some(String::from("Not allowed")));
+        policy.set_rule(|invite| match invite.is_direct {
+            true => InviteAction::CreateDm(format!("dm-{}", invite.sender.localpart())),
+            false if invite.sender.localpart() == "alice" => InviteAction::Accept,
+            false => InviteAction::Forward,
+        });
+        let forwarded = Arc::new(Mutex::new(vec![]));
+        let forwards = forwarded.clone();
+        policy.on_forward(move |invite| forwards.lock().unwrap().push(invite.sender.clone()));
+
+        state.respond(
+            "/register",
+            200,
+            json!({ "user_id": "@_ext_bob:example.org" }),
+        );
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+        state.respond("/state/", 200, json!({ "event_id": "$leave:example.org" }));
+
+        let event = invite("@alice:example.org", "@_ext_bob:example.org", false);
+        let (_, action) = policy
+            .handle_event(&event, &portals)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(action, InviteAction::Accept);
+        assert!(state.requests_to("/join")[0]
+            .path
+            .contains("user_id=@_ext_bob:example.org"));
+
+        let event = invite("@alice:example.org", "@_ext_bob:example.org", true);
+        policy
+            .handle_event(&event, &portals)
+            .await
+            .unwrap()
+            .unwrap();
+        let portal = portals.get("dm-alice").await.unwrap().unwrap();
+        assert_eq!(portal.room_id().as_str(), "!room:example.org");
+        let direct = &state.requests_to("/account_data/m.direct")[1];
+        assert_eq!(
+            direct.body,
+            json!({ "@alice:example.org": ["!room:example.org"] })
+        );
+
+        let event = invite("@carol:example.org", "@_ext_bob:example.org", false);
+        let (_, action) = policy
+            .handle_event(&event, &portals)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(action, InviteAction::Forward);
+        assert_eq!(forwarded.lock().unwrap().len(), 1);
+
+        let event = invite("@mallory:evil.org", "@_ext_bob:example.org", false);
+        let (_, action) = policy
+            .handle_event(&event, &portals)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            action,
+            InviteAction::Reject(Some(String::from("Not allowed")))
+        );
+        let leave = &state.requests_to("/state/m.room.member/@_ext_bob:example.org")[0];
+        assert_eq!(
+            leave.body,
+            json!({ "membership": "leave", "reason": "Not allowed" })
+        );
+        assert_eq!(state.requests_to("/join").len(), 2);
+
+        let event = invite("@alice:example.org", "@dave:example.org", false);
+        assert!(policy
+            .handle_event(&event, &portals)
+            .await
+            .unwrap()
+            .is_none());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        assert!(portals.get_by_room(&room_id).await.unwrap().is_some());
+    }
+}
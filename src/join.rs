@@ -0,0 +1,244 @@
+use std::time::Duration;
+
+use ruma::api::client::error::ErrorKind;
+use ruma::identifiers::RoomId;
+use ruma_client::HttpClient;
+use thiserror::Error;
+
+use crate::api::knock;
+use crate::intent::{Intent, IntentError};
+
+#[cfg(feature = "runtime")]
+use tokio::time::Instant;
+
+/// How `Intent::join_with` gets into a room the user isn't allowed to join directly.
+#[derive(Debug, Clone)]
+pub struct JoinOptions {
+    /// Whether the bot joins the room and invites the user, for rooms with the `invite` or
+    /// `restricted` join rule in which the bot is or may be allowed to join.
+    pub invite_via_bot: bool,
+    /// Whether the user knocks on the room, for rooms with the `knock` join rule.
+    pub knock: bool,
+    /// The reason for joining the room, shown with the knock.
+    pub reason: Option<String>,
+    /// How long to wait for the knock to be accepted.
+    pub knock_timeout: Duration,
+    /// How often to try joining while waiting for the knock to be accepted.
+    pub poll_interval: Duration,
+}
+
+/// Both the bot and knocking are used, and a knock is waited for up to five minutes, trying to
+/// join every five seconds.
+impl Default for JoinOptions {
+    fn default() -> Self {
+        Self {
+            invite_via_bot: true,
+            knock: true,
+            reason: None,
+            knock_timeout: Duration::from_secs(5 * 60),
+            poll_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// An error from `Intent::join_with`.
+#[derive(Debug, Error)]
+pub enum JoinError<E> {
+    /// A request to the homeserver failed, or the user isn't allowed to join.
+    #[error("{0}")]
+    Intent(IntentError<E>),
+    /// The user knocked on the room, but the knock wasn't accepted in time.
+    #[error("the knock on the room wasn't accepted in time")]
+    KnockPending,
+}
+
+impl<E> From<IntentError<E>> for JoinError<E> {
+    fn from(err: IntentError<E>) -> Self {
+        JoinError::Intent(err)
+    }
+}
+
+fn is_forbidden<E>(err: &IntentError<E>) -> bool {
+    err.kind() == Some(&ErrorKind::Forbidden)
+}
+
+impl<C: HttpClient> Intent<C> {
+    /// Knock on the room with the given `room_id`, asking its members to let the user in, with
+    /// the given `reason` if any.
+    ///
+    /// Once the knock is accepted, the user is invited and can join the room.
+    pub async fn knock(
+        &self,
+        room_id: &RoomId,
+        reason: Option<&str>,
+    ) -> Result<RoomId, IntentError<C::Error>> {
+        let mut request = knock::Request::new(room_id.clone());
+        request.reason = reason.map(String::from);
+        let response = self.send(request).await?;
+        Ok(response.room_id)
+    }
+
+    /// Join the room with the given `room_id`, also when the user isn't allowed to join it
+    /// directly.
+    ///
+    /// If joining is forbidden, the `bot` joins the room and invites the user, which works for
+    /// rooms the bot is in and for `restricted` rooms the bot is allowed to join. If that fails
+    /// too, the user knocks on the room and tries to join again until the knock is accepted or
+    /// `knock_timeout` has passed. Which of these are tried is set by `options`.
+    ///
+    /// Waiting for a knock requires the `runtime` feature. Without it, joining is only tried
+    /// again once, right after knocking.
+    pub async fn join_with(
+        &self,
+        room_id: &RoomId,
+        bot: Option<&Intent<C>>,
+        options: &JoinOptions,
+    ) -> Result<RoomId, JoinError<C::Error>> {
+        let err = match self.join(room_id).await {
+            Ok(room_id) => return Ok(room_id),
+            Err(err) if is_forbidden(&err) => err,
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some(bot) = bot.filter(|_| options.invite_via_bot) {
+            let invited = match bot.join(room_id).await {
+                Ok(_) => bot.invite(room_id, self.user_id()).await.is_ok(),
+                Err(_) => false,
+            };
+            if invited {
+                return Ok(self.join(room_id).await?);
+            }
+        }
+        if !options.knock {
+            return Err(err.into());
+        }
+
+        self.knock(room_id, options.reason.as_deref()).await?;
+        self.wait_for_knock(room_id, options).await
+    }
+
+    /// Try to join the room with the given `room_id` until the knock on it is accepted.
+    #[cfg(feature = "runtime")]
+    async fn wait_for_knock(
+        &self,
+        room_id: &RoomId,
+        options: &JoinOptions,
+    ) -> Result<RoomId, JoinError<C::Error>> {
+        let deadline = Instant::now() + options.knock_timeout;
+        loop {
+            match self.join(room_id).await {
+                Ok(room_id) => return Ok(room_id),
+                Err(err) if is_forbidden(&err) => {}
+                Err(err) => return Err(err.into()),
+            }
+            if Instant::now() + options.poll_interval > deadline {
+                return Err(JoinError::KnockPending);
+            }
+            tokio::time::sleep(options.poll_interval).await;
+        }
+    }
+
+    /// Try to join the room with the given `room_id` once, in case the knock on it was accepted
+    /// immediately.
+    #[cfg(not(feature = "runtime"))]
+    async fn wait_for_knock(
+        &self,
+        room_id: &RoomId,
+        _options: &JoinOptions,
+    ) -> Result<RoomId, JoinError<C::Error>> {
+        match self.join(room_id).await {
+            Ok(room_id) => Ok(room_id),
+            Err(err) if is_forbidden(&err) => Err(JoinError::KnockPending),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    use ruma::identifiers::{RoomId, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{Intent, JoinError, JoinOptions};
+
+    fn forbidden() -> serde_json::Value {
+        json!({ "errcode": "M_FORBIDDEN", "error": "You are not invited to this room." })
+    }
+
+    #[tokio::test]
+    async fn test_join_via_bot() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(
+            client.clone(),
+            UserId::try_from("@bot:example.org").unwrap(),
+        );
+        let ghost = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        state.respond_once("/join", 403, forbidden());
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+        let joined = ghost
+            .join_with(&room_id, Some(&bot), &JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(joined, room_id);
+
+        let joins = state.requests_to("/join");
+        assert_eq!(joins.len(), 3);
+        assert!(joins[1].path.contains("user_id=@bot:example.org"));
+        let invite = &state.requests_to("/invite")[0];
+        assert_eq!(invite.body["user_id"], "@_ext_bob:example.org");
+        assert!(state.requests_to("/knock").is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_join_via_knock() {
+        let (client, state) = mock_client();
+        let ghost = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let options = JoinOptions {
+            reason: Some(String::from("Bridging")),
+            knock_timeout: Duration::from_secs(10),
+            poll_interval: Duration::from_secs(1),
+            ..Default::default()
+        };
+
+        state.respond_once("/join", 403, forbidden());
+        state.respond_once("/join", 403, forbidden());
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+        state.respond("/knock/", 200, json!({ "room_id": "!room:example.org" }));
+        let result = ghost.join_with(&room_id, None, &options).await;
+        if cfg!(feature = "runtime") {
+            assert_eq!(result.unwrap(), room_id);
+            assert_eq!(state.requests_to("/join").len(), 3);
+        } else {
+            assert!(matches!(result, Err(JoinError::KnockPending)));
+        }
+
+        let knock = &state.requests_to("/knock/")[0];
+        assert!(knock
+            .path
+            .starts_with("/_matrix/client/v3/knock/!room:example.org"));
+        assert_eq!(knock.body, json!({ "reason": "Bridging" }));
+    }
+
+    #[tokio::test]
+    async fn test_join_forbidden() {
+        let (client, state) = mock_client();
+        let ghost = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let options = JoinOptions {
+            knock: false,
+            ..Default::default()
+        };
+
+        state.respond("/join", 403, forbidden());
+        let err = ghost.join_with(&room_id, None, &options).await.unwrap_err();
+        assert!(matches!(err, JoinError::Intent(_)));
+        assert_eq!(state.requests().len(), 1);
+    }
+}
@@ -0,0 +1,163 @@
+use std::borrow::Cow;
+
+use ruma::events::AnyRoomEvent;
+use ruma::serde::Raw;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+/// The fields of an event needed to decide what to do with it, borrowed from the JSON of the
+/// event where possible.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct EventHeader<'a> {
+    /// The type of the event, like `m.room.message`.
+    #[serde(rename = "type", borrow)]
+    pub event_type: Cow<'a, str>,
+    /// The room the event was sent in.
+    #[serde(borrow)]
+    pub room_id: Cow<'a, str>,
+    /// The user that sent the event.
+    #[serde(borrow)]
+    pub sender: Cow<'a, str>,
+    /// The ID of the event.
+    #[serde(borrow)]
+    pub event_id: Cow<'a, str>,
+    /// The state key of the event, if it's a state event.
+    #[serde(borrow, default)]
+    pub state_key: Option<Cow<'a, str>>,
+}
+
+/// An event of a transaction of which only the `EventHeader` is parsed, borrowing the JSON of
+/// the transaction.
+///
+/// Fully deserializing every event of a transaction is wasteful for bridges that handle only a
+/// few types of events, or only the events in their portals. The header is enough to decide
+/// whether an event is needed, after which it can be deserialized using `deserialize`.
+#[derive(Debug, Clone)]
+pub struct LazyEvent<'a> {
+    json: &'a RawValue,
+    header: EventHeader<'a>,
+}
+
+impl<'a> LazyEvent<'a> {
+    /// Parse the header of the event with the given `json`.
+    pub fn parse(json: &'a RawValue) -> Result<Self, serde_json::Error> {
+        let header = serde_json::from_str(json.get())?;
+        Ok(Self { json, header })
+    }
+
+    /// Get the header of the event.
+    pub fn header(&self) -> &EventHeader<'a> {
+        &self.header
+    }
+
+    /// Get the type of the event.
+    pub fn event_type(&self) -> &str {
+        &self.header.event_type
+    }
+
+    /// Get the ID of the room the event was sent in.
+    pub fn room_id(&self) -> &str {
+        &self.header.room_id
+    }
+
+    /// Get the ID of the user that sent the event.
+    pub fn sender(&self) -> &str {
+        &self.header.sender
+    }
+
+    /// Get the ID of the event.
+    pub fn event_id(&self) -> &str {
+        &self.header.event_id
+    }
+
+    /// Get the JSON of the event.
+    pub fn json(&self) -> &'a RawValue {
+        self.json
+    }
+
+    /// Fully deserialize the event as a `T`, like `AnyRoomEvent` or a specific type of event.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_str(self.json.get())
+    }
+
+    /// Copy the JSON of the event into a `Raw<AnyRoomEvent>`.
+    pub fn to_raw(&self) -> Raw<AnyRoomEvent> {
+        Raw::from_json(self.json.to_owned())
+    }
+}
+
+#[derive(Deserialize)]
+struct TransactionBody<'a> {
+    #[serde(borrow)]
+    events: Vec<&'a RawValue>,
+}
+
+/// Parse the body of a transaction sent by the homeserver, parsing only the headers of its
+/// events, see `LazyEvent`.
+///
+/// Returns an error if the body isn't a valid transaction. Events without a valid header are
+/// skipped.
+pub fn parse_transaction(body: &[u8]) -> Result<Vec<LazyEvent<'_>>, serde_json::Error> {
+    let body: TransactionBody = serde_json::from_slice(body)?;
+    Ok(body
+        .events
+        .into_iter()
+        .filter_map(|json| LazyEvent::parse(json).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use ruma::events::AnyRoomEvent;
+
+    use crate::parse_transaction;
+
+    #[test]
+    fn test_parse_transaction() {
+        let body = r#"{
+            "events": [
+                {
+                    "type": "m.room.message",
+                    "event_id": "$message:example.org",
+                    "room_id": "!room:example.org",
+                    "sender": "@alice:example.org",
+                    "origin_server_ts": 0,
+                    "content": { "msgtype": "m.text", "body": "Hi" }
+                },
+                {
+                    "type": "m.room.name",
+                    "event_id": "$name:example.org",
+                    "room_id": "!room:example.org",
+                    "sender": "\u0040bob:example.org",
+                    "state_key": "",
+                    "origin_server_ts": 0,
+                    "content": { "name": "Room" }
+                },
+                { "type": "m.presence", "content": {} }
+            ]
+        }"#
+        .as_bytes();
+        let events = parse_transaction(body).unwrap();
+        assert_eq!(events.len(), 2);
+
+        assert_eq!(events[0].event_type(), "m.room.message");
+        assert_eq!(events[0].room_id(), "!room:example.org");
+        assert_eq!(events[0].sender(), "@alice:example.org");
+        assert_eq!(events[0].event_id(), "$message:example.org");
+        assert!(matches!(events[0].header().room_id, Cow::Borrowed(_)));
+        assert_eq!(events[1].header().state_key.as_deref(), Some(""));
+        assert_eq!(events[1].sender(), "@bob:example.org");
+        assert!(matches!(events[1].header().sender, Cow::Owned(_)));
+
+        let event: AnyRoomEvent = events[0].deserialize().unwrap();
+        assert_eq!(event.event_id().as_str(), "$message:example.org");
+        let event = events[1].to_raw().deserialize().unwrap();
+        assert_eq!(event.event_id().as_str(), "$name:example.org");
+
+        assert!(parse_transaction(b"{\"events\": 1}").is_err());
+        assert!(parse_transaction(b"not json").is_err());
+    }
+}
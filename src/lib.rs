@@ -1,16 +1,134 @@
+mod accountdata;
 mod appservice;
+mod attachments;
+mod avatars;
+mod backfill;
+mod bot;
+mod bridgestate;
+mod bulkjoin;
+mod capabilities;
+mod circuitbreaker;
+mod client;
+mod commands;
+mod concurrentdict;
+mod customstate;
+mod delivery;
+mod displayname;
+mod doublepuppet;
+mod echo;
+mod error;
+mod filter;
+mod intent;
+mod invites;
+mod join;
+mod lazyevent;
 mod mappingdict;
 mod matrix;
+mod mediacache;
+mod members;
+mod membershipsync;
+mod messages;
+mod multidict;
+mod portal;
+mod portalconfig;
+mod powerlevels;
+mod presence;
+mod puppet;
+mod ratelimit;
+mod receipts;
+mod reconcile;
+mod relay;
+mod replay;
 mod request;
+mod requestlog;
+mod sendqueue;
+mod service;
+mod spaces;
+mod stickers;
+mod store;
+mod typing;
+mod urlpreview;
 mod util;
 
+#[cfg(test)]
+mod testing;
+
+pub mod api;
 #[cfg(feature = "convert")]
 pub mod convert;
 
 pub use appservice::*;
+pub use attachments::*;
+pub use avatars::*;
+pub use backfill::*;
+pub use bot::*;
+pub use bridgestate::*;
+pub use bulkjoin::*;
+pub use capabilities::*;
+pub use circuitbreaker::*;
+pub use client::*;
+pub use commands::*;
+pub use concurrentdict::*;
+pub use customstate::*;
+pub use delivery::*;
+pub use displayname::*;
+pub use doublepuppet::*;
+pub use echo::*;
+pub use error::*;
+pub use filter::*;
+pub use intent::*;
+pub use invites::*;
+pub use join::*;
+pub use lazyevent::*;
 pub use mappingdict::*;
 pub use matrix::*;
+pub use mediacache::*;
+pub use members::*;
+pub use membershipsync::*;
+pub use messages::*;
+pub use multidict::*;
+pub use portal::*;
+pub use portalconfig::*;
+pub use powerlevels::*;
+pub use presence::*;
+pub use puppet::*;
+pub use ratelimit::*;
+pub use receipts::*;
+pub use reconcile::*;
+pub use relay::*;
+pub use replay::*;
 pub use request::RequestBuilder;
+pub use requestlog::*;
+pub use sendqueue::*;
+pub use service::*;
+pub use spaces::*;
+pub use stickers::*;
+pub use store::*;
+pub use typing::*;
+pub use urlpreview::*;
+
+#[cfg(feature = "store")]
+mod migrations;
+#[cfg(feature = "store")]
+mod sqlite;
+#[cfg(feature = "store")]
+pub use migrations::*;
+#[cfg(feature = "store")]
+pub use sqlite::*;
+
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "metrics")]
+pub use metrics::*;
+
+#[cfg(feature = "client")]
+mod httpclient;
+#[cfg(feature = "client")]
+mod proxy;
+#[cfg(feature = "client")]
+pub use httpclient::*;
+#[cfg(feature = "client")]
+pub use proxy::*;
 
 #[cfg(feature = "serve")]
 mod server;
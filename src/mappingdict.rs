@@ -1,6 +1,68 @@
 use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::fs::{self, File};
+use std::future::Future;
 use std::hash::Hash;
+use std::io::{self, BufWriter, Write};
+use std::iter::FromIterator;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ruma::identifiers::{EventId, RoomAliasId, RoomId, UserId};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{Counters, MappingStats};
+
+/// The version of the file format written by `MappingDict::save_to`.
+const FORMAT_VERSION: u64 = 1;
+
+/// An error from saving or loading a `MappingDict`.
+#[derive(Debug, Error)]
+pub enum PersistError {
+    /// There was an error reading or writing the file.
+    #[error("reading or writing the file failed: {0}")]
+    Io(io::Error),
+    /// The file does not contain a valid saved `MappingDict`.
+    #[error("invalid file format: {0}")]
+    Format(serde_json::Error),
+    /// The file was written using an unsupported version of the format.
+    #[error("unsupported file format version {0}")]
+    UnsupportedVersion(u64),
+}
+
+impl From<io::Error> for PersistError {
+    fn from(err: io::Error) -> Self {
+        PersistError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for PersistError {
+    fn from(err: serde_json::Error) -> Self {
+        PersistError::Format(err)
+    }
+}
+
+#[derive(Serialize)]
+struct SavedRef<'a, V> {
+    version: u64,
+    items: Vec<&'a V>,
+}
+
+#[derive(Deserialize)]
+struct SavedVersion {
+    version: u64,
+}
+
+#[derive(Deserialize)]
+struct Saved<V> {
+    items: Vec<V>,
+}
 
 /// An ID being either a Matrix ID or an external ID for one object.
 #[derive(Debug, PartialEq, Eq, Hash)]
@@ -20,6 +82,21 @@ impl<'a, E: ?Sized, M: ?Sized> Clone for MappingId<'a, E, M> {
     }
 }
 
+macro_rules! matrix_mapping_id_from {
+    ($($id:ty),*) => {
+        $(
+            /// Matrix identifiers are always the Matrix ID of an object.
+            impl<'a, E: ?Sized> From<&'a $id> for MappingId<'a, E, $id> {
+                fn from(id: &'a $id) -> Self {
+                    MappingId::Matrix(id)
+                }
+            }
+        )*
+    };
+}
+
+matrix_mapping_id_from!(UserId, RoomId, RoomAliasId, EventId);
+
 /// Represents an object that has both a Matrix ID and an external ID.
 pub trait Mappable {
     type MatrixReference: ?Sized + Eq + Hash + ToOwned<Owned = Self::MatrixType>;
@@ -40,12 +117,269 @@ pub trait Mappable {
     fn into_split(self) -> (Self::MatrixType, Self::ExternalType);
 }
 
+/// A `Mappable` object of which the IDs can be changed, for example when a room is upgraded or an
+/// external channel is renamed.
+pub trait MappableMut: Mappable {
+    /// Set the Matrix ID of this object.
+    fn set_matrix(&mut self, id: Self::MatrixType);
+    /// Set the external ID of this object.
+    fn set_external(&mut self, id: Self::ExternalType);
+}
+
+/// An error from changing the ID of an item in a `MappingDict`.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum KeyUpdateError {
+    /// There is no item with the old ID.
+    #[error("no item with the old ID")]
+    NotFound,
+    /// Another item already has the new ID.
+    #[error("another item already has the new ID")]
+    Occupied,
+}
+
+/// An owned ID, either the Matrix ID or the external ID of an item.
+enum OwnedMappingId<V: Mappable> {
+    External(V::ExternalType),
+    Matrix(V::MatrixType),
+}
+
+/// A view into a single item of a `MappingDict`, which may or may not exist.
+/// This is constructed using `MappingDict::entry`.
+pub enum Entry<'a, V: Mappable> {
+    /// An item associated with the ID exists.
+    Occupied(OccupiedEntry<'a, V>),
+    /// No item is associated with the ID.
+    Vacant(VacantEntry<'a, V>),
+}
+
+/// A view into an existing item of a `MappingDict`.
+pub struct OccupiedEntry<'a, V: Mappable> {
+    dict: &'a mut MappingDict<V>,
+    index: usize,
+}
+
+/// A view into a missing item of a `MappingDict`.
+pub struct VacantEntry<'a, V: Mappable> {
+    dict: &'a mut MappingDict<V>,
+    id: OwnedMappingId<V>,
+}
+
+impl<'a, V: Mappable> Entry<'a, V> {
+    /// Returns a mutable reference to the item of this entry, inserting `item` if there is none.
+    ///
+    /// # Panics
+    /// Panics if `item` doesn't have the ID this entry was created with.
+    pub fn or_insert(self, item: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(item),
+        }
+    }
+
+    /// Returns a mutable reference to the item of this entry, inserting the result of `default`
+    /// if there is none.
+    ///
+    /// # Panics
+    /// Panics if the created item doesn't have the ID this entry was created with.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Run `f` on the item of this entry if it exists.
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Entry::Occupied(entry) = &mut self {
+            f(entry.get_mut());
+        }
+        self
+    }
+}
+
+impl<'a, V: Mappable> OccupiedEntry<'a, V> {
+    /// Returns a reference to the item of this entry.
+    pub fn get(&self) -> &V {
+        &self.dict.items[self.index]
+    }
+
+    /// Returns a mutable reference to the item of this entry.
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.dict.items[self.index]
+    }
+
+    /// Convert this entry into a mutable reference to its item, bound to the lifetime of the
+    /// `MappingDict`.
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.dict.items[self.index]
+    }
+
+    /// Remove the item of this entry from the `MappingDict`, returning it.
+    pub fn remove(self) -> V {
+        let matrix = self.dict.items[self.index].as_matrix().to_owned();
+        self.dict
+            .remove(MappingId::Matrix(matrix.borrow()))
+            .expect("occupied entry should have an item")
+    }
+}
+
+impl<'a, V: Mappable> VacantEntry<'a, V> {
+    /// Insert `item` into the `MappingDict`, returning a mutable reference to it.
+    ///
+    /// # Panics
+    /// Panics if `item` doesn't have the ID this entry was created with.
+    pub fn insert(self, item: V) -> &'a mut V {
+        let matches = match &self.id {
+            OwnedMappingId::External(e) => item.as_external() == e.borrow(),
+            OwnedMappingId::Matrix(m) => item.as_matrix() == m.borrow(),
+        };
+        assert!(matches, "inserted item should have the ID of the entry");
+
+        self.dict.insert(item)
+    }
+}
+
+/// A logical timestamp of the last access of an item, which can be updated through a shared
+/// reference.
+#[derive(Debug, Default)]
+struct AccessTime(AtomicU64);
+
+impl AccessTime {
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, time: u64) {
+        self.0.store(time, Ordering::Relaxed)
+    }
+
+    /// Increment this time, returning the previous value.
+    fn tick(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl Clone for AccessTime {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.get()))
+    }
+}
+
+type Hook<V> = Box<dyn FnMut(&V) + Send + Sync>;
+
+/// The callbacks observing changes of a `MappingDict`.
+struct Hooks<V> {
+    on_insert: Vec<Hook<V>>,
+    on_remove: Vec<Hook<V>>,
+}
+
+impl<V> Hooks<V> {
+    fn new() -> Self {
+        Self {
+            on_insert: vec![],
+            on_remove: vec![],
+        }
+    }
+
+    fn inserted(&mut self, item: &V) {
+        for hook in &mut self.on_insert {
+            hook(item);
+        }
+    }
+
+    fn removed(&mut self, item: &V) {
+        for hook in &mut self.on_remove {
+            hook(item);
+        }
+    }
+}
+
+/// The hooks belong to the original `MappingDict`, so a clone starts without any.
+impl<V> Clone for Hooks<V> {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl<V> std::fmt::Debug for Hooks<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_insert", &self.on_insert.len())
+            .field("on_remove", &self.on_remove.len())
+            .finish()
+    }
+}
+
+/// The differences between two `MappingDict`s, as returned by `MappingDict::diff`.
+/// Items are matched by their Matrix ID.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Diff<'a, V> {
+    /// The items that are only in the new `MappingDict`.
+    pub added: Vec<&'a V>,
+    /// The items that are only in the old `MappingDict`.
+    pub removed: Vec<&'a V>,
+    /// The items that are in both, but are not equal, as pairs of the old and new item.
+    pub changed: Vec<(&'a V, &'a V)>,
+}
+
+impl<'a, V> Diff<'a, V> {
+    /// Returns whether there are no differences.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// An immutable copy of a `MappingDict` at some point in time, as returned by
+/// `MappingDict::snapshot`.
+/// Clones of a `Snapshot` share the same items, so it can be cheaply passed around.
+pub struct Snapshot<V: Mappable>(Arc<MappingDict<V>>);
+
+impl<V: Mappable> std::fmt::Debug for Snapshot<V>
+where
+    MappingDict<V>: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Snapshot").field(&self.0).finish()
+    }
+}
+
+impl<V: Mappable> Clone for Snapshot<V> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<V: Mappable> Deref for Snapshot<V> {
+    type Target = MappingDict<V>;
+
+    fn deref(&self) -> &MappingDict<V> {
+        &self.0
+    }
+}
+
 /// A map comparable to a `HashMap` which contains items that are `Mappable`.
 /// The map keeps track of the mapping between both the external type and Matrix type and an
 /// object.
+///
+/// Items can be given a time to live using `insert_with_ttl`, after which they are treated as if
+/// they were removed. Expired items are freed by `evict_expired`, or when they are accessed
+/// mutably. The times to live are not saved when serializing the `MappingDict`.
+///
+/// A `MappingDict` created using `with_capacity_lru` is bounded, and evicts the least recently
+/// accessed item when it's full.
 #[derive(Debug, Clone)]
 pub struct MappingDict<V: Mappable> {
     items: Vec<V>,
+    /// When the item at the same index expires, if ever.
+    expires: Vec<Option<Instant>>,
+    /// When the item at the same index was last accessed.
+    accessed: Vec<AccessTime>,
+    clock: AccessTime,
+    /// The maximum amount of items, if the `MappingDict` is bounded.
+    capacity: Option<usize>,
+    hooks: Hooks<V>,
+    #[cfg(feature = "metrics")]
+    counters: Counters,
     external_to_index: HashMap<V::ExternalType, usize>,
     matrix_to_index: HashMap<V::MatrixType, usize>,
 }
@@ -58,11 +392,46 @@ where
     pub fn new() -> Self {
         Self {
             items: vec![],
+            expires: vec![],
+            accessed: vec![],
+            clock: AccessTime::default(),
+            capacity: None,
+            hooks: Hooks::new(),
+            #[cfg(feature = "metrics")]
+            counters: Counters::default(),
             external_to_index: HashMap::new(),
             matrix_to_index: HashMap::new(),
         }
     }
 
+    /// Create a new empty `MappingDict` holding at most `capacity` items.
+    /// When the `MappingDict` is full, inserting an item evicts the least recently accessed item,
+    /// preferring expired items. Use `insert_evicting` to get the evicted item.
+    ///
+    /// Finding the item to evict takes time linear in `capacity`.
+    ///
+    /// # Panics
+    /// Panics if `capacity` is zero.
+    pub fn with_capacity_lru(capacity: usize) -> Self {
+        assert!(
+            capacity > 0,
+            "a MappingDict needs room for at least one item"
+        );
+
+        Self {
+            items: Vec::with_capacity(capacity),
+            expires: Vec::with_capacity(capacity),
+            accessed: Vec::with_capacity(capacity),
+            clock: AccessTime::default(),
+            capacity: Some(capacity),
+            hooks: Hooks::new(),
+            #[cfg(feature = "metrics")]
+            counters: Counters::default(),
+            external_to_index: HashMap::with_capacity(capacity),
+            matrix_to_index: HashMap::with_capacity(capacity),
+        }
+    }
+
     /// Create a new `MappingDict` consuming the given `Vec` of items.
     /// All items are put into the newly created map.
     ///
@@ -72,6 +441,13 @@ where
     pub fn from_vec(items: Vec<V>) -> Self {
         let mut res = Self {
             items: Vec::with_capacity(items.len()),
+            expires: Vec::with_capacity(items.len()),
+            accessed: Vec::with_capacity(items.len()),
+            clock: AccessTime::default(),
+            capacity: None,
+            hooks: Hooks::new(),
+            #[cfg(feature = "metrics")]
+            counters: Counters::default(),
             matrix_to_index: HashMap::with_capacity(items.len()),
             external_to_index: HashMap::with_capacity(items.len()),
         };
@@ -83,11 +459,58 @@ where
         res
     }
 
-    /// Inserts the given `item` in the current `MappingDict`.
+    /// Inserts the given `item` in the current `MappingDict`, replacing the items with the same
+    /// Matrix ID or external ID.
     /// Allocates if neccesary.
     ///
     /// Returns a mutable reference to the newly inserted item.
     pub fn insert(&mut self, item: V) -> &mut V {
+        self.insert_expiring(item, None).0
+    }
+
+    /// Inserts the given `item` in the current `MappingDict`, which expires after `ttl`.
+    ///
+    /// Returns a mutable reference to the newly inserted item.
+    pub fn insert_with_ttl(&mut self, item: V, ttl: Duration) -> &mut V {
+        self.insert_expiring(item, Some(Instant::now() + ttl)).0
+    }
+
+    /// Inserts the given `item` in the current `MappingDict`, like `insert`.
+    ///
+    /// Returns a mutable reference to the newly inserted item, and the item that was evicted to
+    /// make room for it if this `MappingDict` was created using `with_capacity_lru` and is full.
+    pub fn insert_evicting(&mut self, item: V) -> (&mut V, Option<V>) {
+        self.insert_expiring(item, None)
+    }
+
+    fn insert_expiring(&mut self, item: V, expires: Option<Instant>) -> (&mut V, Option<V>) {
+        // replace the items with the same IDs, removing the one at the highest index first so
+        // the other one isn't moved.
+        let mut replaced = [
+            self.raw_index(MappingId::Matrix(item.as_matrix())),
+            self.raw_index(MappingId::External(item.as_external())),
+        ];
+        replaced.sort_unstable_by(|a, b| b.cmp(a));
+        if replaced[0] == replaced[1] {
+            replaced[1] = None;
+        }
+        for index in replaced.iter().flatten() {
+            self.remove_index(*index);
+        }
+
+        let evicted = match self.capacity {
+            Some(capacity) if self.items.len() >= capacity => {
+                let now = Instant::now();
+                let index = (0..self.items.len())
+                    .min_by_key(|&i| (!self.is_expired(i, now), self.accessed[i].get()))
+                    .expect("a full MappingDict should contain items");
+                #[cfg(feature = "metrics")]
+                self.counters.evicted(1);
+                Some(self.remove_index(index))
+            }
+            _ => None,
+        };
+
         let index = self.items.len();
 
         self.matrix_to_index
@@ -95,8 +518,97 @@ where
         self.external_to_index
             .insert(item.as_external().to_owned(), index);
         self.items.push(item);
+        self.expires.push(expires);
+        self.accessed
+            .push(AccessTime(AtomicU64::new(self.clock.tick())));
+        self.hooks.inserted(&self.items[index]);
+
+        (&mut self.items[index], evicted)
+    }
+
+    /// Call `f` with every item inserted into this `MappingDict` from now on.
+    ///
+    /// Changing the ID of an item using `update_matrix_id` or `update_external_id` is reported
+    /// as removing the old item and inserting the new one. Changes made through mutable
+    /// references to items are not reported. Hooks are not cloned along with the `MappingDict`.
+    pub fn on_insert<F: FnMut(&V) + Send + Sync + 'static>(&mut self, f: F) {
+        self.hooks.on_insert.push(Box::new(f));
+    }
+
+    /// Call `f` with every item removed from this `MappingDict` from now on, including expired
+    /// and evicted items.
+    ///
+    /// See `on_insert` for which changes are reported.
+    pub fn on_remove<F: FnMut(&V) + Send + Sync + 'static>(&mut self, f: F) {
+        self.hooks.on_remove.push(Box::new(f));
+    }
+
+    /// Set the time to live of the item associated with the given `identifier` to `ttl`, or
+    /// make it never expire if `ttl` is `None`.
+    ///
+    /// Returns whether the item exists.
+    pub fn set_ttl(
+        &mut self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+        ttl: Option<Duration>,
+    ) -> bool {
+        match self.live_index(identifier) {
+            None => false,
+            Some(index) => {
+                self.expires[index] = ttl.map(|ttl| Instant::now() + ttl);
+                true
+            }
+        }
+    }
 
-        &mut self.items[index]
+    fn is_expired(&self, index: usize, now: Instant) -> bool {
+        matches!(self.expires[index], Some(expires) if expires <= now)
+    }
+
+    /// Returns the index of the item associated with the given `identifier`, including expired
+    /// items.
+    fn raw_index(
+        &self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+    ) -> Option<usize> {
+        match identifier {
+            MappingId::Matrix(m) => self.matrix_to_index.get(m).copied(),
+            MappingId::External(e) => self.external_to_index.get(e).copied(),
+        }
+    }
+
+    /// Returns the index of the item associated with the given `identifier`, if it isn't
+    /// expired.
+    fn index(
+        &self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+    ) -> Option<usize> {
+        let index = self.raw_index(identifier).filter(|&index| {
+            self.expires[index].is_none() || !self.is_expired(index, Instant::now())
+        });
+        #[cfg(feature = "metrics")]
+        self.counters.lookup(index.is_some());
+
+        let index = index?;
+        self.accessed[index].set(self.clock.tick());
+        Some(index)
+    }
+
+    /// Returns the index of the item associated with the given `identifier`, removing the item
+    /// if it's expired.
+    fn live_index(
+        &mut self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+    ) -> Option<usize> {
+        let index = self.raw_index(identifier.clone())?;
+        if self.index(identifier).is_none() {
+            self.remove_index(index);
+            #[cfg(feature = "metrics")]
+            self.counters.evicted(1);
+            None
+        } else {
+            Some(index)
+        }
     }
 
     /// Returns a reference to the item associated with the given `identifier`, or `None` if no
@@ -105,15 +617,7 @@ where
         &self,
         identifier: MappingId<V::ExternalReference, V::MatrixReference>,
     ) -> Option<&V> {
-        let index = match identifier {
-            MappingId::Matrix(m) => self.matrix_to_index.get(m),
-            MappingId::External(e) => self.external_to_index.get(e),
-        };
-
-        match index {
-            None => None,
-            Some(i) => self.items.get(*i),
-        }
+        self.index(identifier).map(|i| &self.items[i])
     }
 
     /// Returns a mutable reference to the item associated with the given `identifier`, or `None`
@@ -122,67 +626,438 @@ where
         &mut self,
         identifier: MappingId<V::ExternalReference, V::MatrixReference>,
     ) -> Option<&mut V> {
-        let index = match identifier {
-            MappingId::Matrix(m) => self.matrix_to_index.get(m),
-            MappingId::External(e) => self.external_to_index.get(e),
-        };
+        let index = self.live_index(identifier)?;
+        Some(&mut self.items[index])
+    }
 
-        match index {
-            None => None,
-            Some(i) => self.items.get_mut(*i),
-        }
+    /// Returns a reference to the item with the given Matrix ID.
+    pub fn get_by_matrix(&self, id: &V::MatrixReference) -> Option<&V> {
+        self.get(MappingId::Matrix(id))
+    }
+
+    /// Returns a reference to the item with the given external ID.
+    pub fn get_by_external(&self, id: &V::ExternalReference) -> Option<&V> {
+        self.get(MappingId::External(id))
+    }
+
+    /// Returns a mutable reference to the item with the given Matrix ID.
+    pub fn get_by_matrix_mut(&mut self, id: &V::MatrixReference) -> Option<&mut V> {
+        self.get_mut(MappingId::Matrix(id))
+    }
+
+    /// Returns a mutable reference to the item with the given external ID.
+    pub fn get_by_external_mut(&mut self, id: &V::ExternalReference) -> Option<&mut V> {
+        self.get_mut(MappingId::External(id))
     }
 
     /// Returns whether or not this `MappingDict` contains an item associated with the given
     /// `identifier`.
     pub fn has(&self, identifier: MappingId<V::ExternalReference, V::MatrixReference>) -> bool {
-        match identifier {
-            MappingId::Matrix(m) => self.matrix_to_index.contains_key(m),
-            MappingId::External(e) => self.external_to_index.contains_key(e),
+        self.index(identifier).is_some()
+    }
+
+    /// Change the Matrix ID of the item with the Matrix ID `old` to `new`, keeping the item and
+    /// its external ID.
+    ///
+    /// Returns a mutable reference to the updated item.
+    pub fn update_matrix_id(
+        &mut self,
+        old: &V::MatrixReference,
+        new: V::MatrixType,
+    ) -> Result<&mut V, KeyUpdateError>
+    where
+        V: MappableMut,
+    {
+        let mut index = self
+            .live_index(MappingId::Matrix(old))
+            .ok_or(KeyUpdateError::NotFound)?;
+        if new.borrow() != old {
+            if self.live_index(MappingId::Matrix(new.borrow())).is_some() {
+                return Err(KeyUpdateError::Occupied);
+            }
+            // removing an expired item with the new ID could have moved the item.
+            index = self.matrix_to_index[old];
+
+            self.hooks.removed(&self.items[index]);
+            self.matrix_to_index.remove(old);
+            self.items[index].set_matrix(new);
+            self.matrix_to_index
+                .insert(self.items[index].as_matrix().to_owned(), index);
+            self.hooks.inserted(&self.items[index]);
+        }
+
+        Ok(&mut self.items[index])
+    }
+
+    /// Change the external ID of the item with the external ID `old` to `new`, keeping the item
+    /// and its Matrix ID.
+    ///
+    /// Returns a mutable reference to the updated item.
+    pub fn update_external_id(
+        &mut self,
+        old: &V::ExternalReference,
+        new: V::ExternalType,
+    ) -> Result<&mut V, KeyUpdateError>
+    where
+        V: MappableMut,
+    {
+        let mut index = self
+            .live_index(MappingId::External(old))
+            .ok_or(KeyUpdateError::NotFound)?;
+        if new.borrow() != old {
+            if self.live_index(MappingId::External(new.borrow())).is_some() {
+                return Err(KeyUpdateError::Occupied);
+            }
+            // removing an expired item with the new ID could have moved the item.
+            index = self.external_to_index[old];
+
+            self.hooks.removed(&self.items[index]);
+            self.external_to_index.remove(old);
+            self.items[index].set_external(new);
+            self.external_to_index
+                .insert(self.items[index].as_external().to_owned(), index);
+            self.hooks.inserted(&self.items[index]);
+        }
+
+        Ok(&mut self.items[index])
+    }
+
+    /// Returns a mutable reference to the item associated with the given `identifier`, calling
+    /// `load` to get the item if it's not in this `MappingDict`.
+    /// A loaded item is inserted, so this `MappingDict` can be used as a cache in front of a
+    /// database.
+    ///
+    /// Returns `Ok(None)` if `load` doesn't find the item either, and the error of `load` if it
+    /// fails.
+    ///
+    /// # Panics
+    /// Panics if the loaded item doesn't have the given `identifier`.
+    pub fn get_or_load<E, F>(
+        &mut self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+        load: F,
+    ) -> Result<Option<&mut V>, E>
+    where
+        F: FnOnce(MappingId<V::ExternalReference, V::MatrixReference>) -> Result<Option<V>, E>,
+    {
+        if let Some(index) = self.live_index(identifier.clone()) {
+            return Ok(Some(&mut self.items[index]));
+        }
+
+        match load(identifier.clone())? {
+            None => Ok(None),
+            Some(item) => Ok(Some(self.insert_loaded(identifier, item))),
+        }
+    }
+
+    /// Like `get_or_load`, but using an asynchronous `load` function.
+    ///
+    /// # Panics
+    /// Panics if the loaded item doesn't have the given `identifier`.
+    pub async fn get_or_load_async<'a, E, F, Fut>(
+        &mut self,
+        identifier: MappingId<'a, V::ExternalReference, V::MatrixReference>,
+        load: F,
+    ) -> Result<Option<&mut V>, E>
+    where
+        F: FnOnce(MappingId<'a, V::ExternalReference, V::MatrixReference>) -> Fut,
+        Fut: Future<Output = Result<Option<V>, E>>,
+    {
+        if let Some(index) = self.live_index(identifier.clone()) {
+            return Ok(Some(&mut self.items[index]));
+        }
+
+        match load(identifier.clone()).await? {
+            None => Ok(None),
+            Some(item) => Ok(Some(self.insert_loaded(identifier, item))),
+        }
+    }
+
+    fn insert_loaded(
+        &mut self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+        item: V,
+    ) -> &mut V {
+        let matches = match identifier {
+            MappingId::Matrix(m) => item.as_matrix() == m,
+            MappingId::External(e) => item.as_external() == e,
+        };
+        assert!(matches, "loaded item should have the given ID");
+
+        self.insert(item)
+    }
+
+    /// Get the entry for the item associated with the given `identifier`, for in-place
+    /// manipulation or insertion.
+    pub fn entry(
+        &mut self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+    ) -> Entry<'_, V> {
+        match self.live_index(identifier.clone()) {
+            Some(index) => Entry::Occupied(OccupiedEntry { dict: self, index }),
+            None => Entry::Vacant(VacantEntry {
+                dict: self,
+                id: match identifier {
+                    MappingId::Matrix(m) => OwnedMappingId::Matrix(m.to_owned()),
+                    MappingId::External(e) => OwnedMappingId::External(e.to_owned()),
+                },
+            }),
         }
     }
 
     /// If this `MappingDict` contains an item associated with the given `identifier`, remove it
     /// and return the value that was contained in the `MappingDict`.
     /// If no such item exists, this function returns `None`.
+    ///
+    /// The last item takes the place of the removed item, so this changes the order of the items.
     pub fn remove(
         &mut self,
         identifier: MappingId<V::ExternalReference, V::MatrixReference>,
     ) -> Option<V> {
-        let index = match identifier {
-            MappingId::Matrix(m) => self.matrix_to_index.remove(m),
-            MappingId::External(e) => self.external_to_index.remove(e),
-        };
+        let index = self.live_index(identifier)?;
+        Some(self.remove_index(index))
+    }
 
-        if let Some(id) = index {
-            let item = self.items.remove(id);
+    /// Remove the item with the given Matrix ID, returning it.
+    pub fn remove_by_matrix(&mut self, id: &V::MatrixReference) -> Option<V> {
+        self.remove(MappingId::Matrix(id))
+    }
 
-            match identifier {
-                MappingId::Matrix(_) => self.external_to_index.remove(item.as_external()),
-                MappingId::External(_) => self.matrix_to_index.remove(item.as_matrix()),
-            };
+    /// Remove the item with the given external ID, returning it.
+    pub fn remove_by_external(&mut self, id: &V::ExternalReference) -> Option<V> {
+        self.remove(MappingId::External(id))
+    }
 
-            Some(item)
-        } else {
+    /// Remove all expired items, returning them.
+    pub fn evict_expired(&mut self) -> Vec<V> {
+        let now = Instant::now();
+        let evicted = self.remove_where(|dict, index| dict.is_expired(index, now));
+        #[cfg(feature = "metrics")]
+        self.counters.evicted(evicted.len());
+        evicted
+    }
+
+    /// Retain only the items for which `f` returns `true`, removing the others.
+    pub fn retain<F: FnMut(&V) -> bool>(&mut self, mut f: F) {
+        self.remove_where(|dict, index| !f(&dict.items[index]));
+    }
+
+    /// Remove all items from this `MappingDict`, returning them as an iterator.
+    /// The capacity is kept for reuse.
+    pub fn drain(&mut self) -> std::vec::Drain<'_, V> {
+        for item in &self.items {
+            self.hooks.removed(item);
+        }
+
+        self.matrix_to_index.clear();
+        self.external_to_index.clear();
+        self.expires.clear();
+        self.accessed.clear();
+        self.items.drain(..)
+    }
+
+    /// Remove all items for which `f` returns `true`, returning them.
+    fn remove_where<F: FnMut(&Self, usize) -> bool>(&mut self, mut f: F) -> Vec<V> {
+        let mut res = vec![];
+
+        let mut index = 0;
+        while index < self.items.len() {
+            if f(self, index) {
+                // the last item is moved to `index`, so check that next.
+                res.push(self.remove_index(index));
+            } else {
+                index += 1;
+            }
+        }
+
+        res
+    }
+
+    /// Remove the item at `index`, and the IDs pointing to it.
+    fn remove_index(&mut self, index: usize) -> V {
+        let item = self.items.swap_remove(index);
+        self.expires.swap_remove(index);
+        self.accessed.swap_remove(index);
+        self.hooks.removed(&item);
+
+        // the IDs may have been taken over by another item in the meantime.
+        if self.matrix_to_index.get(item.as_matrix()) == Some(&index) {
+            self.matrix_to_index.remove(item.as_matrix());
+        }
+        if self.external_to_index.get(item.as_external()) == Some(&index) {
+            self.external_to_index.remove(item.as_external());
+        }
+
+        // the last item has been moved into the place of the removed item, so point its IDs to
+        // the new position.
+        if let Some(moved) = self.items.get(index) {
+            let old_index = self.items.len();
+            if let Some(i) = self.matrix_to_index.get_mut(moved.as_matrix()) {
+                if *i == old_index {
+                    *i = index;
+                }
+            }
+            if let Some(i) = self.external_to_index.get_mut(moved.as_external()) {
+                if *i == old_index {
+                    *i = index;
+                }
+            }
+        }
+
+        item
+    }
+
+    /// Returns the item associated with `identifier` if it isn't expired, without counting as
+    /// an access.
+    fn peek(&self, identifier: MappingId<V::ExternalReference, V::MatrixReference>) -> Option<&V> {
+        let index = self.raw_index(identifier)?;
+        if self.is_expired(index, Instant::now()) {
             None
+        } else {
+            Some(&self.items[index])
+        }
+    }
+
+    /// Returns an iterator over the items that are not expired.
+    fn live_items(&self) -> impl Iterator<Item = &V> {
+        let now = Instant::now();
+        (0..self.items.len())
+            .filter(move |&i| !self.is_expired(i, now))
+            .map(move |i| &self.items[i])
+    }
+
+    /// Create an immutable copy of the current items of this `MappingDict`, leaving out expired
+    /// items.
+    pub fn snapshot(&self) -> Snapshot<V>
+    where
+        V: Clone,
+    {
+        Snapshot(Arc::new(self.live_items().cloned().collect()))
+    }
+
+    /// Compare this `MappingDict` to the `other`, newer, `MappingDict`, for example to reconcile
+    /// the items with the actual state on the homeserver or the external network.
+    ///
+    /// Items are matched by their Matrix ID, items with the same Matrix ID that are not equal are
+    /// changed.
+    pub fn diff<'a>(&'a self, other: &'a MappingDict<V>) -> Diff<'a, V>
+    where
+        V: PartialEq,
+    {
+        let mut res = Diff {
+            added: vec![],
+            removed: vec![],
+            changed: vec![],
+        };
+
+        for item in self.live_items() {
+            match other.peek(MappingId::Matrix(item.as_matrix())) {
+                None => res.removed.push(item),
+                Some(new) if new != item => res.changed.push((item, new)),
+                Some(_) => {}
+            }
+        }
+        for item in other.live_items() {
+            if self.peek(MappingId::Matrix(item.as_matrix())).is_none() {
+                res.added.push(item);
+            }
         }
+
+        res
     }
 
     /// Get an iterator over references of the items contained in this `MappingDict`.
+    /// This includes expired items that haven't been evicted yet.
     pub fn iter(&'_ self) -> std::slice::Iter<'_, V> {
         self.items.iter()
     }
 
     /// Get an iterator over mutable references of the items contained in this `MappingDict`.
+    /// This includes expired items that haven't been evicted yet.
     pub fn iter_mut(&'_ mut self) -> std::slice::IterMut<'_, V> {
         self.items.iter_mut()
     }
 
+    /// Save the items of this `MappingDict` to the file at `path` as JSON.
+    ///
+    /// Expired items are left out. The time to live of the other items isn't saved, so they
+    /// don't expire after loading.
+    ///
+    /// The items are written to a temporary file first, which then replaces the file at `path`,
+    /// so the file is never left half-written.
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<(), PersistError>
+    where
+        V: Serialize,
+    {
+        let path = path.as_ref();
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+
+        let file = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(
+            &mut writer,
+            &SavedRef {
+                version: FORMAT_VERSION,
+                items: self.live_items().collect(),
+            },
+        )?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Load a `MappingDict` from the file at `path`, as written by `save_to`.
+    pub fn load_from<P: AsRef<Path>>(path: P) -> Result<Self, PersistError>
+    where
+        V: DeserializeOwned,
+    {
+        let data = fs::read(path)?;
+
+        let SavedVersion { version } = serde_json::from_slice(&data)?;
+        if version != FORMAT_VERSION {
+            return Err(PersistError::UnsupportedVersion(version));
+        }
+
+        let Saved { items } = serde_json::from_slice(&data)?;
+        Ok(Self::from_vec(items))
+    }
+
+    /// Get the statistics of this `MappingDict`.
+    ///
+    /// Every lookup of an item by its ID counts as a hit or a miss, including the lookups done
+    /// by methods like `entry` and `remove`. The memory estimate includes the items and the
+    /// indexes by ID, but not memory allocated by the items themselves, like the contents of
+    /// strings.
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self) -> MappingStats {
+        let index_entry = |key: usize| key + std::mem::size_of::<usize>();
+        let memory = self.items.capacity() * std::mem::size_of::<V>()
+            + self.expires.capacity() * std::mem::size_of::<Option<Instant>>()
+            + self.accessed.capacity() * std::mem::size_of::<AccessTime>()
+            + self.matrix_to_index.capacity() * index_entry(std::mem::size_of::<V::MatrixType>())
+            + self.external_to_index.capacity()
+                * index_entry(std::mem::size_of::<V::ExternalType>());
+
+        MappingStats {
+            len: self.items.len(),
+            capacity: self.capacity,
+            memory: Some(memory),
+            ..self.counters.stats()
+        }
+    }
+
     /// Shrinks the capacity of the map as much as possible. It will drop down as much as possible
     /// while maintaining the internal rules and possibly leaving some space in accordance with the
     /// resize policy.
     pub fn shrink_to_fit(&mut self) {
         self.items.shrink_to_fit();
+        self.expires.shrink_to_fit();
+        self.accessed.shrink_to_fit();
         self.matrix_to_index.shrink_to_fit();
         self.external_to_index.shrink_to_fit();
     }
@@ -194,6 +1069,48 @@ impl<T: Mappable> Default for MappingDict<T> {
     }
 }
 
+impl<V: Mappable> Extend<V> for MappingDict<V> {
+    fn extend<I: IntoIterator<Item = V>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        let (lower, _) = iter.size_hint();
+        self.items.reserve(lower);
+        self.expires.reserve(lower);
+        self.accessed.reserve(lower);
+        self.matrix_to_index.reserve(lower);
+        self.external_to_index.reserve(lower);
+
+        for item in iter {
+            self.insert(item);
+        }
+    }
+}
+
+impl<V: Mappable> FromIterator<V> for MappingDict<V> {
+    fn from_iter<I: IntoIterator<Item = V>>(iter: I) -> Self {
+        let mut res = Self::new();
+        res.extend(iter);
+        res
+    }
+}
+
+impl<V> Serialize for MappingDict<V>
+where
+    V: Mappable + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.live_items())
+    }
+}
+
+impl<'de, V> Deserialize<'de> for MappingDict<V>
+where
+    V: Mappable + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self::from_vec(Vec::deserialize(deserializer)?))
+    }
+}
+
 impl<'a, T> IntoIterator for &'a MappingDict<T>
 where
     T: Mappable,
@@ -217,3 +1134,511 @@ where
         self.items.into_iter()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        Diff, Entry, KeyUpdateError, Mappable, MappableMut, MappingDict, MappingId, PersistError,
+    };
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    struct Item {
+        matrix: String,
+        external: u64,
+    }
+
+    impl Item {
+        fn new(matrix: &str, external: u64) -> Self {
+            Self {
+                matrix: matrix.to_string(),
+                external,
+            }
+        }
+    }
+
+    impl Mappable for Item {
+        type MatrixReference = str;
+        type MatrixType = String;
+        type ExternalReference = u64;
+        type ExternalType = u64;
+
+        fn as_matrix(&self) -> &str {
+            &self.matrix
+        }
+        fn into_matrix(self) -> String {
+            self.matrix
+        }
+        fn as_external(&self) -> &u64 {
+            &self.external
+        }
+        fn into_external(self) -> u64 {
+            self.external
+        }
+        fn into_split(self) -> (String, u64) {
+            (self.matrix, self.external)
+        }
+    }
+
+    impl MappableMut for Item {
+        fn set_matrix(&mut self, id: String) {
+            self.matrix = id;
+        }
+        fn set_external(&mut self, id: u64) {
+            self.external = id;
+        }
+    }
+
+    #[test]
+    fn test_serde() {
+        let dict = MappingDict::from_vec(vec![Item::new("!a", 1), Item::new("!b", 2)]);
+
+        let json = serde_json::to_string(&dict).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"matrix":"!a","external":1},{"matrix":"!b","external":2}]"#
+        );
+
+        let dict: MappingDict<Item> = serde_json::from_str(&json).unwrap();
+        assert_eq!(dict.get(MappingId::External(&2)), Some(&Item::new("!b", 2)));
+        assert_eq!(dict.get(MappingId::Matrix("!a")), Some(&Item::new("!a", 1)));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = std::env::temp_dir().join(format!("mappingdict-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("items.json");
+
+        let dict = MappingDict::from_vec(vec![Item::new("!a", 1), Item::new("!b", 2)]);
+        dict.save_to(&path).unwrap();
+        assert!(!dir.join("items.json.tmp").exists());
+
+        let loaded = MappingDict::<Item>::load_from(&path).unwrap();
+        assert_eq!(
+            loaded.iter().collect::<Vec<_>>(),
+            dict.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            loaded.get(MappingId::External(&1)),
+            Some(&Item::new("!a", 1))
+        );
+
+        // expired items aren't saved, so they don't come back without expiring.
+        let mut dict = MappingDict::new();
+        dict.insert(Item::new("!a", 1));
+        dict.insert_with_ttl(Item::new("!b", 2), std::time::Duration::from_secs(0));
+        dict.save_to(&path).unwrap();
+        let loaded = MappingDict::<Item>::load_from(&path).unwrap();
+        assert_eq!(loaded.iter().collect::<Vec<_>>(), vec![&Item::new("!a", 1)]);
+        assert_eq!(
+            serde_json::to_string(&dict).unwrap(),
+            r#"[{"matrix":"!a","external":1}]"#
+        );
+
+        std::fs::write(&path, r#"{"version":2,"items":[]}"#).unwrap();
+        assert!(matches!(
+            MappingDict::<Item>::load_from(&path),
+            Err(PersistError::UnsupportedVersion(2))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut dict = MappingDict::from_vec(vec![Item::new("!a", 1)]);
+
+        let item = dict
+            .entry(MappingId::External(&2))
+            .or_insert_with(|| Item::new("!b", 2));
+        assert_eq!(item, &mut Item::new("!b", 2));
+
+        let item = dict
+            .entry(MappingId::Matrix("!a"))
+            .or_insert_with(|| unreachable!());
+        assert_eq!(item, &mut Item::new("!a", 1));
+
+        let entry = dict
+            .entry(MappingId::External(&3))
+            .and_modify(|_| unreachable!());
+        assert!(matches!(entry, Entry::Vacant(_)));
+
+        match dict.entry(MappingId::External(&1)) {
+            Entry::Occupied(entry) => assert_eq!(entry.remove(), Item::new("!a", 1)),
+            Entry::Vacant(_) => panic!("item should exist"),
+        }
+        assert!(!dict.has(MappingId::Matrix("!a")));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_entry_wrong_id() {
+        let mut dict = MappingDict::new();
+        dict.entry(MappingId::External(&1))
+            .or_insert(Item::new("!a", 2));
+    }
+
+    #[test]
+    fn test_remove_keeps_indexes() {
+        let mut dict = MappingDict::from_vec(vec![
+            Item::new("!a", 1),
+            Item::new("!b", 2),
+            Item::new("!c", 3),
+        ]);
+
+        assert_eq!(
+            dict.remove(MappingId::Matrix("!a")),
+            Some(Item::new("!a", 1))
+        );
+        assert_eq!(dict.get(MappingId::Matrix("!b")), Some(&Item::new("!b", 2)));
+        assert_eq!(dict.get(MappingId::External(&3)), Some(&Item::new("!c", 3)));
+
+        assert_eq!(
+            dict.remove(MappingId::External(&3)),
+            Some(Item::new("!c", 3))
+        );
+        assert_eq!(dict.remove(MappingId::External(&3)), None);
+        assert_eq!(dict.get(MappingId::External(&2)), Some(&Item::new("!b", 2)));
+        assert_eq!(dict.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_duplicate_insert() {
+        let mut dict = MappingDict::new();
+        dict.insert(Item::new("!a", 1));
+        dict.insert(Item::new("!b", 2));
+        dict.insert(Item::new("!a", 1));
+        assert_eq!(dict.iter().count(), 2);
+        assert_eq!(dict.get(MappingId::External(&1)), Some(&Item::new("!a", 1)));
+
+        // an item sharing its IDs with two different items replaces both.
+        dict.insert(Item::new("!a", 2));
+        assert_eq!(dict.iter().collect::<Vec<_>>(), vec![&Item::new("!a", 2)]);
+        assert_eq!(dict.get(MappingId::Matrix("!a")), Some(&Item::new("!a", 2)));
+        assert_eq!(dict.get(MappingId::External(&2)), Some(&Item::new("!a", 2)));
+        assert_eq!(dict.get(MappingId::External(&1)), None);
+        assert_eq!(dict.get(MappingId::Matrix("!b")), None);
+        assert_eq!(
+            serde_json::to_string(&dict).unwrap(),
+            r#"[{"matrix":"!a","external":2}]"#
+        );
+
+        // replaced items don't take up capacity.
+        let mut dict = MappingDict::with_capacity_lru(2);
+        dict.insert(Item::new("!a", 1));
+        dict.insert(Item::new("!b", 2));
+        let (_, evicted) = dict.insert_evicting(Item::new("!b", 2));
+        assert_eq!(evicted, None);
+        assert!(dict.has(MappingId::Matrix("!a")));
+        assert_eq!(dict.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_interleaved_operations() {
+        use std::collections::HashMap;
+
+        let mut dict = MappingDict::new();
+        let mut expected: HashMap<u64, Item> = HashMap::new();
+
+        // a simple deterministic pseudo random sequence.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..2000 {
+            let external = next() % 64;
+            let item = Item::new(&format!("!{}", external), external);
+
+            match next() % 3 {
+                0 if !expected.contains_key(&external) => {
+                    dict.insert(item.clone());
+                    expected.insert(external, item);
+                }
+                1 => assert_eq!(
+                    dict.remove(MappingId::External(&external)),
+                    expected.remove(&external)
+                ),
+                2 => assert_eq!(
+                    dict.remove(MappingId::Matrix(&item.matrix)),
+                    expected.remove(&external)
+                ),
+                _ => {}
+            }
+
+            for (external, item) in &expected {
+                assert_eq!(dict.get(MappingId::External(external)), Some(item));
+                assert_eq!(dict.get(MappingId::Matrix(&item.matrix)), Some(item));
+            }
+            assert_eq!(dict.iter().count(), expected.len());
+        }
+    }
+
+    #[test]
+    fn test_update_ids() {
+        let mut dict = MappingDict::from_vec(vec![Item::new("!a", 1), Item::new("!b", 2)]);
+
+        let item = dict.update_matrix_id("!a", "!c".to_string()).unwrap();
+        assert_eq!(item, &mut Item::new("!c", 1));
+        assert!(!dict.has(MappingId::Matrix("!a")));
+        assert_eq!(dict.get(MappingId::External(&1)), Some(&Item::new("!c", 1)));
+
+        dict.update_external_id(&1, 3).unwrap();
+        assert!(!dict.has(MappingId::External(&1)));
+        assert_eq!(dict.get(MappingId::Matrix("!c")), Some(&Item::new("!c", 3)));
+
+        assert_eq!(
+            dict.update_matrix_id("!c", "!b".to_string()),
+            Err(KeyUpdateError::Occupied)
+        );
+        assert_eq!(
+            dict.update_external_id(&1, 4),
+            Err(KeyUpdateError::NotFound)
+        );
+        assert_eq!(dict.get(MappingId::External(&2)), Some(&Item::new("!b", 2)));
+    }
+
+    #[test]
+    fn test_ttl() {
+        use std::time::Duration;
+
+        let mut dict = MappingDict::new();
+        dict.insert(Item::new("!a", 1));
+        dict.insert_with_ttl(Item::new("!b", 2), Duration::from_secs(0));
+        dict.insert_with_ttl(Item::new("!c", 3), Duration::from_secs(3600));
+        dict.insert_with_ttl(Item::new("!d", 4), Duration::from_secs(0));
+
+        assert!(!dict.has(MappingId::Matrix("!b")));
+        assert_eq!(dict.get(MappingId::External(&2)), None);
+        assert_eq!(dict.get(MappingId::External(&3)), Some(&Item::new("!c", 3)));
+
+        // removes the item, but it's not returned since it expired.
+        assert_eq!(dict.remove(MappingId::External(&2)), None);
+        assert_eq!(dict.iter().count(), 3);
+
+        assert!(dict.set_ttl(MappingId::Matrix("!a"), Some(Duration::from_secs(0))));
+        assert!(dict.set_ttl(MappingId::Matrix("!c"), None));
+        assert!(!dict.set_ttl(MappingId::Matrix("!b"), None));
+
+        let mut evicted = dict.evict_expired();
+        evicted.sort_by_key(|item| item.external);
+        assert_eq!(evicted, vec![Item::new("!a", 1), Item::new("!d", 4)]);
+        assert_eq!(dict.iter().collect::<Vec<_>>(), vec![&Item::new("!c", 3)]);
+        assert_eq!(dict.get(MappingId::Matrix("!c")), Some(&Item::new("!c", 3)));
+    }
+
+    #[test]
+    fn test_lru() {
+        use std::time::Duration;
+
+        let mut dict = MappingDict::with_capacity_lru(3);
+        dict.insert(Item::new("!a", 1));
+        dict.insert(Item::new("!b", 2));
+        dict.insert(Item::new("!c", 3));
+
+        // "!b" is now the least recently accessed.
+        assert!(dict.get(MappingId::External(&1)).is_some());
+        let (_, evicted) = dict.insert_evicting(Item::new("!d", 4));
+        assert_eq!(evicted, Some(Item::new("!b", 2)));
+        assert!(!dict.has(MappingId::Matrix("!b")));
+
+        // expired items are evicted first.
+        dict.set_ttl(MappingId::Matrix("!d"), Some(Duration::from_secs(0)));
+        let (_, evicted) = dict.insert_evicting(Item::new("!e", 5));
+        assert_eq!(evicted, Some(Item::new("!d", 4)));
+
+        let (_, evicted) = dict.insert_evicting(Item::new("!f", 6));
+        assert_eq!(evicted, Some(Item::new("!c", 3)));
+        assert_eq!(dict.iter().count(), 3);
+        for (matrix, external) in &[("!a", 1), ("!e", 5), ("!f", 6)] {
+            assert_eq!(
+                dict.get(MappingId::External(external)),
+                Some(&Item::new(matrix, *external))
+            );
+        }
+
+        let (_, evicted) = MappingDict::new().insert_evicting(Item::new("!a", 1));
+        assert_eq!(evicted, None);
+    }
+
+    #[test]
+    fn test_bulk_operations() {
+        let mut dict: MappingDict<_> = (1..=4).map(|i| Item::new(&format!("!{}", i), i)).collect();
+        dict.extend(vec![Item::new("!5", 5), Item::new("!6", 6)]);
+        assert_eq!(dict.iter().count(), 6);
+
+        dict.retain(|item| item.external % 2 == 0);
+        assert_eq!(dict.iter().count(), 3);
+        for i in 1..=6 {
+            let expected = Item::new(&format!("!{}", i), i);
+            let expected = if i % 2 == 0 { Some(&expected) } else { None };
+            assert_eq!(dict.get(MappingId::External(&i)), expected);
+            assert_eq!(dict.get(MappingId::Matrix(&format!("!{}", i))), expected);
+        }
+
+        let mut drained: Vec<_> = dict.drain().map(|item| item.external).collect();
+        drained.sort_unstable();
+        assert_eq!(drained, vec![2, 4, 6]);
+        assert!(!dict.has(MappingId::External(&2)));
+        assert_eq!(dict.iter().count(), 0);
+
+        dict.insert(Item::new("!7", 7));
+        assert_eq!(dict.get(MappingId::Matrix("!7")), Some(&Item::new("!7", 7)));
+    }
+
+    #[test]
+    fn test_get_or_load() {
+        let mut dict = MappingDict::from_vec(vec![Item::new("!a", 1)]);
+        let mut loads = 0;
+
+        let mut load = |id: MappingId<u64, str>| {
+            loads += 1;
+            match id {
+                MappingId::External(&2) => Ok(Some(Item::new("!b", 2))),
+                MappingId::External(&3) => Err("database unavailable"),
+                _ => Ok(None),
+            }
+        };
+
+        let item = dict.get_or_load(MappingId::Matrix("!a"), &mut load);
+        assert_eq!(item, Ok(Some(&mut Item::new("!a", 1))));
+        let item = dict.get_or_load(MappingId::External(&2), &mut load);
+        assert_eq!(item, Ok(Some(&mut Item::new("!b", 2))));
+        let item = dict.get_or_load(MappingId::Matrix("!b"), &mut load);
+        assert_eq!(item, Ok(Some(&mut Item::new("!b", 2))));
+        assert_eq!(
+            dict.get_or_load(MappingId::External(&3), &mut load),
+            Err("database unavailable")
+        );
+        assert_eq!(
+            dict.get_or_load(MappingId::External(&4), &mut load),
+            Ok(None)
+        );
+
+        assert_eq!(loads, 3);
+        assert_eq!(dict.iter().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_or_load_async() {
+        let mut dict = MappingDict::new();
+
+        let item = dict
+            .get_or_load_async(MappingId::External(&1), |_| async {
+                Ok::<_, ()>(Some(Item::new("!a", 1)))
+            })
+            .await;
+        assert_eq!(item, Ok(Some(&mut Item::new("!a", 1))));
+
+        let item = dict
+            .get_or_load_async(MappingId::Matrix("!a"), |_| async { Err(()) })
+            .await;
+        assert_eq!(item, Ok(Some(&mut Item::new("!a", 1))));
+    }
+
+    #[test]
+    fn test_hooks() {
+        use std::sync::{Arc, Mutex};
+
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut dict = MappingDict::with_capacity_lru(2);
+
+        let inserted = log.clone();
+        dict.on_insert(move |item: &Item| {
+            inserted.lock().unwrap().push(format!("+{}", item.matrix))
+        });
+        let removed = log.clone();
+        dict.on_remove(move |item: &Item| {
+            removed.lock().unwrap().push(format!("-{}", item.matrix))
+        });
+
+        dict.insert(Item::new("!a", 1));
+        dict.insert(Item::new("!b", 2));
+        dict.insert(Item::new("!c", 3));
+        dict.remove(MappingId::External(&3));
+        dict.update_matrix_id("!b", "!d".to_string()).unwrap();
+        dict.drain();
+
+        // a clone doesn't share the hooks.
+        dict.clone().insert(Item::new("!e", 5));
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["+!a", "+!b", "-!a", "+!c", "-!c", "-!b", "+!d", "-!d"]
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_stats() {
+        use std::time::Duration;
+
+        let mut dict = MappingDict::with_capacity_lru(2);
+        dict.insert(Item::new("!a", 1));
+        dict.insert(Item::new("!b", 2));
+        assert!(dict.get_by_matrix("!a").is_some());
+        assert!(dict.get_by_external(&3).is_none());
+        // evicts !b, then !a, and then the expired !d.
+        dict.insert(Item::new("!c", 3));
+        dict.insert_with_ttl(Item::new("!d", 4), Duration::from_secs(0));
+        dict.evict_expired();
+
+        let stats = dict.stats();
+        assert_eq!((stats.len, stats.capacity), (1, Some(2)));
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 1, 3));
+        assert!(stats.memory.unwrap() >= 2 * std::mem::size_of::<Item>());
+        assert_eq!(dict.clone().stats().hits, 0);
+    }
+
+    #[test]
+    fn test_direct_lookups() {
+        use std::convert::TryFrom;
+
+        use ruma::identifiers::UserId;
+
+        let mut dict = MappingDict::from_vec(vec![Item::new("!a", 1), Item::new("!b", 2)]);
+        assert_eq!(dict.get_by_matrix("!a"), Some(&Item::new("!a", 1)));
+        assert_eq!(dict.get_by_external(&2), Some(&Item::new("!b", 2)));
+        assert_eq!(dict.get_by_external(&3), None);
+
+        assert_eq!(dict.get_by_external_mut(&1), Some(&mut Item::new("!a", 1)));
+        assert_eq!(dict.get_by_matrix_mut("!c"), None);
+
+        assert_eq!(dict.remove_by_matrix("!b"), Some(Item::new("!b", 2)));
+        assert_eq!(dict.remove_by_external(&2), None);
+        assert_eq!(dict.remove_by_external(&1), Some(Item::new("!a", 1)));
+
+        let user_id = UserId::try_from("@alice:example.com").unwrap();
+        let id: MappingId<str, UserId> = (&user_id).into();
+        assert_eq!(id, MappingId::Matrix(&user_id));
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let mut dict = MappingDict::from_vec(vec![Item::new("!a", 1), Item::new("!b", 2)]);
+        let snapshot = dict.snapshot();
+
+        dict.remove_by_matrix("!a");
+        dict.update_external_id(&2, 3).unwrap();
+        dict.insert(Item::new("!c", 4));
+
+        // the snapshot is not affected by the changes.
+        assert_eq!(snapshot.get_by_matrix("!a"), Some(&Item::new("!a", 1)));
+        assert_eq!(snapshot.clone().iter().count(), 2);
+
+        assert_eq!(
+            snapshot.diff(&dict),
+            Diff {
+                added: vec![&Item::new("!c", 4)],
+                removed: vec![&Item::new("!a", 1)],
+                changed: vec![(&Item::new("!b", 2), &Item::new("!b", 3))],
+            }
+        );
+        assert!(dict.diff(&dict.snapshot()).is_empty());
+    }
+}
@@ -1,5 +1,6 @@
 use ruma::api::exports::http::uri;
 use ruma::identifiers::{EventId, MxcUri, RoomId, UserId};
+use thiserror::Error;
 
 /// An item that can be represented using a matrix.to URL.
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -26,11 +27,13 @@ impl<'a> MatrixToItem<'a> {
 }
 
 /// An error from converting an MXC URI to a HTTP URL.
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum MxcConversionError {
     /// The given MXC URI is malformed.
+    #[error("malformed MXC URI")]
     InvalidMxc,
     /// There was an error parsing the resulting URL into an URI object.
+    #[error("invalid URL: {0}")]
     UriParseError(uri::InvalidUri),
 }
 
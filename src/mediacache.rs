@@ -0,0 +1,377 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use hyper::http;
+use ruma::identifiers::MxcUri;
+use ruma_client::HttpClient;
+use thiserror::Error;
+
+use crate::intent::{Intent, IntentError};
+use crate::mappingdict::{Mappable, MappingDict, MappingId};
+use crate::stickers::BridgedMedia;
+use crate::store::MappingStore;
+
+/// Media downloaded from a remote URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Download {
+    /// The content of the media.
+    pub data: Vec<u8>,
+    /// The MIME type of the media, if known.
+    pub content_type: Option<String>,
+    /// The entity tag of this version of the media, if known.
+    pub etag: Option<String>,
+}
+
+/// Downloads media of the external service to be uploaded to Matrix.
+#[async_trait]
+pub trait MediaDownloader: Send + Sync {
+    /// The error returned when a download fails.
+    type Error: Send;
+
+    /// Download the media at `url`.
+    async fn download(&self, url: &str) -> Result<Download, Self::Error>;
+
+    /// Download the media at `url` if it changed since the version with the entity tag `etag`
+    /// was downloaded, returning `None` if it didn't.
+    ///
+    /// The default implementation always downloads the media.
+    async fn download_if_changed(
+        &self,
+        url: &str,
+        etag: Option<&str>,
+    ) -> Result<Option<Download>, Self::Error> {
+        let _ = etag;
+        self.download(url).await.map(Some)
+    }
+}
+
+/// An error from an `HttpDownloader`.
+#[derive(Debug, Error)]
+pub enum DownloadError<E> {
+    /// The URL isn't a valid URL.
+    #[error("invalid URL: {0}")]
+    InvalidUrl(http::Error),
+    /// The request failed.
+    #[error("download failed: {0}")]
+    Http(E),
+    /// The server responded with the given non-success status code.
+    #[error("download failed with status {0}")]
+    Status(u16),
+}
+
+/// A `MediaDownloader` making plain GET requests using an `HttpClient`.
+pub struct HttpDownloader<C> {
+    client: C,
+}
+
+impl<C> HttpDownloader<C> {
+    /// Create a new `HttpDownloader` making requests using `client`.
+    pub fn new(client: C) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl<C: HttpClient + Send> MediaDownloader for HttpDownloader<C> {
+    type Error = DownloadError<C::Error>;
+
+    async fn download(&self, url: &str) -> Result<Download, Self::Error> {
+        let download = self.download_if_changed(url, None).await?;
+        Ok(download.expect("media without etag should be downloaded"))
+    }
+
+    async fn download_if_changed(
+        &self,
+        url: &str,
+        etag: Option<&str>,
+    ) -> Result<Option<Download>, Self::Error> {
+        let mut request = http::Request::get(url);
+        if let Some(etag) = etag {
+            request = request.header(http::header::IF_NONE_MATCH, etag);
+        }
+        let request = request
+            .body(C::RequestBody::default())
+            .map_err(DownloadError::InvalidUrl)?;
+        let response = self
+            .client
+            .send_http_request(request)
+            .await
+            .map_err(DownloadError::Http)?;
+        if etag.is_some() && response.status() == http::StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(DownloadError::Status(response.status().as_u16()));
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        Ok(Some(Download {
+            data: response.body().as_ref().to_vec(),
+            content_type: header(http::header::CONTENT_TYPE),
+            etag: header(http::header::ETAG),
+        }))
+    }
+}
+
+/// An error from a `MediaCache` or `AvatarSync`.
+#[derive(Debug, Error)]
+pub enum MediaCacheError<E, S, D> {
+    /// Uploading the media to the homeserver failed.
+    #[error("{0}")]
+    Intent(IntentError<E>),
+    /// Loading or saving the mapping failed.
+    #[error("loading or saving the media mapping failed: {0}")]
+    Store(S),
+    /// Downloading the media failed.
+    #[error("{0}")]
+    Download(D),
+    /// The media has the given size in bytes, which is larger than allowed.
+    #[error("media of {0} bytes is larger than allowed")]
+    TooLarge(u64),
+    /// The media has the given MIME type, which isn't allowed.
+    #[error("media of type {0} is not allowed")]
+    TypeNotAllowed(String),
+}
+
+impl<E, S, D> From<IntentError<E>> for MediaCacheError<E, S, D> {
+    fn from(err: IntentError<E>) -> Self {
+        MediaCacheError::Intent(err)
+    }
+}
+
+/// Copies media between the external service and Matrix, remembering what was copied.
+///
+/// Media at a remote URL is downloaded and uploaded to Matrix once, after which the MXC URI is
+/// reused, so avatars and forwarded images aren't uploaded again every time they are seen. The
+/// other way around, the URL at which media from Matrix was re-hosted on the external service
+/// can be remembered using `set_rehosted`.
+///
+/// Both directions are kept in a `MappingStore` of `BridgedMedia` with the URL as the external
+/// ID, which can be shared with a `StickerCache` as long as the IDs don't overlap.
+pub struct MediaCache<D, S = Mutex<MappingDict<BridgedMedia>>> {
+    downloader: D,
+    store: S,
+    max_size: Option<u64>,
+    allowed_types: Vec<String>,
+}
+
+impl<D, S> MediaCache<D, S>
+where
+    D: MediaDownloader,
+    S: MappingStore<BridgedMedia>,
+{
+    /// Create a new `MediaCache` downloading media using `downloader` and keeping the mapping in
+    /// `store`.
+    pub fn new(downloader: D, store: S) -> Self {
+        Self {
+            downloader,
+            store,
+            max_size: None,
+            allowed_types: vec![],
+        }
+    }
+
+    /// Get the store containing the mapping.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Set the maximum size in bytes of media that is uploaded, or `None` to allow any size.
+    pub fn set_max_size(&mut self, max_size: Option<u64>) {
+        self.max_size = max_size;
+    }
+
+    /// Only upload media with one of the given MIME types, like `image/png`, or a type in one of
+    /// the given groups, like `image/*`. All types are allowed if `allowed_types` is empty.
+    pub fn set_allowed_types(&mut self, allowed_types: &[&str]) {
+        self.allowed_types = allowed_types.iter().map(|t| t.to_string()).collect();
+    }
+
+    /// Returns whether media with the given `content_type` may be uploaded.
+    pub fn is_allowed_type(&self, content_type: &str) -> bool {
+        let essence = content_type.split(';').next().unwrap_or("").trim();
+        self.allowed_types.is_empty()
+            || self
+                .allowed_types
+                .iter()
+                .any(|allowed| match allowed.strip_suffix("/*") {
+                    Some(group) => essence.split('/').next() == Some(group),
+                    None => allowed.eq_ignore_ascii_case(essence),
+                })
+    }
+
+    /// Get the MXC URI of the media at the remote `url`, if it was uploaded already.
+    pub async fn mxc_for(&self, url: &str) -> Result<Option<MxcUri>, S::Error> {
+        let media = self.store.get(MappingId::External(url)).await?;
+        Ok(media.map(BridgedMedia::into_matrix))
+    }
+
+    /// Get the URL at which the media at `mxc_uri` is hosted on the external service, if it was
+    /// re-hosted or uploaded from there.
+    pub async fn url_for(&self, mxc_uri: &MxcUri) -> Result<Option<String>, S::Error> {
+        let media = self.store.get(MappingId::Matrix(mxc_uri)).await?;
+        Ok(media.map(BridgedMedia::into_external))
+    }
+
+    /// Remember that the media at `mxc_uri` was re-hosted on the external service at `url`.
+    pub async fn set_rehosted(&self, mxc_uri: MxcUri, url: String) -> Result<(), S::Error> {
+        self.store.insert(BridgedMedia::new(mxc_uri, url)).await
+    }
+
+    /// Get the MXC URI of the media at the remote `url`, downloading it and uploading it as
+    /// `intent` if it wasn't uploaded yet.
+    ///
+    /// Media without a MIME type is uploaded as `application/octet-stream`, which has to be
+    /// allowed when a list of allowed types is set.
+    pub async fn ensure_mxc<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+        url: &str,
+    ) -> Result<MxcUri, MediaCacheError<C::Error, S::Error, D::Error>> {
+        let mxc_uri = self.mxc_for(url).await.map_err(MediaCacheError::Store)?;
+        if let Some(mxc_uri) = mxc_uri {
+            return Ok(mxc_uri);
+        }
+
+        let download = self
+            .downloader
+            .download(url)
+            .await
+            .map_err(MediaCacheError::Download)?;
+        let size = download.data.len() as u64;
+        if matches!(self.max_size, Some(max_size) if size > max_size) {
+            return Err(MediaCacheError::TooLarge(size));
+        }
+        let content_type = download
+            .content_type
+            .as_deref()
+            .unwrap_or("application/octet-stream");
+        if !self.is_allowed_type(content_type) {
+            return Err(MediaCacheError::TypeNotAllowed(content_type.to_string()));
+        }
+
+        let mxc_uri = intent.upload(&download.data, content_type, None).await?;
+        self.store
+            .insert(BridgedMedia::new(mxc_uri.clone(), url.to_string()))
+            .await
+            .map_err(MediaCacheError::Store)?;
+        Ok(mxc_uri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use ruma::identifiers::{MxcUri, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{
+        Download, DownloadError, HttpDownloader, Intent, MediaCache, MediaCacheError,
+        MediaDownloader,
+    };
+
+    struct StaticDownloader {
+        downloads: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl MediaDownloader for StaticDownloader {
+        type Error = ();
+
+        async fn download(&self, url: &str) -> Result<Download, ()> {
+            self.downloads.lock().unwrap().push(url.to_string());
+            let content_type = if url.ends_with(".png") {
+                "image/png"
+            } else {
+                "application/pdf"
+            };
+            Ok(Download {
+                data: vec![0; 100],
+                content_type: Some(content_type.to_string()),
+                etag: None,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_media_cache() {
+        let (client, state) = mock_client();
+        let ghost = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        let downloader = StaticDownloader {
+            downloads: Mutex::new(vec![]),
+        };
+        let mut cache: MediaCache<_> = MediaCache::new(downloader, Default::default());
+        cache.set_allowed_types(&["image/*"]);
+
+        state.respond(
+            "/upload",
+            200,
+            json!({ "content_uri": "mxc://example.org/avatar" }),
+        );
+        let url = "https://example.com/avatar.png";
+        let mxc_uri = cache.ensure_mxc(&ghost, url).await.unwrap();
+        cache.ensure_mxc(&ghost, url).await.unwrap();
+        assert_eq!(mxc_uri.as_str(), "mxc://example.org/avatar");
+        assert_eq!(cache.downloader.downloads.lock().unwrap().len(), 1);
+        assert_eq!(state.requests_to("/upload").len(), 1);
+        assert_eq!(cache.url_for(&mxc_uri).await.unwrap().as_deref(), Some(url));
+
+        let err = cache
+            .ensure_mxc(&ghost, "https://example.com/file.pdf")
+            .await;
+        assert!(matches!(err, Err(MediaCacheError::TypeNotAllowed(t)) if t == "application/pdf"));
+
+        cache.set_max_size(Some(10));
+        let err = cache
+            .ensure_mxc(&ghost, "https://example.com/large.png")
+            .await;
+        assert!(matches!(err, Err(MediaCacheError::TooLarge(100))));
+        assert_eq!(state.requests_to("/upload").len(), 1);
+
+        let mxc_uri = MxcUri::from("mxc://example.org/photo");
+        cache
+            .set_rehosted(mxc_uri.clone(), String::from("https://example.com/p.jpg"))
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.url_for(&mxc_uri).await.unwrap().as_deref(),
+            Some("https://example.com/p.jpg")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_downloader() {
+        let (_, state) = mock_client();
+        state.respond("/avatar.png", 200, json!({ "image": true }));
+        let downloader = HttpDownloader::new(state.http_client());
+
+        let download = downloader
+            .download("https://example.com/avatar.png")
+            .await
+            .unwrap();
+        assert_eq!(download.content_type.as_deref(), Some("application/json"));
+        assert_eq!(download.data, br#"{"image":true}"#.to_vec());
+        assert_eq!(state.requests_to("/avatar.png")[0].method, "GET");
+
+        state.respond_once("/avatar.png", 304, json!({}));
+        let download = downloader
+            .download_if_changed("https://example.com/avatar.png", Some("\"v1\""))
+            .await
+            .unwrap();
+        assert!(download.is_none());
+
+        state.respond_once("/missing.png", 404, json!({}));
+        let err = downloader.download("https://example.com/missing.png").await;
+        assert!(matches!(err, Err(DownloadError::Status(404))));
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ruma::api::client::r0::membership::joined_members::RoomMember;
+use ruma::events::room::member::{MemberEvent, MembershipState};
+use ruma::identifiers::{RoomId, UserId};
+use ruma_client::HttpClient;
+
+use crate::displayname::matrix_display_name;
+use crate::intent::{Intent, IntentError};
+
+#[derive(Debug)]
+struct CachedMembers {
+    fetched: Instant,
+    members: BTreeMap<UserId, RoomMember>,
+}
+
+/// A cache of the joined members of rooms and their profiles in those rooms.
+///
+/// Fetches all joined members of a room at once using `/joined_members`, so the displaynames of
+/// the users mentioned in a message can be resolved without a request per user, for example to
+/// build a user mapper for the converters using `convert::generate_user_mapper_from_hashmap`.
+///
+/// The members of a room are fetched again after the time to live has passed, which is ten
+/// minutes by default. Passing the membership events the bridge receives to `update` keeps the
+/// cached rooms up to date in the meantime.
+#[derive(Debug)]
+pub struct MemberCache {
+    ttl: Option<Duration>,
+    rooms: Mutex<HashMap<RoomId, CachedMembers>>,
+}
+
+impl Default for MemberCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemberCache {
+    /// Create a new empty `MemberCache`.
+    pub fn new() -> Self {
+        Self {
+            ttl: Some(Duration::from_secs(10 * 60)),
+            rooms: Mutex::default(),
+        }
+    }
+
+    /// Set the time after which the members of a room are fetched again, or `None` to keep them
+    /// until they are invalidated.
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+
+    /// Get the cached joined members of the room with the given `room_id`, or `None` if they
+    /// aren't cached or have expired.
+    pub fn cached(&self, room_id: &RoomId) -> Option<BTreeMap<UserId, RoomMember>> {
+        let rooms = self.rooms.lock().unwrap();
+        let cached = rooms.get(room_id)?;
+        match self.ttl {
+            Some(ttl) if cached.fetched.elapsed() >= ttl => None,
+            _ => Some(cached.members.clone()),
+        }
+    }
+
+    /// Get the joined members of the room with the given `room_id`, fetching them as `intent`
+    /// if they aren't cached.
+    pub async fn joined_members<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<UserId, RoomMember>, IntentError<C::Error>> {
+        if let Some(members) = self.cached(room_id) {
+            return Ok(members);
+        }
+        let members = intent.joined_members(room_id).await?;
+        self.insert(room_id.clone(), members.clone());
+        Ok(members)
+    }
+
+    /// Get the profiles of the users with the given `user_ids` in the room with the given
+    /// `room_id`, fetching the members of the room as `intent` if they aren't cached.
+    ///
+    /// Users that haven't joined the room are left out.
+    pub async fn profiles<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+        room_id: &RoomId,
+        user_ids: &[UserId],
+    ) -> Result<BTreeMap<UserId, RoomMember>, IntentError<C::Error>> {
+        let mut members = self.joined_members(intent, room_id).await?;
+        members.retain(|user_id, _| user_ids.contains(user_id));
+        Ok(members)
+    }
+
+    /// Get the displaynames of the joined members of the room with the given `room_id`, fetching
+    /// them as `intent` if they aren't cached.
+    ///
+    /// Members without a displayname are left out.
+    pub async fn display_names<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+        room_id: &RoomId,
+    ) -> Result<HashMap<UserId, String>, IntentError<C::Error>> {
+        let members = self.joined_members(intent, room_id).await?;
+        let display_names = members
+            .into_iter()
+            .filter_map(|(user_id, member)| Some((user_id, member.display_name?)))
+            .collect();
+        Ok(display_names)
+    }
+
+    /// Get the cached displayname of the user with the given `user_id` in the room with the
+    /// given `room_id`, without making any requests.
+    pub fn display_name(&self, room_id: &RoomId, user_id: &UserId) -> Option<String> {
+        self.cached(room_id)?.remove(user_id)?.display_name
+    }
+
+    /// Get the name of the user with the given `user_id` as Matrix clients show it in the room
+    /// with the given `room_id`, see `matrix_display_name`, or `None` if the members of the room
+    /// aren't cached.
+    ///
+    /// Only joined members are taken into account, since those are the members that are cached.
+    pub fn disambiguated_name(&self, room_id: &RoomId, user_id: &UserId) -> Option<String> {
+        let members = self.cached(room_id)?;
+        Some(matrix_display_name(user_id, &members))
+    }
+
+    /// Cache `members` as the joined members of the room with the given `room_id`.
+    pub fn insert(&self, room_id: RoomId, members: BTreeMap<UserId, RoomMember>) {
+        let cached = CachedMembers {
+            fetched: Instant::now(),
+            members,
+        };
+        self.rooms.lock().unwrap().insert(room_id, cached);
+    }
+
+    /// Apply the membership `event` to the cached members of its room, if they are cached.
+    pub fn update(&self, event: &MemberEvent) {
+        let user_id = match UserId::try_from(event.state_key.as_str()) {
+            Ok(user_id) => user_id,
+            Err(_) => return,
+        };
+        let mut rooms = self.rooms.lock().unwrap();
+        let cached = match rooms.get_mut(&event.room_id) {
+            Some(cached) => cached,
+            None => return,
+        };
+
+        if event.content.membership == MembershipState::Join {
+            let mut member = RoomMember::new();
+            member.display_name = event.content.displayname.clone();
+            member.avatar_url = event.content.avatar_url.clone();
+            cached.members.insert(user_id, member);
+        } else {
+            cached.members.remove(&user_id);
+        }
+    }
+
+    /// Forget the members of the room with the given `room_id`, so they are fetched again the
+    /// next time they are needed.
+    ///
+    /// Returns whether the members were cached.
+    pub fn invalidate(&self, room_id: &RoomId) -> bool {
+        self.rooms.lock().unwrap().remove(room_id).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    use ruma::events::room::member::MemberEvent;
+    use ruma::identifiers::{RoomId, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{Intent, MemberCache};
+
+    #[tokio::test]
+    async fn test_member_cache() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let alice = UserId::try_from("@alice:example.org").unwrap();
+        let bob = UserId::try_from("@bob:example.org").unwrap();
+        state.respond(
+            "/joined_members",
+            200,
+            json!({
+                "joined": {
+                    "@alice:example.org": { "display_name": "Alice" },
+                    "@bob:example.org": {},
+                },
+            }),
+        );
+
+        let cache = MemberCache::new();
+        assert_eq!(cache.display_name(&room_id, &alice), None);
+        let display_names = cache.display_names(&bot, &room_id).await.unwrap();
+        assert_eq!(display_names.len(), 1);
+        assert_eq!(display_names[&alice], "Alice");
+        let carol = UserId::try_from("@carol:example.org").unwrap();
+        let profiles = cache
+            .profiles(&bot, &room_id, &[bob.clone(), carol])
+            .await
+            .unwrap();
+        assert_eq!(profiles.keys().collect::<Vec<_>>(), [&bob]);
+        assert_eq!(state.requests_to("/joined_members").len(), 1);
+
+        let event: MemberEvent = serde_json::from_value(json!({
+            "type": "m.room.member",
+            "event_id": "$rename:example.org",
+            "room_id": "!room:example.org",
+            "sender": "@bob:example.org",
+            "state_key": "@bob:example.org",
+            "origin_server_ts": 0,
+            "content": { "membership": "join", "displayname": "Bob" },
+        }))
+        .unwrap();
+        cache.update(&event);
+        assert_eq!(cache.display_name(&room_id, &bob).as_deref(), Some("Bob"));
+        assert_eq!(
+            cache.disambiguated_name(&room_id, &alice).as_deref(),
+            Some("Alice")
+        );
+
+        assert!(cache.invalidate(&room_id));
+        cache.joined_members(&bot, &room_id).await.unwrap();
+        assert_eq!(state.requests_to("/joined_members").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        state.respond("/joined_members", 200, json!({ "joined": {} }));
+
+        let mut cache = MemberCache::new();
+        cache.set_ttl(Some(Duration::from_secs(0)));
+        cache.joined_members(&bot, &room_id).await.unwrap();
+        assert!(cache.cached(&room_id).is_none());
+        cache.joined_members(&bot, &room_id).await.unwrap();
+        assert_eq!(state.requests_to("/joined_members").len(), 2);
+    }
+}
@@ -0,0 +1,507 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+use futures::stream::{self, StreamExt};
+use ruma::api::client::r0::membership::joined_members::RoomMember;
+use ruma::events::room::member::{MemberEventContent, MembershipState};
+use ruma::identifiers::{RoomId, UserId};
+use ruma_client::HttpClient;
+use serde_json::value::to_raw_value;
+
+use crate::bulkjoin::{with_retries, JoinProgress, ProgressFn, ProgressTracker};
+use crate::client::RetryPolicy;
+use crate::intent::Intent;
+use crate::mappingdict::MappingDict;
+use crate::members::MemberCache;
+use crate::puppet::{Puppet, PuppetError, PuppetManager};
+use crate::store::MappingStore;
+
+/// An external user that should be in a portal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Participant {
+    /// The ID of the user on the external service.
+    pub external_id: String,
+    /// The displayname the puppet of the user should have in the room, if any.
+    pub displayname: Option<String>,
+}
+
+impl Participant {
+    /// Create a new `Participant` with the given `external_id` and no displayname.
+    pub fn new(external_id: String) -> Self {
+        Self {
+            external_id,
+            displayname: None,
+        }
+    }
+}
+
+/// A change to the membership of a room, made by `MembershipSync`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MembershipAction {
+    /// The bot invites the puppet with the given user ID.
+    Invite(UserId),
+    /// The puppet with the given user ID joins.
+    Join(UserId),
+    /// The bot kicks the puppet with the given user ID.
+    Kick(UserId),
+    /// The puppet with the given user ID sets its displayname in the room.
+    SetDisplayName(UserId, String),
+}
+
+impl MembershipAction {
+    /// Get the user ID of the puppet this action is about.
+    pub fn user_id(&self) -> &UserId {
+        match self {
+            Self::Invite(user_id)
+            | Self::Join(user_id)
+            | Self::Kick(user_id)
+            | Self::SetDisplayName(user_id, _) => user_id,
+        }
+    }
+}
+
+/// The outcome of `MembershipSync::sync`.
+#[derive(Debug)]
+pub struct MembershipSyncReport<E, S> {
+    /// The actions that were carried out, or that would have been in dry-run mode.
+    pub actions: Vec<MembershipAction>,
+    /// The actions that failed. The remaining actions for the same puppet are skipped.
+    pub errors: Vec<(MembershipAction, PuppetError<E, S>)>,
+}
+
+/// The actions needed to sync a room, and what's needed to carry them out.
+struct Plan {
+    actions: Vec<MembershipAction>,
+    external_ids: HashMap<UserId, String>,
+    members: BTreeMap<UserId, MemberEventContent>,
+}
+
+impl Plan {
+    /// Get the members that are joined after carrying out the `done` actions.
+    fn joined_after(&self, done: &[MembershipAction]) -> BTreeMap<UserId, RoomMember> {
+        let mut joined: BTreeMap<UserId, RoomMember> = self
+            .members
+            .iter()
+            .filter(|(_, content)| content.membership == MembershipState::Join)
+            .map(|(user_id, content)| {
+                let mut member = RoomMember::new();
+                member.display_name = content.displayname.clone();
+                member.avatar_url = content.avatar_url.clone();
+                (user_id.clone(), member)
+            })
+            .collect();
+        for action in done {
+            match action {
+                MembershipAction::Invite(_) => {}
+                MembershipAction::Join(user_id) => {
+                    joined.entry(user_id.clone()).or_default();
+                }
+                MembershipAction::Kick(user_id) => {
+                    joined.remove(user_id);
+                }
+                MembershipAction::SetDisplayName(user_id, displayname) => {
+                    joined.entry(user_id.clone()).or_default().display_name =
+                        Some(displayname.clone());
+                }
+            }
+        }
+        joined
+    }
+}
+
+/// Synchronises the puppets in a portal with the participants on the external service.
+///
+/// Compares the participants that should be in a room with the current members of the room, and
+/// invites and joins the puppets that are missing, kicks the puppets that shouldn't be there and
+/// updates the displaynames that differ. Only puppets known to the `PuppetManager` are kicked,
+/// Matrix users are left alone.
+pub struct MembershipSync<'a, C, S = Mutex<MappingDict<Puppet>>> {
+    bot: &'a Intent<C>,
+    puppets: &'a PuppetManager<C, S>,
+    member_cache: Option<&'a MemberCache>,
+    concurrency: usize,
+    retry: RetryPolicy,
+    on_progress: Option<ProgressFn>,
+    dry_run: bool,
+}
+
+impl<'a, C, S> MembershipSync<'a, C, S>
+where
+    C: HttpClient + Clone,
+    S: MappingStore<Puppet>,
+{
+    /// Create a new `MembershipSync` inviting and kicking as `bot`, which has to be in the rooms
+    /// with enough power, and acting as the puppets of `puppets`.
+    pub fn new(bot: &'a Intent<C>, puppets: &'a PuppetManager<C, S>) -> Self {
+        Self {
+            bot,
+            puppets,
+            member_cache: None,
+            concurrency: 5,
+            retry: RetryPolicy::default(),
+            on_progress: None,
+            dry_run: false,
+        }
+    }
+
+    /// Set the amount of puppets that are updated at the same time, returning the current
+    /// `MembershipSync` to allow method chaining.
+    pub fn concurrency(&mut self, concurrency: usize) -> &mut Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Set when and how often failed actions are retried, returning the current
+    /// `MembershipSync` to allow method chaining.
+    pub fn retry_policy(&mut self, retry: RetryPolicy) -> &mut Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Call `f` every time all actions for a puppet are done, returning the current
+    /// `MembershipSync` to allow method chaining.
+    pub fn on_progress<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&JoinProgress) + Send + Sync + 'static,
+    {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Set whether to only compute the actions needed, without carrying them out, returning the
+    /// current `MembershipSync` to allow method chaining.
+    pub fn dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Keep the joined members of the synced rooms in `cache`, returning the current
+    /// `MembershipSync` to allow method chaining.
+    ///
+    /// The members fetched by a sync are cached together with the changes it made, so converters
+    /// can resolve the displaynames of the puppets without fetching them again.
+    pub fn member_cache(&mut self, cache: &'a MemberCache) -> &mut Self {
+        self.member_cache = Some(cache);
+        self
+    }
+
+    async fn plan(
+        &self,
+        room_id: &RoomId,
+        participants: &[Participant],
+    ) -> Result<Plan, PuppetError<C::Error, S::Error>> {
+        let members: BTreeMap<UserId, MemberEventContent> = self
+            .bot
+            .members(room_id)
+            .await?
+            .into_iter()
+            .filter_map(|event| {
+                let user_id = UserId::try_from(event.state_key.as_str()).ok()?;
+                Some((user_id, event.content))
+            })
+            .collect();
+
+        let mut actions = vec![];
+        let mut external_ids = HashMap::new();
+        for participant in participants {
+            let external_id = &participant.external_id;
+            let user_id = match self.puppets.get(external_id).await {
+                Ok(Some(puppet)) => puppet.user_id().clone(),
+                Ok(None) => self
+                    .puppets
+                    .user_id_for(external_id)
+                    .map_err(PuppetError::InvalidUserId)?,
+                Err(err) => return Err(PuppetError::Store(err)),
+            };
+            if external_ids.contains_key(&user_id) {
+                continue;
+            }
+
+            let member = members.get(&user_id);
+            let membership = member.map(|content| &content.membership);
+            match membership {
+                Some(MembershipState::Ban) => continue,
+                Some(MembershipState::Join) => {}
+                Some(MembershipState::Invite) => {
+                    actions.push(MembershipAction::Join(user_id.clone()));
+                }
+                _ => {
+                    actions.push(MembershipAction::Invite(user_id.clone()));
+                    actions.push(MembershipAction::Join(user_id.clone()));
+                }
+            }
+
+            let current = match membership {
+                Some(MembershipState::Join) => member.and_then(|m| m.displayname.as_ref()),
+                _ => None,
+            };
+            if let Some(displayname) = &participant.displayname {
+                if current != Some(displayname) {
+                    let action =
+                        MembershipAction::SetDisplayName(user_id.clone(), displayname.clone());
+                    actions.push(action);
+                }
+            }
+
+            external_ids.insert(user_id, external_id.clone());
+        }
+
+        for (user_id, content) in &members {
+            let is_member = matches!(
+                content.membership,
+                MembershipState::Join | MembershipState::Invite
+            );
+            if !is_member || external_ids.contains_key(user_id) {
+                continue;
+            }
+            let is_puppet = self
+                .puppets
+                .external_id_for(user_id)
+                .await
+                .map_err(PuppetError::Store)?
+                .is_some();
+            if is_puppet {
+                actions.push(MembershipAction::Kick(user_id.clone()));
+            }
+        }
+
+        Ok(Plan {
+            actions,
+            external_ids,
+            members,
+        })
+    }
+
+    async fn apply(
+        &self,
+        room_id: &RoomId,
+        plan: &Plan,
+        action: &MembershipAction,
+    ) -> Result<(), PuppetError<C::Error, S::Error>> {
+        let puppet = || async {
+            let external_id = &plan.external_ids[action.user_id()];
+            self.puppets.puppet_for(external_id).await
+        };
+
+        match action {
+            MembershipAction::Invite(user_id) => {
+                puppet().await?;
+                self.bot.invite(room_id, user_id).await?;
+            }
+            MembershipAction::Join(user_id) => {
+                puppet().await?.join(room_id).await?;
+                self.puppets
+                    .set_joined(user_id, room_id, true)
+                    .await
+                    .map_err(PuppetError::Store)?;
+            }
+            MembershipAction::Kick(user_id) => {
+                self.bot.kick(room_id, user_id, None).await?;
+                self.puppets
+                    .set_joined(user_id, room_id, false)
+                    .await
+                    .map_err(PuppetError::Store)?;
+            }
+            MembershipAction::SetDisplayName(user_id, displayname) => {
+                let mut content = MemberEventContent::new(MembershipState::Join);
+                if let Some(member) = plan.members.get(user_id) {
+                    content.avatar_url = member.avatar_url.clone();
+                }
+                content.displayname = Some(displayname.clone());
+
+                let content = to_raw_value(&content).expect("member event should serialize");
+                puppet()
+                    .await?
+                    .send_state_raw(room_id, "m.room.member", user_id.as_str(), content)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Make the puppets in the room with the given `room_id` match `participants`, and kick all
+    /// other puppets.
+    ///
+    /// Fails only if the current members can't be fetched or the puppets can't be loaded. Failing
+    /// actions are returned in the report.
+    pub async fn sync(
+        &self,
+        room_id: &RoomId,
+        participants: &[Participant],
+    ) -> Result<MembershipSyncReport<C::Error, S::Error>, PuppetError<C::Error, S::Error>> {
+        let plan = self.plan(room_id, participants).await?;
+        if self.dry_run {
+            if let Some(cache) = self.member_cache {
+                cache.insert(room_id.clone(), plan.joined_after(&[]));
+            }
+            return Ok(MembershipSyncReport {
+                actions: plan.actions,
+                errors: vec![],
+            });
+        }
+
+        // actions for the same puppet have to be carried out in order.
+        let mut per_user: BTreeMap<&UserId, Vec<&MembershipAction>> = BTreeMap::new();
+        for action in &plan.actions {
+            per_user.entry(action.user_id()).or_default().push(action);
+        }
+
+        let plan = &plan;
+        let progress = ProgressTracker::new(per_user.len(), self.on_progress.as_ref());
+        let progress = &progress;
+        let results: Vec<_> = stream::iter(per_user.into_values())
+            .map(|actions| async move {
+                let mut done = vec![];
+                for action in actions {
+                    let result =
+                        with_retries(&self.retry, || self.apply(room_id, plan, action)).await;
+                    if let Err(err) = result {
+                        progress.record(true);
+                        return (done, Some((action.clone(), err)));
+                    }
+                    done.push(action.clone());
+                }
+                progress.record(false);
+                (done, None)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await;
+
+        let mut report = MembershipSyncReport {
+            actions: vec![],
+            errors: vec![],
+        };
+        for (done, error) in results {
+            report.actions.extend(done);
+            if let Some((action, err)) = error {
+                report.errors.push((action, err));
+            }
+        }
+        if let Some(cache) = self.member_cache {
+            cache.insert(room_id.clone(), plan.joined_after(&report.actions));
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{RoomId, ServerName, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{
+        Intent, MappingStore, MemberCache, MembershipAction, MembershipSync, Participant, Puppet,
+        PuppetManager,
+    };
+
+    #[tokio::test]
+    async fn test_membership_sync() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(
+            client.clone(),
+            UserId::try_from("@bot:example.org").unwrap(),
+        );
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let puppets: PuppetManager<_> =
+            PuppetManager::new(client, server_name, "_ext_", Default::default());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        let user = |s: &str| UserId::try_from(s).unwrap();
+        for (user_id, external_id) in &[
+            ("@_ext_bob:example.org", "bob"),
+            ("@_ext_carol:example.org", "carol"),
+        ] {
+            let puppet = Puppet::new(user(user_id), external_id.to_string());
+            puppets.store().insert(puppet).await.unwrap();
+        }
+
+        let member = |user_id: &str, membership: &str, displayname: &str| {
+            json!({
+                "type": "m.room.member",
+                "event_id": format!("${}", &user_id[1..]),
+                "room_id": "!room:example.org",
+                "sender": user_id,
+                "state_key": user_id,
+                "origin_server_ts": 0,
+                "content": { "membership": membership, "displayname": displayname },
+            })
+        };
+        state.respond(
+            "/members",
+            200,
+            json!({
+                "chunk": [
+                    member("@bot:example.org", "join", "Bot"),
+                    member("@alice:example.org", "join", "Alice"),
+                    member("@_ext_bob:example.org", "join", "bob"),
+                    member("@_ext_carol:example.org", "join", "Carol"),
+                ],
+            }),
+        );
+
+        state.respond(
+            "/register",
+            200,
+            json!({ "user_id": "@_ext_dave:example.org" }),
+        );
+        let mut participants = vec![
+            Participant::new(String::from("bob")),
+            Participant::new(String::from("dave")),
+        ];
+        participants[0].displayname = Some(String::from("Bob"));
+
+        let mut sync = MembershipSync::new(&bot, &puppets);
+        sync.dry_run(true);
+        let report = sync.sync(&room_id, &participants).await.unwrap();
+        let dave = user("@_ext_dave:example.org");
+        let expected = vec![
+            MembershipAction::SetDisplayName(user("@_ext_bob:example.org"), String::from("Bob")),
+            MembershipAction::Invite(dave.clone()),
+            MembershipAction::Join(dave.clone()),
+            MembershipAction::Kick(user("@_ext_carol:example.org")),
+        ];
+        assert_eq!(report.actions, expected);
+        assert_eq!(state.requests().len(), 1);
+
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+        state.respond("/state/", 200, json!({ "event_id": "$name:example.org" }));
+        let cache = MemberCache::new();
+        sync.dry_run(false).concurrency(2).member_cache(&cache);
+        let report = sync.sync(&room_id, &participants).await.unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.actions.len(), 4);
+
+        let members = cache.cached(&room_id).unwrap();
+        assert!(members.contains_key(&dave));
+        assert!(!members.contains_key(&user("@_ext_carol:example.org")));
+        let bob = user("@_ext_bob:example.org");
+        assert_eq!(cache.display_name(&room_id, &bob).as_deref(), Some("Bob"));
+        let alice = user("@alice:example.org");
+        assert_eq!(
+            cache.display_name(&room_id, &alice).as_deref(),
+            Some("Alice")
+        );
+
+        let kick = &state.requests_to("/kick")[0];
+        assert_eq!(kick.body["user_id"], "@_ext_carol:example.org");
+        let invite = &state.requests_to("/invite")[0];
+        assert_eq!(invite.body["user_id"], "@_ext_dave:example.org");
+        assert_eq!(state.requests_to("/register").len(), 2);
+        let displayname = &state.requests_to("/state/m.room.member/@_ext_bob:example.org")[0];
+        assert_eq!(
+            displayname.body,
+            json!({ "membership": "join", "displayname": "Bob" })
+        );
+        assert!(puppets
+            .get("dave")
+            .await
+            .unwrap()
+            .unwrap()
+            .is_joined(&room_id));
+    }
+}
@@ -0,0 +1,1023 @@
+use ruma::events::room::message::{
+    FormattedBody, InReplyTo, MessageEvent, MessageEventContent, MessageFormat, MessageType,
+    Relation,
+};
+use ruma::events::room::relationships::Replacement;
+use ruma::events::{AnyMessageEvent, AnyRoomEvent};
+use ruma::identifiers::{EventId, RoomId, UserId};
+use ruma::serde::Raw;
+use ruma_client::HttpClient;
+use serde::{Deserialize, Serialize};
+use serde_json::value::{to_raw_value, RawValue};
+use serde_json::{json, Value};
+
+use crate::intent::{Intent, IntentError};
+use crate::mappingdict::{Mappable, MappingId};
+use crate::store::MappingStore;
+
+/// A message bridged between a Matrix event and a message on the external service.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BridgedMessage {
+    event_id: EventId,
+    room_id: RoomId,
+    external_id: String,
+}
+
+impl BridgedMessage {
+    /// Create a new `BridgedMessage`, linking the event with the given `event_id` in the room
+    /// with the given `room_id` to the external message `external_id`.
+    ///
+    /// The external ID has to be unique for the bridge, not just within a channel.
+    pub fn new(event_id: EventId, room_id: RoomId, external_id: String) -> Self {
+        Self {
+            event_id,
+            room_id,
+            external_id,
+        }
+    }
+
+    /// Get the ID of the Matrix event of this message.
+    pub fn event_id(&self) -> &EventId {
+        &self.event_id
+    }
+
+    /// Get the ID of the room the Matrix event of this message is in.
+    pub fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
+    /// Get the ID of the message on the external service.
+    pub fn external_id(&self) -> &str {
+        &self.external_id
+    }
+}
+
+impl Mappable for BridgedMessage {
+    type MatrixReference = EventId;
+    type MatrixType = EventId;
+    type ExternalReference = str;
+    type ExternalType = String;
+
+    fn as_matrix(&self) -> &EventId {
+        &self.event_id
+    }
+    fn into_matrix(self) -> EventId {
+        self.event_id
+    }
+    fn as_external(&self) -> &str {
+        &self.external_id
+    }
+    fn into_external(self) -> String {
+        self.external_id
+    }
+    fn into_split(self) -> (EventId, String) {
+        (self.event_id, self.external_id)
+    }
+}
+
+/// A redaction of a bridged message, which should be deleted on the external service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redaction {
+    /// The ID of the redaction event.
+    pub event_id: EventId,
+    /// The user that redacted the message.
+    pub sender: UserId,
+    /// The reason for the redaction, if any.
+    pub reason: Option<String>,
+    /// The message that was redacted.
+    pub message: BridgedMessage,
+}
+
+impl Redaction {
+    /// Resolve the message redacted by `event` using `store`.
+    ///
+    /// Returns `None` if `event` isn't a redaction or if the redacted event isn't a bridged
+    /// message. The message isn't removed from `store`.
+    pub async fn resolve<S>(store: &S, event: &AnyRoomEvent) -> Result<Option<Self>, S::Error>
+    where
+        S: MappingStore<BridgedMessage>,
+    {
+        let event = match event {
+            AnyRoomEvent::Message(AnyMessageEvent::RoomRedaction(event)) => event,
+            _ => return Ok(None),
+        };
+
+        let message = match store.get(MappingId::Matrix(&event.redacts)).await? {
+            Some(message) if message.room_id == event.room_id => message,
+            _ => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            event_id: event.event_id.clone(),
+            sender: event.sender.clone(),
+            reason: event.content.reason.clone(),
+            message,
+        }))
+    }
+
+    /// Resolve the messages redacted by the redactions in `events`, the events of a transaction,
+    /// using `resolve`. Events that can't be deserialized are skipped.
+    pub async fn resolve_all<S>(
+        store: &S,
+        events: &[Raw<AnyRoomEvent>],
+    ) -> Result<Vec<Self>, S::Error>
+    where
+        S: MappingStore<BridgedMessage>,
+    {
+        let mut redactions = vec![];
+        for event in deserialize_all(events) {
+            if let Some(redaction) = Self::resolve(store, &event).await? {
+                redactions.push(redaction);
+            }
+        }
+        Ok(redactions)
+    }
+}
+
+/// A reaction bridged between a Matrix event and a reaction on the external service.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BridgedReaction {
+    event_id: EventId,
+    room_id: RoomId,
+    external_id: String,
+    message_id: String,
+    key: String,
+}
+
+impl BridgedReaction {
+    /// Create a new `BridgedReaction`, linking the reaction event with the given `event_id` in
+    /// the room with the given `room_id` to the external reaction `external_id`, which is a
+    /// reaction with `key` to the external message `message_id`.
+    ///
+    /// For external services without IDs for reactions, the external ID can be made of the
+    /// message ID, the ID of the user and the key.
+    pub fn new(
+        event_id: EventId,
+        room_id: RoomId,
+        external_id: String,
+        message_id: String,
+        key: String,
+    ) -> Self {
+        Self {
+            event_id,
+            room_id,
+            external_id,
+            message_id,
+            key,
+        }
+    }
+
+    /// Get the ID of the Matrix reaction event.
+    pub fn event_id(&self) -> &EventId {
+        &self.event_id
+    }
+
+    /// Get the ID of the room the reaction event is in.
+    pub fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
+    /// Get the ID of the reaction on the external service.
+    pub fn external_id(&self) -> &str {
+        &self.external_id
+    }
+
+    /// Get the external ID of the message that was reacted to.
+    pub fn message_id(&self) -> &str {
+        &self.message_id
+    }
+
+    /// Get the key of the reaction, usually an emoji.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl Mappable for BridgedReaction {
+    type MatrixReference = EventId;
+    type MatrixType = EventId;
+    type ExternalReference = str;
+    type ExternalType = String;
+
+    fn as_matrix(&self) -> &EventId {
+        &self.event_id
+    }
+    fn into_matrix(self) -> EventId {
+        self.event_id
+    }
+    fn as_external(&self) -> &str {
+        &self.external_id
+    }
+    fn into_external(self) -> String {
+        self.external_id
+    }
+    fn into_split(self) -> (EventId, String) {
+        (self.event_id, self.external_id)
+    }
+}
+
+/// A change to the reactions on a bridged message, which should be applied on the external
+/// service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReactionChange {
+    /// A reaction was added to a bridged message.
+    Added {
+        /// The ID of the reaction event.
+        event_id: EventId,
+        /// The user that reacted.
+        sender: UserId,
+        /// The key of the reaction, usually an emoji.
+        key: String,
+        /// The message that was reacted to.
+        message: BridgedMessage,
+    },
+    /// A bridged reaction was removed by redacting it.
+    Removed {
+        /// The ID of the redaction event.
+        event_id: EventId,
+        /// The user that removed the reaction.
+        sender: UserId,
+        /// The reaction that was removed.
+        reaction: BridgedReaction,
+    },
+}
+
+impl ReactionChange {
+    /// Resolve the reaction change made by `event`, using `messages` to find the message that
+    /// was reacted to and `reactions` to find the reaction that was redacted.
+    ///
+    /// Returns `None` if `event` isn't a reaction to a bridged message or a redaction of a
+    /// bridged reaction. Reactions aren't added to or removed from `reactions`, the bridge should
+    /// do that once they are bridged.
+    pub async fn resolve<M, R>(
+        messages: &M,
+        reactions: &R,
+        event: &AnyRoomEvent,
+    ) -> Result<Option<Self>, M::Error>
+    where
+        M: MappingStore<BridgedMessage>,
+        R: MappingStore<BridgedReaction, Error = M::Error>,
+    {
+        match event {
+            AnyRoomEvent::Message(AnyMessageEvent::Reaction(event)) => {
+                let relation = &event.content.relation;
+                let message = match messages.get(MappingId::Matrix(&relation.event_id)).await? {
+                    Some(message) if message.room_id == event.room_id => message,
+                    _ => return Ok(None),
+                };
+
+                Ok(Some(Self::Added {
+                    event_id: event.event_id.clone(),
+                    sender: event.sender.clone(),
+                    key: relation.emoji.clone(),
+                    message,
+                }))
+            }
+            AnyRoomEvent::Message(AnyMessageEvent::RoomRedaction(event)) => {
+                let reaction = match reactions.get(MappingId::Matrix(&event.redacts)).await? {
+                    Some(reaction) if reaction.room_id == event.room_id => reaction,
+                    _ => return Ok(None),
+                };
+
+                Ok(Some(Self::Removed {
+                    event_id: event.event_id.clone(),
+                    sender: event.sender.clone(),
+                    reaction,
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Resolve the reaction changes made by `events`, the events of a transaction, using
+    /// `resolve`. Events that can't be deserialized are skipped.
+    pub async fn resolve_all<M, R>(
+        messages: &M,
+        reactions: &R,
+        events: &[Raw<AnyRoomEvent>],
+    ) -> Result<Vec<Self>, M::Error>
+    where
+        M: MappingStore<BridgedMessage>,
+        R: MappingStore<BridgedReaction, Error = M::Error>,
+    {
+        let mut changes = vec![];
+        for event in deserialize_all(events) {
+            if let Some(change) = Self::resolve(messages, reactions, &event).await? {
+                changes.push(change);
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// An edit of a bridged message, which should be applied on the external service.
+#[derive(Debug, Clone)]
+pub struct Edit {
+    /// The ID of the edit event.
+    pub event_id: EventId,
+    /// The user that edited the message.
+    pub sender: UserId,
+    /// The new content of the message.
+    pub new_content: MessageEventContent,
+    /// The message that was edited.
+    pub message: BridgedMessage,
+}
+
+impl Edit {
+    /// Resolve the message edited by `event` using `store`.
+    ///
+    /// Returns `None` if `event` isn't an edit, that is a message with an `m.replace` relation
+    /// and `m.new_content`, or if the edited event isn't a bridged message.
+    pub async fn resolve<S>(store: &S, event: &AnyRoomEvent) -> Result<Option<Self>, S::Error>
+    where
+        S: MappingStore<BridgedMessage>,
+    {
+        let event = match event {
+            AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(event)) => event,
+            _ => return Ok(None),
+        };
+        let (replacement, new_content) = match &event.content {
+            MessageEventContent {
+                relates_to: Some(Relation::Replacement(replacement)),
+                new_content: Some(new_content),
+                ..
+            } => (replacement, new_content),
+            _ => return Ok(None),
+        };
+
+        let message = match store.get(MappingId::Matrix(&replacement.event_id)).await? {
+            Some(message) if message.room_id == event.room_id => message,
+            _ => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            event_id: event.event_id.clone(),
+            sender: event.sender.clone(),
+            new_content: (**new_content).clone(),
+            message,
+        }))
+    }
+
+    /// Resolve the messages edited by the edits in `events`, the events of a transaction, using
+    /// `resolve`. Events that can't be deserialized are skipped.
+    pub async fn resolve_all<S>(
+        store: &S,
+        events: &[Raw<AnyRoomEvent>],
+    ) -> Result<Vec<Self>, S::Error>
+    where
+        S: MappingStore<BridgedMessage>,
+    {
+        let mut edits = vec![];
+        for event in deserialize_all(events) {
+            if let Some(edit) = Self::resolve(store, &event).await? {
+                edits.push(edit);
+            }
+        }
+        Ok(edits)
+    }
+}
+
+/// The thread a message is in, from its `m.thread` relation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Thread {
+    /// The ID of the root event of the thread.
+    pub root: EventId,
+    /// The event the message replies to.
+    pub in_reply_to: Option<EventId>,
+    /// Whether `in_reply_to` is only a fallback for clients without thread support, pointing to
+    /// the latest event in the thread, instead of a real reply.
+    pub is_falling_back: bool,
+}
+
+#[derive(Deserialize)]
+struct ThreadEventJson {
+    content: ThreadContentJson,
+}
+
+#[derive(Deserialize)]
+struct ThreadContentJson {
+    #[serde(rename = "m.relates_to")]
+    relates_to: ThreadRelationJson,
+}
+
+#[derive(Deserialize)]
+struct ThreadRelationJson {
+    rel_type: String,
+    event_id: EventId,
+    #[serde(default)]
+    is_falling_back: bool,
+    #[serde(rename = "m.in_reply_to")]
+    in_reply_to: Option<InReplyToJson>,
+}
+
+#[derive(Deserialize)]
+struct InReplyToJson {
+    event_id: EventId,
+}
+
+impl Thread {
+    /// Create a new `Thread` with the given `root`, for a message that isn't a reply.
+    pub fn new(root: EventId) -> Self {
+        Self {
+            root,
+            in_reply_to: None,
+            is_falling_back: true,
+        }
+    }
+
+    /// Get the thread the given `event` is in, or `None` if it isn't in a thread.
+    ///
+    /// This works on the raw event, since `ruma` doesn't know about threads.
+    pub fn from_raw(event: &Raw<AnyRoomEvent>) -> Option<Self> {
+        let event: ThreadEventJson = serde_json::from_str(event.json().get()).ok()?;
+        let relation = event.content.relates_to;
+        if relation.rel_type != "m.thread" {
+            return None;
+        }
+
+        Some(Self {
+            root: relation.event_id,
+            in_reply_to: relation.in_reply_to.map(|reply| reply.event_id),
+            is_falling_back: relation.is_falling_back,
+        })
+    }
+
+    /// Get the bridged message that is the root of this thread using `store`, to get the
+    /// external ID of the thread.
+    pub async fn root_message<S>(&self, store: &S) -> Result<Option<BridgedMessage>, S::Error>
+    where
+        S: MappingStore<BridgedMessage>,
+    {
+        store.get(MappingId::Matrix(&self.root)).await
+    }
+
+    /// Build the JSON content of a message with the given `content` in this thread, which can be
+    /// sent using `Intent::send_message_raw`.
+    ///
+    /// Clients without thread support show the message as a reply to `in_reply_to`, which should
+    /// be the latest event in the thread when falling back. Without `in_reply_to`, the message is
+    /// shown as a reply to the root.
+    pub fn content(&self, content: &MessageEventContent) -> Box<RawValue> {
+        let in_reply_to = self.in_reply_to.as_ref().unwrap_or(&self.root);
+        let relation = json!({
+            "rel_type": "m.thread",
+            "event_id": self.root,
+            "is_falling_back": self.is_falling_back,
+            "m.in_reply_to": { "event_id": in_reply_to },
+        });
+
+        let mut content = serde_json::to_value(content).expect("message should serialize");
+        if let Value::Object(content) = &mut content {
+            content.insert(String::from("m.relates_to"), relation);
+        }
+        to_raw_value(&content).expect("message should serialize")
+    }
+}
+
+/// Build the content of an event that edits the event with the given `event_id` to
+/// `new_content`.
+///
+/// The body of the event is the new body prefixed with `* `, as a fallback for clients that
+/// don't support edits.
+pub fn edit_content(
+    event_id: &EventId,
+    mut new_content: MessageEventContent,
+) -> MessageEventContent {
+    new_content.relates_to = None;
+    new_content.new_content = None;
+
+    let mut content = MessageEventContent::new(new_content.msgtype.clone());
+    if let Some((body, formatted)) = text_mut(&mut content.msgtype) {
+        *body = format!("* {}", body);
+        if let Some(formatted) = formatted.as_mut() {
+            formatted.body = format!("* {}", formatted.body);
+        }
+    }
+    content.relates_to = Some(Relation::Replacement(Replacement::new(event_id.clone())));
+    content.new_content = Some(Box::new(new_content));
+    content
+}
+
+/// Build the content of a reply with `new_content` to the `original` message.
+///
+/// Text, notice and emote replies get the rich reply fallbacks quoting the original message in
+/// both `body` and `formatted_body`, for clients that don't support replies. Fallbacks of the
+/// original message, if it is a reply itself, are left out of the quote.
+pub fn build_reply(
+    original: &MessageEvent,
+    mut new_content: MessageEventContent,
+) -> MessageEventContent {
+    if let Some((body, formatted)) = text_mut(&mut new_content.msgtype) {
+        let (quote, html_quote) = reply_fallback(original);
+        let html = match formatted.take() {
+            Some(formatted) if formatted.format == MessageFormat::Html => formatted.body,
+            _ => text_to_html(body),
+        };
+        *body = format!("{}\n\n{}", quote, body);
+        *formatted = Some(FormattedBody::html(format!("{}{}", html_quote, html)));
+    }
+
+    new_content.relates_to = Some(Relation::Reply {
+        in_reply_to: InReplyTo::new(original.event_id.clone()),
+    });
+    new_content
+}
+
+/// Build the content of a reply with `new_content` to the event with the given `event_id` in the
+/// room with the given `room_id`, fetching the original event as `intent`.
+///
+/// If the original event isn't a message, the reply doesn't get fallbacks, see `build_reply`.
+pub async fn fetch_reply<C: HttpClient>(
+    intent: &Intent<C>,
+    room_id: &RoomId,
+    event_id: &EventId,
+    mut new_content: MessageEventContent,
+) -> Result<MessageEventContent, IntentError<C::Error>> {
+    let original = intent.get_event(room_id, event_id).await?;
+    if let Ok(AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(original))) =
+        original.deserialize()
+    {
+        return Ok(build_reply(&original, new_content));
+    }
+
+    new_content.relates_to = Some(Relation::Reply {
+        in_reply_to: InReplyTo::new(event_id.clone()),
+    });
+    Ok(new_content)
+}
+
+/// Get the plain and HTML reply fallbacks quoting the `original` message.
+fn reply_fallback(original: &MessageEvent) -> (String, String) {
+    let is_reply = matches!(original.content.relates_to, Some(Relation::Reply { .. }));
+    let emote = matches!(original.content.msgtype, MessageType::Emote(_));
+    let mut msgtype = original.content.msgtype.clone();
+    let (body, html) = match text_mut(&mut msgtype) {
+        Some((body, formatted)) => {
+            let html = match formatted {
+                Some(formatted) if formatted.format == MessageFormat::Html => {
+                    formatted.body.clone()
+                }
+                _ => text_to_html(body),
+            };
+            if is_reply {
+                (strip_reply_fallback(body), strip_html_reply_fallback(&html))
+            } else {
+                (body.clone(), html)
+            }
+        }
+        None => {
+            let body = match &original.content.msgtype {
+                MessageType::Audio(_) => "sent an audio file.",
+                MessageType::File(_) => "sent a file.",
+                MessageType::Image(_) => "sent an image.",
+                MessageType::Location(_) => "sent a location.",
+                MessageType::Video(_) => "sent a video.",
+                _ => "sent a message.",
+            };
+            (body.to_string(), body.to_string())
+        }
+    };
+
+    let sender = &original.sender;
+    let emote_prefix = if emote { "* " } else { "" };
+    let mut quote = String::new();
+    for (i, line) in body.lines().enumerate() {
+        if i == 0 {
+            quote.push_str(&format!("> {}<{}> {}", emote_prefix, sender, line));
+        } else {
+            quote.push_str(&format!("\n> {}", line));
+        }
+    }
+    if quote.is_empty() {
+        quote = format!("> {}<{}>", emote_prefix, sender);
+    }
+
+    let html_quote = format!(
+        "<mx-reply><blockquote>\
+        <a href=\"https://matrix.to/#/{room_id}/{event_id}\">In reply to</a> \
+        {emote_prefix}<a href=\"https://matrix.to/#/{sender}\">{sender}</a><br />{html}\
+        </blockquote></mx-reply>",
+        room_id = original.room_id,
+        event_id = original.event_id,
+        emote_prefix = emote_prefix,
+        sender = sender,
+        html = html,
+    );
+    (quote, html_quote)
+}
+
+/// Remove the quoted lines of a reply fallback from the start of `body`.
+fn strip_reply_fallback(body: &str) -> String {
+    let mut lines = body
+        .lines()
+        .skip_while(|line| line.starts_with('>'))
+        .peekable();
+    if lines.peek() == Some(&"") {
+        lines.next();
+    }
+    lines.collect::<Vec<_>>().join("\n")
+}
+
+/// Remove the `<mx-reply>` element of a reply fallback from the start of `html`.
+fn strip_html_reply_fallback(html: &str) -> String {
+    match html.find("</mx-reply>") {
+        Some(end) => html[end + "</mx-reply>".len()..].to_string(),
+        None => html.to_string(),
+    }
+}
+
+/// Escape `text` for use in HTML, turning newlines into line breaks.
+fn text_to_html(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            '\n' => html.push_str("<br />"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// Get the body and formatted body of text, notice and emote messages.
+fn text_mut(msgtype: &mut MessageType) -> Option<(&mut String, &mut Option<FormattedBody>)> {
+    match msgtype {
+        MessageType::Text(content) => Some((&mut content.body, &mut content.formatted)),
+        MessageType::Notice(content) => Some((&mut content.body, &mut content.formatted)),
+        MessageType::Emote(content) => Some((&mut content.body, &mut content.formatted)),
+        _ => None,
+    }
+}
+
+/// Deserialize the given `events`, skipping events that can't be deserialized.
+pub(crate) fn deserialize_all(
+    events: &[Raw<AnyRoomEvent>],
+) -> impl Iterator<Item = AnyRoomEvent> + '_ {
+    events.iter().filter_map(|event| event.deserialize().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::Mutex;
+
+    use ruma::events::room::message::MessageEventContent;
+    use ruma::events::{AnyMessageEvent, AnyRoomEvent};
+    use ruma::identifiers::{EventId, RoomId, UserId};
+    use ruma::serde::Raw;
+    use serde_json::json;
+    use serde_json::value::to_raw_value;
+
+    use crate::testing::mock_client;
+    use crate::{
+        build_reply, edit_content, fetch_reply, BridgedMessage, BridgedReaction, Edit, Intent,
+        MappingDict, MappingStore, ReactionChange, Redaction, Thread,
+    };
+
+    #[tokio::test]
+    async fn test_redaction() {
+        let store = Mutex::new(MappingDict::new());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let event_id = EventId::try_from("$message:example.org").unwrap();
+        let message = BridgedMessage::new(event_id.clone(), room_id.clone(), String::from("42"));
+        store.insert(message.clone()).await.unwrap();
+
+        let redaction = |redacts: &str| {
+            let event = json!({
+                "type": "m.room.redaction",
+                "event_id": "$redaction:example.org",
+                "room_id": "!room:example.org",
+                "sender": "@alice:example.org",
+                "origin_server_ts": 0,
+                "redacts": redacts,
+                "content": { "reason": "spam" },
+            });
+            Raw::from_json(to_raw_value(&event).unwrap())
+        };
+        let events = vec![
+            redaction("$message:example.org"),
+            redaction("$unknown:example.org"),
+        ];
+
+        let redactions = Redaction::resolve_all(&store, &events).await.unwrap();
+        assert_eq!(
+            redactions,
+            vec![Redaction {
+                event_id: EventId::try_from("$redaction:example.org").unwrap(),
+                sender: UserId::try_from("@alice:example.org").unwrap(),
+                reason: Some(String::from("spam")),
+                message,
+            }]
+        );
+
+        let (client, state) = mock_client();
+        let intent = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        state.respond(
+            "/redact/",
+            200,
+            json!({ "event_id": "$redaction:example.org" }),
+        );
+        intent
+            .redact(&room_id, &event_id, Some("Deleted"))
+            .await
+            .unwrap();
+
+        let request = &state.requests_to("/redact/")[0];
+        assert_eq!(request.method, "PUT");
+        assert!(request
+            .path
+            .contains("/rooms/!room:example.org/redact/$message:example.org/"));
+        assert_eq!(request.body, json!({ "reason": "Deleted" }));
+    }
+
+    #[tokio::test]
+    async fn test_edit() {
+        let store = Mutex::new(MappingDict::new());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let event_id = EventId::try_from("$message:example.org").unwrap();
+        let message = BridgedMessage::new(event_id.clone(), room_id, String::from("42"));
+        store.insert(message.clone()).await.unwrap();
+
+        let new_content = MessageEventContent::text_html("hello", "<b>hello</b>");
+        let content = edit_content(&event_id, new_content);
+        assert_eq!(
+            serde_json::to_value(&content).unwrap(),
+            json!({
+                "msgtype": "m.text",
+                "body": "* hello",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "* <b>hello</b>",
+                "m.new_content": {
+                    "msgtype": "m.text",
+                    "body": "hello",
+                    "format": "org.matrix.custom.html",
+                    "formatted_body": "<b>hello</b>",
+                },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": "$message:example.org" },
+            })
+        );
+
+        let event = json!({
+            "type": "m.room.message",
+            "event_id": "$edit:example.org",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 0,
+            "content": content,
+        });
+        let events = vec![Raw::from_json(to_raw_value(&event).unwrap())];
+        let edits = Edit::resolve_all(&store, &events).await.unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].message, message);
+        let new_content = serde_json::to_value(&edits[0].new_content).unwrap();
+        assert_eq!(new_content["body"], "hello");
+
+        let unrelated = MessageEventContent::text_plain("hi");
+        let event = AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(
+            serde_json::from_value(json!({
+                "type": "m.room.message",
+                "event_id": "$other:example.org",
+                "room_id": "!room:example.org",
+                "sender": "@alice:example.org",
+                "origin_server_ts": 0,
+                "content": unrelated,
+            }))
+            .unwrap(),
+        ));
+        assert!(Edit::resolve(&store, &event).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reactions() {
+        let messages = Mutex::new(MappingDict::new());
+        let reactions = Mutex::new(MappingDict::new());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let event_id = EventId::try_from("$message:example.org").unwrap();
+        let message = BridgedMessage::new(event_id.clone(), room_id.clone(), String::from("42"));
+        messages.insert(message.clone()).await.unwrap();
+
+        let (client, state) = mock_client();
+        let intent = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        state.respond(
+            "/send/",
+            200,
+            json!({ "event_id": "$reaction:example.org" }),
+        );
+        intent.react(&room_id, &event_id, "👍").await.unwrap();
+
+        let request = &state.requests_to("/send/m.reaction/")[0];
+        let content = request.body.clone();
+        assert_eq!(
+            content,
+            json!({
+                "m.relates_to": {
+                    "rel_type": "m.annotation",
+                    "event_id": "$message:example.org",
+                    "key": "👍",
+                },
+            })
+        );
+
+        let reaction = json!({
+            "type": "m.reaction",
+            "event_id": "$reaction:example.org",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 0,
+            "content": content,
+        });
+        let redaction = json!({
+            "type": "m.room.redaction",
+            "event_id": "$redaction:example.org",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 0,
+            "redacts": "$reaction:example.org",
+            "content": {},
+        });
+        let events = vec![
+            Raw::from_json(to_raw_value(&reaction).unwrap()),
+            Raw::from_json(to_raw_value(&redaction).unwrap()),
+        ];
+
+        // the redaction is only resolved once the reaction is bridged.
+        let changes = ReactionChange::resolve_all(&messages, &reactions, &events)
+            .await
+            .unwrap();
+        let alice = UserId::try_from("@alice:example.org").unwrap();
+        let reaction_id = EventId::try_from("$reaction:example.org").unwrap();
+        assert_eq!(
+            changes,
+            vec![ReactionChange::Added {
+                event_id: reaction_id.clone(),
+                sender: alice.clone(),
+                key: String::from("👍"),
+                message,
+            }]
+        );
+
+        let bridged = BridgedReaction::new(
+            reaction_id,
+            room_id,
+            String::from("42|alice|👍"),
+            String::from("42"),
+            String::from("👍"),
+        );
+        reactions.insert(bridged.clone()).await.unwrap();
+        let changes = ReactionChange::resolve_all(&messages, &reactions, &events[1..])
+            .await
+            .unwrap();
+        assert_eq!(
+            changes,
+            vec![ReactionChange::Removed {
+                event_id: EventId::try_from("$redaction:example.org").unwrap(),
+                sender: alice,
+                reaction: bridged,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_thread() {
+        let store = Mutex::new(MappingDict::new());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let root = EventId::try_from("$root:example.org").unwrap();
+        let message = BridgedMessage::new(root.clone(), room_id, String::from("thread"));
+        store.insert(message.clone()).await.unwrap();
+
+        let mut thread = Thread::new(root.clone());
+        thread.in_reply_to = Some(EventId::try_from("$latest:example.org").unwrap());
+        let content = thread.content(&MessageEventContent::text_plain("hi"));
+        let content: serde_json::Value = serde_json::from_str(content.get()).unwrap();
+        assert_eq!(
+            content,
+            json!({
+                "msgtype": "m.text",
+                "body": "hi",
+                "m.relates_to": {
+                    "rel_type": "m.thread",
+                    "event_id": "$root:example.org",
+                    "is_falling_back": true,
+                    "m.in_reply_to": { "event_id": "$latest:example.org" },
+                },
+            })
+        );
+
+        let event = |content| {
+            let event = json!({
+                "type": "m.room.message",
+                "event_id": "$message:example.org",
+                "room_id": "!room:example.org",
+                "sender": "@alice:example.org",
+                "origin_server_ts": 0,
+                "content": content,
+            });
+            Raw::from_json(to_raw_value(&event).unwrap())
+        };
+        let parsed = Thread::from_raw(&event(content)).unwrap();
+        assert_eq!(parsed, thread);
+        assert_eq!(parsed.root_message(&store).await.unwrap(), Some(message));
+
+        let reply = json!({
+            "msgtype": "m.text",
+            "body": "hi",
+            "m.relates_to": { "m.in_reply_to": { "event_id": "$root:example.org" } },
+        });
+        assert!(Thread::from_raw(&event(reply)).is_none());
+        assert!(Thread::from_raw(&event(json!({ "msgtype": "m.text", "body": "hi" }))).is_none());
+    }
+
+    fn message_event(content: serde_json::Value) -> serde_json::Value {
+        json!({
+            "type": "m.room.message",
+            "event_id": "$original:example.org",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 0,
+            "content": content,
+        })
+    }
+
+    #[test]
+    fn test_build_reply() {
+        let original = message_event(json!({
+            "msgtype": "m.text",
+            "body": "> <@bob:example.org> first\n\nsecond\n<third>",
+            "format": "org.matrix.custom.html",
+            "formatted_body": "<mx-reply><blockquote>first</blockquote></mx-reply>second<br /><b>third</b>",
+            "m.relates_to": { "m.in_reply_to": { "event_id": "$first:example.org" } },
+        }));
+        let original = serde_json::from_value(original).unwrap();
+
+        let reply = build_reply(&original, MessageEventContent::text_plain("fourth & fifth"));
+        let reply = serde_json::to_value(&reply).unwrap();
+        assert_eq!(
+            reply,
+            json!({
+                "msgtype": "m.text",
+                "body": "> <@alice:example.org> second\n> <third>\n\nfourth & fifth",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<mx-reply><blockquote>\
+                    <a href=\"https://matrix.to/#/!room:example.org/$original:example.org\">In reply to</a> \
+                    <a href=\"https://matrix.to/#/@alice:example.org\">@alice:example.org</a><br />\
+                    second<br /><b>third</b></blockquote></mx-reply>fourth &amp; fifth",
+                "m.relates_to": { "m.in_reply_to": { "event_id": "$original:example.org" } },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_reply() {
+        let (client, state) = mock_client();
+        let intent = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let event_id = EventId::try_from("$original:example.org").unwrap();
+
+        let path = "/event/";
+        state.respond_once(
+            path,
+            200,
+            message_event(json!({ "msgtype": "m.emote", "body": "waves" })),
+        );
+        let reply = fetch_reply(
+            &intent,
+            &room_id,
+            &event_id,
+            MessageEventContent::text_plain("hi"),
+        )
+        .await
+        .unwrap();
+        let reply = serde_json::to_value(&reply).unwrap();
+        assert_eq!(reply["body"], "> * <@alice:example.org> waves\n\nhi");
+        assert_eq!(state.requests_to(path).len(), 1);
+        assert!(state.requests_to(path)[0]
+            .path
+            .contains("/rooms/!room:example.org/event/$original:example.org"));
+
+        let mut member = message_event(json!({ "membership": "join" }));
+        member["type"] = json!("m.room.member");
+        member["state_key"] = json!("@alice:example.org");
+        state.respond_once(path, 200, member);
+        let reply = fetch_reply(
+            &intent,
+            &room_id,
+            &event_id,
+            MessageEventContent::text_plain("hi"),
+        )
+        .await
+        .unwrap();
+        let reply = serde_json::to_value(&reply).unwrap();
+        assert_eq!(
+            reply,
+            json!({
+                "msgtype": "m.text",
+                "body": "hi",
+                "m.relates_to": { "m.in_reply_to": { "event_id": "$original:example.org" } },
+            })
+        );
+    }
+}
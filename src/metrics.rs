@@ -0,0 +1,169 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Statistics of a `MappingDict` or a `MappingStore`, to see whether a cache is big enough.
+///
+/// A cache that is too small for the items in use keeps evicting items that are needed again
+/// soon after, which shows as a low hit rate along with many evictions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MappingStats {
+    /// The amount of items, including expired items that weren't evicted yet.
+    pub len: usize,
+    /// The maximum amount of items, if bounded.
+    pub capacity: Option<usize>,
+    /// The amount of lookups that found an item.
+    pub hits: u64,
+    /// The amount of lookups that didn't find an item.
+    pub misses: u64,
+    /// The amount of items that were evicted because they expired or to make room for others.
+    pub evictions: u64,
+    /// An estimate of the memory used in bytes, or `None` if the items aren't kept in memory.
+    pub memory: Option<usize>,
+}
+
+impl MappingStats {
+    /// Get the fraction of the lookups that found an item, or `None` if there were no lookups.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            None
+        } else {
+            Some(self.hits as f64 / lookups as f64)
+        }
+    }
+}
+
+/// The counters of the lookups and evictions of a `MappingDict` or a `MappingStore`.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl Counters {
+    /// Count a lookup, which found an item if `hit` is `true`.
+    pub(crate) fn lookup(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count `count` evictions.
+    pub(crate) fn evicted(&self, count: usize) {
+        self.evictions.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Get `MappingStats` with the counted lookups and evictions, and otherwise empty.
+    pub(crate) fn stats(&self) -> MappingStats {
+        MappingStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            ..MappingStats::default()
+        }
+    }
+}
+
+/// The counters belong to the original `MappingDict`, so a clone starts counting from zero.
+impl Clone for Counters {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// The metrics rendered by `render_prometheus`, as their name, type and help.
+const METRICS: [(&str, &str, &str); 6] = [
+    ("items", "gauge", "The amount of items."),
+    ("capacity", "gauge", "The maximum amount of items."),
+    (
+        "hits_total",
+        "counter",
+        "The amount of lookups that found an item.",
+    ),
+    (
+        "misses_total",
+        "counter",
+        "The amount of lookups that didn't find an item.",
+    ),
+    ("evictions_total", "counter", "The amount of evicted items."),
+    ("memory_bytes", "gauge", "An estimate of the memory used."),
+];
+
+/// Get the value of the metric with the given `name` of `stats`, if it has one.
+fn metric(stats: &MappingStats, name: &str) -> Option<u64> {
+    match name {
+        "items" => Some(stats.len as u64),
+        "capacity" => stats.capacity.map(|capacity| capacity as u64),
+        "hits_total" => Some(stats.hits),
+        "misses_total" => Some(stats.misses),
+        "evictions_total" => Some(stats.evictions),
+        "memory_bytes" => stats.memory.map(|memory| memory as u64),
+        _ => None,
+    }
+}
+
+/// Render the `stats` of the stores with the given names in the Prometheus text format, to be
+/// served on the metrics endpoint of the bridge.
+///
+/// Every metric has a `store` label with the name of the store.
+pub fn render_prometheus(stats: &[(&str, MappingStats)]) -> String {
+    let mut out = String::new();
+    for (name, kind, help) in &METRICS {
+        let values: Vec<_> = stats
+            .iter()
+            .filter_map(|(store, stats)| Some((store, metric(stats, name)?)))
+            .collect();
+        if values.is_empty() {
+            continue;
+        }
+
+        let name = format!("matrix_appservice_mapping_{}", name);
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (store, value) in values {
+            let store = store.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(out, "{}{{store=\"{}\"}} {}", name, store, value);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{render_prometheus, MappingStats};
+
+    #[test]
+    fn test_render_prometheus() {
+        let cache = MappingStats {
+            len: 2,
+            capacity: Some(10),
+            hits: 3,
+            misses: 1,
+            evictions: 0,
+            memory: Some(512),
+        };
+        let table = MappingStats {
+            len: 5,
+            ..Default::default()
+        };
+        assert_eq!(cache.hit_rate(), Some(0.75));
+        assert_eq!(table.hit_rate(), None);
+
+        let out = render_prometheus(&[("portals", cache), ("puppets", table)]);
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines[..4],
+            [
+                "# HELP matrix_appservice_mapping_items The amount of items.",
+                "# TYPE matrix_appservice_mapping_items gauge",
+                "matrix_appservice_mapping_items{store=\"portals\"} 2",
+                "matrix_appservice_mapping_items{store=\"puppets\"} 5",
+            ]
+        );
+        assert!(lines.contains(&"matrix_appservice_mapping_capacity{store=\"portals\"} 10"));
+        assert!(!lines.contains(&"matrix_appservice_mapping_capacity{store=\"puppets\"} 0"));
+        assert!(lines.contains(&"matrix_appservice_mapping_hits_total{store=\"portals\"} 3"));
+        assert!(lines.contains(&"# TYPE matrix_appservice_mapping_misses_total counter"));
+        assert!(lines.contains(&"matrix_appservice_mapping_memory_bytes{store=\"portals\"} 512"));
+    }
+}
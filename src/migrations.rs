@@ -0,0 +1,155 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::sqlite::StoreError;
+
+/// A versioned list of SQL scripts bringing a database schema up to date.
+///
+/// The applied versions are kept per `scope` in the `schema_version` table, so the migrations of
+/// this library and those of a bridge can live in the same database. Every migration runs in its
+/// own transaction, together with recording its version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migrations {
+    scope: String,
+    scripts: Vec<(u32, String)>,
+}
+
+impl Migrations {
+    /// Create a new `Migrations` without any scripts, recording versions under `scope`.
+    pub fn new(scope: &str) -> Self {
+        Self {
+            scope: scope.to_string(),
+            scripts: vec![],
+        }
+    }
+
+    /// Add the migration to the given `version` using the SQL script `sql`, returning the current
+    /// `Migrations` to allow method chaining.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `version` isn't higher than the version of the previous migration, or is 0.
+    pub fn add(&mut self, version: u32, sql: &str) -> &mut Self {
+        assert!(
+            version > self.latest_version(),
+            "migration versions must be increasing and start at 1"
+        );
+        self.scripts.push((version, sql.to_string()));
+        self
+    }
+
+    /// Get the scope the versions are recorded under.
+    pub fn scope(&self) -> &str {
+        &self.scope
+    }
+
+    /// Get the version of the last migration, or 0 if there are none.
+    pub fn latest_version(&self) -> u32 {
+        self.scripts
+            .last()
+            .map(|(version, _)| *version)
+            .unwrap_or(0)
+    }
+
+    /// Apply the migrations that weren't applied to the SQLite database `conn` yet.
+    ///
+    /// Returns the versions that were applied. Fails with `StoreError::UnknownSchemaVersion` if
+    /// the database has a newer version than the latest migration, for example after a
+    /// downgrade.
+    pub fn apply(&self, conn: &Connection) -> Result<Vec<u32>, StoreError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS schema_version (
+                scope TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                applied_at INTEGER NOT NULL,
+                PRIMARY KEY (scope, version)
+            )",
+        )?;
+
+        let current: Option<u32> = conn
+            .query_row(
+                "SELECT MAX(version) FROM schema_version WHERE scope = ?1",
+                params![self.scope],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        let current = current.unwrap_or(0);
+        if current > self.latest_version() {
+            return Err(StoreError::UnknownSchemaVersion(current));
+        }
+
+        let mut applied = vec![];
+        for (version, sql) in self.scripts.iter().filter(|(v, _)| *v > current) {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute_batch(sql)?;
+            tx.execute(
+                "INSERT INTO schema_version (scope, version, applied_at) VALUES (?1, ?2, ?3)",
+                params![self.scope, version, now()],
+            )?;
+            tx.commit()?;
+            applied.push(*version);
+        }
+        Ok(applied)
+    }
+}
+
+/// Get the current time in seconds since the Unix epoch.
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use crate::{Migrations, StoreError};
+
+    #[test]
+    fn test_migrations() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut migrations = Migrations::new("bridge");
+        migrations.add(1, "CREATE TABLE users (id TEXT NOT NULL)");
+        assert_eq!(migrations.apply(&conn).unwrap(), vec![1]);
+        assert!(migrations.apply(&conn).unwrap().is_empty());
+
+        migrations
+            .add(2, "ALTER TABLE users ADD COLUMN name TEXT")
+            .add(3, "INSERT INTO users (id, name) VALUES ('a', 'Alice')");
+        assert_eq!(migrations.apply(&conn).unwrap(), vec![2, 3]);
+        let name: String = conn
+            .query_row("SELECT name FROM users", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(name, "Alice");
+
+        // scopes are versioned separately.
+        let mut other = Migrations::new("other");
+        other.add(1, "CREATE TABLE other (id TEXT NOT NULL)");
+        assert_eq!(other.apply(&conn).unwrap(), vec![1]);
+
+        // a failing migration is rolled back.
+        let mut failing = Migrations::new("other");
+        failing.add(1, "").add(
+            2,
+            "CREATE TABLE more (id TEXT); INSERT INTO missing VALUES (1)",
+        );
+        assert!(failing.apply(&conn).is_err());
+        assert!(conn.prepare("SELECT * FROM more").is_err());
+
+        let old = Migrations::new("bridge");
+        assert!(matches!(
+            old.apply(&conn),
+            Err(StoreError::UnknownSchemaVersion(3))
+        ));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_decreasing_version() {
+        Migrations::new("bridge").add(2, "").add(1, "");
+    }
+}
@@ -0,0 +1,336 @@
+use std::any::Any;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use crate::mappingdict::{Mappable, MappingDict, MappingId};
+
+/// A secondary index of a `MultiMappingDict`, independent of the key type.
+trait AnyIndex<V>: Send + Sync {
+    fn insert(&mut self, item: &V);
+    fn remove(&mut self, item: &V);
+    fn as_any(&self) -> &dyn Any;
+}
+
+type KeyFn<V, K> = Box<dyn Fn(&V) -> Option<K> + Send + Sync>;
+
+/// A secondary index, mapping keys of type `K` to the Matrix IDs of the items having that key.
+struct Index<V: Mappable, K> {
+    key: KeyFn<V, K>,
+    items: HashMap<K, Vec<V::MatrixType>>,
+}
+
+impl<V, K> AnyIndex<V> for Index<V, K>
+where
+    V: Mappable + 'static,
+    V::MatrixType: Send + Sync + 'static,
+    K: Eq + Hash + Send + Sync + 'static,
+{
+    fn insert(&mut self, item: &V) {
+        if let Some(key) = (self.key)(item) {
+            self.items
+                .entry(key)
+                .or_default()
+                .push(item.as_matrix().to_owned());
+        }
+    }
+
+    fn remove(&mut self, item: &V) {
+        let key = match (self.key)(item) {
+            Some(key) => key,
+            None => return,
+        };
+
+        if let Some(ids) = self.items.get_mut(&key) {
+            ids.retain(|id| Borrow::<V::MatrixReference>::borrow(id) != item.as_matrix());
+            if ids.is_empty() {
+                self.items.remove(&key);
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A handle to a secondary index with keys of type `K`, used to look up items in the
+/// `MultiMappingDict` that created it.
+pub struct SecondaryIndex<K> {
+    index: usize,
+    _key: PhantomData<fn() -> K>,
+}
+
+impl<K> Clone for SecondaryIndex<K> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K> Copy for SecondaryIndex<K> {}
+
+/// A `MappingDict` with additional, user defined, secondary indexes.
+///
+/// This is useful when items need to be found by more than a Matrix and an external ID, for
+/// example bridged messages that have to be found by the thread they're in. Secondary keys don't
+/// have to be unique, so looking up a key gives all items having it.
+pub struct MultiMappingDict<V: Mappable> {
+    dict: MappingDict<V>,
+    indexes: Vec<Box<dyn AnyIndex<V>>>,
+}
+
+impl<V> MultiMappingDict<V>
+where
+    V: Mappable + 'static,
+    V::MatrixType: Send + Sync + 'static,
+{
+    /// Create a new empty `MultiMappingDict` without secondary indexes.
+    pub fn new() -> Self {
+        Self {
+            dict: MappingDict::new(),
+            indexes: vec![],
+        }
+    }
+
+    /// Add a secondary index, using `key` to get the key of an item, or `None` if the item
+    /// shouldn't be in the index. The items already in this `MultiMappingDict` are added to the
+    /// index.
+    ///
+    /// Returns the handle used to look up items using the index.
+    pub fn add_index<K, F>(&mut self, key: F) -> SecondaryIndex<K>
+    where
+        K: Eq + Hash + Send + Sync + 'static,
+        F: Fn(&V) -> Option<K> + Send + Sync + 'static,
+    {
+        let mut index = Index {
+            key: Box::new(key),
+            items: HashMap::new(),
+        };
+        for item in self.dict.iter() {
+            index.insert(item);
+        }
+
+        self.indexes.push(Box::new(index));
+        SecondaryIndex {
+            index: self.indexes.len() - 1,
+            _key: PhantomData,
+        }
+    }
+
+    /// Inserts the given `item`, replacing any items that have the same Matrix ID or external ID.
+    ///
+    /// Returns the replaced items.
+    pub fn insert(&mut self, item: V) -> Vec<V> {
+        let replaced = self
+            .remove(MappingId::Matrix(item.as_matrix()))
+            .into_iter()
+            .chain(self.remove(MappingId::External(item.as_external())))
+            .collect();
+
+        for index in &mut self.indexes {
+            index.insert(&item);
+        }
+        self.dict.insert(item);
+
+        replaced
+    }
+
+    /// Returns a reference to the item associated with the given `identifier`, or `None` if no
+    /// such item exists.
+    pub fn get(
+        &self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+    ) -> Option<&V> {
+        self.dict.get(identifier)
+    }
+
+    /// Returns whether or not this `MultiMappingDict` contains an item associated with the given
+    /// `identifier`.
+    pub fn has(&self, identifier: MappingId<V::ExternalReference, V::MatrixReference>) -> bool {
+        self.dict.has(identifier)
+    }
+
+    /// Returns all items that have the given `key` in the secondary `index`.
+    ///
+    /// # Panics
+    /// Panics if `index` was created by another `MultiMappingDict`.
+    pub fn get_by<K>(&self, index: &SecondaryIndex<K>, key: &K) -> Vec<&V>
+    where
+        K: Eq + Hash + Send + Sync + 'static,
+    {
+        let index = self
+            .indexes
+            .get(index.index)
+            .and_then(|index| index.as_any().downcast_ref::<Index<V, K>>())
+            .expect("secondary index should belong to this MultiMappingDict");
+
+        match index.items.get(key) {
+            None => vec![],
+            Some(ids) => ids
+                .iter()
+                .filter_map(|id| self.dict.get(MappingId::Matrix(id.borrow())))
+                .collect(),
+        }
+    }
+
+    /// Run `f` with a mutable reference to the item associated with the given `identifier`,
+    /// updating the secondary indexes afterwards.
+    ///
+    /// `f` must not change the Matrix ID or external ID of the item.
+    pub fn update<R, F>(
+        &mut self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+        f: F,
+    ) -> Option<R>
+    where
+        F: FnOnce(&mut V) -> R,
+    {
+        let item = self.dict.get(identifier.clone())?;
+        for index in &mut self.indexes {
+            index.remove(item);
+        }
+
+        let item = self.dict.get_mut(identifier)?;
+        let res = f(item);
+        for index in &mut self.indexes {
+            index.insert(item);
+        }
+
+        Some(res)
+    }
+
+    /// If this `MultiMappingDict` contains an item associated with the given `identifier`,
+    /// remove it and return it.
+    pub fn remove(
+        &mut self,
+        identifier: MappingId<V::ExternalReference, V::MatrixReference>,
+    ) -> Option<V> {
+        let item = self.dict.remove(identifier)?;
+        for index in &mut self.indexes {
+            index.remove(&item);
+        }
+        Some(item)
+    }
+
+    /// Remove all items that have the given `key` in the secondary `index`, returning them.
+    ///
+    /// # Panics
+    /// Panics if `index` was created by another `MultiMappingDict`.
+    pub fn remove_by<K>(&mut self, index: &SecondaryIndex<K>, key: &K) -> Vec<V>
+    where
+        K: Eq + Hash + Send + Sync + 'static,
+    {
+        let ids: Vec<_> = self
+            .get_by(index, key)
+            .into_iter()
+            .map(|item| item.as_matrix().to_owned())
+            .collect();
+
+        ids.iter()
+            .filter_map(|id| self.remove(MappingId::Matrix(id.borrow())))
+            .collect()
+    }
+
+    /// Get an iterator over references of the items contained in this `MultiMappingDict`.
+    pub fn iter(&'_ self) -> std::slice::Iter<'_, V> {
+        self.dict.iter()
+    }
+
+    /// Get the `MappingDict` containing the items.
+    pub fn dict(&self) -> &MappingDict<V> {
+        &self.dict
+    }
+}
+
+impl<V> Default for MultiMappingDict<V>
+where
+    V: Mappable + 'static,
+    V::MatrixType: Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Mappable, MappingId, MultiMappingDict};
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Message {
+        event_id: String,
+        message_id: u64,
+        thread: Option<u64>,
+    }
+
+    impl Message {
+        fn new(event_id: &str, message_id: u64, thread: Option<u64>) -> Self {
+            Self {
+                event_id: event_id.to_string(),
+                message_id,
+                thread,
+            }
+        }
+    }
+
+    impl Mappable for Message {
+        type MatrixReference = str;
+        type MatrixType = String;
+        type ExternalReference = u64;
+        type ExternalType = u64;
+
+        fn as_matrix(&self) -> &str {
+            &self.event_id
+        }
+        fn into_matrix(self) -> String {
+            self.event_id
+        }
+        fn as_external(&self) -> &u64 {
+            &self.message_id
+        }
+        fn into_external(self) -> u64 {
+            self.message_id
+        }
+        fn into_split(self) -> (String, u64) {
+            (self.event_id, self.message_id)
+        }
+    }
+
+    fn sorted(mut items: Vec<&Message>) -> Vec<&str> {
+        items.sort_by_key(|item| item.message_id);
+        items.into_iter().map(|item| item.as_matrix()).collect()
+    }
+
+    #[test]
+    fn test_secondary_index() {
+        let mut dict = MultiMappingDict::new();
+        dict.insert(Message::new("$a", 1, None));
+        dict.insert(Message::new("$b", 2, Some(1)));
+
+        let threads = dict.add_index(|message: &Message| message.thread);
+        dict.insert(Message::new("$c", 3, Some(1)));
+        dict.insert(Message::new("$d", 4, Some(2)));
+
+        assert_eq!(sorted(dict.get_by(&threads, &1)), vec!["$b", "$c"]);
+        assert_eq!(sorted(dict.get_by(&threads, &3)), Vec::<&str>::new());
+        assert_eq!(
+            dict.get(MappingId::External(&4)),
+            Some(&Message::new("$d", 4, Some(2)))
+        );
+
+        // replacing and updating items keeps the index consistent.
+        dict.insert(Message::new("$b", 5, None));
+        dict.update(MappingId::Matrix("$d"), |message| message.thread = Some(1));
+        assert_eq!(sorted(dict.get_by(&threads, &1)), vec!["$c", "$d"]);
+        assert!(dict.get_by(&threads, &2).is_empty());
+
+        let removed = dict.remove_by(&threads, &1);
+        assert_eq!(removed.len(), 2);
+        assert!(!dict.has(MappingId::Matrix("$c")));
+        assert_eq!(dict.iter().count(), 2);
+
+        let message_ids = dict.add_index(|message: &Message| Some(message.message_id % 2));
+        assert_eq!(sorted(dict.get_by(&message_ids, &1)), vec!["$a", "$b"]);
+    }
+}
@@ -0,0 +1,1185 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use ruma::api::client::error::ErrorKind;
+use ruma::api::client::r0::alias::get_alias;
+use ruma::api::client::r0::room::create_room::{self, CreationContent, RoomPreset};
+use ruma::api::client::r0::room::Visibility;
+use ruma::events::room::create::RoomType;
+use ruma::events::room::message::MessageEventContent;
+use ruma::events::room::power_levels::PowerLevelsEventContent;
+use ruma::events::room::tombstone::TombstoneEventContent;
+use ruma::events::{AnyInitialStateEvent, AnyMessageEventContent, AnyRoomEvent, AnyStateEvent};
+use ruma::identifiers::{RoomAliasId, RoomId, RoomVersionId, ServerName, UserId};
+use ruma::Int;
+use ruma_client::HttpClient;
+use serde::{Deserialize, Serialize};
+use serde_json::value::to_raw_value;
+use thiserror::Error;
+
+use crate::bulkjoin::{BulkJoin, BulkJoinReport};
+use crate::client::AppserviceClient;
+use crate::intent::{Intent, IntentError};
+use crate::mappingdict::{Mappable, MappingDict, MappingId};
+use crate::puppet::{escape_localpart, Puppet, PuppetError, PuppetManager};
+use crate::store::MappingStore;
+
+/// A Matrix room bridged to a channel on the external service.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Portal {
+    room_id: RoomId,
+    external_id: String,
+    alias: Option<RoomAliasId>,
+}
+
+impl Portal {
+    /// Create a new `Portal`, bridging the room with the given `room_id` to the external channel
+    /// `external_id`.
+    pub fn new(room_id: RoomId, external_id: String, alias: Option<RoomAliasId>) -> Self {
+        Self {
+            room_id,
+            external_id,
+            alias,
+        }
+    }
+
+    /// Get the ID of the Matrix room of this portal.
+    pub fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
+    /// Get the ID of the external channel of this portal.
+    pub fn external_id(&self) -> &str {
+        &self.external_id
+    }
+
+    /// Get the alias of the Matrix room of this portal, if it has one.
+    pub fn alias(&self) -> Option<&RoomAliasId> {
+        self.alias.as_ref()
+    }
+}
+
+impl Mappable for Portal {
+    type MatrixReference = RoomId;
+    type MatrixType = RoomId;
+    type ExternalReference = str;
+    type ExternalType = String;
+
+    fn as_matrix(&self) -> &RoomId {
+        &self.room_id
+    }
+    fn into_matrix(self) -> RoomId {
+        self.room_id
+    }
+    fn as_external(&self) -> &str {
+        &self.external_id
+    }
+    fn into_external(self) -> String {
+        self.external_id
+    }
+    fn into_split(self) -> (RoomId, String) {
+        (self.room_id, self.external_id)
+    }
+}
+
+/// Options for creating the room of a new portal.
+#[derive(Debug, Clone, Default)]
+pub struct RoomOptions {
+    /// The name of the room.
+    pub name: Option<String>,
+    /// The topic of the room.
+    pub topic: Option<String>,
+    /// The type of the room, set in its creation content.
+    pub room_type: Option<RoomType>,
+    /// The version of the room, or the default of the homeserver if `None`.
+    pub room_version: Option<RoomVersionId>,
+    /// The preset of the room, setting its join rules and history visibility.
+    pub preset: Option<RoomPreset>,
+    /// Whether the room is shown in the room directory.
+    pub visibility: Visibility,
+    /// Whether the invites to the room are direct chat invites.
+    pub is_direct: bool,
+    /// The users to invite to the room.
+    pub invite: Vec<UserId>,
+    /// The power levels of users other than the bot, which always gets power level 100.
+    pub power_levels: BTreeMap<UserId, Int>,
+    /// Additional state events to send to the room when creating it.
+    pub initial_state: Vec<AnyInitialStateEvent>,
+}
+
+/// Options for tearing down a portal, see `PortalManager::teardown`.
+#[derive(Debug, Clone, Default)]
+pub struct TeardownOptions {
+    /// A notice the bot sends to the room before anyone leaves, telling the Matrix users why.
+    pub notice: Option<String>,
+    /// The room the Matrix users should go to instead, announced with an `m.room.tombstone`
+    /// event with the notice as its body.
+    pub replacement: Option<RoomId>,
+    /// Whether the bot kicks the puppets instead of making them leave.
+    pub kick: bool,
+    /// The reason given when kicking the puppets.
+    pub reason: Option<String>,
+    /// Whether the bot stays in the room, for example to keep moderating it.
+    pub keep_bot: bool,
+}
+
+/// An error from a `PortalManager`.
+#[derive(Debug, Error)]
+pub enum PortalError<E, S> {
+    /// A request to the homeserver failed.
+    #[error("{0}")]
+    Intent(IntentError<E>),
+    /// Loading or saving a portal failed.
+    #[error("loading or saving a portal failed: {0}")]
+    Store(S),
+    /// The localpart generated for an external channel gives an invalid room alias.
+    #[error("invalid room alias generated for the portal: {0}")]
+    InvalidAlias(ruma::identifiers::Error),
+}
+
+impl<E, S> From<IntentError<E>> for PortalError<E, S> {
+    fn from(err: IntentError<E>) -> Self {
+        PortalError::Intent(err)
+    }
+}
+
+/// The outcome of following the upgrade of a portal room, see `PortalManager::follow_upgrade`.
+#[derive(Debug)]
+pub struct RoomUpgrade<E, S> {
+    /// The room that was upgraded.
+    pub old_room_id: RoomId,
+    /// The portal, which is now in the replacement room.
+    pub portal: Portal,
+    /// The puppets that joined the replacement room.
+    pub joined: Vec<UserId>,
+    /// The puppets that failed to join the replacement room.
+    pub errors: Vec<(UserId, PuppetError<E, S>)>,
+}
+
+/// The outcome of tearing down a portal, see `PortalManager::teardown`.
+#[derive(Debug)]
+pub struct PortalTeardown<E, S> {
+    /// The portal, which is removed from the store.
+    pub portal: Portal,
+    /// The puppets that left the room.
+    pub left: Vec<UserId>,
+    /// The puppets that failed to leave the room.
+    pub errors: Vec<(UserId, PuppetError<E, S>)>,
+}
+
+type LocalpartFn = Box<dyn Fn(&str) -> String + Send + Sync>;
+type UpgradeFn = Box<dyn Fn(&RoomId, &Portal) + Send + Sync>;
+
+/// Manages the portals of an application service, the Matrix rooms bridged to channels on the
+/// external service.
+///
+/// Rooms are created by the bot user of the application service, with an alias in the alias
+/// namespace of the registration. When a portal isn't known yet but its alias exists, for example
+/// because the store was lost, the existing room is reused.
+pub struct PortalManager<C, S = Mutex<MappingDict<Portal>>> {
+    bot: Intent<C>,
+    server_name: Box<ServerName>,
+    alias_localpart: LocalpartFn,
+    network_id: Option<String>,
+    store: S,
+    on_upgrade: Vec<UpgradeFn>,
+}
+
+impl<C, S> PortalManager<C, S>
+where
+    C: HttpClient,
+    S: MappingStore<Portal>,
+{
+    /// Create a new `PortalManager`, creating rooms using the `bot` of the application service and
+    /// keeping the portals in `store`.
+    ///
+    /// The localpart of the alias of a portal is `prefix` followed by the escaped external ID, see
+    /// `escape_localpart`. The `prefix` should match the alias namespace of the registration.
+    pub fn new(bot: Intent<C>, server_name: Box<ServerName>, prefix: &str, store: S) -> Self {
+        let prefix = prefix.to_string();
+        Self {
+            bot,
+            server_name,
+            alias_localpart: Box::new(move |id| format!("{}{}", prefix, escape_localpart(id))),
+            network_id: None,
+            store,
+            on_upgrade: vec![],
+        }
+    }
+
+    /// Publish portals in the room directory of the application service for the network
+    /// `network_id`, instead of in the room directory of the homeserver.
+    pub fn set_network_id(&mut self, network_id: Option<String>) {
+        self.network_id = network_id;
+    }
+
+    /// Use `f` to generate the localpart of the alias of the portal of an external channel,
+    /// instead of the prefix given to `new`.
+    pub fn set_alias_localpart<F>(&mut self, f: F)
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.alias_localpart = Box::new(f);
+    }
+
+    /// Call `f` with the ID of the old room and the moved portal after a portal has been moved
+    /// to the replacement of its upgraded room.
+    pub fn on_upgrade<F>(&mut self, f: F)
+    where
+        F: Fn(&RoomId, &Portal) + Send + Sync + 'static,
+    {
+        self.on_upgrade.push(Box::new(f));
+    }
+
+    /// Get the `Intent` of the bot user creating the rooms.
+    pub fn bot(&self) -> &Intent<C> {
+        &self.bot
+    }
+
+    /// Send the requests of the bot with the settings of the given `AppserviceClient`, or without
+    /// them if it's `None`.
+    pub fn set_appservice_client(&mut self, client: Option<Arc<AppserviceClient<C>>>) {
+        self.bot.set_appservice_client(client);
+    }
+
+    /// Get the store containing the portals.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Get the alias of the portal of the external channel `external_id`.
+    pub fn alias_for(&self, external_id: &str) -> Result<RoomAliasId, ruma::identifiers::Error> {
+        let localpart = (self.alias_localpart)(external_id);
+        RoomAliasId::try_from(format!("#{}:{}", localpart, self.server_name))
+    }
+
+    /// Get the portal of the external channel `external_id`, if it exists.
+    pub async fn get(&self, external_id: &str) -> Result<Option<Portal>, S::Error> {
+        self.store.get(MappingId::External(external_id)).await
+    }
+
+    /// Get the portal of the room with the given `room_id`, if it exists.
+    pub async fn get_by_room(&self, room_id: &RoomId) -> Result<Option<Portal>, S::Error> {
+        self.store.get(MappingId::Matrix(room_id)).await
+    }
+
+    /// Get all known portals.
+    pub async fn portals(&self) -> Result<Vec<Portal>, S::Error> {
+        self.store.items().await
+    }
+
+    /// Forget the portal of the external channel `external_id`, returning it. The room itself
+    /// is left as is.
+    pub async fn remove(&self, external_id: &str) -> Result<Option<Portal>, S::Error> {
+        self.store.remove(MappingId::External(external_id)).await
+    }
+
+    /// Get the room the given `alias` points to, if it exists.
+    async fn resolve_alias(
+        &self,
+        alias: &RoomAliasId,
+    ) -> Result<Option<RoomId>, IntentError<C::Error>> {
+        match self.bot.send(get_alias::Request::new(alias)).await {
+            Ok(response) => Ok(Some(response.room_id)),
+            Err(err) if err.kind() == Some(&ErrorKind::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Get the portal of the external channel `external_id`, creating its room using the given
+    /// `options` if it doesn't exist.
+    ///
+    /// If the portal isn't known but its alias points to a room, that room is used instead of
+    /// creating a new one, and `options` are ignored.
+    pub async fn ensure_portal(
+        &self,
+        external_id: &str,
+        options: &RoomOptions,
+    ) -> Result<Portal, PortalError<C::Error, S::Error>> {
+        let portal = self
+            .store
+            .get(MappingId::External(external_id))
+            .await
+            .map_err(PortalError::Store)?;
+        if let Some(portal) = portal {
+            return Ok(portal);
+        }
+
+        let alias = self
+            .alias_for(external_id)
+            .map_err(PortalError::InvalidAlias)?;
+        let room_id = match self.resolve_alias(&alias).await? {
+            Some(room_id) => {
+                self.bot.join(&room_id).await?;
+                room_id
+            }
+            None => self.create_room(alias.alias(), options).await?,
+        };
+
+        let portal = Portal::new(room_id, external_id.to_string(), Some(alias));
+        self.store
+            .insert(portal.clone())
+            .await
+            .map_err(PortalError::Store)?;
+        Ok(portal)
+    }
+
+    /// Get the portal of the external channel `external_id` like `ensure_portal`, and make the
+    /// puppets of the external users with the given `external_ids` join its room using `bulk`.
+    ///
+    /// The puppets are invited by the bot. Puppets that fail to join are returned in the report,
+    /// together with the puppets that joined.
+    pub async fn ensure_portal_with_puppets<P>(
+        &self,
+        external_id: &str,
+        options: &RoomOptions,
+        bulk: &BulkJoin<'_, C, P>,
+        external_ids: &[String],
+    ) -> Result<(Portal, BulkJoinReport<C::Error, P::Error>), PortalError<C::Error, S::Error>>
+    where
+        C: Clone,
+        P: MappingStore<Puppet>,
+    {
+        let portal = self.ensure_portal(external_id, options).await?;
+        let report = bulk
+            .join_inviting(portal.room_id(), external_ids, Some(&self.bot))
+            .await;
+        Ok((portal, report))
+    }
+
+    /// Get the direct chat portal `external_id` between the puppet `ghost` and the Matrix user
+    /// with the given `user_id`, creating its room if it doesn't exist.
+    ///
+    /// The room is created by `ghost`, without an alias, inviting the user as a direct chat. If
+    /// the user is double puppeted, `double_puppet` is the `Intent` of their own account, which
+    /// is used to accept the invite and to add the room to the `m.direct` account data of the
+    /// user.
+    pub async fn ensure_dm(
+        &self,
+        external_id: &str,
+        ghost: &Intent<C>,
+        user_id: &UserId,
+        double_puppet: Option<&Intent<C>>,
+    ) -> Result<Portal, PortalError<C::Error, S::Error>> {
+        let portal = self
+            .store
+            .get(MappingId::External(external_id))
+            .await
+            .map_err(PortalError::Store)?;
+        if let Some(portal) = portal {
+            return Ok(portal);
+        }
+
+        let invite = [user_id.clone()];
+        let mut request = create_room::Request::new();
+        request.invite = &invite;
+        request.is_direct = true;
+        request.preset = Some(RoomPreset::TrustedPrivateChat);
+        let response = ghost.send(request).await?;
+        let room_id = response.room_id;
+
+        if let Some(double_puppet) = double_puppet {
+            double_puppet.join(&room_id).await?;
+            double_puppet
+                .add_direct_room(ghost.user_id(), &room_id)
+                .await?;
+        }
+
+        let portal = Portal::new(room_id, external_id.to_string(), None);
+        self.store
+            .insert(portal.clone())
+            .await
+            .map_err(PortalError::Store)?;
+        Ok(portal)
+    }
+
+    /// Create a room with the given `alias_localpart` and `options`, giving the bot admin.
+    async fn create_room(
+        &self,
+        alias_localpart: &str,
+        options: &RoomOptions,
+    ) -> Result<RoomId, IntentError<C::Error>> {
+        let mut creation_content = CreationContent::new();
+        creation_content.room_type = options.room_type.clone();
+
+        let mut power_levels = PowerLevelsEventContent::default();
+        power_levels.users = options.power_levels.clone();
+        power_levels
+            .users
+            .insert(self.bot.user_id().clone(), Int::from(100));
+
+        let mut request = create_room::Request::new();
+        request.creation_content = creation_content;
+        request.initial_state = &options.initial_state;
+        request.invite = &options.invite;
+        request.is_direct = options.is_direct;
+        request.name = options.name.as_deref();
+        request.topic = options.topic.as_deref();
+        request.power_level_content_override = Some(power_levels.into());
+        request.preset = options.preset.clone();
+        request.room_alias_name = Some(alias_localpart);
+        request.room_version = options.room_version.as_ref();
+        request.visibility = options.visibility.clone();
+
+        let response = self.bot.send(request).await?;
+        Ok(response.room_id)
+    }
+
+    /// Publish the room of the portal of the external channel `external_id` in the room
+    /// directory if `published` is true, or remove it from the directory otherwise, so public
+    /// channels can be discovered.
+    ///
+    /// The room is published in the directory of the application service if a network ID is set
+    /// using `set_network_id`. Returns the portal, or `None` if it doesn't exist.
+    pub async fn set_published(
+        &self,
+        external_id: &str,
+        published: bool,
+    ) -> Result<Option<Portal>, PortalError<C::Error, S::Error>> {
+        let portal = self
+            .store
+            .get(MappingId::External(external_id))
+            .await
+            .map_err(PortalError::Store)?;
+        let portal = match portal {
+            Some(portal) => portal,
+            None => return Ok(None),
+        };
+
+        let visibility = if published {
+            Visibility::Public
+        } else {
+            Visibility::Private
+        };
+        match &self.network_id {
+            Some(network_id) => {
+                self.bot
+                    .set_appservice_room_visibility(network_id, &portal.room_id, visibility)
+                    .await?
+            }
+            None => {
+                self.bot
+                    .set_room_visibility(&portal.room_id, visibility)
+                    .await?
+            }
+        }
+        Ok(Some(portal))
+    }
+
+    /// Move `portal` to the room `replacement`, replacing the portal in the old room, and notify
+    /// the `on_upgrade` callbacks.
+    async fn move_portal(
+        &self,
+        mut portal: Portal,
+        replacement: &RoomId,
+    ) -> Result<Portal, PortalError<C::Error, S::Error>> {
+        let old_room_id = std::mem::replace(&mut portal.room_id, replacement.clone());
+        // replaces the old portal, since the external ID is the same.
+        self.store
+            .insert(portal.clone())
+            .await
+            .map_err(PortalError::Store)?;
+        for f in &self.on_upgrade {
+            f(&old_room_id, &portal);
+        }
+        Ok(portal)
+    }
+
+    /// Handle the room with the given `room_id` being replaced by the room `replacement`, as
+    /// announced by an `m.room.tombstone` event.
+    ///
+    /// If `room_id` is a portal, the bot joins the new room and the portal is moved to it, which
+    /// is returned. Otherwise nothing happens and `None` is returned. Use `follow_upgrade` to
+    /// move the puppets in the room as well.
+    pub async fn handle_tombstone(
+        &self,
+        room_id: &RoomId,
+        replacement: &RoomId,
+    ) -> Result<Option<Portal>, PortalError<C::Error, S::Error>> {
+        let portal = self
+            .store
+            .get(MappingId::Matrix(room_id))
+            .await
+            .map_err(PortalError::Store)?;
+        let portal = match portal {
+            Some(portal) => portal,
+            None => return Ok(None),
+        };
+
+        self.bot.join(replacement).await?;
+        self.move_portal(portal, replacement).await.map(Some)
+    }
+
+    /// Follow the upgrade of the room with the given `room_id` to the room `replacement`, as
+    /// announced by an `m.room.tombstone` event.
+    ///
+    /// If `room_id` is a portal, the bot and every puppet of `puppets` in the old room join the
+    /// new room, inviting a puppet first if it can't join, and only then the portal is moved to
+    /// the new room. That way the upgrade is followed again when the tombstone is handled again
+    /// after a failure. Puppets that fail to join are returned in the report, and don't stop the
+    /// upgrade. Otherwise nothing happens and `None` is returned.
+    pub async fn follow_upgrade<P>(
+        &self,
+        room_id: &RoomId,
+        replacement: &RoomId,
+        puppets: &PuppetManager<C, P>,
+    ) -> Result<Option<RoomUpgrade<C::Error, S::Error>>, PortalError<C::Error, S::Error>>
+    where
+        C: Clone,
+        P: MappingStore<Puppet, Error = S::Error>,
+    {
+        let portal = self
+            .store
+            .get(MappingId::Matrix(room_id))
+            .await
+            .map_err(PortalError::Store)?;
+        let portal = match portal {
+            Some(portal) => portal,
+            None => return Ok(None),
+        };
+        self.bot.join(replacement).await?;
+
+        let mut joined = vec![];
+        let mut errors = vec![];
+        for puppet in puppets.puppets().await.map_err(PortalError::Store)? {
+            if !puppet.is_joined(room_id) {
+                continue;
+            }
+            let user_id = puppet.user_id().clone();
+            if let Err(err) = self.join_puppet(puppets, &puppet, replacement).await {
+                errors.push((user_id, err));
+                continue;
+            }
+            puppets
+                .set_joined(&user_id, room_id, false)
+                .await
+                .map_err(PortalError::Store)?;
+            puppets
+                .set_joined(&user_id, replacement, true)
+                .await
+                .map_err(PortalError::Store)?;
+            joined.push(user_id);
+        }
+
+        let portal = self.move_portal(portal, replacement).await?;
+        Ok(Some(RoomUpgrade {
+            old_room_id: room_id.clone(),
+            portal,
+            joined,
+            errors,
+        }))
+    }
+
+    /// Make `puppet` join the room with the given `room_id`, inviting it as the bot if it isn't
+    /// allowed to join.
+    async fn join_puppet<P>(
+        &self,
+        puppets: &PuppetManager<C, P>,
+        puppet: &Puppet,
+        room_id: &RoomId,
+    ) -> Result<(), PuppetError<C::Error, P::Error>>
+    where
+        C: Clone,
+        P: MappingStore<Puppet>,
+    {
+        let intent = puppets.puppet_for(puppet.external_id()).await?;
+        match intent.join(room_id).await {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == Some(&ErrorKind::Forbidden) => {
+                self.bot.invite(room_id, puppet.user_id()).await?;
+                intent.join(room_id).await?;
+                Ok(())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Tear down the portal of the external channel `external_id`, for example because the
+    /// channel was deleted.
+    ///
+    /// The notice and tombstone of `options` are sent first, then every puppet of `puppets` in
+    /// the room leaves it or is kicked, the alias of the portal is removed and the bot leaves the
+    /// room. Only then the portal is removed from the store, so a teardown that failed can be
+    /// tried again. Puppets that fail to leave are returned in the report, and don't stop the
+    /// teardown. Returns `None` if the portal doesn't exist.
+    pub async fn teardown<P>(
+        &self,
+        external_id: &str,
+        puppets: &PuppetManager<C, P>,
+        options: &TeardownOptions,
+    ) -> Result<Option<PortalTeardown<C::Error, S::Error>>, PortalError<C::Error, S::Error>>
+    where
+        C: Clone,
+        P: MappingStore<Puppet, Error = S::Error>,
+    {
+        let portal = self
+            .store
+            .get(MappingId::External(external_id))
+            .await
+            .map_err(PortalError::Store)?;
+        let portal = match portal {
+            Some(portal) => portal,
+            None => return Ok(None),
+        };
+        let room_id = &portal.room_id;
+
+        if let Some(notice) = &options.notice {
+            let content = MessageEventContent::notice_plain(notice);
+            self.bot
+                .send_message(room_id, &AnyMessageEventContent::RoomMessage(content))
+                .await?;
+        }
+        if let Some(replacement) = &options.replacement {
+            let body = options.notice.clone().unwrap_or_default();
+            let content = TombstoneEventContent::new(body, replacement.clone());
+            let content = to_raw_value(&content).expect("tombstones serialize to JSON");
+            self.bot
+                .send_state_raw(room_id, "m.room.tombstone", "", content)
+                .await?;
+        }
+
+        let mut left = vec![];
+        let mut errors = vec![];
+        for puppet in puppets.puppets().await.map_err(PortalError::Store)? {
+            if !puppet.is_joined(room_id) {
+                continue;
+            }
+            let user_id = puppet.user_id().clone();
+            if let Err(err) = self.remove_puppet(puppets, &puppet, room_id, options).await {
+                errors.push((user_id, err));
+                continue;
+            }
+            puppets
+                .set_joined(&user_id, room_id, false)
+                .await
+                .map_err(PortalError::Store)?;
+            left.push(user_id);
+        }
+
+        if let Some(alias) = &portal.alias {
+            match self.bot.delete_alias(alias).await {
+                Err(err) if err.kind() != Some(&ErrorKind::NotFound) => return Err(err.into()),
+                _ => {}
+            }
+        }
+        if !options.keep_bot {
+            self.bot.leave(room_id).await?;
+        }
+
+        self.store
+            .remove(MappingId::External(external_id))
+            .await
+            .map_err(PortalError::Store)?;
+        Ok(Some(PortalTeardown {
+            portal,
+            left,
+            errors,
+        }))
+    }
+
+    /// Tear down the portals of the external channels `external_ids` one after another, see
+    /// `teardown`.
+    ///
+    /// Returns the outcome for every portal, in the order of `external_ids`. A portal that fails
+    /// to be torn down doesn't stop the others.
+    pub async fn teardown_all<P>(
+        &self,
+        external_ids: &[String],
+        puppets: &PuppetManager<C, P>,
+        options: &TeardownOptions,
+    ) -> Vec<Result<Option<PortalTeardown<C::Error, S::Error>>, PortalError<C::Error, S::Error>>>
+    where
+        C: Clone,
+        P: MappingStore<Puppet, Error = S::Error>,
+    {
+        let mut results = Vec::with_capacity(external_ids.len());
+        for external_id in external_ids {
+            results.push(self.teardown(external_id, puppets, options).await);
+        }
+        results
+    }
+
+    /// Make `puppet` leave the room with the given `room_id`, or kick it as the bot if `options`
+    /// say so.
+    async fn remove_puppet<P>(
+        &self,
+        puppets: &PuppetManager<C, P>,
+        puppet: &Puppet,
+        room_id: &RoomId,
+        options: &TeardownOptions,
+    ) -> Result<(), PuppetError<C::Error, P::Error>>
+    where
+        C: Clone,
+        P: MappingStore<Puppet>,
+    {
+        if options.kick {
+            let reason = options.reason.as_deref();
+            self.bot.kick(room_id, puppet.user_id(), reason).await?;
+        } else {
+            let intent = puppets.puppet_for(puppet.external_id()).await?;
+            intent.leave(room_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Handle the given `event` from a transaction, following the upgrade of a portal room to
+    /// its replacement if it's an `m.room.tombstone` event, see `follow_upgrade`.
+    ///
+    /// Returns the upgrade that was followed, if any.
+    pub async fn handle_event<P>(
+        &self,
+        event: &AnyRoomEvent,
+        puppets: &PuppetManager<C, P>,
+    ) -> Result<Option<RoomUpgrade<C::Error, S::Error>>, PortalError<C::Error, S::Error>>
+    where
+        C: Clone,
+        P: MappingStore<Puppet, Error = S::Error>,
+    {
+        let event = match event {
+            AnyRoomEvent::State(AnyStateEvent::RoomTombstone(event)) => event,
+            _ => return Ok(None),
+        };
+        let replacement = &event.content.replacement_room;
+        if !event.state_key.is_empty() || replacement == &event.room_id {
+            return Ok(None);
+        }
+        self.follow_upgrade(&event.room_id, replacement, puppets)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+
+    use ruma::events::room::create::RoomType;
+    use ruma::events::AnyRoomEvent;
+    use ruma::identifiers::{RoomId, ServerName, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{
+        BulkJoin, Intent, MappingStore, Portal, PortalManager, Puppet, PuppetManager, RoomOptions,
+        TeardownOptions,
+    };
+
+    #[tokio::test]
+    async fn test_portal_manager() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let manager: PortalManager<_> =
+            PortalManager::new(bot, server_name, "_ext_", Default::default());
+
+        state.respond(
+            "/directory/room/",
+            404,
+            json!({ "errcode": "M_NOT_FOUND", "error": "Room alias not found." }),
+        );
+        state.respond("/createRoom", 200, json!({ "room_id": "!a:example.org" }));
+
+        let options = RoomOptions {
+            name: Some(String::from("General")),
+            room_type: Some(RoomType::Space),
+            invite: vec![UserId::try_from("@alice:example.org").unwrap()],
+            ..Default::default()
+        };
+        let portal = manager.ensure_portal("General", &options).await.unwrap();
+        assert_eq!(portal.room_id().as_str(), "!a:example.org");
+        assert_eq!(
+            portal.alias().unwrap().as_str(),
+            "#_ext__general:example.org"
+        );
+        manager.ensure_portal("General", &options).await.unwrap();
+
+        let creates = state.requests_to("/createRoom");
+        assert_eq!(creates.len(), 1);
+        let body = &creates[0].body;
+        assert_eq!(body["room_alias_name"], "_ext__general");
+        assert_eq!(body["creation_content"]["type"], "m.space");
+        assert_eq!(body["invite"], json!(["@alice:example.org"]));
+        assert_eq!(
+            body["power_level_content_override"]["users"]["@bot:example.org"],
+            100
+        );
+
+        // an existing alias is reused.
+        state.respond_once(
+            "/directory/room/",
+            200,
+            json!({ "room_id": "!b:example.org", "servers": [] }),
+        );
+        state.respond("/join", 200, json!({ "room_id": "!b:example.org" }));
+        let portal = manager.ensure_portal("random", &options).await.unwrap();
+        assert_eq!(portal.room_id().as_str(), "!b:example.org");
+        assert_eq!(state.requests_to("/createRoom").len(), 1);
+
+        let old = RoomId::try_from("!b:example.org").unwrap();
+        let new = RoomId::try_from("!c:example.org").unwrap();
+        let portal = manager.handle_tombstone(&old, &new).await.unwrap().unwrap();
+        assert_eq!(portal.external_id(), "random");
+        assert!(manager.get_by_room(&old).await.unwrap().is_none());
+        assert_eq!(
+            manager.get("random").await.unwrap().unwrap().room_id(),
+            &new
+        );
+        assert!(manager
+            .handle_tombstone(&old, &new)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ensure_dm() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(
+            client.clone(),
+            UserId::try_from("@bot:example.org").unwrap(),
+        );
+        let ghost = Intent::new(
+            client.clone(),
+            UserId::try_from("@_ext_bob:example.org").unwrap(),
+        );
+        let alice = UserId::try_from("@alice:example.org").unwrap();
+        let double_puppet = Intent::authenticated(client, alice.clone());
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let manager: PortalManager<_> =
+            PortalManager::new(bot, server_name, "_ext_", Default::default());
+
+        state.respond("/createRoom", 200, json!({ "room_id": "!dm:example.org" }));
+        state.respond("/join", 200, json!({ "room_id": "!dm:example.org" }));
+        state.respond(
+            "/account_data/m.direct",
+            200,
+            json!({ "@carol:example.org": ["!other:example.org"] }),
+        );
+        let portal = manager
+            .ensure_dm("bob-alice", &ghost, &alice, Some(&double_puppet))
+            .await
+            .unwrap();
+        assert_eq!(portal.room_id().as_str(), "!dm:example.org");
+        assert!(portal.alias().is_none());
+        manager
+            .ensure_dm("bob-alice", &ghost, &alice, None)
+            .await
+            .unwrap();
+
+        let creates = state.requests_to("/createRoom");
+        assert_eq!(creates.len(), 1);
+        assert!(creates[0].path.contains("user_id=@_ext_bob:example.org"));
+        assert_eq!(creates[0].body["is_direct"], true);
+        assert_eq!(creates[0].body["invite"], json!(["@alice:example.org"]));
+        assert_eq!(creates[0].body["preset"], "trusted_private_chat");
+
+        let joins = state.requests_to("/join");
+        assert_eq!(joins.len(), 1);
+        assert!(!joins[0].path.contains("user_id="));
+        let account_data = state.requests_to("/account_data/m.direct");
+        assert_eq!(account_data.len(), 2);
+        assert_eq!(
+            account_data[1].body,
+            json!({
+                "@_ext_bob:example.org": ["!dm:example.org"],
+                "@carol:example.org": ["!other:example.org"],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_published() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let mut manager: PortalManager<_> =
+            PortalManager::new(bot, server_name, "_ext_", Default::default());
+        let room_id = RoomId::try_from("!a:example.org").unwrap();
+        manager
+            .store()
+            .insert(Portal::new(room_id, String::from("general"), None))
+            .await
+            .unwrap();
+
+        state.respond("/directory/list/", 200, json!({}));
+        assert!(manager
+            .set_published("general", true)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(manager
+            .set_published("unknown", true)
+            .await
+            .unwrap()
+            .is_none());
+        manager.set_network_id(Some(String::from("irc")));
+        manager.set_published("general", false).await.unwrap();
+
+        let requests = state.requests_to("/directory/list/");
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0]
+            .path
+            .starts_with("/_matrix/client/r0/directory/list/room/!a:example.org"));
+        assert_eq!(requests[0].body["visibility"], "public");
+        assert!(requests[1]
+            .path
+            .starts_with("/_matrix/client/r0/directory/list/appservice/irc/!a:example.org"));
+        assert_eq!(requests[1].body["visibility"], "private");
+    }
+
+    #[tokio::test]
+    async fn test_follow_upgrade() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(
+            client.clone(),
+            UserId::try_from("@bot:example.org").unwrap(),
+        );
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let puppets: PuppetManager<_> =
+            PuppetManager::new(client, server_name.clone(), "_ext_", Default::default());
+        let mut manager: PortalManager<_> =
+            PortalManager::new(bot, server_name, "_ext_", Default::default());
+        let upgrades = Arc::new(Mutex::new(vec![]));
+        let upgraded = upgrades.clone();
+        manager.on_upgrade(move |old, portal| {
+            upgraded
+                .lock()
+                .unwrap()
+                .push((old.clone(), portal.room_id().clone()));
+        });
+
+        let old = RoomId::try_from("!old:example.org").unwrap();
+        let new = RoomId::try_from("!new:example.org").unwrap();
+        let portal = Portal::new(old.clone(), String::from("general"), None);
+        manager.store().insert(portal).await.unwrap();
+        for name in &["bob", "carol", "dave", "erin"] {
+            let user_id = UserId::try_from(format!("@_ext_{}:example.org", name)).unwrap();
+            puppets
+                .store()
+                .insert(Puppet::new(user_id.clone(), name.to_string()))
+                .await
+                .unwrap();
+            if *name != "erin" {
+                puppets.set_joined(&user_id, &old, true).await.unwrap();
+            }
+        }
+
+        state.respond(
+            "/register",
+            200,
+            json!({ "user_id": "@_ext_bob:example.org" }),
+        );
+        state.respond(
+            "user_id=@_ext_dave:example.org",
+            500,
+            json!({ "errcode": "M_UNKNOWN", "error": "Internal error" }),
+        );
+        state.respond("/join", 200, json!({ "room_id": "!new:example.org" }));
+        state.respond_once(
+            "user_id=@_ext_carol:example.org",
+            403,
+            json!({ "errcode": "M_FORBIDDEN", "error": "You are not invited" }),
+        );
+        let tombstone: AnyRoomEvent = serde_json::from_value(json!({
+            "type": "m.room.tombstone",
+            "event_id": "$tombstone:example.org",
+            "room_id": "!old:example.org",
+            "sender": "@alice:example.org",
+            "state_key": "",
+            "origin_server_ts": 0,
+            "content": { "body": "Upgraded", "replacement_room": "!new:example.org" },
+        }))
+        .unwrap();
+
+        let upgrade = manager
+            .handle_event(&tombstone, &puppets)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(upgrade.old_room_id, old);
+        assert_eq!(upgrade.portal.room_id(), &new);
+        let joined: Vec<_> = upgrade.joined.iter().map(|u| u.as_str()).collect();
+        assert_eq!(joined, ["@_ext_bob:example.org", "@_ext_carol:example.org"]);
+        assert_eq!(upgrade.errors.len(), 1);
+        assert_eq!(upgrade.errors[0].0.as_str(), "@_ext_dave:example.org");
+
+        let invites = state.requests_to("/invite");
+        assert_eq!(invites.len(), 1);
+        assert_eq!(invites[0].body["user_id"], "@_ext_carol:example.org");
+        let bob = puppets.get("bob").await.unwrap().unwrap();
+        assert!(bob.is_joined(&new) && !bob.is_joined(&old));
+        let dave = puppets.get("dave").await.unwrap().unwrap();
+        assert!(dave.is_joined(&old));
+        assert!(!puppets.get("erin").await.unwrap().unwrap().is_joined(&new));
+
+        assert_eq!(
+            manager.get("general").await.unwrap().unwrap().room_id(),
+            &new
+        );
+        assert_eq!(*upgrades.lock().unwrap(), [(old, new)]);
+        assert!(manager
+            .handle_event(&tombstone, &puppets)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ensure_portal_with_puppets() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(
+            client.clone(),
+            UserId::try_from("@bot:example.org").unwrap(),
+        );
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let puppets: PuppetManager<_> =
+            PuppetManager::new(client, server_name.clone(), "_ext_", Default::default());
+        let manager: PortalManager<_> =
+            PortalManager::new(bot, server_name, "_ext_", Default::default());
+
+        state.respond(
+            "/directory/room/",
+            404,
+            json!({ "errcode": "M_NOT_FOUND", "error": "Room alias not found." }),
+        );
+        state.respond("/createRoom", 200, json!({ "room_id": "!a:example.org" }));
+        state.respond(
+            "/register",
+            200,
+            json!({ "user_id": "@_ext_bob:example.org" }),
+        );
+        state.respond("/join", 200, json!({ "room_id": "!a:example.org" }));
+        let external_ids = vec![String::from("bob"), String::from("carol")];
+        let (portal, report) = manager
+            .ensure_portal_with_puppets(
+                "general",
+                &RoomOptions::default(),
+                &BulkJoin::new(&puppets),
+                &external_ids,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(portal.room_id().as_str(), "!a:example.org");
+        assert_eq!(report.joined.len(), 2);
+        assert!(report.errors.is_empty());
+        let invites = state.requests_to("/invite");
+        assert_eq!(invites.len(), 2);
+        assert!(invites[0].path.contains("user_id=@bot:example.org"));
+        assert!(puppets
+            .get("carol")
+            .await
+            .unwrap()
+            .unwrap()
+            .is_joined(portal.room_id()));
+    }
+
+    #[tokio::test]
+    async fn test_teardown() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(
+            client.clone(),
+            UserId::try_from("@bot:example.org").unwrap(),
+        );
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let puppets: PuppetManager<_> =
+            PuppetManager::new(client, server_name.clone(), "_ext_", Default::default());
+        let manager: PortalManager<_> =
+            PortalManager::new(bot, server_name, "_ext_", Default::default());
+
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let other = RoomId::try_from("!other:example.org").unwrap();
+        let alias = manager.alias_for("general").unwrap();
+        let portal = Portal::new(room_id.clone(), String::from("general"), Some(alias));
+        manager.store().insert(portal).await.unwrap();
+        let portal = Portal::new(other.clone(), String::from("random"), None);
+        manager.store().insert(portal).await.unwrap();
+        for name in &["bob", "carol", "dave"] {
+            let user_id = UserId::try_from(format!("@_ext_{}:example.org", name)).unwrap();
+            puppets
+                .store()
+                .insert(Puppet::new(user_id.clone(), name.to_string()))
+                .await
+                .unwrap();
+            if *name != "dave" {
+                puppets.set_joined(&user_id, &room_id, true).await.unwrap();
+            }
+            puppets.set_joined(&user_id, &other, true).await.unwrap();
+        }
+
+        state.respond(
+            "/register",
+            200,
+            json!({ "user_id": "@_ext_bob:example.org" }),
+        );
+        state.respond(
+            "/leave?user_id=@_ext_carol:example.org",
+            403,
+            json!({ "errcode": "M_FORBIDDEN", "error": "No" }),
+        );
+        state.respond("/send/m.room.message/", 200, json!({ "event_id": "$n" }));
+        state.respond("/state/m.room.tombstone", 200, json!({ "event_id": "$t" }));
+        let options = TeardownOptions {
+            notice: Some(String::from("The channel was deleted")),
+            replacement: Some(RoomId::try_from("!new:example.org").unwrap()),
+            ..Default::default()
+        };
+        let teardown = manager
+            .teardown("general", &puppets, &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(teardown.portal.room_id(), &room_id);
+        let left: Vec<_> = teardown.left.iter().map(|u| u.as_str()).collect();
+        assert_eq!(left, ["@_ext_bob:example.org"]);
+        assert_eq!(teardown.errors.len(), 1);
+        assert_eq!(teardown.errors[0].0.as_str(), "@_ext_carol:example.org");
+
+        let notices = state.requests_to("/send/m.room.message/");
+        assert_eq!(notices[0].body["msgtype"], "m.notice");
+        let tombstones = state.requests_to("/state/m.room.tombstone");
+        assert_eq!(tombstones[0].body["replacement_room"], "!new:example.org");
+        assert_eq!(tombstones[0].body["body"], "The channel was deleted");
+        let deletes = state.requests_to("/directory/room/#_ext_general:example.org");
+        assert_eq!(deletes.len(), 1);
+        assert_eq!(deletes[0].method, "DELETE");
+        let leaves = state.requests_to("/rooms/!room:example.org/leave");
+        assert_eq!(leaves.len(), 3);
+        assert!(leaves[2].path.contains("user_id=@bot:example.org"));
+        assert!(!puppets
+            .get("bob")
+            .await
+            .unwrap()
+            .unwrap()
+            .is_joined(&room_id));
+        assert!(puppets
+            .get("carol")
+            .await
+            .unwrap()
+            .unwrap()
+            .is_joined(&room_id));
+        assert!(manager.get("general").await.unwrap().is_none());
+
+        let options = TeardownOptions {
+            kick: true,
+            reason: Some(String::from("Bye")),
+            keep_bot: true,
+            ..Default::default()
+        };
+        let external_ids = vec![String::from("random"), String::from("general")];
+        let results = manager
+            .teardown_all(&external_ids, &puppets, &options)
+            .await;
+        let teardown = results[0].as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(teardown.left.len(), 3);
+        assert!(results[1].as_ref().unwrap().is_none());
+        let kicks = state.requests_to("/kick");
+        assert_eq!(kicks.len(), 3);
+        assert_eq!(kicks[0].body["reason"], "Bye");
+        assert!(state
+            .requests_to("/rooms/!other:example.org/leave")
+            .is_empty());
+        assert!(manager.portals().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,367 @@
+use std::sync::Mutex;
+
+use ruma::identifiers::{EventId, RoomId};
+use ruma_client::HttpClient;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::customstate::BridgeStateContent;
+use crate::intent::Intent;
+use crate::mappingdict::{Mappable, MappingDict, MappingId};
+use crate::portal::PortalError;
+use crate::relay::RelayFormatter;
+use crate::store::MappingStore;
+use crate::urlpreview::UrlPreviewer;
+
+/// Settings of a portal that override the defaults of the bridge.
+///
+/// Every setting that is `None` uses the default. Bridge specific settings are kept in `extra`,
+/// and can be read using `extra`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PortalConfig {
+    /// Whether formatting is converted, instead of sending plain text only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formatting: Option<bool>,
+    /// The maximum length of a message sent to the external service.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_message_length: Option<usize>,
+    /// Whether relay mode is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay: Option<bool>,
+    /// Whether previews are added to URLs in messages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url_previews: Option<bool>,
+    /// Settings specific to the bridge.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl PortalConfig {
+    /// Create a new `PortalConfig` without any settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the config with the settings of `self`, and the settings of `defaults` for the
+    /// settings that `self` doesn't have.
+    pub fn or(&self, defaults: &PortalConfig) -> PortalConfig {
+        let mut extra = defaults.extra.clone();
+        extra.extend(self.extra.clone());
+        PortalConfig {
+            formatting: self.formatting.or(defaults.formatting),
+            max_message_length: self.max_message_length.or(defaults.max_message_length),
+            relay: self.relay.or(defaults.relay),
+            url_previews: self.url_previews.or(defaults.url_previews),
+            extra,
+        }
+    }
+
+    /// Returns whether formatting is converted, which it is by default.
+    pub fn formatting_enabled(&self) -> bool {
+        self.formatting.unwrap_or(true)
+    }
+
+    /// Returns whether relay mode is enabled, which it isn't by default.
+    pub fn relay_enabled(&self) -> bool {
+        self.relay.unwrap_or(false)
+    }
+
+    /// Returns whether URL previews are enabled, which they are by default.
+    pub fn url_previews_enabled(&self) -> bool {
+        self.url_previews.unwrap_or(true)
+    }
+
+    /// Get the bridge specific setting `key`, or `None` if it isn't set or can't be
+    /// deserialized as `T`.
+    pub fn extra<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self.extra.get(key)?;
+        serde_json::from_value(value.clone()).ok()
+    }
+
+    /// Set the bridge specific setting `key` to `value`, or remove it if `value` is `None`.
+    pub fn set_extra<T: Serialize>(&mut self, key: &str, value: Option<T>) {
+        match value.and_then(|value| serde_json::to_value(value).ok()) {
+            Some(value) => self.extra.insert(key.to_string(), value),
+            None => self.extra.remove(key),
+        };
+    }
+}
+
+impl BridgeStateContent for PortalConfig {
+    const EVENT_TYPE: &'static str = "config";
+}
+
+/// The settings of the portal with the given room ID, as kept in the store of `PortalConfigs`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomConfig {
+    room_id: RoomId,
+    config: PortalConfig,
+}
+
+impl RoomConfig {
+    /// Create a new `RoomConfig` with the settings `config` of the room with the given
+    /// `room_id`.
+    pub fn new(room_id: RoomId, config: PortalConfig) -> Self {
+        Self { room_id, config }
+    }
+
+    /// Get the ID of the room of the portal.
+    pub fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
+    /// Get the settings of the portal.
+    pub fn config(&self) -> &PortalConfig {
+        &self.config
+    }
+
+    /// Get the settings of the portal, consuming the `RoomConfig`.
+    pub fn into_config(self) -> PortalConfig {
+        self.config
+    }
+}
+
+/// Settings are kept by room ID only, so both sides of the mapping are the room ID.
+impl Mappable for RoomConfig {
+    type MatrixReference = RoomId;
+    type MatrixType = RoomId;
+    type ExternalReference = RoomId;
+    type ExternalType = RoomId;
+
+    fn as_matrix(&self) -> &RoomId {
+        &self.room_id
+    }
+    fn into_matrix(self) -> RoomId {
+        self.room_id
+    }
+    fn as_external(&self) -> &RoomId {
+        &self.room_id
+    }
+    fn into_external(self) -> RoomId {
+        self.room_id
+    }
+    fn into_split(self) -> (RoomId, RoomId) {
+        (self.room_id.clone(), self.room_id)
+    }
+}
+
+/// Keeps the settings of every portal, so the behaviour of the bridge can differ per room.
+///
+/// The settings of a portal are kept in a `MappingStore`, and can be synced with the
+/// `<prefix>.config` bridge state event in the room using `load_from_room` and `save_to_room`.
+/// Lookups using `get` give the settings of the portal on top of the defaults of the bridge.
+pub struct PortalConfigs<S = Mutex<MappingDict<RoomConfig>>> {
+    defaults: PortalConfig,
+    store: S,
+}
+
+impl<S: MappingStore<RoomConfig>> PortalConfigs<S> {
+    /// Create a new `PortalConfigs` with the given `defaults`, keeping the settings of the
+    /// portals in `store`.
+    pub fn new(defaults: PortalConfig, store: S) -> Self {
+        Self { defaults, store }
+    }
+
+    /// Get the default settings.
+    pub fn defaults(&self) -> &PortalConfig {
+        &self.defaults
+    }
+
+    /// Get the store containing the settings of the portals.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Get the settings of the portal with the given `room_id`, with the defaults for settings
+    /// the portal doesn't have.
+    pub async fn get(&self, room_id: &RoomId) -> Result<PortalConfig, S::Error> {
+        let overrides = self.overrides(room_id).await?.unwrap_or_default();
+        Ok(overrides.or(&self.defaults))
+    }
+
+    /// Get the settings set for the portal with the given `room_id` only, if any.
+    pub async fn overrides(&self, room_id: &RoomId) -> Result<Option<PortalConfig>, S::Error> {
+        let config = self.store.get(MappingId::Matrix(room_id)).await?;
+        Ok(config.map(RoomConfig::into_config))
+    }
+
+    /// Set the settings of the portal with the given `room_id` to `config`.
+    pub async fn set(&self, room_id: &RoomId, config: PortalConfig) -> Result<(), S::Error> {
+        self.store
+            .insert(RoomConfig::new(room_id.clone(), config))
+            .await
+    }
+
+    /// Change the settings of the portal with the given `room_id` using `f`.
+    ///
+    /// Returns the new settings of the portal only.
+    pub async fn update<F>(&self, room_id: &RoomId, f: F) -> Result<PortalConfig, S::Error>
+    where
+        F: FnOnce(&mut PortalConfig),
+    {
+        let mut config = self.overrides(room_id).await?.unwrap_or_default();
+        f(&mut config);
+        self.set(room_id, config.clone()).await?;
+        Ok(config)
+    }
+
+    /// Remove the settings of the portal with the given `room_id`, so it uses the defaults
+    /// again.
+    pub async fn reset(&self, room_id: &RoomId) -> Result<Option<PortalConfig>, S::Error> {
+        let config = self.store.remove(MappingId::Matrix(room_id)).await?;
+        Ok(config.map(RoomConfig::into_config))
+    }
+
+    /// Load the settings of the portal with the given `room_id` from the bridge state event of
+    /// the bridge with the event type `prefix` in the room, replacing the stored settings.
+    ///
+    /// Returns the loaded settings, or `None` if the room has no settings, in which case the
+    /// stored settings are kept.
+    pub async fn load_from_room<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+        room_id: &RoomId,
+        prefix: &str,
+    ) -> Result<Option<PortalConfig>, PortalError<C::Error, S::Error>> {
+        let config: Option<PortalConfig> =
+            intent.get_bridge_state_event(room_id, prefix, "").await?;
+        if let Some(config) = &config {
+            self.set(room_id, config.clone())
+                .await
+                .map_err(PortalError::Store)?;
+        }
+        Ok(config)
+    }
+
+    /// Save the settings of the portal with the given `room_id` to the bridge state event of
+    /// the bridge with the event type `prefix` in the room, as `intent`.
+    ///
+    /// Returns the ID of the sent event.
+    pub async fn save_to_room<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+        room_id: &RoomId,
+        prefix: &str,
+    ) -> Result<EventId, PortalError<C::Error, S::Error>> {
+        let config = self
+            .overrides(room_id)
+            .await
+            .map_err(PortalError::Store)?
+            .unwrap_or_default();
+        let event_id = intent
+            .set_bridge_state_event(room_id, prefix, "", &config)
+            .await?;
+        Ok(event_id)
+    }
+}
+
+impl RelayFormatter {
+    /// Enable or disable relay mode for the portal with the given `room_id` following its
+    /// settings `config`.
+    pub fn apply_config(&self, room_id: &RoomId, config: &PortalConfig) {
+        self.set_room_enabled(room_id, config.relay);
+    }
+}
+
+impl UrlPreviewer {
+    /// Enable or disable URL previews for the portal with the given `room_id` following its
+    /// settings `config`.
+    pub fn apply_config(&self, room_id: &RoomId, config: &PortalConfig) {
+        self.set_room_enabled(room_id, config.url_previews);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{RoomId, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{Intent, PortalConfig, PortalConfigs, RelayFormatter};
+
+    #[tokio::test]
+    async fn test_portal_configs() {
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let other = RoomId::try_from("!other:example.org").unwrap();
+        let defaults = PortalConfig {
+            max_message_length: Some(400),
+            ..PortalConfig::new()
+        };
+        let configs: PortalConfigs = PortalConfigs::new(defaults, Default::default());
+
+        configs
+            .update(&room_id, |config| {
+                config.formatting = Some(false);
+                config.relay = Some(true);
+                config.set_extra("nick_color", Some("red"));
+            })
+            .await
+            .unwrap();
+        let config = configs.get(&room_id).await.unwrap();
+        assert!(!config.formatting_enabled());
+        assert!(config.relay_enabled());
+        assert_eq!(config.max_message_length, Some(400));
+        assert_eq!(config.extra::<String>("nick_color").as_deref(), Some("red"));
+
+        let config = configs.get(&other).await.unwrap();
+        assert!(config.formatting_enabled());
+        assert!(!config.relay_enabled());
+
+        let relay = RelayFormatter::new();
+        relay.apply_config(&room_id, &configs.get(&room_id).await.unwrap());
+        assert!(relay.is_enabled(&room_id));
+        assert!(!relay.is_enabled(&other));
+
+        configs.reset(&room_id).await.unwrap();
+        assert!(configs.get(&room_id).await.unwrap().formatting_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_room_state() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let configs: PortalConfigs = PortalConfigs::new(PortalConfig::new(), Default::default());
+        let path = "/rooms/!room:example.org/state/org.example.bridge.config";
+
+        state.respond_once(
+            path,
+            200,
+            json!({ "max_message_length": 100, "nick_color": "blue" }),
+        );
+        let config = configs
+            .load_from_room(&bot, &room_id, "org.example.bridge")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.max_message_length, Some(100));
+        assert_eq!(
+            configs
+                .get(&room_id)
+                .await
+                .unwrap()
+                .extra::<String>("nick_color"),
+            Some(String::from("blue"))
+        );
+
+        configs
+            .update(&room_id, |config| config.url_previews = Some(false))
+            .await
+            .unwrap();
+        state.respond(path, 200, json!({ "event_id": "$config:example.org" }));
+        configs
+            .save_to_room(&bot, &room_id, "org.example.bridge")
+            .await
+            .unwrap();
+        let requests = state.requests_to(path);
+        assert_eq!(requests[1].method, "PUT");
+        assert_eq!(
+            requests[1].body,
+            json!({ "max_message_length": 100, "url_previews": false, "nick_color": "blue" })
+        );
+    }
+}
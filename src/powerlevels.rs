@@ -0,0 +1,255 @@
+use std::collections::{BTreeMap, HashMap};
+
+use ruma::events::room::power_levels::PowerLevelsEventContent;
+use ruma::identifiers::{RoomId, UserId};
+use ruma::Int;
+use ruma_client::HttpClient;
+use serde_json::value::to_raw_value;
+
+use crate::intent::{Intent, IntentError};
+
+/// Translates the roles of users on the external service, like op or admin, to power levels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoleLevels {
+    levels: HashMap<String, Int>,
+}
+
+impl Default for RoleLevels {
+    /// `admin` and `owner` get power level 100, `op` and `moderator` get 50, and `voice` gets 10.
+    fn default() -> Self {
+        let mut levels = Self::new();
+        levels
+            .set("owner", Int::from(100))
+            .set("admin", Int::from(100))
+            .set("op", Int::from(50))
+            .set("moderator", Int::from(50))
+            .set("voice", Int::from(10));
+        levels
+    }
+}
+
+impl RoleLevels {
+    /// Create a new `RoleLevels` without any roles.
+    pub fn new() -> Self {
+        Self {
+            levels: HashMap::new(),
+        }
+    }
+
+    /// Set the power level of users with the given `role`, returning the current `RoleLevels` to
+    /// allow method chaining.
+    pub fn set(&mut self, role: &str, level: Int) -> &mut Self {
+        self.levels.insert(role.to_string(), level);
+        self
+    }
+
+    /// Get the power level of users with the given `role`, if it's known.
+    pub fn level(&self, role: &str) -> Option<Int> {
+        self.levels.get(role).copied()
+    }
+
+    /// Get the power levels of the users with the given roles.
+    ///
+    /// A user can be given multiple times to give them multiple roles, in which case the highest
+    /// level is used. Users with only unknown roles are left out.
+    pub fn levels<I, R>(&self, roles: I) -> BTreeMap<UserId, Int>
+    where
+        I: IntoIterator<Item = (UserId, R)>,
+        R: AsRef<str>,
+    {
+        let mut levels: BTreeMap<UserId, Int> = BTreeMap::new();
+        for (user_id, role) in roles {
+            let level = match self.level(role.as_ref()) {
+                Some(level) => level,
+                None => continue,
+            };
+            let entry = levels.entry(user_id).or_insert(level);
+            *entry = (*entry).max(level);
+        }
+        levels
+    }
+}
+
+/// Apply the given power `levels` to `content`, returning whether anything changed.
+///
+/// Users for which `is_managed` returns true and that aren't in `levels` get the default level.
+/// The level of `bot`, which sends the event, is never changed, no user gets a higher level than
+/// the bot, and users with a level equal to or higher than the bot are left alone, since the
+/// homeserver wouldn't allow those changes.
+pub fn apply_power_levels<F>(
+    content: &mut PowerLevelsEventContent,
+    bot: &UserId,
+    levels: &BTreeMap<UserId, Int>,
+    is_managed: F,
+) -> bool
+where
+    F: Fn(&UserId) -> bool,
+{
+    let default = content.users_default;
+    let bot_level = content.users.get(bot).copied().unwrap_or(default);
+    let level_of = |users: &BTreeMap<UserId, Int>, user_id: &UserId| {
+        users.get(user_id).copied().unwrap_or(default)
+    };
+
+    let mut users = content.users.clone();
+    users.retain(|user_id, level| {
+        user_id == bot
+            || *level >= bot_level
+            || levels.contains_key(user_id)
+            || !is_managed(user_id)
+    });
+
+    for (user_id, &level) in levels {
+        if user_id == bot || level_of(&content.users, user_id) >= bot_level {
+            continue;
+        }
+
+        let level = level.min(bot_level);
+        if level == default {
+            users.remove(user_id);
+        } else {
+            users.insert(user_id.clone(), level);
+        }
+    }
+
+    let changed = users != content.users;
+    content.users = users;
+    changed
+}
+
+/// Make the power levels of the room with the given `room_id` match `levels`, as `bot`, using
+/// `apply_power_levels`.
+///
+/// The power levels event is only sent if anything changed. Returns whether it was sent.
+pub async fn sync_power_levels<C, F>(
+    bot: &Intent<C>,
+    room_id: &RoomId,
+    levels: &BTreeMap<UserId, Int>,
+    is_managed: F,
+) -> Result<bool, IntentError<C::Error>>
+where
+    C: HttpClient,
+    F: Fn(&UserId) -> bool,
+{
+    let content = bot
+        .get_state_raw(room_id, "m.room.power_levels", "")
+        .await?;
+    let mut content: PowerLevelsEventContent =
+        serde_json::from_str(content.get()).unwrap_or_default();
+
+    if !apply_power_levels(&mut content, bot.user_id(), levels, is_managed) {
+        return Ok(false);
+    }
+
+    let content = to_raw_value(&content).expect("power levels should serialize");
+    bot.send_state_raw(room_id, "m.room.power_levels", "", content)
+        .await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+
+    use ruma::events::room::power_levels::PowerLevelsEventContent;
+    use ruma::identifiers::{RoomId, UserId};
+    use ruma::Int;
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{apply_power_levels, sync_power_levels, Intent, RoleLevels};
+
+    fn user(s: &str) -> UserId {
+        UserId::try_from(s).unwrap()
+    }
+
+    fn is_puppet(user_id: &UserId) -> bool {
+        user_id.localpart().starts_with("_ext_")
+    }
+
+    #[test]
+    fn test_role_levels() {
+        let mut roles = RoleLevels::default();
+        roles.set("halfop", Int::from(25));
+        let levels = roles.levels(vec![
+            (user("@_ext_a:example.org"), "voice"),
+            (user("@_ext_a:example.org"), "op"),
+            (user("@_ext_b:example.org"), "halfop"),
+            (user("@_ext_c:example.org"), "unknown"),
+        ]);
+
+        let expected: BTreeMap<_, _> = vec![
+            (user("@_ext_a:example.org"), Int::from(50)),
+            (user("@_ext_b:example.org"), Int::from(25)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(levels, expected);
+    }
+
+    #[test]
+    fn test_apply_power_levels() {
+        let bot = user("@bot:example.org");
+        let mut content: PowerLevelsEventContent = serde_json::from_value(json!({
+            "users": {
+                "@bot:example.org": 90,
+                "@admin:example.org": 100,
+                "@alice:example.org": 50,
+                "@_ext_old:example.org": 50,
+            },
+        }))
+        .unwrap();
+
+        let levels: BTreeMap<_, _> = vec![
+            (bot.clone(), Int::from(0)),
+            (user("@admin:example.org"), Int::from(0)),
+            (user("@_ext_a:example.org"), Int::from(100)),
+            (user("@_ext_b:example.org"), Int::from(0)),
+        ]
+        .into_iter()
+        .collect();
+        assert!(apply_power_levels(&mut content, &bot, &levels, is_puppet));
+
+        let expected: BTreeMap<_, _> = vec![
+            (bot.clone(), Int::from(90)),
+            (user("@admin:example.org"), Int::from(100)),
+            (user("@alice:example.org"), Int::from(50)),
+            (user("@_ext_a:example.org"), Int::from(90)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(content.users, expected);
+
+        assert!(!apply_power_levels(&mut content, &bot, &levels, is_puppet));
+    }
+
+    #[tokio::test]
+    async fn test_sync_power_levels() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, user("@bot:example.org"));
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let levels = RoleLevels::default().levels(vec![(user("@_ext_a:example.org"), "op")]);
+
+        let path = "/state/m.room.power_levels";
+        state.respond_once(path, 200, json!({ "users": { "@bot:example.org": 100 } }));
+        state.respond(path, 200, json!({ "event_id": "$levels:example.org" }));
+        assert!(sync_power_levels(&bot, &room_id, &levels, is_puppet)
+            .await
+            .unwrap());
+
+        let requests = state.requests_to(path);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].method, "PUT");
+        assert_eq!(
+            requests[1].body["users"],
+            json!({ "@bot:example.org": 100, "@_ext_a:example.org": 50 })
+        );
+
+        state.respond_once(path, 200, requests[1].body.clone());
+        assert!(!sync_power_levels(&bot, &room_id, &levels, is_puppet)
+            .await
+            .unwrap());
+        assert_eq!(state.requests_to(path).len(), 3);
+    }
+}
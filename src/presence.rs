@@ -0,0 +1,272 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use ruma::events::presence::PresenceEvent;
+use ruma::identifiers::UserId;
+use ruma::presence::PresenceState;
+use ruma::serde::Raw;
+use ruma_client::{Client, HttpClient};
+
+#[cfg(feature = "runtime")]
+use ruma::api::client::error::ErrorKind;
+#[cfg(feature = "runtime")]
+use tokio::task::JoinHandle;
+
+use crate::client::AppserviceClient;
+use crate::intent::{Intent, IntentError};
+
+/// A presence update of a user, parsed from an `m.presence` EDU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresenceUpdate {
+    /// The user whose presence changed.
+    pub user_id: UserId,
+    /// The new presence of the user.
+    pub presence: PresenceState,
+    /// The status message of the user, if any.
+    pub status_msg: Option<String>,
+    /// How long ago the user was last active, if known.
+    pub last_active_ago: Option<Duration>,
+    /// Whether the user is currently active.
+    pub currently_active: bool,
+}
+
+impl PresenceUpdate {
+    /// Get the presence update in the given presence `event`.
+    pub fn from_event(event: &PresenceEvent) -> Self {
+        let content = &event.content;
+        Self {
+            user_id: event.sender.clone(),
+            presence: content.presence.clone(),
+            status_msg: content.status_msg.clone(),
+            last_active_ago: content
+                .last_active_ago
+                .map(|ms| Duration::from_millis(ms.into())),
+            currently_active: content.currently_active.unwrap_or(false),
+        }
+    }
+
+    /// Get the presence update in the given `event`, or `None` if it isn't a valid `m.presence`
+    /// event.
+    pub fn from_raw(event: &Raw<PresenceEvent>) -> Option<Self> {
+        event
+            .deserialize()
+            .ok()
+            .map(|event| Self::from_event(&event))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Presence {
+    state: PresenceState,
+    status_msg: Option<String>,
+}
+
+/// Sets the presence of ghost users in batches.
+///
+/// Presence changes are queued using `set`, replacing earlier changes of the same user that
+/// weren't sent yet, and sent using `flush`, which sends at most `batch_size` changes at a time
+/// to stay within the rate limits of the homeserver.
+pub struct PresenceQueue<C> {
+    client: Client<C>,
+    appservice: Option<Arc<AppserviceClient<C>>>,
+    batch_size: usize,
+    pending: Mutex<BTreeMap<UserId, Presence>>,
+    sent: Mutex<HashMap<UserId, Presence>>,
+}
+
+impl<C: HttpClient + Clone> PresenceQueue<C> {
+    /// Create a new `PresenceQueue` sending presence changes using `client`, ten at a time.
+    pub fn new(client: Client<C>) -> Self {
+        Self {
+            client,
+            appservice: None,
+            batch_size: 10,
+            pending: Mutex::new(BTreeMap::new()),
+            sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Send the presence changes with the settings of the given `AppserviceClient`, or without
+    /// them if it's `None`.
+    pub fn set_appservice_client(&mut self, client: Option<Arc<AppserviceClient<C>>>) {
+        self.appservice = client;
+    }
+
+    /// Set the amount of presence changes sent per `flush`.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size;
+    }
+
+    /// Queue setting the presence of the ghost user with the given `user_id`.
+    ///
+    /// Nothing is sent if the presence is the same as the one that was sent last.
+    pub fn set(&self, user_id: UserId, presence: PresenceState, status_msg: Option<String>) {
+        let presence = Presence {
+            state: presence,
+            status_msg,
+        };
+
+        let sent = self.sent.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        if sent.get(&user_id) == Some(&presence) {
+            pending.remove(&user_id);
+        } else {
+            pending.insert(user_id, presence);
+        }
+    }
+
+    /// Get the amount of presence changes that weren't sent yet.
+    pub fn pending(&self) -> usize {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    fn take_batch(&self) -> Vec<(UserId, Presence)> {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let user_ids: Vec<_> = pending.keys().take(self.batch_size).cloned().collect();
+        user_ids
+            .into_iter()
+            .filter_map(|user_id| pending.remove_entry(&user_id))
+            .collect()
+    }
+
+    /// Send the next batch of queued presence changes.
+    ///
+    /// When sending a change fails, the changes that weren't sent are queued again, unless a newer
+    /// change of the same user was queued in the meantime.
+    ///
+    /// Returns the amount of changes sent.
+    pub async fn flush(&self) -> Result<usize, IntentError<C::Error>> {
+        let mut batch = self.take_batch().into_iter();
+
+        let mut count = 0;
+        while let Some((user_id, presence)) = batch.next() {
+            let intent = Intent::new(self.client.clone(), user_id.clone())
+                .with_appservice_client(self.appservice.clone());
+            let result = intent
+                .set_presence(presence.state.clone(), presence.status_msg.as_deref())
+                .await;
+
+            if let Err(err) = result {
+                let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+                for (user_id, presence) in std::iter::once((user_id, presence)).chain(batch) {
+                    pending.entry(user_id).or_insert(presence);
+                }
+                return Err(err);
+            }
+
+            let mut sent = self.sent.lock().unwrap_or_else(PoisonError::into_inner);
+            sent.insert(user_id, presence);
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl<C> PresenceQueue<C>
+where
+    C: HttpClient + Clone + Send + 'static,
+{
+    /// Flush the queue every `interval` in a background task, until the returned handle is
+    /// aborted.
+    ///
+    /// When the homeserver rate limits the requests, the task waits as long as the homeserver
+    /// asks before flushing again. Other errors are ignored.
+    pub fn spawn(self: Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(err) = self.flush().await {
+                    if let Some(ErrorKind::LimitExceeded {
+                        retry_after_ms: Some(retry_after),
+                    }) = err.kind()
+                    {
+                        tokio::time::sleep(*retry_after).await;
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    use ruma::identifiers::UserId;
+    use ruma::presence::PresenceState;
+    use ruma::serde::Raw;
+    use serde_json::json;
+    use serde_json::value::to_raw_value;
+
+    use crate::testing::mock_client;
+    use crate::{PresenceQueue, PresenceUpdate};
+
+    #[test]
+    fn test_presence_update() {
+        let event = json!({
+            "type": "m.presence",
+            "sender": "@alice:example.org",
+            "content": {
+                "presence": "unavailable",
+                "status_msg": "Lunch",
+                "last_active_ago": 5000,
+            },
+        });
+        let update = PresenceUpdate::from_raw(&Raw::from_json(to_raw_value(&event).unwrap()));
+        assert_eq!(
+            update.unwrap(),
+            PresenceUpdate {
+                user_id: UserId::try_from("@alice:example.org").unwrap(),
+                presence: PresenceState::Unavailable,
+                status_msg: Some(String::from("Lunch")),
+                last_active_ago: Some(Duration::from_secs(5)),
+                currently_active: false,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_presence_queue() {
+        let (client, state) = mock_client();
+        let mut queue = PresenceQueue::new(client);
+        queue.set_batch_size(2);
+
+        let user = |i| UserId::try_from(format!("@_ext_{}:example.org", i)).unwrap();
+        queue.set(user(1), PresenceState::Unavailable, None);
+        queue.set(user(1), PresenceState::Online, Some(String::from("Hi")));
+        queue.set(user(2), PresenceState::Online, None);
+        queue.set(user(3), PresenceState::Offline, None);
+        assert_eq!(queue.pending(), 3);
+
+        assert_eq!(queue.flush().await.unwrap(), 2);
+        let requests = state.requests_to("/presence/");
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0]
+            .path
+            .starts_with("/_matrix/client/r0/presence/@_ext_1:example.org/status?"));
+        assert_eq!(
+            requests[0].body,
+            json!({ "presence": "online", "status_msg": "Hi" })
+        );
+
+        // unchanged presence isn't sent again.
+        queue.set(user(1), PresenceState::Online, Some(String::from("Hi")));
+        assert_eq!(queue.pending(), 1);
+
+        state.respond_once(
+            "/presence/",
+            429,
+            json!({ "errcode": "M_LIMIT_EXCEEDED", "error": "Too many requests" }),
+        );
+        assert!(queue.flush().await.is_err());
+        assert_eq!(queue.pending(), 1);
+        assert_eq!(queue.flush().await.unwrap(), 1);
+        assert_eq!(queue.pending(), 0);
+    }
+}
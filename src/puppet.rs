@@ -0,0 +1,402 @@
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use ruma::identifiers::{RoomId, ServerName, UserId};
+use ruma_client::{Client, HttpClient};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::client::AppserviceClient;
+use crate::echo::EchoTracker;
+use crate::intent::{Intent, IntentError};
+use crate::mappingdict::{Mappable, MappingDict, MappingId};
+use crate::store::MappingStore;
+
+/// Escape `s` so it only contains characters allowed in the localpart of a user ID.
+///
+/// Lowercase ASCII letters, digits, `.`, `-` and `/` are kept, uppercase letters become `_`
+/// followed by the lowercase letter, `_` becomes `__` and every byte of other characters becomes
+/// `=` followed by its hex value. Different strings never give the same escaped string.
+pub fn escape_localpart(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            'a'..='z' | '0'..='9' | '.' | '-' | '/' => res.push(c),
+            'A'..='Z' => {
+                res.push('_');
+                res.push(c.to_ascii_lowercase());
+            }
+            '_' => res.push_str("__"),
+            _ => {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    res.push_str(&format!("={:02x}", b));
+                }
+            }
+        }
+    }
+    res
+}
+
+/// A virtual user of the application service, representing a user on the external service.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Puppet {
+    user_id: UserId,
+    external_id: String,
+    registered: bool,
+    joined_rooms: BTreeSet<RoomId>,
+}
+
+impl Puppet {
+    /// Create a new `Puppet` that isn't registered yet and hasn't joined any rooms.
+    pub fn new(user_id: UserId, external_id: String) -> Self {
+        Self {
+            user_id,
+            external_id,
+            registered: false,
+            joined_rooms: BTreeSet::new(),
+        }
+    }
+
+    /// Get the Matrix ID of this puppet.
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
+
+    /// Get the ID of the external user this puppet represents.
+    pub fn external_id(&self) -> &str {
+        &self.external_id
+    }
+
+    /// Returns whether the account of this puppet has been registered on the homeserver.
+    pub fn is_registered(&self) -> bool {
+        self.registered
+    }
+
+    /// Returns whether this puppet is known to have joined the room with the given `room_id`.
+    pub fn is_joined(&self, room_id: &RoomId) -> bool {
+        self.joined_rooms.contains(room_id)
+    }
+
+    /// Get an iterator over the rooms this puppet is known to have joined.
+    pub fn joined_rooms(&self) -> impl Iterator<Item = &RoomId> {
+        self.joined_rooms.iter()
+    }
+}
+
+impl Mappable for Puppet {
+    type MatrixReference = UserId;
+    type MatrixType = UserId;
+    type ExternalReference = str;
+    type ExternalType = String;
+
+    fn as_matrix(&self) -> &UserId {
+        &self.user_id
+    }
+    fn into_matrix(self) -> UserId {
+        self.user_id
+    }
+    fn as_external(&self) -> &str {
+        &self.external_id
+    }
+    fn into_external(self) -> String {
+        self.external_id
+    }
+    fn into_split(self) -> (UserId, String) {
+        (self.user_id, self.external_id)
+    }
+}
+
+/// An error from a `PuppetManager`.
+#[derive(Debug, Error)]
+pub enum PuppetError<E, S> {
+    /// A request to the homeserver failed.
+    #[error("{0}")]
+    Intent(IntentError<E>),
+    /// Loading or saving a puppet failed.
+    #[error("loading or saving a puppet failed: {0}")]
+    Store(S),
+    /// The localpart generated for an external user gives an invalid user ID.
+    #[error("invalid user ID generated for the puppet: {0}")]
+    InvalidUserId(ruma::identifiers::Error),
+}
+
+impl<E, S> From<IntentError<E>> for PuppetError<E, S> {
+    fn from(err: IntentError<E>) -> Self {
+        PuppetError::Intent(err)
+    }
+}
+
+type LocalpartFn = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Manages the virtual users of an application service, representing the users of the external
+/// service on Matrix.
+///
+/// The puppets are kept in a `MappingStore`, which is an in-memory `MappingDict` by default. The
+/// account of a puppet is registered the first time it's used, and the rooms it joined are
+/// tracked so it only joins a room once.
+pub struct PuppetManager<C, S = Mutex<MappingDict<Puppet>>> {
+    client: Client<C>,
+    server_name: Box<ServerName>,
+    localpart: LocalpartFn,
+    store: S,
+    echo: Option<Arc<EchoTracker>>,
+    appservice: Option<Arc<AppserviceClient<C>>>,
+}
+
+impl<C, S> PuppetManager<C, S>
+where
+    C: HttpClient + Clone,
+    S: MappingStore<Puppet>,
+{
+    /// Create a new `PuppetManager` making requests using `client` and keeping the puppets in
+    /// `store`.
+    ///
+    /// The localpart of a puppet is `prefix` followed by the escaped external ID, see
+    /// `escape_localpart`. The `prefix` should match the user namespace of the registration.
+    pub fn new(client: Client<C>, server_name: Box<ServerName>, prefix: &str, store: S) -> Self {
+        let prefix = prefix.to_string();
+        Self {
+            client,
+            server_name,
+            localpart: Box::new(move |id| format!("{}{}", prefix, escape_localpart(id))),
+            store,
+            echo: None,
+            appservice: None,
+        }
+    }
+
+    /// Use `f` to generate the localpart of the puppet of an external user, instead of the
+    /// prefix given to `new`.
+    ///
+    /// This only affects puppets that aren't in the store yet.
+    pub fn set_localpart<F>(&mut self, f: F)
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.localpart = Box::new(f);
+    }
+
+    /// Mark the puppets as bridge users in the given echo `tracker` when they are used, and
+    /// record the events sent by the `Intent`s of the puppets in it.
+    pub fn set_echo_tracker(&mut self, tracker: Option<Arc<EchoTracker>>) {
+        self.echo = tracker;
+    }
+
+    /// Send the requests of the puppets, including their registration, with the settings of the
+    /// given `AppserviceClient`, or without them if it's `None`.
+    pub fn set_appservice_client(&mut self, client: Option<Arc<AppserviceClient<C>>>) {
+        self.appservice = client;
+    }
+
+    /// Get the `AppserviceClient` of which the settings are used by the `Intent`s of the
+    /// puppets, if any.
+    pub fn appservice_client(&self) -> Option<&Arc<AppserviceClient<C>>> {
+        self.appservice.as_ref()
+    }
+
+    /// Get the store containing the puppets.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Get the user ID a new puppet for the external user `external_id` would get.
+    pub fn user_id_for(&self, external_id: &str) -> Result<UserId, ruma::identifiers::Error> {
+        let localpart = (self.localpart)(external_id);
+        UserId::try_from(format!("@{}:{}", localpart, self.server_name))
+    }
+
+    /// Get the puppet of the external user `external_id`, if it exists.
+    pub async fn get(&self, external_id: &str) -> Result<Option<Puppet>, S::Error> {
+        self.store.get(MappingId::External(external_id)).await
+    }
+
+    /// Get the ID of the external user represented by the puppet with the given `user_id`, or
+    /// `None` if `user_id` isn't a known puppet.
+    pub async fn external_id_for(&self, user_id: &UserId) -> Result<Option<String>, S::Error> {
+        let puppet = self.store.get(MappingId::Matrix(user_id)).await?;
+        Ok(puppet.map(Puppet::into_external))
+    }
+
+    /// Get all known puppets.
+    pub async fn puppets(&self) -> Result<Vec<Puppet>, S::Error> {
+        self.store.items().await
+    }
+
+    /// Get the puppet of the external user `external_id`, creating it and registering its
+    /// account if needed.
+    async fn ensure_puppet(
+        &self,
+        external_id: &str,
+    ) -> Result<Puppet, PuppetError<C::Error, S::Error>> {
+        let puppet = self
+            .store
+            .get(MappingId::External(external_id))
+            .await
+            .map_err(PuppetError::Store)?;
+        let mut puppet = match puppet {
+            Some(puppet) if puppet.registered => return Ok(puppet),
+            Some(puppet) => puppet,
+            None => {
+                let user_id = self
+                    .user_id_for(external_id)
+                    .map_err(PuppetError::InvalidUserId)?;
+                Puppet::new(user_id, external_id.to_string())
+            }
+        };
+
+        Intent::new(self.client.clone(), puppet.user_id.clone())
+            .with_appservice_client(self.appservice.clone())
+            .ensure_registered()
+            .await?;
+        puppet.registered = true;
+        self.store
+            .insert(puppet.clone())
+            .await
+            .map_err(PuppetError::Store)?;
+        Ok(puppet)
+    }
+
+    /// Get an `Intent` acting as the puppet with the given `user_id`, marking it as a bridge
+    /// user in the echo tracker.
+    fn intent(&self, user_id: UserId) -> Intent<C> {
+        if let Some(tracker) = &self.echo {
+            tracker.add_user(user_id.clone());
+        }
+        Intent::new(self.client.clone(), user_id)
+            .with_echo_tracker(self.echo.clone())
+            .with_appservice_client(self.appservice.clone())
+    }
+
+    /// Get an `Intent` acting as the puppet of the external user `external_id`, creating the
+    /// puppet and registering its account if needed.
+    pub async fn puppet_for(
+        &self,
+        external_id: &str,
+    ) -> Result<Intent<C>, PuppetError<C::Error, S::Error>> {
+        let puppet = self.ensure_puppet(external_id).await?;
+        Ok(self.intent(puppet.user_id))
+    }
+
+    /// Get an `Intent` acting as the puppet of the external user `external_id`, making sure it
+    /// has joined the room with the given `room_id`.
+    ///
+    /// The room is only joined if the puppet isn't known to be in it already. Concurrent calls
+    /// for the same puppet may join a room twice, which is harmless.
+    pub async fn ensure_joined(
+        &self,
+        external_id: &str,
+        room_id: &RoomId,
+    ) -> Result<Intent<C>, PuppetError<C::Error, S::Error>> {
+        let mut puppet = self.ensure_puppet(external_id).await?;
+        let intent = self.intent(puppet.user_id.clone());
+        if puppet.is_joined(room_id) {
+            return Ok(intent);
+        }
+
+        intent.join(room_id).await?;
+        puppet.joined_rooms.insert(room_id.clone());
+        self.store
+            .insert(puppet)
+            .await
+            .map_err(PuppetError::Store)?;
+        Ok(intent)
+    }
+
+    /// Record whether the puppet with the given `user_id` is in the room with the given
+    /// `room_id`, for example after receiving a membership event for it.
+    ///
+    /// Does nothing if `user_id` isn't a known puppet.
+    pub async fn set_joined(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        joined: bool,
+    ) -> Result<(), S::Error> {
+        let mut puppet = match self.store.get(MappingId::Matrix(user_id)).await? {
+            Some(puppet) => puppet,
+            None => return Ok(()),
+        };
+
+        let changed = if joined {
+            puppet.joined_rooms.insert(room_id.clone())
+        } else {
+            puppet.joined_rooms.remove(room_id)
+        };
+        if changed {
+            self.store.insert(puppet).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{RoomId, ServerName, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{escape_localpart, PuppetManager};
+
+    #[test]
+    fn test_escape_localpart() {
+        assert_eq!(escape_localpart("alice.99"), "alice.99");
+        assert_eq!(escape_localpart("Alice_B"), "_alice___b");
+        assert_eq!(escape_localpart("a b:é"), "a=20b=3a=c3=a9");
+    }
+
+    #[tokio::test]
+    async fn test_puppet_manager() {
+        let (client, state) = mock_client();
+        state.respond(
+            "/register",
+            200,
+            json!({ "user_id": "@_ext_alice:example.org" }),
+        );
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let manager: PuppetManager<_> =
+            PuppetManager::new(client, server_name, "_ext_", Default::default());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        let intent = manager.puppet_for("alice").await.unwrap();
+        let user_id = UserId::try_from("@_ext_alice:example.org").unwrap();
+        assert_eq!(intent.user_id(), &user_id);
+
+        manager.ensure_joined("alice", &room_id).await.unwrap();
+        manager.ensure_joined("alice", &room_id).await.unwrap();
+        assert_eq!(state.requests_to("/register").len(), 1);
+        assert_eq!(state.requests_to("/join").len(), 1);
+        assert_eq!(
+            state.requests_to("/register")[0].body["username"],
+            "_ext_alice"
+        );
+
+        assert_eq!(
+            manager.external_id_for(&user_id).await.unwrap().as_deref(),
+            Some("alice")
+        );
+        manager.set_joined(&user_id, &room_id, false).await.unwrap();
+        assert!(!manager
+            .get("alice")
+            .await
+            .unwrap()
+            .unwrap()
+            .is_joined(&room_id));
+
+        // an existing account counts as registered.
+        state.respond_once(
+            "/register",
+            400,
+            json!({ "errcode": "M_USER_IN_USE", "error": "User ID already taken." }),
+        );
+        manager.puppet_for("Bob").await.unwrap();
+        let bob = manager.get("Bob").await.unwrap().unwrap();
+        assert!(bob.is_registered());
+        assert_eq!(bob.user_id().localpart(), "_ext__bob");
+    }
+}
@@ -0,0 +1,226 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ruma::api::client::error::ErrorKind;
+
+use crate::appservice::Registration;
+use crate::intent::IntentError;
+
+type DiagnosticFn = Box<dyn Fn(&str) + Send + Sync>;
+
+#[derive(Debug, Default)]
+struct RateLimitState {
+    count: u64,
+    consecutive: u32,
+    until: Option<Instant>,
+    reported: bool,
+}
+
+/// Watches the responses of the homeserver for rate limiting, to slow down sending when the
+/// homeserver rate-limits the users of the application service.
+///
+/// When `rate_limited` is `false` in the registration, the homeserver shouldn't rate-limit the
+/// virtual users at all. If it does anyway, the registration probably isn't loaded the way the
+/// bridge expects, which is reported once to the `on_unexpected_limit` callbacks with a
+/// diagnostic to show to the administrator of the bridge.
+///
+/// After every rate limited request, sending should wait for `wait_time`, which grows
+/// exponentially with consecutive rate limited requests between `set_delay`'s bounds, and is at
+/// least the time the homeserver asked to wait. A `SendQueue` given this monitor using
+/// `SendQueue::set_rate_limit_monitor` does so automatically.
+pub struct RateLimitMonitor {
+    rate_limited: bool,
+    min_delay: Duration,
+    max_delay: Duration,
+    state: Mutex<RateLimitState>,
+    on_unexpected_limit: Vec<DiagnosticFn>,
+}
+
+impl RateLimitMonitor {
+    /// Create a new `RateLimitMonitor` for an application service of which the virtual users are
+    /// expected to be rate limited if `rate_limited` is `true`.
+    ///
+    /// The delay after a rate limited request is half a second up to thirty seconds.
+    pub fn new(rate_limited: bool) -> Self {
+        Self {
+            rate_limited,
+            min_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            state: Mutex::default(),
+            on_unexpected_limit: vec![],
+        }
+    }
+
+    /// Create a new `RateLimitMonitor` for the application service with the given
+    /// `registration`.
+    ///
+    /// Homeservers rate-limit the users of an application service if `rate_limited` is missing
+    /// from the registration.
+    pub fn from_registration(registration: &Registration) -> Self {
+        Self::new(registration.rate_limited.unwrap_or(true))
+    }
+
+    /// Set the delay after the first rate limited request to `min`, which is doubled after every
+    /// next consecutive rate limited request up to `max`.
+    pub fn set_delay(&mut self, min: Duration, max: Duration) {
+        self.min_delay = min;
+        self.max_delay = max;
+    }
+
+    /// Call `f` with a diagnostic the first time the homeserver rate-limits a request while
+    /// `rate_limited` is `false`.
+    pub fn on_unexpected_limit<F>(&mut self, f: F)
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.on_unexpected_limit.push(Box::new(f));
+    }
+
+    /// Observe the error `err` of a failed request.
+    ///
+    /// Returns the time to wait before sending again if the request was rate limited, or `None`
+    /// if it failed otherwise.
+    pub fn observe<E>(&self, err: &IntentError<E>) -> Option<Duration> {
+        let retry_after = match err.matrix_error() {
+            Some(err) => match &err.kind {
+                ErrorKind::LimitExceeded { retry_after_ms } => *retry_after_ms,
+                _ if err.status_code.as_u16() == 429 => None,
+                _ => return None,
+            },
+            None => return None,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.count += 1;
+        state.consecutive = state.consecutive.saturating_add(1);
+        let factor = 2u32.saturating_pow(state.consecutive - 1);
+        let delay = self
+            .min_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+            .max(retry_after.unwrap_or_default());
+        state.until = Some(Instant::now() + delay);
+
+        let report = !self.rate_limited && !state.reported;
+        state.reported |= report;
+        drop(state);
+        if report {
+            let diagnostic = self.diagnostic().expect("rate limit should be unexpected");
+            for f in &self.on_unexpected_limit {
+                f(&diagnostic);
+            }
+        }
+        Some(delay)
+    }
+
+    /// Observe a request that succeeded, resetting the growth of the delay.
+    pub fn observe_success(&self) {
+        self.state.lock().unwrap().consecutive = 0;
+    }
+
+    /// Get the time to wait before sending again, or `None` if sending doesn't need to slow
+    /// down.
+    pub fn wait_time(&self) -> Option<Duration> {
+        let until = self.state.lock().unwrap().until?;
+        let now = Instant::now();
+        if until > now {
+            Some(until - now)
+        } else {
+            None
+        }
+    }
+
+    /// Get the amount of rate limited requests observed.
+    pub fn rate_limited_count(&self) -> u64 {
+        self.state.lock().unwrap().count
+    }
+
+    /// Returns whether the homeserver rate-limited requests even though `rate_limited` is
+    /// `false`.
+    pub fn is_unexpectedly_limited(&self) -> bool {
+        !self.rate_limited && self.rate_limited_count() > 0
+    }
+
+    /// Get a description of the unexpected rate limiting and how it can be fixed, to show to the
+    /// administrator of the bridge, or `None` if no requests were unexpectedly rate limited.
+    pub fn diagnostic(&self) -> Option<String> {
+        if !self.is_unexpectedly_limited() {
+            return None;
+        }
+        Some(format!(
+            "the homeserver rate-limited {} requests even though rate_limited is false, is the \
+             current registration file loaded by the homeserver?",
+            self.rate_limited_count()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use ruma::identifiers::{RoomId, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{Intent, IntentError, RateLimitMonitor};
+
+    #[tokio::test]
+    async fn test_rate_limit_monitor() {
+        let (client, state) = mock_client();
+        let ghost = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let mut monitor = RateLimitMonitor::new(false);
+        monitor.set_delay(Duration::from_secs(1), Duration::from_secs(3));
+        let reports = Arc::new(Mutex::new(vec![]));
+        let reported = reports.clone();
+        monitor.on_unexpected_limit(move |diagnostic| {
+            reported.lock().unwrap().push(diagnostic.to_string());
+        });
+
+        let limited = json!({ "errcode": "M_LIMIT_EXCEEDED", "error": "Too many requests" });
+        state.respond("/leave", 429, limited);
+        let err: IntentError<_> = ghost.leave(&room_id).await.unwrap_err();
+        assert_eq!(monitor.observe(&err), Some(Duration::from_secs(1)));
+        assert_eq!(monitor.observe(&err), Some(Duration::from_secs(2)));
+        assert_eq!(monitor.observe(&err), Some(Duration::from_secs(3)));
+        assert!(monitor.wait_time().is_some());
+        monitor.observe_success();
+        assert_eq!(monitor.observe(&err), Some(Duration::from_secs(1)));
+
+        state.respond_once(
+            "/leave",
+            429,
+            json!({ "errcode": "M_LIMIT_EXCEEDED", "error": "Slow down", "retry_after_ms": 5000 }),
+        );
+        let err = ghost.leave(&room_id).await.unwrap_err();
+        assert_eq!(monitor.observe(&err), Some(Duration::from_secs(5)));
+
+        assert!(monitor.is_unexpectedly_limited());
+        assert_eq!(monitor.rate_limited_count(), 5);
+        assert_eq!(reports.lock().unwrap().len(), 1);
+        assert!(monitor
+            .diagnostic()
+            .unwrap()
+            .contains("rate_limited is false"));
+
+        state.respond_once(
+            "/join",
+            403,
+            json!({ "errcode": "M_FORBIDDEN", "error": "Not invited" }),
+        );
+        let err = ghost.join(&room_id).await.unwrap_err();
+        assert_eq!(monitor.observe(&err), None);
+    }
+
+    #[test]
+    fn test_expected_rate_limits() {
+        let monitor = RateLimitMonitor::new(true);
+        assert!(!monitor.is_unexpectedly_limited());
+        assert!(monitor.diagnostic().is_none());
+        assert!(monitor.wait_time().is_none());
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+
+use ruma::events::receipt::ReceiptEvent;
+use ruma::events::AnyEphemeralRoomEvent;
+use ruma::identifiers::{EventId, RoomId, UserId};
+use ruma::receipt::ReceiptType;
+use ruma::serde::Raw;
+use ruma::MilliSecondsSinceUnixEpoch;
+
+/// The position up to which a user has read a room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadPosition {
+    /// The ID of the last event the user has read.
+    pub event_id: EventId,
+    /// The time the user read the event, if known.
+    pub timestamp: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+/// The read positions of users in a room, parsed from an `m.receipt` EDU.
+///
+/// Application services receive these EDUs in the `ephemeral` field of transactions when
+/// `de.sorunome.msc2409.push_ephemeral` is enabled in the registration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadReceipts {
+    /// The room the receipts are in.
+    pub room_id: RoomId,
+    /// The read position of every user that sent a read receipt.
+    pub positions: BTreeMap<UserId, ReadPosition>,
+}
+
+impl ReadReceipts {
+    /// Get the read positions in the given receipt `event`.
+    ///
+    /// When the event contains multiple read receipts of the same user, the latest one is used.
+    pub fn from_event(event: &ReceiptEvent) -> Self {
+        let mut positions: BTreeMap<UserId, ReadPosition> = BTreeMap::new();
+
+        for (event_id, receipts) in event.content.iter() {
+            let users = match receipts.get(&ReceiptType::Read) {
+                Some(users) => users,
+                None => continue,
+            };
+
+            for (user_id, receipt) in users {
+                let is_newer = match positions.get(user_id) {
+                    Some(position) => receipt.ts > position.timestamp,
+                    None => true,
+                };
+                if is_newer {
+                    let position = ReadPosition {
+                        event_id: event_id.clone(),
+                        timestamp: receipt.ts,
+                    };
+                    positions.insert(user_id.clone(), position);
+                }
+            }
+        }
+
+        Self {
+            room_id: event.room_id.clone(),
+            positions,
+        }
+    }
+
+    /// Get the read positions in the given ephemeral `event`, or `None` if it isn't a valid
+    /// `m.receipt` event.
+    pub fn from_raw(event: &Raw<AnyEphemeralRoomEvent>) -> Option<Self> {
+        match event.deserialize() {
+            Ok(AnyEphemeralRoomEvent::Receipt(event)) => Some(Self::from_event(&event)),
+            _ => None,
+        }
+    }
+
+    /// Get the read position of the user with the given `user_id`, if they sent a read receipt.
+    pub fn position(&self, user_id: &UserId) -> Option<&ReadPosition> {
+        self.positions.get(user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::events::AnyEphemeralRoomEvent;
+    use ruma::identifiers::{EventId, RoomId, UserId};
+    use ruma::serde::Raw;
+    use serde_json::json;
+    use serde_json::value::to_raw_value;
+
+    use crate::testing::mock_client;
+    use crate::{Intent, ReadReceipts};
+
+    #[test]
+    fn test_read_receipts() {
+        let event = json!({
+            "type": "m.receipt",
+            "room_id": "!room:example.org",
+            "content": {
+                "$a:example.org": {
+                    "m.read": {
+                        "@alice:example.org": { "ts": 1000 },
+                        "@bob:example.org": { "ts": 3000 },
+                    },
+                },
+                "$b:example.org": {
+                    "m.read": { "@alice:example.org": { "ts": 2000 } },
+                },
+            },
+        });
+        let event: Raw<AnyEphemeralRoomEvent> = Raw::from_json(to_raw_value(&event).unwrap());
+        let receipts = ReadReceipts::from_raw(&event).unwrap();
+
+        assert_eq!(receipts.room_id.as_str(), "!room:example.org");
+        assert_eq!(receipts.positions.len(), 2);
+        let alice = UserId::try_from("@alice:example.org").unwrap();
+        let position = receipts.position(&alice).unwrap();
+        assert_eq!(position.event_id.as_str(), "$b:example.org");
+        assert_eq!(u64::from(position.timestamp.unwrap().get()), 2000);
+
+        let typing = json!({
+            "type": "m.typing",
+            "room_id": "!room:example.org",
+            "content": { "user_ids": [] },
+        });
+        let typing = Raw::from_json(to_raw_value(&typing).unwrap());
+        assert!(ReadReceipts::from_raw(&typing).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_receipts() {
+        let (client, state) = mock_client();
+        let user_id = UserId::try_from("@_ext_alice:example.org").unwrap();
+        let intent = Intent::new(client, user_id);
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let event_id = EventId::try_from("$event:example.org").unwrap();
+
+        intent.send_read_receipt(&room_id, &event_id).await.unwrap();
+        intent
+            .set_read_markers(&room_id, &event_id, Some(&event_id))
+            .await
+            .unwrap();
+
+        let requests = state.requests();
+        assert!(requests[0].path.starts_with(
+            "/_matrix/client/r0/rooms/!room:example.org/receipt/m.read/$event:example.org?user_id="
+        ));
+        assert!(requests[1].path.contains("/read_markers?"));
+        assert_eq!(
+            requests[1].body,
+            json!({ "m.fully_read": "$event:example.org", "m.read": "$event:example.org" })
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use futures::stream::{self, StreamExt};
+use ruma::identifiers::{RoomId, UserId};
+use ruma_client::HttpClient;
+use thiserror::Error;
+
+use crate::intent::{Intent, IntentError};
+use crate::mappingdict::MappingDict;
+use crate::portal::{Portal, PortalManager};
+use crate::puppet::{Puppet, PuppetManager};
+use crate::store::MappingStore;
+
+/// A difference between the rooms the bridge is in and the rooms it has stored, found by a
+/// `Reconciler`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// The bot isn't in the room of the portal anymore, so the portal can't be bridged.
+    OrphanedPortal(Portal),
+    /// The bot or a puppet is in a room that isn't a portal.
+    ///
+    /// The management rooms of the bot and the direct chats of the users with it are reported too,
+    /// since only the bridge knows about them.
+    UnknownRoom {
+        /// The user in the room.
+        user_id: UserId,
+        /// The ID of the room.
+        room_id: RoomId,
+    },
+    /// The store says the puppet is in a room it has left.
+    StaleMembership {
+        /// The user ID of the puppet.
+        user_id: UserId,
+        /// The ID of the room.
+        room_id: RoomId,
+    },
+    /// The puppet is in a portal the store doesn't know it's in.
+    UntrackedMembership {
+        /// The user ID of the puppet.
+        user_id: UserId,
+        /// The ID of the room.
+        room_id: RoomId,
+    },
+}
+
+/// The outcome of `Reconciler::run`.
+#[derive(Debug)]
+pub struct ReconcileReport<E> {
+    /// The differences that were found.
+    pub discrepancies: Vec<Discrepancy>,
+    /// The puppets of which the rooms couldn't be listed, which are skipped.
+    pub errors: Vec<(UserId, IntentError<E>)>,
+}
+
+/// An error from a `Reconciler`.
+#[derive(Debug, Error)]
+pub enum ReconcileError<E, P, S> {
+    /// Listing the rooms of the bot failed.
+    #[error("{0}")]
+    Intent(IntentError<E>),
+    /// Loading the portals failed.
+    #[error("loading the portals failed: {0}")]
+    PortalStore(P),
+    /// Loading or saving a puppet failed.
+    #[error("loading or saving a puppet failed: {0}")]
+    PuppetStore(S),
+}
+
+impl<E, P, S> From<IntentError<E>> for ReconcileError<E, P, S> {
+    fn from(err: IntentError<E>) -> Self {
+        ReconcileError::Intent(err)
+    }
+}
+
+/// Compares the rooms the bot and the puppets are joined to with the portals and puppets in the
+/// stores, for example at startup after the bridge was down for a while.
+///
+/// Finds portals of which the bot has left the room, rooms joined by the bot or a puppet that
+/// aren't portals, and puppets that are stored as joined to rooms they have left or the other way
+/// around. Only unregistered puppets are skipped, since they can't be in any room.
+///
+/// Nothing is changed unless `update_puppets` is set, since what to do with an orphaned portal or
+/// an unknown room is up to the bridge.
+pub struct Reconciler<'a, C, P = Mutex<MappingDict<Portal>>, S = Mutex<MappingDict<Puppet>>> {
+    portals: &'a PortalManager<C, P>,
+    puppets: &'a PuppetManager<C, S>,
+    concurrency: usize,
+    update_puppets: bool,
+}
+
+impl<'a, C, P, S> Reconciler<'a, C, P, S>
+where
+    C: HttpClient + Clone,
+    P: MappingStore<Portal>,
+    S: MappingStore<Puppet>,
+{
+    /// Create a new `Reconciler` comparing the rooms of the bot of `portals` and the puppets of
+    /// `puppets` with their stores.
+    pub fn new(portals: &'a PortalManager<C, P>, puppets: &'a PuppetManager<C, S>) -> Self {
+        Self {
+            portals,
+            puppets,
+            concurrency: 5,
+            update_puppets: false,
+        }
+    }
+
+    /// Set the amount of puppets of which the rooms are listed at the same time, returning the
+    /// current `Reconciler` to allow method chaining.
+    pub fn concurrency(&mut self, concurrency: usize) -> &mut Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Set whether to fix the rooms stored for the puppets, returning the current `Reconciler`
+    /// to allow method chaining.
+    ///
+    /// The stale and untracked memberships are still reported.
+    pub fn update_puppets(&mut self, update_puppets: bool) -> &mut Self {
+        self.update_puppets = update_puppets;
+        self
+    }
+
+    /// Compare the joined rooms with the stores.
+    ///
+    /// Fails if the rooms of the bot or the stored portals or puppets can't be loaded. Puppets of
+    /// which the rooms can't be listed are reported in `ReconcileReport::errors`.
+    pub async fn run(
+        &self,
+    ) -> Result<ReconcileReport<C::Error>, ReconcileError<C::Error, P::Error, S::Error>> {
+        let bot = self.portals.bot();
+        let portals: BTreeMap<RoomId, Portal> = self
+            .portals
+            .portals()
+            .await
+            .map_err(ReconcileError::PortalStore)?
+            .into_iter()
+            .map(|portal| (portal.room_id().clone(), portal))
+            .collect();
+        let bot_rooms: BTreeSet<RoomId> = bot.joined_rooms().await?.into_iter().collect();
+
+        let mut discrepancies: Vec<_> = portals
+            .iter()
+            .filter(|(room_id, _)| !bot_rooms.contains(room_id))
+            .map(|(_, portal)| Discrepancy::OrphanedPortal(portal.clone()))
+            .collect();
+        discrepancies.extend(
+            bot_rooms
+                .iter()
+                .filter(|room_id| !portals.contains_key(room_id))
+                .map(|room_id| Discrepancy::UnknownRoom {
+                    user_id: bot.user_id().clone(),
+                    room_id: room_id.clone(),
+                }),
+        );
+
+        let puppets = self
+            .puppets
+            .puppets()
+            .await
+            .map_err(ReconcileError::PuppetStore)?;
+        let results: Vec<_> = stream::iter(puppets.iter().filter(|p| p.is_registered()))
+            .map(|puppet| async move {
+                let intent = Intent::new(bot.client().clone(), puppet.user_id().clone())
+                    .with_appservice_client(bot.appservice_client().cloned());
+                (puppet, intent.joined_rooms().await)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await;
+
+        let mut errors = vec![];
+        for (puppet, result) in results {
+            let joined: BTreeSet<RoomId> = match result {
+                Ok(rooms) => rooms.into_iter().collect(),
+                Err(err) => {
+                    errors.push((puppet.user_id().clone(), err));
+                    continue;
+                }
+            };
+            let stored: BTreeSet<&RoomId> = puppet.joined_rooms().collect();
+            let user_id = puppet.user_id();
+
+            for room_id in stored.iter().filter(|room_id| !joined.contains(room_id)) {
+                discrepancies.push(Discrepancy::StaleMembership {
+                    user_id: user_id.clone(),
+                    room_id: (*room_id).clone(),
+                });
+                self.set_joined(user_id, room_id, false).await?;
+            }
+            for room_id in joined.iter().filter(|room_id| !stored.contains(room_id)) {
+                if !portals.contains_key(room_id) {
+                    discrepancies.push(Discrepancy::UnknownRoom {
+                        user_id: user_id.clone(),
+                        room_id: room_id.clone(),
+                    });
+                    continue;
+                }
+                discrepancies.push(Discrepancy::UntrackedMembership {
+                    user_id: user_id.clone(),
+                    room_id: room_id.clone(),
+                });
+                self.set_joined(user_id, room_id, true).await?;
+            }
+        }
+
+        Ok(ReconcileReport {
+            discrepancies,
+            errors,
+        })
+    }
+
+    async fn set_joined(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        joined: bool,
+    ) -> Result<(), ReconcileError<C::Error, P::Error, S::Error>> {
+        if !self.update_puppets {
+            return Ok(());
+        }
+        self.puppets
+            .set_joined(user_id, room_id, joined)
+            .await
+            .map_err(ReconcileError::PuppetStore)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{RoomId, ServerName, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{
+        Discrepancy, Intent, MappingStore, Portal, PortalManager, PuppetManager, Reconciler,
+    };
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(
+            client.clone(),
+            UserId::try_from("@bot:example.org").unwrap(),
+        );
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let portals: PortalManager<_> =
+            PortalManager::new(bot, server_name.clone(), "_ext_", Default::default());
+        let puppets: PuppetManager<_> =
+            PuppetManager::new(client, server_name, "_ext_", Default::default());
+
+        let room = |s: &str| RoomId::try_from(s).unwrap();
+        let bob = UserId::try_from("@_ext_bob:example.org").unwrap();
+        let left = Portal::new(room("!left:example.org"), "left".to_string(), None);
+        for portal in &[
+            Portal::new(room("!a:example.org"), "a".to_string(), None),
+            Portal::new(room("!b:example.org"), "b".to_string(), None),
+            left.clone(),
+        ] {
+            portals.store().insert(portal.clone()).await.unwrap();
+        }
+
+        state.respond("/register", 200, json!({ "user_id": bob.as_str() }));
+        puppets.puppet_for("bob").await.unwrap();
+        puppets.puppet_for("carol").await.unwrap();
+        let (a, b) = (room("!a:example.org"), room("!b:example.org"));
+        puppets.set_joined(&bob, &a, true).await.unwrap();
+
+        state.respond(
+            "user_id=@_ext_bob:example.org",
+            200,
+            json!({ "joined_rooms": ["!b:example.org", "!dm:example.org"] }),
+        );
+        state.respond(
+            "user_id=@_ext_carol:example.org",
+            403,
+            json!({ "errcode": "M_FORBIDDEN", "error": "No" }),
+        );
+        state.respond(
+            "/joined_rooms",
+            200,
+            json!({ "joined_rooms": ["!a:example.org", "!b:example.org", "!admin:example.org"] }),
+        );
+
+        let mut reconciler = Reconciler::new(&portals, &puppets);
+        let report = reconciler.run().await.unwrap();
+        let expected = vec![
+            Discrepancy::OrphanedPortal(left),
+            Discrepancy::UnknownRoom {
+                user_id: UserId::try_from("@bot:example.org").unwrap(),
+                room_id: room("!admin:example.org"),
+            },
+            Discrepancy::StaleMembership {
+                user_id: bob.clone(),
+                room_id: a.clone(),
+            },
+            Discrepancy::UntrackedMembership {
+                user_id: bob.clone(),
+                room_id: b.clone(),
+            },
+            Discrepancy::UnknownRoom {
+                user_id: bob.clone(),
+                room_id: room("!dm:example.org"),
+            },
+        ];
+        assert_eq!(report.discrepancies, expected);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0.as_str(), "@_ext_carol:example.org");
+        let puppet = puppets.get("bob").await.unwrap().unwrap();
+        assert_eq!(puppet.joined_rooms().collect::<Vec<_>>(), [&a]);
+
+        let report = reconciler.update_puppets(true).run().await.unwrap();
+        assert_eq!(report.discrepancies, expected);
+        let puppet = puppets.get("bob").await.unwrap().unwrap();
+        assert_eq!(puppet.joined_rooms().collect::<Vec<_>>(), [&b]);
+    }
+}
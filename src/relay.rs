@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+use ruma::events::room::message::{
+    FormattedBody, MessageEventContent, MessageFormat, MessageType, TextMessageEventContent,
+};
+use ruma::events::AnyMessageEventContent;
+use ruma::identifiers::{EventId, RoomId};
+use ruma_client::HttpClient;
+
+use crate::displayname::disambiguate;
+use crate::intent::{Intent, IntentError};
+use crate::util::escape_html;
+
+/// The colors used for the names of relayed users.
+const COLORS: [&str; 8] = [
+    "#e5534b", "#c69026", "#57ab5a", "#39c5cf", "#539bf5", "#986ee2", "#d669a6", "#8e8e8e",
+];
+
+/// A user of the external service whose messages are relayed by the bot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelaySender {
+    /// The ID of the user on the external service.
+    pub id: String,
+    /// The display name of the user on the external service.
+    pub display_name: String,
+}
+
+impl RelaySender {
+    /// Create a new `RelaySender` for the external user `id` with the given `display_name`.
+    pub fn new(id: &str, display_name: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            display_name: display_name.to_string(),
+        }
+    }
+}
+
+/// Formats messages of users of the external service that are sent by a single bot account,
+/// instead of by a virtual user for every external user.
+///
+/// Every message is prefixed with the name of its sender using a template, like `<{name}> `,
+/// in which `{name}` is replaced with the display name of the sender and `{id}` with their ID.
+/// When different users with the same display name send messages in a room, their ID is added
+/// to the name to tell them apart. The name can be given a color based on the ID of the user in
+/// the formatted body.
+///
+/// Relay mode is disabled for all portals by default, which can be changed using `set_enabled`,
+/// and overridden for a single portal using `set_room_enabled`. Bridges should check
+/// `is_enabled` to choose between sending a message as the virtual user of the sender or
+/// through the relay.
+pub struct RelayFormatter {
+    template: String,
+    colors: bool,
+    enabled: bool,
+    rooms: Mutex<HashMap<RoomId, bool>>,
+    names: Mutex<HashMap<RoomId, HashMap<String, String>>>,
+}
+
+impl RelayFormatter {
+    /// Create a new `RelayFormatter` with the template `<{name}> ` and without colors.
+    pub fn new() -> Self {
+        Self {
+            template: String::from("<{name}> "),
+            colors: false,
+            enabled: false,
+            rooms: Mutex::new(HashMap::new()),
+            names: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set the template of the prefix of relayed messages.
+    pub fn set_template(&mut self, template: &str) {
+        self.template = template.to_string();
+    }
+
+    /// Set whether the names of users are colored in formatted bodies.
+    pub fn set_colors(&mut self, colors: bool) {
+        self.colors = colors;
+    }
+
+    /// Set whether relay mode is enabled for portals without their own setting.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Set whether relay mode is enabled for the portal with the given `room_id`, or use the
+    /// default again if `enabled` is `None`.
+    pub fn set_room_enabled(&self, room_id: &RoomId, enabled: Option<bool>) {
+        let mut rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+        match enabled {
+            Some(enabled) => rooms.insert(room_id.clone(), enabled),
+            None => rooms.remove(room_id),
+        };
+    }
+
+    /// Returns whether relay mode is enabled for the portal with the given `room_id`.
+    pub fn is_enabled(&self, room_id: &RoomId) -> bool {
+        let rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+        rooms.get(room_id).copied().unwrap_or(self.enabled)
+    }
+
+    /// Get the color of the external user `id`, as a hex color like `#539bf5`.
+    pub fn color_for(id: &str) -> &'static str {
+        // FNV-1a, so the color of a user stays the same between runs.
+        let hash = id.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+        });
+        COLORS[(hash % COLORS.len() as u64) as usize]
+    }
+
+    /// Get the name shown for `sender` in the room with the given `room_id`, which is their
+    /// display name followed by their ID if another user with the same display name sent a
+    /// message in the room.
+    pub fn display_name_for(&self, room_id: &RoomId, sender: &RelaySender) -> String {
+        let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+        let room = names.entry(room_id.clone()).or_default();
+        room.insert(sender.id.clone(), sender.display_name.clone());
+
+        let ambiguous = room
+            .iter()
+            .any(|(id, name)| *id != sender.id && *name == sender.display_name);
+        if ambiguous {
+            disambiguate(&sender.display_name, &sender.id)
+        } else {
+            sender.display_name.clone()
+        }
+    }
+
+    /// Forget the display names of the users seen in the room with the given `room_id`, like
+    /// when the portal is removed.
+    pub fn forget_room(&self, room_id: &RoomId) {
+        let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+        names.remove(room_id);
+    }
+
+    fn prefix(&self, name: &str, id: &str) -> String {
+        self.template.replace("{name}", name).replace("{id}", id)
+    }
+
+    fn html_name(&self, name: &str, id: &str) -> String {
+        if self.colors {
+            format!(
+                "<font color=\"{}\">{}</font>",
+                Self::color_for(id),
+                escape_html(name)
+            )
+        } else {
+            escape_html(name)
+        }
+    }
+
+    fn html_prefix(&self, name: &str, id: &str) -> String {
+        escape_html(&self.template)
+            .replace("{name}", &self.html_name(name, id))
+            .replace("{id}", &escape_html(id))
+    }
+
+    /// Format the message `content` of `sender`, to be sent to the room with the given
+    /// `room_id` by the bot.
+    ///
+    /// Texts and notices get the prefix with the name of the sender, and emotes become texts
+    /// starting with `* ` and the name of the sender. Returns `None` for other messages, like
+    /// media, which can't get a prefix; these should be sent after `announcement`.
+    pub fn format(
+        &self,
+        room_id: &RoomId,
+        sender: &RelaySender,
+        mut content: MessageEventContent,
+    ) -> Option<MessageEventContent> {
+        let name = self.display_name_for(room_id, sender);
+        let (prefix, html_prefix) = match &content.msgtype {
+            MessageType::Emote(_) => (
+                format!("* {} ", name),
+                format!("* {} ", self.html_name(&name, &sender.id)),
+            ),
+            _ => (
+                self.prefix(&name, &sender.id),
+                self.html_prefix(&name, &sender.id),
+            ),
+        };
+        let prefixed = |body: &str, formatted: Option<FormattedBody>| {
+            let html = match formatted {
+                Some(formatted) if formatted.format == MessageFormat::Html => formatted.body,
+                _ => escape_html(body).replace('\n', "<br>"),
+            };
+            (
+                format!("{}{}", prefix, body),
+                Some(FormattedBody::html(format!("{}{}", html_prefix, html))),
+            )
+        };
+
+        content.msgtype = match content.msgtype {
+            MessageType::Text(mut text) => {
+                let (body, formatted) = prefixed(&text.body, text.formatted.take());
+                text.body = body;
+                text.formatted = formatted;
+                MessageType::Text(text)
+            }
+            MessageType::Notice(mut notice) => {
+                let (body, formatted) = prefixed(&notice.body, notice.formatted.take());
+                notice.body = body;
+                notice.formatted = formatted;
+                MessageType::Notice(notice)
+            }
+            MessageType::Emote(emote) => {
+                let (body, formatted) = prefixed(&emote.body, emote.formatted);
+                let mut text = TextMessageEventContent::plain(body);
+                text.formatted = formatted;
+                MessageType::Text(text)
+            }
+            _ => return None,
+        };
+        Some(content)
+    }
+
+    /// Get the notice announcing a message of `sender` that can't get a prefix, like an image,
+    /// in the room with the given `room_id`.
+    pub fn announcement(
+        &self,
+        room_id: &RoomId,
+        sender: &RelaySender,
+        content: &MessageEventContent,
+    ) -> MessageEventContent {
+        let name = self.display_name_for(room_id, sender);
+        let what = match &content.msgtype {
+            MessageType::Image(_) => "an image",
+            MessageType::Audio(_) => "an audio message",
+            MessageType::Video(_) => "a video",
+            MessageType::File(_) => "a file",
+            MessageType::Location(_) => "a location",
+            _ => "a message",
+        };
+        MessageEventContent::notice_html(
+            format!("{}sent {}", self.prefix(&name, &sender.id), what),
+            format!("{}sent {}", self.html_prefix(&name, &sender.id), what),
+        )
+    }
+
+    /// Send the message `content` of `sender` as the bot `intent` to the room with the given
+    /// `room_id`, formatted using `format`, or after an `announcement` if it can't get a
+    /// prefix.
+    ///
+    /// Returns the ID of the event with the message itself.
+    pub async fn send_message<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+        room_id: &RoomId,
+        sender: &RelaySender,
+        content: MessageEventContent,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let content = match self.format(room_id, sender, content.clone()) {
+            Some(formatted) => formatted,
+            None => {
+                let announcement = self.announcement(room_id, sender, &content);
+                intent
+                    .send_message(room_id, &AnyMessageEventContent::RoomMessage(announcement))
+                    .await?;
+                content
+            }
+        };
+        intent
+            .send_message(room_id, &AnyMessageEventContent::RoomMessage(content))
+            .await
+    }
+}
+
+impl Default for RelayFormatter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::events::room::message::{EmoteMessageEventContent, MessageEventContent, MessageType};
+    use ruma::identifiers::{MxcUri, RoomId, UserId};
+    use serde_json::{json, to_value};
+
+    use crate::testing::mock_client;
+    use crate::{Attachment, Intent, RelayFormatter, RelaySender};
+
+    #[test]
+    fn test_format() {
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let mut relay = RelayFormatter::new();
+        relay.set_colors(true);
+        let alice = RelaySender::new("alice#1", "Alice");
+
+        let content = relay
+            .format(
+                &room_id,
+                &alice,
+                MessageEventContent::text_plain("hi <3\nbye"),
+            )
+            .unwrap();
+        let color = RelayFormatter::color_for("alice#1");
+        assert_eq!(
+            to_value(&content).unwrap(),
+            json!({
+                "msgtype": "m.text",
+                "body": "<Alice> hi <3\nbye",
+                "format": "org.matrix.custom.html",
+                "formatted_body": format!(
+                    "&lt;<font color=\"{}\">Alice</font>&gt; hi &lt;3<br>bye",
+                    color
+                ),
+            })
+        );
+
+        relay.set_colors(false);
+        relay.set_template("{name}: ");
+        let other = RelaySender::new("alice#2", "Alice");
+        let content = relay
+            .format(
+                &room_id,
+                &other,
+                MessageEventContent::new(MessageType::Emote(EmoteMessageEventContent::plain(
+                    "waves",
+                ))),
+            )
+            .unwrap();
+        let content = to_value(&content).unwrap();
+        assert_eq!(content["msgtype"], "m.text");
+        assert_eq!(content["body"], "* Alice (alice#2) waves");
+
+        let image = Attachment::image("cat.png").build(MxcUri::from("mxc://example.org/cat"));
+        assert!(relay.format(&room_id, &alice, image.clone()).is_none());
+        let announcement = to_value(relay.announcement(&room_id, &alice, &image)).unwrap();
+        assert_eq!(announcement["body"], "Alice (alice#1): sent an image");
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let mut relay = RelayFormatter::new();
+        assert!(!relay.is_enabled(&room_id));
+        relay.set_enabled(true);
+        relay.set_room_enabled(&room_id, Some(false));
+        assert!(!relay.is_enabled(&room_id));
+        relay.set_room_enabled(&room_id, None);
+        assert!(relay.is_enabled(&room_id));
+
+        state.respond("/send/", 200, json!({ "event_id": "$msg:example.org" }));
+        let bob = RelaySender::new("bob", "Bob");
+        relay
+            .send_message(
+                &bot,
+                &room_id,
+                &bob,
+                MessageEventContent::notice_plain("hello"),
+            )
+            .await
+            .unwrap();
+        let image = Attachment::image("cat.png").build(MxcUri::from("mxc://example.org/cat"));
+        relay
+            .send_message(&bot, &room_id, &bob, image)
+            .await
+            .unwrap();
+
+        let sends = state.requests_to("/send/m.room.message/");
+        assert_eq!(sends.len(), 3);
+        assert_eq!(sends[0].body["body"], "<Bob> hello");
+        assert_eq!(sends[0].body["msgtype"], "m.notice");
+        assert_eq!(sends[1].body["body"], "<Bob> sent an image");
+        assert_eq!(sends[2].body["msgtype"], "m.image");
+    }
+}
@@ -0,0 +1,281 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use ruma::events::AnyRoomEvent;
+use ruma::serde::Raw;
+use ruma_client::HttpClient;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::requestlog::with_transaction;
+use crate::service::{Appservice, AppserviceError};
+use crate::store::BridgeStore;
+
+/// A transaction sent by the homeserver, as written by a `TransactionRecorder`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedTransaction {
+    /// The ID of the transaction.
+    pub txn_id: String,
+    /// The events in the transaction.
+    pub events: Vec<Raw<AnyRoomEvent>>,
+}
+
+#[derive(Serialize)]
+struct TransactionRef<'a> {
+    txn_id: &'a str,
+    events: &'a [Raw<AnyRoomEvent>],
+}
+
+/// Writes the transactions sent by the homeserver to a JSONL file, one transaction per line, so
+/// they can be replayed later using `Appservice::replay`.
+///
+/// The events are written as they were received, so a recording contains the messages of the
+/// users of the bridge and should be handled with care.
+pub struct TransactionRecorder {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl TransactionRecorder {
+    /// Create a new `TransactionRecorder` writing to `writer`.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Create a new `TransactionRecorder` appending to the file at `path`, creating it if needed.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+
+    /// Write the transaction with the given `txn_id` and `events`.
+    pub fn record(&self, txn_id: &str, events: &[Raw<AnyRoomEvent>]) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&TransactionRef { txn_id, events })?;
+        line.push(b'\n');
+
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        writer.write_all(&line)?;
+        writer.flush()
+    }
+}
+
+/// An error from reading recorded transactions.
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    /// There was an error reading the file.
+    #[error("reading the file failed: {0}")]
+    Io(io::Error),
+    /// A line doesn't contain a valid transaction.
+    #[error("invalid transaction on line {line}: {source}")]
+    Format {
+        /// The number of the line, starting at 1.
+        line: usize,
+        /// The error from parsing the line.
+        source: serde_json::Error,
+    },
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(err: io::Error) -> Self {
+        ReplayError::Io(err)
+    }
+}
+
+/// Read the transactions written by a `TransactionRecorder` from `reader`. Empty lines are
+/// skipped.
+pub fn read_transactions<R: BufRead>(reader: R) -> Result<Vec<RecordedTransaction>, ReplayError> {
+    let mut transactions = vec![];
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let transaction = serde_json::from_str(&line).map_err(|source| ReplayError::Format {
+            line: i + 1,
+            source,
+        })?;
+        transactions.push(transaction);
+    }
+    Ok(transactions)
+}
+
+/// Read the transactions written by a `TransactionRecorder` from the file at `path`.
+pub fn load_transactions<P: AsRef<Path>>(path: P) -> Result<Vec<RecordedTransaction>, ReplayError> {
+    read_transactions(BufReader::new(File::open(path)?))
+}
+
+/// The outcome of `Appservice::replay`.
+#[derive(Debug)]
+pub struct ReplayReport<E, S> {
+    /// The amount of transactions that were replayed.
+    pub transactions: usize,
+    /// The amount of events in the replayed transactions, including the ones that were filtered
+    /// out.
+    pub events: usize,
+    /// How long replaying took.
+    pub elapsed: Duration,
+    /// The IDs of the transactions that failed, and their errors.
+    pub errors: Vec<(String, AppserviceError<E, S>)>,
+}
+
+impl<E, S> ReplayReport<E, S> {
+    /// Get the amount of events that were handled per second.
+    pub fn events_per_second(&self) -> f64 {
+        self.events as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl<C, B> Appservice<C, B>
+where
+    C: HttpClient + Clone,
+    B: BridgeStore,
+{
+    /// Handle the recorded `transactions` in order, as if the homeserver sent them.
+    ///
+    /// Unlike `handle_transaction`, transactions are handled also if they were handled before,
+    /// and they aren't marked as processed. A failing transaction doesn't stop the others, its
+    /// error is returned in the report.
+    pub async fn replay(
+        self: &Arc<Self>,
+        transactions: &[RecordedTransaction],
+    ) -> ReplayReport<C::Error, B::Error> {
+        let start = Instant::now();
+        let mut errors = vec![];
+        for transaction in transactions {
+            let dispatch = self.dispatch(&transaction.events);
+            if let Err(err) = with_transaction(&transaction.txn_id, dispatch).await {
+                errors.push((transaction.txn_id.clone(), err));
+            }
+        }
+
+        ReplayReport {
+            transactions: transactions.len(),
+            events: transactions.iter().map(|txn| txn.events.len()).sum(),
+            elapsed: start.elapsed(),
+            errors,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+
+    use ruma::events::AnyRoomEvent;
+    use ruma::identifiers::ServerName;
+    use ruma::serde::Raw;
+    use serde_json::{json, value::to_raw_value};
+
+    use crate::testing::mock_client;
+    use crate::{
+        load_transactions, read_transactions, ApplicationService, Appservice, Error,
+        MemoryBridgeStore, Namespaces, Registration, RegistrationInit, ReplayError,
+        TransactionRecorder,
+    };
+
+    fn message(room_id: &str, body: &str) -> Raw<AnyRoomEvent> {
+        let event = json!({
+            "type": "m.room.message",
+            "event_id": format!("${}:example.org", body),
+            "room_id": room_id,
+            "sender": "@alice:example.org",
+            "origin_server_ts": 0,
+            "content": { "msgtype": "m.text", "body": body },
+        });
+        Raw::from_json(to_raw_value(&event).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_record_replay() {
+        let dir = std::env::temp_dir().join(format!("replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("transactions.jsonl");
+
+        let (client, _) = mock_client();
+        let registration = Registration::from(RegistrationInit {
+            id: String::from("bridge"),
+            as_token: String::from("as_token"),
+            hs_token: String::from("hs_token"),
+            namespaces: Namespaces::new(),
+            url: String::from("http://localhost:8080"),
+            sender_localpart: String::from("bridgebot"),
+            rate_limited: None,
+            protocols: None,
+        });
+        let appservice = ApplicationService::new(
+            <Box<ServerName>>::try_from("example.org").unwrap(),
+            "https://matrix.example.org".parse().unwrap(),
+        );
+        let mut service = Appservice::new(
+            appservice,
+            registration,
+            client,
+            "_ext_",
+            MemoryBridgeStore::new(),
+        )
+        .unwrap();
+        service.set_recorder(Some(TransactionRecorder::create(&path).unwrap()));
+        let handled = Arc::new(Mutex::new(vec![]));
+        let ids = handled.clone();
+        service.on_event(move |_, event| {
+            let ids = ids.clone();
+            async move {
+                ids.lock().unwrap().push(event.event_id().to_string());
+                match event.room_id().as_str() {
+                    "!room:example.org" => Ok(()),
+                    _ => Err(Error::Config(String::from("unknown room"))),
+                }
+            }
+        });
+        let service = Arc::new(service);
+
+        let first = vec![
+            message("!room:example.org", "a"),
+            message("!room:example.org", "b"),
+        ];
+        service.handle_transaction("txn1", &first).await.unwrap();
+        service.handle_transaction("txn1", &first).await.unwrap();
+        let second = vec![message("!other:example.org", "c")];
+        service
+            .handle_transaction("txn2", &second)
+            .await
+            .unwrap_err();
+        assert_eq!(handled.lock().unwrap().len(), 3);
+
+        let transactions = load_transactions(&path).unwrap();
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].txn_id, "txn1");
+        assert_eq!(transactions[0].events.len(), 2);
+        assert_eq!(transactions[1].txn_id, "txn2");
+
+        // processed transactions are replayed too.
+        let report = service.replay(&transactions).await;
+        assert_eq!((report.transactions, report.events), (2, 3));
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, "txn2");
+        assert_eq!(handled.lock().unwrap().len(), 6);
+        assert_eq!(
+            handled.lock().unwrap()[3..],
+            ["$a:example.org", "$b:example.org", "$c:example.org"]
+        );
+        assert_eq!(load_transactions(&path).unwrap().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_transactions() {
+        let data = "{\"txn_id\":\"1\",\"events\":[]}\n\n{\"txn_id\":\"2\"}\n";
+        let err = read_transactions(data.as_bytes()).unwrap_err();
+        assert!(matches!(err, ReplayError::Format { line: 3, .. }));
+
+        let transactions = read_transactions(&data.as_bytes()[..27]).unwrap();
+        assert_eq!(transactions.len(), 1);
+        assert!(transactions[0].events.is_empty());
+    }
+}
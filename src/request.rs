@@ -1,10 +1,60 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::time::Instant;
 
-use ruma::identifiers::UserId;
+use ruma::api::exports::percent_encoding::{
+    percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC,
+};
+use ruma::identifiers::{DeviceId, UserId};
 use ruma_client::{Client, HttpClient, ResponseResult};
 
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
 use hyper::Uri;
 
+use crate::requestlog::{
+    current_transaction, describe_error, request_logger, RequestId, RequestLog,
+};
+
+/// The characters that are percent-encoded in the keys and values of url parameters, which are
+/// all characters except the unreserved ones.
+const QUERY: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Get the url parameters `params` as a query string, with their keys and values
+/// percent-encoded.
+fn encode_query(params: &BTreeMap<String, String>) -> String {
+    let encode = |s: &str| utf8_percent_encode(s, QUERY).to_string();
+    params
+        .iter()
+        .map(|(key, value)| format!("{}={}", encode(key), encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Merge the url parameters `params` into the existing query string `query`. Parameters of
+/// `query` with a key that is also in `params` are dropped, so the values of `params` win and no
+/// key is sent twice.
+fn merge_query(query: Option<&str>, params: &BTreeMap<String, String>) -> String {
+    let existing = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter(|pair| {
+            let key = pair.split('=').next().unwrap_or_default();
+            let key = percent_decode_str(key).decode_utf8_lossy();
+            !params.contains_key(key.as_ref())
+        });
+
+    let new = encode_query(params);
+    existing
+        .chain(Some(new.as_str()).filter(|new| !new.is_empty()))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
 /// A builder for a request to the Matrix homeserver.
 #[derive(Debug, Clone)]
 pub struct RequestBuilder<'a, C, R>
@@ -15,7 +65,8 @@ where
     client: &'a Client<C>,
     request: R,
 
-    params: HashMap<String, String>,
+    params: BTreeMap<String, String>,
+    headers: HeaderMap,
 }
 
 impl<'a, C, R> RequestBuilder<'a, C, R>
@@ -29,10 +80,29 @@ where
             client,
             request,
 
-            params: HashMap::new(),
+            params: BTreeMap::new(),
+            headers: HeaderMap::new(),
         }
     }
 
+    /// Set the url parameter `key` to `value`, replacing any value set before, returning the
+    /// current builder to allow method chaining.
+    ///
+    /// This can be used for query extensions without a method of their own. The value replaces
+    /// any value of `key` set by the request itself. The key and value are percent-encoded when
+    /// the request is sent, so they shouldn't be encoded already.
+    pub fn param<V: Display>(&mut self, key: &str, value: V) -> &mut Self {
+        self.params.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Set the header `name` to `value`, replacing any value set before, returning the current
+    /// builder to allow method chaining.
+    pub fn header(&mut self, name: HeaderName, value: HeaderValue) -> &mut Self {
+        self.headers.insert(name, value);
+        self
+    }
+
     /// Set the `user_id` url parameter, returning the current builder to allow method chaining.
     pub fn user_id(&mut self, user_id: &UserId) -> &mut Self {
         self.params
@@ -40,6 +110,16 @@ where
         self
     }
 
+    /// Set the `org.matrix.msc3202.device_id` url parameter, masquerading as the given device of
+    /// the user set using `user_id`, returning the current builder to allow method chaining.
+    pub fn device_id(&mut self, device_id: &DeviceId) -> &mut Self {
+        self.params.insert(
+            String::from("org.matrix.msc3202.device_id"),
+            device_id.to_string(),
+        );
+        self
+    }
+
     /// Set the `ts` url parameter, returning the current builder to allow method chaining.
     pub fn timestamp(&mut self, timestamp: i64) -> &mut Self {
         self.params
@@ -57,26 +137,31 @@ where
 
     /// Submit the request, waiting on the response.
     /// This will consume the current builder.
+    ///
+    /// The request is given to the request logger, see `set_request_logger`.
     pub async fn request(self) -> ResponseResult<C, R> {
-        let mut new_params = String::new();
-        for (i, s) in self
-            .params
-            .into_iter()
-            .map(|(k, v)| format!("{}={}", k, v))
-            .enumerate()
-        {
-            if i > 0 {
-                new_params.push('&');
-            }
-            new_params.push_str(&s);
-        }
-
-        self.client
+        let params = self.params;
+        let headers = self.headers;
+        let id = RequestId::next();
+        let logger = request_logger();
+        let mut sent = None;
+        let start = Instant::now();
+        let result = self
+            .client
             .send_customized_request(self.request, |req| {
+                req.headers_mut().extend(headers);
+                if logger.is_some() {
+                    sent = Some((req.method().clone(), req.uri().path().to_string()));
+                }
+                if params.is_empty() {
+                    return Ok(());
+                }
+
                 let uri = req.uri_mut();
-                let new_path_and_query = match uri.query() {
-                    Some(params) => format!("{}?{}&{}", uri.path(), params, new_params),
-                    None => format!("{}?{}", uri.path(), new_params),
+                let query = merge_query(uri.query(), &params);
+                let new_path_and_query = match query.as_str() {
+                    "" => uri.path().to_string(),
+                    query => format!("{}?{}", uri.path(), query),
                 };
 
                 let mut parts = uri.clone().into_parts();
@@ -85,6 +170,105 @@ where
 
                 Ok(())
             })
+            .await;
+
+        if let (Some(logger), Some((method, path))) = (logger, sent) {
+            let txn_id = current_transaction();
+            logger(&RequestLog {
+                id,
+                txn_id: txn_id.as_deref(),
+                method: &method,
+                path: &path,
+                user_id: params.get("user_id").map(String::as_str),
+                elapsed: start.elapsed(),
+                error: result.as_ref().err().map(describe_error),
+            });
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::api::client::r0::membership::join_room_by_id;
+    use ruma::api::client::r0::message::get_message_events;
+    use ruma::identifiers::{RoomId, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::RequestBuilder;
+
+    #[tokio::test]
+    async fn test_encoded_params() {
+        let (client, state) = mock_client();
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        let mut builder = RequestBuilder::new(&client, join_room_by_id::Request::new(&room_id));
+        builder
+            .user_id(&UserId::try_from("@_ext_a+b:example.org").unwrap())
+            .timestamp(1234)
+            .param("org.example.note", "a&b=c d/é")
+            .param("org.example.count", 3);
+        builder.request().await.unwrap();
+
+        let request = &state.requests()[0];
+        assert!(request.raw_path.ends_with(
+            "?org.example.count=3&org.example.note=a%26b%3Dc%20d%2F%C3%A9\
+             &ts=1234&user_id=%40_ext_a%2Bb%3Aexample.org"
+        ));
+        assert!(request.path.contains("user_id=@_ext_a+b:example.org"));
+
+        RequestBuilder::new(&client, join_room_by_id::Request::new(&room_id))
+            .request()
             .await
+            .unwrap();
+        assert!(!state.requests()[1].raw_path.contains('?'));
+    }
+
+    #[tokio::test]
+    async fn test_device_id() {
+        let (client, state) = mock_client();
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        let mut builder = RequestBuilder::new(&client, join_room_by_id::Request::new(&room_id));
+        builder
+            .user_id(&UserId::try_from("@_ext_bob:example.org").unwrap())
+            .device_id("BRIDGEDEV".into());
+        builder.request().await.unwrap();
+
+        let request = &state.requests()[0];
+        let query = request.raw_path.split('?').nth(1).unwrap();
+        let pairs: Vec<_> = query.split('&').collect();
+        assert!(pairs.contains(&"org.matrix.msc3202.device_id=BRIDGEDEV"));
+        assert!(pairs.contains(&"user_id=%40_ext_bob%3Aexample.org"));
+    }
+
+    #[tokio::test]
+    async fn test_merge_params() {
+        let (client, state) = mock_client();
+        state.respond("/messages", 200, json!({}));
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        let mut builder = RequestBuilder::new(
+            &client,
+            get_message_events::Request::backward(&room_id, "t1"),
+        );
+        builder
+            .param("from", "t2")
+            .param("org.matrix.msc3202.device_id", "A")
+            .device_id("DEVICE".into());
+        builder.request().await.unwrap();
+
+        let request = &state.requests()[0];
+        let query = request.raw_path.split('?').nth(1).unwrap();
+        let pairs: Vec<_> = query.split('&').collect();
+        assert!(pairs.contains(&"dir=b"));
+        assert!(pairs.contains(&"from=t2"));
+        assert!(pairs.contains(&"org.matrix.msc3202.device_id=DEVICE"));
+        assert_eq!(pairs.iter().filter(|p| p.starts_with("from=")).count(), 1);
     }
 }
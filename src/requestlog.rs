@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::Method;
+
+/// The ID of a request sent to the homeserver, unique within the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RequestId(u64);
+
+impl RequestId {
+    /// Get a new `RequestId`.
+    pub(crate) fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        RequestId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Get the number of the request, counting from 1.
+    pub fn get(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "req-{:08x}", self.0)
+    }
+}
+
+/// A request sent to the homeserver, as given to the request logger.
+#[derive(Debug)]
+pub struct RequestLog<'a> {
+    /// The ID of the request.
+    pub id: RequestId,
+    /// The ID of the transaction of the homeserver being handled when the request was sent, see
+    /// `with_transaction`.
+    pub txn_id: Option<&'a str>,
+    /// The method of the request.
+    pub method: &'a Method,
+    /// The percent-encoded path of the request, without the url parameters since they can
+    /// contain secrets.
+    pub path: &'a str,
+    /// The user the request masqueraded as, if any.
+    pub user_id: Option<&'a str>,
+    /// How long it took to get the response.
+    pub elapsed: Duration,
+    /// The error the request failed with, or `None` if it succeeded.
+    pub error: Option<String>,
+}
+
+/// A function called with every request sent to the homeserver.
+pub type RequestLogger = Arc<dyn Fn(&RequestLog<'_>) + Send + Sync>;
+
+static LOGGER: RwLock<Option<RequestLogger>> = RwLock::new(None);
+
+/// Call `logger` with every request sent to the homeserver by a `RequestBuilder`, which includes
+/// the requests of `Intent`s and `AppserviceClient`s, or stop logging requests if `logger` is
+/// `None`.
+///
+/// There's one logger for the whole process, like the loggers of the `log` crate.
+pub fn set_request_logger(logger: Option<RequestLogger>) {
+    *LOGGER.write().unwrap_or_else(PoisonError::into_inner) = logger;
+}
+
+pub(crate) fn request_logger() -> Option<RequestLogger> {
+    LOGGER
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+thread_local! {
+    static TRANSACTION: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+/// Get the ID of the transaction of the homeserver currently being handled, see
+/// `with_transaction`.
+pub fn current_transaction() -> Option<Arc<str>> {
+    TRANSACTION.with(|txn| txn.borrow().clone())
+}
+
+struct InTransaction<F> {
+    txn_id: Arc<str>,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for InTransaction<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let txn_id = self.txn_id.clone();
+        let outer = TRANSACTION.with(|txn| txn.replace(Some(txn_id)));
+        let poll = self.inner.as_mut().poll(cx);
+        TRANSACTION.with(|txn| *txn.borrow_mut() = outer);
+        poll
+    }
+}
+
+/// Run `fut` as handling the transaction with the given `txn_id`, so the requests it sends are
+/// logged with the ID of the transaction and `current_transaction` returns it.
+///
+/// `Appservice::handle_transaction` already does this. Tasks spawned by `fut` don't inherit the
+/// transaction, since they aren't run by it.
+pub fn with_transaction<F: Future>(txn_id: &str, fut: F) -> impl Future<Output = F::Output> {
+    InTransaction {
+        txn_id: Arc::from(txn_id),
+        inner: Box::pin(fut),
+    }
+}
+
+/// Describe the error `err` of a request, without requiring the error of the `HttpClient` to be
+/// displayable.
+pub(crate) fn describe_error<E, F: fmt::Display>(err: &ruma_client::Error<E, F>) -> String {
+    match err {
+        ruma_client::Error::AuthenticationRequired => String::from("authentication required"),
+        ruma_client::Error::IntoHttp(err) => err.to_string(),
+        ruma_client::Error::Url(err) => err.to_string(),
+        ruma_client::Error::Response(_) => String::from("couldn't obtain a response"),
+        ruma_client::Error::FromHttpResponse(err) => err.to_string(),
+        _ => String::from("unknown error"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+
+    use ruma::api::client::r0::membership::join_room_by_id;
+    use ruma::identifiers::{RoomId, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{
+        current_transaction, set_request_logger, with_transaction, AppserviceClient, RetryPolicy,
+    };
+
+    #[tokio::test]
+    async fn test_request_log() {
+        let (client, state) = mock_client();
+        let mut client = AppserviceClient::from_client(client);
+        client.set_retry_policy(RetryPolicy::none());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let ghost = UserId::try_from("@_ext_bob:example.org").unwrap();
+        state.respond_once(
+            "/join",
+            403,
+            json!({ "errcode": "M_FORBIDDEN", "error": "No" }),
+        );
+        state.respond("/join", 200, json!({ "room_id": "!room:example.org" }));
+
+        let logs = Arc::new(Mutex::new(vec![]));
+        let logs2 = logs.clone();
+        set_request_logger(Some(Arc::new(move |log| {
+            // other tests send requests too.
+            if log.txn_id == Some("log-txn") {
+                logs2.lock().unwrap().push((
+                    log.id,
+                    log.method.clone(),
+                    log.path.to_string(),
+                    log.user_id.map(String::from),
+                    log.error.clone(),
+                ));
+            }
+        })));
+
+        with_transaction("log-txn", async {
+            assert_eq!(current_transaction().as_deref(), Some("log-txn"));
+            let request = join_room_by_id::Request::new(&room_id);
+            client.send_as(&ghost, request.clone()).await.unwrap_err();
+            tokio::task::yield_now().await;
+            assert_eq!(current_transaction().as_deref(), Some("log-txn"));
+            client.send(request).await.unwrap();
+        })
+        .await;
+        assert_eq!(current_transaction(), None);
+        set_request_logger(None);
+
+        let logs = logs.lock().unwrap();
+        assert_eq!(logs.len(), 2);
+        assert_ne!(logs[0].0, logs[1].0);
+        assert_eq!(logs[0].1, "POST");
+        assert_eq!(
+            logs[0].2,
+            "/_matrix/client/r0/rooms/%21room%3Aexample%2Eorg/join"
+        );
+        assert_eq!(logs[0].3.as_deref(), Some("@_ext_bob:example.org"));
+        assert!(logs[0].4.as_ref().unwrap().contains("No"));
+        assert_eq!(logs[1].3, None);
+        assert_eq!(logs[1].4, None);
+        assert_eq!(logs[1].0.to_string().len(), 12);
+    }
+}
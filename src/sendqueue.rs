@@ -0,0 +1,904 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use ruma::api::client::error::ErrorKind;
+use ruma::api::client::r0::message::send_message_event;
+use ruma::events::{AnyMessageEventContent, EventContent};
+use ruma::identifiers::{EventId, RoomId, UserId};
+use ruma::serde::Raw;
+use ruma::MilliSecondsSinceUnixEpoch;
+use ruma_client::{Client, HttpClient};
+use serde::{Deserialize, Serialize};
+use serde_json::value::{to_raw_value, RawValue};
+use serde_json::Value;
+
+use std::sync::Arc;
+
+#[cfg(feature = "runtime")]
+use tokio::sync::Notify;
+#[cfg(feature = "runtime")]
+use tokio::task::JoinHandle;
+
+use crate::circuitbreaker::{CircuitBreaker, CircuitState};
+use crate::client::{AppserviceClient, RetryPolicy};
+use crate::intent::{Intent, IntentError};
+use crate::mappingdict::{Mappable, MappingDict, MappingId};
+use crate::ratelimit::RateLimitMonitor;
+use crate::store::MappingStore;
+use crate::util::transaction_id;
+
+/// A message waiting to be sent by a `SendQueue`.
+///
+/// The transaction ID of the message is its Matrix ID, and it's sent with the same transaction
+/// ID on every attempt, so the homeserver ignores attempts of which the response was lost.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    txn_id: String,
+    external_id: String,
+    seq: u64,
+    room_id: RoomId,
+    sender: UserId,
+    #[serde(default)]
+    timestamp: Option<MilliSecondsSinceUnixEpoch>,
+    event_type: String,
+    content: Value,
+    attempts: u32,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    dead: bool,
+}
+
+impl QueuedMessage {
+    /// Get the transaction ID of the message.
+    pub fn txn_id(&self) -> &str {
+        &self.txn_id
+    }
+
+    /// Get the ID of the message on the external service.
+    pub fn external_id(&self) -> &str {
+        &self.external_id
+    }
+
+    /// Get the ID of the room the message is sent to.
+    pub fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
+    /// Get the ID of the user sending the message.
+    pub fn sender(&self) -> &UserId {
+        &self.sender
+    }
+
+    /// Get the original timestamp of the message on the external service, if any.
+    pub fn timestamp(&self) -> Option<MilliSecondsSinceUnixEpoch> {
+        self.timestamp
+    }
+
+    /// Get the type of the event.
+    pub fn event_type(&self) -> &str {
+        &self.event_type
+    }
+
+    /// Get the JSON content of the event.
+    pub fn content(&self) -> &Value {
+        &self.content
+    }
+
+    /// Get the amount of failed attempts to send the message.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Get the error message of the last failed attempt, if any.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// Returns whether the message was dropped after failing permanently.
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+}
+
+impl Mappable for QueuedMessage {
+    type MatrixReference = str;
+    type MatrixType = String;
+    type ExternalReference = str;
+    type ExternalType = String;
+
+    fn as_matrix(&self) -> &str {
+        &self.txn_id
+    }
+    fn into_matrix(self) -> String {
+        self.txn_id
+    }
+    fn as_external(&self) -> &str {
+        &self.external_id
+    }
+    fn into_external(self) -> String {
+        self.external_id
+    }
+    fn into_split(self) -> (String, String) {
+        (self.txn_id, self.external_id)
+    }
+}
+
+type FailureFn<E> = Box<dyn Fn(&QueuedMessage, &IntentError<E>) + Send + Sync>;
+
+#[derive(Debug, Default)]
+struct RoomQueue {
+    messages: VecDeque<QueuedMessage>,
+    retry_at: Option<Instant>,
+    sending: bool,
+}
+
+impl RoomQueue {
+    fn is_due(&self, now: Instant) -> bool {
+        let retry = match self.retry_at {
+            Some(retry_at) => retry_at <= now,
+            None => true,
+        };
+        !self.sending && !self.messages.is_empty() && retry
+    }
+}
+
+/// Sends messages to rooms as ghost users, in order per room, retrying failed messages.
+///
+/// Messages are queued using `enqueue`, which stores them in `store`, and sent using `flush`,
+/// which sends the messages of different rooms concurrently. When sending a message fails, the
+/// messages after it in the same room wait until it's retried, with an exponential backoff
+/// between `set_backoff`'s bounds. Messages that fail `set_max_attempts` times or are rejected
+/// by the homeserver are dropped and reported to the `on_failure` callbacks. With a
+/// `RateLimitMonitor`, sending to all rooms pauses while the homeserver rate-limits requests,
+/// and with a `CircuitBreaker`, messages stay queued while the homeserver is unreachable.
+///
+/// With a persistent store, like a `SqliteMappingStore`, messages that weren't sent yet can be
+/// loaded after a restart using `restore`. Dropped messages can be kept in the store as dead
+/// letters using `set_keep_dead_letters`, to be inspected and retried later.
+pub struct SendQueue<C: HttpClient, S = Mutex<MappingDict<QueuedMessage>>> {
+    client: Client<C>,
+    appservice: Option<Arc<AppserviceClient<C>>>,
+    store: S,
+    rooms: Mutex<HashMap<RoomId, RoomQueue>>,
+    next_seq: AtomicU64,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    keep_dead_letters: bool,
+    rate_limit: Option<Arc<RateLimitMonitor>>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    on_failure: Vec<FailureFn<C::Error>>,
+    #[cfg(feature = "runtime")]
+    notify: Notify,
+}
+
+impl<C: HttpClient + Clone> SendQueue<C> {
+    /// Create a new `SendQueue` sending messages using `client`, keeping the queue in memory.
+    pub fn new(client: Client<C>) -> Self {
+        Self::with_store(client, Mutex::new(MappingDict::new()))
+    }
+}
+
+impl<C, S> SendQueue<C, S>
+where
+    C: HttpClient + Clone,
+    S: MappingStore<QueuedMessage>,
+{
+    /// Create a new `SendQueue` sending messages using `client`, keeping the queue in `store`.
+    ///
+    /// Messages are attempted ten times, with a backoff from one second up to five minutes.
+    pub fn with_store(client: Client<C>, store: S) -> Self {
+        Self {
+            client,
+            appservice: None,
+            store,
+            rooms: Mutex::new(HashMap::new()),
+            next_seq: AtomicU64::new(0),
+            max_attempts: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5 * 60),
+            keep_dead_letters: false,
+            rate_limit: None,
+            circuit_breaker: None,
+            on_failure: vec![],
+            #[cfg(feature = "runtime")]
+            notify: Notify::new(),
+        }
+    }
+
+    /// Get the store the queue is kept in.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Set the amount of times a message is attempted before it's dropped.
+    pub fn set_max_attempts(&mut self, max_attempts: u32) {
+        self.max_attempts = max_attempts;
+    }
+
+    /// Set the backoff after the first failed attempt to `initial`, which is doubled after
+    /// every next failed attempt up to `max`.
+    pub fn set_backoff(&mut self, initial: Duration, max: Duration) {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+    }
+
+    /// Set whether dropped messages are kept in the store as dead letters, instead of being
+    /// removed.
+    pub fn set_keep_dead_letters(&mut self, keep: bool) {
+        self.keep_dead_letters = keep;
+    }
+
+    /// Report the responses of the homeserver to the given rate limit `monitor`, and pause
+    /// sending while it asks to wait.
+    pub fn set_rate_limit_monitor(&mut self, monitor: Option<Arc<RateLimitMonitor>>) {
+        self.rate_limit = monitor;
+    }
+
+    /// Report the responses of the homeserver to the given circuit `breaker`, and keep the
+    /// messages queued while it's open. When it's half open, one message is sent as its probe.
+    pub fn set_circuit_breaker(&mut self, breaker: Option<Arc<CircuitBreaker>>) {
+        self.circuit_breaker = breaker;
+    }
+
+    /// Send the messages with the default url parameters and headers, the rate limit monitor and
+    /// the circuit breaker of the given `AppserviceClient`, or without them if it's `None`.
+    ///
+    /// Every attempt of a message is sent once, as the queue retries failed messages itself. The
+    /// rate limit monitor and circuit breaker of the client shouldn't also be given to the queue,
+    /// or every response is reported to them twice.
+    pub fn set_appservice_client(&mut self, client: Option<Arc<AppserviceClient<C>>>) {
+        self.appservice = client.map(|client| {
+            let mut client = (*client).clone();
+            client.set_retry_policy(RetryPolicy::none());
+            Arc::new(client)
+        });
+    }
+
+    /// Call `f` with every message that is dropped, along with the error of its last attempt.
+    pub fn on_failure<F>(&mut self, f: F)
+    where
+        F: Fn(&QueuedMessage, &IntentError<C::Error>) + Send + Sync + 'static,
+    {
+        self.on_failure.push(Box::new(f));
+    }
+
+    /// Load the messages in the store that weren't sent yet, in the order they were queued.
+    ///
+    /// This should be called before queueing any messages. Returns the amount of messages loaded.
+    pub async fn restore(&self) -> Result<usize, S::Error> {
+        let mut messages = self.store.items().await?;
+        messages.sort_by_key(|message| message.seq);
+
+        if let Some(last) = messages.last() {
+            self.next_seq.fetch_max(last.seq + 1, Ordering::Relaxed);
+        }
+        messages.retain(|message| !message.dead);
+
+        let count = messages.len();
+        let mut rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+        for message in messages {
+            let room = rooms.entry(message.room_id.clone()).or_default();
+            room.messages.push_back(message);
+        }
+        drop(rooms);
+
+        #[cfg(feature = "runtime")]
+        self.notify.notify_one();
+
+        Ok(count)
+    }
+
+    /// Get the dead letters in the store, in the order they were queued.
+    pub async fn dead_letters(&self) -> Result<Vec<QueuedMessage>, S::Error> {
+        let mut messages = self.store.items().await?;
+        messages.retain(|message| message.dead);
+        messages.sort_by_key(|message| message.seq);
+        Ok(messages)
+    }
+
+    /// Queue the dead letter with the given `txn_id` again, after the messages that are already
+    /// queued in its room.
+    ///
+    /// Returns whether the dead letter exists.
+    pub async fn retry_dead_letter(&self, txn_id: &str) -> Result<bool, S::Error> {
+        let mut message = match self.store.get(MappingId::Matrix(txn_id)).await? {
+            Some(message) if message.dead => message,
+            _ => return Ok(false),
+        };
+        message.seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        message.attempts = 0;
+        message.error = None;
+        message.dead = false;
+        self.store.insert(message.clone()).await?;
+        self.push(message);
+        Ok(true)
+    }
+
+    /// Remove the dead letter with the given `txn_id` from the store, returning it.
+    pub async fn discard_dead_letter(
+        &self,
+        txn_id: &str,
+    ) -> Result<Option<QueuedMessage>, S::Error> {
+        match self.store.get(MappingId::Matrix(txn_id)).await? {
+            Some(message) if message.dead => self.store.remove(MappingId::Matrix(txn_id)).await,
+            _ => Ok(None),
+        }
+    }
+
+    /// Queue sending `content` as the user with the given `sender` ID to the room with the given
+    /// `room_id`, identified by `external_id`.
+    ///
+    /// The message is sent with the original `timestamp` of the message on the external service,
+    /// if given, see `Intent::with_timestamp`.
+    ///
+    /// Returns the transaction ID of the message.
+    pub async fn enqueue(
+        &self,
+        sender: UserId,
+        room_id: RoomId,
+        external_id: String,
+        timestamp: Option<MilliSecondsSinceUnixEpoch>,
+        content: &AnyMessageEventContent,
+    ) -> Result<String, S::Error> {
+        let event_type = content.event_type().to_string();
+        let content = serde_json::to_value(content).expect("message should serialize");
+        self.enqueue_json(
+            sender,
+            room_id,
+            external_id,
+            timestamp,
+            &event_type,
+            content,
+        )
+        .await
+    }
+
+    /// Queue sending an event of type `event_type` with the given JSON `content`, like
+    /// `enqueue`, for event types or content not known to `ruma`.
+    pub async fn enqueue_raw(
+        &self,
+        sender: UserId,
+        room_id: RoomId,
+        external_id: String,
+        timestamp: Option<MilliSecondsSinceUnixEpoch>,
+        event_type: &str,
+        content: Box<RawValue>,
+    ) -> Result<String, S::Error> {
+        let content = serde_json::from_str(content.get()).expect("raw value should be valid JSON");
+        self.enqueue_json(sender, room_id, external_id, timestamp, event_type, content)
+            .await
+    }
+
+    async fn enqueue_json(
+        &self,
+        sender: UserId,
+        room_id: RoomId,
+        external_id: String,
+        timestamp: Option<MilliSecondsSinceUnixEpoch>,
+        event_type: &str,
+        content: Value,
+    ) -> Result<String, S::Error> {
+        let message = QueuedMessage {
+            txn_id: transaction_id(),
+            external_id,
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            room_id,
+            sender,
+            timestamp,
+            event_type: event_type.to_string(),
+            content,
+            attempts: 0,
+            error: None,
+            dead: false,
+        };
+        self.store.insert(message.clone()).await?;
+
+        let txn_id = message.txn_id.clone();
+        self.push(message);
+        Ok(txn_id)
+    }
+
+    fn push(&self, message: QueuedMessage) {
+        let mut rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+        let room = rooms.entry(message.room_id.clone()).or_default();
+        room.messages.push_back(message);
+        drop(rooms);
+
+        #[cfg(feature = "runtime")]
+        self.notify.notify_one();
+    }
+
+    /// Get the amount of messages that weren't sent yet.
+    pub fn pending(&self) -> usize {
+        let rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+        rooms.values().map(|room| room.messages.len()).sum()
+    }
+
+    /// Send the queued messages of all rooms that aren't waiting for a retry.
+    ///
+    /// Errors removing sent messages from the store are ignored, such messages are sent again
+    /// after a restart with the same transaction ID.
+    ///
+    /// Returns the amount of messages sent.
+    pub async fn flush(&self) -> usize {
+        if self.rate_limit_wait().is_some() {
+            return 0;
+        }
+        let probe = match &self.circuit_breaker {
+            Some(breaker) if !breaker.allow() => return 0,
+            Some(breaker) => breaker.state() == CircuitState::HalfOpen,
+            None => false,
+        };
+
+        let now = Instant::now();
+        let room_ids: Vec<RoomId> = {
+            let mut rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+            rooms
+                .iter_mut()
+                .filter(|(_, room)| room.is_due(now))
+                .take(if probe { 1 } else { usize::MAX })
+                .map(|(room_id, room)| {
+                    room.sending = true;
+                    room_id.clone()
+                })
+                .collect()
+        };
+
+        let sent = join_all(room_ids.iter().map(|room_id| self.flush_room(room_id))).await;
+        sent.into_iter().sum()
+    }
+
+    async fn flush_room(&self, room_id: &RoomId) -> usize {
+        let mut count = 0;
+        loop {
+            let message = {
+                let mut rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+                let room = rooms.entry(room_id.clone()).or_default();
+                if self.circuit_wait().is_some() && count > 0 {
+                    room.sending = false;
+                    return count;
+                }
+                match room.messages.front() {
+                    Some(message) => message.clone(),
+                    None => {
+                        room.sending = false;
+                        room.retry_at = None;
+                        return count;
+                    }
+                }
+            };
+
+            let result = self.send(&message).await;
+            if let Some(monitor) = &self.rate_limit {
+                match &result {
+                    Ok(_) => monitor.observe_success(),
+                    Err(err) => {
+                        monitor.observe(err);
+                    }
+                }
+            }
+            if let Some(breaker) = &self.circuit_breaker {
+                match &result {
+                    Ok(_) => breaker.observe_success(),
+                    Err(err) => breaker.observe(err),
+                }
+            }
+            match result {
+                Ok(_) => {
+                    self.pop(room_id);
+                    let _ = self.store.remove(MappingId::Matrix(&message.txn_id)).await;
+                    count += 1;
+                }
+                Err(err) if message.attempts + 1 >= self.max_attempts || is_permanent(&err) => {
+                    let mut message = message;
+                    message.attempts += 1;
+                    message.error = Some(error_message(&err));
+                    message.dead = true;
+
+                    self.pop(room_id);
+                    if self.keep_dead_letters {
+                        let _ = self.store.insert(message.clone()).await;
+                    } else {
+                        let _ = self.store.remove(MappingId::Matrix(&message.txn_id)).await;
+                    }
+                    for f in &self.on_failure {
+                        f(&message, &err);
+                    }
+                }
+                Err(err) => {
+                    let mut message = message;
+                    message.attempts += 1;
+                    message.error = Some(error_message(&err));
+                    let backoff = self.backoff(message.attempts, &err);
+                    let _ = self.store.insert(message.clone()).await;
+
+                    let mut rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+                    let room = rooms.entry(room_id.clone()).or_default();
+                    if let Some(front) = room.messages.front_mut() {
+                        *front = message;
+                    }
+                    room.retry_at = Some(Instant::now() + backoff);
+                    room.sending = false;
+                    return count;
+                }
+            }
+        }
+    }
+
+    fn pop(&self, room_id: &RoomId) {
+        let mut rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(room) = rooms.get_mut(room_id) {
+            room.messages.pop_front();
+        }
+    }
+
+    async fn send(&self, message: &QueuedMessage) -> Result<EventId, IntentError<C::Error>> {
+        let intent = Intent::new(self.client.clone(), message.sender.clone())
+            .with_timestamp(message.timestamp)
+            .with_appservice_client(self.appservice.clone());
+        let content = to_raw_value(&message.content).expect("message should serialize");
+        let request = send_message_event::Request::new_raw(
+            &message.room_id,
+            &message.txn_id,
+            &message.event_type,
+            Raw::from_json(content),
+        );
+        Ok(intent.send(request).await?.event_id)
+    }
+
+    /// Get the time to wait after the given amount of failed `attempts`, the last failing with
+    /// `err`.
+    fn backoff(&self, attempts: u32, err: &IntentError<C::Error>) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        let backoff = self
+            .initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+
+        let backoff = backoff
+            .max(self.rate_limit_wait().unwrap_or_default())
+            .max(self.circuit_wait().unwrap_or_default());
+        match err.kind() {
+            Some(ErrorKind::LimitExceeded {
+                retry_after_ms: Some(retry_after),
+            }) => backoff.max(*retry_after),
+            _ => backoff,
+        }
+    }
+
+    /// Get the time the rate limit monitor asks to wait before sending again, if any.
+    fn rate_limit_wait(&self) -> Option<Duration> {
+        self.rate_limit.as_ref()?.wait_time()
+    }
+
+    /// Get the time until the circuit breaker allows sending again, if it's open.
+    fn circuit_wait(&self) -> Option<Duration> {
+        self.circuit_breaker.as_ref()?.wait_time()
+    }
+
+    /// Get the time until the next room waiting for a retry is due, or `None` if no rooms are
+    /// waiting, to know when to `flush` again.
+    pub fn next_retry(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+        let retry = rooms
+            .values()
+            .filter(|room| !room.messages.is_empty())
+            .filter_map(|room| room.retry_at)
+            .min()
+            .map(|retry_at| retry_at.saturating_duration_since(now));
+        let pending = rooms.values().any(|room| !room.messages.is_empty());
+        let wait = match (self.rate_limit_wait(), self.circuit_wait()) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        match wait {
+            Some(wait) if pending => Some(retry.map_or(wait, |retry| retry.max(wait))),
+            _ => retry,
+        }
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl<C, S> SendQueue<C, S>
+where
+    C: HttpClient + Clone + Send + Sync + 'static,
+    C::Error: Send,
+    S: MappingStore<QueuedMessage> + 'static,
+{
+    /// Flush the queue in a background task whenever messages are queued or due for a retry,
+    /// until the returned handle is aborted.
+    pub fn spawn(self: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                self.flush().await;
+                match self.next_retry() {
+                    Some(wait) => {
+                        let _ = tokio::time::timeout(wait, self.notify.notified()).await;
+                    }
+                    None => self.notify.notified().await,
+                }
+            }
+        })
+    }
+}
+
+/// Get the message of the error returned by the homeserver, or a generic message if the request
+/// failed otherwise.
+fn error_message<E>(err: &IntentError<E>) -> String {
+    match err.matrix_error() {
+        Some(err) => err.message.clone(),
+        None => String::from("The request to the homeserver failed"),
+    }
+}
+
+/// Returns whether retrying the request that failed with `err` won't help.
+fn is_permanent<E>(err: &IntentError<E>) -> bool {
+    matches!(
+        err.kind(),
+        Some(ErrorKind::Forbidden)
+            | Some(ErrorKind::BadJson)
+            | Some(ErrorKind::NotJson)
+            | Some(ErrorKind::TooLarge)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use ruma::events::room::message::MessageEventContent;
+    use ruma::events::AnyMessageEventContent;
+    use ruma::identifiers::{RoomId, UserId};
+    use ruma::{MilliSecondsSinceUnixEpoch, UInt};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{
+        BridgeStateEvent, CircuitBreaker, MappingDict, MappingStore, RateLimitMonitor, SendQueue,
+    };
+
+    #[tokio::test]
+    async fn test_send_queue() {
+        let (client, state) = mock_client();
+        let mut queue = SendQueue::new(client);
+        queue.set_max_attempts(2);
+        queue.set_backoff(Duration::from_secs(0), Duration::from_secs(0));
+        let failed = Arc::new(Mutex::new(vec![]));
+        let failures = failed.clone();
+        queue.on_failure(move |message, _| {
+            failures
+                .lock()
+                .unwrap()
+                .push(message.external_id().to_string());
+        });
+
+        let sender = UserId::try_from("@_ext_alice:example.org").unwrap();
+        let room = |name: &str| RoomId::try_from(format!("!{}:example.org", name)).unwrap();
+        let text =
+            |body: &str| AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(body));
+        for &(room_name, body) in &[("a", "a1"), ("a", "a2"), ("b", "b1")] {
+            queue
+                .enqueue(
+                    sender.clone(),
+                    room(room_name),
+                    body.to_string(),
+                    None,
+                    &text(body),
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(queue.store().items().await.unwrap().len(), 3);
+
+        state.respond("/send/", 200, json!({ "event_id": "$sent:example.org" }));
+        state.respond_once(
+            "/rooms/!a:example.org/send/",
+            500,
+            json!({ "errcode": "M_UNKNOWN", "error": "Oops" }),
+        );
+        assert_eq!(queue.flush().await, 1);
+        assert_eq!(queue.pending(), 2);
+
+        // a2 waited for a1 to be retried.
+        assert_eq!(queue.flush().await, 2);
+        let bodies: Vec<_> = state
+            .requests_to("/rooms/!a:example.org/send/")
+            .into_iter()
+            .map(|request| (request.path, request.body["body"].clone()))
+            .collect();
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(bodies[0], bodies[1]);
+        assert_eq!(bodies[1].1, "a1");
+        assert_eq!(bodies[2].1, "a2");
+        assert!(queue.store().items().await.unwrap().is_empty());
+
+        state.respond_once(
+            "/send/",
+            403,
+            json!({ "errcode": "M_FORBIDDEN", "error": "Not in room" }),
+        );
+        queue
+            .enqueue(
+                sender.clone(),
+                room("c"),
+                String::from("c1"),
+                None,
+                &text("c1"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(queue.flush().await, 0);
+        assert_eq!(queue.pending(), 0);
+        assert_eq!(*failed.lock().unwrap(), vec![String::from("c1")]);
+    }
+
+    #[tokio::test]
+    async fn test_restore() {
+        let (client, state) = mock_client();
+        let queue = SendQueue::new(client.clone());
+
+        let sender = UserId::try_from("@_ext_alice:example.org").unwrap();
+        let room_id = RoomId::try_from("!a:example.org").unwrap();
+        for body in &["1", "2", "3"] {
+            let content = MessageEventContent::text_plain(*body);
+            queue
+                .enqueue(
+                    sender.clone(),
+                    room_id.clone(),
+                    body.to_string(),
+                    Some(MilliSecondsSinceUnixEpoch(UInt::from(1000u32))),
+                    &AnyMessageEventContent::RoomMessage(content),
+                )
+                .await
+                .unwrap();
+        }
+
+        // the messages are in the store of a new queue after a restart.
+        let store = Mutex::new(MappingDict::new());
+        for message in queue.store().items().await.unwrap().into_iter().rev() {
+            store.insert(message).await.unwrap();
+        }
+        let queue = SendQueue::with_store(client, store);
+        assert_eq!(queue.restore().await.unwrap(), 3);
+        state.respond("/send/", 200, json!({ "event_id": "$sent:example.org" }));
+        assert_eq!(queue.flush().await, 3);
+        let requests = state.requests_to("/send/");
+        let bodies: Vec<_> = requests
+            .iter()
+            .map(|request| request.body["body"].clone())
+            .collect();
+        assert_eq!(bodies, vec!["1", "2", "3"]);
+        assert!(requests[0].path.contains("ts=1000"));
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters() {
+        let (client, state) = mock_client();
+        let mut queue = SendQueue::new(client);
+        queue.set_keep_dead_letters(true);
+
+        let sender = UserId::try_from("@_ext_alice:example.org").unwrap();
+        let room_id = RoomId::try_from("!a:example.org").unwrap();
+        let content = MessageEventContent::text_plain("hi");
+        let txn_id = queue
+            .enqueue(
+                sender,
+                room_id,
+                String::from("1"),
+                None,
+                &AnyMessageEventContent::RoomMessage(content),
+            )
+            .await
+            .unwrap();
+
+        state.respond_once(
+            "/send/",
+            403,
+            json!({ "errcode": "M_FORBIDDEN", "error": "Not in room" }),
+        );
+        assert_eq!(queue.flush().await, 0);
+        let dead = queue.dead_letters().await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert!(dead[0].is_dead());
+        assert_eq!(dead[0].error(), Some("Not in room"));
+        assert_eq!(queue.restore().await.unwrap(), 0);
+
+        assert!(queue.retry_dead_letter(&txn_id).await.unwrap());
+        assert!(queue.dead_letters().await.unwrap().is_empty());
+        state.respond("/send/", 200, json!({ "event_id": "$sent:example.org" }));
+        assert_eq!(queue.flush().await, 1);
+        assert!(queue.store().items().await.unwrap().is_empty());
+        assert!(!queue.retry_dead_letter(&txn_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_monitor() {
+        let (client, state) = mock_client();
+        let mut queue = SendQueue::new(client);
+        queue.set_backoff(Duration::from_secs(0), Duration::from_secs(0));
+        let mut monitor = RateLimitMonitor::new(false);
+        monitor.set_delay(Duration::from_secs(60), Duration::from_secs(60));
+        let monitor = Arc::new(monitor);
+        queue.set_rate_limit_monitor(Some(monitor.clone()));
+
+        let sender = UserId::try_from("@_ext_alice:example.org").unwrap();
+        let room_id = RoomId::try_from("!a:example.org").unwrap();
+        let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("hi"));
+        queue
+            .enqueue(sender, room_id, String::from("m1"), None, &content)
+            .await
+            .unwrap();
+
+        state.respond_once(
+            "/send/",
+            429,
+            json!({ "errcode": "M_LIMIT_EXCEEDED", "error": "Too many requests" }),
+        );
+        assert_eq!(queue.flush().await, 0);
+        assert!(monitor.is_unexpectedly_limited());
+        assert!(queue.next_retry().unwrap() > Duration::from_secs(50));
+
+        // Sending stays paused while the monitor asks to wait.
+        assert_eq!(queue.flush().await, 0);
+        assert_eq!(state.requests_to("/send/").len(), 1);
+        assert_eq!(queue.pending(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let (client, state) = mock_client();
+        let mut queue = SendQueue::new(client);
+        queue.set_backoff(Duration::from_secs(0), Duration::from_secs(0));
+        let cooldown = Duration::from_millis(50);
+        let mut breaker = CircuitBreaker::new(1, cooldown);
+        let states = Arc::new(Mutex::new(vec![]));
+        let reported = states.clone();
+        breaker.on_state_change(move |state| reported.lock().unwrap().push(state.state_event));
+        queue.set_circuit_breaker(Some(Arc::new(breaker)));
+
+        let sender = UserId::try_from("@_ext_alice:example.org").unwrap();
+        let room_id = RoomId::try_from("!a:example.org").unwrap();
+        let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("hi"));
+        for id in &["m1", "m2"] {
+            queue
+                .enqueue(
+                    sender.clone(),
+                    room_id.clone(),
+                    id.to_string(),
+                    None,
+                    &content,
+                )
+                .await
+                .unwrap();
+        }
+
+        // a gateway error without a Matrix error means the homeserver is unreachable.
+        state.respond_once("/send/", 502, json!({}));
+        assert_eq!(queue.flush().await, 0);
+        assert_eq!(*states.lock().unwrap(), [BridgeStateEvent::UnknownError]);
+        assert!(queue.next_retry().unwrap() <= cooldown);
+
+        // the messages stay queued while the breaker is open.
+        assert_eq!(queue.flush().await, 0);
+        assert_eq!(state.requests_to("/send/").len(), 1);
+        assert_eq!(queue.pending(), 2);
+
+        std::thread::sleep(cooldown);
+        state.respond("/send/", 200, json!({ "event_id": "$sent:example.org" }));
+        assert_eq!(queue.flush().await, 2);
+        assert_eq!(queue.pending(), 0);
+        assert_eq!(
+            *states.lock().unwrap(),
+            [BridgeStateEvent::UnknownError, BridgeStateEvent::Running]
+        );
+    }
+}
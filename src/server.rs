@@ -7,60 +7,26 @@ use ruma::serde::Raw;
 
 use hyper::server::Server;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{
-    body::{aggregate, Buf},
-    header, Body, Request, Response, StatusCode,
-};
+use hyper::{body::to_bytes, header, Body, Request, Response, StatusCode};
 
-use serde_json::value::to_raw_value;
+use crate::lazyevent::{parse_transaction, LazyEvent};
 
 /// Listen on `addrs` for incoming events, and use the given `handler` to handle those events.
-pub async fn serve<S, F, R>(addrs: S, handler: F) -> Result<(), hyper::Error>
+///
+/// If `handler` fails, the homeserver is answered with an error, so it sends the transaction
+/// again later.
+pub async fn serve<S, F, R, E>(addrs: S, handler: F) -> Result<(), hyper::Error>
 where
     S: ToSocketAddrs,
     F: Fn(String, Vec<Raw<AnyRoomEvent>>) -> R + Sync + Send + Clone + 'static,
-    R: Future<Output = Result<String, Infallible>> + Send,
+    R: Future<Output = Result<String, E>> + Send,
 {
     let service = make_service_fn(move |_| {
         let handler = handler.clone();
         async {
             let f = service_fn(move |req: Request<Body>| {
                 let handler = handler.clone();
-                async move {
-                    let (parts, body) = req.into_parts();
-
-                    // skip "/transactions/"
-                    let txn_id = parts.uri.path()[14..].to_string();
-
-                    let body = aggregate(body).await.unwrap();
-                    let json: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
-
-                    let events: Vec<Raw<AnyRoomEvent>> = json
-                        .as_object()
-                        .unwrap()
-                        .get("events")
-                        .unwrap()
-                        .as_array()
-                        .unwrap()
-                        .iter()
-                        .map(|e| {
-                            let raw_value = to_raw_value(e).unwrap();
-                            Raw::from_json(raw_value)
-                        })
-                        .collect();
-
-                    match handler(txn_id, events).await {
-                        Err(_) => {} // TODO
-                        Ok(_) => {}
-                    }
-
-                    let response = Response::builder()
-                        .status(StatusCode::OK)
-                        .header(header::CONTENT_TYPE, "application/json")
-                        .body(Body::from("{}"))
-                        .unwrap();
-                    Ok::<_, Infallible>(response)
-                }
+                async move { Ok::<_, Infallible>(handle_request(&handler, req).await) }
             });
 
             Ok::<_, Infallible>(f)
@@ -72,3 +38,88 @@ where
 
     server.await
 }
+
+/// Handle the transaction pushed by the homeserver in `req` using `handler`.
+async fn handle_request<F, R, E>(handler: &F, req: Request<Body>) -> Response<Body>
+where
+    F: Fn(String, Vec<Raw<AnyRoomEvent>>) -> R,
+    R: Future<Output = Result<String, E>>,
+{
+    let (parts, body) = req.into_parts();
+
+    // skip "/transactions/"
+    let txn_id = parts.uri.path()[14..].to_string();
+
+    let body = to_bytes(body).await.unwrap();
+    let events = match parse_transaction(&body) {
+        Ok(events) => events.iter().map(LazyEvent::to_raw).collect(),
+        Err(_) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                r#"{"errcode":"M_NOT_JSON","error":"Invalid transaction"}"#,
+            );
+        }
+    };
+
+    match handler(txn_id, events).await {
+        Ok(_) => json_response(StatusCode::OK, "{}"),
+        Err(_) => json_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            r#"{"errcode":"M_UNKNOWN","error":"Handling the transaction failed"}"#,
+        ),
+    }
+}
+
+fn json_response(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::{Body, Request, StatusCode};
+
+    use super::handle_request;
+
+    #[tokio::test]
+    async fn test_failed_transaction() {
+        let calls = AtomicUsize::new(0);
+        let handler = |txn_id: String, events: Vec<_>| {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                assert_eq!(txn_id, "txn1");
+                assert_eq!(events.len(), 1);
+                if call == 0 {
+                    Err(())
+                } else {
+                    Ok(String::new())
+                }
+            }
+        };
+        let request = || {
+            let body = r#"{"events":[{"type":"m.room.message","event_id":"$1:example.org","room_id":"!room:example.org","sender":"@alice:example.org","origin_server_ts":0,"content":{"msgtype":"m.text","body":"Hi"}}]}"#;
+            Request::put("/transactions/txn1")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = handle_request(&handler, request()).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // the homeserver retries the transaction, which is handled again.
+        let response = handle_request(&handler, request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let request = Request::put("/transactions/txn2")
+            .body(Body::from("nope"))
+            .unwrap();
+        let response = handle_request(&handler, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
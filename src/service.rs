@@ -0,0 +1,485 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use ruma::events::AnyRoomEvent;
+use ruma::serde::Raw;
+use ruma_client::{Client, HttpClient};
+use thiserror::Error;
+
+use crate::appservice::{ApplicationService, Registration};
+use crate::bot::BridgeBot;
+use crate::client::AppserviceClient;
+use crate::commands::CommandProcessor;
+use crate::error::Error;
+use crate::filter::{EchoFilter, EventFilters};
+use crate::intent::IntentError;
+use crate::invites::InvitePolicy;
+use crate::portal::{Portal, PortalError, PortalManager};
+use crate::puppet::{Puppet, PuppetManager};
+use crate::replay::TransactionRecorder;
+use crate::requestlog::with_transaction;
+use crate::store::{BridgeStore, SharedStore};
+
+#[cfg(feature = "serve")]
+use std::net::ToSocketAddrs;
+
+/// An error from an `Appservice`.
+#[derive(Debug, Error)]
+pub enum AppserviceError<E, S> {
+    /// A request to the homeserver failed.
+    #[error("{0}")]
+    Intent(IntentError<E>),
+    /// Loading or saving the state of the bridge failed.
+    #[error("loading or saving the state of the bridge failed: {0}")]
+    Store(S),
+    /// Handling an event with the `PortalManager` or `InvitePolicy` failed.
+    #[error("{0}")]
+    Portal(PortalError<E, S>),
+    /// Recording the transaction with the `TransactionRecorder` failed.
+    #[error("recording the transaction failed: {0}")]
+    Record(io::Error),
+    /// An `on_event` handler failed.
+    #[error("event handler failed: {0}")]
+    Handler(Error),
+    /// Serving the application service API failed.
+    #[cfg(feature = "serve")]
+    #[error("serving failed: {0}")]
+    Server(hyper::Error),
+}
+
+impl<E, S> From<IntentError<E>> for AppserviceError<E, S> {
+    fn from(err: IntentError<E>) -> Self {
+        AppserviceError::Intent(err)
+    }
+}
+
+impl<E, S> From<PortalError<E, S>> for AppserviceError<E, S> {
+    fn from(err: PortalError<E, S>) -> Self {
+        AppserviceError::Portal(err)
+    }
+}
+
+type HandlerFn<C, B> = Box<
+    dyn Fn(
+            Arc<Appservice<C, B>>,
+            AnyRoomEvent,
+        ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>
+        + Send
+        + Sync,
+>;
+type ErrorFn<C, B> = Box<
+    dyn Fn(&str, &AppserviceError<<C as HttpClient>::Error, <B as BridgeStore>::Error>)
+        + Send
+        + Sync,
+>;
+
+/// An application service, tying together its registration, the bot user, the puppets, the
+/// portals and the store, and dispatching the events the homeserver sends to it.
+///
+/// The puppets and portals are kept in the stores of the `BridgeStore`. Events pass through the
+/// `EventFilters` first, which drop the events of the bridge itself by default. The remaining
+/// events are handled in order: upgrades of portal rooms are followed, invites of puppets are
+/// handled by the `InvitePolicy` if one is set, commands are run by the `CommandProcessor` if one
+/// is set, and every event that isn't a command is passed to the `on_event` handlers.
+///
+/// Transactions are handled at most once, using `BridgeStore::is_processed`. A transaction that
+/// fails isn't marked as processed, so it's handled again when the homeserver retries it.
+pub struct Appservice<C: HttpClient, B: BridgeStore> {
+    appservice: ApplicationService,
+    registration: Registration,
+    client: Client<C>,
+    appservice_client: Option<Arc<AppserviceClient<C>>>,
+    bot: BridgeBot<C>,
+    puppets: PuppetManager<C, SharedStore<Puppet, B::Error>>,
+    portals: PortalManager<C, SharedStore<Portal, B::Error>>,
+    store: B,
+    filters: EventFilters,
+    commands: Option<CommandProcessor<C>>,
+    invites: Option<InvitePolicy<C>>,
+    recorder: Option<TransactionRecorder>,
+    handlers: Vec<HandlerFn<C, B>>,
+    on_error: Vec<ErrorFn<C, B>>,
+}
+
+impl<C, B> Appservice<C, B>
+where
+    C: HttpClient + Clone,
+    B: BridgeStore,
+{
+    /// Create a new `Appservice` with the given `registration`, making requests using `client`,
+    /// which has the `as_token` of the registration, and keeping its state in `store`.
+    ///
+    /// The localparts of the puppets and the aliases of the portals start with `prefix`, which
+    /// should match the namespaces of the registration.
+    pub fn new(
+        appservice: ApplicationService,
+        registration: Registration,
+        client: Client<C>,
+        prefix: &str,
+        store: B,
+    ) -> Result<Self, ruma::identifiers::Error> {
+        let bot = BridgeBot::new(client.clone(), &registration, appservice.server_name())?;
+        let server_name = appservice.server_name().to_owned();
+        let puppets =
+            PuppetManager::new(client.clone(), server_name.clone(), prefix, store.puppets());
+        let portals =
+            PortalManager::new(bot.intent().clone(), server_name, prefix, store.portals());
+        let mut filters = EventFilters::new();
+        filters.add(EchoFilter::with_prefix(bot.user_id().clone(), prefix));
+
+        Ok(Self {
+            appservice,
+            registration,
+            client,
+            appservice_client: None,
+            bot,
+            puppets,
+            portals,
+            store,
+            filters,
+            commands: None,
+            invites: None,
+            recorder: None,
+            handlers: vec![],
+            on_error: vec![],
+        })
+    }
+
+    /// Get the information about the homeserver of the application service.
+    pub fn appservice(&self) -> &ApplicationService {
+        &self.appservice
+    }
+
+    /// Get the registration of the application service.
+    pub fn registration(&self) -> &Registration {
+        &self.registration
+    }
+
+    /// Get the client of the application service.
+    pub fn client(&self) -> &Client<C> {
+        &self.client
+    }
+
+    /// Send the requests of the bot, the puppets, the portals and the `InvitePolicy` with the
+    /// settings of the given `AppserviceClient`, or without them if it's `None`.
+    ///
+    /// A `CommandProcessor` acts as the `Intent` it was created with, so it should be created
+    /// from the bot after this is set.
+    pub fn set_appservice_client(&mut self, client: Option<Arc<AppserviceClient<C>>>) {
+        self.bot.set_appservice_client(client.clone());
+        self.puppets.set_appservice_client(client.clone());
+        self.portals.set_appservice_client(client.clone());
+        if let Some(invites) = &mut self.invites {
+            invites.set_appservice_client(client.clone());
+        }
+        self.appservice_client = client;
+    }
+
+    /// Get the `AppserviceClient` of which the settings are used by the application service, if
+    /// any.
+    pub fn appservice_client(&self) -> Option<&Arc<AppserviceClient<C>>> {
+        self.appservice_client.as_ref()
+    }
+
+    /// Get the bot user of the application service.
+    pub fn bot(&self) -> &BridgeBot<C> {
+        &self.bot
+    }
+
+    /// Get the bot user of the application service, to set its profile.
+    pub fn bot_mut(&mut self) -> &mut BridgeBot<C> {
+        &mut self.bot
+    }
+
+    /// Get the `PuppetManager` of the application service.
+    pub fn puppets(&self) -> &PuppetManager<C, SharedStore<Puppet, B::Error>> {
+        &self.puppets
+    }
+
+    /// Get the `PuppetManager` of the application service, to configure it.
+    pub fn puppets_mut(&mut self) -> &mut PuppetManager<C, SharedStore<Puppet, B::Error>> {
+        &mut self.puppets
+    }
+
+    /// Get the `PortalManager` of the application service.
+    pub fn portals(&self) -> &PortalManager<C, SharedStore<Portal, B::Error>> {
+        &self.portals
+    }
+
+    /// Get the `PortalManager` of the application service, to configure it.
+    pub fn portals_mut(&mut self) -> &mut PortalManager<C, SharedStore<Portal, B::Error>> {
+        &mut self.portals
+    }
+
+    /// Get the store of the application service.
+    pub fn store(&self) -> &B {
+        &self.store
+    }
+
+    /// Get the filters the events pass before they're handled, to add filters to them.
+    pub fn filters_mut(&mut self) -> &mut EventFilters {
+        &mut self.filters
+    }
+
+    /// Set the `CommandProcessor` running the commands sent to the bot.
+    pub fn set_command_processor(&mut self, commands: Option<CommandProcessor<C>>) {
+        self.commands = commands;
+    }
+
+    /// Set the `InvitePolicy` handling the invites of puppets, which is given the
+    /// `AppserviceClient` of the application service if it has one.
+    pub fn set_invite_policy(&mut self, mut invites: Option<InvitePolicy<C>>) {
+        if let (Some(invites), Some(client)) = (&mut invites, &self.appservice_client) {
+            invites.set_appservice_client(Some(client.clone()));
+        }
+        self.invites = invites;
+    }
+
+    /// Set the `TransactionRecorder` writing every transaction that's handled, to replay them
+    /// later.
+    pub fn set_recorder(&mut self, recorder: Option<TransactionRecorder>) {
+        self.recorder = recorder;
+    }
+
+    /// Call `f` with every event that passes the filters and isn't a command.
+    ///
+    /// The handlers are called in the order they were added, and an event is passed to the next
+    /// handler only when the previous one is done.
+    pub fn on_event<F, R>(&mut self, f: F)
+    where
+        F: Fn(Arc<Self>, AnyRoomEvent) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.handlers.push(Box::new(move |appservice, event| {
+            Box::pin(f(appservice, event))
+        }));
+    }
+
+    /// Call `f` with the ID and the error of every transaction that fails while serving, see
+    /// `run`.
+    pub fn on_error<F>(&mut self, f: F)
+    where
+        F: Fn(&str, &AppserviceError<C::Error, B::Error>) + Send + Sync + 'static,
+    {
+        self.on_error.push(Box::new(f));
+    }
+
+    /// Handle the transaction with the given `txn_id` and `events`, unless it was handled
+    /// before.
+    ///
+    /// Handling stops at the first error, in which case the transaction isn't marked as
+    /// processed.
+    pub async fn handle_transaction(
+        self: &Arc<Self>,
+        txn_id: &str,
+        events: &[Raw<AnyRoomEvent>],
+    ) -> Result<(), AppserviceError<C::Error, B::Error>> {
+        let processed = self
+            .store
+            .is_processed(txn_id)
+            .await
+            .map_err(AppserviceError::Store)?;
+        if processed {
+            return Ok(());
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder
+                .record(txn_id, events)
+                .map_err(AppserviceError::Record)?;
+        }
+
+        with_transaction(txn_id, self.dispatch(events)).await?;
+        self.store
+            .mark_processed(txn_id)
+            .await
+            .map_err(AppserviceError::Store)
+    }
+
+    /// Handle the `events` of a transaction, without checking whether it was handled before.
+    pub(crate) async fn dispatch(
+        self: &Arc<Self>,
+        events: &[Raw<AnyRoomEvent>],
+    ) -> Result<(), AppserviceError<C::Error, B::Error>> {
+        for event in self.filters.filter(events) {
+            self.handle_event(event).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_event(
+        self: &Arc<Self>,
+        event: AnyRoomEvent,
+    ) -> Result<(), AppserviceError<C::Error, B::Error>> {
+        self.portals.handle_event(&event, &self.puppets).await?;
+        if let Some(invites) = &self.invites {
+            invites.handle_event(&event, &self.portals).await?;
+        }
+        if let Some(commands) = &self.commands {
+            if commands.handle_event(&event).await? {
+                return Ok(());
+            }
+        }
+
+        for handler in &self.handlers {
+            handler(self.clone(), event.clone())
+                .await
+                .map_err(AppserviceError::Handler)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serve")]
+impl<C, B> Appservice<C, B>
+where
+    C: HttpClient + Clone + Send + Sync + 'static,
+    C::Error: Send,
+    B: BridgeStore + 'static,
+{
+    /// Start the bot user and serve the application service API on `addrs`, handling the
+    /// transactions of the homeserver using `handle_transaction`.
+    ///
+    /// Failed transactions are passed to the `on_error` callbacks, and answered with an error so
+    /// the homeserver retries them. This only returns when starting the bot or serving fails.
+    pub async fn run<A: ToSocketAddrs>(
+        self: Arc<Self>,
+        addrs: A,
+    ) -> Result<(), AppserviceError<C::Error, B::Error>> {
+        self.bot.start().await?;
+
+        let appservice = self;
+        crate::server::serve(addrs, move |txn_id, events| {
+            let appservice = appservice.clone();
+            async move {
+                if let Err(err) = appservice.handle_transaction(&txn_id, &events).await {
+                    for f in &appservice.on_error {
+                        f(&txn_id, &err);
+                    }
+                    return Err(());
+                }
+                Ok(String::new())
+            }
+        })
+        .await
+        .map_err(AppserviceError::Server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+
+    use ruma::events::AnyRoomEvent;
+    use ruma::identifiers::{RoomId, ServerName};
+    use ruma::serde::Raw;
+    use serde_json::{json, value::to_raw_value};
+
+    use crate::testing::mock_client;
+    use crate::{
+        ApplicationService, Appservice, AppserviceError, Command, CommandProcessor, Error,
+        MemoryBridgeStore, Namespaces, Registration, RegistrationInit,
+    };
+
+    fn registration() -> Registration {
+        Registration::from(RegistrationInit {
+            id: String::from("bridge"),
+            as_token: String::from("as_token"),
+            hs_token: String::from("hs_token"),
+            namespaces: Namespaces::new(),
+            url: String::from("http://localhost:8080"),
+            sender_localpart: String::from("bridgebot"),
+            rate_limited: None,
+            protocols: None,
+        })
+    }
+
+    fn message(txn: usize, sender: &str, body: &str) -> Raw<AnyRoomEvent> {
+        let event = json!({
+            "type": "m.room.message",
+            "event_id": format!("$event{}:example.org", txn),
+            "room_id": "!room:example.org",
+            "sender": sender,
+            "origin_server_ts": 0,
+            "content": { "msgtype": "m.text", "body": body },
+        });
+        Raw::from_json(to_raw_value(&event).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_appservice() {
+        let (client, state) = mock_client();
+        let appservice = ApplicationService::new(
+            <Box<ServerName>>::try_from("example.org").unwrap(),
+            "https://matrix.example.org".parse().unwrap(),
+        );
+        let mut service = Appservice::new(
+            appservice,
+            registration(),
+            client,
+            "_ext_",
+            MemoryBridgeStore::new(),
+        )
+        .unwrap();
+        assert_eq!(service.bot().user_id().as_str(), "@bridgebot:example.org");
+
+        let mut commands = CommandProcessor::new(service.bot().intent().clone(), "!bridge");
+        commands.set_admin_room(Some(RoomId::try_from("!room:example.org").unwrap()));
+        commands.add_command(Command::new("hello", "Say hello", |_| async {
+            Ok(String::from("Hello"))
+        }));
+        service.set_command_processor(Some(commands));
+
+        let handled = Arc::new(Mutex::new(vec![]));
+        let events = handled.clone();
+        service.on_event(move |_, event| {
+            let events = events.clone();
+            async move {
+                events.lock().unwrap().push(event.event_id().clone());
+                if event.room_id().as_str() == "!room:example.org" {
+                    Ok(())
+                } else {
+                    Err(Error::Config(String::from("unknown room")))
+                }
+            }
+        });
+        let service = Arc::new(service);
+
+        state.respond("/send/", 200, json!({ "event_id": "$reply:example.org" }));
+        let events = vec![
+            message(1, "@alice:example.org", "Hi"),
+            message(2, "@_ext_bob:example.org", "Echo"),
+            message(3, "@bridgebot:example.org", "Echo"),
+            message(4, "@alice:example.org", "!bridge hello"),
+        ];
+        service.handle_transaction("txn1", &events).await.unwrap();
+        assert_eq!(handled.lock().unwrap().len(), 1);
+        assert_eq!(handled.lock().unwrap()[0].as_str(), "$event1:example.org");
+        let reply = &state.requests_to("/send/m.room.message/")[0];
+        assert_eq!(reply.body["body"], "Hello");
+
+        // transactions are handled once.
+        service.handle_transaction("txn1", &events).await.unwrap();
+        assert_eq!(handled.lock().unwrap().len(), 1);
+        assert_eq!(state.requests_to("/send/").len(), 1);
+
+        let event = json!({
+            "type": "m.room.message",
+            "event_id": "$other:example.org",
+            "room_id": "!other:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 0,
+            "content": { "msgtype": "m.text", "body": "Hi" },
+        });
+        let events = vec![Raw::from_json(to_raw_value(&event).unwrap())];
+        let err = service.handle_transaction("txn2", &events).await;
+        assert!(matches!(err, Err(AppserviceError::Handler(_))));
+        service
+            .handle_transaction("txn2", &events)
+            .await
+            .unwrap_err();
+        assert_eq!(handled.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+use ruma::api::client::r0::state::get_state_events;
+use ruma::events::room::create::RoomType;
+use ruma::identifiers::RoomId;
+use ruma_client::HttpClient;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::value::to_raw_value;
+
+use crate::intent::{Intent, IntentError};
+use crate::mappingdict::MappingDict;
+use crate::portal::{Portal, PortalError, PortalManager, RoomOptions};
+use crate::store::MappingStore;
+
+/// A state event of a room, as far as needed to find the children of a space.
+#[derive(Deserialize)]
+struct StateEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    state_key: String,
+    content: SpaceChildContent,
+}
+
+#[derive(Deserialize)]
+struct SpaceChildContent {
+    via: Option<Vec<String>>,
+}
+
+/// Manages the spaces of an application service, grouping the portals of a network or team of
+/// the external service.
+///
+/// The spaces themselves are portals of type `m.space`, kept by a `PortalManager` that should use
+/// another store and alias prefix than the manager of the other portals. Children are linked to
+/// their space using both `m.space.child` and `m.space.parent` events sent by the bot, so the
+/// bot needs to be able to send state events in the rooms of the children.
+pub struct SpaceManager<C, S = Mutex<MappingDict<Portal>>> {
+    spaces: PortalManager<C, S>,
+}
+
+impl<C, S> SpaceManager<C, S>
+where
+    C: HttpClient,
+    S: MappingStore<Portal>,
+{
+    /// Create a new `SpaceManager`, keeping the spaces using `spaces`.
+    pub fn new(spaces: PortalManager<C, S>) -> Self {
+        Self { spaces }
+    }
+
+    /// Get the `PortalManager` keeping the spaces.
+    pub fn spaces(&self) -> &PortalManager<C, S> {
+        &self.spaces
+    }
+
+    fn bot(&self) -> &Intent<C> {
+        self.spaces.bot()
+    }
+
+    /// Get the space of the external network or team `external_id`, creating it using the given
+    /// `options` if it doesn't exist.
+    pub async fn ensure_space(
+        &self,
+        external_id: &str,
+        options: &RoomOptions,
+    ) -> Result<Portal, PortalError<C::Error, S::Error>> {
+        let options = RoomOptions {
+            room_type: Some(RoomType::Space),
+            ..options.clone()
+        };
+        self.spaces.ensure_portal(external_id, &options).await
+    }
+
+    /// Get the rooms that are children of the space with the given `space_id`.
+    pub async fn children(
+        &self,
+        space_id: &RoomId,
+    ) -> Result<BTreeSet<RoomId>, IntentError<C::Error>> {
+        let response = self
+            .bot()
+            .send(get_state_events::Request::new(space_id))
+            .await?;
+        let children = response
+            .room_state
+            .iter()
+            .filter_map(|event| serde_json::from_str::<StateEvent>(event.json().get()).ok())
+            .filter(|event| event.event_type == "m.space.child")
+            .filter(|event| matches!(&event.content.via, Some(via) if !via.is_empty()))
+            .filter_map(|event| RoomId::try_from(event.state_key).ok())
+            .collect();
+        Ok(children)
+    }
+
+    /// Add the room with the given `room_id` as a child of the space with the given `space_id`,
+    /// and set the space as its canonical parent.
+    pub async fn add_child(
+        &self,
+        space_id: &RoomId,
+        room_id: &RoomId,
+    ) -> Result<(), IntentError<C::Error>> {
+        let via = [self.bot().user_id().server_name().as_str()];
+        let child = to_raw_value(&json!({ "via": via })).expect("content should serialize");
+        let parent = to_raw_value(&json!({ "via": via, "canonical": true }))
+            .expect("content should serialize");
+
+        self.bot()
+            .send_state_raw(space_id, "m.space.child", room_id.as_str(), child)
+            .await?;
+        self.bot()
+            .send_state_raw(room_id, "m.space.parent", space_id.as_str(), parent)
+            .await?;
+        Ok(())
+    }
+
+    /// Remove the room with the given `room_id` from the space with the given `space_id`.
+    pub async fn remove_child(
+        &self,
+        space_id: &RoomId,
+        room_id: &RoomId,
+    ) -> Result<(), IntentError<C::Error>> {
+        let empty = || to_raw_value(&json!({})).expect("content should serialize");
+        self.bot()
+            .send_state_raw(space_id, "m.space.child", room_id.as_str(), empty())
+            .await?;
+        self.bot()
+            .send_state_raw(room_id, "m.space.parent", space_id.as_str(), empty())
+            .await?;
+        Ok(())
+    }
+
+    /// Make the rooms with the given `room_ids` the only children of the space of the external
+    /// network or team `external_id`, following the structure of the external service.
+    ///
+    /// Returns the space, or `None` if it doesn't exist.
+    pub async fn sync_children(
+        &self,
+        external_id: &str,
+        room_ids: &[RoomId],
+    ) -> Result<Option<Portal>, PortalError<C::Error, S::Error>> {
+        let space = match self.spaces.get(external_id).await {
+            Ok(Some(space)) => space,
+            Ok(None) => return Ok(None),
+            Err(err) => return Err(PortalError::Store(err)),
+        };
+
+        let current = self.children(space.room_id()).await?;
+        let wanted: BTreeSet<&RoomId> = room_ids.iter().collect();
+        for room_id in &wanted {
+            if !current.contains(*room_id) {
+                self.add_child(space.room_id(), room_id).await?;
+            }
+        }
+        for room_id in &current {
+            if !wanted.contains(room_id) {
+                self.remove_child(space.room_id(), room_id).await?;
+            }
+        }
+        Ok(Some(space))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{RoomId, ServerName, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{Intent, PortalManager, RoomOptions, SpaceManager};
+
+    #[tokio::test]
+    async fn test_space_manager() {
+        let (client, state) = mock_client();
+        let bot = Intent::new(client, UserId::try_from("@bot:example.org").unwrap());
+        let server_name = <Box<ServerName>>::try_from("example.org").unwrap();
+        let manager: SpaceManager<_> = SpaceManager::new(PortalManager::new(
+            bot,
+            server_name,
+            "_ext_space_",
+            Default::default(),
+        ));
+
+        state.respond(
+            "/directory/room/",
+            404,
+            json!({ "errcode": "M_NOT_FOUND", "error": "Room alias not found." }),
+        );
+        state.respond(
+            "/createRoom",
+            200,
+            json!({ "room_id": "!space:example.org" }),
+        );
+        let space = manager
+            .ensure_space("team", &RoomOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            state.requests_to("/createRoom")[0].body["creation_content"]["type"],
+            "m.space"
+        );
+
+        state.respond(
+            "/state?",
+            200,
+            json!([
+                {
+                    "type": "m.space.child",
+                    "state_key": "!a:example.org",
+                    "content": { "via": ["example.org"] },
+                    "event_id": "$1:example.org",
+                    "sender": "@bot:example.org",
+                    "origin_server_ts": 1,
+                },
+                {
+                    "type": "m.space.child",
+                    "state_key": "!removed:example.org",
+                    "content": {},
+                    "event_id": "$2:example.org",
+                    "sender": "@bot:example.org",
+                    "origin_server_ts": 2,
+                },
+            ]),
+        );
+        let children = manager.children(space.room_id()).await.unwrap();
+        assert_eq!(
+            children.into_iter().collect::<Vec<_>>(),
+            vec![RoomId::try_from("!a:example.org").unwrap()]
+        );
+
+        state.respond(
+            "/state/m.space.",
+            200,
+            json!({ "event_id": "$state:example.org" }),
+        );
+        let b = RoomId::try_from("!b:example.org").unwrap();
+        manager.sync_children("team", &[b]).await.unwrap().unwrap();
+        assert!(manager
+            .sync_children("unknown", &[])
+            .await
+            .unwrap()
+            .is_none());
+
+        let child = state.requests_to("/state/m.space.child/");
+        assert_eq!(child.len(), 2);
+        assert!(child[0]
+            .path
+            .contains("!space:example.org/state/m.space.child/!b:example.org"));
+        assert_eq!(child[0].body, json!({ "via": ["example.org"] }));
+        assert!(child[1].path.contains("/m.space.child/!a:example.org"));
+        assert_eq!(child[1].body, json!({}));
+
+        let parent = state.requests_to("/state/m.space.parent/");
+        assert!(parent[0]
+            .path
+            .contains("!b:example.org/state/m.space.parent/!space:example.org"));
+        assert_eq!(
+            parent[0].body,
+            json!({ "via": ["example.org"], "canonical": true })
+        );
+    }
+}
@@ -0,0 +1,567 @@
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::doublepuppet::DoublePuppet;
+use crate::mappingdict::{Mappable, MappingId};
+use crate::messages::{BridgedMessage, BridgedReaction};
+use crate::migrations::Migrations;
+use crate::portal::Portal;
+use crate::puppet::Puppet;
+use crate::sendqueue::QueuedMessage;
+use crate::store::{BridgeStore, MappingStore, SharedStore};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{Counters, MappingStats};
+
+/// An error from a `SqliteMappingStore`.
+#[derive(Debug, Error)]
+pub enum StoreError {
+    /// There was an error from SQLite.
+    #[error("SQLite error: {0}")]
+    Sqlite(rusqlite::Error),
+    /// There was an error (de)serializing an item or ID.
+    #[error("(de)serializing failed: {0}")]
+    Serde(serde_json::Error),
+    /// The blocking task running the query failed.
+    #[error("the query task failed: {0}")]
+    Join(tokio::task::JoinError),
+    /// The given table name is not a valid identifier.
+    #[error("invalid table name")]
+    InvalidTableName,
+    /// The database schema has a newer version than the latest known migration.
+    #[error("unknown database schema version {0}")]
+    UnknownSchemaVersion(u32),
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        StoreError::Sqlite(err)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        StoreError::Serde(err)
+    }
+}
+
+impl From<tokio::task::JoinError> for StoreError {
+    fn from(err: tokio::task::JoinError) -> Self {
+        StoreError::Join(err)
+    }
+}
+
+/// A `MappingStore` keeping the items as JSON in a table of an SQLite database.
+///
+/// Both IDs of the items are stored JSON encoded in separate indexed columns, so the references
+/// to the IDs must serialize the same as the owned IDs. Queries run on the blocking thread pool
+/// of tokio, and every write is a single durable transaction.
+pub struct SqliteMappingStore<V> {
+    conn: Arc<Mutex<Connection>>,
+    table: Arc<str>,
+    #[cfg(feature = "metrics")]
+    counters: Arc<Counters>,
+    _items: PhantomData<fn() -> V>,
+}
+
+impl<V> Clone for SqliteMappingStore<V> {
+    fn clone(&self) -> Self {
+        Self {
+            conn: self.conn.clone(),
+            table: self.table.clone(),
+            #[cfg(feature = "metrics")]
+            counters: self.counters.clone(),
+            _items: PhantomData,
+        }
+    }
+}
+
+/// Check that `table` can be used as a table name without quoting.
+fn check_table_name(table: &str) -> Result<(), StoreError> {
+    let valid = !table.is_empty()
+        && !table.starts_with(|c: char| c.is_ascii_digit())
+        && table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(StoreError::InvalidTableName)
+    }
+}
+
+/// Get the SQL creating the table of a `SqliteMappingStore`.
+///
+/// The table may already exist, since stores used to create it outside of any migration.
+fn mapping_table(table: &str) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+            matrix_id TEXT NOT NULL UNIQUE,
+            external_id TEXT NOT NULL UNIQUE,
+            value TEXT NOT NULL
+        );",
+        table
+    )
+}
+
+impl<V> SqliteMappingStore<V> {
+    /// Open or create the SQLite database at `path`, storing the items in `table`.
+    pub fn open<P: AsRef<Path>>(path: P, table: &str) -> Result<Self, StoreError> {
+        Self::new(Connection::open(path)?, table)
+    }
+
+    /// Store the items in `table` of the given database connection, creating the table if it
+    /// doesn't exist yet.
+    ///
+    /// Multiple stores can share a database by using separate tables. The schema of the table is
+    /// migrated in the `matrix_appservice_rs.<table>` scope.
+    pub fn new(conn: Connection, table: &str) -> Result<Self, StoreError> {
+        check_table_name(table)?;
+        let mut migrations = Migrations::new(&format!("matrix_appservice_rs.{}", table));
+        migrations.add(1, &mapping_table(table));
+        migrations.apply(&conn)?;
+
+        Self::shared(Arc::new(Mutex::new(conn)), table)
+    }
+
+    /// Like `new`, with a connection shared with other stores that already has the table.
+    fn shared(conn: Arc<Mutex<Connection>>, table: &str) -> Result<Self, StoreError> {
+        check_table_name(table)?;
+
+        Ok(Self {
+            conn,
+            table: table.into(),
+            #[cfg(feature = "metrics")]
+            counters: Arc::default(),
+            _items: PhantomData,
+        })
+    }
+
+    /// Get the statistics of this store, which are shared with its clones.
+    ///
+    /// Every `get` counts as a hit or a miss. Items are never evicted from the table, and it
+    /// isn't kept in memory.
+    #[cfg(feature = "metrics")]
+    pub async fn stats(&self) -> Result<MappingStats, StoreError> {
+        let len: i64 = self
+            .with_conn(|conn, table| {
+                let query = format!("SELECT COUNT(*) FROM {}", table);
+                Ok(conn.query_row(&query, [], |row| row.get(0))?)
+            })
+            .await?;
+
+        Ok(MappingStats {
+            len: len as usize,
+            ..self.counters.stats()
+        })
+    }
+
+    /// Run `f` with the database connection on the blocking thread pool.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection, &str) -> Result<T, StoreError> + Send + 'static,
+    {
+        let table = self.table.clone();
+        with_conn(&self.conn, move |conn| f(conn, &table)).await
+    }
+}
+
+/// Run `f` with the database connection `conn` on the blocking thread pool.
+async fn with_conn<T, F>(conn: &Arc<Mutex<Connection>>, f: F) -> Result<T, StoreError>
+where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> Result<T, StoreError> + Send + 'static,
+{
+    let conn = conn.clone();
+    tokio::task::spawn_blocking(move || {
+        let conn = conn.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&conn)
+    })
+    .await?
+}
+
+/// Get the column and JSON encoded value of the given `identifier`.
+fn column_and_key<E, M>(
+    identifier: MappingId<'_, E, M>,
+) -> Result<(&'static str, String), StoreError>
+where
+    E: ?Sized + Serialize,
+    M: ?Sized + Serialize,
+{
+    Ok(match identifier {
+        MappingId::Matrix(m) => ("matrix_id", serde_json::to_string(m)?),
+        MappingId::External(e) => ("external_id", serde_json::to_string(e)?),
+    })
+}
+
+#[async_trait]
+impl<V> MappingStore<V> for SqliteMappingStore<V>
+where
+    V: Mappable + Serialize + DeserializeOwned + Send + 'static,
+    V::ExternalReference: Serialize + Sync,
+    V::MatrixReference: Serialize + Sync,
+{
+    type Error = StoreError;
+
+    async fn get(
+        &self,
+        identifier: MappingId<'_, V::ExternalReference, V::MatrixReference>,
+    ) -> Result<Option<V>, StoreError> {
+        let (column, key) = column_and_key(identifier)?;
+        let value: Option<String> = self
+            .with_conn(move |conn, table| {
+                let query = format!("SELECT value FROM {} WHERE {} = ?1", table, column);
+                Ok(conn
+                    .query_row(&query, params![key], |row| row.get(0))
+                    .optional()?)
+            })
+            .await?;
+        #[cfg(feature = "metrics")]
+        self.counters.lookup(value.is_some());
+
+        Ok(match value {
+            Some(value) => Some(serde_json::from_str(&value)?),
+            None => None,
+        })
+    }
+
+    async fn insert(&self, item: V) -> Result<(), StoreError> {
+        let matrix_id = serde_json::to_string(item.as_matrix())?;
+        let external_id = serde_json::to_string(item.as_external())?;
+        let value = serde_json::to_string(&item)?;
+
+        self.with_conn(move |conn, table| {
+            // `REPLACE` removes all rows conflicting on either ID.
+            let query = format!(
+                "INSERT OR REPLACE INTO {} (matrix_id, external_id, value) VALUES (?1, ?2, ?3)",
+                table
+            );
+            conn.execute(&query, params![matrix_id, external_id, value])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove(
+        &self,
+        identifier: MappingId<'_, V::ExternalReference, V::MatrixReference>,
+    ) -> Result<Option<V>, StoreError> {
+        let (column, key) = column_and_key(identifier)?;
+        let value: Option<String> = self
+            .with_conn(move |conn, table| {
+                let query = format!(
+                    "DELETE FROM {} WHERE {} = ?1 RETURNING value",
+                    table, column
+                );
+                Ok(conn
+                    .query_row(&query, params![key], |row| row.get(0))
+                    .optional()?)
+            })
+            .await?;
+
+        Ok(match value {
+            Some(value) => Some(serde_json::from_str(&value)?),
+            None => None,
+        })
+    }
+
+    async fn items(&self) -> Result<Vec<V>, StoreError> {
+        let values: Vec<String> = self
+            .with_conn(|conn, table| {
+                let mut stmt =
+                    conn.prepare(&format!("SELECT value FROM {} ORDER BY rowid", table))?;
+                let values = stmt
+                    .query_map([], |row| row.get(0))?
+                    .collect::<Result<_, _>>()?;
+                Ok(values)
+            })
+            .await?;
+
+        values
+            .iter()
+            .map(|value| Ok(serde_json::from_str(value)?))
+            .collect()
+    }
+}
+
+/// A `BridgeStore` keeping everything in an SQLite database, with a `SqliteMappingStore` table
+/// for every kind of item.
+#[derive(Clone)]
+pub struct SqliteBridgeStore {
+    conn: Arc<Mutex<Connection>>,
+    puppets: SqliteMappingStore<Puppet>,
+    double_puppets: SqliteMappingStore<DoublePuppet>,
+    portals: SqliteMappingStore<Portal>,
+    messages: SqliteMappingStore<BridgedMessage>,
+    reactions: SqliteMappingStore<BridgedReaction>,
+    send_queue: SqliteMappingStore<QueuedMessage>,
+}
+
+impl SqliteBridgeStore {
+    /// Open or create the SQLite database at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        Self::new(Connection::open(path)?)
+    }
+
+    /// Keep everything in the given database connection, migrating its schema to the latest
+    /// version.
+    pub fn new(conn: Connection) -> Result<Self, StoreError> {
+        Self::migrations().apply(&conn)?;
+
+        let conn = Arc::new(Mutex::new(conn));
+        Ok(Self {
+            puppets: SqliteMappingStore::shared(conn.clone(), "puppets")?,
+            double_puppets: SqliteMappingStore::shared(conn.clone(), "double_puppets")?,
+            portals: SqliteMappingStore::shared(conn.clone(), "portals")?,
+            messages: SqliteMappingStore::shared(conn.clone(), "messages")?,
+            reactions: SqliteMappingStore::shared(conn.clone(), "reactions")?,
+            send_queue: SqliteMappingStore::shared(conn.clone(), "send_queue")?,
+            conn,
+        })
+    }
+
+    /// Get the migrations of the schema of the tables of this store, in the
+    /// `matrix_appservice_rs` scope.
+    pub fn migrations() -> Migrations {
+        let mut migrations = Migrations::new("matrix_appservice_rs");
+        let mut initial = String::new();
+        for table in &[
+            "puppets",
+            "double_puppets",
+            "portals",
+            "messages",
+            "reactions",
+            "send_queue",
+        ] {
+            initial.push_str(&mapping_table(table));
+        }
+        initial.push_str(
+            "CREATE TABLE IF NOT EXISTS processed_transactions (
+                txn_id TEXT NOT NULL PRIMARY KEY
+            );
+            CREATE TABLE IF NOT EXISTS key_values (
+                key TEXT NOT NULL PRIMARY KEY,
+                value TEXT NOT NULL
+            );",
+        );
+        migrations.add(1, &initial);
+        migrations
+    }
+
+    /// Get the statistics of the tables of this store, by the name of their table.
+    #[cfg(feature = "metrics")]
+    pub async fn stats(&self) -> Result<Vec<(&'static str, MappingStats)>, StoreError> {
+        Ok(vec![
+            ("puppets", self.puppets.stats().await?),
+            ("double_puppets", self.double_puppets.stats().await?),
+            ("portals", self.portals.stats().await?),
+            ("messages", self.messages.stats().await?),
+            ("reactions", self.reactions.stats().await?),
+            ("send_queue", self.send_queue.stats().await?),
+        ])
+    }
+
+    /// Apply the given `migrations` of the bridge to the database of this store, in their own
+    /// scope.
+    ///
+    /// Returns the versions that were applied.
+    pub async fn migrate(&self, migrations: Migrations) -> Result<Vec<u32>, StoreError> {
+        with_conn(&self.conn, move |conn| migrations.apply(conn)).await
+    }
+}
+
+#[async_trait]
+impl BridgeStore for SqliteBridgeStore {
+    type Error = StoreError;
+
+    fn puppets(&self) -> SharedStore<Puppet, StoreError> {
+        Arc::new(self.puppets.clone())
+    }
+
+    fn double_puppets(&self) -> SharedStore<DoublePuppet, StoreError> {
+        Arc::new(self.double_puppets.clone())
+    }
+
+    fn portals(&self) -> SharedStore<Portal, StoreError> {
+        Arc::new(self.portals.clone())
+    }
+
+    fn messages(&self) -> SharedStore<BridgedMessage, StoreError> {
+        Arc::new(self.messages.clone())
+    }
+
+    fn reactions(&self) -> SharedStore<BridgedReaction, StoreError> {
+        Arc::new(self.reactions.clone())
+    }
+
+    fn send_queue(&self) -> SharedStore<QueuedMessage, StoreError> {
+        Arc::new(self.send_queue.clone())
+    }
+
+    async fn is_processed(&self, txn_id: &str) -> Result<bool, StoreError> {
+        let txn_id = txn_id.to_string();
+        with_conn(&self.conn, move |conn| {
+            let query = "SELECT 1 FROM processed_transactions WHERE txn_id = ?1";
+            let row: Option<i64> = conn
+                .query_row(query, params![txn_id], |row| row.get(0))
+                .optional()?;
+            Ok(row.is_some())
+        })
+        .await
+    }
+
+    async fn mark_processed(&self, txn_id: &str) -> Result<(), StoreError> {
+        let txn_id = txn_id.to_string();
+        with_conn(&self.conn, move |conn| {
+            let query = "INSERT OR IGNORE INTO processed_transactions (txn_id) VALUES (?1)";
+            conn.execute(query, params![txn_id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<Value>, StoreError> {
+        let key = key.to_string();
+        let value: Option<String> = with_conn(&self.conn, move |conn| {
+            let query = "SELECT value FROM key_values WHERE key = ?1";
+            Ok(conn
+                .query_row(query, params![key], |row| row.get(0))
+                .optional()?)
+        })
+        .await?;
+
+        Ok(match value {
+            Some(value) => Some(serde_json::from_str(&value)?),
+            None => None,
+        })
+    }
+
+    async fn set_value(&self, key: &str, value: Value) -> Result<(), StoreError> {
+        let key = key.to_string();
+        let value = serde_json::to_string(&value)?;
+        with_conn(&self.conn, move |conn| {
+            let query = "INSERT OR REPLACE INTO key_values (key, value) VALUES (?1, ?2)";
+            conn.execute(query, params![key, value])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_value(&self, key: &str) -> Result<Option<Value>, StoreError> {
+        let key = key.to_string();
+        let value: Option<String> = with_conn(&self.conn, move |conn| {
+            let query = "DELETE FROM key_values WHERE key = ?1 RETURNING value";
+            Ok(conn
+                .query_row(query, params![key], |row| row.get(0))
+                .optional()?)
+        })
+        .await?;
+
+        Ok(match value {
+            Some(value) => Some(serde_json::from_str(&value)?),
+            None => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use crate::store::tests::{check_bridge_store, check_store, Portal};
+    use crate::{Migrations, SqliteBridgeStore, SqliteMappingStore, StoreError};
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let store = SqliteMappingStore::new(Connection::open_in_memory().unwrap(), "portals");
+        check_store(&store.unwrap()).await;
+
+        assert!(matches!(
+            SqliteMappingStore::<Portal>::new(Connection::open_in_memory().unwrap(), "a; DROP"),
+            Err(StoreError::InvalidTableName)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_migrations() {
+        use crate::{MappingId, MappingStore};
+
+        let dir = std::env::temp_dir().join(format!("sqlite-store-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("store.db");
+        let _ = std::fs::remove_file(&path);
+
+        // a table created before it was migrated is kept.
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE portals (
+                matrix_id TEXT NOT NULL UNIQUE,
+                external_id TEXT NOT NULL UNIQUE,
+                value TEXT NOT NULL
+            );
+            INSERT INTO portals VALUES ('\"!a\"', '\"#a\"', '{\"room_id\":\"!a\",\"channel\":\"#a\"}')",
+        )
+        .unwrap();
+        SqliteMappingStore::<Portal>::new(conn, "portals").unwrap();
+
+        let store = SqliteMappingStore::<Portal>::open(&path, "portals").unwrap();
+        assert_eq!(
+            store.get(MappingId::Matrix("!a")).await.unwrap(),
+            Some(Portal::new("!a", "#a"))
+        );
+
+        let conn = Connection::open(&path).unwrap();
+        let versions: Vec<(String, u32)> = conn
+            .prepare("SELECT scope, version FROM schema_version")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            versions,
+            vec![("matrix_appservice_rs.portals".to_string(), 1)]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_bridge_store() {
+        let store = SqliteBridgeStore::new(Connection::open_in_memory().unwrap()).unwrap();
+        check_bridge_store(&store).await;
+
+        let mut migrations = Migrations::new("bridge");
+        migrations.add(1, "CREATE TABLE bridge_users (id TEXT NOT NULL)");
+        assert_eq!(store.migrate(migrations.clone()).await.unwrap(), vec![1]);
+        assert!(store.migrate(migrations).await.unwrap().is_empty());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_sqlite_stats() {
+        use crate::{MappingId, MappingStore};
+
+        let store = SqliteMappingStore::new(Connection::open_in_memory().unwrap(), "portals");
+        let store = store.unwrap();
+        store.insert(Portal::new("!a", "#a")).await.unwrap();
+        store.get(MappingId::Matrix("!a")).await.unwrap();
+        store.clone().get(MappingId::External("#b")).await.unwrap();
+
+        let stats = store.stats().await.unwrap();
+        assert_eq!((stats.len, stats.hits, stats.misses), (1, 1, 1));
+        assert_eq!(stats.memory, None);
+
+        let store = SqliteBridgeStore::new(Connection::open_in_memory().unwrap()).unwrap();
+        let stats = store.stats().await.unwrap();
+        assert_eq!(stats.len(), 6);
+        assert!(stats.iter().all(|(_, stats)| stats.len == 0));
+    }
+}
@@ -0,0 +1,296 @@
+use std::sync::Mutex;
+
+use ruma::events::sticker::StickerEventContent;
+use ruma::events::{AnyMessageEvent, AnyMessageEventContent, AnyRoomEvent};
+use ruma::identifiers::{EventId, MxcUri, RoomId, UserId};
+use ruma::serde::Raw;
+use ruma_client::HttpClient;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::intent::{Intent, IntentError};
+use crate::mappingdict::{Mappable, MappingDict, MappingId};
+use crate::messages::deserialize_all;
+use crate::store::MappingStore;
+use crate::util::escape_html;
+
+/// Media uploaded to Matrix for media on the external service, like a sticker or custom emoji.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BridgedMedia {
+    mxc_uri: MxcUri,
+    external_id: String,
+}
+
+impl BridgedMedia {
+    /// Create a new `BridgedMedia`, linking the media at `mxc_uri` to the media `external_id` on
+    /// the external service.
+    pub fn new(mxc_uri: MxcUri, external_id: String) -> Self {
+        Self {
+            mxc_uri,
+            external_id,
+        }
+    }
+
+    /// Get the MXC URI of the media on Matrix.
+    pub fn mxc_uri(&self) -> &MxcUri {
+        &self.mxc_uri
+    }
+
+    /// Get the ID of the media on the external service.
+    pub fn external_id(&self) -> &str {
+        &self.external_id
+    }
+}
+
+impl Mappable for BridgedMedia {
+    type MatrixReference = MxcUri;
+    type MatrixType = MxcUri;
+    type ExternalReference = str;
+    type ExternalType = String;
+
+    fn as_matrix(&self) -> &MxcUri {
+        &self.mxc_uri
+    }
+    fn into_matrix(self) -> MxcUri {
+        self.mxc_uri
+    }
+    fn as_external(&self) -> &str {
+        &self.external_id
+    }
+    fn into_external(self) -> String {
+        self.external_id
+    }
+    fn into_split(self) -> (MxcUri, String) {
+        (self.mxc_uri, self.external_id)
+    }
+}
+
+/// An error from a `StickerCache`.
+#[derive(Debug, Error)]
+pub enum MediaError<E, S> {
+    /// A request to the homeserver failed.
+    #[error("{0}")]
+    Intent(IntentError<E>),
+    /// Loading or saving media failed.
+    #[error("loading or saving media failed: {0}")]
+    Store(S),
+}
+
+impl<E, S> From<IntentError<E>> for MediaError<E, S> {
+    fn from(err: IntentError<E>) -> Self {
+        MediaError::Intent(err)
+    }
+}
+
+/// Keeps the stickers and custom emoji of the external service that were uploaded to Matrix, so
+/// every image is only uploaded once.
+///
+/// The media is kept in a `MappingStore`, which is an in-memory `MappingDict` by default. The
+/// mapping works both ways, so the external sticker of a sticker sent on Matrix can be found
+/// using `external_id_for`.
+pub struct StickerCache<S = Mutex<MappingDict<BridgedMedia>>> {
+    store: S,
+}
+
+impl<S: MappingStore<BridgedMedia>> StickerCache<S> {
+    /// Create a new `StickerCache` keeping the uploaded media in `store`.
+    pub fn new(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Get the store containing the uploaded media.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Get the MXC URI of the media `external_id` of the external service, if it was uploaded
+    /// already.
+    pub async fn mxc_for(&self, external_id: &str) -> Result<Option<MxcUri>, S::Error> {
+        let media = self.store.get(MappingId::External(external_id)).await?;
+        Ok(media.map(BridgedMedia::into_matrix))
+    }
+
+    /// Get the ID on the external service of the media at `mxc_uri`, if it was bridged.
+    pub async fn external_id_for(&self, mxc_uri: &MxcUri) -> Result<Option<String>, S::Error> {
+        let media = self.store.get(MappingId::Matrix(mxc_uri)).await?;
+        Ok(media.map(BridgedMedia::into_external))
+    }
+
+    /// Get the MXC URI of the media `external_id` of the external service, uploading `data`
+    /// with the given `content_type` as `intent` if it wasn't uploaded yet.
+    ///
+    /// Use `mxc_for` first to avoid downloading media that was uploaded already.
+    pub async fn ensure_uploaded<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+        external_id: &str,
+        data: &[u8],
+        content_type: &str,
+    ) -> Result<MxcUri, MediaError<C::Error, S::Error>> {
+        let mxc_uri = self.mxc_for(external_id).await.map_err(MediaError::Store)?;
+        if let Some(mxc_uri) = mxc_uri {
+            return Ok(mxc_uri);
+        }
+
+        let mxc_uri = intent.upload(data, content_type, None).await?;
+        self.store
+            .insert(BridgedMedia::new(mxc_uri.clone(), external_id.to_string()))
+            .await
+            .map_err(MediaError::Store)?;
+        Ok(mxc_uri)
+    }
+}
+
+/// Build the HTML of a custom emoji with the given `shortcode`, like `:party:`, showing the
+/// image at `mxc_uri` inline in a formatted body.
+pub fn emote_html(mxc_uri: &MxcUri, shortcode: &str) -> String {
+    let shortcode = escape_html(shortcode);
+    format!(
+        "<img data-mx-emoticon src=\"{}\" alt=\"{}\" title=\"{}\" height=\"32\" />",
+        mxc_uri, shortcode, shortcode
+    )
+}
+
+/// A sticker sent by a Matrix user, which should be sent to the external service.
+#[derive(Debug, Clone)]
+pub struct Sticker {
+    /// The ID of the sticker event.
+    pub event_id: EventId,
+    /// The room the sticker was sent in.
+    pub room_id: RoomId,
+    /// The user that sent the sticker.
+    pub sender: UserId,
+    /// The content of the sticker event.
+    pub content: StickerEventContent,
+    /// The ID on the external service of the sticker image, if it was bridged from there.
+    pub external_id: Option<String>,
+}
+
+impl Sticker {
+    /// Resolve the sticker sent in `event` using `store`, finding the external sticker if the
+    /// image was bridged from the external service.
+    ///
+    /// Returns `None` if `event` isn't a sticker.
+    pub async fn resolve<S>(store: &S, event: &AnyRoomEvent) -> Result<Option<Self>, S::Error>
+    where
+        S: MappingStore<BridgedMedia>,
+    {
+        let event = match event {
+            AnyRoomEvent::Message(AnyMessageEvent::Sticker(event)) => event,
+            _ => return Ok(None),
+        };
+
+        let media = store.get(MappingId::Matrix(&event.content.url)).await?;
+        Ok(Some(Self {
+            event_id: event.event_id.clone(),
+            room_id: event.room_id.clone(),
+            sender: event.sender.clone(),
+            content: event.content.clone(),
+            external_id: media.map(BridgedMedia::into_external),
+        }))
+    }
+
+    /// Resolve the stickers in `events`, the events of a transaction, using `resolve`. Events
+    /// that can't be deserialized are skipped.
+    pub async fn resolve_all<S>(
+        store: &S,
+        events: &[Raw<AnyRoomEvent>],
+    ) -> Result<Vec<Self>, S::Error>
+    where
+        S: MappingStore<BridgedMedia>,
+    {
+        let mut stickers = vec![];
+        for event in deserialize_all(events) {
+            if let Some(sticker) = Self::resolve(store, &event).await? {
+                stickers.push(sticker);
+            }
+        }
+        Ok(stickers)
+    }
+}
+
+impl<C: HttpClient> Intent<C> {
+    /// Send a sticker with the given `content` to the room with the given `room_id`.
+    ///
+    /// Returns the ID of the sent event.
+    pub async fn send_sticker(
+        &self,
+        room_id: &RoomId,
+        content: StickerEventContent,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        self.send_message(room_id, &AnyMessageEventContent::Sticker(content))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::events::room::ImageInfo;
+    use ruma::events::sticker::StickerEventContent;
+    use ruma::identifiers::{MxcUri, RoomId, UserId};
+    use ruma::serde::Raw;
+    use serde_json::json;
+    use serde_json::value::to_raw_value;
+
+    use crate::testing::mock_client;
+    use crate::{emote_html, Intent, Sticker, StickerCache};
+
+    #[tokio::test]
+    async fn test_sticker_cache() {
+        let (client, state) = mock_client();
+        let ghost = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let cache: StickerCache = StickerCache::new(Default::default());
+
+        state.respond(
+            "/upload",
+            200,
+            json!({ "content_uri": "mxc://example.org/sticker" }),
+        );
+        assert!(cache.mxc_for("cat").await.unwrap().is_none());
+        let mxc_uri = cache
+            .ensure_uploaded(&ghost, "cat", b"image", "image/png")
+            .await
+            .unwrap();
+        cache
+            .ensure_uploaded(&ghost, "cat", b"image", "image/png")
+            .await
+            .unwrap();
+        assert_eq!(mxc_uri.as_str(), "mxc://example.org/sticker");
+        let uploads = state.requests_to("/_matrix/media/r0/upload");
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].method, "POST");
+
+        state.respond("/send/", 200, json!({ "event_id": "$sticker:example.org" }));
+        let content = StickerEventContent::new(String::from("Cat"), ImageInfo::new(), mxc_uri);
+        ghost.send_sticker(&room_id, content).await.unwrap();
+        let sends = state.requests_to("/send/m.sticker/");
+        assert_eq!(sends[0].body["url"], "mxc://example.org/sticker");
+
+        let event = json!({
+            "type": "m.sticker",
+            "event_id": "$sticker:example.org",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 1,
+            "content": { "body": "Cat", "info": {}, "url": "mxc://example.org/sticker" },
+        });
+        let events = vec![Raw::from_json(to_raw_value(&event).unwrap())];
+        let stickers = Sticker::resolve_all(cache.store(), &events).await.unwrap();
+        assert_eq!(stickers.len(), 1);
+        assert_eq!(stickers[0].external_id.as_deref(), Some("cat"));
+        assert_eq!(stickers[0].content.body, "Cat");
+    }
+
+    #[test]
+    fn test_emote_html() {
+        let mxc_uri = MxcUri::from("mxc://example.org/party");
+        assert_eq!(
+            emote_html(&mxc_uri, ":party\":"),
+            "<img data-mx-emoticon src=\"mxc://example.org/party\" alt=\":party&quot;:\" \
+             title=\":party&quot;:\" height=\"32\" />"
+        );
+    }
+}
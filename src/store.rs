@@ -0,0 +1,407 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::doublepuppet::DoublePuppet;
+use crate::mappingdict::{Mappable, MappingDict, MappingId};
+use crate::messages::{BridgedMessage, BridgedReaction};
+use crate::portal::Portal;
+use crate::puppet::Puppet;
+use crate::sendqueue::QueuedMessage;
+
+#[cfg(feature = "metrics")]
+use crate::metrics::MappingStats;
+
+/// Storage of `Mappable` items, with lookups by either ID, like a `MappingDict` that doesn't
+/// necessarily live in memory.
+#[async_trait]
+pub trait MappingStore<V>: Send + Sync
+where
+    V: Mappable + Send + 'static,
+    V::ExternalReference: Sync,
+    V::MatrixReference: Sync,
+{
+    type Error: Send;
+
+    /// Get the item associated with the given `identifier`, if any.
+    async fn get(
+        &self,
+        identifier: MappingId<'_, V::ExternalReference, V::MatrixReference>,
+    ) -> Result<Option<V>, Self::Error>;
+
+    /// Store the given `item`, replacing any items with the same Matrix or external ID.
+    async fn insert(&self, item: V) -> Result<(), Self::Error>;
+
+    /// Remove the item associated with the given `identifier`, returning it.
+    async fn remove(
+        &self,
+        identifier: MappingId<'_, V::ExternalReference, V::MatrixReference>,
+    ) -> Result<Option<V>, Self::Error>;
+
+    /// Returns whether there is an item associated with the given `identifier`.
+    async fn has(
+        &self,
+        identifier: MappingId<'_, V::ExternalReference, V::MatrixReference>,
+    ) -> Result<bool, Self::Error> {
+        Ok(self.get(identifier).await?.is_some())
+    }
+
+    /// Get all items in this store.
+    async fn items(&self) -> Result<Vec<V>, Self::Error>;
+}
+
+#[async_trait]
+impl<V> MappingStore<V> for Mutex<MappingDict<V>>
+where
+    V: Mappable + Clone + Send + 'static,
+    V::ExternalReference: Sync,
+    V::MatrixReference: Sync,
+    V::ExternalType: Send,
+    V::MatrixType: Send,
+{
+    type Error = Infallible;
+
+    async fn get(
+        &self,
+        identifier: MappingId<'_, V::ExternalReference, V::MatrixReference>,
+    ) -> Result<Option<V>, Infallible> {
+        let dict = self.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(dict.get(identifier).cloned())
+    }
+
+    async fn insert(&self, item: V) -> Result<(), Infallible> {
+        let mut dict = self.lock().unwrap_or_else(PoisonError::into_inner);
+        dict.remove(MappingId::Matrix(item.as_matrix()));
+        dict.remove(MappingId::External(item.as_external()));
+        dict.insert(item);
+        Ok(())
+    }
+
+    async fn remove(
+        &self,
+        identifier: MappingId<'_, V::ExternalReference, V::MatrixReference>,
+    ) -> Result<Option<V>, Infallible> {
+        let mut dict = self.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(dict.remove(identifier))
+    }
+
+    async fn items(&self) -> Result<Vec<V>, Infallible> {
+        let dict = self.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(dict.iter().cloned().collect())
+    }
+}
+
+/// A `MappingStore` shared through an `Arc`, so one store can be given to multiple managers.
+#[async_trait]
+impl<V, T> MappingStore<V> for Arc<T>
+where
+    V: Mappable + Send + 'static,
+    V::ExternalReference: Sync,
+    V::MatrixReference: Sync,
+    T: MappingStore<V> + ?Sized,
+{
+    type Error = T::Error;
+
+    async fn get(
+        &self,
+        identifier: MappingId<'_, V::ExternalReference, V::MatrixReference>,
+    ) -> Result<Option<V>, T::Error> {
+        (**self).get(identifier).await
+    }
+
+    async fn insert(&self, item: V) -> Result<(), T::Error> {
+        (**self).insert(item).await
+    }
+
+    async fn remove(
+        &self,
+        identifier: MappingId<'_, V::ExternalReference, V::MatrixReference>,
+    ) -> Result<Option<V>, T::Error> {
+        (**self).remove(identifier).await
+    }
+
+    async fn has(
+        &self,
+        identifier: MappingId<'_, V::ExternalReference, V::MatrixReference>,
+    ) -> Result<bool, T::Error> {
+        (**self).has(identifier).await
+    }
+
+    async fn items(&self) -> Result<Vec<V>, T::Error> {
+        (**self).items().await
+    }
+}
+
+/// A shared `MappingStore` of a `BridgeStore`.
+pub type SharedStore<V, E> = Arc<dyn MappingStore<V, Error = E>>;
+
+/// All persistent state of a bridge, in one place.
+///
+/// The stores of the different items are given to the managers using them, for example
+/// `PuppetManager::new(client, server_name, prefix, store.puppets())`, so they all share the
+/// same backend. Besides those, a `BridgeStore` keeps the IDs of the transactions that were
+/// processed, and arbitrary JSON values for bridge specific state.
+#[async_trait]
+pub trait BridgeStore: Send + Sync {
+    type Error: Send;
+
+    /// Get the store of the puppets.
+    fn puppets(&self) -> SharedStore<Puppet, Self::Error>;
+
+    /// Get the store of the double puppets.
+    fn double_puppets(&self) -> SharedStore<DoublePuppet, Self::Error>;
+
+    /// Get the store of the portals.
+    fn portals(&self) -> SharedStore<Portal, Self::Error>;
+
+    /// Get the store of the bridged messages.
+    fn messages(&self) -> SharedStore<BridgedMessage, Self::Error>;
+
+    /// Get the store of the bridged reactions.
+    fn reactions(&self) -> SharedStore<BridgedReaction, Self::Error>;
+
+    /// Get the store of the messages in a `SendQueue`.
+    fn send_queue(&self) -> SharedStore<QueuedMessage, Self::Error>;
+
+    /// Returns whether the transaction with the given `txn_id` was processed.
+    async fn is_processed(&self, txn_id: &str) -> Result<bool, Self::Error>;
+
+    /// Mark the transaction with the given `txn_id` as processed.
+    async fn mark_processed(&self, txn_id: &str) -> Result<(), Self::Error>;
+
+    /// Get the value stored under `key`, if any.
+    async fn get_value(&self, key: &str) -> Result<Option<Value>, Self::Error>;
+
+    /// Store `value` under `key`, replacing any previous value.
+    async fn set_value(&self, key: &str, value: Value) -> Result<(), Self::Error>;
+
+    /// Remove the value stored under `key`, returning it.
+    async fn remove_value(&self, key: &str) -> Result<Option<Value>, Self::Error>;
+}
+
+/// A `BridgeStore` keeping everything in memory, which is lost on a restart.
+#[derive(Clone, Default)]
+pub struct MemoryBridgeStore {
+    puppets: Arc<Mutex<MappingDict<Puppet>>>,
+    double_puppets: Arc<Mutex<MappingDict<DoublePuppet>>>,
+    portals: Arc<Mutex<MappingDict<Portal>>>,
+    messages: Arc<Mutex<MappingDict<BridgedMessage>>>,
+    reactions: Arc<Mutex<MappingDict<BridgedReaction>>>,
+    send_queue: Arc<Mutex<MappingDict<QueuedMessage>>>,
+    processed: Arc<Mutex<HashSet<String>>>,
+    values: Arc<Mutex<HashMap<String, Value>>>,
+}
+
+impl MemoryBridgeStore {
+    /// Create a new, empty `MemoryBridgeStore`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the statistics of the `MappingDict`s of this store, by the name of the items they
+    /// keep.
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self) -> Vec<(&'static str, MappingStats)> {
+        fn stats<V: Mappable>(dict: &Mutex<MappingDict<V>>) -> MappingStats {
+            dict.lock().unwrap_or_else(PoisonError::into_inner).stats()
+        }
+
+        vec![
+            ("puppets", stats(&self.puppets)),
+            ("double_puppets", stats(&self.double_puppets)),
+            ("portals", stats(&self.portals)),
+            ("messages", stats(&self.messages)),
+            ("reactions", stats(&self.reactions)),
+            ("send_queue", stats(&self.send_queue)),
+        ]
+    }
+}
+
+#[async_trait]
+impl BridgeStore for MemoryBridgeStore {
+    type Error = Infallible;
+
+    fn puppets(&self) -> SharedStore<Puppet, Infallible> {
+        self.puppets.clone()
+    }
+
+    fn double_puppets(&self) -> SharedStore<DoublePuppet, Infallible> {
+        self.double_puppets.clone()
+    }
+
+    fn portals(&self) -> SharedStore<Portal, Infallible> {
+        self.portals.clone()
+    }
+
+    fn messages(&self) -> SharedStore<BridgedMessage, Infallible> {
+        self.messages.clone()
+    }
+
+    fn reactions(&self) -> SharedStore<BridgedReaction, Infallible> {
+        self.reactions.clone()
+    }
+
+    fn send_queue(&self) -> SharedStore<QueuedMessage, Infallible> {
+        self.send_queue.clone()
+    }
+
+    async fn is_processed(&self, txn_id: &str) -> Result<bool, Infallible> {
+        let processed = self
+            .processed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        Ok(processed.contains(txn_id))
+    }
+
+    async fn mark_processed(&self, txn_id: &str) -> Result<(), Infallible> {
+        let mut processed = self
+            .processed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        processed.insert(txn_id.to_string());
+        Ok(())
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<Value>, Infallible> {
+        let values = self.values.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(values.get(key).cloned())
+    }
+
+    async fn set_value(&self, key: &str, value: Value) -> Result<(), Infallible> {
+        let mut values = self.values.lock().unwrap_or_else(PoisonError::into_inner);
+        values.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    async fn remove_value(&self, key: &str) -> Result<Option<Value>, Infallible> {
+        let mut values = self.values.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(values.remove(key))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use serde::{Deserialize, Serialize};
+
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::UserId;
+    use serde_json::json;
+
+    use crate::{
+        BridgeStore, Mappable, MappingDict, MappingId, MappingStore, MemoryBridgeStore, Puppet,
+    };
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub(crate) struct Portal {
+        room_id: String,
+        channel: String,
+    }
+
+    impl Portal {
+        pub(crate) fn new(room_id: &str, channel: &str) -> Self {
+            Self {
+                room_id: room_id.to_string(),
+                channel: channel.to_string(),
+            }
+        }
+    }
+
+    impl Mappable for Portal {
+        type MatrixReference = str;
+        type MatrixType = String;
+        type ExternalReference = str;
+        type ExternalType = String;
+
+        fn as_matrix(&self) -> &str {
+            &self.room_id
+        }
+        fn into_matrix(self) -> String {
+            self.room_id
+        }
+        fn as_external(&self) -> &str {
+            &self.channel
+        }
+        fn into_external(self) -> String {
+            self.channel
+        }
+        fn into_split(self) -> (String, String) {
+            (self.room_id, self.channel)
+        }
+    }
+
+    pub(crate) async fn check_store<S: MappingStore<Portal>>(store: &S)
+    where
+        S::Error: std::fmt::Debug,
+    {
+        store.insert(Portal::new("!a", "#a")).await.unwrap();
+        store.insert(Portal::new("!b", "#b")).await.unwrap();
+        assert_eq!(
+            store.get(MappingId::External("#b")).await.unwrap(),
+            Some(Portal::new("!b", "#b"))
+        );
+        assert!(store.has(MappingId::Matrix("!a")).await.unwrap());
+
+        // replaces both the item with the same room and the one with the same channel.
+        store.insert(Portal::new("!a", "#b")).await.unwrap();
+        assert_eq!(store.items().await.unwrap(), vec![Portal::new("!a", "#b")]);
+
+        assert_eq!(
+            store.remove(MappingId::Matrix("!a")).await.unwrap(),
+            Some(Portal::new("!a", "#b"))
+        );
+        assert_eq!(store.remove(MappingId::Matrix("!a")).await.unwrap(), None);
+        assert!(!store.has(MappingId::External("#b")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_dict_store() {
+        check_store(&Mutex::new(MappingDict::new())).await;
+    }
+
+    pub(crate) async fn check_bridge_store<S: BridgeStore>(store: &S)
+    where
+        S::Error: std::fmt::Debug,
+    {
+        let user_id = UserId::try_from("@_ext_alice:example.org").unwrap();
+        let puppet = Puppet::new(user_id.clone(), String::from("alice"));
+        store.puppets().insert(puppet.clone()).await.unwrap();
+        assert_eq!(
+            store
+                .puppets()
+                .get(MappingId::Matrix(&user_id))
+                .await
+                .unwrap(),
+            Some(puppet)
+        );
+        assert!(store.portals().items().await.unwrap().is_empty());
+
+        assert!(!store.is_processed("txn1").await.unwrap());
+        store.mark_processed("txn1").await.unwrap();
+        store.mark_processed("txn1").await.unwrap();
+        assert!(store.is_processed("txn1").await.unwrap());
+
+        assert_eq!(store.get_value("version").await.unwrap(), None);
+        store.set_value("version", json!(1)).await.unwrap();
+        store.set_value("version", json!({ "a": 2 })).await.unwrap();
+        assert_eq!(
+            store.get_value("version").await.unwrap(),
+            Some(json!({ "a": 2 }))
+        );
+        assert_eq!(
+            store.remove_value("version").await.unwrap(),
+            Some(json!({ "a": 2 }))
+        );
+        assert_eq!(store.remove_value("version").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_bridge_store() {
+        check_bridge_store(&MemoryBridgeStore::new()).await;
+    }
+}
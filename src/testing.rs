@@ -0,0 +1,158 @@
+//! A mock homeserver used by the tests of modules that make requests.
+
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use async_trait::async_trait;
+use ruma::api::exports::http;
+use ruma::api::exports::percent_encoding::percent_decode_str;
+use ruma_client::{Client, HttpClient};
+use serde_json::Value;
+
+/// A request received by a `MockClient`.
+#[derive(Debug, Clone)]
+pub(crate) struct MockRequest {
+    pub method: String,
+    /// The percent-decoded path and query of the request.
+    pub path: String,
+    /// The path and query of the request as it was sent.
+    pub raw_path: String,
+    pub headers: http::HeaderMap,
+    pub body: Value,
+}
+
+struct Response {
+    path: String,
+    status: u16,
+    body: Value,
+    once: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct MockState {
+    requests: Mutex<Vec<MockRequest>>,
+    responses: Mutex<Vec<Response>>,
+}
+
+impl MockState {
+    /// Respond to all requests having a path containing `path` with the given `status` and `body`.
+    pub fn respond(&self, path: &str, status: u16, body: Value) {
+        self.add(path, status, body, false);
+    }
+
+    /// Respond to the next request having a path containing `path` with the given `status` and
+    /// `body`, before any responses added using `respond`.
+    pub fn respond_once(&self, path: &str, status: u16, body: Value) {
+        self.add(path, status, body, true);
+    }
+
+    fn add(&self, path: &str, status: u16, body: Value, once: bool) {
+        let mut responses = self
+            .responses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        responses.push(Response {
+            path: path.to_string(),
+            status,
+            body,
+            once,
+        });
+    }
+
+    /// Get all requests received so far.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Get the requests received so far with a path containing `path`.
+    pub fn requests_to(&self, path: &str) -> Vec<MockRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|req| req.path.contains(path))
+            .collect()
+    }
+
+    /// Get another `MockClient` for this homeserver.
+    pub fn http_client(self: &Arc<Self>) -> MockClient {
+        MockClient(self.clone())
+    }
+
+    fn response(&self, path: &str) -> (u16, Value) {
+        let mut responses = self
+            .responses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let index = responses
+            .iter()
+            .position(|resp| resp.once && path.contains(&resp.path))
+            .or_else(|| responses.iter().position(|resp| path.contains(&resp.path)));
+
+        match index {
+            Some(i) if responses[i].once => {
+                let resp = responses.remove(i);
+                (resp.status, resp.body)
+            }
+            Some(i) => (responses[i].status, responses[i].body.clone()),
+            None => (200, serde_json::json!({})),
+        }
+    }
+}
+
+/// An `HttpClient` answering requests with the responses set in its `MockState`.
+#[derive(Clone)]
+pub(crate) struct MockClient(Arc<MockState>);
+
+#[async_trait]
+impl HttpClient for MockClient {
+    type RequestBody = Vec<u8>;
+    type ResponseBody = Vec<u8>;
+    type Error = Infallible;
+
+    async fn send_http_request(
+        &self,
+        req: http::Request<Vec<u8>>,
+    ) -> Result<http::Response<Vec<u8>>, Infallible> {
+        let raw_path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str().to_string())
+            .unwrap_or_default();
+        let path = percent_decode_str(&raw_path)
+            .decode_utf8_lossy()
+            .into_owned();
+        let body = serde_json::from_slice(req.body()).unwrap_or(Value::Null);
+
+        let (status, response) = self.0.response(&path);
+        self.0
+            .requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(MockRequest {
+                method: req.method().to_string(),
+                path,
+                raw_path,
+                headers: req.headers().clone(),
+                body,
+            });
+
+        Ok(http::Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(serde_json::to_vec(&response).unwrap())
+            .unwrap())
+    }
+}
+
+/// Create a `Client` for a mock homeserver, and the state of that homeserver.
+pub(crate) fn mock_client() -> (Client<MockClient>, Arc<MockState>) {
+    let state = Arc::new(MockState::default());
+    let client = Client::with_http_client(
+        MockClient(state.clone()),
+        String::from("https://matrix.example.org"),
+        Some(String::from("as_token")),
+    );
+    (client, state)
+}
@@ -0,0 +1,257 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Mutex, PoisonError};
+
+use ruma::events::typing::TypingEvent;
+use ruma::events::AnyEphemeralRoomEvent;
+use ruma::identifiers::{RoomId, UserId};
+use ruma::serde::Raw;
+
+#[cfg(feature = "runtime")]
+use std::time::Duration;
+
+#[cfg(feature = "runtime")]
+use ruma_client::HttpClient;
+#[cfg(feature = "runtime")]
+use tokio::sync::{mpsc, oneshot};
+#[cfg(feature = "runtime")]
+use tokio::task::JoinHandle;
+
+#[cfg(feature = "runtime")]
+use crate::intent::Intent;
+
+/// A user starting or stopping typing in a room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypingChange {
+    /// The room the user is typing in.
+    pub room_id: RoomId,
+    /// The user that started or stopped typing.
+    pub user_id: UserId,
+    /// Whether the user is typing now.
+    pub typing: bool,
+}
+
+/// Keeps track of who is typing in which room.
+///
+/// An `m.typing` EDU contains all users that are typing in a room, this turns them into a
+/// `TypingChange` for every user that started or stopped typing.
+#[derive(Debug, Default)]
+pub struct TypingTracker {
+    rooms: Mutex<HashMap<RoomId, BTreeSet<UserId>>>,
+    #[cfg(feature = "runtime")]
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<TypingChange>>>,
+}
+
+impl TypingTracker {
+    /// Create a new `TypingTracker` for which nobody is typing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the users that are typing in the room with the given `room_id`.
+    pub fn typing_in(&self, room_id: &RoomId) -> Vec<UserId> {
+        let rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+        rooms
+            .get(room_id)
+            .map(|users| users.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Handle the given typing `event`, returning the changes since the previous event of the
+    /// room.
+    pub fn handle_event(&self, event: &TypingEvent) -> Vec<TypingChange> {
+        let typing: BTreeSet<UserId> = event.content.user_ids.iter().cloned().collect();
+
+        let previous = {
+            let mut rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+            if typing.is_empty() {
+                rooms.remove(&event.room_id)
+            } else {
+                rooms.insert(event.room_id.clone(), typing.clone())
+            }
+        }
+        .unwrap_or_default();
+
+        let change = |user_id: &UserId, typing| TypingChange {
+            room_id: event.room_id.clone(),
+            user_id: user_id.clone(),
+            typing,
+        };
+        let changes: Vec<_> = typing
+            .difference(&previous)
+            .map(|user_id| change(user_id, true))
+            .chain(
+                previous
+                    .difference(&typing)
+                    .map(|user_id| change(user_id, false)),
+            )
+            .collect();
+
+        #[cfg(feature = "runtime")]
+        self.notify(&changes);
+
+        changes
+    }
+
+    /// Handle the given ephemeral `event`, returning no changes if it isn't a valid `m.typing`
+    /// event.
+    pub fn handle_raw(&self, event: &Raw<AnyEphemeralRoomEvent>) -> Vec<TypingChange> {
+        match event.deserialize() {
+            Ok(AnyEphemeralRoomEvent::Typing(event)) => self.handle_event(&event),
+            _ => vec![],
+        }
+    }
+
+    /// Get a receiver of all `TypingChange`s handled from now on.
+    #[cfg(feature = "runtime")]
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<TypingChange> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        subscribers.push(sender);
+        receiver
+    }
+
+    #[cfg(feature = "runtime")]
+    fn notify(&self, changes: &[TypingChange]) {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        subscribers.retain(|subscriber| {
+            changes
+                .iter()
+                .all(|change| subscriber.send(change.clone()).is_ok())
+        });
+    }
+}
+
+/// A typing notification that is kept alive in the background, created by `Intent::typing`.
+///
+/// The user stops typing when this is stopped or dropped.
+#[cfg(feature = "runtime")]
+#[derive(Debug)]
+pub struct TypingHandle {
+    stop: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+#[cfg(feature = "runtime")]
+impl TypingHandle {
+    /// Stop typing, waiting until the homeserver is told so.
+    pub async fn stop(mut self) {
+        self.stop.take();
+        let _ = (&mut self.task).await;
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl<C> Intent<C>
+where
+    C: HttpClient + Clone + Send + 'static,
+{
+    /// Mark the user as typing in the room with the given `room_id` until the returned handle is
+    /// stopped or dropped.
+    ///
+    /// The typing notification is sent with the given `timeout`, and re-sent in a background task
+    /// before it expires. Errors of the background task are ignored.
+    pub fn typing(&self, room_id: RoomId, timeout: Duration) -> TypingHandle {
+        let (stop, mut stopped) = oneshot::channel::<()>();
+        let intent = self.clone();
+        let refresh = timeout - timeout / 4;
+
+        let task = tokio::spawn(async move {
+            loop {
+                let _ = intent.set_typing(&room_id, Some(timeout)).await;
+                if tokio::time::timeout(refresh, &mut stopped).await.is_ok() {
+                    break;
+                }
+            }
+            let _ = intent.set_typing(&room_id, None).await;
+        });
+
+        TypingHandle {
+            stop: Some(stop),
+            task,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma::identifiers::{RoomId, UserId};
+    use ruma::serde::Raw;
+    use serde_json::json;
+    use serde_json::value::to_raw_value;
+
+    use crate::{TypingChange, TypingTracker};
+
+    fn typing_event(user_ids: &[&str]) -> Raw<ruma::events::AnyEphemeralRoomEvent> {
+        let event = json!({
+            "type": "m.typing",
+            "room_id": "!room:example.org",
+            "content": { "user_ids": user_ids },
+        });
+        Raw::from_json(to_raw_value(&event).unwrap())
+    }
+
+    #[test]
+    fn test_typing_tracker() {
+        let tracker = TypingTracker::new();
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let alice = UserId::try_from("@alice:example.org").unwrap();
+        let bob = UserId::try_from("@bob:example.org").unwrap();
+        let change = |user_id: &UserId, typing| TypingChange {
+            room_id: room_id.clone(),
+            user_id: user_id.clone(),
+            typing,
+        };
+
+        let changes = tracker.handle_raw(&typing_event(&["@alice:example.org"]));
+        assert_eq!(changes, vec![change(&alice, true)]);
+
+        let changes = tracker.handle_raw(&typing_event(&["@bob:example.org"]));
+        assert_eq!(changes, vec![change(&bob, true), change(&alice, false)]);
+        assert_eq!(tracker.typing_in(&room_id), vec![bob.clone()]);
+
+        let changes = tracker.handle_raw(&typing_event(&[]));
+        assert_eq!(changes, vec![change(&bob, false)]);
+        assert!(tracker.typing_in(&room_id).is_empty());
+    }
+
+    #[cfg(feature = "runtime")]
+    #[tokio::test(start_paused = true)]
+    async fn test_typing_handle() {
+        use std::time::Duration;
+
+        use crate::testing::mock_client;
+        use crate::Intent;
+
+        let (client, state) = mock_client();
+        let intent = Intent::new(client, UserId::try_from("@_ext_alice:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        let tracker = TypingTracker::new();
+        let mut changes = tracker.subscribe();
+        tracker.handle_raw(&typing_event(&["@alice:example.org"]));
+        assert!(changes.recv().await.unwrap().typing);
+
+        let handle = intent.typing(room_id, Duration::from_secs(20));
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        handle.stop().await;
+
+        let requests = state.requests_to("/typing/");
+        assert_eq!(requests.len(), 4);
+        assert!(requests[0]
+            .path
+            .contains("/rooms/!room:example.org/typing/@_ext_alice:example.org?"));
+        assert_eq!(
+            requests[0].body,
+            json!({ "typing": true, "timeout": 20000 })
+        );
+        assert_eq!(requests[3].body, json!({ "typing": false }));
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+use async_trait::async_trait;
+use ruma::api::client::r0::media::get_media_preview;
+use ruma::events::room::message::{MessageEventContent, MessageType};
+use ruma::events::AnyMessageEventContent;
+use ruma::identifiers::{EventId, MxcUri, RoomId};
+use ruma::MilliSecondsSinceUnixEpoch;
+use ruma_client::HttpClient;
+use serde_json::value::to_raw_value;
+use serde_json::{Map, Value};
+
+use crate::intent::{Intent, IntentError};
+
+/// The field of message content containing link previews, as used by existing clients and
+/// bridges.
+pub const LINK_PREVIEWS_FIELD: &str = "com.beeper.linkpreviews";
+
+/// A preview of a URL, with the OpenGraph data of the page.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UrlPreview {
+    /// The URL as it appeared in the message.
+    pub url: String,
+    /// The title of the page.
+    pub title: Option<String>,
+    /// The description of the page.
+    pub description: Option<String>,
+    /// The name of the site of the page.
+    pub site_name: Option<String>,
+    /// The MXC URI of the image of the page.
+    pub image: Option<MxcUri>,
+    /// The MIME type of the image.
+    pub image_type: Option<String>,
+    /// The size of the image in bytes.
+    pub image_size: Option<u64>,
+    /// The width of the image in pixels.
+    pub image_width: Option<u64>,
+    /// The height of the image in pixels.
+    pub image_height: Option<u64>,
+}
+
+impl UrlPreview {
+    /// Create a new `UrlPreview` of `url` from the OpenGraph `data` returned by the
+    /// `/preview_url` endpoint of the homeserver.
+    ///
+    /// Returns `None` if `data` has no title or description.
+    pub fn from_opengraph(url: &str, data: &Value) -> Option<Self> {
+        let string = |key: &str| data.get(key).and_then(Value::as_str).map(String::from);
+        let number = |key: &str| data.get(key).and_then(Value::as_u64);
+
+        let preview = Self {
+            url: url.to_string(),
+            title: string("og:title"),
+            description: string("og:description"),
+            site_name: string("og:site_name"),
+            image: string("og:image").map(MxcUri::from),
+            image_type: string("og:image:type"),
+            image_size: number("matrix:image:size"),
+            image_width: number("og:image:width"),
+            image_height: number("og:image:height"),
+        };
+        if preview.title.is_none() && preview.description.is_none() {
+            return None;
+        }
+        Some(preview)
+    }
+
+    /// Get the preview as an entry of the `com.beeper.linkpreviews` field of message content.
+    pub fn to_json(&self) -> Value {
+        let mut object = Map::new();
+        object.insert(String::from("matched_url"), Value::from(self.url.as_str()));
+        let strings = [
+            ("og:title", &self.title),
+            ("og:description", &self.description),
+            ("og:site_name", &self.site_name),
+            ("og:image:type", &self.image_type),
+        ];
+        for (key, value) in &strings {
+            if let Some(value) = value {
+                object.insert(key.to_string(), Value::from(value.as_str()));
+            }
+        }
+        if let Some(image) = &self.image {
+            object.insert(String::from("og:image"), Value::from(image.as_str()));
+        }
+        let numbers = [
+            ("matrix:image:size", self.image_size),
+            ("og:image:width", self.image_width),
+            ("og:image:height", self.image_height),
+        ];
+        for (key, value) in &numbers {
+            if let Some(value) = value {
+                object.insert(key.to_string(), Value::from(*value));
+            }
+        }
+        Value::Object(object)
+    }
+
+    /// Get a plain text summary of the preview, used for notices.
+    pub fn summary(&self) -> String {
+        let mut lines = vec![];
+        match (&self.site_name, &self.title) {
+            (Some(site_name), Some(title)) => lines.push(format!("{}: {}", site_name, title)),
+            (None, Some(title)) => lines.push(title.clone()),
+            (Some(site_name), None) => lines.push(site_name.clone()),
+            (None, None) => {}
+        }
+        if let Some(description) = &self.description {
+            lines.push(description.clone());
+        }
+        lines.push(self.url.clone());
+        lines.join("\n")
+    }
+}
+
+/// Fetches previews of URLs, for bridges that generate previews themselves instead of using the
+/// homeserver.
+#[async_trait]
+pub trait PreviewFetcher: Send + Sync {
+    /// Fetch a preview of `url`, or `None` if no preview could be made.
+    async fn fetch(&self, url: &str) -> Option<UrlPreview>;
+}
+
+/// Find the HTTP and HTTPS URLs in the plain text `body` of a message.
+///
+/// Punctuation directly after a URL, like the period ending a sentence, isn't part of the URL.
+pub fn extract_urls(body: &str) -> Vec<&str> {
+    body.split(|c: char| c.is_whitespace() || c == '<' || c == '>')
+        .filter_map(|word| {
+            let start = word.find("https://").or_else(|| word.find("http://"))?;
+            let url = word[start..].trim_end_matches(|c| ".,;:!?)]}'\"".contains(c));
+            let (_, host) = url.split_once("://")?;
+            if host.is_empty() {
+                return None;
+            }
+            Some(url)
+        })
+        .collect()
+}
+
+/// How previews are added to messages sent to Matrix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreviewMode {
+    /// Embed the previews in the `com.beeper.linkpreviews` field of the message itself.
+    Embed,
+    /// Send every preview as a notice after the message.
+    Notice,
+}
+
+/// Adds previews of the URLs in messages bridged to Matrix.
+///
+/// Previews are made using the `/preview_url` endpoint of the homeserver, unless a
+/// `PreviewFetcher` is set. Making a preview is best-effort: URLs that can't be previewed are
+/// skipped, and never stop the message itself from being sent.
+///
+/// Previews are enabled for all portals by default, which can be changed using `set_enabled`,
+/// and overridden for a single portal using `set_room_enabled`.
+pub struct UrlPreviewer {
+    fetcher: Option<Box<dyn PreviewFetcher>>,
+    mode: PreviewMode,
+    max_previews: usize,
+    enabled: bool,
+    rooms: Mutex<HashMap<RoomId, bool>>,
+}
+
+impl UrlPreviewer {
+    /// Create a new `UrlPreviewer` embedding at most one preview per message, using the
+    /// homeserver.
+    pub fn new() -> Self {
+        Self {
+            fetcher: None,
+            mode: PreviewMode::Embed,
+            max_previews: 1,
+            enabled: true,
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Make previews using `fetcher` instead of the homeserver.
+    pub fn set_fetcher<F: PreviewFetcher + 'static>(&mut self, fetcher: F) {
+        self.fetcher = Some(Box::new(fetcher));
+    }
+
+    /// Set how previews are added to messages.
+    pub fn set_mode(&mut self, mode: PreviewMode) {
+        self.mode = mode;
+    }
+
+    /// Set the maximum number of URLs previewed per message.
+    pub fn set_max_previews(&mut self, max_previews: usize) {
+        self.max_previews = max_previews;
+    }
+
+    /// Set whether previews are enabled for portals without their own setting.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Set whether previews are enabled for the portal with the given `room_id`, or use the
+    /// default again if `enabled` is `None`.
+    pub fn set_room_enabled(&self, room_id: &RoomId, enabled: Option<bool>) {
+        let mut rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+        match enabled {
+            Some(enabled) => rooms.insert(room_id.clone(), enabled),
+            None => rooms.remove(room_id),
+        };
+    }
+
+    /// Returns whether previews are enabled for the portal with the given `room_id`.
+    pub fn is_enabled(&self, room_id: &RoomId) -> bool {
+        let rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+        rooms.get(room_id).copied().unwrap_or(self.enabled)
+    }
+
+    /// Get the previews of the URLs in `body`, a message to be sent in the room with the given
+    /// `room_id`, fetching them as `intent` if the homeserver is used.
+    pub async fn previews<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+        room_id: &RoomId,
+        body: &str,
+    ) -> Vec<UrlPreview> {
+        if !self.is_enabled(room_id) {
+            return vec![];
+        }
+
+        let mut urls = extract_urls(body);
+        urls.dedup();
+        let mut previews = vec![];
+        for url in urls.into_iter().take(self.max_previews) {
+            let preview = match &self.fetcher {
+                Some(fetcher) => fetcher.fetch(url).await,
+                None => intent.preview_url(url).await.ok().flatten(),
+            };
+            previews.extend(preview);
+        }
+        previews
+    }
+
+    /// Send the message `content` as `intent` to the room with the given `room_id`, with
+    /// previews of the URLs in its body.
+    ///
+    /// Returns the ID of the sent message.
+    pub async fn send_message<C: HttpClient>(
+        &self,
+        intent: &Intent<C>,
+        room_id: &RoomId,
+        content: MessageEventContent,
+    ) -> Result<EventId, IntentError<C::Error>> {
+        let body = match &content.msgtype {
+            MessageType::Text(content) => Some(content.body.as_str()),
+            MessageType::Notice(content) => Some(content.body.as_str()),
+            MessageType::Emote(content) => Some(content.body.as_str()),
+            _ => None,
+        };
+        let previews = match body {
+            Some(body) => self.previews(intent, room_id, body).await,
+            None => vec![],
+        };
+
+        if previews.is_empty() {
+            return intent
+                .send_message(room_id, &AnyMessageEventContent::RoomMessage(content))
+                .await;
+        }
+
+        match self.mode {
+            PreviewMode::Embed => {
+                let mut json = serde_json::to_value(&content)?;
+                let previews = previews.iter().map(UrlPreview::to_json).collect();
+                json[LINK_PREVIEWS_FIELD] = Value::Array(previews);
+                intent
+                    .send_message_raw(room_id, "m.room.message", to_raw_value(&json)?)
+                    .await
+            }
+            PreviewMode::Notice => {
+                let event_id = intent
+                    .send_message(room_id, &AnyMessageEventContent::RoomMessage(content))
+                    .await?;
+                for preview in &previews {
+                    let content = MessageEventContent::notice_plain(preview.summary());
+                    intent
+                        .send_message(room_id, &AnyMessageEventContent::RoomMessage(content))
+                        .await?;
+                }
+                Ok(event_id)
+            }
+        }
+    }
+}
+
+impl Default for UrlPreviewer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: HttpClient> Intent<C> {
+    /// Get a preview of `url` from the homeserver, or `None` if the page has no title or
+    /// description.
+    pub async fn preview_url(
+        &self,
+        url: &str,
+    ) -> Result<Option<UrlPreview>, IntentError<C::Error>> {
+        let request = get_media_preview::Request::new(url, MilliSecondsSinceUnixEpoch::now());
+        let response = self.send(request).await?;
+        let data = match response.data {
+            Some(data) => serde_json::from_str(data.get())?,
+            None => return Ok(None),
+        };
+        Ok(UrlPreview::from_opengraph(url, &data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use async_trait::async_trait;
+    use ruma::events::room::message::MessageEventContent;
+    use ruma::identifiers::{RoomId, UserId};
+    use serde_json::json;
+
+    use crate::testing::mock_client;
+    use crate::{extract_urls, Intent, PreviewFetcher, PreviewMode, UrlPreview, UrlPreviewer};
+
+    #[test]
+    fn test_extract_urls() {
+        assert_eq!(
+            extract_urls("See https://example.org/a?b=c, and (http://example.com). https:// no"),
+            vec!["https://example.org/a?b=c", "http://example.com"]
+        );
+        assert_eq!(
+            extract_urls("<https://example.org/>"),
+            vec!["https://example.org/"]
+        );
+        assert!(extract_urls("no links here").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_embed_previews() {
+        let (client, state) = mock_client();
+        let ghost = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let previewer = UrlPreviewer::new();
+
+        state.respond(
+            "/preview_url",
+            200,
+            json!({
+                "og:title": "Example",
+                "og:description": "An example page",
+                "og:image": "mxc://example.org/image",
+                "matrix:image:size": 1024,
+            }),
+        );
+        state.respond("/send/", 200, json!({ "event_id": "$msg:example.org" }));
+        let content = MessageEventContent::text_plain("Look at https://example.org/page!");
+        previewer
+            .send_message(&ghost, &room_id, content)
+            .await
+            .unwrap();
+
+        let previews = state.requests_to("/preview_url");
+        assert_eq!(previews.len(), 1);
+        assert!(previews[0].path.contains("url=https://example.org/page"));
+        let sends = state.requests_to("/send/m.room.message/");
+        assert_eq!(
+            sends[0].body["com.beeper.linkpreviews"],
+            json!([{
+                "matched_url": "https://example.org/page",
+                "og:title": "Example",
+                "og:description": "An example page",
+                "og:image": "mxc://example.org/image",
+                "matrix:image:size": 1024,
+            }])
+        );
+
+        previewer.set_room_enabled(&room_id, Some(false));
+        let content = MessageEventContent::text_plain("https://example.org/other");
+        previewer
+            .send_message(&ghost, &room_id, content)
+            .await
+            .unwrap();
+        assert_eq!(state.requests_to("/preview_url").len(), 1);
+        let sends = state.requests_to("/send/m.room.message/");
+        assert!(sends[1].body.get("com.beeper.linkpreviews").is_none());
+    }
+
+    struct StaticFetcher;
+
+    #[async_trait]
+    impl PreviewFetcher for StaticFetcher {
+        async fn fetch(&self, url: &str) -> Option<UrlPreview> {
+            Some(UrlPreview {
+                url: url.to_string(),
+                title: Some(String::from("Fetched")),
+                site_name: Some(String::from("Example")),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_notice_previews() {
+        let (client, state) = mock_client();
+        let ghost = Intent::new(client, UserId::try_from("@_ext_bob:example.org").unwrap());
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+        let mut previewer = UrlPreviewer::new();
+        previewer.set_fetcher(StaticFetcher);
+        previewer.set_mode(PreviewMode::Notice);
+
+        state.respond("/send/", 200, json!({ "event_id": "$msg:example.org" }));
+        let content = MessageEventContent::text_plain("https://example.org/page");
+        previewer
+            .send_message(&ghost, &room_id, content)
+            .await
+            .unwrap();
+
+        assert!(state.requests_to("/preview_url").is_empty());
+        let sends = state.requests_to("/send/m.room.message/");
+        assert_eq!(sends.len(), 2);
+        assert_eq!(sends[1].body["msgtype"], "m.notice");
+        assert_eq!(
+            sends[1].body["body"],
+            "Example: Fetched\nhttps://example.org/page"
+        );
+    }
+}